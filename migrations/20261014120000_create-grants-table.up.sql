@@ -0,0 +1,13 @@
+-- "Household" sharing: a grant lets one user (the grantee) see another
+-- user's (the owner's) dogears list, read-only. Grants are one-way, so a
+-- mutually shared view is just two grants. A grant is only an invitation
+-- until the grantee accepts it; accepted stays NULL until then.
+
+CREATE TABLE IF NOT EXISTS grants(
+    id INTEGER PRIMARY KEY NOT NULL,
+    owner_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    grantee_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    accepted TIMESTAMP,
+    UNIQUE (owner_id, grantee_id)
+);
@@ -0,0 +1 @@
+ALTER TABLE dogears DROP COLUMN paused;
@@ -0,0 +1,3 @@
+-- A paused dogear ignores updates (so you can re-read a series from the top
+-- without losing your real place) until you unpause it.
+ALTER TABLE dogears ADD COLUMN paused BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,3 @@
+-- When this session last looked at its own dogears list, for the "what's
+-- new since I was last here" banner. Null until the first visit.
+ALTER TABLE sessions ADD COLUMN last_index_visit TIMESTAMP;
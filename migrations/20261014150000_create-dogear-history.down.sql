@@ -0,0 +1,2 @@
+DROP INDEX dogear_history_dogear_id;
+DROP TABLE dogear_history;
@@ -0,0 +1,13 @@
+-- Every spot a dogear gets marked at, plus an optional short note from
+-- whoever marked it ("stopped mid-scene"). Paused dogears don't move, so
+-- they don't get entries.
+
+CREATE TABLE IF NOT EXISTS dogear_history(
+    id INTEGER PRIMARY KEY NOT NULL,
+    dogear_id INTEGER NOT NULL REFERENCES dogears (id) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    note TEXT,
+    created TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX IF NOT EXISTS dogear_history_dogear_id ON dogear_history (dogear_id);
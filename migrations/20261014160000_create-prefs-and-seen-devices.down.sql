@@ -0,0 +1,2 @@
+DROP TABLE seen_devices;
+DROP TABLE user_prefs;
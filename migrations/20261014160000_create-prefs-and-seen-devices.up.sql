@@ -0,0 +1,19 @@
+-- Per-user settings that don't belong on the users table itself. Users
+-- without a row here get the defaults.
+
+CREATE TABLE IF NOT EXISTS user_prefs(
+    user_id INTEGER PRIMARY KEY NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    notify_new_device BOOLEAN NOT NULL DEFAULT false
+);
+
+-- Every browser+address combo a user has logged in from, so we can tell
+-- when a login comes from somewhere new. Only a hash of the pair is kept.
+
+CREATE TABLE IF NOT EXISTS seen_devices(
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    device_hash TEXT NOT NULL,
+    first_seen TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    last_seen TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    UNIQUE (user_id, device_hash)
+);
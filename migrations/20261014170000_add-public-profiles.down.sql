@@ -0,0 +1,2 @@
+ALTER TABLE dogears DROP COLUMN public;
+ALTER TABLE user_prefs DROP COLUMN public_profile;
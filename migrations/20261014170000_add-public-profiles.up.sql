@@ -0,0 +1,5 @@
+-- Opt-in public profile pages (/u/:username). Both halves default to
+-- private: the profile has to be turned on, and then it only lists the
+-- dogears you've marked public.
+ALTER TABLE user_prefs ADD COLUMN public_profile BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE dogears ADD COLUMN public BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,3 @@
+-- A little user-written CSS snippet that gets added to every page for that
+-- user's own logged-in sessions.
+ALTER TABLE user_prefs ADD COLUMN custom_css TEXT;
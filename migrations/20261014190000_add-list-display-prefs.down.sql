@@ -0,0 +1,3 @@
+ALTER TABLE user_prefs DROP COLUMN hide_dates;
+ALTER TABLE user_prefs DROP COLUMN show_prefix;
+ALTER TABLE user_prefs DROP COLUMN compact_list;
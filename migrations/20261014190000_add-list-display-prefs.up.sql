@@ -0,0 +1,5 @@
+-- Display options for the dogears list. All default to the way the list
+-- looked before there were options.
+ALTER TABLE user_prefs ADD COLUMN compact_list BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE user_prefs ADD COLUMN show_prefix BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE user_prefs ADD COLUMN hide_dates BOOLEAN NOT NULL DEFAULT false;
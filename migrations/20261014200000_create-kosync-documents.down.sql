@@ -0,0 +1 @@
+DROP TABLE kosync_documents;
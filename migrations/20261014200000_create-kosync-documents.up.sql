@@ -0,0 +1,18 @@
+-- Reading progress from KOReader's sync plugin, one row per user per book.
+-- KOReader identifies books by an opaque hash ("document"), and expects
+-- its own progress string back verbatim, so we keep all of that here. Each
+-- book also gets a dogear so it shows up in the list; if the user deletes
+-- it, the next sync makes a new one.
+
+CREATE TABLE IF NOT EXISTS kosync_documents(
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    document TEXT NOT NULL,
+    dogear_id INTEGER REFERENCES dogears (id) ON DELETE SET NULL,
+    progress TEXT NOT NULL,
+    percentage REAL NOT NULL,
+    device TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    updated TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    UNIQUE (user_id, document)
+);
@@ -0,0 +1,9 @@
+-- Free-form labels on dogears. For now they only come from imports (a
+-- browser bookmark's folders, or a read-later app's tags), and the list
+-- just shows them.
+
+CREATE TABLE IF NOT EXISTS dogear_tags(
+    dogear_id INTEGER NOT NULL REFERENCES dogears (id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (dogear_id, tag)
+);
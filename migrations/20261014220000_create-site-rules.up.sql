@@ -0,0 +1,11 @@
+-- Site knowledge for prefix suggestions: for a host where the URL-shape
+-- guess goes wrong, how many path segments name a story. Instance-wide,
+-- not per-user; operators load these from signed rule bundles.
+
+CREATE TABLE IF NOT EXISTS site_rules(
+    id INTEGER PRIMARY KEY NOT NULL,
+    host TEXT NOT NULL UNIQUE,
+    segments INTEGER NOT NULL,
+    note TEXT,
+    updated TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
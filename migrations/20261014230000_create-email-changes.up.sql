@@ -0,0 +1,16 @@
+-- Email address changes, so the new address can be confirmed before it
+-- counts and the old one can undo the change. Each row backs two links:
+-- confirm (mailed to the new address) and revert (mailed to the old one).
+-- Each link works once; the timestamps record which ones got used.
+
+CREATE TABLE IF NOT EXISTS email_changes(
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    old_email TEXT,
+    new_email TEXT,
+    created TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    confirmed TIMESTAMP,
+    reverted TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS email_changes_user_id ON email_changes (user_id);
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS dogear_history_created;
+ALTER TABLE user_prefs DROP COLUMN history_months;
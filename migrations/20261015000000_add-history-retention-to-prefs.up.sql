@@ -0,0 +1,7 @@
+-- How many months of dogear history a user wants kept, if they'd rather
+-- it go sooner than the site's retention default. NULL means "whatever the
+-- site does."
+ALTER TABLE user_prefs ADD COLUMN history_months INTEGER;
+
+-- The retention sweep goes by age.
+CREATE INDEX IF NOT EXISTS dogear_history_created ON dogear_history (created);
@@ -0,0 +1,9 @@
+-- Which kind of bookmarklet a token was generated for, if any: 'plain' or
+-- 'prompt_note'. NULL means it's not a bookmarklet's token. Regenerating a
+-- bookmarklet needs to know which kind to build.
+ALTER TABLE tokens ADD COLUMN bookmarklet TEXT;
+
+-- Until now the only record of that was the generated comment. Whether
+-- they asked for notes is lost, so plain is the best guess.
+UPDATE tokens SET bookmarklet = 'plain'
+WHERE scope = 'write_dogears' AND comment LIKE 'Personal bookmarklet created %';
@@ -0,0 +1,25 @@
+-- Scheduled personal backups. Users with a row here get an export of their
+-- dogears once a week, mailed to their email address or POSTed to a
+-- webhook. Destination is "email" or "webhook"; webhook_url only matters
+-- for the latter.
+
+CREATE TABLE IF NOT EXISTS backup_schedules(
+    user_id INTEGER PRIMARY KEY NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    destination TEXT NOT NULL,
+    webhook_url TEXT,
+    last_run TIMESTAMP
+);
+
+-- How recent backups went, so people can tell they're actually working.
+-- Only the last few per user are kept. A NULL error means it went out.
+
+CREATE TABLE IF NOT EXISTS backup_runs(
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    destination TEXT NOT NULL,
+    ran TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    dogears INTEGER NOT NULL,
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS backup_runs_user_id ON backup_runs (user_id);
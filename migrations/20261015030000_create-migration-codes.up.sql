@@ -0,0 +1,10 @@
+-- One-time codes for moving an account here from another instance. The
+-- other instance pushes its export to /api/v1/migrate_in with the code,
+-- which says whose account to put it in. One per user at a time; using it
+-- deletes it, and it's only good for a little while anyway.
+
+CREATE TABLE IF NOT EXISTS migration_codes(
+    user_id INTEGER PRIMARY KEY NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    code_hash TEXT UNIQUE NOT NULL,
+    created TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
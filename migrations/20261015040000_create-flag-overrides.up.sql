@@ -0,0 +1,9 @@
+-- Feature flag rollouts set live by an operator, on top of the config
+-- file's. Instance-wide; which users a rollout covers is worked out from
+-- the flag name and user ID, not stored.
+
+CREATE TABLE IF NOT EXISTS flag_overrides(
+    name TEXT PRIMARY KEY NOT NULL,
+    rollout INTEGER NOT NULL,
+    updated TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
@@ -0,0 +1,2 @@
+ALTER TABLE dogears DROP COLUMN archived;
+ALTER TABLE user_prefs DROP COLUMN stale_months;
@@ -0,0 +1,7 @@
+-- Archived dogears are ones you're done with for now but don't want to
+-- lose: they drop off the main list, but keep their spot and history.
+ALTER TABLE dogears ADD COLUMN archived BOOLEAN NOT NULL DEFAULT false;
+
+-- How many months a dogear can sit still before the index page suggests
+-- archiving it. NULL means the default; 0 means never.
+ALTER TABLE user_prefs ADD COLUMN stale_months INTEGER;
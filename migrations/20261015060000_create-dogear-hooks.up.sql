@@ -0,0 +1,14 @@
+-- Inbound webhooks: a secret URL per dogear that a site (or an
+-- RSS-to-webhook service) can POST new chapter URLs to. Like tokens, only
+-- the hash of the secret gets stored. One per dogear; making a new one
+-- replaces the old one.
+
+CREATE TABLE IF NOT EXISTS dogear_hooks(
+    dogear_id INTEGER PRIMARY KEY NOT NULL REFERENCES dogears (id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    secret_hash TEXT UNIQUE NOT NULL,
+    created TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    last_used TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS dogear_hooks_user_id ON dogear_hooks (user_id);
@@ -0,0 +1 @@
+DROP TABLE dogear_feeds;
@@ -0,0 +1,22 @@
+-- RSS/Atom feeds for dogears, which a background job checks for new
+-- chapters. One per dogear. The job keeps its own bookkeeping here: the
+-- newest link it's seen (so it can tell what's new next time), any new
+-- chapter it found but didn't move the dogear to, and how it's been
+-- failing, which pushes next_check further out.
+
+CREATE TABLE IF NOT EXISTS dogear_feeds(
+    dogear_id INTEGER PRIMARY KEY NOT NULL REFERENCES dogears (id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    feed_url TEXT NOT NULL,
+    auto_advance BOOLEAN NOT NULL DEFAULT false,
+    latest_seen TEXT,
+    new_chapter TEXT,
+    new_chapter_found TIMESTAMP,
+    last_checked TIMESTAMP,
+    next_check TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS dogear_feeds_user_id ON dogear_feeds (user_id);
+CREATE INDEX IF NOT EXISTS dogear_feeds_next_check ON dogear_feeds (next_check);
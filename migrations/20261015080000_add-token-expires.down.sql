@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS tokens_expires;
+ALTER TABLE tokens DROP COLUMN expires;
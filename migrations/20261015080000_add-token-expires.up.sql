@@ -0,0 +1,4 @@
+-- When a token stops working, if ever. NULL means it doesn't expire.
+-- Stored in datetime() form, so it compares right against datetime('now').
+ALTER TABLE tokens ADD COLUMN expires TIMESTAMP;
+CREATE INDEX IF NOT EXISTS tokens_expires ON tokens (expires);
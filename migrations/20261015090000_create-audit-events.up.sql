@@ -0,0 +1,19 @@
+-- A record of account events (logins, password changes, tokens made and
+-- deleted, accounts deleted), with where they came from, so people can
+-- spot someone else in their account. Events go away with their user,
+-- except the one saying the account was deleted, which keeps the username
+-- and a null user_id until retention gets it.
+
+CREATE TABLE IF NOT EXISTS audit_events(
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    detail TEXT,
+    ip TEXT,
+    user_agent TEXT,
+    created TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX IF NOT EXISTS audit_events_user_id_created ON audit_events (user_id, created);
+CREATE INDEX IF NOT EXISTS audit_events_created ON audit_events (created);
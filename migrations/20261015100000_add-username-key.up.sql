@@ -0,0 +1,8 @@
+-- What two usernames have to share to count as the same name: lowercased,
+-- with lookalike letters from other scripts folded onto Latin (see
+-- util/usernames.rs). Every name so far is ASCII, where that's just
+-- lower(). Not unique, since existing accounts that only differ by case
+-- get to keep their names; the app checks it for new ones instead.
+ALTER TABLE users ADD COLUMN username_key TEXT;
+UPDATE users SET username_key = lower(username);
+CREATE INDEX IF NOT EXISTS users_username_key ON users (username_key);
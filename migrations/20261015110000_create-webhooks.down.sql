@@ -0,0 +1,6 @@
+DROP TRIGGER IF EXISTS webhook_dogear_deleted;
+DROP TRIGGER IF EXISTS webhook_dogear_archived;
+DROP TRIGGER IF EXISTS webhook_dogear_updated;
+DROP TRIGGER IF EXISTS webhook_dogear_created;
+DROP TABLE webhook_deliveries;
+DROP TABLE webhooks;
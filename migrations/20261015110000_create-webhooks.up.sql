@@ -0,0 +1,146 @@
+-- Outbound webhooks: URLs that get a JSON POST whenever one of their
+-- owner's dogears is made, moves, gets archived, or gets deleted. Each
+-- delivery is signed with the webhook's secret (HMAC-SHA256), so unlike
+-- token and inbound hook secrets, this one's stored as-is.
+
+CREATE TABLE IF NOT EXISTS webhooks(
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    created TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    last_delivered TIMESTAMP,
+    last_error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS webhooks_user_id ON webhooks (user_id);
+
+-- Deliveries waiting to go out, one per webhook per change. The triggers
+-- below fill this in, so every way a dogear can change (the API, the
+-- bookmarklet, imports, feeds, inbound hooks) gets noticed without each
+-- one having to remember to. Each delivery carries a versioned dogear event
+-- (see DogearEvent in api_types): what kind of change it was, plus the
+-- dogear as it was right before and right after, snapshotted by the
+-- trigger as the change happens. That way deletions get an event too, which
+-- is why deliveries don't hang off the dogear with a foreign key. A failed
+-- delivery waits longer before each retry, and gets dropped after enough
+-- of them.
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries(
+    id INTEGER PRIMARY KEY NOT NULL,
+    webhook_id INTEGER NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    -- Not a foreign key, since the dogear might be gone by send time.
+    dogear_id INTEGER NOT NULL,
+    -- created, updated, deleted, or archived.
+    event TEXT NOT NULL,
+    -- The dogear as JSON (same shape as the API's), before and after the
+    -- change. No before for created, and no after for deleted.
+    before_snapshot TEXT,
+    after_snapshot TEXT,
+    created TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id);
+CREATE INDEX IF NOT EXISTS webhook_deliveries_next_attempt ON webhook_deliveries (next_attempt);
+
+CREATE TRIGGER IF NOT EXISTS webhook_dogear_created
+AFTER INSERT ON dogears
+BEGIN
+    INSERT INTO webhook_deliveries (webhook_id, dogear_id, event, after_snapshot)
+    SELECT id, NEW.id, 'created', json_object(
+        'id', NEW.id,
+        'user_id', NEW.user_id,
+        'prefix', NEW.prefix,
+        'current', NEW.current,
+        'display_name', NEW.display_name,
+        'updated', strftime('%Y-%m-%dT%H:%M:%SZ', NEW.updated),
+        'paused', json(CASE WHEN NEW.paused THEN 'true' ELSE 'false' END),
+        'public', json(CASE WHEN NEW.public THEN 'true' ELSE 'false' END),
+        'archived', json(CASE WHEN NEW.archived THEN 'true' ELSE 'false' END)
+    )
+    FROM webhooks WHERE user_id = NEW.user_id;
+END;
+
+-- Where it's at and what it's called count, and so does coming back off
+-- the archive (which marking an archived dogear does on its own). Pausing
+-- and publishing don't.
+CREATE TRIGGER IF NOT EXISTS webhook_dogear_updated
+AFTER UPDATE OF prefix, current, display_name, archived ON dogears
+WHEN NEW.prefix IS NOT OLD.prefix
+    OR NEW.current IS NOT OLD.current
+    OR NEW.display_name IS NOT OLD.display_name
+    OR (OLD.archived AND NOT NEW.archived)
+BEGIN
+    INSERT INTO webhook_deliveries (webhook_id, dogear_id, event, before_snapshot, after_snapshot)
+    SELECT id, NEW.id, 'updated', json_object(
+        'id', OLD.id,
+        'user_id', OLD.user_id,
+        'prefix', OLD.prefix,
+        'current', OLD.current,
+        'display_name', OLD.display_name,
+        'updated', strftime('%Y-%m-%dT%H:%M:%SZ', OLD.updated),
+        'paused', json(CASE WHEN OLD.paused THEN 'true' ELSE 'false' END),
+        'public', json(CASE WHEN OLD.public THEN 'true' ELSE 'false' END),
+        'archived', json(CASE WHEN OLD.archived THEN 'true' ELSE 'false' END)
+    ), json_object(
+        'id', NEW.id,
+        'user_id', NEW.user_id,
+        'prefix', NEW.prefix,
+        'current', NEW.current,
+        'display_name', NEW.display_name,
+        'updated', strftime('%Y-%m-%dT%H:%M:%SZ', NEW.updated),
+        'paused', json(CASE WHEN NEW.paused THEN 'true' ELSE 'false' END),
+        'public', json(CASE WHEN NEW.public THEN 'true' ELSE 'false' END),
+        'archived', json(CASE WHEN NEW.archived THEN 'true' ELSE 'false' END)
+    )
+    FROM webhooks WHERE user_id = NEW.user_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS webhook_dogear_archived
+AFTER UPDATE OF archived ON dogears
+WHEN NEW.archived AND NOT OLD.archived
+BEGIN
+    INSERT INTO webhook_deliveries (webhook_id, dogear_id, event, before_snapshot, after_snapshot)
+    SELECT id, NEW.id, 'archived', json_object(
+        'id', OLD.id,
+        'user_id', OLD.user_id,
+        'prefix', OLD.prefix,
+        'current', OLD.current,
+        'display_name', OLD.display_name,
+        'updated', strftime('%Y-%m-%dT%H:%M:%SZ', OLD.updated),
+        'paused', json(CASE WHEN OLD.paused THEN 'true' ELSE 'false' END),
+        'public', json(CASE WHEN OLD.public THEN 'true' ELSE 'false' END),
+        'archived', json(CASE WHEN OLD.archived THEN 'true' ELSE 'false' END)
+    ), json_object(
+        'id', NEW.id,
+        'user_id', NEW.user_id,
+        'prefix', NEW.prefix,
+        'current', NEW.current,
+        'display_name', NEW.display_name,
+        'updated', strftime('%Y-%m-%dT%H:%M:%SZ', NEW.updated),
+        'paused', json(CASE WHEN NEW.paused THEN 'true' ELSE 'false' END),
+        'public', json(CASE WHEN NEW.public THEN 'true' ELSE 'false' END),
+        'archived', json(CASE WHEN NEW.archived THEN 'true' ELSE 'false' END)
+    )
+    FROM webhooks WHERE user_id = NEW.user_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS webhook_dogear_deleted
+AFTER DELETE ON dogears
+BEGIN
+    INSERT INTO webhook_deliveries (webhook_id, dogear_id, event, before_snapshot)
+    SELECT id, OLD.id, 'deleted', json_object(
+        'id', OLD.id,
+        'user_id', OLD.user_id,
+        'prefix', OLD.prefix,
+        'current', OLD.current,
+        'display_name', OLD.display_name,
+        'updated', strftime('%Y-%m-%dT%H:%M:%SZ', OLD.updated),
+        'paused', json(CASE WHEN OLD.paused THEN 'true' ELSE 'false' END),
+        'public', json(CASE WHEN OLD.public THEN 'true' ELSE 'false' END),
+        'archived', json(CASE WHEN OLD.archived THEN 'true' ELSE 'false' END)
+    )
+    FROM webhooks WHERE user_id = OLD.user_id;
+END;
@@ -0,0 +1,24 @@
+-- Two-factor auth: each user can have one TOTP secret. It's NULL-confirmed
+-- while they're still setting up their app, and only counts once they've
+-- typed a working code. Authenticator apps need the secret itself, so it's
+-- stored as-is (base32). last_step is the time step of the last code that
+-- worked, so no code works twice.
+
+CREATE TABLE IF NOT EXISTS totp_secrets(
+    user_id INTEGER PRIMARY KEY NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    secret TEXT NOT NULL,
+    confirmed TIMESTAMP,
+    last_step INTEGER,
+    created TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+-- One-shot codes for getting in without the app. Like tokens, only their
+-- hashes get stored, and a used one gets deleted.
+
+CREATE TABLE IF NOT EXISTS totp_recovery_codes(
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS totp_recovery_codes_user_id ON totp_recovery_codes (user_id);
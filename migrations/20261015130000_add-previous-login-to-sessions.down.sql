@@ -0,0 +1,2 @@
+ALTER TABLE sessions DROP COLUMN previous_login_user_agent;
+ALTER TABLE sessions DROP COLUMN previous_login;
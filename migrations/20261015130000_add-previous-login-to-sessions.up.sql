@@ -0,0 +1,6 @@
+-- The login before this one (its time and browser, from the audit log),
+-- copied onto the session when it's made so the page header can show it
+-- without a lookup on every request. Null for a first-ever login, or when
+-- the last one's aged out of the audit log.
+ALTER TABLE sessions ADD COLUMN previous_login TIMESTAMP;
+ALTER TABLE sessions ADD COLUMN previous_login_user_agent TEXT;
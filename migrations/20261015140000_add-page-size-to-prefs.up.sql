@@ -0,0 +1,3 @@
+-- How many items a page of a list shows (dogears, tokens, sessions) when
+-- the URL doesn't say. NULL means the site default.
+ALTER TABLE user_prefs ADD COLUMN page_size INTEGER;
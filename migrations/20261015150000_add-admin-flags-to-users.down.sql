@@ -0,0 +1,3 @@
+ALTER TABLE users DROP COLUMN must_reset_password;
+ALTER TABLE users DROP COLUMN disabled;
+ALTER TABLE users DROP COLUMN is_admin;
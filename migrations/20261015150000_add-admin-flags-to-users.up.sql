@@ -0,0 +1,8 @@
+-- Site admins get the /admin/users page, where they can see who's around,
+-- disable accounts, and make people pick a new password. There's no UI for
+-- making admins; that's `eardogger-rs db admin USERNAME`.
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT false;
+-- A disabled account can't log in, and loses its sessions and tokens.
+ALTER TABLE users ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT false;
+-- Set by an admin; cleared when the user sets a new password.
+ALTER TABLE users ADD COLUMN must_reset_password BOOLEAN NOT NULL DEFAULT false;
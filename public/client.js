@@ -58,6 +58,14 @@ function submitDogear(dest, dogObj, triggerElement) {
   });
 };
 
+// Reload whichever dogears list the button was in (the main one, or the archive).
+function refreshDogears(triggerElement) {
+  let list = document.getElementById('dogears-fragment');
+  let fragmentUrl = list.getAttribute('data-fragment-url') || '/fragments/dogears';
+  let pageUrl = list.getAttribute('data-page-url') || '/';
+  replaceFragment(fragmentUrl, pageUrl, 'dogears-fragment', triggerElement);
+}
+
 // u guessed it,
 function deleteDogear(id, triggerElement) {
   triggerElement.classList.add('busy-fetching');
@@ -66,34 +74,216 @@ function deleteDogear(id, triggerElement) {
     credentials: 'include',
     headers: {'Content-Type': 'application/json', 'Accept': 'application/json'},
   }).then(() => {
-    replaceFragment('/fragments/dogears', '/', 'dogears-fragment', triggerElement);
+    refreshDogears(triggerElement);
+  });
+}
+
+// action is 'pause', 'unpause', 'publish', 'unpublish', 'archive', or 'unarchive'
+function setDogearFlag(id, action, triggerElement) {
+  triggerElement.classList.add('busy-fetching');
+  fetch(`/api/v1/dogear/${id}/${action}`, {
+    method: 'POST',
+    credentials: 'include',
+    headers: {'Content-Type': 'application/json', 'Accept': 'application/json'},
+  }).then(() => {
+    refreshDogears(triggerElement);
+  });
+}
+
+// Change a dogear's prefix and/or display name. The prefix has to still
+// match where the dogear is now; the server says so if it doesn't.
+function editDogear(id, currentPrefix, currentDisplayName, triggerElement) {
+  let prefix = window.prompt("Prefix (the part of the URL every page of the serial starts with):", currentPrefix);
+  if (prefix === null) {
+    return;
+  }
+  let displayName = window.prompt("Display name (leave it blank to just show the prefix):", currentDisplayName);
+  if (displayName === null) {
+    return;
+  }
+  let changes = {};
+  if (prefix.trim() !== currentPrefix) {
+    changes.prefix = prefix.trim();
+  }
+  if (displayName.trim() !== currentDisplayName) {
+    changes.display_name = displayName.trim();
+  }
+  if (Object.keys(changes).length === 0) {
+    return;
+  }
+  triggerElement.classList.add('busy-fetching');
+  fetch(`/api/v1/dogear/${id}`, {
+    method: 'PATCH',
+    credentials: 'include',
+    headers: {'Content-Type': 'application/json', 'Accept': 'application/json'},
+    body: JSON.stringify(changes),
+  }).then(response => {
+    if (!response.ok) {
+      response.json().then(err => window.alert(err.error || "Couldn't change that dogear."));
+    }
+    refreshDogears(triggerElement);
   });
 }
 
+// Give a dogear a webhook (or a fresh URL for the one it has), and show
+// the URL, which is the only time we'll ever see it.
+function makeHook(id, triggerElement) {
+  if (!window.confirm("Make a webhook URL for this dogear? If it already has one, the old URL stops working.")) {
+    return;
+  }
+  triggerElement.classList.add('busy-fetching');
+  fetch(`/api/v1/dogear/${id}/hook`, {
+    method: 'POST',
+    credentials: 'include',
+    headers: {'Content-Type': 'application/json', 'Accept': 'application/json'},
+  }).then(response => response.json()).then(hook => {
+    triggerElement.classList.remove('busy-fetching');
+    window.prompt("Here's the webhook URL. Copy it now; you can't see it again later.", hook.url);
+  });
+}
+
+// Watch a feed for a dogear's new chapters, change the feed, or (if you
+// blank out the URL) stop watching.
+function setFeed(id, currentFeedUrl, currentAutoAdvance, triggerElement) {
+  let feedUrl = window.prompt("RSS or Atom feed URL to watch for new chapters (leave it blank to stop watching):", currentFeedUrl);
+  if (feedUrl === null) {
+    return;
+  }
+  feedUrl = feedUrl.trim();
+  let request;
+  if (feedUrl === '') {
+    request = {method: 'DELETE'};
+  } else {
+    let autoAdvance = window.confirm(
+      `Move this dogear along on its own when a new chapter shows up, as long as you're caught up? (Cancel to just flag it instead.)${currentAutoAdvance ? ' It does now.' : ''}`
+    );
+    request = {
+      method: 'PUT',
+      body: JSON.stringify({feed_url: feedUrl, auto_advance: autoAdvance}),
+    };
+  }
+  triggerElement.classList.add('busy-fetching');
+  fetch(`/api/v1/dogear/${id}/feed`, {
+    credentials: 'include',
+    headers: {'Content-Type': 'application/json', 'Accept': 'application/json'},
+    ...request,
+  }).then(response => {
+    if (!response.ok) {
+      response.json().then(err => window.alert(err.error || "Couldn't set that feed."));
+    }
+    refreshDogears(triggerElement);
+  });
+}
+
+function deleteHook(id, triggerElement) {
+  triggerElement.classList.add('busy-fetching');
+  fetch(`/api/v1/dogear/${id}/hook`, {
+    method: 'DELETE',
+    credentials: 'include',
+    headers: {'Accept': 'application/json'},
+  }).then(() => {
+    replaceFragment('/fragments/hooks', '/account', 'hooks-fragment', triggerElement);
+  })
+}
+
+function deleteWebhook(id, triggerElement) {
+  triggerElement.classList.add('busy-fetching');
+  fetch(`/webhooks/${id}`, {
+    method: 'DELETE',
+    credentials: 'include',
+  }).then(() => {
+    replaceFragment('/fragments/webhooks', '/account', 'webhooks-fragment', triggerElement);
+  })
+}
+
 function deleteToken(id, triggerElement) {
   triggerElement.classList.add('busy-fetching');
   fetch(`/tokens/${id}`, {
     method: 'DELETE',
     credentials: 'include',
+  }).then(response => {
+    rememberCsrfToken(response);
+    replaceFragment('/fragments/tokens', '/account', 'tokens-fragment', triggerElement);
+  })
+}
+
+// changes is {scope: 'write_dogears' | 'manage_dogears'} and/or {comment: string}
+function patchToken(id, changes, triggerElement) {
+  triggerElement.classList.add('busy-fetching');
+  fetch(`/tokens/${id}`, {
+    method: 'PATCH',
+    credentials: 'include',
+    headers: {'Content-Type': 'application/json'},
+    body: JSON.stringify(changes),
   }).then(() => {
     replaceFragment('/fragments/tokens', '/account', 'tokens-fragment', triggerElement);
   })
 }
 
+function deleteBookmarklet(id, triggerElement) {
+  triggerElement.classList.add('busy-fetching');
+  fetch(`/tokens/${id}`, {
+    method: 'DELETE',
+    credentials: 'include',
+  }).then(response => {
+    rememberCsrfToken(response);
+    replaceFragment('/fragments/bookmarklets', '/bookmarklets', 'bookmarklets-fragment', triggerElement);
+  })
+}
+
 function deleteSession(id, triggerElement) {
   triggerElement.classList.add('busy-fetching');
   fetch(`/sessions/${id}`, {
     method: 'DELETE',
     credentials: 'include',
   }).then(() => {
-    replaceFragment('/fragments/sessions', '/account', 'sessions-fragment', triggerElement);
+    // Could be the account page, or the sessions page by itself.
+    let list = document.getElementById('sessions-fragment');
+    let fragmentUrl = list.getAttribute('data-fragment-url') || '/fragments/sessions';
+    let pageUrl = list.getAttribute('data-page-url') || '/account';
+    replaceFragment(fragmentUrl, pageUrl, 'sessions-fragment', triggerElement);
+  })
+}
+
+function deleteGrant(id, triggerElement) {
+  triggerElement.classList.add('busy-fetching');
+  fetch(`/grants/${id}`, {
+    method: 'DELETE',
+    credentials: 'include',
+  }).then(() => {
+    replaceFragment('/fragments/grants', '/account', 'grants-fragment', triggerElement);
   })
 }
 
+// Sensitive actions (making or deleting tokens) swap out the session's
+// anti-CSRF token and send the new one in a header. Everything else on the
+// page still has the old one, so it needs patching after every fragment.
+let currentCsrfToken = null;
+
+function rememberCsrfToken(response) {
+  const token = response.headers.get('eardogger-csrf-token');
+  if (token) {
+    currentCsrfToken = token;
+  }
+}
+
+function applyCsrfToken() {
+  if (!currentCsrfToken) {
+    return;
+  }
+  for (const input of document.querySelectorAll('input[name="csrf_token"]')) {
+    input.value = currentCsrfToken;
+  }
+  for (const button of document.querySelectorAll('[data-csrf-token]')) {
+    button.setAttribute('data-csrf-token', currentCsrfToken);
+  }
+}
+
 let originalHistoryState = null;
 
-// general-purpose way to update a fragment of a page
-function replaceFragment(fragmentUrl, newPageUrl, fragmentElementId, triggerElement, method = 'GET') {
+// general-purpose way to update a fragment of a page. POSTs need a csrfToken,
+// which goes in a header so it stays out of logs and Referers.
+function replaceFragment(fragmentUrl, newPageUrl, fragmentElementId, triggerElement, method = 'GET', csrfToken = null) {
   let fragmentElement = document.getElementById(fragmentElementId);
   // Stash this _before_ revving up the spinner, so we don't get perma-spin on final back-nav.
   let previousText = fragmentElement.outerHTML;
@@ -101,7 +291,9 @@ function replaceFragment(fragmentUrl, newPageUrl, fragmentElementId, triggerElem
   return fetch(fragmentUrl, {
     method,
     credentials: 'include',
+    headers: csrfToken ? {'X-CSRF-Token': csrfToken} : {},
   }).then(response => {
+    rememberCsrfToken(response);
     response.text().then(text => {
       if (response.ok) {
         // Preserve original condition, if this is the first time we're making history:
@@ -114,6 +306,7 @@ function replaceFragment(fragmentUrl, newPageUrl, fragmentElementId, triggerElem
         }
         // Replace fragment, and update history state:
         fragmentElement.outerHTML = text;
+        applyCsrfToken();
         history.pushState({fragmentElementId, fragmentText: text}, '', newPageUrl);
       } else {
         fragmentElement.prepend(`Hmm, something went wrong: ${text}`);
@@ -133,9 +326,11 @@ window.addEventListener('popstate', function(e) {
   if (e.state && e.state.fragmentElementId && e.state.fragmentText) {
     let { fragmentElementId, fragmentText } = e.state;
     document.getElementById(fragmentElementId).outerHTML = fragmentText;
+    applyCsrfToken();
   } else if (!e.state && originalHistoryState) {
     let { fragmentElementId, fragmentText } = originalHistoryState;
     document.getElementById(fragmentElementId).outerHTML = fragmentText;
+    applyCsrfToken();
   }
 });
 
@@ -164,11 +359,30 @@ document.addEventListener('click', function(e){
   } else if (that.matches('#generate-personal-bookmarklet')) {
     // This one's a one-off, so just hardcode everything.
     replaceFragment(
-      '/fragments/personalmark?csrf_token=' + encodeURIComponent(that.getAttribute('data-csrf-token')),
-      '/install',
+      '/fragments/personalmark' + (document.getElementById('prompt-note').checked ? '?prompt_note=true' : ''),
+      '/bookmarklets',
       'generate-personal-bookmarklet-fragment',
       that,
-      'POST'
+      'POST',
+      that.getAttribute('data-csrf-token')
+    );
+  } else if (that.matches('#generate-quickmark')) {
+    replaceFragment(
+      '/fragments/quickmark',
+      '/install',
+      'generate-quickmark-fragment',
+      that,
+      'POST',
+      that.getAttribute('data-csrf-token')
+    );
+  } else if (that.matches('#generate-kosync')) {
+    replaceFragment(
+      '/fragments/kosync',
+      '/install',
+      'generate-kosync-fragment',
+      that,
+      'POST',
+      that.getAttribute('data-csrf-token')
     );
   } else if (that.matches('.tabs .tab')) {
     e.preventDefault();
@@ -183,6 +397,56 @@ document.addEventListener('click', function(e){
     // Clipboard copy buttons:
     e.preventDefault();
     clipboardHandler(that);
+  } else if (that.matches('.pause-dogear')) {
+    e.preventDefault();
+    setDogearFlag(that.getAttribute('data-dogear-id'), 'pause', that);
+  } else if (that.matches('.unpause-dogear')) {
+    e.preventDefault();
+    setDogearFlag(that.getAttribute('data-dogear-id'), 'unpause', that);
+  } else if (that.matches('.publish-dogear')) {
+    e.preventDefault();
+    setDogearFlag(that.getAttribute('data-dogear-id'), 'publish', that);
+  } else if (that.matches('.unpublish-dogear')) {
+    e.preventDefault();
+    setDogearFlag(that.getAttribute('data-dogear-id'), 'unpublish', that);
+  } else if (that.matches('.archive-dogear')) {
+    e.preventDefault();
+    setDogearFlag(that.getAttribute('data-dogear-id'), 'archive', that);
+  } else if (that.matches('.unarchive-dogear')) {
+    e.preventDefault();
+    setDogearFlag(that.getAttribute('data-dogear-id'), 'unarchive', that);
+  } else if (that.matches('.edit-dogear')) {
+    e.preventDefault();
+    editDogear(
+      that.getAttribute('data-dogear-id'),
+      that.getAttribute('data-prefix'),
+      that.getAttribute('data-display-name'),
+      that
+    );
+  } else if (that.matches('.make-hook')) {
+    e.preventDefault();
+    makeHook(that.getAttribute('data-dogear-id'), that);
+  } else if (that.matches('.set-feed')) {
+    e.preventDefault();
+    setFeed(
+      that.getAttribute('data-dogear-id'),
+      that.getAttribute('data-feed-url'),
+      that.getAttribute('data-auto-advance') === 'true',
+      that
+    );
+  } else if (that.matches('.bookmarklet-regenerate')) {
+    e.preventDefault();
+    replaceFragment(
+      `/fragments/bookmarklets/${that.getAttribute('data-token-id')}/regenerate`,
+      '/bookmarklets',
+      'bookmarklets-fragment',
+      that,
+      'POST',
+      that.getAttribute('data-csrf-token')
+    );
+  } else if (that.matches('.token-scope-change')) {
+    e.preventDefault();
+    patchToken(that.getAttribute('data-token-id'), {scope: that.getAttribute('data-scope')}, that);
   } else if (that.matches('.really-delete.delete-dogear')) {
     // Armed delete buttons (order matters, must check this before the "really" one):
     e.preventDefault();
@@ -190,9 +454,21 @@ document.addEventListener('click', function(e){
   } else if (that.matches('.really-delete.token-delete')) {
     e.preventDefault();
     deleteToken(that.getAttribute('data-token-id'), that);
+  } else if (that.matches('.really-delete.bookmarklet-delete')) {
+    e.preventDefault();
+    deleteBookmarklet(that.getAttribute('data-token-id'), that);
   } else if (that.matches('.really-delete.session-delete')) {
     e.preventDefault();
     deleteSession(that.getAttribute('data-session-id'), that);
+  } else if (that.matches('.really-delete.grant-delete')) {
+    e.preventDefault();
+    deleteGrant(that.getAttribute('data-grant-id'), that);
+  } else if (that.matches('.really-delete.hook-delete')) {
+    e.preventDefault();
+    deleteHook(that.getAttribute('data-dogear-id'), that);
+  } else if (that.matches('.really-delete.webhook-delete')) {
+    e.preventDefault();
+    deleteWebhook(that.getAttribute('data-webhook-id'), that);
   } else if (that.matches('.delete-button')) {
     // Unarmed delete buttons:
     e.preventDefault();
@@ -228,6 +504,93 @@ document.addEventListener('submit', function(e){
   }
 });
 
+// Inline token rename forms on the account page
+document.addEventListener('submit', function(e){
+  const that = e.target;
+  if (that.matches('.token-comment-form')) {
+    e.preventDefault();
+    patchToken(that.getAttribute('data-token-id'), {comment: that.elements['comment'].value}, that);
+  }
+});
+
+// New token form on the account page: show the cleartext in place, then
+// refresh the list so the new one shows up there too.
+document.addEventListener('submit', function(e){
+  const that = e.target;
+  if (that.matches('#create-token')) {
+    e.preventDefault();
+    that.classList.add('busy-fetching');
+    fetch(that.action, {
+      method: 'POST',
+      credentials: 'include',
+      body: new URLSearchParams(new FormData(that)),
+    }).then(response => {
+      rememberCsrfToken(response);
+      response.text().then(text => {
+        that.classList.remove('busy-fetching');
+        if (response.ok) {
+          document.getElementById('new-token-fragment').outerHTML = text;
+          that.reset();
+          applyCsrfToken();
+          replaceFragment('/fragments/tokens', '/account', 'tokens-fragment', that);
+        } else {
+          window.alert("Couldn't make that token. Try reloading the page.");
+        }
+      });
+    });
+  }
+});
+
+// Import form: read the chosen export file into the textarea, since the
+// form posts as plain urlencoded text.
+document.addEventListener('change', function(e){
+  const that = e.target;
+  if (that.matches('#import_file') && that.files.length > 0) {
+    that.files[0].text().then(text => {
+      document.getElementById(that.getAttribute('data-target')).value = text;
+    });
+  }
+});
+
+// Dismiss the "what's new" banner without a page reload
+document.addEventListener('submit', function(e){
+  const that = e.target;
+  if (that.matches('#whats-new-dismiss')) {
+    e.preventDefault();
+    that.classList.add('busy-fetching');
+    fetch(that.action, {
+      method: 'POST',
+      credentials: 'include',
+      body: new URLSearchParams(new FormData(that)),
+    }).then(() => {
+      document.getElementById('whats-new').remove();
+    });
+  }
+});
+
+// Signup proof-of-work: count up until sha256(challenge + ':' + nonce) starts
+// with enough zero bits. The server checks the same math in util/antispam.rs.
+async function solveProofOfWork(challenge, bits) {
+  const encoder = new TextEncoder();
+  for (let nonce = 0; ; nonce++) {
+    const digest = await crypto.subtle.digest('SHA-256', encoder.encode(challenge + ':' + nonce));
+    if (leadingZeroBits(new Uint8Array(digest)) >= bits) {
+      return nonce.toString();
+    }
+  }
+}
+
+function leadingZeroBits(bytes) {
+  let count = 0;
+  for (const byte of bytes) {
+    if (byte !== 0) {
+      return count + Math.clz32(byte) - 24;
+    }
+    count += 8;
+  }
+  return count;
+}
+
 // OK, here's all the stuff where I need to know the page state before doing something:
 whenever(() => {
   // Reveal copy buttons if they're functional
@@ -252,17 +615,13 @@ whenever(() => {
     tick();
   }
 
-  // Creating new dogear: Suggest the domain name as the default prefix, but let them customize it
-  // if the same domain hosts several sites.
-  // TODO: push some of this logic back into the server side.
+  // Creating new dogear: The server fills in its best guess at a prefix. Lock it, but let them
+  // customize it if the guess is off.
   const createForm = document.getElementById('create-dogear');
   if (createForm && createForm.elements['prefix'] && createForm.elements['prefix'].value) {
     const prefix = createForm.elements['prefix'];
     const changePrefix = document.getElementById('change-prefix');
 
-    const prefixHost = (new URL(prefix.defaultValue)).host + '/';
-
-    prefix.value = prefixHost;
     prefix.readOnly = true;
     prefix.classList.add('read-only');
     changePrefix.style.display = 'inline-block'; // 'cause it's hidden by default.
@@ -271,10 +630,35 @@ whenever(() => {
       this.style.display = 'none';
       prefix.readOnly = false;
       prefix.classList.remove('read-only');
-      prefix.value = prefix.defaultValue.replace(/^https?:\/\//, '');
       prefix.focus();
     });
   }
 
+  // Signup form with a proof-of-work challenge: start solving it right away,
+  // so it's usually done before anyone finishes typing a password. If they
+  // beat it anyway, hold the submit until it's done.
+  const powForm = document.querySelector('form[data-pow-challenge]');
+  if (powForm && window.crypto && crypto.subtle) {
+    const solved = solveProofOfWork(
+      powForm.getAttribute('data-pow-challenge'),
+      parseInt(powForm.getAttribute('data-pow-difficulty'), 10)
+    ).then(nonce => {
+      powForm.elements['pow_nonce'].value = nonce;
+    });
+    powForm.addEventListener('submit', function(e){
+      if (!powForm.elements['pow_nonce'].value) {
+        e.preventDefault();
+        powForm.classList.add('busy-fetching');
+        solved.then(() => powForm.submit());
+      }
+    });
+  }
+
+  // Service worker, so the site can be installed as an app (and show up in
+  // the Android share menu). It doesn't do anything else, so failure is fine.
+  if ('serviceWorker' in navigator) {
+    navigator.serviceWorker.register('/sw.js').catch(() => {});
+  }
+
 }); // end whenever()
 })(); // that's a wrap
@@ -0,0 +1,12 @@
+// Eardogger's service worker. It doesn't cache anything (every page needs
+// fresh data anyway); it only exists because browsers want one before
+// they'll let you install the site as an app, which is what puts Eardogger
+// in the Android share menu. Served from /sw.js so its scope is the whole site.
+
+self.addEventListener('install', () => {
+  self.skipWaiting();
+});
+
+self.addEventListener('activate', (event) => {
+  event.waitUntil(self.clients.claim());
+});
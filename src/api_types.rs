@@ -0,0 +1,590 @@
+//! The JSON API's wire types. The server uses these directly in its route
+//! handlers, and the `client` feature uses them on the other end, so the
+//! two sides are stuck agreeing with each other. This module needs to stay
+//! light on dependencies (serde and time, basically), since anything that
+//! uses the client has to compile it.
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use time::{serde::iso8601, Duration, OffsetDateTime};
+
+/// A record struct for user web serial bookmarks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dogear {
+    pub id: i64,
+    pub user_id: i64,
+    pub prefix: String,
+    pub current: String,
+    pub display_name: Option<String>,
+    #[serde(with = "iso8601")]
+    pub updated: OffsetDateTime,
+    /// Paused dogears ignore updates until unpaused. (Defaults to false when
+    /// deserializing, for the sake of older servers that don't send it.)
+    #[serde(default)]
+    pub paused: bool,
+    /// Public dogears show up on the owner's public profile page, if they've
+    /// turned it on. (Same deal as `paused` for older servers.)
+    #[serde(default)]
+    pub public: bool,
+    /// Archived dogears are off the main list, but keep their spot; marking
+    /// one again brings it back. (Same deal as `paused` for older servers.)
+    #[serde(default)]
+    pub archived: bool,
+}
+
+impl Dogear {
+    pub fn freshness(&self, now: OffsetDateTime) -> Freshness {
+        Freshness::of(self.updated, now)
+    }
+}
+
+/// How long ago a dogear last moved, in rough buckets, so a long list is
+/// easy to scan. Counted back from now rather than by the calendar, since
+/// the server doesn't know where anybody's midnight is: "today" is the
+/// last 24 hours, "this week" the last 7 days, and "this month" the last 30.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Freshness {
+    Today,
+    ThisWeek,
+    ThisMonth,
+    Stale,
+}
+
+impl Freshness {
+    pub fn of(updated: OffsetDateTime, now: OffsetDateTime) -> Self {
+        let age = now - updated;
+        if age < Duration::days(1) {
+            Self::Today
+        } else if age < Duration::days(7) {
+            Self::ThisWeek
+        } else if age < Duration::days(30) {
+            Self::ThisMonth
+        } else {
+            Self::Stale
+        }
+    }
+
+    /// The same name it serializes as.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Today => "today",
+            Self::ThisWeek => "this_week",
+            Self::ThisMonth => "this_month",
+            Self::Stale => "stale",
+        }
+    }
+}
+
+/// Pagination details built from a ListMeta, useful when displaying
+/// page-turning controls in a template.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Pagination {
+    pub current_page: u32,
+    // The reason page_size is optional is so that you get cleaner URLs if
+    // you didn't override the default size.
+    pub page_size: Option<u32>,
+    pub prev_page: Option<u32>,
+    pub next_page: Option<u32>,
+    pub total_pages: u32,
+    pub total_count: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiMeta {
+    pub pagination: Pagination,
+    /// Each listed dogear's freshness, by ID, as of when the list went
+    /// out. (Empty from older servers that don't send it.)
+    #[serde(default)]
+    pub freshness: BTreeMap<i64, Freshness>,
+}
+
+/// Response body for `GET /api/v1/list`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiDogearsList {
+    pub data: Vec<Dogear>,
+    pub meta: ApiMeta,
+}
+
+impl ApiDogearsList {
+    pub fn new(dogears: Vec<Dogear>, pagination: Pagination) -> Self {
+        let now = OffsetDateTime::now_utc();
+        let freshness = dogears.iter().map(|d| (d.id, d.freshness(now))).collect();
+        Self {
+            data: dogears,
+            meta: ApiMeta {
+                pagination,
+                freshness,
+            },
+        }
+    }
+}
+
+/// Request body for `POST /api/v1/create`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiCreatePayload {
+    pub prefix: String,
+    pub current: String,
+    pub display_name: Option<String>,
+}
+
+/// Request body for `PATCH /api/v1/dogear/:id`. Leave out whatever you're
+/// not changing. The dogear's current URL has to match a new prefix.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ApiEditPayload {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// Blank clears it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+/// Request body for `POST /api/v1/update`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiUpdatePayload {
+    pub current: String,
+    /// A short note about where you left off ("stopped mid-scene"). Kept in
+    /// the dogear's history; max 200 characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// Response body for `GET /api/v1/suggest`: our best guess at a prefix
+/// for a new dogear on the given page. It's a guess, so let the user edit
+/// it before you create anything with it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiPrefixSuggestion {
+    pub prefix: String,
+}
+
+/// One spot a dogear was marked at, from `GET /api/v1/dogear/:id/history`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DogearHistoryEntry {
+    pub url: String,
+    pub note: Option<String>,
+    #[serde(with = "iso8601")]
+    pub created: OffsetDateTime,
+}
+
+/// Response body for `POST /api/v1/tokens/rotate`. This is the only time
+/// the new token's cleartext is ever available, so hang onto it; the old
+/// token stops working immediately.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiRotatedToken {
+    pub id: i64,
+    pub token: String,
+    pub scope: String,
+    pub comment: Option<String>,
+    /// Same as the old token's; rotating doesn't buy more time. (Defaults
+    /// to None for older servers that don't send it.)
+    #[serde(default, with = "iso8601::option")]
+    pub expires: Option<OffsetDateTime>,
+}
+
+/// Request body for `POST /api/v1/token`: trade a username and password
+/// for a new `write_dogears` token, so a browser extension (or anything
+/// else) can set itself up without anyone copying a token by hand. A blank
+/// or missing comment gets a dated default.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiTokenRequest {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+/// Response body for `POST /api/v1/token`. Same deal as a rotated token:
+/// this is the only time the cleartext is ever available.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiNewToken {
+    pub id: i64,
+    pub token: String,
+    pub scope: String,
+    pub comment: Option<String>,
+    /// If the site gives tokens a lifetime.
+    #[serde(default, with = "iso8601::option")]
+    pub expires: Option<OffsetDateTime>,
+}
+
+/// Response body for `POST /api/v1/dogear/:id/hook`. This is the only time
+/// the webhook URL is ever available, so hang onto it; any older URL for
+/// the same dogear stops working immediately.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiHook {
+    pub dogear_id: i64,
+    pub url: String,
+}
+
+/// Request body for `POST /hooks/:secret`: the new spot to move the hook's
+/// dogear to. Feed services tend to call an item's URL its `link`, so that
+/// works too.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiHookPayload {
+    #[serde(alias = "link")]
+    pub url: String,
+    pub note: Option<String>,
+}
+
+/// The version of [DogearEvent]'s shape. Adding a field doesn't change it;
+/// removing one, renaming one, or changing what one means does.
+pub const DOGEAR_EVENT_VERSION: u32 = 1;
+
+/// [DogearEvent]'s JSON Schema, which the server also serves at
+/// `/api/schemas/dogear-event.v1.json`.
+pub const DOGEAR_EVENT_SCHEMA: &str = include_str!("../schemas/dogear-event.v1.json");
+
+/// What happened to a dogear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DogearEventKind {
+    /// It's new, so there's no `before`.
+    Created,
+    /// It moved, got renamed, or came back off the archive. (Pausing and
+    /// publishing don't count.)
+    Updated,
+    /// It's gone, so there's no `after`.
+    Deleted,
+    /// It went onto the archive.
+    Archived,
+}
+
+impl DogearEventKind {
+    /// The same name it serializes as.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Updated => "updated",
+            Self::Deleted => "deleted",
+            Self::Archived => "archived",
+        }
+    }
+}
+
+/// One change to a dogear, in the stable shape that anything telling the
+/// outside world about dogear changes sends: the kind of change, when it
+/// happened, and the dogear right before and right after it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DogearEvent {
+    /// [DOGEAR_EVENT_VERSION], as of when this was sent.
+    pub version: u32,
+    pub event: DogearEventKind,
+    pub dogear_id: i64,
+    #[serde(with = "iso8601")]
+    pub occurred: OffsetDateTime,
+    /// None for `created`.
+    pub before: Option<Dogear>,
+    /// None for `deleted`.
+    pub after: Option<Dogear>,
+}
+
+impl DogearEvent {
+    /// The dogear as the change left it: `after`, or `before` if it's gone.
+    pub fn latest(&self) -> Option<&Dogear> {
+        self.after.as_ref().or(self.before.as_ref())
+    }
+}
+
+/// Request body for the POSTs an outgoing webhook gets whenever one of its
+/// owner's dogears is made, moves, gets renamed, gets archived or comes
+/// back, or gets deleted: a [DogearEvent], plus when it went out. `dogear`
+/// is the same as [DogearEvent::latest], for receivers written before
+/// events had versions. Every change gets its own POST, in order. The
+/// request's `X-Eardogger-Signature` header is `sha256=` plus the hex
+/// HMAC-SHA256 of the body, keyed with the webhook's secret.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiWebhookEvent {
+    #[serde(flatten)]
+    pub change: DogearEvent,
+    pub dogear: Dogear,
+    /// For `updated` events, how many chapters it moved (negative for
+    /// backward), if the before and after URLs are numbered the same way.
+    /// Same guesswork as the marked page's "+N chapters".
+    #[serde(default)]
+    pub chapter_delta: Option<i64>,
+    #[serde(with = "iso8601")]
+    pub sent: OffsetDateTime,
+}
+
+/// A dogear's feed, which the server checks every so often for new
+/// chapters. From `PUT /api/v1/dogear/:id/feed`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DogearFeed {
+    pub dogear_id: i64,
+    pub user_id: i64,
+    pub feed_url: String,
+    /// Whether a new chapter moves the dogear, instead of just flagging it.
+    /// It only moves if you were caught up, so it never skips past
+    /// something you haven't read.
+    pub auto_advance: bool,
+    /// The newest matching link in the feed, as of the last check.
+    pub latest_seen: Option<String>,
+    /// The next chapter after your spot, if a check turned one up that it
+    /// didn't move the dogear to.
+    pub new_chapter: Option<String>,
+    #[serde(with = "iso8601::option")]
+    pub new_chapter_found: Option<OffsetDateTime>,
+    #[serde(with = "iso8601::option")]
+    pub last_checked: Option<OffsetDateTime>,
+    /// Checks that have failed in a row. Each one pushes the next check
+    /// further out.
+    pub failures: i64,
+    pub last_error: Option<String>,
+}
+
+/// Request body for `PUT /api/v1/dogear/:id/feed`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiFeedPayload {
+    pub feed_url: String,
+    #[serde(default)]
+    pub auto_advance: bool,
+}
+
+/// Response body for `GET /api/v1/wait_for_update`, whether it found
+/// something right away, found something while waiting, or gave up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiWaitResult {
+    /// Whether any dogears moved after `since`. False means we ran out of
+    /// waiting time first; just ask again.
+    pub changed: bool,
+    /// What to pass as `since` next time: the newest `updated` in
+    /// `dogears`, or the `since` you sent if nothing changed. Timestamps
+    /// only go down to the second, so a move in the same second as the
+    /// newest one won't show up until something moves again.
+    #[serde(with = "iso8601")]
+    pub next_since: OffsetDateTime,
+    /// The dogears that moved, most recently updated first.
+    pub dogears: Vec<Dogear>,
+}
+
+/// One entry in the response body for `GET /api/v1/cadences`: a guess at
+/// how often a dogear's serial updates, going by how often it gets marked.
+/// Only dogears with enough history get one. Sorted soonest-due first, so
+/// the overdue ones lead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiCadence {
+    pub dogear: Dogear,
+    /// The typical time between reading sittings, in seconds.
+    pub interval_secs: i64,
+    /// The same, in words: "~5 days".
+    pub interval_text: String,
+    #[serde(with = "iso8601")]
+    pub last_marked: OffsetDateTime,
+    #[serde(with = "iso8601")]
+    pub expected_next: OffsetDateTime,
+    /// Whether it's been long enough that there's probably something new
+    /// (but not so long that you've probably moved on). Always false for
+    /// paused dogears.
+    pub probably_new: bool,
+}
+
+/// Request body for `POST /api/v1/migrate_out`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiMigrateOutPayload {
+    /// The base URL of the instance you're moving to.
+    pub target: String,
+    /// The one-time migration code from that instance's import page.
+    pub code: String,
+}
+
+/// One entry in the request body for `POST /api/v1/import`, which takes a
+/// JSON array of these. Dogears from an export fit too (their other fields
+/// get ignored), so an export's lines can go straight back in as an array.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiImportDogear {
+    pub prefix: String,
+    pub current: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub public: bool,
+    #[serde(default)]
+    pub archived: bool,
+}
+
+/// Response body for `POST /api/v1/import`. Everything that could be made
+/// got made; the rest are in `problems`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ApiBulkImportResult {
+    pub created: usize,
+    pub skipped: usize,
+    pub problems: Vec<ApiImportProblem>,
+}
+
+/// Why one entry of a bulk import got skipped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiImportProblem {
+    /// Its position in the request's array, counting from 0.
+    pub index: usize,
+    pub prefix: String,
+    pub error: String,
+}
+
+/// Response body for `POST /api/v1/migrate_in`: what happened to the
+/// dogears an instance pushed over.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ApiImportReport {
+    /// Dogears in the export.
+    pub entries: usize,
+    /// New dogears.
+    pub created: usize,
+    /// Dogears the account already had, which were left alone.
+    pub existing: usize,
+    /// Dogears that couldn't be made (weird URLs, mostly).
+    pub invalid: usize,
+}
+
+/// One line of the response body for `POST /api/v1/migrate_out`, which
+/// streams these as JSON lines while the move happens. The last line is
+/// always `done` or `failed`; if it's neither, the connection got cut off
+/// and you can't tell how it went. Tagged by `stage`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum ApiMigrationProgress {
+    /// Packing up your dogears.
+    Started { target: String },
+    /// All packed, and about to send.
+    Packaged { dogears: i64, bytes: usize },
+    /// The other instance took it, and here's what it made of it.
+    Done { report: ApiImportReport },
+    /// Something went wrong, and nothing more is coming.
+    Failed { error: String },
+}
+
+/// Request body for `POST /dev/users`, on instances with `dev_tools` on.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ApiDevUserPayload {
+    /// A username, or leave it out for a random one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Response body for `POST /dev/users`: a synthetic user with two dogears
+/// (example.com/comic and /serial), an email address, and everything you
+/// need to act as them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiDevUser {
+    pub id: i64,
+    pub name: String,
+    pub password: String,
+    pub write_token: String,
+    pub manage_token: String,
+    /// A login session (the `eardogger.sessid` cookie), and its CSRF token
+    /// for posting forms.
+    pub session_id: String,
+    pub csrf_token: String,
+}
+
+/// Request body for `POST /dev/dogears`: make a pile of synthetic dogears
+/// for an existing user. Responds with the new dogears.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiDevDogearsPayload {
+    pub user: String,
+    pub count: u32,
+    /// What to start each prefix with; each dogear gets `/<n>` on the end.
+    /// Leave it out for a random one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+}
+
+/// Request body for `POST /dev/sessions`: log an existing user in again.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiDevSessionPayload {
+    pub user: String,
+}
+
+/// Response body for `POST /dev/sessions`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiDevSession {
+    pub session_id: String,
+    pub csrf_token: String,
+}
+
+/// Response body for `GET /.well-known/eardogger.json`: what an instance
+/// runs and what it can do, for clients that want to know before they ask
+/// anyone to log in. Fields get added over time, so older clients should
+/// ignore ones they don't know.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstanceMetadata {
+    /// Always "eardogger-rs", for now.
+    pub software: String,
+    /// The server's crate version.
+    pub version: String,
+    /// The commit the server was built from, if known.
+    pub commit: String,
+    /// Full URL of the JSON API, ending in a slash.
+    pub api_base: String,
+    /// Whether anyone can make an account: "open" or "closed".
+    pub signups: String,
+    /// Token scopes the API understands.
+    pub token_scopes: Vec<String>,
+    /// Whether the API takes `Authorization: Basic` as well as tokens.
+    pub api_basic_auth: bool,
+    /// Whether users can turn on a public profile page.
+    pub public_profiles: bool,
+    /// Whether `POST /api/v1/token` trades a username and password for a
+    /// write token. (Defaults to false for older servers that don't.)
+    #[serde(default)]
+    pub token_exchange: bool,
+    /// What new passwords have to clear, so sign-up UIs can say so up front.
+    #[serde(default)]
+    pub password_policy: PasswordPolicyInfo,
+}
+
+/// The password rules an instance enforces. The banned list stays private.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PasswordPolicyInfo {
+    /// Fewest characters allowed.
+    pub min_length: usize,
+    /// Lowest zxcvbn strength score (0-4) allowed.
+    pub min_strength: u8,
+    /// Whether passwords from known data breaches get turned away.
+    #[serde(default)]
+    pub check_breached: bool,
+}
+
+/// Response body for `GET /api/changelog`: what's changed in the API lately,
+/// and which endpoints are on their way out. Dates are `YYYY-MM-DD`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiChangelog {
+    /// The API version current clients should use, like "v1".
+    pub version: String,
+    /// Newest first.
+    pub changes: Vec<ApiChangelogEntry>,
+    pub deprecations: Vec<ApiDeprecation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiChangelogEntry {
+    pub date: String,
+    pub changes: Vec<String>,
+}
+
+/// One deprecated endpoint. Its responses carry the same dates in their
+/// `Deprecation` and `Sunset` headers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiDeprecation {
+    pub method: String,
+    /// The route, with `:params` for the variable parts.
+    pub path: String,
+    /// When it stopped being recommended.
+    pub deprecated: String,
+    /// When it stops working, if that's been decided.
+    pub sunset: Option<String>,
+    /// The route to use instead, if there is one.
+    pub successor: Option<String>,
+    pub note: String,
+}
+
+// A dumb Serialize wrapper for `{ "error":"blah blah" }` so I don't have to
+// use the dynamic json!() object macro.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RawJsonError {
+    pub error: Cow<'static, str>,
+    /// The ID from the response's X-Request-Id header, for bug reports.
+    /// Older servers don't send it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
@@ -0,0 +1,244 @@
+//! Operator routes under `/admin`. These aren't for any user account; they
+//! take the `site_rules.admin_token` from the config file as a bearer
+//! token, and they don't exist at all unless that's set. (The rate limits
+//! report and feature flags aren't about site rules, but it's the one admin
+//! token we've got.) For admin user accounts, see `admin_users`.
+
+use super::state::DogState;
+use super::web_result::{ApiError, ApiResult};
+use crate::db::FlagOverride;
+use crate::site_rules::{self, SiteRulesBundle, SiteRulesReport};
+use crate::util::{sha256sum, FailureCountsSnapshot, LimiterSnapshot, RateLimitAllowlist};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+use time::{serde::iso8601, OffsetDateTime};
+use tracing::{info, warn};
+
+/// How many keys per limiter the rate limits report lists.
+const RATE_LIMITS_TOP: usize = 50;
+
+/// Check for the admin token. 404 if the config doesn't have one, so the
+/// routes look like they aren't there.
+fn check_admin_token(state: &DogState, headers: &HeaderMap) -> ApiResult<()> {
+    let Some(site_rules) = &state.config.site_rules else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "Not found.".to_string(),
+        ));
+    };
+    let sent = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    // Compare hashes, so how long the check takes says nothing about how
+    // much of the token was right.
+    match sent {
+        Some(token) if sha256sum(token.trim()) == sha256sum(&site_rules.admin_token) => Ok(()),
+        _ => {
+            warn!(target: "audit", "admin: bad or missing admin token");
+            Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "This needs the admin token from the config file.".to_string(),
+            ))
+        }
+    }
+}
+
+/// `GET /admin/site_rules`: every site rule, as a bundle signed by this
+/// instance.
+#[tracing::instrument(skip_all)]
+pub async fn admin_export_site_rules(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+) -> ApiResult<Json<SiteRulesBundle>> {
+    check_admin_token(&state, &headers)?;
+    let key = site_rules::signing_key(&state.cookie_key);
+    Ok(Json(site_rules::export(&state.db, &key).await?))
+}
+
+/// `POST /admin/site_rules`: check a bundle's signature and load its rules.
+#[tracing::instrument(skip_all)]
+pub async fn admin_import_site_rules(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+    Json(bundle): Json<SiteRulesBundle>,
+) -> ApiResult<Json<SiteRulesReport>> {
+    check_admin_token(&state, &headers)?;
+    let mut trusted = vec![site_rules::signing_key(&state.cookie_key).verifying_key()];
+    if let Some(config) = &state.config.site_rules {
+        trusted.extend(
+            config
+                .trusted_keys
+                .iter()
+                .filter_map(|k| site_rules::parse_public_key(k)),
+        );
+    }
+    let report = site_rules::import(&state.db, &bundle, &trusted).await?;
+    info!(target: "audit", signer = %bundle.public_key, changed = report.changed, "admin: imported site rules");
+    Ok(Json(report))
+}
+
+/// What `GET /admin/rate_limits` says.
+#[derive(Serialize, Debug)]
+pub struct RateLimitsReport {
+    pub allowlist: RateLimitAllowlist,
+    /// Failed API Basic auth attempts, keyed by username.
+    pub basic_auth: LimiterSnapshot,
+    /// Quickmark requests, keyed by the sha256 of the token.
+    pub quickmark: LimiterSnapshot,
+    /// Inbound webhook calls, keyed by the sha256 of the hook's secret.
+    pub hooks: LimiterSnapshot,
+    /// Tokens from the token exchange, keyed by username.
+    pub token_exchange: LimiterSnapshot,
+    /// Failed Bearer token logins, keyed by client IP.
+    pub bad_tokens: LimiterSnapshot,
+    /// Failed Bearer token logins since this process started: how many,
+    /// how many had no IP to pin them on, how many times a client hit the
+    /// limit (and set off an alert), and how many requests got turned away
+    /// for it.
+    pub bad_token_counts: FailureCountsSnapshot,
+}
+
+/// `GET /admin/rate_limits`: who's been throttled lately (and who's
+/// coming up on it), for sorting out "I'm locked out" reports. Only sees
+/// this process's limiters, which under fcgi might not be the one they hit.
+#[tracing::instrument(skip_all)]
+pub async fn admin_rate_limits(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+) -> ApiResult<Json<RateLimitsReport>> {
+    check_admin_token(&state, &headers)?;
+    let report = RateLimitsReport {
+        allowlist: state.config.rate_limit_allowlist.clone(),
+        basic_auth: state.basic_auth_limiter.snapshot(RATE_LIMITS_TOP),
+        quickmark: state.quickmark_limiter.snapshot(RATE_LIMITS_TOP),
+        hooks: state.hook_limiter.snapshot(RATE_LIMITS_TOP),
+        token_exchange: state.token_exchange_limiter.snapshot(RATE_LIMITS_TOP),
+        bad_tokens: state.bad_token_limiter.snapshot(RATE_LIMITS_TOP),
+        bad_token_counts: state.bad_token_counts.snapshot(),
+    };
+    Ok(Json(report))
+}
+
+/// One feature flag, as `/admin/features` reports it.
+#[derive(Serialize, Debug)]
+pub struct FlagStatus {
+    pub name: String,
+    /// The rollout percentage from the config file.
+    pub configured: u8,
+    /// The live override, if someone's set one.
+    pub overridden: Option<u8>,
+    #[serde(with = "iso8601::option")]
+    pub overridden_at: Option<OffsetDateTime>,
+    /// The rollout actually in effect.
+    pub rollout: u8,
+}
+
+impl FlagStatus {
+    fn new(name: &str, configured: u8, over: Option<&FlagOverride>) -> Self {
+        let overridden = over.map(|o| o.rollout.clamp(0, 100) as u8);
+        Self {
+            name: name.to_string(),
+            configured,
+            overridden,
+            overridden_at: over.map(|o| o.updated),
+            rollout: overridden.unwrap_or(configured),
+        }
+    }
+}
+
+/// Request body for `PUT /admin/features/:name`.
+#[derive(Deserialize, Debug)]
+pub struct FlagPayload {
+    /// Percent of users who get the feature, 0 to 100.
+    pub rollout: u8,
+}
+
+/// The config's rollout for a flag, or a 404 if the config doesn't have
+/// it. New flags go in the config file first.
+fn configured_rollout(state: &DogState, name: &str) -> ApiResult<u8> {
+    state.live.features().get(name).copied().ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!(
+                "There's no feature flag named {:?}; add it to the config file's [features] first.",
+                name
+            ),
+        )
+    })
+}
+
+/// Look up one flag's status, after checking it exists.
+async fn flag_status(state: &DogState, name: &str) -> ApiResult<FlagStatus> {
+    let configured = configured_rollout(state, name)?;
+    let overrides = state.db.flag_overrides().list().await?;
+    let over = overrides.iter().find(|o| o.name == name);
+    Ok(FlagStatus::new(name, configured, over))
+}
+
+/// `GET /admin/features`: every feature flag, and where its rollout stands.
+#[tracing::instrument(skip_all)]
+pub async fn admin_features(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<FlagStatus>>> {
+    check_admin_token(&state, &headers)?;
+    let overrides = state.db.flag_overrides().list().await?;
+    let flags = state
+        .live
+        .features()
+        .iter()
+        .map(|(name, configured)| {
+            let over = overrides.iter().find(|o| &o.name == name);
+            FlagStatus::new(name, *configured, over)
+        })
+        .collect();
+    Ok(Json(flags))
+}
+
+/// `PUT /admin/features/:name`: change a flag's rollout, right away and
+/// until someone clears it. Logged-in users pick it up on their next page.
+#[tracing::instrument(skip_all)]
+pub async fn admin_set_feature(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(payload): Json<FlagPayload>,
+) -> ApiResult<Json<FlagStatus>> {
+    check_admin_token(&state, &headers)?;
+    configured_rollout(&state, &name)?;
+    if payload.rollout > 100 {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Rollouts go from 0 to 100 percent.".to_string(),
+        ));
+    }
+    state
+        .db
+        .flag_overrides()
+        .set(&name, payload.rollout)
+        .await?;
+    info!(target: "audit", flag = %name, rollout = payload.rollout, "admin: set feature flag rollout");
+    Ok(Json(flag_status(&state, &name).await?))
+}
+
+/// `DELETE /admin/features/:name`: put a flag back to the config file's
+/// rollout.
+#[tracing::instrument(skip_all)]
+pub async fn admin_clear_feature(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> ApiResult<Json<FlagStatus>> {
+    check_admin_token(&state, &headers)?;
+    configured_rollout(&state, &name)?;
+    if state.db.flag_overrides().clear(&name).await? {
+        info!(target: "audit", flag = %name, "admin: cleared feature flag override");
+    }
+    Ok(Json(flag_status(&state, &name).await?))
+}
@@ -0,0 +1,176 @@
+//! The /admin/users pages, where site admins (see `db admin`) can look over
+//! every account and deal with the troublesome ones. Unlike the operator
+//! routes in `admin`, these belong to a logged-in user, so they take the
+//! AuthAdmin extractor instead of the config file's admin token. There's
+//! an HTML page with a form per button, and a JSON flavor of each.
+
+use super::authentication::{accepts_json, AuthAdmin};
+use super::routes::{fragment_response, later_page, paged_url, PaginationQuery};
+use super::state::DogState;
+use super::templates::*;
+use super::web_result::{ApiError, ApiResult, WebError, WebResult};
+use crate::db::{User, UserFlags};
+use crate::util::IntoHandlerError;
+
+use axum::{
+    extract::{Form, Path, Query, State},
+    http::StatusCode,
+    response::{Html, Json, Redirect, Response},
+};
+use http::HeaderMap;
+use minijinja::context;
+use serde::Deserialize;
+use tracing::info;
+
+/// `GET /admin/users`: everyone, with their dogear and session counts and
+/// their flags. JSON if the Accept header asks for it.
+#[tracing::instrument(skip_all)]
+pub async fn admin_users(
+    State(state): State<DogState>,
+    AuthAdmin(auth): AuthAdmin,
+    Query(query): Query<PaginationQuery>,
+    headers: HeaderMap,
+) -> WebResult<Response> {
+    let size = query.size(auth.prefs.default_page_size());
+    let (users, meta) = state.db.users().list_for_admin(query.page(), size).await?;
+    let users_list = AdminUsersList {
+        users: &users,
+        pagination: meta.to_pagination(auth.prefs.default_page_size()),
+        current_user_id: auth.user.id,
+    };
+    if accepts_json(&headers) {
+        let pagination_links =
+            PaginationLinks::new(&users_list.pagination, "/admin/users", "/admin/users", "");
+        return Ok(fragment_response(Json(FragmentJson {
+            list: users_list,
+            pagination_links,
+        })));
+    }
+    let page = later_page(&query);
+    let common = Common {
+        page,
+        breadcrumbs: ACCOUNT_CRUMBS,
+        canonical_url: Some(paged_url(
+            &state.config.public_url,
+            "/admin/users",
+            None,
+            page,
+        )),
+        ..auth.common_args("Users")
+    };
+    let ctx = context! {common, users_list};
+    Ok(fragment_response(Html(
+        state.render_view("admin_users.html.j2", ctx)?,
+    )))
+}
+
+/// What an admin can do to an account. Anything left out stays as it was.
+#[derive(Deserialize, Debug, Default)]
+pub struct AdminUserPatch {
+    pub disabled: Option<bool>,
+    /// Only `true` does anything; the user un-forces it by picking a new
+    /// password.
+    pub force_password_reset: Option<bool>,
+}
+
+/// Make the changes, and return the account's flags after. Errors are a
+/// status and message, for the caller to wrap in whichever error format.
+async fn apply_patch(
+    state: &DogState,
+    admin: &User,
+    id: i64,
+    patch: &AdminUserPatch,
+) -> Result<UserFlags, (StatusCode, String)> {
+    if patch.disabled == Some(true) && id == admin.id {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "You can't disable your own account.".to_string(),
+        ));
+    }
+    let not_found = || (StatusCode::NOT_FOUND, "There's no such user.".to_string());
+    let users = state.db.users();
+    if let Some(disabled) = patch.disabled {
+        users
+            .set_disabled(id, disabled)
+            .await
+            .map_err(|e| e.status_and_message())?
+            .ok_or_else(not_found)?;
+        info!(target: "audit", admin = %admin.username, user_id = id, disabled, "admin: set account disabled");
+    }
+    if patch.force_password_reset == Some(true) {
+        users
+            .force_password_reset(id)
+            .await
+            .map_err(|e| e.status_and_message())?
+            .ok_or_else(not_found)?;
+        info!(target: "audit", admin = %admin.username, user_id = id, "admin: forced password reset");
+    }
+    users
+        .flags(id)
+        .await
+        .map_err(|e| e.status_and_message())?
+        .ok_or_else(not_found)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminActionParams {
+    csrf_token: String,
+}
+
+/// `POST /admin/users/:id/:action`, for the buttons on the users page:
+/// `disable`, `enable`, or `force_password_reset`.
+#[tracing::instrument(skip_all)]
+pub async fn post_admin_user_action(
+    State(state): State<DogState>,
+    AuthAdmin(auth): AuthAdmin,
+    Path((id, action)): Path<(i64, String)>,
+    Form(params): Form<AdminActionParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The form you used was stale or mangled. Go back,
+                refresh the page, and try again."#
+                .to_string(),
+        ));
+    }
+    let patch = match action.as_str() {
+        "disable" => AdminUserPatch {
+            disabled: Some(true),
+            ..Default::default()
+        },
+        "enable" => AdminUserPatch {
+            disabled: Some(false),
+            ..Default::default()
+        },
+        "force_password_reset" => AdminUserPatch {
+            force_password_reset: Some(true),
+            ..Default::default()
+        },
+        _ => {
+            return Err(WebError::new(
+                StatusCode::NOT_FOUND,
+                "There's no such admin action.".to_string(),
+            ))
+        }
+    };
+    apply_patch(&state, &auth.user, id, &patch)
+        .await
+        .map_err(|(status, message)| WebError::new(status, message))?;
+    Ok(Redirect::to("/admin/users"))
+}
+
+/// `PATCH /admin/users/:id`, the JSON version of the buttons. Returns the
+/// account's flags after. Not CSRF-vulnerable, since it takes a JSON body.
+#[tracing::instrument(skip_all)]
+pub async fn patch_admin_user(
+    State(state): State<DogState>,
+    AuthAdmin(auth): AuthAdmin,
+    Path(id): Path<i64>,
+    Json(patch): Json<AdminUserPatch>,
+) -> ApiResult<Json<UserFlags>> {
+    let flags = apply_patch(&state, &auth.user, id, &patch)
+        .await
+        .map_err(|(status, message)| ApiError::new(status, message))?;
+    Ok(Json(flags))
+}
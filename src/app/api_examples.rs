@@ -0,0 +1,300 @@
+//! Copy-and-paste examples for the API, so someone wiring up a script can
+//! get going from the account page instead of reading routes.rs. Every
+//! endpoint worth showing gets an entry in [API_ROUTES], with which token
+//! scopes can call it and what a typical request looks like; the account
+//! page shows each token the examples its scope can actually use, as curl
+//! and `fetch` commands pointed at this instance. Tokens are only ever
+//! visible once, so the examples use a placeholder where the token goes.
+
+use crate::db::TokenScope;
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use url::Url;
+
+use super::routes::own_url;
+
+/// What the examples say instead of a real token.
+pub const TOKEN_PLACEHOLDER: &str = "YOUR_TOKEN";
+
+/// Where a route expects the token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenIn {
+    /// An `Authorization: Bearer ...` header, like nearly everything.
+    Header,
+    /// A `token` query parameter, which only quickmark tokens get away with.
+    Query,
+}
+
+/// One API endpoint, as far as the examples care.
+#[derive(Debug)]
+pub struct ApiRoute {
+    /// The HTTP method, like "GET".
+    pub method: &'static str,
+    /// The route as the router spells it, `:params` and all.
+    pub path: &'static str,
+    /// Anything to tack onto the example URL, without the `?`.
+    pub query: Option<&'static str>,
+    /// A JSON request body for the example, if it takes one.
+    pub body: Option<&'static str>,
+    /// The token scopes that can call it.
+    pub scopes: &'static [TokenScope],
+    pub token_in: TokenIn,
+    /// What it does, in a few words.
+    pub summary: &'static str,
+}
+
+const MARKING: &[TokenScope] = &[TokenScope::WriteDogears, TokenScope::ManageDogears];
+const MANAGING: &[TokenScope] = &[TokenScope::ManageDogears];
+
+/// The endpoints the examples cover, in the order they're shown. Not the
+/// whole API (the FAQ has that); just the ones people actually script.
+pub static API_ROUTES: &[ApiRoute] = &[
+    ApiRoute {
+        method: "POST",
+        path: "/api/v1/update",
+        query: None,
+        body: Some(r#"{"current": "https://example.com/serial/2"}"#),
+        scopes: MARKING,
+        token_in: TokenIn::Header,
+        summary: "Mark your spot",
+    },
+    ApiRoute {
+        method: "PUT",
+        path: "/api/v1/dogear",
+        query: Some("current=https%3A%2F%2Fexample.com%2Fserial%2F2"),
+        body: None,
+        scopes: MARKING,
+        token_in: TokenIn::Header,
+        summary: "Mark your spot, without a JSON body",
+    },
+    ApiRoute {
+        method: "POST",
+        path: "/api/v1/create",
+        query: None,
+        body: Some(
+            r#"{"prefix": "example.com/serial", "current": "https://example.com/serial/1"}"#,
+        ),
+        scopes: MARKING,
+        token_in: TokenIn::Header,
+        summary: "Make a new dogear",
+    },
+    ApiRoute {
+        method: "GET",
+        path: "/api/v1/suggest",
+        query: Some("url=https%3A%2F%2Fexample.com%2Fserial%2F1"),
+        body: None,
+        scopes: MARKING,
+        token_in: TokenIn::Header,
+        summary: "Guess a prefix for a new dogear",
+    },
+    ApiRoute {
+        method: "GET",
+        path: "/api/v1/list",
+        query: None,
+        body: None,
+        scopes: MANAGING,
+        token_in: TokenIn::Header,
+        summary: "List your dogears",
+    },
+    ApiRoute {
+        method: "GET",
+        path: "/api/v1/dogear/:id",
+        query: None,
+        body: None,
+        scopes: MANAGING,
+        token_in: TokenIn::Header,
+        summary: "Get one dogear",
+    },
+    ApiRoute {
+        method: "POST",
+        path: "/api/v1/dogear/:id/pause",
+        query: None,
+        body: None,
+        scopes: MANAGING,
+        token_in: TokenIn::Header,
+        summary: "Pause a dogear",
+    },
+    ApiRoute {
+        method: "DELETE",
+        path: "/api/v1/dogear/:id",
+        query: None,
+        body: None,
+        scopes: MANAGING,
+        token_in: TokenIn::Header,
+        summary: "Delete a dogear",
+    },
+    ApiRoute {
+        method: "POST",
+        path: "/api/v1/tokens/rotate",
+        query: None,
+        body: None,
+        scopes: MARKING,
+        token_in: TokenIn::Header,
+        summary: "Swap this token for a fresh one",
+    },
+    ApiRoute {
+        method: "GET",
+        path: "/api/v1/quickmark",
+        query: Some("url=https%3A%2F%2Fexample.com%2Fserial%2F2"),
+        body: None,
+        scopes: &[TokenScope::Quickmark],
+        token_in: TokenIn::Query,
+        summary: "Mark your spot with a plain link",
+    },
+];
+
+/// One ready-to-copy example, for the templates.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ApiExample {
+    pub method: &'static str,
+    pub path: &'static str,
+    pub summary: &'static str,
+    pub curl: String,
+    pub fetch: String,
+}
+
+impl ApiRoute {
+    /// The example URL: the path on this instance (with a made-up id for
+    /// any `:id`), plus the query and maybe the token.
+    fn url(&self, public_url: &Url) -> String {
+        let mut url = own_url(public_url, &self.path.replace(":id", "1"));
+        let mut query = Vec::new();
+        if self.token_in == TokenIn::Query {
+            query.push(format!("token={}", TOKEN_PLACEHOLDER));
+        }
+        query.extend(self.query.map(String::from));
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query.join("&"));
+        }
+        url
+    }
+
+    pub fn example(&self, public_url: &Url) -> ApiExample {
+        let url = self.url(public_url);
+        let auth = (self.token_in == TokenIn::Header)
+            .then(|| format!("Authorization: Bearer {}", TOKEN_PLACEHOLDER));
+
+        let mut curl = String::from("curl");
+        if self.method != "GET" {
+            curl.push_str(&format!(" -X {}", self.method));
+        }
+        if let Some(auth) = &auth {
+            curl.push_str(&format!(" \\\n  -H '{}'", auth));
+        }
+        if let Some(body) = self.body {
+            curl.push_str(" \\\n  -H 'Content-Type: application/json'");
+            curl.push_str(&format!(" \\\n  -d '{}'", body));
+        }
+        curl.push_str(&format!(" \\\n  '{}'", url));
+
+        let mut options = vec![format!("  method: '{}',", self.method)];
+        let mut headers = Vec::new();
+        if auth.is_some() {
+            headers.push(format!(
+                "    'Authorization': 'Bearer {}',",
+                TOKEN_PLACEHOLDER
+            ));
+        }
+        if self.body.is_some() {
+            headers.push("    'Content-Type': 'application/json',".to_string());
+        }
+        if !headers.is_empty() {
+            options.push(format!("  headers: {{\n{}\n  }},", headers.join("\n")));
+        }
+        if let Some(body) = self.body {
+            options.push(format!("  body: JSON.stringify({}),", body));
+        }
+        let fetch = format!(
+            "fetch('{}', {{\n{}\n}}).then((res) => res.json());",
+            url,
+            options.join("\n")
+        );
+
+        ApiExample {
+            method: self.method,
+            path: self.path,
+            summary: self.summary,
+            curl,
+            fetch,
+        }
+    }
+}
+
+/// Every scope's examples, keyed by the scope's name, so a template can
+/// look up `api_examples[token.scope]`. Scopes with nothing to show (like
+/// kosync, which only KOReader ever uses) don't get a key.
+pub fn api_examples(public_url: &Url) -> BTreeMap<&'static str, Vec<ApiExample>> {
+    let mut examples: BTreeMap<&'static str, Vec<ApiExample>> = BTreeMap::new();
+    for route in API_ROUTES {
+        let example = route.example(public_url);
+        for &scope in route.scopes {
+            examples
+                .entry(scope.into())
+                .or_default()
+                .push(example.clone());
+        }
+    }
+    examples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn examples() {
+        let public_url = Url::parse("https://eardogger.example").unwrap();
+        let examples = api_examples(&public_url);
+        assert!(!examples.contains_key("kosync"));
+
+        let update = &examples["write_dogears"][0];
+        assert_eq!(update.path, "/api/v1/update");
+        assert_eq!(
+            update.curl,
+            "curl -X POST \\\n  -H 'Authorization: Bearer YOUR_TOKEN' \\\n  -H 'Content-Type: application/json' \\\n  -d '{\"current\": \"https://example.com/serial/2\"}' \\\n  'https://eardogger.example/api/v1/update'"
+        );
+        assert!(update
+            .fetch
+            .starts_with("fetch('https://eardogger.example/api/v1/update', {\n  method: 'POST',"));
+        assert!(update
+            .fetch
+            .contains("body: JSON.stringify({\"current\": \"https://example.com/serial/2\"}),"));
+
+        // The no-JSON flavor is just a URL.
+        let put = &examples["write_dogears"][1];
+        assert_eq!(
+            put.curl,
+            "curl -X PUT \\\n  -H 'Authorization: Bearer YOUR_TOKEN' \\\n  'https://eardogger.example/api/v1/dogear?current=https%3A%2F%2Fexample.com%2Fserial%2F2'"
+        );
+
+        // Marking tokens don't get told about things they can't do.
+        assert!(examples["write_dogears"]
+            .iter()
+            .all(|e| e.path != "/api/v1/list"));
+        let list = examples["manage_dogears"]
+            .iter()
+            .find(|e| e.path == "/api/v1/list")
+            .unwrap();
+        assert_eq!(
+            list.curl,
+            "curl \\\n  -H 'Authorization: Bearer YOUR_TOKEN' \\\n  'https://eardogger.example/api/v1/list'"
+        );
+        let delete = examples["manage_dogears"]
+            .iter()
+            .find(|e| e.method == "DELETE")
+            .unwrap();
+        assert!(delete
+            .curl
+            .ends_with("'https://eardogger.example/api/v1/dogear/1'"));
+
+        // Quickmarks carry their token in the URL.
+        let quickmark = &examples["quickmark"][0];
+        assert_eq!(
+            quickmark.curl,
+            "curl \\\n  'https://eardogger.example/api/v1/quickmark?token=YOUR_TOKEN&url=https%3A%2F%2Fexample.com%2Fserial%2F2'"
+        );
+        assert!(!quickmark.fetch.contains("headers"));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::util::url_encoding::encode_uri_component;
+
 use super::app_tests::*;
 
 #[tokio::test]
@@ -98,6 +100,190 @@ async fn api_list_test() {
         assert_eq!(list.data.len(), 1);
         assert!(list.data[0].current.contains("example.com"));
     }
+    // 8: Filtered: only the matches, and the counts agree.
+    {
+        let req = new_req("GET", "/api/v1/list?q=SERIAL")
+            .json()
+            .session(&user.session_id)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body_bytes = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let list: ApiDogearsList = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(list.meta.pagination.total_count, 1);
+        assert_eq!(list.data.len(), 1);
+        assert!(list.data[0].current.contains("/serial/"));
+
+        // Blank is no filter; overlong is a 400.
+        let req = new_req("GET", "/api/v1/list?q=+")
+            .json()
+            .session(&user.session_id)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        let body_bytes = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let list: ApiDogearsList = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(list.meta.pagination.total_count, 2);
+
+        let uri = format!("/api/v1/list?q={}", "x".repeat(3000));
+        let req = new_req("GET", uri).json().session(&user.session_id).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let _ = api_error_body(resp).await;
+    }
+    // 9: ETags: same list, same tag, and If-None-Match gets an empty 304
+    // until something changes.
+    {
+        let list_req = |etag: Option<&str>| {
+            let mut req = new_req("GET", "/api/v1/list")
+                .json()
+                .token(&user.manage_token);
+            if let Some(etag) = etag {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+            req.empty()
+        };
+        let resp = do_req(&mut app, list_req(None)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = resp.headers()[header::ETAG].to_str().unwrap().to_string();
+        assert!(resp.headers()[header::CACHE_CONTROL]
+            .to_str()
+            .unwrap()
+            .contains("private"));
+
+        let resp = do_req(&mut app, list_req(Some(&etag))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[header::ETAG], etag.as_str());
+        assert!(body_bytes(resp).await.is_empty());
+
+        // Other pages and searches are other lists.
+        let req = new_req("GET", "/api/v1/list?size=1")
+            .json()
+            .token(&user.manage_token)
+            .header(header::IF_NONE_MATCH, &etag)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Pausing doesn't touch `updated`, but it still changes the list.
+        let (list, _) = state.db.dogears().list(user.id, 1, 50).await.unwrap();
+        state
+            .db
+            .dogears()
+            .set_paused(list[0].id, user.id, true)
+            .await
+            .unwrap();
+        let resp = do_req(&mut app, list_req(Some(&etag))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_ne!(resp.headers()[header::ETAG], etag.as_str());
+    }
+    // 10: The default page size preference applies when there's no ?size,
+    // for tokens as well as sessions.
+    {
+        state
+            .db
+            .prefs()
+            .set_page_size(user.id, Some(1))
+            .await
+            .unwrap();
+        for req in [
+            new_req("GET", "/api/v1/list")
+                .json()
+                .token(&user.manage_token)
+                .empty(),
+            new_req("GET", "/api/v1/list")
+                .json()
+                .session(&user.session_id)
+                .empty(),
+        ] {
+            let resp = do_req(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body_bytes = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            let list: ApiDogearsList = serde_json::from_slice(&body_bytes).unwrap();
+            assert_eq!(list.meta.pagination.total_count, 2);
+            assert_eq!(list.meta.pagination.total_pages, 2);
+            assert_eq!(list.data.len(), 1);
+        }
+
+        // An explicit size still wins.
+        let req = new_req("GET", "/api/v1/list?size=50")
+            .json()
+            .token(&user.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        let body_bytes = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let list: ApiDogearsList = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(list.data.len(), 2);
+    }
+}
+
+#[tokio::test]
+async fn api_dogear_test() {
+    use crate::db::Dogear;
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+
+    let user = state.db.test_user("whoever").await.unwrap();
+    let other = state.db.test_user("someone_else").await.unwrap();
+    let user_id = state
+        .db
+        .users()
+        .by_name(&user.name)
+        .await
+        .unwrap()
+        .unwrap()
+        .id;
+    let (dogears, _) = state.db.dogears().list(user_id, 1, 50).await.unwrap();
+    let uri = format!("/api/v1/dogear/{}", dogears[0].id);
+
+    // 1. 401 when logged out
+    {
+        assert_api_auth_required(&mut app, "GET", &uri, None).await;
+    }
+    // 2. Requires manage scope
+    {
+        let req = new_req("GET", &uri).json().token(&user.write_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_api_insufficient_permissions(resp).await;
+    }
+    // 3. Hit
+    {
+        let req = new_req("GET", &uri)
+            .json()
+            .token(&user.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let d: Dogear = serde_json::from_slice(&body).unwrap();
+        assert_eq!(d.id, dogears[0].id);
+        assert_eq!(d.current, dogears[0].current);
+    }
+    // 4. Someone else's dogear is the same as no dogear
+    {
+        let req = new_req("GET", &uri)
+            .json()
+            .token(&other.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let _ = api_error_body(resp).await.expect("need error body");
+    }
+    // 5. So's one that's gone, or never was
+    {
+        state
+            .db
+            .dogears()
+            .destroy(dogears[0].id, user_id)
+            .await
+            .unwrap();
+        for uri in [uri.as_str(), "/api/v1/dogear/999999"] {
+            let req = new_req("GET", uri).json().token(&user.manage_token).empty();
+            let resp = do_req(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", uri);
+            let _ = api_error_body(resp).await.expect("need error body");
+        }
+    }
 }
 
 #[tokio::test]
@@ -172,226 +358,3095 @@ async fn api_delete_test() {
 }
 
 #[tokio::test]
-async fn api_create_test() {
+async fn api_pause_test() {
     use crate::db::Dogear;
 
     let state = test_state().await;
     let mut app = eardogger_app(state.clone());
 
     let user = state.db.test_user("whoever").await.unwrap();
-    let uri = "/api/v1/create";
+    let user_id = state
+        .db
+        .users()
+        .by_name(&user.name)
+        .await
+        .unwrap()
+        .unwrap()
+        .id;
+    let (dogears, _) = state.db.dogears().list(user_id, 1, 50).await.unwrap();
+    let comic = dogears
+        .iter()
+        .find(|d| d.prefix == "example.com/comic")
+        .unwrap();
+    let pause = format!("/api/v1/dogear/{}/pause", comic.id);
+    let unpause = format!("/api/v1/dogear/{}/unpause", comic.id);
 
-    // 1. No cors preflight approval.
+    // 401 when logged out
     {
-        let body = r#"{
-            "prefix": "example.com/cors",
-            "current": "http://example.com/cors/0"
-        }"#;
-        let req = new_req("OPTIONS", uri)
+        assert_api_auth_required(&mut app, "POST", &pause, None).await;
+    }
+    // Tokens: requires manage scope
+    {
+        let req = new_req("POST", &pause)
             .json()
-            .header(header::ORIGIN, "https://example.com")
-            .body(body.into())
-            .unwrap();
+            .token(&user.write_token)
+            .empty();
         let resp = do_req(&mut app, req).await;
-        assert_no_cors(&resp);
+        assert_api_insufficient_permissions(resp).await;
     }
-    // 2. 401 when not authenticated
+    // 404 on whiff
     {
-        let body = r#"{
-            "prefix": "example.com/noone",
-            "current": "http://example.com/noone/0"
-        }"#;
-        assert_api_auth_required(&mut app, "POST", uri, Some(body.into())).await;
+        let req = new_req("POST", "/api/v1/dogear/20566/pause")
+            .json()
+            .token(&user.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
-    // 3. Happy path: 201 and a dogear
-    // (changed from ed.v1, which returned a 1-item array)
+    // Pause, and get the dogear back
     {
-        // reusable test case; returns a dogear for further inspection. if u even care.
-        let happy_path = |auth: Auth, body: &'static str| {
-            let req = new_req("POST", uri)
-                .json()
-                .auth(auth)
-                .body(body.into())
-                .unwrap();
-            // async closures are unstable... and also I can't retain a &mut to that app
-            // after I've returned a future. So, clone.
-            let mut app = app.clone();
-            async move {
-                let resp = do_req(&mut app, req).await;
-                assert_eq!(resp.status(), StatusCode::CREATED);
-                let body_bytes = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
-                // Got back a dogear
-                let d: Dogear =
-                    serde_json::from_slice(&body_bytes).expect("couldn't deserialize Dogear");
-                // Didn't get back an error object
-                let e = serde_json::from_slice::<RawJsonError>(&body_bytes);
-                assert!(e.is_err());
-                d
-            }
-        };
-        // 3.1: logged in
-        {
-            let body = r#"{
-                "prefix": "example.com/login",
-                "current": "http://example.com/login/1"
-            }"#;
-            let d = happy_path(Auth::Session(&user.session_id), body).await;
-            assert_eq!(d.display_name, None);
-        }
-        // 3.2: write token is ok
-        {
-            let body = r#"{
-                "prefix": "example.com/write",
-                "current": "http://example.com/write/5",
-                "display_name": "write token"
-            }"#;
-            let d = happy_path(Auth::Token(&user.write_token), body).await;
-            assert_eq!(d.display_name.as_deref(), Some("write token"));
-        }
-        // 3.3: manage token is ok
-        {
-            let body = r#"{
-                "prefix": "example.com/manage",
-                "current": "http://example.com/manage/91",
-                "display_name": "manage token"
-            }"#;
-            let _ = happy_path(Auth::Token(&user.manage_token), body).await;
-        }
+        let req = new_req("POST", &pause)
+            .json()
+            .token(&user.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let paused: Dogear = serde_json::from_slice(&body).expect("wanted Dogear back");
+        assert!(paused.paused);
     }
-    // 4: Legible 409 conflict err on duplicate create
+    // Updates come back with the paused flag and the old position
     {
-        let body = r#"{
-            "prefix": "example.com/comic",
-            "current": "http://example.com/comic/99"
-        }"#;
-        let req = new_req("POST", uri)
+        let req = new_req("POST", "/api/v1/update")
             .json()
             .token(&user.write_token)
-            .body(body.into())
+            .body(r#"{"current": "https://example.com/comic/30"}"#.into())
             .unwrap();
         let resp = do_req(&mut app, req).await;
-        assert_eq!(resp.status(), StatusCode::CONFLICT);
-        let _ = api_error_body(resp).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let updated: Vec<Dogear> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(updated.len(), 1);
+        assert!(updated[0].paused);
+        assert_eq!(updated[0].current, "https://example.com/comic/24");
+    }
+    // Unpause works with a login session too
+    {
+        let req = new_req("POST", &unpause)
+            .json()
+            .session(&user.session_id)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let unpaused: Dogear = serde_json::from_slice(&body).unwrap();
+        assert!(!unpaused.paused);
     }
 }
 
 #[tokio::test]
-async fn api_update_test() {
+async fn api_edit_test() {
     use crate::db::Dogear;
 
     let state = test_state().await;
     let mut app = eardogger_app(state.clone());
 
     let user = state.db.test_user("whoever").await.unwrap();
-    let uri = "/api/v1/update";
+    let user_id = state
+        .db
+        .users()
+        .by_name(&user.name)
+        .await
+        .unwrap()
+        .unwrap()
+        .id;
+    let (dogears, _) = state.db.dogears().list(user_id, 1, 50).await.unwrap();
+    let comic = dogears
+        .iter()
+        .find(|d| d.prefix == "example.com/comic")
+        .unwrap();
+    let edit = format!("/api/v1/dogear/{}", comic.id);
 
-    // reusable test case -- wants a new page number for our example comic.
-    // success means: 200 and a Vec<Dogear> with all updated bookmarks.
-    let closure_cloneable = app.clone(); // so we can mutably borrow `app` in other test cases.
-    let happy_path = |num: u32, auth: Auth| {
-        let mut app = closure_cloneable.clone();
-        // since it's a format string, the json curlies need doubling.
-        let body = format!(r#"{{"current": "http://example.com/comic/{}"}}"#, num);
-        let req = new_req("POST", uri)
+    // 401 when logged out
+    {
+        assert_api_auth_required(&mut app, "PATCH", &edit, None).await;
+    }
+    // Tokens: requires manage scope
+    {
+        let req = new_req("PATCH", &edit)
             .json()
-            .auth(auth)
-            .header(header::ORIGIN, "http://example.com")
-            .body(body.into())
+            .token(&user.write_token)
+            .body(r#"{"display_name": "Nope"}"#.into())
             .unwrap();
-        async move {
-            let resp = do_req(&mut app, req).await;
-            assert_eq!(resp.status(), StatusCode::OK);
-            let body = body_bytes(resp).await;
-            let updated: Vec<Dogear> =
-                serde_json::from_slice(&body).expect("wanted Vec<Dogear> back");
-            assert_eq!(updated.len(), 1);
-            // updated the current value
-            assert_eq!(
-                updated[0].current,
-                format!("http://example.com/comic/{}", num)
-            );
-            // hit the expected pre-existing prefix from test data
-            assert_eq!(updated[0].prefix, "example.com/comic");
-            updated
-        }
-    };
-
-    // 1: CORS is yes, actually.
-    // 1.1: write token works, manage token works, login session works.
+        let resp = do_req(&mut app, req).await;
+        assert_api_insufficient_permissions(resp).await;
+    }
+    // 404 on whiff
     {
-        // preflight
-        let opt_req = new_req("OPTIONS", uri)
+        let req = new_req("PATCH", "/api/v1/dogear/20566")
             .json()
-            .header(header::ORIGIN, "http://example.com")
+            .token(&user.manage_token)
+            .body(r#"{"display_name": "Nope"}"#.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+    // 400 if there's nothing to change
+    {
+        let req = new_req("PATCH", &edit)
+            .json()
+            .token(&user.manage_token)
+            .body("{}".into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+    // 400 if the current URL wouldn't match the new prefix
+    {
+        let req = new_req("PATCH", &edit)
+            .json()
+            .token(&user.manage_token)
+            .body(r#"{"prefix": "example.com/comics"}"#.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+    // 409 if another dogear already has it
+    {
+        let req = new_req("PATCH", &edit)
+            .json()
+            .token(&user.manage_token)
+            .body(r#"{"prefix": "example.com/serial"}"#.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+    // Widen the prefix (normalized, like on create) and rename it
+    {
+        let req = new_req("PATCH", &edit)
+            .json()
+            .token(&user.manage_token)
+            .body(
+                r#"{"prefix": "https://www.example.com/com", "display_name": " Renamed "}"#.into(),
+            )
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let edited: Dogear = serde_json::from_slice(&body).expect("wanted Dogear back");
+        assert_eq!(edited.prefix, "example.com/com");
+        assert_eq!(edited.display_name.as_deref(), Some("Renamed"));
+        assert_eq!(edited.current, comic.current);
+    }
+    // A blank name clears it, and the prefix stays put. Sessions work too.
+    {
+        let req = new_req("PATCH", &edit)
+            .json()
+            .session(&user.session_id)
+            .body(r#"{"display_name": ""}"#.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let edited = state
+            .db
+            .dogears()
+            .by_id(comic.id, user_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(edited.prefix, "example.com/com");
+        assert_eq!(edited.display_name, None);
+    }
+}
+
+#[tokio::test]
+async fn api_archive_test() {
+    use crate::db::Dogear;
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+
+    let user = state.db.test_user("whoever").await.unwrap();
+    let user_id = state
+        .db
+        .users()
+        .by_name(&user.name)
+        .await
+        .unwrap()
+        .unwrap()
+        .id;
+    let (dogears, _) = state.db.dogears().list(user_id, 1, 50).await.unwrap();
+    let comic = dogears
+        .iter()
+        .find(|d| d.prefix == "example.com/comic")
+        .unwrap();
+    let archive = format!("/api/v1/dogear/{}/archive", comic.id);
+    let unarchive = format!("/api/v1/dogear/{}/unarchive", comic.id);
+
+    assert_api_auth_required(&mut app, "POST", &archive, None).await;
+    // Tokens: requires manage scope
+    {
+        let req = new_req("POST", &archive)
+            .json()
+            .token(&user.write_token)
             .empty();
-        let opt = do_req(&mut app, opt_req).await;
-        // u can post
-        assert_eq!(
-            opt.headers()
-                .get(header::ACCESS_CONTROL_ALLOW_METHODS)
-                .unwrap(),
-            "POST"
-        );
-        assert_eq!(
-            opt.headers()
-                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
-                .unwrap(),
-            "http://example.com"
-        );
+        let resp = do_req(&mut app, req).await;
+        assert_api_insufficient_permissions(resp).await;
+    }
+    // 404 on whiff
+    {
+        let req = new_req("POST", "/api/v1/dogear/20566/archive")
+            .json()
+            .token(&user.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+    // Archive it, and it's off the list
+    {
+        let req = new_req("POST", &archive)
+            .json()
+            .token(&user.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let archived: Dogear = serde_json::from_slice(&body).expect("wanted Dogear back");
+        assert!(archived.archived);
 
-        // now some real requests
-        happy_path(10, Auth::Token(&user.write_token)).await;
-        happy_path(13, Auth::Token(&user.manage_token)).await;
-        happy_path(14, Auth::Session(&user.session_id)).await;
-        // Well, never mind that a session request prolly wouldn't come with an Origin header...
+        let req = new_req("GET", "/api/v1/list")
+            .json()
+            .token(&user.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        let body = body_bytes(resp).await;
+        let list: ApiDogearsList = serde_json::from_slice(&body).unwrap();
+        assert!(list.data.iter().all(|d| d.id != comic.id));
     }
-    // 2. CORS from wrong origin is 404 even if matching bookmark exists.
+    // Unarchive works with a login session too
+    {
+        let req = new_req("POST", &unarchive)
+            .json()
+            .session(&user.session_id)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let unarchived: Dogear = serde_json::from_slice(&body).unwrap();
+        assert!(!unarchived.archived);
+    }
+}
+
+#[tokio::test]
+async fn api_create_test() {
+    use crate::db::Dogear;
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+
+    let user = state.db.test_user("whoever").await.unwrap();
+    let uri = "/api/v1/create";
+
+    // 1. No cors preflight approval.
     {
         let body = r#"{
-            "current": "http://example.com/comic/12"
+            "prefix": "example.com/cors",
+            "current": "http://example.com/cors/0"
         }"#;
-        let req = new_req("POST", uri)
+        let req = new_req("OPTIONS", uri)
             .json()
-            .token(&user.write_token)
-            .header(header::ORIGIN, "http://example.horse")
+            .header(header::ORIGIN, "https://example.com")
             .body(body.into())
             .unwrap();
         let resp = do_req(&mut app, req).await;
-        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
-        let _ = api_error_body(resp).await.expect("need error body");
+        assert_no_cors(&resp);
     }
-    // 3. 401 when not authenticated
+    // 2. 401 when not authenticated
     {
         let body = r#"{
-            "current": "http://example.com/comic/12"
+            "prefix": "example.com/noone",
+            "current": "http://example.com/noone/0"
+        }"#;
+        assert_api_auth_required(&mut app, "POST", uri, Some(body.into())).await;
+    }
+    // 3. Happy path: 201 and a dogear
+    // (changed from ed.v1, which returned a 1-item array)
+    {
+        // reusable test case; returns a dogear for further inspection. if u even care.
+        let happy_path = |auth: Auth, body: &'static str| {
+            let req = new_req("POST", uri)
+                .json()
+                .auth(auth)
+                .body(body.into())
+                .unwrap();
+            // async closures are unstable... and also I can't retain a &mut to that app
+            // after I've returned a future. So, clone.
+            let mut app = app.clone();
+            async move {
+                let resp = do_req(&mut app, req).await;
+                assert_eq!(resp.status(), StatusCode::CREATED);
+                let location = resp.headers()[header::LOCATION]
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                let body_bytes = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+                // Got back a dogear
+                let d: Dogear =
+                    serde_json::from_slice(&body_bytes).expect("couldn't deserialize Dogear");
+                // ...and where to find it later
+                assert_eq!(location, format!("/api/v1/dogear/{}", d.id));
+                // Didn't get back an error object
+                let e = serde_json::from_slice::<RawJsonError>(&body_bytes);
+                assert!(e.is_err());
+                d
+            }
+        };
+        // 3.1: logged in
+        {
+            let body = r#"{
+                "prefix": "example.com/login",
+                "current": "http://example.com/login/1"
+            }"#;
+            let d = happy_path(Auth::Session(&user.session_id), body).await;
+            assert_eq!(d.display_name, None);
+        }
+        // 3.2: write token is ok
+        {
+            let body = r#"{
+                "prefix": "example.com/write",
+                "current": "http://example.com/write/5",
+                "display_name": "write token"
+            }"#;
+            let d = happy_path(Auth::Token(&user.write_token), body).await;
+            assert_eq!(d.display_name.as_deref(), Some("write token"));
+        }
+        // 3.3: manage token is ok
+        {
+            let body = r#"{
+                "prefix": "example.com/manage",
+                "current": "http://example.com/manage/91",
+                "display_name": "manage token"
+            }"#;
+            let _ = happy_path(Auth::Token(&user.manage_token), body).await;
+        }
+    }
+    // 4: Legible 409 conflict err on duplicate create
+    {
+        let body = r#"{
+            "prefix": "example.com/comic",
+            "current": "http://example.com/comic/99"
         }"#;
         let req = new_req("POST", uri)
             .json()
-            .header(header::ORIGIN, "http://example.com")
+            .token(&user.write_token)
             .body(body.into())
             .unwrap();
         let resp = do_req(&mut app, req).await;
-        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
-        let _ = api_error_body(resp).await.expect("need error body");
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let _ = api_error_body(resp).await;
     }
-    // 4. Busted request: unprocessable
+    // 5: Legible 400 for a display name that's way too long
     {
-        let body = r#"{
-            "whuh???": "http://example.com/comic/12"
-        }"#;
+        let body = serde_json::json!({
+            "prefix": "example.com/huge",
+            "current": "http://example.com/huge/1",
+            "display_name": "x".repeat(100_000),
+        });
         let req = new_req("POST", uri)
             .json()
             .token(&user.write_token)
-            .body(body.into())
+            .body(body.to_string().into())
             .unwrap();
         let resp = do_req(&mut app, req).await;
-        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
-        // TODO: The error is coming from the Json extractor's Rejection type,
-        // which doesn't match the format of ApiError. (It's a line of plain
-        // text message.) I can wrap the extractor to customize the Rejection,
-        // but maybe that's more trouble than this is worth, since no one else
-        // is using this API but me.
-        // https://github.com/tokio-rs/axum/blob/main/examples/customize-extractor-error/src/derive_from_request.rs
-        // let _ = api_error_body(resp).await.expect("need error body");
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err = api_error_body(resp).await.unwrap();
+        assert_eq!(
+            err.error,
+            "Display names can't be longer than 200 characters."
+        );
+    }
+}
+
+#[tokio::test]
+async fn api_suggest_test() {
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let uri = format!(
+        "/api/v1/suggest?url={}",
+        encode_uri_component("https://archiveofourown.org/works/12345678/chapters/98765432")
+    );
+
+    // 401 when not authenticated
+    assert_api_auth_required(&mut app, "GET", &uri, None).await;
+    // Any dogear-writing auth gets a guess
+    for auth in [
+        Auth::Session(&user.session_id),
+        Auth::Token(&user.write_token),
+        Auth::Token(&user.manage_token),
+    ] {
+        let req = new_req("GET", &uri).auth(auth).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let suggestion: ApiPrefixSuggestion = serde_json::from_slice(&body).unwrap();
+        assert_eq!(suggestion.prefix, "archiveofourown.org/works/12345678");
     }
+    // Not a web page: 400
+    {
+        let req = new_req("GET", "/api/v1/suggest?url=ftp%3A%2F%2Fexample.com%2Ffile")
+            .token(&user.write_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let _ = api_error_body(resp).await;
+    }
+    // A site rule beats the guess
+    {
+        state
+            .db
+            .site_rules()
+            .put("archiveofourown.org", 1, None)
+            .await
+            .unwrap();
+        let req = new_req("GET", &uri).token(&user.write_token).empty();
+        let resp = do_req(&mut app, req).await;
+        let body = body_bytes(resp).await;
+        let suggestion: ApiPrefixSuggestion = serde_json::from_slice(&body).unwrap();
+        assert_eq!(suggestion.prefix, "archiveofourown.org/works");
+    }
+}
+
+#[tokio::test]
+async fn admin_site_rules_test() {
+    use crate::config::SiteRulesConfig;
+    use crate::site_rules::{signing_key, BundleRule, SiteRulesBundle};
+
+    let admin_token = "correct horse battery staple, but longer";
+    let uri = "/admin/site_rules";
+
+    // Not configured: not there
+    {
+        let state = test_state().await;
+        let mut app = eardogger_app(state.clone());
+        let req = new_req("GET", uri).token(admin_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    let stranger = signing_key(&tower_cookies::Key::generate());
+    let friend = signing_key(&tower_cookies::Key::generate());
+    let friend_key = crate::site_rules::public_key_string(&friend.verifying_key());
+    let state = test_state_with_config(|c| {
+        c.site_rules = Some(SiteRulesConfig {
+            admin_token: admin_token.to_string(),
+            trusted_keys: vec![friend_key],
+        })
+    })
+    .await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let bundle_of = |key: &ed25519_dalek::SigningKey| {
+        let rules = vec![BundleRule {
+            host: "example.com".to_string(),
+            segments: 2,
+            note: None,
+        }];
+        serde_json::to_string(&SiteRulesBundle::sign(rules, key)).unwrap()
+    };
+
+    // Wrong token, or a user's token: nope
+    for auth in [
+        "Bearer nope".to_string(),
+        format!("Bearer {}", &user.manage_token),
+        format!("Token {}", admin_token),
+    ] {
+        let req = new_req("GET", uri)
+            .header(header::AUTHORIZATION, auth)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let _ = api_error_body(resp).await;
+    }
+
+    // Import from a trusted instance
+    {
+        let req = new_req("POST", uri)
+            .token(admin_token)
+            .json()
+            .body(bundle_of(&friend).into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(report["changed"], 1);
+        let rule = state
+            .db
+            .site_rules()
+            .for_host("example.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rule.segments, 2);
+    }
+    // From a stranger: 403
+    {
+        let req = new_req("POST", uri)
+            .token(admin_token)
+            .json()
+            .body(bundle_of(&stranger).into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let _ = api_error_body(resp).await;
+    }
+    // Export is signed by us, and we'd take it right back
+    {
+        let req = new_req("GET", uri).token(admin_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bundle: SiteRulesBundle = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(bundle.rules.len(), 1);
+        let ours = signing_key(&state.cookie_key).verifying_key();
+        assert!(bundle.verify(&[ours]).is_ok());
+
+        let req = new_req("POST", uri)
+            .token(admin_token)
+            .json()
+            .body(serde_json::to_string(&bundle).unwrap().into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(report["unchanged"], 1);
+    }
+    // And the create page's guess follows the rule
+    {
+        let req = new_req("GET", "/mark/https%3A%2F%2Fexample.com%2Fa%2Fb%2Fc%2Fd")
+            .session(&user.session_id)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        let body = body_bytes(resp).await;
+        let doc = bytes_doc(&body);
+        let prefix: String = doc
+            .select(&sel("form#create-dogear textarea[name=prefix]"))
+            .next()
+            .unwrap()
+            .text()
+            .collect();
+        assert_eq!(prefix, "example.com/a/b");
+    }
+}
+
+#[tokio::test]
+async fn api_update_test() {
+    use crate::db::Dogear;
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+
+    let user = state.db.test_user("whoever").await.unwrap();
+    let uri = "/api/v1/update";
+
+    // reusable test case -- wants a new page number for our example comic.
+    // success means: 200 and a Vec<Dogear> with all updated bookmarks.
+    let closure_cloneable = app.clone(); // so we can mutably borrow `app` in other test cases.
+    let happy_path = |num: u32, auth: Auth| {
+        let mut app = closure_cloneable.clone();
+        // since it's a format string, the json curlies need doubling.
+        let body = format!(r#"{{"current": "http://example.com/comic/{}"}}"#, num);
+        let req = new_req("POST", uri)
+            .json()
+            .auth(auth)
+            .header(header::ORIGIN, "http://example.com")
+            .body(body.into())
+            .unwrap();
+        async move {
+            let resp = do_req(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let link = resp.headers()[header::LINK].to_str().unwrap().to_string();
+            let body = body_bytes(resp).await;
+            let updated: Vec<Dogear> =
+                serde_json::from_slice(&body).expect("wanted Vec<Dogear> back");
+            assert_eq!(updated.len(), 1);
+            // and a link to each one
+            assert_eq!(
+                link,
+                format!(r#"</api/v1/dogear/{}>; rel="item""#, updated[0].id)
+            );
+            // updated the current value
+            assert_eq!(
+                updated[0].current,
+                format!("http://example.com/comic/{}", num)
+            );
+            // hit the expected pre-existing prefix from test data
+            assert_eq!(updated[0].prefix, "example.com/comic");
+            updated
+        }
+    };
+
+    // 1: CORS is yes, actually.
+    // 1.1: write token works, manage token works, login session works.
+    {
+        // preflight
+        let opt_req = new_req("OPTIONS", uri)
+            .json()
+            .header(header::ORIGIN, "http://example.com")
+            .empty();
+        let opt = do_req(&mut app, opt_req).await;
+        // u can post (or put)
+        assert_eq!(
+            opt.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_METHODS)
+                .unwrap(),
+            "POST,PUT"
+        );
+        assert_eq!(
+            opt.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "http://example.com"
+        );
+
+        // now some real requests
+        happy_path(10, Auth::Token(&user.write_token)).await;
+        happy_path(13, Auth::Token(&user.manage_token)).await;
+        happy_path(14, Auth::Session(&user.session_id)).await;
+        // Well, never mind that a session request prolly wouldn't come with an Origin header...
+    }
+    // 2. CORS from wrong origin is 404 even if matching bookmark exists.
+    {
+        let body = r#"{
+            "current": "http://example.com/comic/12"
+        }"#;
+        let req = new_req("POST", uri)
+            .json()
+            .token(&user.write_token)
+            .header(header::ORIGIN, "http://example.horse")
+            .body(body.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let _ = api_error_body(resp).await.expect("need error body");
+    }
+    // 3. 401 when not authenticated
+    {
+        let body = r#"{
+            "current": "http://example.com/comic/12"
+        }"#;
+        let req = new_req("POST", uri)
+            .json()
+            .header(header::ORIGIN, "http://example.com")
+            .body(body.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let _ = api_error_body(resp).await.expect("need error body");
+    }
+    // 4. Busted request: unprocessable
+    {
+        let body = r#"{
+            "whuh???": "http://example.com/comic/12"
+        }"#;
+        let req = new_req("POST", uri)
+            .json()
+            .token(&user.write_token)
+            .body(body.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        // TODO: The error is coming from the Json extractor's Rejection type,
+        // which doesn't match the format of ApiError. (It's a line of plain
+        // text message.) I can wrap the extractor to customize the Rejection,
+        // but maybe that's more trouble than this is worth, since no one else
+        // is using this API but me.
+        // https://github.com/tokio-rs/axum/blob/main/examples/customize-extractor-error/src/derive_from_request.rs
+        // let _ = api_error_body(resp).await.expect("need error body");
+    }
+}
+
+#[tokio::test]
+async fn api_put_dogear_test() {
+    use crate::db::Dogear;
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let put_uri =
+        |current: &str| format!("/api/v1/dogear?current={}", encode_uri_component(current));
+
+    // Same CORS rule as update
+    {
+        let req = new_req("OPTIONS", put_uri("http://example.com/comic/20"))
+            .header(header::ORIGIN, "http://example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(
+            resp.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://example.com"
+        );
+    }
+    // Same results as update, no body required
+    {
+        let req = new_req("PUT", put_uri("http://example.com/comic/20"))
+            .token(&user.write_token)
+            .header(header::ORIGIN, "http://example.com")
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let link = resp.headers()[header::LINK].to_str().unwrap().to_string();
+        let updated: Vec<Dogear> = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].current, "http://example.com/comic/20");
+        assert_eq!(
+            link,
+            format!(r#"</api/v1/dogear/{}>; rel="item""#, updated[0].id)
+        );
+
+        // Again is fine, and changes nothing.
+        let req = new_req("PUT", put_uri("http://example.com/comic/20"))
+            .token(&user.write_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let again: Vec<Dogear> = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(again[0].id, updated[0].id);
+        assert_eq!(again[0].current, "http://example.com/comic/20");
+    }
+    // Notes come along as a query param too
+    {
+        let uri = format!(
+            "{}&note={}",
+            put_uri("http://example.com/comic/21"),
+            encode_uri_component("stopped mid-scene")
+        );
+        let req = new_req("PUT", uri).token(&user.write_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    // Same same-site rule for other origins
+    {
+        let req = new_req("PUT", put_uri("http://example.com/comic/22"))
+            .token(&user.write_token)
+            .header(header::ORIGIN, "http://example.horse")
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let _ = api_error_body(resp).await.expect("need error body");
+    }
+    // Same auth
+    {
+        let req = new_req("PUT", put_uri("http://example.com/comic/22")).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let _ = api_error_body(resp).await.expect("need error body");
+    }
+    // No match is a 404, and no URL at all is a 400
+    {
+        let req = new_req("PUT", put_uri("http://example.com/nothing-here"))
+            .token(&user.write_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = new_req("PUT", "/api/v1/dogear")
+            .token(&user.write_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
+
+#[tokio::test]
+async fn api_rotate_token_test() {
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+
+    let user = state.db.test_user("whoever").await.unwrap();
+    let uri = "/api/v1/tokens/rotate";
+
+    // 401 when logged out
+    {
+        assert_api_auth_required(&mut app, "POST", uri, None).await;
+    }
+    // Sessions have nothing to rotate
+    {
+        let req = new_req("POST", uri)
+            .json()
+            .session(&user.session_id)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let _ = api_error_body(resp).await;
+    }
+    // Write token: new cleartext comes back once, with the same scope...
+    let new_cleartext = {
+        let req = new_req("POST", uri).json().token(&user.write_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let rotated: ApiRotatedToken = serde_json::from_slice(&body).unwrap();
+        assert_eq!(rotated.scope, "write_dogears");
+        assert_ne!(rotated.token, user.write_token);
+        rotated.token
+    };
+    // ...and the old token is dead.
+    {
+        let req = new_req("POST", uri).json().token(&user.write_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    // New one works (and can rotate itself in turn).
+    {
+        let req = new_req("POST", uri).json().token(&new_cleartext).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn api_wait_for_update_test() {
+    let state = test_state().await;
+    let app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    // Timestamps only go down to the second, so push the test data way back
+    // to make "after since" unambiguous.
+    sqlx::query("UPDATE dogears SET updated = '2020-01-01 00:00:00';")
+        .execute(&state.db.write_pool)
+        .await
+        .unwrap();
+    let wait = |since: &str, timeout: u32| {
+        let mut app = app.clone();
+        let req = new_req(
+            "GET",
+            format!(
+                "/api/v1/wait_for_update?since={}&timeout={}",
+                encode_uri_component(since),
+                timeout
+            ),
+        )
+        .token(&user.manage_token)
+        .empty();
+        async move {
+            let resp = do_req(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = body_bytes(resp).await;
+            serde_json::from_slice::<ApiWaitResult>(&body).unwrap()
+        }
+    };
+
+    // 1. Already-moved dogears come back right away
+    {
+        let res = wait("2019-06-01T00:00:00Z", 30).await;
+        assert!(res.changed);
+        assert_eq!(res.dogears.len(), 2);
+        assert_eq!(res.next_since.year(), 2020);
+    }
+    // 2. Nothing new: waits out the timeout, then says so
+    {
+        let started = std::time::Instant::now();
+        let res = wait("2021-01-01T00:00:00Z", 1).await;
+        assert!(!res.changed);
+        assert!(res.dogears.is_empty());
+        assert_eq!(res.next_since.year(), 2021);
+        assert!(started.elapsed() >= std::time::Duration::from_secs(1));
+    }
+    // 3. A move while waiting wakes it up early
+    {
+        let waiting = tokio::spawn(wait("2021-01-01T00:00:00Z", 30));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let user_id = state
+            .db
+            .users()
+            .by_name(&user.name)
+            .await
+            .unwrap()
+            .unwrap()
+            .id;
+        state
+            .db
+            .dogears()
+            .update(user_id, "https://example.com/comic/25", None)
+            .await
+            .unwrap();
+        let res = tokio::time::timeout(std::time::Duration::from_secs(5), waiting)
+            .await
+            .expect("woke up before the timeout")
+            .unwrap();
+        assert!(res.changed);
+        assert_eq!(res.dogears.len(), 1);
+        assert_eq!(res.dogears[0].current, "https://example.com/comic/25");
+    }
+    // 4. Manage tokens only
+    {
+        let mut app = app.clone();
+        let req = new_req("GET", "/api/v1/wait_for_update?since=2021-01-01T00:00:00Z")
+            .token(&user.write_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_api_insufficient_permissions(resp).await;
+    }
+}
+
+#[tokio::test]
+async fn api_export_test() {
+    use futures_util::StreamExt;
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+    let uri = "/api/v1/export";
+
+    let user = state.db.test_user("whoever").await.unwrap();
+    let user_id = state
+        .db
+        .users()
+        .by_name(&user.name)
+        .await
+        .unwrap()
+        .unwrap()
+        .id;
+    // Enough to need a few pages.
+    for n in 0..1100 {
+        state
+            .db
+            .dogears()
+            .create(
+                user_id,
+                &format!("example.com/export/{}/", n),
+                &format!("https://example.com/export/{}/1", n),
+                None,
+            )
+            .await
+            .unwrap();
+    }
+    let total = 1100 + 2;
+
+    // 401 when logged out
+    {
+        assert_api_auth_required(&mut app, "GET", uri, None).await;
+    }
+    // 403 with write token
+    {
+        let req = new_req("GET", uri).json().token(&user.write_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_api_insufficient_permissions(resp).await;
+    }
+    // Full read: every dogear exactly once, in id order.
+    {
+        let req = new_req("GET", uri).json().token(&user.manage_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/x-ndjson"
+        );
+        let body = body_bytes(resp).await;
+        let dogears: Vec<crate::db::Dogear> = bytes_str(&body)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(dogears.len(), total);
+        assert!(dogears.windows(2).all(|w| w[0].id < w[1].id));
+    }
+    // OPML: same dogears, as link outlines, with the markup escaped.
+    {
+        state
+            .db
+            .dogears()
+            .create(
+                user_id,
+                "example.com/fish",
+                "https://example.com/fish?a=1&b=2",
+                Some("Fish & \"Chips\" <3"),
+            )
+            .await
+            .unwrap();
+        let req = new_req("GET", &format!("{}?format=opml", uri))
+            .token(&user.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/x-opml"
+        );
+        assert!(resp
+            .headers()
+            .get(header::CONTENT_DISPOSITION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .contains("eardogger-export.opml"));
+        let body = body_bytes(resp).await;
+        let text = bytes_str(&body);
+        assert!(text.starts_with("<?xml"));
+        assert!(text.ends_with("</opml>\n"));
+        assert_eq!(text.matches("<outline ").count(), total + 1);
+        assert!(text.contains(
+            r#"text="Fish &amp; &quot;Chips&quot; &lt;3" url="https://example.com/fish?a=1&amp;b=2" prefix="example.com/fish""#
+        ));
+    }
+    // Unknown formats are a bad request.
+    {
+        let req = new_req("GET", &format!("{}?format=csv", uri))
+            .token(&user.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+    // Partial read: one page-sized chunk arrives before the rest is even
+    // fetched, and hanging up early doesn't hurt anything.
+    {
+        let req = new_req("GET", uri).json().session(&user.session_id).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut chunks = resp.into_body().into_data_stream();
+        let first = chunks.next().await.unwrap().unwrap();
+        assert_eq!(bytes_str(&first).lines().count(), 500);
+        drop(chunks);
+
+        let req = new_req("GET", "/api/v1/list")
+            .json()
+            .token(&user.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn api_cadences_test() {
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+    let uri = "/api/v1/cadences";
+    let user = state.db.test_user("whoever").await.unwrap();
+    dogear_marked_days_ago(&state, user.id, "fresh", &[22, 15, 8, 1]).await;
+    dogear_marked_days_ago(&state, user.id, "weekly", &[36, 29, 22, 15, 8]).await;
+
+    // 401 when logged out
+    {
+        assert_api_auth_required(&mut app, "GET", uri, None).await;
+    }
+    // 403 with write token
+    {
+        let req = new_req("GET", uri).json().token(&user.write_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_api_insufficient_permissions(resp).await;
+    }
+    // Only dogears with enough history, overdue first.
+    {
+        let req = new_req("GET", uri).json().token(&user.manage_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let cadences: Vec<ApiCadence> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(cadences.len(), 2);
+        assert_eq!(cadences[0].dogear.prefix, "example.com/weekly");
+        assert!(cadences[0].probably_new);
+        assert_eq!(cadences[0].interval_secs, 7 * 24 * 60 * 60);
+        assert_eq!(cadences[0].interval_text, "~7 days");
+        assert!(cadences[0].expected_next < cadences[1].expected_next);
+        assert!(!cadences[1].probably_new);
+    }
+}
+
+#[tokio::test]
+async fn api_import_test() {
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+    let uri = "/api/v1/import";
+    let user = state.db.test_user("whoever").await.unwrap();
+    let batch = r#"[
+        {"prefix": "example.com/new", "current": "https://example.com/new/1", "display_name": "New", "paused": true},
+        {"prefix": "example.com/comic", "current": "https://example.com/comic/2"},
+        {"prefix": "example.com/elsewhere", "current": "https://example.org/elsewhere/1"},
+        {"prefix": "example.com/new", "current": "https://example.com/new/2"},
+        {"id": 9, "user_id": 1, "prefix": "example.com/exported", "current": "https://example.com/exported/5", "display_name": null, "updated": "2024-03-15T17:09:14.000000000Z", "public": true}
+    ]"#;
+
+    // 401 when logged out
+    {
+        assert_api_auth_required(&mut app, "POST", uri, Some(batch.into())).await;
+    }
+    // 403 with write token
+    {
+        let req = new_req("POST", uri)
+            .json()
+            .token(&user.write_token)
+            .body(batch.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_api_insufficient_permissions(resp).await;
+    }
+    // Good ones go in, bad ones get listed, and one doesn't sink the rest.
+    {
+        let req = new_req("POST", uri)
+            .json()
+            .token(&user.manage_token)
+            .body(batch.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let result: ApiBulkImportResult = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result.created, 2);
+        assert_eq!(result.skipped, 3);
+        let skipped: Vec<usize> = result.problems.iter().map(|p| p.index).collect();
+        assert_eq!(skipped, vec![1, 2, 3]);
+        assert!(result.problems[0].error.contains("already"));
+
+        let (dogears, _) = state.db.dogears().list(user.id, 1, 50).await.unwrap();
+        assert_eq!(dogears.len(), 4);
+        let new = dogears
+            .iter()
+            .find(|d| d.prefix == "example.com/new")
+            .unwrap();
+        assert!(new.paused && !new.public);
+        assert_eq!(new.current, "https://example.com/new/1");
+        assert_eq!(new.display_name.as_deref(), Some("New"));
+        let exported = dogears
+            .iter()
+            .find(|d| d.prefix == "example.com/exported")
+            .unwrap();
+        assert!(exported.public && !exported.paused);
+    }
+    // Too many at once
+    {
+        let entry = r#"{"prefix": "example.com/x", "current": "https://example.com/x/1"}"#;
+        let too_many = format!(
+            "[{}]",
+            vec![entry; crate::import::IMPORT_MAX_ENTRIES + 1].join(",")
+        );
+        let req = new_req("POST", uri)
+            .json()
+            .token(&user.manage_token)
+            .body(too_many.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
+
+#[tokio::test]
+async fn api_basic_auth_test() {
+    use crate::db::Db;
+
+    // Off by default: basic auth is just ignored.
+    {
+        let state = test_state().await;
+        let mut app = eardogger_app(state.clone());
+        let user = state.db.test_user("whoever").await.unwrap();
+        let req = new_req("GET", "/api/v1/list")
+            .json()
+            .basic(&user.name, Db::TEST_PASSWORD)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let state = test_state_with_config(|c| c.api_basic_auth = true).await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+
+    // Right password: acts like a manage token.
+    {
+        let req = new_req("GET", "/api/v1/list")
+            .json()
+            .basic(&user.name, Db::TEST_PASSWORD)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    // Doesn't log you into web pages, though.
+    {
+        let req = new_req("GET", "/account")
+            .basic(&user.name, Db::TEST_PASSWORD)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    // Garbage header: same as nothing
+    {
+        let req = new_req("GET", "/api/v1/list")
+            .json()
+            .header(header::AUTHORIZATION, "Basic definitely not base64!!")
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    // Wrong password, a bunch of times: 401s until the limiter kicks in...
+    for _ in 0..5 {
+        let req = new_req("GET", "/api/v1/list")
+            .json()
+            .basic(&user.name, "wrong")
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    // ...and then even the right password gets a 429.
+    {
+        let req = new_req("GET", "/api/v1/list")
+            .json()
+            .basic(&user.name, Db::TEST_PASSWORD)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let _ = api_error_body(resp).await;
+    }
+    // Tokens still work fine.
+    {
+        let req = new_req("GET", "/api/v1/list")
+            .json()
+            .token(&user.manage_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
+
+/// Swapping a password for a write token: works with no other auth, counts
+/// failures against the Basic auth limiter, caps new tokens, and leaves a
+/// trail.
+#[tokio::test]
+async fn api_token_exchange_test() {
+    use crate::db::{Db, TokenScope};
+    use eardogger_rs::api_types::ApiNewToken;
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let exchange = |username: &str, password: &str, comment: Option<&str>| {
+        let body = serde_json::json!({
+            "username": username,
+            "password": password,
+            "comment": comment,
+        })
+        .to_string();
+        new_req("POST", "/api/v1/token")
+            .json()
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    // Right password: a fresh write token that works right away.
+    let new_token: ApiNewToken = {
+        let resp = do_req(
+            &mut app,
+            exchange(&user.name, Db::TEST_PASSWORD, Some("My extension")),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        serde_json::from_slice(&body_bytes(resp).await).unwrap()
+    };
+    assert_eq!(new_token.scope, "write_dogears");
+    assert_eq!(new_token.comment.as_deref(), Some("My extension"));
+    {
+        let body = serde_json::json!({"url": "https://example.com/comic/99"}).to_string();
+        let req = new_req("POST", "/api/v1/update")
+            .json()
+            .token(&new_token.token)
+            .body(Body::from(body))
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    let (token, _) = state
+        .db
+        .tokens()
+        .authenticate(&new_token.token)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(token.scope(), TokenScope::WriteDogears);
+    // It's in the audit log.
+    let events = state.db.audit().recent(user.id, 5).await.unwrap();
+    assert!(events.iter().any(|e| e.kind == "token_created"
+        && e.detail
+            .as_deref()
+            .is_some_and(|d| d.contains("token exchange"))));
+    // No comment gets a dated one.
+    {
+        let resp = do_req(&mut app, exchange(&user.name, Db::TEST_PASSWORD, None)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: ApiNewToken = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert!(body.comment.unwrap().starts_with("Token exchange "));
+    }
+
+    // Wrong password, or nobody by that name: 401.
+    {
+        let resp = do_req(&mut app, exchange("nobody", Db::TEST_PASSWORD, None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let _ = api_error_body(resp).await;
+    }
+    // Failures share Basic auth's budget, so after five more, even the
+    // right password gets a 429.
+    for _ in 0..5 {
+        let resp = do_req(&mut app, exchange(&user.name, "wrong", None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    {
+        let resp = do_req(&mut app, exchange(&user.name, Db::TEST_PASSWORD, None)).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    // Only so many new tokens per account, even with the right password.
+    let other = state.db.test_user("prolific").await.unwrap();
+    for _ in 0..10 {
+        let resp = do_req(&mut app, exchange(&other.name, Db::TEST_PASSWORD, None)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    {
+        let resp = do_req(&mut app, exchange(&other.name, Db::TEST_PASSWORD, None)).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
+
+/// Allowlisted clients skip the limiters, and the admin report shows who
+/// didn't.
+#[tokio::test]
+async fn rate_limit_allowlist_test() {
+    use crate::config::SiteRulesConfig;
+    use crate::db::Db;
+    use crate::util::{IpRange, RateLimitAllowlist};
+
+    let admin_token = "correct horse battery staple, but longer";
+    let state = test_state_with_config(|c| {
+        c.api_basic_auth = true;
+        c.rate_limit_allowlist = RateLimitAllowlist {
+            ips: vec![IpRange::parse("198.51.100.0/24").unwrap()],
+            user_agents: vec!["UptimeRobot".to_string()],
+        };
+        c.site_rules = Some(SiteRulesConfig {
+            admin_token: admin_token.to_string(),
+            trusted_keys: Vec::new(),
+        });
+    })
+    .await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let list = |password: &str, header: (&'static str, &'static str)| {
+        new_req("GET", "/api/v1/list")
+            .json()
+            .basic(&user.name, password)
+            .header(header.0, header.1)
+            .empty()
+    };
+    let monitor = ("x-forwarded-for", "198.51.100.7");
+    let robot = ("user-agent", "Mozilla/5.0+(compatible; UptimeRobot/2.0)");
+    let stranger = ("x-forwarded-for", "203.0.113.9");
+
+    // Allowlisted: wrong passwords never add up to a lockout
+    for header in [monitor, robot] {
+        for _ in 0..6 {
+            let resp = do_req(&mut app, list("wrong", header)).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        let resp = do_req(&mut app, list(Db::TEST_PASSWORD, header)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    // Everybody else gets the usual treatment...
+    for _ in 0..5 {
+        let resp = do_req(&mut app, list("wrong", stranger)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    let resp = do_req(&mut app, list(Db::TEST_PASSWORD, stranger)).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    // ...which the allowlist still gets around.
+    let resp = do_req(&mut app, list(Db::TEST_PASSWORD, monitor)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // The report
+    {
+        let req = new_req("GET", "/admin/rate_limits").empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = new_req("GET", "/admin/rate_limits")
+            .token(admin_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(report["allowlist"]["ips"][0], "198.51.100.0/24");
+        assert_eq!(report["basic_auth"]["max"], 5);
+        assert_eq!(report["basic_auth"]["tracked"], 1);
+        let entry = &report["basic_auth"]["entries"][0];
+        assert_eq!(entry["key"], "whoever");
+        assert_eq!(entry["count"], 5);
+        assert_eq!(entry["limited"], true);
+        assert_eq!(report["quickmark"]["tracked"], 0);
+    }
+}
+
+/// Clients that keep sending bad tokens get blocked for a while, per IP,
+/// and the admin report keeps count.
+#[tokio::test]
+async fn bad_token_limit_test() {
+    use crate::config::SiteRulesConfig;
+    use crate::util::{IpRange, RateLimitAllowlist};
+
+    let admin_token = "correct horse battery staple, but longer";
+    let state = test_state_with_config(|c| {
+        c.rate_limit_allowlist = RateLimitAllowlist {
+            ips: vec![IpRange::parse("198.51.100.0/24").unwrap()],
+            user_agents: Vec::new(),
+        };
+        c.site_rules = Some(SiteRulesConfig {
+            admin_token: admin_token.to_string(),
+            trusted_keys: Vec::new(),
+        });
+    })
+    .await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let list = |token: &str, ip: Option<&'static str>| {
+        let req = new_req("GET", "/api/v1/list").json().token(token);
+        match ip {
+            Some(ip) => req.header("x-forwarded-for", ip),
+            None => req,
+        }
+        .empty()
+    };
+    let scanner = Some("203.0.113.9");
+    let neighbor = Some("203.0.113.10");
+    let monitor = Some("198.51.100.7");
+
+    // Twenty strikes, and you're out...
+    for i in 0..20 {
+        let resp = do_req(&mut app, list(&format!("eardoggerv1.guess{}", i), scanner)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    let resp = do_req(&mut app, list(&user.manage_token, scanner)).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    let _ = api_error_body(resp).await;
+    // ...but only you.
+    let resp = do_req(&mut app, list(&user.manage_token, neighbor)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    // No IP and allowlisted IPs never get blocked.
+    for ip in [None, monitor] {
+        for _ in 0..21 {
+            let resp = do_req(&mut app, list("eardoggerv1.nope", ip)).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        let resp = do_req(&mut app, list(&user.manage_token, ip)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    // The report
+    let req = new_req("GET", "/admin/rate_limits")
+        .token(admin_token)
+        .empty();
+    let resp = do_req(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let report: serde_json::Value = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+    assert_eq!(report["bad_tokens"]["tracked"], 1);
+    let entry = &report["bad_tokens"]["entries"][0];
+    assert_eq!(entry["key"], "203.0.113.9");
+    assert_eq!(entry["limited"], true);
+    let counts = &report["bad_token_counts"];
+    assert_eq!(counts["failures"], 20 + 21 + 21);
+    assert_eq!(counts["unattributed"], 21);
+    assert_eq!(counts["alerts"], 1);
+    assert_eq!(counts["blocked"], 1);
+}
+
+#[tokio::test]
+async fn status_details_test() {
+    let status_token = "a status token that's long enough to count";
+
+    // Without a configured token, it's not there (but plain /status is).
+    {
+        let state = test_state().await;
+        let mut app = eardogger_app(state.clone());
+        let req = new_req("GET", "/status/details")
+            .token(status_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = do_req(&mut app, new_req("GET", "/status").empty()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    }
+
+    let state = test_state_with_config(|c| {
+        c.status_token = Some(status_token.to_string());
+    })
+    .await;
+    let mut app = eardogger_app(state.clone());
+
+    // Wrong token or no token
+    for req in [
+        new_req("GET", "/status/details").empty(),
+        new_req("GET", "/status/details").token("nope").empty(),
+    ] {
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(api_error_body(resp)
+            .await
+            .unwrap()
+            .error
+            .contains("status token"));
+    }
+
+    // The report
+    state.job_clock.ran("daily_cleanup");
+    let req = new_req("GET", "/status/details")
+        .token(status_token)
+        .empty();
+    let resp = do_req(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CACHE_CONTROL).unwrap(),
+        "no-store"
+    );
+    let report: serde_json::Value = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+    for pool in ["read_pool", "write_pool"] {
+        let size = report[pool]["size"].as_u64().unwrap();
+        assert!(size <= report[pool]["max"].as_u64().unwrap());
+        assert!(report[pool]["idle"].as_u64().unwrap() <= size);
+    }
+    // Tests share one connection for reads and writes.
+    assert_eq!(report["write_pool"]["max"], 1);
+    assert!(report["background_tasks"].is_u64());
+    let jobs = report["jobs"].as_array().unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0]["job"], "daily_cleanup");
+    assert!(jobs[0]["last_run"].is_string());
+}
+
+#[tokio::test]
+async fn metrics_test() {
+    let status_token = "a status token that's long enough to count";
+    let state = test_state_with_config(|c| {
+        c.status_token = Some(status_token.to_string());
+    })
+    .await;
+    let mut app = eardogger_app(state.clone());
+
+    // Same gate as /status/details.
+    let resp = do_req(&mut app, new_req("GET", "/metrics").empty()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // Some traffic to count
+    for _ in 0..2 {
+        let resp = do_req(&mut app, new_req("GET", "/status").empty()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    }
+    let resp = do_req(&mut app, new_req("GET", "/wp-login.php").empty()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    state.metrics.sessions_pruned(4);
+
+    let req = new_req("GET", "/metrics").token(status_token).empty();
+    let resp = do_req(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain; version=0.0.4"));
+    let body = String::from_utf8(body_bytes(resp).await.to_vec()).unwrap();
+    for expected in [
+        r#"eardogger_http_requests_total{method="GET",route="/status",status="204"} 2"#,
+        // Routes as the router spells them, so no unbounded labels.
+        r#"eardogger_http_requests_total{method="GET",route="unmatched",status="404"} 1"#,
+        r#"eardogger_http_requests_total{method="GET",route="/metrics",status="401"} 1"#,
+        r#"eardogger_http_request_duration_seconds_count{method="GET",route="/status"} 2"#,
+        "eardogger_sessions_pruned_total 4",
+        r#"eardogger_db_pool_max_connections{pool="write"} 1"#,
+        "# TYPE eardogger_background_tasks gauge",
+    ] {
+        assert!(
+            body.lines().any(|l| l == expected),
+            "no line {:?} in:\n{}",
+            expected,
+            body
+        );
+    }
+}
+
+#[tokio::test]
+async fn request_id_test() {
+    let mut app = eardogger_app(test_state().await);
+    let id_of = |resp: &Response<Body>| {
+        resp.headers()
+            .get("x-request-id")
+            .expect("every response has a request ID")
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+
+    // Made up when the request didn't bring one, and different every time
+    let resp = do_req(&mut app, new_req("GET", "/status").empty()).await;
+    let first = id_of(&resp);
+    let resp = do_req(&mut app, new_req("GET", "/status").empty()).await;
+    assert_ne!(id_of(&resp), first);
+
+    // Inbound IDs get kept, if they look like IDs
+    let req = new_req("GET", "/status")
+        .header("x-request-id", "edge-1234")
+        .empty();
+    let resp = do_req(&mut app, req).await;
+    assert_eq!(id_of(&resp), "edge-1234");
+    let req = new_req("GET", "/status")
+        .header("x-request-id", "<b>hi</b>")
+        .empty();
+    let resp = do_req(&mut app, req).await;
+    assert_ne!(id_of(&resp), "<b>hi</b>");
+
+    // JSON errors include it
+    let req = new_req("GET", "/api/v1/list")
+        .header("x-request-id", "edge-json")
+        .empty();
+    let resp = do_req(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(id_of(&resp), "edge-json");
+    let err = api_error_body(resp).await.unwrap();
+    assert_eq!(err.request_id.as_deref(), Some("edge-json"));
+
+    // So do error pages
+    let req = new_req("GET", "/wp-login.php")
+        .header("x-request-id", "edge-html")
+        .empty();
+    let resp = do_req(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body = body_bytes(resp).await;
+    assert!(bytes_str(&body).contains("<code>edge-html</code>"));
+}
+
+#[tokio::test]
+async fn request_timeout_test() {
+    use axum::routing::get;
+    use std::time::Duration;
+
+    // None of the real routes are slow on purpose, so make one.
+    let state = test_state().await;
+    let slow = || async {
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        "finally"
+    };
+    let budget = Duration::from_millis(10);
+    let mut app = with_timeout(
+        axum::Router::new().route("/slow.json", get(slow)),
+        budget,
+        AppErrorKind::Json,
+    )
+    .merge(with_timeout(
+        axum::Router::new().route("/slow.html", get(slow)),
+        budget,
+        AppErrorKind::Html,
+    ))
+    .with_state(state);
+
+    // JSON flavor
+    {
+        let req = new_req("GET", "/slow.json").empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        let err = api_error_body(resp).await.unwrap();
+        assert!(err.error.contains("too long"));
+    }
+    // HTML flavor
+    {
+        let req = new_req("GET", "/slow.html").empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = body_bytes(resp).await;
+        assert!(bytes_str(&body).contains("too long"));
+    }
+}
+
+#[tokio::test]
+async fn api_create_resolve_redirects_test() {
+    use crate::db::Dogear;
+    use axum::{extract::Path, response::Redirect, routing::get, Router};
+
+    // A little site with short links that bounce to the real chapter URLs.
+    let site =
+        Router::new()
+            .route(
+                "/c/:n",
+                get(|Path(n): Path<u32>| async move {
+                    Redirect::permanent(&format!("/chapters/{}", n))
+                }),
+            )
+            .route("/chapters/:n", get(|| async { "chapter" }));
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+        .await
+        .unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    let site_server = tokio::spawn(async move { axum::serve(listener, site).await });
+
+    let create = |app: &Router, token: &str, prefix: String, current: String| {
+        let body = serde_json::json!({"prefix": prefix, "current": current}).to_string();
+        let req = new_req("POST", "/api/v1/create")
+            .json()
+            .token(token)
+            .body(body.into())
+            .unwrap();
+        let mut app = app.clone();
+        async move { do_req(&mut app, req).await }
+    };
+
+    // 1. Off by default: saves exactly what you sent.
+    {
+        let state = test_state().await;
+        let app = eardogger_app(state.clone());
+        let user = state.db.test_user("whoever").await.unwrap();
+        let resp = create(
+            &app,
+            &user.write_token,
+            format!("{}/c", host),
+            format!("http://{}/c/5", host),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let dogear: Dogear = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(dogear.prefix, format!("{}/c", host));
+        assert_eq!(dogear.current, format!("http://{}/c/5", host));
+    }
+    // 2. On: saves where the redirect went, and catches the duplicate.
+    {
+        let state = test_state_with_config(|c| c.resolve_redirects = true).await;
+        let app = eardogger_app(state.clone());
+        let user = state.db.test_user("whoever").await.unwrap();
+        let resp = create(
+            &app,
+            &user.write_token,
+            format!("{}/chapters", host),
+            format!("http://{}/chapters/2", host),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let dogear: Dogear = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(dogear.current, format!("http://{}/chapters/2", host));
+
+        // Same story via a short link
+        let resp = create(
+            &app,
+            &user.write_token,
+            format!("{}/c", host),
+            format!("http://{}/c/5", host),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+
+    site_server.abort();
+}
+
+#[tokio::test]
+async fn api_quickmark_test() {
+    use crate::db::{Dogear, TokenScope};
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let user_id = state
+        .db
+        .users()
+        .by_name(&user.name)
+        .await
+        .unwrap()
+        .unwrap()
+        .id;
+    let (_, quickmark_token) = state
+        .db
+        .tokens()
+        .create(user_id, TokenScope::Quickmark, None)
+        .await
+        .unwrap();
+    let uri = |token: &str, url: &str| {
+        format!(
+            "/api/v1/quickmark?token={}&url={}",
+            encode_uri_component(token),
+            encode_uri_component(url)
+        )
+    };
+
+    // Happy path: same results as update, and no caching
+    {
+        let req = new_req("GET", uri(&quickmark_token, "https://example.com/comic/25")).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CACHE_CONTROL).unwrap(),
+            "no-store"
+        );
+        let body = body_bytes(resp).await;
+        let updated: Vec<Dogear> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].current, "https://example.com/comic/25");
+    }
+    // Unknown site
+    {
+        let req = new_req("GET", uri(&quickmark_token, "https://example.horse/1")).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+    // Regular tokens don't work in a URL...
+    for token in [&user.write_token, &user.manage_token] {
+        let req = new_req("GET", uri(token, "https://example.com/comic/26")).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+    // ...garbage doesn't either...
+    {
+        let req = new_req(
+            "GET",
+            uri("eardoggerv1.nope", "https://example.com/comic/26"),
+        )
+        .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    // ...and quickmark tokens don't work anywhere else.
+    {
+        let body = r#"{"current": "https://example.com/comic/26"}"#;
+        let req = new_req("POST", "/api/v1/update")
+            .json()
+            .token(&quickmark_token)
+            .body(body.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+    // Rate limited per token (two used up already)
+    {
+        for _ in 0..28 {
+            let req = new_req("GET", uri(&quickmark_token, "https://example.com/comic/27")).empty();
+            let resp = do_req(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let req = new_req("GET", uri(&quickmark_token, "https://example.com/comic/28")).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
+
+/// Inbound webhooks: made and deleted with a manage token, called with
+/// nothing but the secret URL, and only ever good for their own dogear.
+#[tokio::test]
+async fn api_hooks_test() {
+    use crate::db::Dogear;
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let other = state.db.test_user("someone_else").await.unwrap();
+    let (dogears, _) = state.db.dogears().list(user.id, 1, 50).await.unwrap();
+    let comic = dogears
+        .iter()
+        .find(|d| d.prefix == "example.com/comic")
+        .unwrap();
+    let serial = dogears
+        .iter()
+        .find(|d| d.prefix == "example.com/serial")
+        .unwrap();
+    let (theirs, _) = state.db.dogears().list(other.id, 1, 50).await.unwrap();
+
+    let create = |id: i64, token: &str| {
+        new_req("POST", format!("/api/v1/dogear/{}/hook", id))
+            .json()
+            .token(token)
+            .empty()
+    };
+    let call =
+        |path: &str, body: &'static str| new_req("POST", path).json().body(body.into()).unwrap();
+    // Just the path part of a hook URL.
+    let hook_path = |resp_body: &[u8]| {
+        let hook: ApiHook = serde_json::from_slice(resp_body).unwrap();
+        let (_, secret) = hook.url.split_once("/hooks/").unwrap();
+        format!("/hooks/{}", secret)
+    };
+
+    // Making one takes a manage token, and your own dogear.
+    {
+        assert_api_auth_required(
+            &mut app,
+            "POST",
+            format!("/api/v1/dogear/{}/hook", comic.id),
+            None,
+        )
+        .await;
+        let resp = do_req(&mut app, create(comic.id, &user.write_token)).await;
+        assert_api_insufficient_permissions(resp).await;
+        let resp = do_req(&mut app, create(theirs[0].id, &user.manage_token)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+    let resp = do_req(&mut app, create(comic.id, &user.manage_token)).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let path = hook_path(&body_bytes(resp).await);
+
+    // Happy path, and `link` works as well as `url`.
+    {
+        let resp = do_req(
+            &mut app,
+            call(&path, r#"{"link": "https://example.com/comic/25"}"#),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let dogear: Dogear = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(dogear.id, comic.id);
+        assert_eq!(dogear.current, "https://example.com/comic/25");
+    }
+    // Only moves its own dogear, even to a URL another one matches.
+    {
+        let resp = do_req(
+            &mut app,
+            call(&path, r#"{"url": "https://example.com/serial/5"}"#),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let serial_now = state
+            .db
+            .dogears()
+            .by_id(serial.id, user.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(serial_now.current, serial.current);
+    }
+    // Paused dogears stay put.
+    {
+        state
+            .db
+            .dogears()
+            .set_paused(comic.id, user.id, true)
+            .await
+            .unwrap();
+        let resp = do_req(
+            &mut app,
+            call(&path, r#"{"url": "https://example.com/comic/26"}"#),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        state
+            .db
+            .dogears()
+            .set_paused(comic.id, user.id, false)
+            .await
+            .unwrap();
+    }
+    // A new URL replaces the old one.
+    let path = {
+        let resp = do_req(&mut app, create(comic.id, &user.manage_token)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let new_path = hook_path(&body_bytes(resp).await);
+        let body = r#"{"url": "https://example.com/comic/26"}"#;
+        let resp = do_req(&mut app, call(&path, body)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = do_req(&mut app, call(&new_path, body)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        new_path
+    };
+    // Deleting turns it off.
+    {
+        let delete = || {
+            new_req("DELETE", format!("/api/v1/dogear/{}/hook", comic.id))
+                .json()
+                .token(&user.manage_token)
+                .empty()
+        };
+        let resp = do_req(&mut app, delete()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = do_req(
+            &mut app,
+            call(&path, r#"{"url": "https://example.com/comic/27"}"#),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = do_req(&mut app, delete()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+    // Rate limited per hook.
+    {
+        let resp = do_req(&mut app, create(serial.id, &user.manage_token)).await;
+        let path = hook_path(&body_bytes(resp).await);
+        for _ in 0..12 {
+            let resp = do_req(
+                &mut app,
+                call(&path, r#"{"url": "https://example.com/serial/5"}"#),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = do_req(
+            &mut app,
+            call(&path, r#"{"url": "https://example.com/serial/6"}"#),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
+
+#[tokio::test]
+async fn api_feeds_test() {
+    let state = test_state_with_config(|c| c.feed_polling = true).await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let other = state.db.test_user("someone_else").await.unwrap();
+    let (dogears, _) = state.db.dogears().list(user.id, 1, 50).await.unwrap();
+    let comic = dogears
+        .iter()
+        .find(|d| d.prefix == "example.com/comic")
+        .unwrap();
+    let (theirs, _) = state.db.dogears().list(other.id, 1, 50).await.unwrap();
+
+    let set = |id: i64, token: &str, body: &'static str| {
+        new_req("PUT", format!("/api/v1/dogear/{}/feed", id))
+            .json()
+            .token(token)
+            .body(body.into())
+            .unwrap()
+    };
+    let feed_body = r#"{"feed_url": "https://example.com/comic/feed.xml"}"#;
+
+    // Setting one takes a manage token, your own dogear, and a real URL.
+    {
+        assert_api_auth_required(
+            &mut app,
+            "PUT",
+            format!("/api/v1/dogear/{}/feed", comic.id),
+            Some(feed_body.into()),
+        )
+        .await;
+        let resp = do_req(&mut app, set(comic.id, &user.write_token, feed_body)).await;
+        assert_api_insufficient_permissions(resp).await;
+        let resp = do_req(&mut app, set(theirs[0].id, &user.manage_token, feed_body)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = do_req(
+            &mut app,
+            set(comic.id, &user.manage_token, r#"{"feed_url": "feed.xml"}"#),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+    let resp = do_req(
+        &mut app,
+        set(
+            comic.id,
+            &user.manage_token,
+            r#"{"feed_url": "https://example.com/comic/feed.xml", "auto_advance": true}"#,
+        ),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let feed: DogearFeed = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+    assert_eq!(feed.dogear_id, comic.id);
+    assert!(feed.auto_advance);
+
+    // Flagged chapters show up on the list until the dogear moves.
+    let frag = || {
+        new_req("GET", "/fragments/dogears")
+            .session(&user.session_id)
+            .empty()
+    };
+    {
+        let resp = do_req(&mut app, frag()).await;
+        let doc = bytes_frag(&body_bytes(resp).await);
+        assert!(doc.has(&format!(
+            ".set-feed[data-dogear-id='{}'][data-auto-advance='true']",
+            comic.id
+        )));
+        assert!(!doc.has(".new-chapter-badge"));
+        state
+            .db
+            .feeds()
+            .record_check(
+                comic.id,
+                Some("https://example.com/comic/25"),
+                Some("https://example.com/comic/25"),
+                "+60 minutes",
+            )
+            .await
+            .unwrap();
+        // Needs to be later than the dogear's own updated time.
+        sqlx::query("UPDATE dogear_feeds SET new_chapter_found = datetime('now', '+1 minutes');")
+            .execute(&state.db.write_pool)
+            .await
+            .unwrap();
+        let resp = do_req(&mut app, frag()).await;
+        let doc = bytes_frag(&body_bytes(resp).await);
+        assert!(doc.has(".new-chapter-badge[href='https://example.com/comic/25']"));
+        state
+            .db
+            .dogears()
+            .update_one(comic.id, user.id, "https://example.com/comic/25", None)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE dogears SET updated = datetime('now', '+2 minutes');")
+            .execute(&state.db.write_pool)
+            .await
+            .unwrap();
+        let resp = do_req(&mut app, frag()).await;
+        let doc = bytes_frag(&body_bytes(resp).await);
+        assert!(!doc.has(".new-chapter-badge"));
+    }
+
+    // Deleting stops it.
+    {
+        let delete = || {
+            new_req("DELETE", format!("/api/v1/dogear/{}/feed", comic.id))
+                .json()
+                .token(&user.manage_token)
+                .empty()
+        };
+        let resp = do_req(&mut app, delete()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(state.db.feeds().for_user(user.id).await.unwrap().is_empty());
+        let resp = do_req(&mut app, delete()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    // Not on sites that don't watch feeds.
+    {
+        let state = test_state().await;
+        let mut app = eardogger_app(state.clone());
+        let user = state.db.test_user("whoever").await.unwrap();
+        let (dogears, _) = state.db.dogears().list(user.id, 1, 50).await.unwrap();
+        let resp = do_req(&mut app, set(dogears[0].id, &user.manage_token, feed_body)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = do_req(
+            &mut app,
+            new_req("GET", "/fragments/dogears")
+                .session(&user.session_id)
+                .empty(),
+        )
+        .await;
+        let doc = bytes_frag(&body_bytes(resp).await);
+        assert!(!doc.has(".set-feed"));
+    }
+}
+
+/// KOReader's sync protocol: header auth with the md5 of a kosync token,
+/// and every synced book gets a dogear.
+#[tokio::test]
+async fn kosync_progress_test() {
+    use crate::db::TokenScope;
+    use crate::util::md5sum;
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let user_id = state
+        .db
+        .users()
+        .by_name(&user.name)
+        .await
+        .unwrap()
+        .unwrap()
+        .id;
+    let (_, password) = state
+        .db
+        .tokens()
+        .create(user_id, TokenScope::Kosync, None)
+        .await
+        .unwrap();
+    let key = md5sum(&password);
+    let kosync = |method: &str, uri: &str, username: &str, key: &str| {
+        new_req(method, uri)
+            .header(header::ACCEPT, "application/vnd.koreader.v1+json")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("x-auth-user", username)
+            .header("x-auth-key", key)
+    };
+    let json_body = |b: Bytes| -> serde_json::Value { serde_json::from_slice(&b).unwrap() };
+
+    // Login works with the md5'd password...
+    {
+        let req = kosync("GET", "/kosync/users/auth", &user.name, &key).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(json_body(body_bytes(resp).await)["authorized"], "OK");
+    }
+    // ...but not the raw password, the wrong username, other kinds of
+    // tokens, or nothing.
+    for (username, key) in [
+        (user.name.as_str(), password.as_str()),
+        ("someone_else", key.as_str()),
+        (user.name.as_str(), md5sum(&user.write_token).as_str()),
+        (user.name.as_str(), user.write_token.as_str()),
+    ] {
+        let req = kosync("GET", "/kosync/users/auth", username, key).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(json_body(body_bytes(resp).await)["code"], 2001);
+    }
+    {
+        let req = new_req("GET", "/kosync/users/auth").empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    // And kosync tokens can't touch the regular API.
+    {
+        let req = new_req("GET", "/api/v1/list").json().token(&key).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_api_insufficient_permissions(resp).await;
+    }
+    // No signups through KOReader.
+    {
+        let body = r#"{"username": "newbie", "password": "abc"}"#;
+        let req = new_req("POST", "/kosync/users/create")
+            .json()
+            .body(body.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(json_body(body_bytes(resp).await)["message"]
+            .as_str()
+            .unwrap()
+            .contains("install page"));
+    }
+
+    // Nothing synced yet: empty object.
+    let get_uri = "/kosync/syncs/progress/0123456789abcdef0123456789abcdef";
+    {
+        let req = kosync("GET", get_uri, &user.name, &key).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(json_body(body_bytes(resp).await), serde_json::json!({}));
+    }
+    // First sync makes a dogear...
+    let put = |percentage: f64| {
+        format!(
+            r#"{{"document": "0123456789abcdef0123456789abcdef", "progress": "/body/DocFragment[12]/body/p[3]/text().0", "percentage": {}, "device": "Kobo", "device_id": "ABC123"}}"#,
+            percentage
+        )
+    };
+    {
+        let req = kosync("PUT", "/kosync/syncs/progress", &user.name, &key)
+            .body(put(0.25).into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let result = json_body(body_bytes(resp).await);
+        assert_eq!(result["document"], "0123456789abcdef0123456789abcdef");
+        assert!(result["timestamp"].as_i64().unwrap() > 0);
+    }
+    let book_dogears = || {
+        let state = &state;
+        async move {
+            state
+                .db
+                .dogears()
+                .list(user_id, 1, 50)
+                .await
+                .unwrap()
+                .0
+                .into_iter()
+                .filter(|d| d.prefix.contains("/kosync/books/"))
+                .collect::<Vec<_>>()
+        }
+    };
+    {
+        let dogears = book_dogears().await;
+        assert_eq!(dogears.len(), 1);
+        assert_eq!(
+            dogears[0].prefix,
+            "eardogger.com/kosync/books/0123456789abcdef0123456789abcdef"
+        );
+        assert!(dogears[0].current.ends_with("?percent=25"));
+    }
+    // ...and later ones move it instead of making more.
+    {
+        let req = kosync("PUT", "/kosync/syncs/progress", &user.name, &key)
+            .body(put(0.5).into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let dogears = book_dogears().await;
+        assert_eq!(dogears.len(), 1);
+        assert!(dogears[0].current.ends_with("?percent=50"));
+    }
+    // GET hands back exactly what came in.
+    {
+        let req = kosync("GET", get_uri, &user.name, &key).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let record = json_body(body_bytes(resp).await);
+        assert_eq!(
+            record["progress"],
+            "/body/DocFragment[12]/body/p[3]/text().0"
+        );
+        assert_eq!(record["percentage"], 0.5);
+        assert_eq!(record["device"], "Kobo");
+        assert_eq!(record["device_id"], "ABC123");
+    }
+    // Fixed-layout books send page numbers, which round-trip as strings.
+    {
+        let body = r#"{"document": "pdfpdfpdf", "progress": 42, "percentage": 0.1, "device": "Kobo", "device_id": "ABC123"}"#;
+        let req = kosync("PUT", "/kosync/syncs/progress", &user.name, &key)
+            .body(body.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = kosync("GET", "/kosync/syncs/progress/pdfpdfpdf", &user.name, &key).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(json_body(body_bytes(resp).await)["progress"], "42");
+    }
+    // Bad bodies
+    for (body, code) in [
+        (r#"{"progress": "x", "percentage": 0.1}"#, 2004),
+        (
+            r#"{"document": "", "progress": "x", "percentage": 0.1}"#,
+            2004,
+        ),
+        (r#"{"document": "abc", "percentage": 0.1}"#, 2003),
+        (
+            r#"{"document": "abc", "progress": "x", "percentage": 7}"#,
+            2003,
+        ),
+        ("not json", 2003),
+    ] {
+        let req = kosync("PUT", "/kosync/syncs/progress", &user.name, &key)
+            .body(body.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", body);
+        assert_eq!(json_body(body_bytes(resp).await)["code"], code, "{}", body);
+    }
+    // Deleting the dogear doesn't lose the position, and the next sync
+    // makes a fresh dogear.
+    {
+        let old = book_dogears().await;
+        state
+            .db
+            .dogears()
+            .destroy(old[0].id, user_id)
+            .await
+            .unwrap();
+        let req = kosync("GET", get_uri, &user.name, &key).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(json_body(body_bytes(resp).await)["percentage"], 0.5);
+        let req = kosync("PUT", "/kosync/syncs/progress", &user.name, &key)
+            .body(put(0.75).into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let dogears = book_dogears().await;
+        assert_eq!(dogears.len(), 1);
+        assert_ne!(dogears[0].id, old[0].id);
+    }
+}
+
+#[tokio::test]
+async fn api_update_notes_and_history_test() {
+    use crate::db::{Dogear, DogearHistoryEntry};
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+
+    let update = |body: &'static str| {
+        new_req("POST", "/api/v1/update")
+            .json()
+            .token(&user.write_token)
+            .body(body.into())
+            .unwrap()
+    };
+
+    // Update with a note
+    let id = {
+        let req =
+            update(r#"{"current": "https://example.com/comic/25", "note": "stopped mid-scene"}"#);
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let updated: Vec<Dogear> = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        updated[0].id
+    };
+    // And without one; blank counts as none
+    {
+        let req = update(r#"{"current": "https://example.com/comic/26", "note": "  "}"#);
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    // Too long is a 400
+    {
+        let body = serde_json::json!({
+            "current": "https://example.com/comic/27",
+            "note": "x".repeat(500),
+        })
+        .to_string();
+        let req = new_req("POST", "/api/v1/update")
+            .json()
+            .token(&user.write_token)
+            .body(body.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(api_error_body(resp).await.unwrap().error.contains("200"));
+    }
+
+    // History needs manage
+    let uri = format!("/api/v1/dogear/{}/history", id);
+    {
+        let req = new_req("GET", &uri).token(&user.write_token).empty();
+        assert_api_insufficient_permissions(do_req(&mut app, req).await).await;
+    }
+    {
+        let req = new_req("GET", &uri).token(&user.manage_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let history: Vec<DogearHistoryEntry> =
+            serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].url, "https://example.com/comic/26");
+        assert_eq!(history[0].note, None);
+        assert_eq!(history[1].note.as_deref(), Some("stopped mid-scene"));
+    }
+    // 404 for someone else's
+    {
+        let other = state.db.test_user("someone_else").await.unwrap();
+        let req = new_req("GET", &uri).token(&other.manage_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
+
+#[tokio::test]
+async fn api_migrate_test() {
+    // The instance we're moving to, running for real on a local port.
+    let target_state = test_state().await;
+    let newcomer = target_state
+        .db
+        .users()
+        .create("newcomer", "password123", None)
+        .await
+        .unwrap();
+    // They already have one of the dogears we're bringing.
+    target_state
+        .db
+        .dogears()
+        .create(
+            newcomer.id,
+            "example.com/comic",
+            "https://example.com/comic/1",
+            None,
+        )
+        .await
+        .unwrap();
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+        .await
+        .unwrap();
+    let target_url = format!("http://{}/", listener.local_addr().unwrap());
+    let target_app = eardogger_app(target_state.clone());
+    let target_server = tokio::spawn(async move { axum::serve(listener, target_app).await });
+
+    // The instance we're leaving.
+    let state = test_state_with_config(|c| c.migrate_out = true).await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let user_id = state
+        .db
+        .users()
+        .by_name(&user.name)
+        .await
+        .unwrap()
+        .unwrap()
+        .id;
+    let paused = state
+        .db
+        .dogears()
+        .create(
+            user_id,
+            "example.com/paused",
+            "https://example.com/paused/3",
+            Some("On hold"),
+        )
+        .await
+        .unwrap();
+    state
+        .db
+        .dogears()
+        .set_paused(paused.id, user_id, true)
+        .await
+        .unwrap();
+
+    let migrate = |token: &str, target: &str, code: &str| {
+        let body = serde_json::json!({"target": target, "code": code}).to_string();
+        new_req("POST", "/api/v1/migrate_out")
+            .json()
+            .token(token)
+            .body(body.into())
+            .unwrap()
+    };
+    let progress = |body: &Bytes| -> Vec<ApiMigrationProgress> {
+        bytes_str(body)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    };
+
+    // 401 when logged out, 403 with a write token
+    {
+        assert_api_auth_required(&mut app, "POST", "/api/v1/migrate_out", None).await;
+        let req = migrate(&user.write_token, &target_url, "whatever");
+        let resp = do_req(&mut app, req).await;
+        assert_api_insufficient_permissions(resp).await;
+    }
+    // 400 for a target that isn't a URL, or a missing code
+    {
+        let req = migrate(&user.manage_token, "not a url", "whatever");
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let req = migrate(&user.manage_token, &target_url, "  ");
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+    // A bad code gets as far as the other instance, which turns it down.
+    {
+        let req = migrate(&user.manage_token, &target_url, "eardoggermove1.nope");
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let lines = progress(&body_bytes(resp).await);
+        assert_eq!(
+            lines[0],
+            ApiMigrationProgress::Started {
+                target: format!("{}api/v1/migrate_in", target_url)
+            }
+        );
+        assert!(matches!(
+            lines[1],
+            ApiMigrationProgress::Packaged { dogears: 3, .. }
+        ));
+        let ApiMigrationProgress::Failed { error } = &lines[2] else {
+            panic!("expected a failure, got {:?}", lines[2]);
+        };
+        assert!(error.contains("403"));
+        assert_eq!(lines.len(), 3);
+    }
+    // A good code moves everything over, once.
+    {
+        let code = target_state
+            .db
+            .migration_codes()
+            .create(newcomer.id)
+            .await
+            .unwrap();
+        let req = migrate(&user.manage_token, &target_url, &code);
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/x-ndjson"
+        );
+        let lines = progress(&body_bytes(resp).await);
+        assert_eq!(
+            lines.last().unwrap(),
+            &ApiMigrationProgress::Done {
+                report: ApiImportReport {
+                    entries: 3,
+                    created: 2,
+                    existing: 1,
+                    invalid: 0,
+                }
+            }
+        );
+        let (moved, _) = target_state
+            .db
+            .dogears()
+            .list(newcomer.id, 1, 50)
+            .await
+            .unwrap();
+        assert_eq!(moved.len(), 3);
+        let on_hold = moved
+            .iter()
+            .find(|d| d.prefix == "example.com/paused")
+            .unwrap();
+        assert!(on_hold.paused);
+        assert_eq!(on_hold.display_name.as_deref(), Some("On hold"));
+        // Nothing changed back home.
+        let (home, _) = state.db.dogears().list(user_id, 1, 50).await.unwrap();
+        assert_eq!(home.len(), 3);
+
+        // The code's used up now.
+        let req = migrate(&user.manage_token, &target_url, &code);
+        let resp = do_req(&mut app, req).await;
+        let lines = progress(&body_bytes(resp).await);
+        assert!(matches!(
+            lines.last().unwrap(),
+            ApiMigrationProgress::Failed { .. }
+        ));
+    }
+    // Receiving end: no code, or an unreadable export, gets turned away
+    // without using up a good code.
+    {
+        let mut target = eardogger_app(target_state.clone());
+        let req = new_req("POST", "/api/v1/migrate_in")
+            .body(Body::from("{}\n"))
+            .unwrap();
+        let resp = do_req(&mut target, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let code = target_state
+            .db
+            .migration_codes()
+            .create(newcomer.id)
+            .await
+            .unwrap();
+        let req = new_req("POST", "/api/v1/migrate_in")
+            .header("eardogger-migration-code", &code)
+            .body(Body::from("this is not json\n"))
+            .unwrap();
+        let resp = do_req(&mut target, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let req = new_req("POST", "/api/v1/migrate_in")
+            .header("eardogger-migration-code", &code)
+            .body(Body::empty())
+            .unwrap();
+        let resp = do_req(&mut target, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_bytes(resp).await;
+        let report: ApiImportReport = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report, ApiImportReport::default());
+    }
+    // Off by default.
+    {
+        let state = test_state().await;
+        let mut app = eardogger_app(state.clone());
+        let user = state.db.test_user("stayer").await.unwrap();
+        let req = migrate(&user.manage_token, &target_url, "whatever");
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+    target_server.abort();
+}
+
+#[cfg(feature = "dev_tools")]
+#[tokio::test]
+async fn dev_tools_test() {
+    use eardogger_rs::api_types::{ApiDevSession, ApiDevUser};
+
+    let post = |uri: &'static str, body: &str| {
+        new_req("POST", uri)
+            .json()
+            .body(body.to_string().into())
+            .unwrap()
+    };
+
+    // Off by default, and looks like it isn't there.
+    {
+        let state = test_state().await;
+        let mut app = eardogger_app(state.clone());
+        let resp = do_req(&mut app, post("/dev/users", "{}")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(state.db.users().by_name("dev").await.unwrap().is_none());
+    }
+
+    let state = test_state_with_config(|c| c.dev_tools = true).await;
+    let mut app = eardogger_app(state.clone());
+
+    // Users: random name if you don't pick one, and their creds work.
+    {
+        let resp = do_req(&mut app, post("/dev/users", "{}")).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let user: ApiDevUser = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert!(user.name.starts_with("dev-"));
+        assert_eq!(user.password, Db::TEST_PASSWORD);
+        let req = new_req("GET", "/api/v1/list")
+            .json()
+            .token(&user.write_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let list: ApiDogearsList = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(list.data.len(), 2);
+    }
+    {
+        let resp = do_req(&mut app, post("/dev/users", r#"{"name": "dev"}"#)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let user: ApiDevUser = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(user.name, "dev");
+        let req = new_req("GET", "/account").session(&user.session_id).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Same name again: 409
+        let resp = do_req(&mut app, post("/dev/users", r#"{"name": "dev"}"#)).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+
+    // Dogears: as many as you ask for, within reason.
+    {
+        let body = r#"{"user": "dev", "count": 5, "prefix": "example.com/synth/"}"#;
+        let resp = do_req(&mut app, post("/dev/dogears", body)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let made: Vec<Dogear> = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(made.len(), 5);
+        assert_eq!(made[4].prefix, "example.com/synth/5");
+        assert_eq!(made[4].current, "https://example.com/synth/5/1");
+
+        let body = r#"{"user": "dev", "count": 1001}"#;
+        let resp = do_req(&mut app, post("/dev/dogears", body)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = r#"{"user": "nobody", "count": 1}"#;
+        let resp = do_req(&mut app, post("/dev/dogears", body)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    // Sessions: a working login.
+    {
+        let resp = do_req(&mut app, post("/dev/sessions", r#"{"user": "dev"}"#)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let session: ApiDevSession = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        let req = new_req("GET", "/account")
+            .session(&session.session_id)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = do_req(&mut app, post("/dev/sessions", r#"{"user": "nobody"}"#)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
+
+#[cfg(not(feature = "dev_tools"))]
+#[tokio::test]
+async fn dev_routes_need_feature_test() {
+    // Without the feature compiled in, the config can't turn any of the
+    // write routes on.
+    let state = test_state_with_config(|c| c.dev_tools = true).await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let post = |uri: &'static str, body: &str| {
+        new_req("POST", uri)
+            .json()
+            .body(body.to_string().into())
+            .unwrap()
+    };
+    let resp = do_req(&mut app, post("/dev/users", r#"{"name": "dev"}"#)).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert!(state.db.users().by_name("dev").await.unwrap().is_none());
+
+    let body = r#"{"user": "whoever", "count": 3}"#;
+    let resp = do_req(&mut app, post("/dev/dogears", body)).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let (dogears, _) = state.db.dogears().list(user.id, 1, 50).await.unwrap();
+    assert_eq!(dogears.len(), 2);
+
+    let resp = do_req(&mut app, post("/dev/sessions", r#"{"user": "whoever"}"#)).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let (sessions, _) = state.db.sessions().list(user.id, 1, 50).await.unwrap();
+    assert_eq!(sessions.len(), 1);
+}
+
+#[tokio::test]
+async fn api_cors_test() {
+    let preflight = |uri: &str, origin: &str, method: &str| {
+        new_req("OPTIONS", uri)
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+            .header(
+                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                "authorization,content-type",
+            )
+            .empty()
+    };
+    let allowed_origin = |resp: &Response<Body>| {
+        resp.headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|v| v.to_str().unwrap().to_string())
+    };
+
+    // Defaults: update is open to everybody but ourselves...
+    {
+        let state = test_state().await;
+        let mut app = eardogger_app(state.clone());
+        let user = state.db.test_user("whoever").await.unwrap();
+
+        let resp = do_req(
+            &mut app,
+            preflight("/api/v1/update", "https://example.com", "POST"),
+        )
+        .await;
+        assert!(resp.status().is_success());
+        assert_eq!(allowed_origin(&resp).unwrap(), "https://example.com");
+        let headers = resp.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "POST,PUT");
+        let allow_headers = headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap()
+            .to_lowercase();
+        assert!(allow_headers.contains("authorization"));
+        assert!(allow_headers.contains("content-type"));
+        // No cookies cross-origin.
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        assert!(headers[header::VARY]
+            .to_str()
+            .unwrap()
+            .to_lowercase()
+            .contains("origin"));
+
+        // The real thing gets the header too.
+        let req = new_req("POST", "/api/v1/update")
+            .json()
+            .token(&user.write_token)
+            .header(header::ORIGIN, "https://example.com")
+            .body(r#"{"current": "https://example.com/comic/40"}"#.into())
+            .unwrap();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(allowed_origin(&resp).unwrap(), "https://example.com");
+
+        // Same-origin isn't CORS.
+        let own = state.config.public_url.origin().ascii_serialization();
+        let resp = do_req(&mut app, preflight("/api/v1/update", &own, "POST")).await;
+        assert!(allowed_origin(&resp).is_none());
+
+        // ...and the rest of the API is open to nobody.
+        for (uri, method) in [
+            ("/api/v1/list", "GET"),
+            ("/api/v1/create", "POST"),
+            ("/api/v1/dogear/1", "DELETE"),
+        ] {
+            let resp = do_req(&mut app, preflight(uri, "https://example.com", method)).await;
+            assert!(allowed_origin(&resp).is_none());
+            assert_no_cors(&resp);
+        }
+    }
+
+    // Configured: update locked down to one site, and the API opened up.
+    {
+        let state = test_state_with_config(|c| {
+            c.cors.update.origins = vec!["https://example.com".to_string()];
+            c.cors.api.origins = vec!["https://app.example.org".to_string()];
+            c.cors.api.methods = vec!["GET".to_string()];
+        })
+        .await;
+        let mut app = eardogger_app(state.clone());
+        let user = state.db.test_user("whoever").await.unwrap();
+
+        let resp = do_req(
+            &mut app,
+            preflight("/api/v1/update", "https://example.com", "POST"),
+        )
+        .await;
+        assert_eq!(allowed_origin(&resp).unwrap(), "https://example.com");
+        let resp = do_req(
+            &mut app,
+            preflight("/api/v1/update", "https://example.horse", "POST"),
+        )
+        .await;
+        assert!(allowed_origin(&resp).is_none());
+
+        let resp = do_req(
+            &mut app,
+            preflight("/api/v1/list", "https://app.example.org", "GET"),
+        )
+        .await;
+        assert_eq!(allowed_origin(&resp).unwrap(), "https://app.example.org");
+        assert_eq!(resp.headers()[header::ACCESS_CONTROL_ALLOW_METHODS], "GET");
+        let req = new_req("GET", "/api/v1/list")
+            .json()
+            .token(&user.manage_token)
+            .header(header::ORIGIN, "https://app.example.org")
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(allowed_origin(&resp).unwrap(), "https://app.example.org");
+        // Other sites still can't.
+        let resp = do_req(
+            &mut app,
+            preflight("/api/v1/list", "https://example.com", "GET"),
+        )
+        .await;
+        assert!(allowed_origin(&resp).is_none());
+    }
+}
+
+#[tokio::test]
+async fn admin_features_test() {
+    use crate::config::SiteRulesConfig;
+    use std::collections::BTreeMap;
+
+    let admin_token = "an admin token that's long enough to count";
+    let state = test_state_with_config(|c| {
+        c.site_rules = Some(SiteRulesConfig {
+            admin_token: admin_token.to_string(),
+            trusted_keys: Vec::new(),
+        });
+        c.features = BTreeMap::from([
+            ("queue_mode".to_string(), 0),
+            ("sse_refresh".to_string(), 100),
+        ]);
+    })
+    .await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+    let closure_cloneable = app.clone(); // so we can mutably borrow `app` in other test cases.
+    let page_features = |sessid: String| {
+        let mut app = closure_cloneable.clone();
+        async move {
+            let req = new_req("GET", "/account").session(&sessid).empty();
+            let resp = do_req(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let doc = bytes_doc(&body_bytes(resp).await);
+            doc.select(&sel("body"))
+                .next()
+                .unwrap()
+                .value()
+                .attr("data-features")
+                .map(str::to_string)
+        }
+    };
+    let put = |uri: &str, rollout: u32| {
+        new_req("PUT", uri)
+            .json()
+            .token(admin_token)
+            .body(format!(r#"{{"rollout": {}}}"#, rollout).into())
+            .unwrap()
+    };
+
+    // Straight from the config
+    assert_eq!(
+        page_features(user.session_id.clone()).await.as_deref(),
+        Some("sse_refresh")
+    );
+    {
+        let req = new_req("GET", "/admin/features").empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = new_req("GET", "/admin/features").token(admin_token).empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let flags: serde_json::Value = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(flags[0]["name"], "queue_mode");
+        assert_eq!(flags[0]["rollout"], 0);
+        assert!(flags[0]["overridden"].is_null());
+        assert_eq!(flags[1]["rollout"], 100);
+    }
+
+    // Turned on live
+    {
+        let resp = do_req(&mut app, put("/admin/features/queue_mode", 100)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let flag: serde_json::Value = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert_eq!(flag["configured"], 0);
+        assert_eq!(flag["overridden"], 100);
+        assert_eq!(flag["rollout"], 100);
+        assert_eq!(
+            page_features(user.session_id.clone()).await.as_deref(),
+            Some("queue_mode sse_refresh")
+        );
+    }
+
+    // No such flag, or no such percentage
+    {
+        let resp = do_req(&mut app, put("/admin/features/queue_mood", 100)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = do_req(&mut app, put("/admin/features/queue_mode", 101)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // Back to the config, then all the way off
+    {
+        let req = new_req("DELETE", "/admin/features/queue_mode")
+            .token(admin_token)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let flag: serde_json::Value = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+        assert!(flag["overridden"].is_null());
+        assert_eq!(flag["rollout"], 0);
+
+        let resp = do_req(&mut app, put("/admin/features/sse_refresh", 0)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(page_features(user.session_id.clone()).await, None);
+    }
+}
+
+#[tokio::test]
+async fn api_changelog_test() {
+    use eardogger_rs::api_types::ApiChangelog;
+
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+
+    // No login needed, and readable cross-origin.
+    let req = new_req("GET", "/api/changelog")
+        .header(header::ORIGIN, "https://example.com")
+        .empty();
+    let resp = do_req(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    // Nothing's deprecated yet, so no headers about it.
+    assert!(resp.headers().get("deprecation").is_none());
+    let body = body_bytes(resp).await;
+    let changelog: ApiChangelog = serde_json::from_slice(&body).unwrap();
+    assert_eq!(changelog.version, "v1");
+    assert!(!changelog.changes.is_empty());
+    // Newest first.
+    assert!(changelog
+        .changes
+        .windows(2)
+        .all(|pair| pair[0].date > pair[1].date));
+}
+
+#[tokio::test]
+async fn dogear_event_schema_test() {
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+
+    let req = new_req("GET", "/api/schemas/dogear-event.v1.json")
+        .header(header::ORIGIN, "https://example.com")
+        .empty();
+    let resp = do_req(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    assert_eq!(
+        resp.headers()[header::CONTENT_TYPE],
+        "application/schema+json"
+    );
+    let schema: serde_json::Value = serde_json::from_slice(&body_bytes(resp).await).unwrap();
+    assert_eq!(schema["properties"]["version"]["const"], 1);
+}
+
+/// Responses from deprecated routes say so, and nothing else changes.
+#[tokio::test]
+async fn deprecation_headers_test() {
+    use crate::app::deprecations::Deprecation;
+    use time::macros::date;
+
+    static TEST_DEPRECATIONS: &[Deprecation] = &[Deprecation {
+        method: "GET",
+        path: "/api/v0/thing/:id",
+        deprecated: date!(2026 - 01 - 01),
+        sunset: Some(date!(2030 - 01 - 01)),
+        successor: Some("/api/v1/things"),
+        note: "Use the v1 list instead.",
+    }];
+    let public_url = Url::parse("http://eardogger.com").unwrap();
+    let mut app: Router = Router::new()
+        .route(
+            "/api/v0/thing/:id",
+            get(|| async { "old" }).post(|| async { "still fine" }),
+        )
+        .route("/api/v1/things", get(|| async { "new" }))
+        .layer(from_fn_with_state(
+            DeprecationTable::new(TEST_DEPRECATIONS, &public_url),
+            deprecation_middleware,
+        ));
+
+    let resp = do_req(&mut app, new_req("GET", "/api/v0/thing/5").empty()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let headers = resp.headers();
+    assert_eq!(headers["deprecation"], "@1767225600");
+    assert_eq!(headers["sunset"], "Tue, 01 Jan 2030 00:00:00 GMT");
+    assert_eq!(
+        headers[header::LINK],
+        "<http://eardogger.com/api/changelog>; rel=\"deprecation\"; type=\"application/json\", \
+        <http://eardogger.com/api/v1/things>; rel=\"successor-version\""
+    );
+    assert_eq!(bytes_str(&body_bytes(resp).await), "old");
+
+    // Same path, other method: not deprecated.
+    let resp = do_req(&mut app, new_req("POST", "/api/v0/thing/5").empty()).await;
+    assert!(resp.headers().get("deprecation").is_none());
+    // Other route, ditto.
+    let resp = do_req(&mut app, new_req("GET", "/api/v1/things").empty()).await;
+    assert!(resp.headers().get("deprecation").is_none());
+    assert!(resp.headers().get(header::LINK).is_none());
 }
@@ -0,0 +1,479 @@
+//! End-to-end tests: boot the real app on an ephemeral port and talk to it
+//! over actual HTTP with the API client (plus a raw reqwest client, for
+//! the browser-ish CORS stuff the API client has no reason to do). Unlike
+//! the rest of the app tests, this uses a temp-file database with the same
+//! split read/write pools as production, instead of the one-connection
+//! in-memory db.
+//!
+//! These only build with the `client` feature: `cargo test --features client`.
+
+use eardogger_rs::api_types::ApiCreatePayload;
+use eardogger_rs::client::{Client, ClientError};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use super::*;
+use crate::db::{Db, TestUser};
+
+/// A live server plus the stuff keeping it alive. Call `shutdown` at the
+/// end of a test to stop serving and close the db.
+struct TestServer {
+    base_url: String,
+    state: DogState,
+    server: JoinHandle<std::io::Result<()>>,
+    // Holds the db file; deletes it on drop.
+    _dir: TempDir,
+}
+
+impl TestServer {
+    async fn spawn() -> Self {
+        Self::spawn_with_config(|_| {}).await
+    }
+
+    /// Like spawn, but lets you tweak the config first.
+    async fn spawn_with_config(tweak: impl FnOnce(&mut DogConfig)) -> Self {
+        let mut config = DogConfig::test_config().unwrap();
+        tweak(&mut config);
+        let dir = tempfile::tempdir().unwrap();
+        let db_file = dir.path().join("e2e.db");
+        // An empty file is a valid empty sqlite db, and our pool options don't
+        // create missing files.
+        std::fs::File::create(&db_file).unwrap();
+        let read_pool = crate::db_pool(&db_file, 4, None).await.unwrap();
+        let write_pool = crate::db_pool(&db_file, 1, None).await.unwrap();
+        let task_tracker = TaskTracker::new();
+        let db = Db::new(read_pool, write_pool, task_tracker.clone());
+        db.migrations().run().await.unwrap();
+
+        let cancel_token = CancellationToken::new();
+        let checkers = Checkers {
+            pwned_checker: PwnedChecker::with_source(StubRange::new(&[])),
+            mx_checker: MxChecker::with_source(StubMx::new(&[])),
+            signup_guard: SignupGuard::with_source(&config.signup, StubCaptcha::new(&[])),
+        };
+        let inner = DSInner::new(
+            db,
+            config,
+            load_templates().unwrap(),
+            tower_cookies::Key::generate(),
+            task_tracker,
+            cancel_token.clone(),
+            checkers,
+        )
+        .unwrap();
+        let state: DogState = Arc::new(inner);
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let app = eardogger_app(state.clone());
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(cancel_token.cancelled_owned())
+                .await
+        });
+
+        Self {
+            base_url,
+            state,
+            server,
+            _dir: dir,
+        }
+    }
+
+    async fn test_user(&self, name: &str) -> TestUser {
+        self.state.db.test_user(name).await.unwrap()
+    }
+
+    fn client(&self, token: &str) -> Client {
+        Client::new(&self.base_url, token).unwrap()
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", &self.base_url, path)
+    }
+
+    async fn shutdown(self) {
+        self.state.cancel_token.cancel();
+        self.server.await.unwrap().unwrap();
+        self.state.task_tracker.close();
+        self.state.task_tracker.wait().await;
+        self.state.db.close().await;
+    }
+}
+
+/// Panics unless the client call failed with the expected status.
+fn assert_api_status<T: std::fmt::Debug>(res: Result<T, ClientError>, status: StatusCode) {
+    match res {
+        Err(e) => assert_eq!(e.status(), Some(status), "wrong error: {}", e),
+        Ok(v) => panic!("expected {} error, got success: {:?}", status, v),
+    }
+}
+
+#[tokio::test]
+async fn e2e_auth() {
+    let server = TestServer::spawn().await;
+    let user = server.test_user("whoever").await;
+
+    // Manage token can do everything
+    let manage = server.client(&user.manage_token);
+    let list = manage.list(1, 50).await.expect("manage token can list");
+    assert_eq!(list.data.len(), 2);
+
+    // Write token can't list or delete...
+    let write = server.client(&user.write_token);
+    assert_api_status(write.list(1, 50).await, StatusCode::FORBIDDEN);
+    assert_api_status(write.delete(list.data[0].id).await, StatusCode::FORBIDDEN);
+    // ...but it can update.
+    let updated = write
+        .update("https://example.com/comic/25")
+        .await
+        .expect("write token can update");
+    assert_eq!(updated.len(), 1);
+
+    // Garbage token is the same as no token
+    let nobody = server.client("eardoggerv1.not-a-real-token");
+    match nobody.list(1, 50).await {
+        Err(ClientError::Api { status, message }) => {
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            // Error body came through as the server's JSON error, not raw text
+            assert!(message.contains("aren't"));
+            assert!(message.contains("(request ID "));
+        }
+        other => panic!("expected 401, got {:?}", other),
+    }
+
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn e2e_dogear_lifecycle_and_pagination() {
+    let server = TestServer::spawn().await;
+    let user = server.test_user("whoever").await;
+    let client = server.client(&user.manage_token);
+
+    // CREATE, then make sure a repeat conflicts
+    let payload = ApiCreatePayload {
+        prefix: "example.com/novel".to_string(),
+        current: "https://example.com/novel/1".to_string(),
+        display_name: Some("Example Novel".to_string()),
+    };
+    let created = client.create(&payload).await.expect("created");
+    assert_eq!(created.prefix, "example.com/novel");
+    assert_api_status(client.create(&payload).await, StatusCode::CONFLICT);
+
+    // GET it back
+    let fetched = client.get(created.id).await.expect("fetched");
+    assert_eq!(fetched.current, created.current);
+
+    // UPDATE, plus 404 for sites we haven't seen
+    let updated = client
+        .update("https://www.example.com/novel/2")
+        .await
+        .expect("updated");
+    assert_eq!(updated[0].id, created.id);
+    assert_eq!(updated[0].current, "https://www.example.com/novel/2");
+    assert_api_status(
+        client.update("https://example.com/elsewhere/1").await,
+        StatusCode::NOT_FOUND,
+    );
+
+    // PAGINATION: three dogears, one per page
+    let first = client.list(1, 1).await.expect("page 1");
+    assert_eq!(first.data.len(), 1);
+    assert_eq!(first.meta.pagination.total_count, 3);
+    assert_eq!(first.meta.pagination.total_pages, 3);
+    assert_eq!(first.meta.pagination.prev_page, None);
+    assert_eq!(first.meta.pagination.next_page, Some(2));
+    let last = client.list(3, 1).await.expect("page 3");
+    assert_eq!(last.data.len(), 1);
+    assert_eq!(last.meta.pagination.prev_page, Some(2));
+    assert_eq!(last.meta.pagination.next_page, None);
+    // Way too big
+    assert_api_status(client.list(1, 9000).await, StatusCode::BAD_REQUEST);
+
+    // DELETE, then 404 on a repeat
+    client.delete(created.id).await.expect("deleted");
+    assert_api_status(client.delete(created.id).await, StatusCode::NOT_FOUND);
+    let list = client.list(1, 50).await.expect("list");
+    assert_eq!(list.data.len(), 2);
+
+    server.shutdown().await;
+}
+
+/// Neither the instance metadata nor the changelog cares what token you've got.
+#[tokio::test]
+async fn e2e_instance_metadata() {
+    let server = TestServer::spawn().await;
+    let nobody = server.client("eardoggerv1.not-a-real-token");
+    let metadata = nobody.instance_metadata().await.expect("metadata");
+    assert_eq!(metadata.software, "eardogger-rs");
+    assert_eq!(metadata.version, env!("CARGO_PKG_VERSION"));
+    assert!(metadata.api_base.ends_with("/api/v1/"));
+    let changelog = nobody.changelog().await.expect("changelog");
+    assert_eq!(changelog.version, "v1");
+
+    server.shutdown().await;
+}
+
+/// CORS as a browser would do it: preflight, then the real request, with
+/// the Origin header set by the "page" the bookmarklet runs on.
+#[tokio::test]
+async fn e2e_update_cors() {
+    let server = TestServer::spawn().await;
+    let user = server.test_user("whoever").await;
+    let http = reqwest::Client::new();
+    let update_url = server.url("/api/v1/update");
+    let body = r#"{"current": "https://example.com/comic/30"}"#;
+
+    // Preflight from another site gets the goods
+    {
+        let resp = http
+            .request(reqwest::Method::OPTIONS, &update_url)
+            .header(header::ORIGIN, "https://example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://example.com"
+        );
+        assert_eq!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_METHODS)
+                .unwrap(),
+            "POST"
+        );
+    }
+    // Real request from the matching site
+    {
+        let resp = http
+            .post(&update_url)
+            .bearer_auth(&user.write_token)
+            .header(header::ORIGIN, "https://example.com")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://example.com"
+        );
+    }
+    // Other sites can't touch your bookmark for this one
+    {
+        let resp = http
+            .post(&update_url)
+            .bearer_auth(&user.write_token)
+            .header(header::ORIGIN, "https://example.horse")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+    // Same-origin requests don't get CORS headers at all
+    {
+        let same_origin = server
+            .state
+            .config
+            .public_url
+            .origin()
+            .ascii_serialization();
+        let resp = http
+            .post(&update_url)
+            .bearer_auth(&user.write_token)
+            .header(header::ORIGIN, same_origin)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+    }
+    // And other routes don't do CORS, even when preflighted
+    {
+        let resp = http
+            .request(reqwest::Method::OPTIONS, server.url("/api/v1/list"))
+            .header(header::ORIGIN, "https://example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .send()
+            .await
+            .unwrap();
+        assert!(!resp
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    server.shutdown().await;
+}
+
+/// Exports are streamed, so a client that hangs up partway through
+/// shouldn't wedge anything on the server side.
+#[tokio::test]
+async fn e2e_export_partial_read() {
+    let server = TestServer::spawn().await;
+    let user = server.test_user("whoever").await;
+    let client = server.client(&user.manage_token);
+    for n in 0..1100 {
+        let payload = ApiCreatePayload {
+            prefix: format!("example.com/export/{}/", n),
+            current: format!("https://example.com/export/{}/1", n),
+            display_name: None,
+        };
+        client.create(&payload).await.expect("created");
+    }
+    let http = reqwest::Client::new();
+
+    // Read one chunk, then hang up.
+    {
+        let mut resp = http
+            .get(server.url("/api/v1/export"))
+            .bearer_auth(&user.manage_token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let first = resp.chunk().await.unwrap().expect("some data");
+        assert!(!first.is_empty());
+        drop(resp);
+    }
+    // Server's still fine, and a full read gets everything.
+    {
+        let text = http
+            .get(server.url("/api/v1/export"))
+            .bearer_auth(&user.manage_token)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(text.lines().count(), 1102);
+    }
+
+    // And shutdown doesn't hang on the abandoned stream.
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn e2e_loadtest_provision() {
+    use crate::args::LoadtestArgs;
+
+    let args = |target: &str| LoadtestArgs {
+        target: target.to_string(),
+        token: None,
+        session: None,
+        provision: true,
+        clients: 2,
+        requests: 5,
+        mix: "mark=50,resume=25,list=25".to_string(),
+    };
+
+    // No dev tools, no provisioning.
+    let server = TestServer::spawn().await;
+    let err = crate::loadtest::run(args(&server.base_url))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("dev_tools"));
+    server.shutdown().await;
+
+    // With them (and a build that has the user factory), it makes its own
+    // account and runs clean.
+    #[cfg(feature = "dev_tools")]
+    {
+        let server = TestServer::spawn_with_config(|c| c.dev_tools = true).await;
+        crate::loadtest::run(args(&server.base_url)).await.unwrap();
+        server.shutdown().await;
+    }
+}
+
+#[tokio::test]
+async fn e2e_client_mark_and_resume() {
+    use crate::args::{MarkArgs, RemoteArgs};
+    use crate::remote::{find_dogear, mark};
+
+    let server = TestServer::spawn().await;
+    let user = server.test_user("whoever").await;
+    let client = server.client(&user.manage_token);
+    let args = |url: &str, create: bool, prefix: Option<&str>| MarkArgs {
+        url: url.to_string(),
+        note: None,
+        create,
+        prefix: prefix.map(String::from),
+        remote: RemoteArgs {
+            instance: None,
+            token: None,
+        },
+    };
+
+    // Marking a known spot moves it.
+    let marked = mark(&client, &args("https://example.com/comic/30", false, None))
+        .await
+        .unwrap();
+    assert_eq!(marked.len(), 1);
+    assert_eq!(marked[0].current, "https://example.com/comic/30");
+
+    // Unknown spots need --create.
+    let err = mark(&client, &args("https://example.com/novel/1", false, None))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("--create"));
+    let marked = mark(
+        &client,
+        &args(
+            "https://example.com/novel/1",
+            true,
+            Some("example.com/novel"),
+        ),
+    )
+    .await
+    .unwrap();
+    assert_eq!(marked[0].prefix, "example.com/novel");
+
+    // Resume finds the longest matching prefix, ignoring scheme and case.
+    let found = find_dogear(&client, "http://EXAMPLE.com/comic/1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(found.current, "https://example.com/comic/30");
+    mark(
+        &client,
+        &args(
+            "https://example.com/comic/extra/1",
+            true,
+            Some("example.com/comic/extra"),
+        ),
+    )
+    .await
+    .unwrap();
+    let found = find_dogear(&client, "https://example.com/comic/extra/5")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(found.prefix, "example.com/comic/extra");
+    assert!(find_dogear(&client, "https://example.com/nope")
+        .await
+        .unwrap()
+        .is_none());
+
+    // Resume needs a manage token, since it lists everything.
+    let write = server.client(&user.write_token);
+    assert!(find_dogear(&write, "https://example.com/comic/1")
+        .await
+        .is_err());
+
+    server.shutdown().await;
+}
@@ -1,6 +1,9 @@
 #![cfg(test)]
 
 mod api;
+#[cfg(feature = "client")]
+mod e2e;
+mod routes;
 mod web;
 
 use axum::body::{to_bytes, Body, Bytes};
@@ -15,6 +18,7 @@ use super::state::*;
 use super::web_result::RawJsonError;
 use super::*;
 use crate::config::DogConfig;
+use crate::util::{MxChecker, PwnedChecker, SignupGuard, StubCaptcha, StubMx, StubRange};
 
 // Right, here's the ground rules for tests in this module. We're taking as
 // axiomatic that DB methods like Dogears::destroy work as advertised, bc
@@ -26,21 +30,87 @@ use crate::config::DogConfig;
 
 // SHORTCUTS FOR MAKING THINGS
 
+/// The one password test states' stubbed breach list knows about. Strong
+/// enough to get past zxcvbn, so only the breach check turns it away.
+const PWNED_TEST_PASSWORD: &str = "lantern-quokka-saffron-74";
+
+/// The one domain test states' stubbed resolver says takes mail.
+const MX_TEST_DOMAIN: &str = "example.com";
+
+/// The one captcha answer test states' stubbed provider accepts.
+const CAPTCHA_TEST_RESPONSE: &str = "a-real-person";
+
 async fn test_state() -> DogState {
+    test_state_with_config(|_| {}).await
+}
+
+/// Like test_state, but lets you tweak the config first.
+async fn test_state_with_config(tweak: impl FnOnce(&mut DogConfig)) -> DogState {
     let db = crate::db::Db::new_test_db().await;
-    let config = DogConfig::test_config().unwrap();
-    let templates = load_templates().unwrap();
-    let inner = DSInner {
+    let mut config = DogConfig::test_config().unwrap();
+    tweak(&mut config);
+    let checkers = Checkers {
+        pwned_checker: PwnedChecker::with_source(StubRange::new(&[PWNED_TEST_PASSWORD])),
+        mx_checker: MxChecker::with_source(StubMx::new(&[MX_TEST_DOMAIN])),
+        signup_guard: SignupGuard::with_source(
+            &config.signup,
+            StubCaptcha::new(&[CAPTCHA_TEST_RESPONSE]),
+        ),
+    };
+    let inner = DSInner::new(
         db,
         config,
-        templates,
-        cookie_key: tower_cookies::Key::generate(),
-        task_tracker: TaskTracker::new(),
-        cancel_token: CancellationToken::new(),
-    };
+        load_templates().unwrap(),
+        tower_cookies::Key::generate(),
+        TaskTracker::new(),
+        CancellationToken::new(),
+        checkers,
+    )
+    .unwrap();
     Arc::new(inner)
 }
 
+/// Make a new dogear with a history of marks some days back, for tests
+/// that care about update schedules. Returns the dogear's ID.
+async fn dogear_marked_days_ago(
+    state: &DogState,
+    user_id: i64,
+    name: &str,
+    days_ago: &[i64],
+) -> i64 {
+    let dogear = state
+        .db
+        .dogears()
+        .create(
+            user_id,
+            &format!("example.com/{}", name),
+            &format!("https://example.com/{}/1", name),
+            None,
+        )
+        .await
+        .unwrap();
+    for days in days_ago {
+        sqlx::query(
+            "INSERT INTO dogear_history (dogear_id, url, created) VALUES (?1, ?2, datetime('now', ?3));",
+        )
+        .bind(dogear.id)
+        .bind(&dogear.current)
+        .bind(format!("-{} days", days))
+        .execute(&state.db.write_pool)
+        .await
+        .unwrap();
+    }
+    dogear.id
+}
+
+/// Share one user's dogears with a test user, invitation accepted and all.
+async fn share_dogears(state: &DogState, owner_id: i64, grantee: &crate::db::TestUser) {
+    let grants = state.db.grants();
+    grants.invite(owner_id, &grantee.name).await.unwrap();
+    let invitations = grants.list_invitations(grantee.id).await.unwrap();
+    grants.accept(invitations[0].id, grantee.id).await.unwrap();
+}
+
 /// Shortcut for request builder w/ method and URI.
 fn new_req(method: impl AsRef<str>, uri: impl AsRef<str>) -> Builder {
     Request::builder().method(method.as_ref()).uri(uri.as_ref())
@@ -76,8 +146,12 @@ trait TestRequestBuilder {
     fn token(self, token: &str) -> Self;
     /// Adds session auth cookie w/ the provided session ID.
     fn session(self, session: &str) -> Self;
+    /// Adds basic auth w/ the provided username and password.
+    fn basic(self, username: &str, password: &str) -> Self;
     /// Convenience wrapper for reusable test cases: takes either token or session.
     fn auth(self, auth: Auth) -> Self;
+    /// Adds the anti-CSRF header that fragment POSTs use.
+    fn csrf(self, csrf_token: &str) -> Self;
     /// Sets accept + content-type json.
     fn json(self) -> Self;
     /// Sets an empty body and finalizes the request.
@@ -91,12 +165,20 @@ impl TestRequestBuilder for Builder {
     fn session(self, sessid: &str) -> Self {
         self.header(header::COOKIE, format!("eardogger.sessid={}", sessid))
     }
+    fn basic(self, username: &str, password: &str) -> Self {
+        use base64::{engine::general_purpose::STANDARD, Engine as _};
+        let creds = STANDARD.encode(format!("{}:{}", username, password));
+        self.header(header::AUTHORIZATION, format!("Basic {}", creds))
+    }
     fn auth(self, auth: Auth) -> Self {
         match auth {
             Auth::Token(t) => self.token(t),
             Auth::Session(s) => self.session(s),
         }
     }
+    fn csrf(self, csrf_token: &str) -> Self {
+        self.header(crate::util::CSRF_SUBMIT_HEADER, csrf_token)
+    }
     fn json(self) -> Self {
         self.header(header::ACCEPT, "application/json")
             .header(header::CONTENT_TYPE, "application/json")
@@ -194,6 +276,30 @@ fn assert_no_cors(resp: &Response<Body>) {
         .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
 }
 
+/// The fresh anti-CSRF token a sensitive fragment or fetch sent back.
+/// Panics if it didn't rotate.
+fn rotated_csrf(resp: &Response<Body>) -> String {
+    resp.headers()
+        .get(crate::util::CSRF_TOKEN_HEADER)
+        .expect("rotated the csrf token")
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// A session's anti-CSRF token as of right now, for checking up on forms
+/// that redirect instead of sending it back.
+async fn current_csrf(state: &DogState, sessid: &str) -> String {
+    let (session, _) = state
+        .db
+        .sessions()
+        .authenticate(sessid)
+        .await
+        .unwrap()
+        .unwrap();
+    session.csrf_token
+}
+
 /// Panics unless the response is a 403 due to insufficient token scope.
 /// This one consumes the response body, so it needs ownership and async.
 async fn assert_api_insufficient_permissions(resp: Response<Body>) {
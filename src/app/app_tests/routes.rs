@@ -0,0 +1,368 @@
+use super::*;
+
+// Table-driven checks that run over every route the app registers. Add a
+// route to app_and_routes without adding it to CASES, and route_coverage
+// fails; so does a case for a route that's gone away. The per-route tests
+// in api.rs and web.rs are still where the real behavior gets tested; this
+// is just making sure auth and error formats stay consistent everywhere.
+
+/// Who a route lets in without credentials of its own.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Access {
+    /// Logged-in session only (AuthSession). Anonymous requests get a 401,
+    /// as an HTML page or a JSON error depending on what they asked for.
+    Session,
+    /// Session or token (AuthAny). Anonymous requests get a 401.
+    Any,
+    /// A site admin's session (AuthAdmin). Anonymous requests get the same
+    /// 401s as Session, and logged-in non-admins get a 403.
+    Admin,
+    /// Anyone gets in the door, whether or not they're logged in, since
+    /// either the page works logged-out or the route brings its own auth.
+    /// The most we can say generally is that nothing should blow up.
+    Open,
+}
+
+use Access::*;
+
+/// Every (method, route, access) the app serves, not counting HEAD. Routes
+/// use axum's `:param` syntax, exactly as registered.
+const CASES: &[(&str, &str, Access)] = &[
+    // Web pages and forms
+    ("GET", "/", Open),
+    ("GET", "/mark/:url", Open),
+    ("POST", "/mark", Session),
+    ("GET", "/resume/:url", Open),
+    ("GET", "/share", Open),
+    ("GET", "/faq", Open),
+    ("GET", "/account", Session),
+    ("GET", "/account/sessions", Session),
+    ("GET", "/install", Open),
+    ("GET", "/bookmarklets", Session),
+    ("GET", "/new_chapters", Session),
+    ("GET", "/archived", Session),
+    ("POST", "/archive_stale", Session),
+    ("POST", "/login", Open),
+    ("GET", "/login/totp", Open),
+    ("POST", "/login/totp", Open),
+    ("POST", "/home_instance", Open),
+    ("POST", "/theme", Open),
+    ("POST", "/logout", Session),
+    ("POST", "/whats_new/dismiss", Session),
+    ("POST", "/signup", Open),
+    ("GET", "/changepassword", Session),
+    ("POST", "/changepassword", Session),
+    ("POST", "/change_email", Session),
+    ("POST", "/login_alerts", Session),
+    ("POST", "/public_profile", Session),
+    ("POST", "/list_display", Session),
+    ("POST", "/history_retention", Session),
+    ("POST", "/stale_nudge", Session),
+    ("POST", "/page_size", Session),
+    ("POST", "/backups", Session),
+    ("GET", "/account/custom_css", Session),
+    ("POST", "/account/custom_css", Session),
+    ("POST", "/account/import/migration_code", Session),
+    ("GET", "/account/import", Session),
+    ("POST", "/account/import", Session),
+    ("POST", "/delete_account", Session),
+    ("GET", "/kosync/books/:document", Session),
+    ("POST", "/tokens", Session),
+    ("PATCH", "/tokens/:id", Session),
+    ("DELETE", "/tokens/:id", Session),
+    ("DELETE", "/sessions/:id", Session),
+    ("POST", "/grants", Session),
+    ("DELETE", "/grants/:id", Session),
+    ("POST", "/grants/:id/:action", Session),
+    ("POST", "/webhooks", Session),
+    ("DELETE", "/webhooks/:id", Session),
+    ("POST", "/totp/setup", Session),
+    ("POST", "/totp/confirm", Session),
+    ("POST", "/totp/recovery_codes", Session),
+    ("POST", "/totp/disable", Session),
+    // Fragments
+    ("GET", "/fragments/dogears", Session),
+    ("GET", "/fragments/archived", Session),
+    ("GET", "/fragments/tokens", Session),
+    ("GET", "/fragments/sessions", Session),
+    ("GET", "/fragments/grants", Session),
+    ("GET", "/fragments/hooks", Session),
+    ("GET", "/fragments/webhooks", Session),
+    ("POST", "/fragments/personalmark", Session),
+    ("GET", "/fragments/bookmarklets", Session),
+    ("POST", "/fragments/bookmarklets/:id/regenerate", Session),
+    ("POST", "/fragments/quickmark", Session),
+    ("POST", "/fragments/kosync", Session),
+    // API
+    ("GET", "/api/v1/list", Any),
+    ("GET", "/api/v1/dogear/:id", Any),
+    ("PATCH", "/api/v1/dogear/:id", Any),
+    ("DELETE", "/api/v1/dogear/:id", Any),
+    ("GET", "/api/v1/dogear/:id/history", Any),
+    ("POST", "/api/v1/dogear/:id/pause", Any),
+    ("POST", "/api/v1/dogear/:id/unpause", Any),
+    ("POST", "/api/v1/dogear/:id/publish", Any),
+    ("POST", "/api/v1/dogear/:id/unpublish", Any),
+    ("POST", "/api/v1/dogear/:id/archive", Any),
+    ("POST", "/api/v1/dogear/:id/unarchive", Any),
+    ("POST", "/api/v1/dogear/:id/hook", Any),
+    ("DELETE", "/api/v1/dogear/:id/hook", Any),
+    ("PUT", "/api/v1/dogear/:id/feed", Any),
+    ("DELETE", "/api/v1/dogear/:id/feed", Any),
+    ("POST", "/api/v1/create", Any),
+    ("GET", "/api/v1/suggest", Any),
+    ("GET", "/api/v1/cadences", Any),
+    ("POST", "/api/v1/tokens/rotate", Any),
+    ("GET", "/api/v1/quickmark", Open),
+    ("POST", "/api/v1/update", Any),
+    ("PUT", "/api/v1/dogear", Any),
+    ("GET", "/api/v1/export", Any),
+    ("POST", "/api/v1/import", Any),
+    ("POST", "/api/v1/migrate_out", Any),
+    ("POST", "/api/v1/migrate_in", Open),
+    ("POST", "/api/v1/token", Open),
+    ("GET", "/api/v1/wait_for_update", Any),
+    // Public pages and links
+    ("GET", "/u/:username", Open),
+    ("GET", "/email/:action/:token", Open),
+    ("POST", "/email/:action/:token", Open),
+    ("POST", "/hooks/:secret", Open),
+    // KOReader sync
+    ("POST", "/kosync/users/create", Open),
+    ("GET", "/kosync/users/auth", Open),
+    ("PUT", "/kosync/syncs/progress", Open),
+    ("GET", "/kosync/syncs/progress/:document", Open),
+    // Site admins
+    ("GET", "/admin/users", Admin),
+    ("POST", "/admin/users/:id/:action", Admin),
+    ("PATCH", "/admin/users/:id", Admin),
+    // Operator and dev stuff
+    ("GET", "/admin/site_rules", Open),
+    ("POST", "/admin/site_rules", Open),
+    ("GET", "/admin/rate_limits", Open),
+    ("GET", "/admin/features", Open),
+    ("PUT", "/admin/features/:name", Open),
+    ("DELETE", "/admin/features/:name", Open),
+    ("POST", "/dev/users", Open),
+    ("POST", "/dev/dogears", Open),
+    ("POST", "/dev/sessions", Open),
+    ("GET", "/dev/preview/:page", Open),
+    // Site root odds and ends
+    ("GET", "/status", Open),
+    ("GET", "/status/details", Open),
+    ("GET", "/metrics", Open),
+    ("GET", "/robots.txt", Open),
+    ("GET", "/sitemap.xml", Open),
+    ("GET", "/.well-known/security.txt", Open),
+    ("GET", "/.well-known/eardogger.json", Open),
+    ("GET", "/api/changelog", Open),
+    ("GET", "/api/schemas/dogear-event.v1.json", Open),
+    ("GET", "/favicon.ico", Open),
+    ("GET", "/favicon.gif", Open),
+];
+
+/// The fragments that can answer in JSON when asked; the rest are HTML-only.
+const JSON_FRAGMENTS: &[&str] = &["/fragments/dogears", "/fragments/tokens"];
+
+/// A requestable URI for a route, with every `:param` filled in.
+fn fill_params(route: &str) -> String {
+    route
+        .split('/')
+        .map(|seg| if seg.starts_with(':') { "1" } else { seg })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn content_type(resp: &Response<Body>) -> &str {
+    resp.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+}
+
+/// Asks the app which methods a route takes, by sending one it definitely
+/// doesn't and reading the Allow header off the 405.
+async fn allowed_methods(app: &mut Router, route: &str) -> Vec<String> {
+    let resp = do_req(app, new_req("TRACE", fill_params(route)).empty()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::METHOD_NOT_ALLOWED,
+        "TRACE {}",
+        route
+    );
+    let allow = resp
+        .headers()
+        .get(header::ALLOW)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    allow
+        .split(',')
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty() && m != "HEAD")
+        .collect()
+}
+
+#[tokio::test]
+async fn route_coverage() {
+    let state = test_state().await;
+    let (mut app, table) = app_and_routes(state);
+
+    let mut registered: Vec<String> = Vec::new();
+    for route in &table.paths {
+        for method in allowed_methods(&mut app, route).await {
+            registered.push(format!("{} {}", method, route));
+        }
+    }
+    assert!(!registered.is_empty());
+    let cases: Vec<String> = CASES
+        .iter()
+        .map(|(method, route, _)| format!("{} {}", method, route))
+        .collect();
+
+    let missing: Vec<&String> = registered.iter().filter(|r| !cases.contains(r)).collect();
+    let stale: Vec<&String> = cases.iter().filter(|c| !registered.contains(c)).collect();
+    assert!(
+        missing.is_empty(),
+        "routes with no entry in routes.rs CASES: {:?}",
+        missing
+    );
+    assert!(
+        stale.is_empty(),
+        "CASES entries for routes that don't exist: {:?}",
+        stale
+    );
+}
+
+#[tokio::test]
+async fn route_auth_and_errors() {
+    let state = test_state().await;
+    let mut app = eardogger_app(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+
+    for &(method, route, access) in CASES {
+        let uri = fill_params(route);
+        match access {
+            Session | Admin => {
+                // Browsers get a page...
+                let resp = do_req(&mut app, new_req(method, &uri).empty()).await;
+                assert_eq!(
+                    resp.status(),
+                    StatusCode::UNAUTHORIZED,
+                    "{} {}",
+                    method,
+                    route
+                );
+                assert!(
+                    content_type(&resp).starts_with("text/html"),
+                    "{} {}",
+                    method,
+                    route
+                );
+                // ...and scripts get an error object.
+                let resp = do_req(&mut app, new_req(method, &uri).json().empty()).await;
+                assert_eq!(
+                    resp.status(),
+                    StatusCode::UNAUTHORIZED,
+                    "{} {}",
+                    method,
+                    route
+                );
+                assert!(
+                    content_type(&resp).starts_with("application/json"),
+                    "{} {}",
+                    method,
+                    route
+                );
+                let err = api_error_body(resp).await.unwrap();
+                assert!(
+                    err.error.contains("aren't logged in"),
+                    "{} {}",
+                    method,
+                    route
+                );
+                // Tokens don't count, even the manage kind.
+                let req = new_req(method, &uri)
+                    .token(&user.manage_token)
+                    .json()
+                    .empty();
+                let resp = do_req(&mut app, req).await;
+                assert_eq!(
+                    resp.status(),
+                    StatusCode::UNAUTHORIZED,
+                    "{} {}",
+                    method,
+                    route
+                );
+                assert!(api_error_body(resp).await.is_ok(), "{} {}", method, route);
+                // And for admin routes, a plain user's session doesn't either.
+                if access == Admin {
+                    let req = new_req(method, &uri)
+                        .session(&user.session_id)
+                        .json()
+                        .empty();
+                    let resp = do_req(&mut app, req).await;
+                    assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{} {}", method, route);
+                    assert!(api_error_body(resp).await.is_ok(), "{} {}", method, route);
+                }
+            }
+            Any => {
+                assert_api_auth_required(&mut app, method, &uri, None).await;
+            }
+            Open => {
+                let resp = do_req(&mut app, new_req(method, &uri).empty()).await;
+                assert!(
+                    !resp.status().is_server_error(),
+                    "{} {}: {}",
+                    method,
+                    route,
+                    resp.status()
+                );
+            }
+        }
+    }
+}
+
+/// Every GET fragment works for a logged-in user, and answers in JSON if it
+/// can (saying that it varies by Accept) and HTML if it can't.
+#[tokio::test]
+async fn fragment_formats() {
+    let state = test_state().await;
+    let (mut app, table) = app_and_routes(state.clone());
+    let user = state.db.test_user("whoever").await.unwrap();
+
+    let fragments: Vec<&str> = CASES
+        .iter()
+        .filter(|(m, r, _)| *m == "GET" && r.starts_with("/fragments/"))
+        .map(|(_, r, _)| *r)
+        .collect();
+    assert!(fragments.iter().all(|f| table.paths.contains(f)));
+    for &json in JSON_FRAGMENTS {
+        assert!(fragments.contains(&json), "{} isn't a GET fragment", json);
+    }
+
+    for route in fragments {
+        let req = new_req("GET", route)
+            .json()
+            .session(&user.session_id)
+            .empty();
+        let resp = do_req(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{}", route);
+        if JSON_FRAGMENTS.contains(&route) {
+            assert_eq!(
+                resp.headers().get(header::VARY).unwrap(),
+                "Accept",
+                "{}",
+                route
+            );
+            assert!(
+                content_type(&resp).starts_with("application/json"),
+                "{}",
+                route
+            );
+            let body = body_bytes(resp).await;
+            assert!(serde_json::from_slice::<serde_json::Value>(&body).is_ok());
+        } else {
+            assert!(content_type(&resp).starts_with("text/html"), "{}", route);
+        }
+    }
+}
@@ -7,21 +7,28 @@
 //! - Most "web page" routes should use the AuthSession extractor to get a user.
 //! - API routes can use the AuthAny extractor, and should immediately call
 //!   `.allowed_scopes()?` on the value.
+//! - The /admin/users routes use AuthAdmin, which is an AuthSession whose
+//!   user is a site admin.
 
 use super::state::DogState;
+use super::templates::{LastLogin, Theme};
 use super::web_result::{ApiError, AppError, AppErrorKind};
-use crate::db::{Session, Token, TokenScope, User};
-use crate::util::COOKIE_SESSION;
+use crate::db::{AuditSource, Session, Token, TokenScope, User, UserPrefs};
+use crate::util::{client_ip, IntoHandlerError, COOKIE_SESSION, COOKIE_THEME, CSRF_SUBMIT_HEADER};
 use axum::{
     async_trait,
     extract::{FromRequestParts, Request, State},
     middleware::Next,
-    response::{IntoResponse, Response},
+    response::{IntoResponse, Redirect, Response},
 };
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 use http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode};
+use std::collections::BTreeSet;
+use std::convert::Infallible;
 use std::fmt::Debug;
 use std::sync::Arc;
-use tower_cookies::Cookies;
+use tower_cookies::{Cookie, Cookies};
+use tracing::{info, warn};
 
 // ok let's get our types in a row.
 // The db types all use String for text because that's what Sqlx demands,
@@ -39,11 +46,21 @@ pub enum AuthAny {
     Session {
         user: Arc<User>,
         session: Arc<Session>,
+        /// Loaded up front, since the page layout needs some of it.
+        prefs: Arc<UserPrefs>,
+        /// The feature flags that are on for this user.
+        features: Arc<BTreeSet<String>>,
     },
     Token {
         user: Arc<User>,
         token: Arc<Token>,
     },
+    /// Username and password via `Authorization: Basic`, for API clients
+    /// that can't do tokens. Only exists if the config allows it, and only
+    /// on /api/v1/* routes. Counts as a manage_dogears token.
+    Basic {
+        user: Arc<User>,
+    },
 }
 
 impl AuthAny {
@@ -57,16 +74,19 @@ impl AuthAny {
     pub fn allowed_scopes(&self, scopes: &[TokenScope]) -> Result<(), ApiError> {
         match self {
             AuthAny::Session { .. } => Ok(()),
-            AuthAny::Token { token, .. } => {
-                if scopes.iter().any(|s| *s == token.scope()) {
-                    Ok(())
-                } else {
-                    Err(ApiError::new(
-                        StatusCode::FORBIDDEN,
-                        "The provided authentication token doesn't have the right permissions to perform this action.".to_string()),
-                    )
-                }
-            }
+            AuthAny::Token { token, .. } => Self::check_scope(scopes, token.scope()),
+            AuthAny::Basic { .. } => Self::check_scope(scopes, TokenScope::ManageDogears),
+        }
+    }
+
+    fn check_scope(scopes: &[TokenScope], scope: TokenScope) -> Result<(), ApiError> {
+        if scopes.iter().any(|s| *s == scope) {
+            Ok(())
+        } else {
+            Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "The provided authentication token doesn't have the right permissions to perform this action.".to_string()),
+            )
         }
     }
 
@@ -76,6 +96,7 @@ impl AuthAny {
         match self {
             AuthAny::Session { user, .. } => user.clone(),
             AuthAny::Token { user, .. } => user.clone(),
+            AuthAny::Basic { user } => user.clone(),
         }
     }
 }
@@ -86,6 +107,13 @@ impl AuthAny {
 pub struct AuthSession {
     pub user: Arc<User>,
     pub session: Arc<Session>,
+    pub prefs: Arc<UserPrefs>,
+    /// The feature flags that are on for this user. Check these with
+    /// `.contains("flag_name")`.
+    pub features: Arc<BTreeSet<String>>,
+    /// This browser's theme cookie. It's not part of the session, but
+    /// every page that uses common_args needs it.
+    pub theme: Theme,
 }
 
 impl AuthSession {
@@ -96,6 +124,13 @@ impl AuthSession {
             title,
             user: Some(&*self.user),
             csrf_token: &self.session.csrf_token,
+            custom_css: self.prefs.custom_css.as_deref(),
+            features: &self.features,
+            breadcrumbs: &[],
+            page: None,
+            canonical_url: None,
+            last_login: LastLogin::from_session(&self.session),
+            theme: self.theme,
         }
     }
 }
@@ -103,10 +138,8 @@ impl AuthSession {
 // Checks both the Accept and Content-Type (in case of POST/PUT) headers to
 // see if we should be returning json error objects; defaults to html otherwise.
 fn error_kind_from_headers(headers: &HeaderMap<HeaderValue>) -> AppErrorKind {
-    if let Some(v) = headers.get(http::header::ACCEPT) {
-        if header_val_matches(v, "application/json") {
-            return AppErrorKind::Json;
-        }
+    if accepts_json(headers) {
+        return AppErrorKind::Json;
     }
     if let Some(v) = headers.get(http::header::CONTENT_TYPE) {
         if header_val_matches(v, "application/json") {
@@ -116,6 +149,14 @@ fn error_kind_from_headers(headers: &HeaderMap<HeaderValue>) -> AppErrorKind {
     AppErrorKind::Html
 }
 
+/// True if the Accept header asks for JSON. Besides picking an error format,
+/// this lets the fragment routes hand back raw data instead of HTML.
+pub fn accepts_json(headers: &HeaderMap<HeaderValue>) -> bool {
+    headers
+        .get(http::header::ACCEPT)
+        .is_some_and(|v| header_val_matches(v, "application/json"))
+}
+
 // True if the header value is a valid string AND equals the provided text.
 fn header_val_matches(val: &HeaderValue, text: &str) -> bool {
     match val.to_str() {
@@ -169,10 +210,19 @@ where
         // we'll remember to render json errors later.
         let kind = error_kind_from_headers(&parts.headers);
 
-        if let Some(AuthAny::Session { user, session }) = parts.extensions.get::<AuthAny>() {
+        if let Some(AuthAny::Session {
+            user,
+            session,
+            prefs,
+            features,
+        }) = parts.extensions.get::<AuthAny>()
+        {
             Ok(AuthSession {
                 user: user.clone(),
                 session: session.clone(),
+                prefs: prefs.clone(),
+                features: features.clone(),
+                theme: theme_from_parts(parts),
             })
         } else {
             Err(AppError::new(
@@ -184,6 +234,134 @@ where
     }
 }
 
+/// A logged-in site admin (see `db admin`). Logged-out requests get the same
+/// 401 as AuthSession, and everyone else gets a 403. Unlike the other
+/// extractors, this one has to ask the db, since admin-ness isn't part of
+/// the session; but only the admin routes pay for that.
+#[derive(Clone, Debug)]
+pub struct AuthAdmin(pub AuthSession);
+
+#[async_trait]
+impl FromRequestParts<DogState> for AuthAdmin {
+    type Rejection = AppError;
+
+    #[tracing::instrument(skip_all)]
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &DogState,
+    ) -> Result<Self, Self::Rejection> {
+        let auth = AuthSession::from_request_parts(parts, state).await?;
+        let kind = error_kind_from_headers(&parts.headers);
+        let flags = state
+            .db
+            .users()
+            .flags(auth.user.id)
+            .await
+            .map_err(|e| {
+                let (status, message) = e.status_and_message();
+                AppError::new(status, message, kind)
+            })?
+            .unwrap_or_default();
+        if flags.is_admin {
+            Ok(AuthAdmin(auth))
+        } else {
+            warn!(target: "audit", username = %auth.user.username, "admin: not an admin");
+            Err(AppError::new(
+                StatusCode::FORBIDDEN,
+                "That's only for site admins.".to_string(),
+                kind,
+            ))
+        }
+    }
+}
+
+/// The anti-CSRF token from a fragment POST's `X-CSRF-Token` header. This
+/// only reads it; each route compares it to the session's token itself, so
+/// it can say which button was stale.
+#[derive(Debug)]
+pub struct CsrfHeader(pub String);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CsrfHeader
+where
+    S: Send + Sync + Debug,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        match parts
+            .headers
+            .get(CSRF_SUBMIT_HEADER)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(token) => Ok(CsrfHeader(token.to_string())),
+            None => Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "That button didn't send its anti-CSRF token. Refresh the page and try again."
+                    .to_string(),
+                error_kind_from_headers(&parts.headers),
+            )),
+        }
+    }
+}
+
+/// Where a request came from, for the audit log. Never fails; anything we
+/// can't tell is just None.
+#[async_trait]
+impl<S> FromRequestParts<S> for AuditSource
+where
+    S: Send + Sync + Debug,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(AuditSource {
+            ip: client_ip(&parts.headers).map(String::from),
+            user_agent: parts
+                .headers
+                .get(header::USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from),
+        })
+    }
+}
+
+/// Read the theme cookie. No cookie (or a junk one) means Auto.
+pub fn theme_from_cookies(cookies: &Cookies) -> Theme {
+    cookies
+        .get(COOKIE_THEME)
+        .and_then(|c| Theme::parse(c.value()))
+        .unwrap_or_default()
+}
+
+/// Read the theme cookie straight from the headers, since some of the pages
+/// that want it are outside the cookie manager layer.
+fn theme_from_parts(parts: &Parts) -> Theme {
+    parts
+        .headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .filter_map(Result::ok)
+        .find(|c| c.name() == COOKIE_THEME)
+        .and_then(|c| Theme::parse(c.value()))
+        .unwrap_or_default()
+}
+
+/// The theme cookie, for pages that render without a session. Never fails.
+#[async_trait]
+impl<S> FromRequestParts<S> for Theme
+where
+    S: Send + Sync + Debug,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(theme_from_parts(parts))
+    }
+}
+
 // So, about those middlewares... how's about a refresher.
 //
 // My auth middleware is deeply entangled with the way I store and authenticate
@@ -213,13 +391,31 @@ pub async fn session_middleware(
 
     // get sessid out of cookie
     if let Some(sessid) = cookies.get(COOKIE_SESSION) {
-        match state.db.sessions().authenticate(sessid.value()).await {
+        match authenticate_session(&state, sessid.value()).await {
             Ok(maybe) => {
-                if let Some((session, user)) = maybe {
+                if let Some((session, user, prefs, features)) = maybe {
+                    // An admin's making them pick a new password, so that's
+                    // all this session gets to do (besides logging out).
+                    if !reset_allows(&request) {
+                        match must_reset_password(&state, user.id).await {
+                            Ok(false) => (),
+                            Ok(true) => return reset_required(error_kind),
+                            Err(e) => {
+                                return AppError::new(
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                    e.to_string(),
+                                    error_kind,
+                                )
+                                .into_response();
+                            }
+                        }
+                    }
                     // ok rad, do it
                     request.extensions_mut().insert(AuthAny::Session {
                         user: Arc::new(user),
                         session: Arc::new(session.clone()),
+                        prefs: Arc::new(prefs),
+                        features: Arc::new(features),
                     });
                     // Update cookie with new expiration date...
                     // tower_cookies will ship this on the outbound leg.
@@ -237,9 +433,52 @@ pub async fn session_middleware(
     next.run(request).await
 }
 
+/// Whether an admin is making this user choose a new password. Until they
+/// do, their password only gets them as far as the change password page.
+pub async fn must_reset_password(state: &DogState, user_id: i64) -> sqlx::Result<bool> {
+    let flags = state.db.users().flags(user_id).await?.unwrap_or_default();
+    Ok(flags.must_reset_password)
+}
+
+/// The requests a session can still make while its user owes us a new
+/// password: the change password page and form, and logging out.
+fn reset_allows(request: &Request) -> bool {
+    let path = request.uri().path();
+    path == "/changepassword" || (path == "/logout" && request.method() == http::Method::POST)
+}
+
+/// Everything else: pages go to the change password page, and scripts get
+/// told why they can't have what they asked for.
+fn reset_required(error_kind: AppErrorKind) -> Response {
+    match error_kind {
+        AppErrorKind::Html => Redirect::to("/changepassword").into_response(),
+        AppErrorKind::Json => AppError::new(
+            StatusCode::FORBIDDEN,
+            "A site admin has asked you to choose a new password. Change it on the website first."
+                .to_string(),
+            error_kind,
+        )
+        .into_response(),
+    }
+}
+
+/// Look up a session and its user, plus the user's prefs and feature flags.
+async fn authenticate_session(
+    state: &DogState,
+    sessid: &str,
+) -> sqlx::Result<Option<(Session, User, UserPrefs, BTreeSet<String>)>> {
+    let Some((session, user)) = state.db.sessions().authenticate(sessid).await? else {
+        return Ok(None);
+    };
+    let prefs = state.db.prefs().get(user.id).await?;
+    let features = state.features_for(user.id).await?;
+    Ok(Some((session, user, prefs, features)))
+}
+
 /// Function middleware to validate a token passed in the `Authorization: Bearer STUFF`
 /// header and make the token's user available to routes. This overrides the session
-/// user if both would have been present.
+/// user if both would have been present. If the config allows it, this also
+/// accepts `Authorization: Basic` on API routes.
 #[tracing::instrument(skip_all)]
 pub async fn token_middleware(
     State(state): State<DogState>,
@@ -252,8 +491,52 @@ pub async fn token_middleware(
 
     if let Some(auth_header) = request.headers().get(header::AUTHORIZATION) {
         if let Ok(auth_val) = auth_header.to_str() {
-            if let Some(bearer_val) = auth_val.strip_prefix("Bearer ") {
+            if let Some(basic_val) = auth_val.strip_prefix("Basic ") {
+                // Opt-in only, and only for the API. Web pages want a real login.
+                if state.config.api_basic_auth && request.uri().path().starts_with("/api/v1/") {
+                    match basic_auth(
+                        &state,
+                        request.headers(),
+                        basic_val.trim(),
+                        request.uri().path(),
+                    )
+                    .await
+                    {
+                        Ok(BasicAuthOutcome::User(user)) => {
+                            request.extensions_mut().insert(AuthAny::Basic {
+                                user: Arc::new(user),
+                            });
+                        }
+                        Ok(BasicAuthOutcome::Rejected) => (),
+                        Ok(BasicAuthOutcome::Limited) => {
+                            return AppError::new(
+                                StatusCode::TOO_MANY_REQUESTS,
+                                "Too many failed login attempts for that user. Try again later."
+                                    .to_string(),
+                                error_kind,
+                            )
+                            .into_response();
+                        }
+                        Err(e) => {
+                            return AppError::new(
+                                StatusCode::INTERNAL_SERVER_ERROR,
+                                e.to_string(),
+                                error_kind,
+                            )
+                            .into_response();
+                        }
+                    }
+                }
+            } else if let Some(bearer_val) = auth_val.strip_prefix("Bearer ") {
                 // phew!!
+                if bad_token_blocked(&state, request.headers()) {
+                    return AppError::new(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "Too many bad tokens from your address. Try again later.".to_string(),
+                        error_kind,
+                    )
+                    .into_response();
+                }
                 let token_cleartext = bearer_val.trim();
                 match state.db.tokens().authenticate(token_cleartext).await {
                     Ok(maybe) => {
@@ -264,6 +547,8 @@ pub async fn token_middleware(
                                 user: Arc::new(user),
                                 token: Arc::new(token),
                             });
+                        } else {
+                            bad_token(&state, request.headers(), request.uri().path());
                         }
                     }
                     Err(e) => {
@@ -282,3 +567,114 @@ pub async fn token_middleware(
     // Ok, carry on
     next.run(request).await
 }
+
+/// Whether this client's sent too many bad tokens lately to get another
+/// look. Clients we can't get an IP for never are, since lumping them all
+/// together would let one scanner lock everybody out.
+fn bad_token_blocked(state: &DogState, headers: &HeaderMap) -> bool {
+    let Some(ip) = client_ip(headers) else {
+        return false;
+    };
+    if !state.bad_token_limiter.is_limited(ip) || state.config.rate_limit_allowlist.exempts(headers)
+    {
+        return false;
+    }
+    state.bad_token_counts.blocked();
+    true
+}
+
+/// Note a Bearer token that didn't match anything. One now and then is
+/// just a revoked token in some old script, so those only get counted; a
+/// client that keeps at it is probably guessing, so when it hits the
+/// limit, that gets an alert in the log (once per window) and it gets
+/// turned away until the window's up. Failures from allowlisted clients
+/// count toward the totals, but never toward a block.
+fn bad_token(state: &DogState, headers: &HeaderMap, path: &str) {
+    let ip = client_ip(headers);
+    state.bad_token_counts.failure(ip.is_some());
+    let Some(ip) = ip else {
+        info!(target: "audit", %path, "bearer auth: bad token (no client IP)");
+        return;
+    };
+    if state.config.rate_limit_allowlist.exempts(headers) {
+        info!(target: "audit", %ip, %path, "bearer auth: bad token (allowlisted client)");
+        return;
+    }
+    let failures = state.bad_token_limiter.record(ip);
+    info!(target: "audit", %ip, %path, failures, "bearer auth: bad token");
+    if failures == state.bad_token_limiter.max() {
+        state.bad_token_counts.alert();
+        warn!(target: "alerts", %ip, %path, failures, "possible token scanning: blocking this client for a while");
+    }
+}
+
+enum BasicAuthOutcome {
+    User(User),
+    /// Malformed header or wrong password; carry on unauthenticated.
+    Rejected,
+    /// Too many recent failures for this username; don't even check.
+    Limited,
+}
+
+/// Check an `Authorization: Basic` value (base64 of `username:password`).
+/// This is a password login without the session, so it gets the same
+/// treatment as a login would deserve: failures count against a per-username
+/// rate limit (unless the client's on the allowlist), and everything goes
+/// in the log under the `audit` target.
+async fn basic_auth(
+    state: &DogState,
+    headers: &HeaderMap,
+    encoded: &str,
+    path: &str,
+) -> anyhow::Result<BasicAuthOutcome> {
+    let Some((username, password)) = STANDARD
+        .decode(encoded)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|creds| {
+            creds
+                .split_once(':')
+                .map(|(u, p)| (u.to_string(), p.to_string()))
+        })
+    else {
+        warn!(target: "audit", %path, "api basic auth: malformed credentials");
+        return Ok(BasicAuthOutcome::Rejected);
+    };
+
+    let limiter = &state.basic_auth_limiter;
+    let exempt = state.config.rate_limit_allowlist.exempts(headers);
+    if !exempt && limiter.is_limited(&username) {
+        warn!(target: "audit", %username, %path, "api basic auth: rate limited");
+        return Ok(BasicAuthOutcome::Limited);
+    }
+    match state.db.users().authenticate(&username, &password).await? {
+        Some(user) => {
+            // Their password's only half of what it takes to log in, so it
+            // doesn't get to reset the count of wrong two-factor codes.
+            if state.db.totp().enabled(user.id).await? {
+                warn!(target: "audit", %username, %path, "api basic auth: account uses two-factor");
+                return Ok(BasicAuthOutcome::Rejected);
+            }
+            limiter.reset(&username);
+            // And if an admin wants it changed, it's no good until it is.
+            if must_reset_password(state, user.id).await? {
+                warn!(target: "audit", %username, %path, "api basic auth: password reset required");
+                return Ok(BasicAuthOutcome::Rejected);
+            }
+            info!(target: "audit", %username, %path, "api basic auth: success");
+            Ok(BasicAuthOutcome::User(user))
+        }
+        None if exempt => {
+            warn!(target: "audit", %username, %path, "api basic auth: bad password (allowlisted client)");
+            Ok(BasicAuthOutcome::Rejected)
+        }
+        None => {
+            let failures = limiter.record(&username);
+            warn!(target: "audit", %username, %path, failures, "api basic auth: bad password");
+            if failures == limiter.max() {
+                warn!(target: "alerts", %username, %path, failures, "possible password guessing: locking this username out for a while");
+            }
+            Ok(BasicAuthOutcome::Rejected)
+        }
+    }
+}
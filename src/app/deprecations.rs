@@ -0,0 +1,184 @@
+//! Retiring API endpoints without surprising anybody. An endpoint that's on
+//! its way out gets an entry in [DEPRECATIONS], and from then on its
+//! responses carry a `Deprecation` header (RFC 9745), a `Sunset` header
+//! (RFC 8594) once there's a date for it to go away, and a link to
+//! `/api/changelog`, which is the human- and script-readable list of what
+//! changed and what's going. Every request to a deprecated endpoint gets
+//! logged, so we can see who still needs a nudge before the sunset.
+
+use super::routes::own_url;
+use eardogger_rs::api_types::{ApiChangelog, ApiChangelogEntry, ApiDeprecation};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::{Json, Response},
+};
+use time::{
+    format_description::FormatItem,
+    macros::{date, format_description},
+    Date,
+};
+use tracing::warn;
+use url::Url;
+
+/// The API version current clients should be writing against.
+pub const API_VERSION: &str = "v1";
+
+/// One endpoint on its way out.
+#[derive(Debug)]
+pub struct Deprecation {
+    /// The HTTP method, like "GET".
+    pub method: &'static str,
+    /// The route as the router spells it, `:params` and all.
+    pub path: &'static str,
+    /// When it stopped being recommended. Can be in the future, to give
+    /// advance notice.
+    pub deprecated: Date,
+    /// When it goes away for good, if we've picked a day.
+    pub sunset: Option<Date>,
+    /// The route to use instead, if there is one.
+    pub successor: Option<&'static str>,
+    /// What to do about it, in a sentence or two.
+    pub note: &'static str,
+}
+
+/// One day's worth of API changes.
+#[derive(Debug)]
+pub struct ChangelogEntry {
+    pub date: Date,
+    pub changes: &'static [&'static str],
+}
+
+/// Endpoints on their way out. Nothing yet; the v2 pagination and error
+/// formats will put the first ones here.
+pub static DEPRECATIONS: &[Deprecation] = &[];
+
+/// What's changed in the API, newest first.
+pub static CHANGELOG: &[ChangelogEntry] = &[ChangelogEntry {
+    date: date!(2026 - 10 - 15),
+    changes: &[
+        "Added `GET /api/changelog`, this document.",
+        "Deprecated endpoints now answer with `Deprecation` and `Sunset` headers, and a `Link` to this changelog.",
+        "`GET /api/v1/list` takes a `q` parameter, which filters to dogears whose name, prefix, or current URL contains it.",
+        "`GET /api/v1/list` includes `meta.freshness`: each listed dogear's ID, mapped to how recently it moved (`today`, `this_week`, `this_month`, or `stale`).",
+        "Added `POST /api/v1/token`, which trades a username and password for a new `write_dogears` token. `/.well-known/eardogger.json` says whether an instance has it, as `token_exchange`.",
+        "Accounts with two-factor logins turned on can't use Basic auth or `POST /api/v1/token`, since a password alone isn't enough to log in to them. The token exchange answers 403.",
+        "Every response has an `X-Request-Id` header (kept from the request, if it sent a reasonable one), and JSON errors include it as `request_id`. Quote it when reporting a problem.",
+        "Added `PUT /api/v1/dogear?current=...`, which does the same thing as `POST /api/v1/update` (same tokens, CORS rule, and response) with the fields as query params instead of a JSON body. `note` works too.",
+        "Outgoing webhook bodies are versioned dogear events: `version`, `event` (`created`, `updated`, `archived`, or `deleted`), `dogear_id`, `occurred`, and the dogear `before` and `after` the change, next to the old `dogear` and `sent`, plus a `chapter_delta` guess for updates. Every change gets its own POST now, in order, instead of piling up into one. The JSON Schema is at `GET /api/schemas/dogear-event.v1.json`.",
+    ],
+}];
+
+/// The one date format HTTP headers take, always in GMT.
+const HTTP_DATE: &[FormatItem<'static>] = format_description!(
+    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+);
+
+/// The deprecation middleware's state: a list of deprecations, plus where
+/// to point people for details. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct DeprecationTable {
+    entries: &'static [Deprecation],
+    public_url: Url,
+}
+
+impl DeprecationTable {
+    pub fn new(entries: &'static [Deprecation], public_url: &Url) -> Self {
+        Self {
+            entries,
+            public_url: public_url.clone(),
+        }
+    }
+
+    fn find(&self, method: &str, path: &str) -> Option<&'static Deprecation> {
+        self.entries
+            .iter()
+            .find(|d| d.method == method && d.path == path)
+    }
+}
+
+/// Middleware that marks responses from deprecated endpoints, and logs
+/// who's still calling them. Needs to go on as a Router layer (not
+/// outside the router), so it can see which route matched.
+pub async fn deprecation_middleware(
+    State(table): State<DeprecationTable>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let deprecation = req
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|path| table.find(req.method().as_str(), path.as_str()));
+    let Some(deprecation) = deprecation else {
+        return next.run(req).await;
+    };
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    warn!(
+        target: "deprecations",
+        method = deprecation.method,
+        path = deprecation.path,
+        %user_agent,
+        "deprecated endpoint called"
+    );
+
+    let mut resp = next.run(req).await;
+    let headers = resp.headers_mut();
+    let deprecated = deprecation.deprecated.midnight().assume_utc();
+    if let Ok(v) = HeaderValue::try_from(format!("@{}", deprecated.unix_timestamp())) {
+        headers.insert(HeaderName::from_static("deprecation"), v);
+    }
+    if let Some(sunset) = deprecation.sunset {
+        let sunset = sunset.midnight().assume_utc().format(HTTP_DATE);
+        if let Some(v) = sunset.ok().and_then(|s| HeaderValue::try_from(s).ok()) {
+            headers.insert(HeaderName::from_static("sunset"), v);
+        }
+    }
+    let mut links = vec![format!(
+        "<{}>; rel=\"deprecation\"; type=\"application/json\"",
+        own_url(&table.public_url, "/api/changelog")
+    )];
+    if let Some(successor) = deprecation.successor {
+        links.push(format!(
+            "<{}>; rel=\"successor-version\"",
+            own_url(&table.public_url, successor)
+        ));
+    }
+    if let Ok(v) = HeaderValue::try_from(links.join(", ")) {
+        headers.append(header::LINK, v);
+    }
+    resp
+}
+
+/// `GET /api/changelog`: what's changed in the API, and what's going away.
+/// Public info, so no login and any site's JS can read it.
+pub async fn api_changelog() -> Json<ApiChangelog> {
+    Json(ApiChangelog {
+        version: API_VERSION.to_string(),
+        changes: CHANGELOG
+            .iter()
+            .map(|entry| ApiChangelogEntry {
+                date: entry.date.to_string(),
+                changes: entry.changes.iter().map(|c| c.to_string()).collect(),
+            })
+            .collect(),
+        deprecations: DEPRECATIONS.iter().map(api_deprecation).collect(),
+    })
+}
+
+fn api_deprecation(deprecation: &Deprecation) -> ApiDeprecation {
+    ApiDeprecation {
+        method: deprecation.method.to_string(),
+        path: deprecation.path.to_string(),
+        deprecated: deprecation.deprecated.to_string(),
+        sunset: deprecation.sunset.map(|d| d.to_string()),
+        successor: deprecation.successor.map(|s| s.to_string()),
+        note: deprecation.note.to_string(),
+    }
+}
@@ -0,0 +1,215 @@
+//! Test-data routes under `/dev`, for throwaway instances with `dev_tools`
+//! turned on: make users, dogears, and sessions over HTTP, so UI work and
+//! the loadtest don't need a compiled test or a hand-made account. There's
+//! no auth at all (that's the point), and `/dev/sessions` will log anybody
+//! in as anybody, so they don't exist unless the build AND the config both
+//! say so: they need the `dev_tools` cargo feature (which also compiles in
+//! the tests' user factory), plus `dev_tools` in the config, which won't
+//! say so in production. Without the feature, they're 404 stubs.
+//!
+//! `/dev/preview` is the other half: real templates rendered with made-up
+//! data from `templates::fixtures`, for styling work that shouldn't need
+//! any account at all.
+
+use super::state::DogState;
+use super::templates::fixtures;
+use super::web_result::{ApiError, ApiResult, WebError, WebResult};
+#[cfg(feature = "dev_tools")]
+use crate::db::{Db, Dogear, User};
+#[cfg(feature = "dev_tools")]
+use crate::util::uuid_string;
+#[cfg(feature = "dev_tools")]
+use eardogger_rs::api_types::{
+    ApiDevDogearsPayload, ApiDevSession, ApiDevSessionPayload, ApiDevUser, ApiDevUserPayload,
+};
+
+#[cfg(feature = "dev_tools")]
+use axum::response::Json;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Html,
+};
+use serde::Deserialize;
+#[cfg(feature = "dev_tools")]
+use tracing::info;
+
+/// The most dogears one `POST /dev/dogears` will make.
+#[cfg(feature = "dev_tools")]
+const DEV_DOGEARS_MAX: u32 = 1000;
+
+/// 404 unless dev tools are on, so the routes look like they aren't there.
+#[cfg(feature = "dev_tools")]
+fn check_dev_tools(state: &DogState) -> ApiResult<()> {
+    if state.config.dev_tools {
+        Ok(())
+    } else {
+        Err(not_found())
+    }
+}
+
+fn not_found() -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "Not found.".to_string())
+}
+
+#[cfg(feature = "dev_tools")]
+async fn user_named(db: &Db, name: &str) -> ApiResult<User> {
+    db.users().by_name(name).await?.ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("There's no user named {:?}.", name),
+        )
+    })
+}
+
+/// `POST /dev/users`: a new user, just like the tests' `Db::test_user`.
+#[cfg(feature = "dev_tools")]
+#[tracing::instrument(skip_all)]
+pub async fn dev_create_user(
+    State(state): State<DogState>,
+    Json(payload): Json<ApiDevUserPayload>,
+) -> ApiResult<(StatusCode, Json<ApiDevUser>)> {
+    check_dev_tools(&state)?;
+    let name = payload
+        .name
+        .unwrap_or_else(|| format!("dev-{}", &uuid_string()[..8]));
+    if state.db.users().by_name(&name).await?.is_some() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("There's already a user named {:?}.", name),
+        ));
+    }
+    let user = state.db.test_user(&name).await?;
+    info!(username = %user.name, "dev: created user");
+    Ok((
+        StatusCode::CREATED,
+        Json(ApiDevUser {
+            id: user.id,
+            name: user.name,
+            password: Db::TEST_PASSWORD.to_string(),
+            write_token: user.write_token,
+            manage_token: user.manage_token,
+            session_id: user.session_id,
+            csrf_token: user.csrf_token,
+        }),
+    ))
+}
+
+/// `POST /dev/users`, in a build without the user factory: 404, same as
+/// when the config has dev tools off.
+#[cfg(not(feature = "dev_tools"))]
+pub async fn dev_create_user() -> ApiResult<StatusCode> {
+    Err(not_found())
+}
+
+/// `POST /dev/dogears`: a pile of dogears for an existing user, each on
+/// its own prefix under the one you give (or a random one).
+#[cfg(feature = "dev_tools")]
+#[tracing::instrument(skip_all)]
+pub async fn dev_create_dogears(
+    State(state): State<DogState>,
+    Json(payload): Json<ApiDevDogearsPayload>,
+) -> ApiResult<(StatusCode, Json<Vec<Dogear>>)> {
+    check_dev_tools(&state)?;
+    if payload.count > DEV_DOGEARS_MAX {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("That's too many; the max is {}.", DEV_DOGEARS_MAX),
+        ));
+    }
+    let user = user_named(&state.db, &payload.user).await?;
+    let prefix = payload
+        .prefix
+        .unwrap_or_else(|| format!("dev.eardogger.invalid/{}", &uuid_string()[..8]));
+    let prefix = prefix.trim_end_matches('/');
+    let mut dogears = Vec::with_capacity(payload.count as usize);
+    for n in 1..=payload.count {
+        let dogear = state
+            .db
+            .dogears()
+            .create(
+                user.id,
+                &format!("{}/{}", prefix, n),
+                &format!("https://{}/{}/1", prefix, n),
+                Some(&format!("Synthetic dogear {}", n)),
+            )
+            .await?;
+        dogears.push(dogear);
+    }
+    info!(username = %user.username, count = dogears.len(), "dev: created dogears");
+    Ok((StatusCode::CREATED, Json(dogears)))
+}
+
+/// `POST /dev/dogears`, in a build without the feature: 404.
+#[cfg(not(feature = "dev_tools"))]
+pub async fn dev_create_dogears() -> ApiResult<StatusCode> {
+    Err(not_found())
+}
+
+/// `POST /dev/sessions`: a fresh login session for an existing user.
+#[cfg(feature = "dev_tools")]
+#[tracing::instrument(skip_all)]
+pub async fn dev_create_session(
+    State(state): State<DogState>,
+    Json(payload): Json<ApiDevSessionPayload>,
+) -> ApiResult<(StatusCode, Json<ApiDevSession>)> {
+    check_dev_tools(&state)?;
+    let user = user_named(&state.db, &payload.user).await?;
+    let session = state
+        .db
+        .sessions()
+        .create(user.id, Some("eardogger dev_tools"))
+        .await?;
+    info!(username = %user.username, "dev: created session");
+    Ok((
+        StatusCode::CREATED,
+        Json(ApiDevSession {
+            session_id: session.id,
+            csrf_token: session.csrf_token,
+        }),
+    ))
+}
+
+/// `POST /dev/sessions`, in a build without the feature: 404. This one
+/// especially; it'd log anybody in as anybody.
+#[cfg(not(feature = "dev_tools"))]
+pub async fn dev_create_session() -> ApiResult<StatusCode> {
+    Err(not_found())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PreviewQuery {
+    fixture: Option<String>,
+}
+
+/// `GET /dev/preview/:page?fixture=...`: one of the logged-in pages as the
+/// made-up user from a fixture would see it, with a banner saying so. No
+/// database involved. Leaving off the fixture gets you the first one.
+#[tracing::instrument(skip_all)]
+pub async fn dev_preview(
+    State(state): State<DogState>,
+    Path(page): Path<String>,
+    Query(query): Query<PreviewQuery>,
+) -> WebResult<Html<String>> {
+    // Same deal as check_dev_tools, but as a web page.
+    if !state.config.dev_tools {
+        return Err(WebError::new(
+            StatusCode::NOT_FOUND,
+            "Not found.".to_string(),
+        ));
+    }
+    let fixture = query.fixture.as_deref().unwrap_or(fixtures::FIXTURES[0]);
+    let (template, ctx) = fixtures::preview(&page, fixture).ok_or_else(|| {
+        WebError::new(
+            StatusCode::NOT_FOUND,
+            format!(
+                "Can't preview {:?} with {:?}. The pages are {} and the fixtures are {}.",
+                page,
+                fixture,
+                fixtures::PAGES.join(", "),
+                fixtures::FIXTURES.join(", "),
+            ),
+        )
+    })?;
+    Ok(Html(state.render_view(template, ctx)?))
+}
@@ -0,0 +1,322 @@
+//! The optional in-process cache for the index page's dogear list (see
+//! `IndexCacheConfig`). Entries are keyed by user and page, and the cache
+//! listens for dogear change notices, so a write throws out that user's
+//! lists before their next page load can see a stale one.
+
+use crate::config::IndexCacheConfig;
+use crate::db::{ChangeListener, Db, Dogear};
+use crate::util::{ListMeta, MixedError};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio_util::task::TaskTracker;
+use tracing::error;
+
+/// Once there are this many cached pages, sweep out the expired ones
+/// before adding another.
+const PRUNE_THRESHOLD: usize = 1000;
+
+/// Everything the index page shows from one page of someone's dogears.
+#[derive(Debug)]
+pub struct IndexData {
+    pub dogears: Vec<Dogear>,
+    pub meta: ListMeta,
+    pub notes: HashMap<i64, String>,
+    pub tags: HashMap<i64, Vec<String>>,
+}
+
+impl IndexData {
+    /// Read it all straight from the db, optionally narrowed down by a
+    /// search. (Searches are never cached.)
+    pub async fn load(
+        db: &Db,
+        user_id: i64,
+        filter: Option<&str>,
+        page: u32,
+        size: u32,
+    ) -> Result<Self, MixedError<sqlx::Error>> {
+        let (dogears, meta) = db
+            .dogears()
+            .list_filtered(user_id, filter, page, size)
+            .await?;
+        let notes = db.dogears().current_notes(user_id).await?;
+        let tags = db.dogears().tags(user_id).await?;
+        Ok(Self {
+            dogears,
+            meta,
+            notes,
+            tags,
+        })
+    }
+
+    /// Same, but for a page of the archive. (Never cached.)
+    pub async fn load_archived(
+        db: &Db,
+        user_id: i64,
+        page: u32,
+        size: u32,
+    ) -> Result<Self, MixedError<sqlx::Error>> {
+        let (dogears, meta) = db.dogears().list_archived(user_id, page, size).await?;
+        let notes = db.dogears().current_notes(user_id).await?;
+        let tags = db.dogears().tags(user_id).await?;
+        Ok(Self {
+            dogears,
+            meta,
+            notes,
+            tags,
+        })
+    }
+}
+
+/// (user_id, page, size)
+type Key = (i64, u32, u32);
+
+#[derive(Debug)]
+struct Entry {
+    data: Arc<IndexData>,
+    /// When the read that produced this started.
+    loaded: Instant,
+    /// Whether a background refresh is already on its way.
+    refreshing: bool,
+}
+
+#[derive(Debug)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    changes: ChangeListener,
+    /// Goes up with every invalidation. A read only gets cached if this
+    /// didn't move while it was running, so a slow read can't put back
+    /// something a write just threw out.
+    epoch: u64,
+}
+
+impl Entries {
+    /// Throw out the lists of anyone whose dogears moved since last time.
+    fn catch_up(&mut self) {
+        match self.changes.drain() {
+            Some(moved) if moved.is_empty() => {}
+            Some(moved) => {
+                self.map
+                    .retain(|(user_id, _, _), _| !moved.contains(user_id));
+                self.epoch += 1;
+            }
+            // Dropped notices; could've been anyone.
+            None => {
+                self.map.clear();
+                self.epoch += 1;
+            }
+        }
+    }
+}
+
+/// Cheap to clone; clones share the same cache.
+#[derive(Clone, Debug)]
+pub struct IndexCache {
+    fresh: Duration,
+    max_age: Duration,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl IndexCache {
+    pub fn new(config: &IndexCacheConfig, db: &Db) -> Self {
+        Self::with_ages(
+            Duration::from_secs(config.fresh_secs),
+            Duration::from_secs(config.stale_secs),
+            db,
+        )
+    }
+
+    fn with_ages(fresh: Duration, stale: Duration, db: &Db) -> Self {
+        Self {
+            fresh,
+            max_age: fresh + stale,
+            entries: Arc::new(Mutex::new(Entries {
+                map: HashMap::new(),
+                changes: db.changes().subscribe(),
+                epoch: 0,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// One page of a user's dogears, from the cache if it's young enough.
+    /// A stale page also kicks off a refresh on the task tracker, so the
+    /// next visit gets a fresh one without waiting.
+    pub async fn get(
+        &self,
+        db: &Db,
+        tracker: &TaskTracker,
+        user_id: i64,
+        page: u32,
+        size: u32,
+    ) -> Result<Arc<IndexData>, MixedError<sqlx::Error>> {
+        let key = (user_id, page, size);
+        let epoch = {
+            let mut guard = self.lock();
+            let entries = &mut *guard;
+            entries.catch_up();
+            if let Some(entry) = entries.map.get_mut(&key) {
+                let age = entry.loaded.elapsed();
+                if age < self.fresh {
+                    return Ok(entry.data.clone());
+                }
+                if age < self.max_age {
+                    if !entry.refreshing {
+                        entry.refreshing = true;
+                        self.spawn_refresh(db, tracker, key, entries.epoch);
+                    }
+                    return Ok(entry.data.clone());
+                }
+            }
+            entries.epoch
+        };
+        let started = Instant::now();
+        let data = Arc::new(IndexData::load(db, user_id, None, page, size).await?);
+        self.store(key, data.clone(), epoch, started);
+        Ok(data)
+    }
+
+    fn spawn_refresh(&self, db: &Db, tracker: &TaskTracker, key: Key, epoch: u64) {
+        let cache = self.clone();
+        let db = db.clone();
+        tracker.spawn(async move {
+            let started = Instant::now();
+            let (user_id, page, size) = key;
+            match IndexData::load(&db, user_id, None, page, size).await {
+                Ok(data) => cache.store(key, Arc::new(data), epoch, started),
+                Err(e) => {
+                    error!(user_id, "couldn't refresh cached index list: {}", e);
+                    if let Some(entry) = cache.lock().map.get_mut(&key) {
+                        entry.refreshing = false;
+                    }
+                }
+            }
+        });
+    }
+
+    fn store(&self, key: Key, data: Arc<IndexData>, epoch: u64, started: Instant) {
+        let mut guard = self.lock();
+        let entries = &mut *guard;
+        entries.catch_up();
+        if entries.epoch != epoch {
+            // Something moved mid-read, maybe not even for this user, but
+            // it's not worth sorting out. Let the next visit try again.
+            if let Some(entry) = entries.map.get_mut(&key) {
+                entry.refreshing = false;
+            }
+            return;
+        }
+        if entries.map.len() >= PRUNE_THRESHOLD {
+            let max_age = self.max_age;
+            entries
+                .map
+                .retain(|_, entry| entry.loaded.elapsed() < max_age);
+        }
+        entries.map.insert(
+            key,
+            Entry {
+                data,
+                loaded: started,
+                refreshing: false,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn setup() -> (Db, i64, i64) {
+        let db = Db::new_test_db().await;
+        let user = db.test_user("whoever").await.unwrap();
+        let (dogears, _) = db.dogears().list(user.id, 1, 50).await.unwrap();
+        let dogear_id = dogears
+            .iter()
+            .find(|d| d.prefix == "example.com/comic")
+            .unwrap()
+            .id;
+        (db, user.id, dogear_id)
+    }
+
+    #[tokio::test]
+    async fn fresh_hits_and_invalidation() {
+        let (db, user_id, dogear_id) = setup().await;
+        let tracker = TaskTracker::new();
+        let cache = IndexCache::with_ages(Duration::from_secs(60), Duration::ZERO, &db);
+
+        let first = cache.get(&db, &tracker, user_id, 1, 50).await.unwrap();
+        let again = cache.get(&db, &tracker, user_id, 1, 50).await.unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        // Different page size, different entry.
+        let other = cache.get(&db, &tracker, user_id, 1, 10).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &other));
+
+        // A write that sends a notice throws the user's lists out.
+        db.dogears()
+            .set_paused(dogear_id, user_id, true)
+            .await
+            .unwrap();
+        let after = cache.get(&db, &tracker, user_id, 1, 50).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &after));
+        assert!(after.dogears.iter().any(|d| d.id == dogear_id && d.paused));
+
+        // Someone else's write leaves this user's lists alone.
+        let other_user = db.test_user("someone_else").await.unwrap();
+        let (theirs, _) = db.dogears().list(other_user.id, 1, 50).await.unwrap();
+        db.dogears()
+            .set_paused(theirs[0].id, other_user.id, true)
+            .await
+            .unwrap();
+        let still = cache.get(&db, &tracker, user_id, 1, 50).await.unwrap();
+        assert!(Arc::ptr_eq(&after, &still));
+    }
+
+    #[tokio::test]
+    async fn stale_while_revalidate() {
+        let (db, user_id, dogear_id) = setup().await;
+        let tracker = TaskTracker::new();
+        let cache = IndexCache::with_ages(Duration::from_millis(20), Duration::from_secs(60), &db);
+
+        let first = cache.get(&db, &tracker, user_id, 1, 50).await.unwrap();
+        // A write that doesn't send a notice, like one from another process.
+        db.dogears()
+            .set_location(
+                dogear_id,
+                "example.com/comic",
+                "https://example.com/comic/99",
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        // Stale: same old list, but a refresh is underway.
+        let stale = cache.get(&db, &tracker, user_id, 1, 50).await.unwrap();
+        assert!(Arc::ptr_eq(&first, &stale));
+        tracker.close();
+        tracker.wait().await;
+
+        let fresh = cache.get(&db, &tracker, user_id, 1, 50).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &fresh));
+        assert!(fresh
+            .dogears
+            .iter()
+            .any(|d| d.current == "https://example.com/comic/99"));
+    }
+
+    #[tokio::test]
+    async fn expired_waits_for_the_db() {
+        let (db, user_id, _) = setup().await;
+        let tracker = TaskTracker::new();
+        let cache = IndexCache::with_ages(Duration::from_millis(10), Duration::ZERO, &db);
+
+        let first = cache.get(&db, &tracker, user_id, 1, 50).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let second = cache.get(&db, &tracker, user_id, 1, 50).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(tracker.is_empty());
+    }
+}
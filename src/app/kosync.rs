@@ -0,0 +1,306 @@
+//! A sync server for KOReader's "Progress sync" plugin, so e-books can keep
+//! their place in Eardogger right alongside the web serials. Point the
+//! plugin's custom sync server at `{public_url}kosync`, and log in with your
+//! username and a KOReader password from the install page.
+//!
+//! The protocol is koreader-sync-server's: `x-auth-user` and `x-auth-key`
+//! headers (the key is the md5 of the password, which is why kosync tokens
+//! get hashed differently), JSON bodies, and `{ "code", "message" }` errors
+//! with its own numbering. We only do the parts the plugin actually uses,
+//! and signing up happens on the website like with everything else.
+//!
+//! Each book gets a dogear whose current URL is a little progress page
+//! here on the site, so it shows up in your list and moves when you read.
+
+use super::authentication::AuthSession;
+use super::routes::own_url;
+use super::state::DogState;
+use super::templates::*;
+use super::web_result::{WebError, WebResult};
+use crate::db::{KosyncDocument, KosyncPosition, TokenScope, User};
+use crate::util::{matchable_from_url, url_encoding::encode_uri_component, IntoHandlerError};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Json, Response},
+};
+use minijinja::context;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, warn};
+
+const HEADER_USER: &str = "x-auth-user";
+const HEADER_KEY: &str = "x-auth-key";
+
+// Length caps for what the reader sends. Real values are way shorter:
+// documents are md5 hashes, and xpointers are a few dozen characters.
+const MAX_DOCUMENT_LEN: usize = 256;
+const MAX_PROGRESS_LEN: usize = 2048;
+const MAX_DEVICE_LEN: usize = 256;
+
+/// An error in koreader-sync-server's format. KOReader shows the message
+/// when something goes wrong, so make it something a person can act on.
+#[derive(Debug)]
+pub struct KosyncError {
+    status: StatusCode,
+    code: u16,
+    message: String,
+}
+
+impl KosyncError {
+    fn unauthorized() -> Self {
+        Self {
+            status: StatusCode::UNAUTHORIZED,
+            code: 2001,
+            message: "Unauthorized".to_string(),
+        }
+    }
+
+    fn invalid(message: &str) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            code: 2003,
+            message: message.to_string(),
+        }
+    }
+
+    fn no_document() -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            code: 2004,
+            message: "Field 'document' not provided.".to_string(),
+        }
+    }
+}
+
+impl<E: IntoHandlerError> From<E> for KosyncError {
+    fn from(value: E) -> Self {
+        let (status, message) = value.status_and_message();
+        if status.is_server_error() {
+            // Nothing KOReader can do about these, so just log them.
+            error!(%message, "kosync: server error");
+            return Self {
+                status,
+                code: 2000,
+                message: "Unknown server error.".to_string(),
+            };
+        }
+        Self {
+            status,
+            code: 2003,
+            message,
+        }
+    }
+}
+
+impl IntoResponse for KosyncError {
+    fn into_response(self) -> Response {
+        let body = json!({ "code": self.code, "message": self.message });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+type KosyncResult<T> = Result<T, KosyncError>;
+
+/// Check the auth headers. Only kosync tokens count, and only for the
+/// user they belong to.
+async fn kosync_user(state: &DogState, headers: &HeaderMap) -> KosyncResult<User> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let (Some(username), Some(key)) = (header(HEADER_USER), header(HEADER_KEY)) else {
+        return Err(KosyncError::unauthorized());
+    };
+    let Some((token, user)) = state.db.tokens().authenticate(key).await? else {
+        return Err(KosyncError::unauthorized());
+    };
+    if token.scope() != TokenScope::Kosync || user.username != username {
+        warn!(target: "audit", user = %user.username, token_id = token.id, "kosync: wrong token or username");
+        return Err(KosyncError::unauthorized());
+    }
+    Ok(user)
+}
+
+/// `POST /kosync/users/create`: KOReader's register button. Nope.
+pub async fn kosync_create_user() -> KosyncError {
+    KosyncError::invalid("Sign up on the Eardogger website instead, then generate a KOReader password on the install page.")
+}
+
+/// `GET /kosync/users/auth`: KOReader's login button.
+pub async fn kosync_auth(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+) -> KosyncResult<Json<serde_json::Value>> {
+    kosync_user(&state, &headers).await?;
+    Ok(Json(json!({ "authorized": "OK" })))
+}
+
+#[derive(Deserialize, Debug)]
+struct ProgressPayload {
+    document: Option<String>,
+    // A string for reflowable books, but fixed-layout ones send a page number.
+    progress: Option<serde_json::Value>,
+    percentage: Option<f64>,
+    device: Option<String>,
+    device_id: Option<String>,
+}
+
+/// What KOReader gets back when it asks where a book is at.
+#[derive(Serialize, Debug)]
+struct ProgressRecord<'a> {
+    document: &'a str,
+    progress: &'a str,
+    percentage: f64,
+    device: &'a str,
+    device_id: &'a str,
+    /// Unix seconds.
+    timestamp: i64,
+}
+
+impl<'a> From<&'a KosyncDocument> for ProgressRecord<'a> {
+    fn from(doc: &'a KosyncDocument) -> Self {
+        Self {
+            document: &doc.document,
+            progress: &doc.progress,
+            percentage: doc.percentage,
+            device: &doc.device,
+            device_id: &doc.device_id,
+            timestamp: doc.updated.unix_timestamp(),
+        }
+    }
+}
+
+/// The page a book's dogear points at, without any position on it. This
+/// (minus the scheme) is also the dogear's prefix.
+fn book_url(state: &DogState, document: &str) -> String {
+    format!(
+        "{}kosync/books/{}",
+        state.config.public_url,
+        encode_uri_component(document)
+    )
+}
+
+/// `PUT /kosync/syncs/progress`: save a book's position, and move (or make)
+/// its dogear to match.
+#[tracing::instrument(skip_all)]
+pub async fn kosync_put_progress(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> KosyncResult<Json<serde_json::Value>> {
+    let user = kosync_user(&state, &headers).await?;
+    let payload: ProgressPayload = serde_json::from_slice(&body)
+        .map_err(|_| KosyncError::invalid("Couldn't read that progress update."))?;
+
+    let document = match payload.document.as_deref().map(str::trim) {
+        Some(d) if !d.is_empty() => d,
+        _ => return Err(KosyncError::no_document()),
+    };
+    let progress = match &payload.progress {
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(serde_json::Value::Number(n)) => n.to_string(),
+        _ => return Err(KosyncError::invalid("Field 'progress' not provided.")),
+    };
+    let percentage = match payload.percentage {
+        Some(p) if (0.0..=1.0).contains(&p) => p,
+        _ => {
+            return Err(KosyncError::invalid(
+                "Field 'percentage' is missing or out of range.",
+            ))
+        }
+    };
+    let device = payload.device.as_deref().unwrap_or_default();
+    let device_id = payload.device_id.as_deref().unwrap_or_default();
+    if document.len() > MAX_DOCUMENT_LEN
+        || progress.len() > MAX_PROGRESS_LEN
+        || device.len() > MAX_DEVICE_LEN
+        || device_id.len() > MAX_DEVICE_LEN
+    {
+        return Err(KosyncError::invalid("That progress update is too big."));
+    }
+
+    // Move the dogear, or make one if this is a new book (or the user
+    // deleted the old one).
+    let base = book_url(&state, document);
+    let current = format!("{}?percent={}", &base, (percentage * 100.0).round());
+    let dogears = state.db.dogears();
+    let dogear_id = match dogears.update(user.id, &current, None).await? {
+        Some(updates) if !updates.is_empty() => Some(updates[0].dogear.id),
+        _ => {
+            let prefix = matchable_from_url(&base)?;
+            let short_doc: String = document.chars().take(8).collect();
+            let display_name = format!("E-book from KOReader ({})", short_doc);
+            Some(
+                dogears
+                    .create(user.id, prefix, &current, Some(&display_name))
+                    .await?
+                    .id,
+            )
+        }
+    };
+
+    let position = KosyncPosition {
+        progress: &progress,
+        percentage,
+        device,
+        device_id,
+    };
+    let saved = state
+        .db
+        .kosync()
+        .put(user.id, document, dogear_id, position)
+        .await?;
+    Ok(Json(json!({
+        "document": saved.document,
+        "timestamp": saved.updated.unix_timestamp(),
+    })))
+}
+
+/// `GET /kosync/syncs/progress/:document`: where a book is at, or `{}` if
+/// it's never synced.
+#[tracing::instrument(skip_all)]
+pub async fn kosync_get_progress(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> KosyncResult<Response> {
+    let user = kosync_user(&state, &headers).await?;
+    match state.db.kosync().get(user.id, &document).await? {
+        Some(doc) => Ok(Json(ProgressRecord::from(&doc)).into_response()),
+        None => Ok(Json(json!({})).into_response()),
+    }
+}
+
+/// `GET /kosync/books/:document`: what a book's dogear points at. Just a
+/// little note about where you left off, since the book itself is on your
+/// e-reader. Requires logged-in.
+#[tracing::instrument(skip_all)]
+pub async fn kosync_book(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Path(document): Path<String>,
+) -> WebResult<Html<String>> {
+    let Some(doc) = state.db.kosync().get(auth.user.id, &document).await? else {
+        return Err(WebError::new(
+            StatusCode::NOT_FOUND,
+            "No e-book by that name has synced to your account.".to_string(),
+        ));
+    };
+    let dogear = match doc.dogear_id {
+        Some(id) => state.db.dogears().by_id(id, auth.user.id).await?,
+        None => None,
+    };
+    let path = format!("/kosync/books/{}", encode_uri_component(&document));
+    let common = Common {
+        canonical_url: Some(own_url(&state.config.public_url, &path)),
+        ..auth.common_args("E-book progress")
+    };
+    let kosync_book = KosyncBookPage {
+        display_name: dogear.as_ref().and_then(|d| d.display_name.as_deref()),
+        percent: (doc.percentage * 100.0).round() as u8,
+        device: &doc.device,
+        updated: doc.updated,
+    };
+    let ctx = context! { common, kosync_book };
+    Ok(Html(state.render_view("kosync_book.html.j2", ctx)?))
+}
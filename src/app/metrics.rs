@@ -0,0 +1,303 @@
+//! `/metrics`, for Prometheus (or anything else that scrapes its text
+//! format). Request counts and latencies get tallied by a middleware as
+//! they go by; the db pool and background task numbers get read off the
+//! app state at scrape time, same as `/status/details`. Like the rate
+//! limiters, it's all per-process, so under mod_fcgid each scrape only sees
+//! whichever process answered it. It's gated by the same `status_token`.
+//!
+//! The text format's simple enough that a few counters and one histogram
+//! don't need a whole metrics crate.
+
+use super::routes::{check_status_token, PoolStatus};
+use super::state::DogState;
+use super::web_result::ApiResult;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds for the latency histogram, in seconds. Most pages are a
+/// few ms; the long polls are the ones way out past 10.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// What a route gets called when nothing matched (so, a 404). Lumping
+/// those together keeps random scanner URLs from blowing up the label
+/// count.
+const UNMATCHED: &str = "unmatched";
+
+/// Running totals since this process started. Cheap to clone; clones share
+/// the same numbers.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    // std Mutex is fine, since nobody holds it across an await.
+    requests: Arc<Mutex<Requests>>,
+    sessions_pruned: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Default)]
+struct Requests {
+    /// (method, route, status) => count
+    counts: BTreeMap<(String, String, u16), u64>,
+    /// (method, route) => latencies
+    latencies: BTreeMap<(String, String), Histogram>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Not cumulative; `render` adds them up.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+impl Metrics {
+    /// Count one finished request.
+    pub fn request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        *requests
+            .counts
+            .entry((method.to_string(), route.to_string(), status))
+            .or_default() += 1;
+        requests
+            .latencies
+            .entry((method.to_string(), route.to_string()))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Count sessions the daily cleanup threw out.
+    pub fn sessions_pruned(&self, count: u64) {
+        self.sessions_pruned.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Write out the counters and histograms.
+    fn render(&self, out: &mut String) {
+        let requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+
+        describe(
+            out,
+            "eardogger_http_requests_total",
+            "counter",
+            "Requests served, by route and status.",
+        );
+        for ((method, route, status), count) in &requests.counts {
+            let _ = writeln!(
+                out,
+                "eardogger_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                escape(method),
+                escape(route),
+                status,
+                count
+            );
+        }
+
+        let name = "eardogger_http_request_duration_seconds";
+        describe(out, name, "histogram", "How long requests took, by route.");
+        for ((method, route), histogram) in &requests.latencies {
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+            let mut cumulative = 0;
+            for (le, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name, labels, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"+Inf\"}} {}",
+                name, labels, histogram.count
+            );
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+        }
+
+        describe(
+            out,
+            "eardogger_sessions_pruned_total",
+            "counter",
+            "Expired sessions deleted by the daily cleanup.",
+        );
+        let _ = writeln!(
+            out,
+            "eardogger_sessions_pruned_total {}",
+            self.sessions_pruned.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// The HELP and TYPE lines that go before a metric's samples.
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Label values are quoted, so backslashes, quotes, and newlines need
+/// escaping. (None of our routes have any, but methods come from clients.)
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+/// Middleware that times every request and counts it under the route that
+/// matched. Like the deprecation middleware, it needs to go on as a Router
+/// layer, so it can see which route that was.
+pub async fn metrics_middleware(
+    State(metrics): State<Metrics>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| UNMATCHED.to_string());
+    let start = Instant::now();
+    let resp = next.run(req).await;
+    metrics.request(&method, &route, resp.status().as_u16(), start.elapsed());
+    resp
+}
+
+/// `GET /metrics`: everything in Prometheus's text format. Needs the
+/// config's `status_token` as a bearer token, and is a 404 without one.
+#[tracing::instrument(skip_all)]
+pub async fn metrics(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+) -> ApiResult<impl IntoResponse> {
+    check_status_token(&state, &headers)?;
+    let mut out = String::new();
+    state.metrics.render(&mut out);
+
+    let read = PoolStatus::of(&state.db.read_pool);
+    let write = PoolStatus::of(&state.db.write_pool);
+    for (name, help, read_value, write_value) in [
+        (
+            "eardogger_db_pool_connections",
+            "Open database connections.",
+            read.size as usize,
+            write.size as usize,
+        ),
+        (
+            "eardogger_db_pool_idle_connections",
+            "Open database connections nobody's using right now.",
+            read.idle,
+            write.idle,
+        ),
+        (
+            "eardogger_db_pool_max_connections",
+            "The most database connections the pool will open.",
+            read.max as usize,
+            write.max as usize,
+        ),
+    ] {
+        describe(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "{}{{pool=\"read\"}} {}", name, read_value);
+        let _ = writeln!(out, "{}{{pool=\"write\"}} {}", name, write_value);
+    }
+
+    describe(
+        &mut out,
+        "eardogger_background_tasks",
+        "gauge",
+        "Tasks the tracker is waiting on, including the long-running workers.",
+    );
+    let _ = writeln!(
+        out,
+        "eardogger_background_tasks {}",
+        state.task_tracker.len()
+    );
+
+    describe(
+        &mut out,
+        "eardogger_job_last_run_timestamp_seconds",
+        "gauge",
+        "When each background worker last finished a pass.",
+    );
+    for run in state.job_clock.snapshot() {
+        let _ = writeln!(
+            out,
+            "eardogger_job_last_run_timestamp_seconds{{job=\"{}\"}} {}",
+            escape(run.job),
+            run.last_run.unix_timestamp()
+        );
+    }
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/plain; version=0.0.4; charset=utf-8",
+            ),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        out,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.request("GET", "/status", 204, Duration::from_millis(2));
+        metrics.request("GET", "/status", 204, Duration::from_millis(30));
+        metrics.request("GET", UNMATCHED, 404, Duration::from_secs(20));
+        metrics.sessions_pruned(3);
+        metrics.clone().sessions_pruned(2);
+        let mut out = String::new();
+        metrics.render(&mut out);
+
+        for expected in [
+            "# TYPE eardogger_http_requests_total counter",
+            r#"eardogger_http_requests_total{method="GET",route="/status",status="204"} 2"#,
+            r#"eardogger_http_requests_total{method="GET",route="unmatched",status="404"} 1"#,
+            "# TYPE eardogger_http_request_duration_seconds histogram",
+            // Buckets are cumulative...
+            r#"eardogger_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.005"} 1"#,
+            r#"eardogger_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.025"} 1"#,
+            r#"eardogger_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.05"} 2"#,
+            r#"eardogger_http_request_duration_seconds_bucket{method="GET",route="/status",le="+Inf"} 2"#,
+            r#"eardogger_http_request_duration_seconds_count{method="GET",route="/status"} 2"#,
+            // ...and the slowpokes only show up in +Inf.
+            r#"eardogger_http_request_duration_seconds_bucket{method="GET",route="unmatched",le="10"} 0"#,
+            r#"eardogger_http_request_duration_seconds_bucket{method="GET",route="unmatched",le="+Inf"} 1"#,
+            "eardogger_sessions_pruned_total 5",
+        ] {
+            assert!(
+                out.lines().any(|l| l == expected),
+                "no line {:?} in:\n{}",
+                expected,
+                out
+            );
+        }
+
+        assert_eq!(escape("a\"b\\c\nd"), r#"a\"b\\c\nd"#);
+    }
+}
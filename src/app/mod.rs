@@ -1,68 +1,508 @@
+mod admin;
+mod admin_users;
+mod api_examples;
 mod app_tests;
 mod authentication;
+mod deprecations;
+mod dev;
+mod index_cache;
+mod kosync;
+mod metrics;
+mod request_id;
 mod routes;
 pub mod state;
 mod templates;
+mod two_factor;
 mod web_result;
 
 use authentication::{session_middleware, token_middleware};
+use deprecations::{api_changelog, deprecation_middleware, DeprecationTable, DEPRECATIONS};
 use routes::*;
 use state::DogState;
 pub use templates::load_templates;
+use web_result::{AppError, AppErrorKind};
 
+use crate::config::CorsRule;
 use axum::{
+    error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
     handler::HandlerWithoutStateExt,
-    middleware::from_fn_with_state,
-    routing::{delete, get, post},
-    Router,
+    http::{header, request::Parts, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{from_fn, from_fn_with_state, map_response_with_state},
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post, put, MethodRouter},
+    BoxError, Router,
 };
+use std::time::Duration;
+use tower::{timeout::TimeoutLayer, ServiceBuilder};
 use tower_cookies::CookieManagerLayer;
-use tower_http::services::ServeDir;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, CorsLayer};
+use tower_http::services::{ServeDir, ServeFile};
+use url::Url;
+
+/// Time budget for normal requests. Nothing we do should take more than a
+/// fraction of a second, so anything that hits this is wedged (probably
+/// waiting on the db writer), and the client is better off with an error
+/// than with a connection that hangs open forever.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+/// Time budget for bulk operations like import and export, which can
+/// legitimately chew on a lot of rows.
+const BULK_TIMEOUT: Duration = Duration::from_secs(120);
+/// Export files can be hefty (Wallabag's include every article's full
+/// text), so the import form gets a bigger body limit than axum's 2MB.
+const IMPORT_BODY_LIMIT: usize = 32 * 1024 * 1024;
+/// Long-polls hold the request open on purpose, so they get their own
+/// longest wait plus a little slack.
+const LONG_POLL_TIMEOUT: Duration = Duration::from_secs(WAIT_MAX_SECS + 5);
 
 /// Return a fully-functional eardogger app! The caller is in charge of building
 /// the state, but we DO need it here in order to construct our auth middleware,
 /// since we're using slacker mode instead of writing proper Tower middleware types.
 pub fn eardogger_app(state: DogState) -> Router {
+    app_and_routes(state).0
+}
+
+/// Every path the app routes, as `app_and_routes` registered them. Static
+/// files (the /public dir and /sw.js) aren't in here. The tests walk this
+/// to make sure no route gets added without coverage; see
+/// app_tests/routes.rs.
+#[derive(Debug, Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct RouteTable {
+    pub paths: Vec<&'static str>,
+}
+
+impl RouteTable {
+    /// Build a Router from some routes, noting down their paths.
+    fn routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (&'static str, MethodRouter<DogState>)>,
+    ) -> Router<DogState> {
+        routes
+            .into_iter()
+            .fold(Router::new(), |router, (path, method_router)| {
+                self.paths.push(path);
+                router.route(path, method_router)
+            })
+    }
+}
+
+/// The guts of `eardogger_app`, plus the table of routes it registered.
+pub fn app_and_routes(state: DogState) -> (Router, RouteTable) {
+    let mut table = RouteTable::default();
     let session_auth = from_fn_with_state(state.clone(), session_middleware);
     let token_auth = from_fn_with_state(state.clone(), token_middleware);
-    Router::new()
-        .route("/", get(root))
-        .route("/mark/:url", get(mark_url))
-        .route("/mark", post(post_mark))
-        .route("/resume/:url", get(resume))
-        .route("/faq", get(faq))
-        .route("/account", get(account))
-        .route("/install", get(install))
-        .route("/login", post(post_login))
-        .route("/logout", post(post_logout))
-        .route("/signup", post(post_signup))
-        .route("/changepassword", post(post_changepassword))
-        .route("/change_email", post(post_change_email))
-        .route("/delete_account", post(post_delete_account))
-        .route("/fragments/dogears", get(fragment_dogears))
-        .route("/fragments/tokens", get(fragment_tokens))
-        .route("/fragments/sessions", get(fragment_sessions))
-        .route("/fragments/personalmark", post(post_fragment_personalmark))
-        .route("/tokens/:id", delete(delete_token))
-        .route("/sessions/:id", delete(delete_session))
-        .route("/api/v1/list", get(api_list))
-        .route("/api/v1/dogear/:id", delete(api_delete))
-        .route("/api/v1/create", post(api_create))
-        .route(
-            "/api/v1/update",
-            post(api_update).options(api_update_cors_preflight),
-        )
+
+    // Routes come in groups that share a time budget and an error format.
+    let web_routes = table
+        .routes([
+            ("/", get(root)),
+            ("/mark/:url", get(mark_url)),
+            ("/mark", post(post_mark)),
+            ("/resume/:url", get(resume)),
+            ("/share", get(share)),
+            ("/faq", get(faq)),
+            ("/account", get(account)),
+            ("/account/sessions", get(account_sessions)),
+            ("/install", get(install)),
+            ("/bookmarklets", get(bookmarklets_page)),
+            ("/new_chapters", get(new_chapters)),
+            ("/archived", get(archived_page)),
+            ("/archive_stale", post(post_archive_stale)),
+            ("/login", post(post_login)),
+            (
+                "/login/totp",
+                get(two_factor::login_totp).post(two_factor::post_login_totp),
+            ),
+            ("/home_instance", post(post_home_instance)),
+            ("/theme", post(post_theme)),
+            ("/logout", post(post_logout)),
+            ("/whats_new/dismiss", post(post_dismiss_whats_new)),
+            ("/signup", post(post_signup)),
+            (
+                "/changepassword",
+                get(change_password_page).post(post_changepassword),
+            ),
+            ("/change_email", post(post_change_email)),
+            ("/login_alerts", post(post_login_alerts)),
+            ("/public_profile", post(post_public_profile)),
+            ("/list_display", post(post_list_display)),
+            ("/history_retention", post(post_history_retention)),
+            ("/stale_nudge", post(post_stale_nudge)),
+            ("/page_size", post(post_page_size)),
+            ("/backups", post(post_backups)),
+            (
+                "/account/custom_css",
+                get(custom_css_page).post(post_custom_css),
+            ),
+            ("/account/import/migration_code", post(post_migration_code)),
+            ("/delete_account", post(post_delete_account)),
+            ("/fragments/dogears", get(fragment_dogears)),
+            ("/fragments/archived", get(fragment_archived)),
+            ("/fragments/tokens", get(fragment_tokens)),
+            ("/fragments/sessions", get(fragment_sessions)),
+            ("/fragments/grants", get(fragment_grants)),
+            ("/fragments/hooks", get(fragment_hooks)),
+            ("/fragments/webhooks", get(fragment_webhooks)),
+            ("/fragments/personalmark", post(post_fragment_personalmark)),
+            ("/fragments/bookmarklets", get(fragment_bookmarklets)),
+            (
+                "/fragments/bookmarklets/:id/regenerate",
+                post(post_fragment_regenerate_bookmarklet),
+            ),
+            ("/fragments/quickmark", post(post_fragment_quickmark)),
+            ("/fragments/kosync", post(post_fragment_kosync)),
+            ("/kosync/books/:document", get(kosync::kosync_book)),
+            ("/tokens", post(post_token)),
+            ("/tokens/:id", delete(delete_token).patch(patch_token)),
+            ("/sessions/:id", delete(delete_session)),
+            ("/grants", post(post_grant)),
+            ("/grants/:id", delete(delete_grant)),
+            ("/grants/:id/:action", post(post_grant_action)),
+            ("/webhooks", post(post_webhook)),
+            ("/webhooks/:id", delete(delete_webhook)),
+            ("/totp/setup", post(two_factor::post_totp_setup)),
+            ("/totp/confirm", post(two_factor::post_totp_confirm)),
+            (
+                "/totp/recovery_codes",
+                post(two_factor::post_totp_recovery_codes),
+            ),
+            ("/totp/disable", post(two_factor::post_totp_disable)),
+        ])
+        .layer(map_response_with_state(state.clone(), app_robots_tag));
+    let api_routes = table.routes([
+        ("/api/v1/list", get(api_list)),
+        (
+            "/api/v1/dogear/:id",
+            get(api_dogear).patch(api_edit).delete(api_delete),
+        ),
+        ("/api/v1/dogear/:id/history", get(api_history)),
+        ("/api/v1/dogear/:id/pause", post(api_pause)),
+        ("/api/v1/dogear/:id/unpause", post(api_unpause)),
+        ("/api/v1/dogear/:id/publish", post(api_publish)),
+        ("/api/v1/dogear/:id/unpublish", post(api_unpublish)),
+        ("/api/v1/dogear/:id/archive", post(api_archive)),
+        ("/api/v1/dogear/:id/unarchive", post(api_unarchive)),
+        (
+            "/api/v1/dogear/:id/hook",
+            post(api_create_hook).delete(api_delete_hook),
+        ),
+        (
+            "/api/v1/dogear/:id/feed",
+            put(api_set_feed).delete(api_clear_feed),
+        ),
+        ("/api/v1/create", post(api_create)),
+        ("/api/v1/suggest", get(api_suggest)),
+        ("/api/v1/cadences", get(api_cadences)),
+        ("/api/v1/tokens/rotate", post(api_rotate_token)),
+        ("/api/v1/quickmark", get(api_quickmark)),
+    ]);
+    // The bookmarklets' endpoint, which gets called from every site on the
+    // web, so it gets its own CORS rule. (Plus its no-JSON twin, which gets
+    // the same rule.)
+    let update_routes = table.routes([
+        ("/api/v1/update", post(api_update)),
+        ("/api/v1/dogear", put(api_put_dogear)),
+    ]);
+    // Pages anyone can see. These go outside the auth layers, so they can't
+    // accidentally depend on (or leak) who's looking.
+    let public_routes = table
+        .routes([("/u/:username", get(public_profile))])
+        .layer(map_response_with_state(state.clone(), public_robots_tag));
+    // Email change links. No login needed (the link's signature is the
+    // proof), so these are outside the auth layers too, but they're part
+    // of the app as far as crawlers go.
+    let email_link_routes = table
+        .routes([(
+            "/email/:action/:token",
+            get(email_link).post(post_email_link),
+        )])
+        .layer(map_response_with_state(state.clone(), app_robots_tag));
+    // Inbound webhooks, which bring their own auth (the secret in the URL)
+    // and get called by other servers, not browsers.
+    let hook_routes = table.routes([("/hooks/:secret", post(hook_receiver))]);
+    // KOReader's sync protocol, which brings its own auth and error format.
+    let kosync_routes = table.routes([
+        ("/kosync/users/create", post(kosync::kosync_create_user)),
+        ("/kosync/users/auth", get(kosync::kosync_auth)),
+        ("/kosync/syncs/progress", put(kosync::kosync_put_progress)),
+        (
+            "/kosync/syncs/progress/:document",
+            get(kosync::kosync_get_progress),
+        ),
+    ]);
+    // Operator stuff, which brings its own auth (the config file's admin
+    // token) and can take a while.
+    let admin_routes = table.routes([
+        (
+            "/admin/site_rules",
+            get(admin::admin_export_site_rules).post(admin::admin_import_site_rules),
+        ),
+        ("/admin/rate_limits", get(admin::admin_rate_limits)),
+        ("/admin/features", get(admin::admin_features)),
+        (
+            "/admin/features/:name",
+            put(admin::admin_set_feature).delete(admin::admin_clear_feature),
+        ),
+    ]);
+    // Import and export, which can take a while.
+    let bulk_routes = table.routes([
+        ("/api/v1/export", get(api_export)),
+        (
+            "/api/v1/import",
+            post(api_import).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)),
+        ),
+        ("/api/v1/migrate_out", post(api_migrate_out)),
+    ]);
+    let bulk_web_routes = table
+        .routes([("/account/import", get(import_page).post(post_import))])
+        .layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT));
+    // Site admins' pages, which are for a logged-in user like the rest of
+    // the web routes, but check for admin-ness on top.
+    let admin_web_routes = table
+        .routes([
+            ("/admin/users", get(admin_users::admin_users)),
+            (
+                "/admin/users/:id/:action",
+                post(admin_users::post_admin_user_action),
+            ),
+        ])
+        .layer(map_response_with_state(state.clone(), app_robots_tag));
+    // ...and their JSON flavor.
+    let admin_api_routes =
+        table.routes([("/admin/users/:id", patch(admin_users::patch_admin_user))]);
+    // Test-data factory for throwaway instances, which brings no auth at
+    // all and only exists if the build and the config both turn it on.
+    let dev_routes = table.routes([
+        ("/dev/users", post(dev::dev_create_user)),
+        ("/dev/dogears", post(dev::dev_create_dogears)),
+        ("/dev/sessions", post(dev::dev_create_session)),
+    ]);
+    // Same gate, for template previews with made-up data.
+    let dev_web_routes = table.routes([("/dev/preview/:page", get(dev::dev_preview))]);
+    // Migrations from other instances, which bring their own auth (a
+    // one-time code) and as much data as an import.
+    let migrate_in_routes = table
+        .routes([("/api/v1/migrate_in", post(api_migrate_in))])
+        .layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT));
+    // Swapping a password for a token, which brings its own auth (the
+    // password) but gets called from extensions, so it takes the API's
+    // CORS rule.
+    let token_exchange_routes = table.routes([("/api/v1/token", post(api_token_exchange))]);
+    // Requests that wait around for something to happen.
+    let long_poll_routes = table.routes([("/api/v1/wait_for_update", get(api_wait_for_update))]);
+
+    // Odds and ends at the site root, with no auth, timeout, or robots tag.
+    let site_routes = table.routes([
+        ("/status", get(status)),
+        ("/status/details", get(status_details)),
+        ("/metrics", get(metrics::metrics)),
+        ("/robots.txt", get(robots_txt)),
+        ("/sitemap.xml", get(sitemap_xml)),
+        ("/.well-known/security.txt", get(security_txt)),
+        // Public info, so any site's JS is welcome to read it.
+        (
+            "/.well-known/eardogger.json",
+            get(instance_metadata).layer(
+                CorsLayer::new()
+                    .allow_origin(Any)
+                    .allow_methods(AllowMethods::exact(Method::GET)),
+            ),
+        ),
+        // Same deal.
+        (
+            "/api/changelog",
+            get(api_changelog).layer(
+                CorsLayer::new()
+                    .allow_origin(Any)
+                    .allow_methods(AllowMethods::exact(Method::GET)),
+            ),
+        ),
+        (
+            "/api/schemas/dogear-event.v1.json",
+            get(dogear_event_schema).layer(
+                CorsLayer::new()
+                    .allow_origin(Any)
+                    .allow_methods(AllowMethods::exact(Method::GET)),
+            ),
+        ),
+        ("/favicon.ico", get(status)),
+        ("/favicon.gif", get(status)),
+    ]);
+
+    let cors = &state.config.cors;
+    let public_url = &state.config.public_url;
+
+    let app = with_timeout(web_routes, DEFAULT_TIMEOUT, AppErrorKind::Html)
+        .merge(with_timeout(
+            with_cors(api_routes, &cors.api, public_url),
+            DEFAULT_TIMEOUT,
+            AppErrorKind::Json,
+        ))
+        .merge(with_timeout(
+            with_cors(update_routes, &cors.update, public_url),
+            DEFAULT_TIMEOUT,
+            AppErrorKind::Json,
+        ))
+        .merge(with_timeout(bulk_routes, BULK_TIMEOUT, AppErrorKind::Json))
+        .merge(with_timeout(
+            bulk_web_routes.layer(map_response_with_state(state.clone(), app_robots_tag)),
+            BULK_TIMEOUT,
+            AppErrorKind::Html,
+        ))
+        .merge(with_timeout(
+            long_poll_routes,
+            LONG_POLL_TIMEOUT,
+            AppErrorKind::Json,
+        ))
+        .merge(with_timeout(
+            admin_web_routes,
+            DEFAULT_TIMEOUT,
+            AppErrorKind::Html,
+        ))
+        .merge(with_timeout(
+            admin_api_routes,
+            DEFAULT_TIMEOUT,
+            AppErrorKind::Json,
+        ))
         .layer(token_auth) // inner, so can override session.
         .layer(session_auth)
         .layer(CookieManagerLayer::new())
+        .merge(with_timeout(
+            public_routes,
+            DEFAULT_TIMEOUT,
+            AppErrorKind::Html,
+        ))
+        .merge(with_timeout(
+            email_link_routes,
+            DEFAULT_TIMEOUT,
+            AppErrorKind::Html,
+        ))
+        .merge(with_timeout(
+            with_cors(token_exchange_routes, &cors.api, public_url),
+            DEFAULT_TIMEOUT,
+            AppErrorKind::Json,
+        ))
+        .merge(with_timeout(
+            hook_routes,
+            DEFAULT_TIMEOUT,
+            AppErrorKind::Json,
+        ))
+        .merge(with_timeout(
+            kosync_routes,
+            DEFAULT_TIMEOUT,
+            AppErrorKind::Json,
+        ))
+        .merge(with_timeout(admin_routes, BULK_TIMEOUT, AppErrorKind::Json))
+        .merge(with_timeout(
+            migrate_in_routes,
+            BULK_TIMEOUT,
+            AppErrorKind::Json,
+        ))
+        .merge(with_timeout(dev_routes, BULK_TIMEOUT, AppErrorKind::Json))
+        .merge(with_timeout(
+            dev_web_routes.layer(map_response_with_state(state.clone(), app_robots_tag)),
+            DEFAULT_TIMEOUT,
+            AppErrorKind::Html,
+        ))
         // put static files and 404 outside the auth layers
         .nest_service(
             "/public",
             ServeDir::new(&state.config.assets_dir).not_found_service(four_oh_four.into_service()),
         )
-        .route("/status", get(status))
-        .route("/favicon.ico", get(status))
-        .route("/favicon.gif", get(status))
+        // The service worker has to live at the root to cover the whole site.
+        .route_service(
+            "/sw.js",
+            ServeFile::new(state.config.assets_dir.join("sw.js")),
+        )
+        .merge(site_routes)
         .fallback(four_oh_four)
-        .with_state(state)
+        // Outermost, so it covers every route no matter which group it's in.
+        .layer(from_fn_with_state(
+            DeprecationTable::new(DEPRECATIONS, public_url),
+            deprecation_middleware,
+        ))
+        // Same, and outside the deprecation middleware so its time counts.
+        .layer(from_fn_with_state(
+            state.metrics.clone(),
+            metrics::metrics_middleware,
+        ))
+        // Outside everything, so even the metrics and deprecation layers'
+        // logs get the request's ID.
+        .layer(from_fn(request_id::request_id_middleware))
+        .with_state(state);
+    (app, table)
+}
+
+/// Wrap a group of routes in a deadline. If a handler is still going when
+/// the budget runs out, we drop it on the floor and send a 504 instead.
+/// (Dropping a handler mid-query is safe: sqlx rolls back any open
+/// transaction, and the must-finish writes are already spawned on the
+/// task tracker.)
+fn with_timeout(
+    router: Router<DogState>,
+    budget: Duration,
+    kind: AppErrorKind,
+) -> Router<DogState> {
+    router.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(move |err: BoxError| async move {
+                timeout_error(err, kind)
+            }))
+            .layer(TimeoutLayer::new(budget)),
+    )
+}
+
+/// Let other sites' scripts call a group of routes, per one of the config's
+/// CORS rules. The layer answers preflights itself, so they never reach a
+/// handler. A rule with no origins leaves the group alone entirely.
+fn with_cors(router: Router<DogState>, rule: &CorsRule, public_url: &Url) -> Router<DogState> {
+    if rule.origins.is_empty() {
+        return router;
+    }
+    let own_origin = public_url.origin().ascii_serialization();
+    let anybody = rule.origins.iter().any(|o| o == "*");
+    let origins = rule.origins.clone();
+    // Same-origin requests aren't CORS, so they don't get CORS headers.
+    let allow_origin = AllowOrigin::predicate(move |origin: &HeaderValue, _: &Parts| {
+        origin
+            .to_str()
+            .is_ok_and(|o| o != own_origin && (anybody || origins.iter().any(|a| a == o)))
+    });
+    // Validation already turned away anything that won't parse.
+    let methods = rule.methods.iter().filter_map(|m| m.parse::<Method>().ok());
+    router.layer(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(AllowMethods::list(methods))
+            .allow_headers(AllowHeaders::list([
+                header::CONTENT_TYPE,
+                header::AUTHORIZATION,
+                header::CONTENT_LENGTH,
+                HeaderName::from_static("x-requested-with"),
+            ]))
+            // So scripts can read the ID back when reporting a problem.
+            .expose_headers([request_id::REQUEST_ID_HEADER])
+            // No cookie auth cross-origin; it's tokens or the highway.
+            .allow_credentials(false),
+    )
+}
+
+fn timeout_error(err: BoxError, kind: AppErrorKind) -> Response {
+    if err.is::<tower::timeout::error::Elapsed>() {
+        AppError::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "The server took too long to handle that, so it gave up. Try again in a bit."
+                .to_string(),
+            kind,
+        )
+        .into_response()
+    } else {
+        AppError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Unhandled middleware error: {}", err),
+            kind,
+        )
+        .into_response()
+    }
 }
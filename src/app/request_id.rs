@@ -0,0 +1,104 @@
+//! Request IDs, so "it broke when I clicked the thing" can turn into a grep
+//! through the logs. Every request gets an ID (or keeps the one a proxy in
+//! front of us already put in `X-Request-Id`), every log line it causes
+//! gets tagged with it via a `request` span, and it goes back out in the
+//! response's `X-Request-Id` header. Error pages and JSON errors print it
+//! too, since that's what people actually copy into a bug report.
+
+use crate::util::uuid_string;
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{info_span, Instrument};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest inbound ID we'll keep. Anything past this is somebody being silly.
+const MAX_INBOUND_LEN: usize = 128;
+
+tokio::task_local! {
+    // A task-local instead of a request extension, because the error
+    // responses get built way down in IntoResponse impls that never see
+    // the request.
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request we're in the middle of handling, if any. (Spawned
+/// tasks don't inherit it, so they get None.)
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Use the inbound ID if it looks like an ID. Since it ends up in logs and
+/// in HTML, anything with characters beyond the usual uuid/token suspects
+/// gets tossed and replaced.
+fn inbound_id(headers: &HeaderMap) -> Option<String> {
+    let id = headers.get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let plausible = !id.is_empty()
+        && id.len() <= MAX_INBOUND_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'));
+    plausible.then(|| id.to_string())
+}
+
+/// Middleware that gives each request its ID, runs the rest of the app
+/// inside a span (and a task-local scope) carrying it, and sends it back
+/// in the response headers. Goes outermost, so everything else is covered.
+pub async fn request_id_middleware(req: Request, next: Next) -> Response {
+    let id = inbound_id(req.headers()).unwrap_or_else(uuid_string);
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        method = %req.method(),
+        path = %req.uri().path(),
+    );
+    let mut resp = REQUEST_ID
+        .scope(id.clone(), next.run(req).instrument(span))
+        .await;
+    // Can't fail for a uuid or for anything that passed inbound_id.
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inbound_ids() {
+        let with = |v: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(REQUEST_ID_HEADER, HeaderValue::from_str(v).unwrap());
+            inbound_id(&headers)
+        };
+        assert_eq!(
+            with("5f0c8a8e-1d2b-4c1e-9a43-3f1a2b3c4d5e").as_deref(),
+            Some("5f0c8a8e-1d2b-4c1e-9a43-3f1a2b3c4d5e")
+        );
+        assert_eq!(
+            with("edge:1234.abc_DEF").as_deref(),
+            Some("edge:1234.abc_DEF")
+        );
+        assert_eq!(with(""), None);
+        assert_eq!(with("has spaces"), None);
+        assert_eq!(with("<script>"), None);
+        assert_eq!(with(&"a".repeat(MAX_INBOUND_LEN + 1)), None);
+        assert_eq!(inbound_id(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn scoped() {
+        assert_eq!(current_request_id(), None);
+        let inside = REQUEST_ID
+            .scope("abc".to_string(), async { current_request_id() })
+            .await;
+        assert_eq!(inside.as_deref(), Some("abc"));
+    }
+}
@@ -1,29 +1,63 @@
-use super::authentication::{AuthAny, AuthSession};
-use super::state::DogState;
+use super::api_examples::api_examples;
+use super::authentication::{
+    accepts_json, must_reset_password, theme_from_cookies, AuthAny, AuthSession, CsrfHeader,
+};
+use super::state::{DogState, IndexData};
 use super::templates::*;
+use super::two_factor::{start_totp_login, totp_settings};
 use super::web_result::{ApiError, ApiResult, WebError, WebResult};
-use crate::db::{Dogear, TokenScope};
+use crate::backups::{export_stream, ExportFormat, EXPORT_PAGE_SIZE};
+use crate::cadence::{guessed_dogears, Cadence};
+use crate::config::{CrawlPolicy, MAX_RETENTION_MONTHS};
+use crate::db::{
+    AuditKind, AuditSource, BackupDestination, Bookmarklet, DbTx, Dogear, DogearHistoryEntry,
+    DogearUpdate, EmailChange, Grant, Token, TokenScope, User, UserPrefs, CONFIRM_WINDOW_DAYS,
+    DEFAULT_STALE_MONTHS, MAX_STALE_MONTHS, MAX_WEBHOOKS, MIGRATION_CODE_MINUTES,
+    REVERT_WINDOW_DAYS,
+};
+use crate::feeds::validate_feed_url;
+use crate::import::{self, ImportSource, IMPORT_MAX_ENTRIES};
+use crate::migration::{
+    import_export, migrate_in_url, parse_export, Migration, MIGRATION_CODE_HEADER,
+};
+use crate::outbound::{validate_url, Schemes};
 use crate::util::{
-    check_new_password, clean_optional_form_field, uuid_string, ListMeta, Pagination, UserError,
-    COOKIE_LOGIN_CSRF, COOKIE_SESSION, DELETE_ACCOUNT_CONFIRM_STRING, PAGE_DEFAULT_SIZE,
-    SHORT_DATE,
+    chapter_delta, clean_custom_css, clean_field, clean_note, clean_optional_form_field, etag,
+    handoff_url, home_peer, if_none_match, sha256sum, url_encoding::encode_uri_component,
+    uuid_string, validate, verify_action_link, Email, Field, JobRun, MixedError, SignupGuardFields,
+    UserError, UsernamePolicy, COOKIE_HOME_INSTANCE, COOKIE_LOGIN_CSRF, COOKIE_SESSION,
+    COOKIE_THEME, CSRF_TOKEN_HEADER, DELETE_ACCOUNT_CONFIRM_STRING, PAGE_DEFAULT_SIZE,
+    PAGE_MAX_SIZE, SHORT_DATE, TOKEN_COMMENT_MAX_LENGTH,
+};
+// The API's wire types live in the library half of the crate, so the client
+// can share them. Re-exported for the sake of the tests.
+pub use eardogger_rs::api_types::{
+    ApiBulkImportResult, ApiCadence, ApiCreatePayload, ApiDogearsList, ApiEditPayload,
+    ApiFeedPayload, ApiHook, ApiHookPayload, ApiImportDogear, ApiImportProblem, ApiImportReport,
+    ApiMigrateOutPayload, ApiMigrationProgress, ApiNewToken, ApiPrefixSuggestion, ApiRotatedToken,
+    ApiTokenRequest, ApiUpdatePayload, ApiWaitResult, DogearFeed, Freshness, InstanceMetadata,
+    PasswordPolicyInfo, DOGEAR_EVENT_SCHEMA,
 };
 
 use axum::extract::Path;
 use axum::{
+    body::Body,
     extract::{Form, Query, State},
     http::{StatusCode, Uri},
     response::{Html, IntoResponse, Json, Redirect, Response},
 };
-use http::{header, HeaderMap, HeaderValue};
+use http::{header, HeaderMap, HeaderName, HeaderValue};
 use minijinja::context;
 use serde::{Deserialize, Serialize};
-use time::OffsetDateTime;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::Arc;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use tower_cookies::{Cookie, Cookies};
-use tracing::error;
+use tracing::{error, info, warn};
 use url::Url;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct PaginationQuery {
     page: Option<u32>,
     size: Option<u32>,
@@ -34,9 +68,94 @@ impl PaginationQuery {
     pub fn page(&self) -> u32 {
         self.page.unwrap_or(1)
     }
-    /// Getter w/ default value
-    pub fn size(&self) -> u32 {
-        self.size.unwrap_or(PAGE_DEFAULT_SIZE)
+    /// Getter w/ default value, which is usually the user's page size pref.
+    pub fn size(&self, default: u32) -> u32 {
+        self.size.unwrap_or(default)
+    }
+}
+
+/// For viewing someone else's dogears list via a sharing grant, on the
+/// routes that support it. Separate from the pagination query so it can
+/// ride alongside.
+#[derive(Deserialize, Debug)]
+pub struct SharedViewQuery {
+    view: Option<String>,
+}
+
+/// Per-request overrides for the dogears list display prefs, so you can
+/// bookmark a compact view for your phone without changing the default.
+/// Values other than the ones listed are ignored.
+#[derive(Deserialize, Debug)]
+pub struct ListDisplayQuery {
+    /// "compact" or "comfortable"
+    density: Option<String>,
+    /// "show" or "hide"
+    prefix: Option<String>,
+    /// "show" or "hide"
+    dates: Option<String>,
+}
+
+impl ListDisplayQuery {
+    /// Combine the overrides with the user's saved prefs.
+    fn resolve(&self, prefs: &UserPrefs) -> ListDisplay {
+        let density = match self.density.as_deref() {
+            Some(d @ ("compact" | "comfortable")) => Some(d),
+            _ => None,
+        };
+        let show_hide = |v: &Option<String>| match v.as_deref() {
+            Some(v @ ("show" | "hide")) => Some(v),
+            _ => None,
+        };
+        let (prefix, dates) = (show_hide(&self.prefix), show_hide(&self.dates));
+
+        let mut query = String::new();
+        for (name, value) in [("density", density), ("prefix", prefix), ("dates", dates)] {
+            if let Some(value) = value {
+                query.push_str(&format!("{}={}&", name, value));
+            }
+        }
+        ListDisplay {
+            compact: density.map_or(prefs.compact_list, |d| d == "compact"),
+            show_prefix: prefix.map_or(prefs.show_prefix, |p| p == "show"),
+            show_dates: dates.map_or(!prefs.hide_dates, |d| d == "show"),
+            query,
+        }
+    }
+}
+
+/// The dogears list's search box: only show dogears whose prefix, current
+/// URL, or name contains `q`. Blank means everything.
+#[derive(Deserialize, Debug)]
+pub struct ListFilterQuery {
+    q: Option<String>,
+}
+
+impl ListFilterQuery {
+    /// The trimmed search, if there is one.
+    fn filter(&self) -> Result<Option<&str>, UserError> {
+        clean_field(Field::Search, self.q.as_deref())
+    }
+}
+
+/// Permission check for the list routes. If the query asks to view someone
+/// else's dogears, return the grant that lets us do it, or a 404 if there
+/// isn't one. (Same error whether or not that user exists, so this can't be
+/// used to go fishing for usernames.) Returns None for your own list.
+async fn shared_view_grant(
+    state: &DogState,
+    auth: &AuthSession,
+    query: &SharedViewQuery,
+) -> WebResult<Option<Grant>> {
+    match query.view.as_deref() {
+        None | Some("") => Ok(None),
+        Some(name) if name == auth.user.username => Ok(None),
+        Some(name) => match state.db.grants().find(name, auth.user.id).await? {
+            Some(grant) => Ok(Some(grant)),
+            None => Err(UserError::Grant404 {
+                name: name.to_string(),
+            }
+            .into()),
+        },
     }
 }
 
@@ -50,12 +169,342 @@ pub async fn status() -> StatusCode {
     StatusCode::NO_CONTENT
 }
 
+/// A database connection pool, as `/status/details` reports it: how many
+/// connections are open, how many of those are sitting idle, and the cap.
+#[derive(Serialize, Debug)]
+pub struct PoolStatus {
+    pub size: u32,
+    pub idle: usize,
+    pub max: u32,
+}
+
+impl PoolStatus {
+    pub fn of(pool: &SqlitePool) -> Self {
+        Self {
+            size: pool.size(),
+            idle: pool.num_idle(),
+            max: pool.options().get_max_connections(),
+        }
+    }
+}
+
+/// The `/status/details` report. Like everything in memory, it only
+/// covers this process.
+#[derive(Serialize, Debug)]
+pub struct StatusReport {
+    pub read_pool: PoolStatus,
+    pub write_pool: PoolStatus,
+    /// Tasks the tracker is waiting on at shutdown, including the
+    /// long-running workers.
+    pub background_tasks: usize,
+    /// When each worker last finished a pass. See `JobClock`.
+    pub jobs: Vec<JobRun>,
+}
+
+/// Check for the config's `status_token`, which `/status/details` and
+/// `/metrics` need as a bearer token. 404 if the config doesn't have one,
+/// so the routes look like they aren't there.
+pub fn check_status_token(state: &DogState, headers: &HeaderMap) -> ApiResult<()> {
+    let Some(status_token) = &state.config.status_token else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "Not found.".to_string(),
+        ));
+    };
+    let sent = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    // Compare hashes, same as the admin token.
+    if !sent.is_some_and(|token| sha256sum(token.trim()) == sha256sum(status_token)) {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "This needs the status token from the config file.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// `GET /status/details`: a little operational introspection, for when
+/// plain /status says we're up but something still feels stuck. Needs the
+/// status token (see `check_status_token`).
+#[tracing::instrument(skip_all)]
+pub async fn status_details(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+) -> ApiResult<impl IntoResponse> {
+    check_status_token(&state, &headers)?;
+    let report = StatusReport {
+        read_pool: PoolStatus::of(&state.db.read_pool),
+        write_pool: PoolStatus::of(&state.db.write_pool),
+        background_tasks: state.task_tracker.len(),
+        jobs: state.job_clock.snapshot(),
+    };
+    Ok(([(header::CACHE_CONTROL, "no-store")], Json(report)))
+}
+
+/// Most dogears a public profile lists.
+const PUBLIC_PROFILE_LIMIT: u32 = 100;
+
+/// Someone's public "what I'm reading" page. This lives outside the auth
+/// middlewares, so it looks the same to everyone, logged in or not. Users
+/// who don't exist and users who haven't turned their profile on get the
+/// same 404, so this can't be used to check who has an account.
+#[tracing::instrument(skip(state))]
+pub async fn public_profile(
+    State(state): State<DogState>,
+    Path(username): Path<String>,
+    theme: Theme,
+) -> WebResult<Html<String>> {
+    let not_found = || {
+        WebError::new(
+            StatusCode::NOT_FOUND,
+            "There's no public profile here.".to_string(),
+        )
+    };
+    let Some(user) = state.db.users().active_by_name(&username).await? else {
+        return Err(not_found());
+    };
+    if !state.db.prefs().get(user.id).await?.public_profile {
+        return Err(not_found());
+    }
+    let dogears = state
+        .db
+        .dogears()
+        .list_public(user.id, PUBLIC_PROFILE_LIMIT)
+        .await?;
+    let title = format!("What {} is reading", &user.username);
+    let common = Common {
+        canonical_url: Some(profile_url(&state.config.public_url, &user.username)),
+        theme,
+        ..Common::anonymous(&title)
+    };
+    let meta = state.config.crawlers.seo.then(|| ProfileMeta {
+        url: profile_url(&state.config.public_url, &user.username),
+        description: profile_description(&user.username, &dogears),
+    });
+    let profile = PublicProfile {
+        username: &user.username,
+        dogears: &dogears,
+        meta,
+    };
+    let ctx = context! {common, profile};
+    Ok(Html(state.render_view("public_profile.html.j2", ctx)?))
+}
+
+/// robots.txt, from the crawler config. The API and fragments are never
+/// worth crawling, so they're always out.
+pub async fn robots_txt(State(state): State<DogState>) -> impl IntoResponse {
+    let crawlers = &state.config.crawlers;
+    let rules = match (crawlers.public_pages, crawlers.app) {
+        (CrawlPolicy::Allow, CrawlPolicy::Allow) => "Disallow: /api/\nDisallow: /fragments/\n",
+        (CrawlPolicy::Allow, CrawlPolicy::Deny) => "Allow: /u/\nDisallow: /\n",
+        (CrawlPolicy::Deny, CrawlPolicy::Allow) => {
+            "Disallow: /u/\nDisallow: /api/\nDisallow: /fragments/\n"
+        }
+        (CrawlPolicy::Deny, CrawlPolicy::Deny) => "Disallow: /\n",
+    };
+    let mut body = format!("User-agent: *\n{}", rules);
+    if crawlers.seo {
+        body.push_str(&format!(
+            "\nSitemap: {}sitemap.xml\n",
+            &state.config.public_url
+        ));
+    }
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body)
+}
+
+/// Response mapper for the app's pages: adds a noindex header if the config
+/// doesn't want them crawled. (robots.txt keeps polite crawlers out, but
+/// a page that's linked from elsewhere can still get indexed without this.)
+pub async fn app_robots_tag(State(state): State<DogState>, resp: Response) -> Response {
+    with_robots_tag(resp, state.config.crawlers.app)
+}
+
+/// Same deal as `app_robots_tag`, for the public profile pages.
+pub async fn public_robots_tag(State(state): State<DogState>, resp: Response) -> Response {
+    with_robots_tag(resp, state.config.crawlers.public_pages)
+}
+
+fn with_robots_tag(mut resp: Response, policy: CrawlPolicy) -> Response {
+    if policy == CrawlPolicy::Deny {
+        resp.headers_mut().insert(
+            HeaderName::from_static("x-robots-tag"),
+            HeaderValue::from_static("noindex, nofollow"),
+        );
+    }
+    resp
+}
+
+/// /.well-known/security.txt, if the config has one (RFC 9116).
+pub async fn security_txt(State(state): State<DogState>) -> WebResult<Response> {
+    let Some(conf) = &state.config.security_txt else {
+        return Err(four_oh_four().await);
+    };
+    let mut body = String::new();
+    for contact in &conf.contact {
+        body.push_str(&format!("Contact: {}\n", contact));
+    }
+    body.push_str(&format!("Expires: {}\n", &conf.expires));
+    if let Some(policy) = &conf.policy {
+        body.push_str(&format!("Policy: {}\n", policy));
+    }
+    if let Some(languages) = &conf.preferred_languages {
+        body.push_str(&format!("Preferred-Languages: {}\n", languages));
+    }
+    body.push_str(&format!(
+        "Canonical: {}.well-known/security.txt\n",
+        &state.config.public_url
+    ));
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body).into_response())
+}
+
+/// `GET /api/schemas/dogear-event.v1.json`: the JSON Schema for the dogear
+/// events outgoing webhooks send. Public, like the changelog.
+pub async fn dogear_event_schema() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "application/schema+json")],
+        DOGEAR_EVENT_SCHEMA,
+    )
+}
+
+/// /.well-known/eardogger.json: what this instance is and what it can do.
+/// Public info, so any site's JS is welcome to read it.
+pub async fn instance_metadata(State(state): State<DogState>) -> impl IntoResponse {
+    let config = &state.config;
+    let metadata = InstanceMetadata {
+        software: "eardogger-rs".to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        commit: crate::version::commit_sha().to_string(),
+        api_base: format!("{}api/v1/", &config.public_url),
+        signups: "open".to_string(),
+        token_scopes: [
+            TokenScope::WriteDogears,
+            TokenScope::ManageDogears,
+            TokenScope::Quickmark,
+            TokenScope::Kosync,
+        ]
+        .into_iter()
+        .map(|scope| <&str>::from(scope).to_string())
+        .collect(),
+        api_basic_auth: config.api_basic_auth,
+        public_profiles: true,
+        token_exchange: true,
+        password_policy: PasswordPolicyInfo {
+            min_length: config.passwords.min_length,
+            min_strength: config.passwords.min_strength,
+            check_breached: config.passwords.check_breached,
+        },
+    };
+    Json(metadata)
+}
+
+/// The full URL of someone's public profile.
+fn profile_url(public_url: &Url, username: &str) -> String {
+    own_url(
+        public_url,
+        &format!("/u/{}", encode_uri_component(username)),
+    )
+}
+
+/// The full URL of one of our own pages, given its path (and query, if any).
+pub fn own_url(public_url: &Url, path: &str) -> String {
+    // public_url is always a bare origin, so it ends with a slash.
+    format!("{}{}", public_url, path.trim_start_matches('/'))
+}
+
+/// The page number worth mentioning in titles and canonical URLs; page one
+/// is just the page.
+pub fn later_page(query: &PaginationQuery) -> Option<u32> {
+    Some(query.page()).filter(|&p| p > 1)
+}
+
+/// The canonical URL for a page of a paginated list. Keeps the page (past
+/// the first), and drops the page size and any display overrides, since
+/// those only change how much you see and how it looks.
+pub fn paged_url(public_url: &Url, path: &str, view: Option<&str>, page: Option<u32>) -> String {
+    let mut params = url::form_urlencoded::Serializer::new(String::new());
+    if let Some(view) = view {
+        params.append_pair("view", view);
+    }
+    if let Some(page) = page {
+        params.append_pair("page", &page.to_string());
+    }
+    let params = params.finish();
+    if params.is_empty() {
+        own_url(public_url, path)
+    } else {
+        own_url(public_url, &format!("{}?{}", path, params))
+    }
+}
+
+/// A one-liner about a public profile, for link previews.
+fn profile_description(username: &str, dogears: &[Dogear]) -> String {
+    const NAMED: usize = 3;
+    let names: Vec<&str> = dogears
+        .iter()
+        .take(NAMED)
+        .map(|d| d.display_name.as_deref().unwrap_or(&d.prefix))
+        .collect();
+    let others = dogears.len().saturating_sub(NAMED);
+    match (names.as_slice(), others) {
+        ([], _) => format!("{}'s reading list, on Eardogger.", username),
+        ([one], _) => format!("{} is reading {}.", username, one),
+        ([rest @ .., last], 0) => {
+            format!("{} is reading {} and {}.", username, rest.join(", "), last)
+        }
+        (names, others) => format!(
+            "{} is reading {}, and {} more.",
+            username,
+            names.join(", "),
+            others
+        ),
+    }
+}
+
+/// Most URLs one sitemap file is allowed to list.
+const SITEMAP_LIMIT: u32 = 50_000;
+
+/// sitemap.xml, listing the public profiles. Only exists if the crawler
+/// config turns on seo.
+#[tracing::instrument(skip_all)]
+pub async fn sitemap_xml(State(state): State<DogState>) -> WebResult<Response> {
+    if !state.config.crawlers.seo {
+        return Err(four_oh_four().await);
+    }
+    let profiles = state.db.prefs().public_profiles(SITEMAP_LIMIT).await?;
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for profile in &profiles {
+        let loc = profile_url(&state.config.public_url, &profile.username);
+        xml.push_str("  <url>\n");
+        xml.push_str(&format!(
+            "    <loc>{}</loc>\n",
+            html_escape::encode_text(&loc)
+        ));
+        if let Some(lastmod) = profile.last_read.and_then(|t| t.format(&Rfc3339).ok()) {
+            xml.push_str(&format!("    <lastmod>{}</lastmod>\n", lastmod));
+        }
+        xml.push_str("  </url>\n");
+    }
+    xml.push_str("</urlset>\n");
+    Ok((
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        xml,
+    )
+        .into_response())
+}
+
 /// The home page! Shows your dogears list if logged in, and the login
 /// form if not.
 #[tracing::instrument(skip_all)]
 pub async fn root(
     State(state): State<DogState>,
     Query(query): Query<PaginationQuery>,
+    Query(shared_query): Query<SharedViewQuery>,
+    Query(display_query): Query<ListDisplayQuery>,
+    Query(filter_query): Query<ListFilterQuery>,
     maybe_auth: Option<AuthSession>,
     // for login form:
     uri: Uri,
@@ -67,41 +516,395 @@ pub async fn root(
         return login_form(state, cookies, &path).await;
     };
 
-    let (dogears, meta) = state
-        .db
-        .dogears()
-        .list(auth.user.id, query.page(), query.size())
-        .await?;
-    let title = format!("{}'s Dogears", &auth.user.username);
+    let shared = shared_view_grant(&state, &auth, &shared_query).await?;
+    let filter = filter_query.filter()?;
+    let (owner_id, owner_name) = match &shared {
+        Some(grant) => (grant.owner_id, grant.owner_username.as_str()),
+        None => (auth.user.id, auth.user.username.as_str()),
+    };
+    // Your own list can come from the cache; shared views and searches
+    // always read fresh.
+    let list = match (&state.index_cache, &shared, filter) {
+        (Some(cache), None, None) => {
+            cache
+                .get(
+                    &state.db,
+                    &state.task_tracker,
+                    owner_id,
+                    query.page(),
+                    query.size(auth.prefs.default_page_size()),
+                )
+                .await?
+        }
+        _ => Arc::new(
+            IndexData::load(
+                &state.db,
+                owner_id,
+                filter,
+                query.page(),
+                query.size(auth.prefs.default_page_size()),
+            )
+            .await?,
+        ),
+    };
+    let (cadences, stale, feeds) = match &shared {
+        Some(_) => (HashMap::new(), None, HashMap::new()),
+        None => (
+            cadence_notes(&*state.cadences.get(&state.db, owner_id).await?),
+            stale_nudge(&state, &auth).await?,
+            feed_notes(&state, owner_id, &list.dogears).await?,
+        ),
+    };
+    let received_grants = state.db.grants().list_received(auth.user.id).await?;
+    let title = format!("{}'s Dogears", owner_name);
+
+    // What's new: only for your own list, and only once this session has a
+    // previous visit to compare against.
+    let mut moved: Option<(u32, Vec<Dogear>)> = None;
+    if shared.is_none() {
+        if let Some(since) = auth.session.last_index_visit {
+            let (count, recent) = state
+                .db
+                .dogears()
+                .updated_since(auth.user.id, since, WHATS_NEW_LIMIT)
+                .await?;
+            if count > 0 {
+                moved = Some((count, recent));
+            }
+        }
+        state.db.sessions().touch_index_visit(&auth.session.id);
+    }
 
-    let common = auth.common_args(&title);
+    let page = later_page(&query);
+    let common = Common {
+        page,
+        canonical_url: Some(paged_url(
+            &state.config.public_url,
+            "/",
+            shared.as_ref().map(|g| g.owner_username.as_str()),
+            page,
+        )),
+        ..auth.common_args(&title)
+    };
     let dogears_list = DogearsList {
-        dogears: &dogears,
-        pagination: meta.to_pagination(),
+        dogears: &list.dogears,
+        notes: &list.notes,
+        tags: &list.tags,
+        cadences: &cadences,
+        feeds: &feeds,
+        feed_polling: state.config.feed_polling,
+        pagination: list.meta.to_pagination(auth.prefs.default_page_size()),
+        shared_from: shared.as_ref().map(|g| g.owner_username.as_str()),
+        filter,
+        display: display_query.resolve(&auth.prefs),
+        stale: stale.as_ref(),
+        archived: false,
     };
-    let ctx = context! {common, dogears_list};
+    let shared_with_me = GrantsList {
+        grants: &received_grants,
+    };
+    let whats_new = moved.as_ref().map(|(count, recent)| WhatsNew {
+        count: *count,
+        dogears: recent,
+    });
+    let ctx = context! {common, dogears_list, shared_with_me, whats_new};
 
     Ok(Html(state.render_view("index.html.j2", ctx)?))
 }
 
+/// Schedule guesses for the dogears list, as of now. Only your own list
+/// gets these; your reading habits aren't part of what a share grant shares.
+fn cadence_notes(cadences: &HashMap<i64, Cadence>) -> HashMap<i64, CadenceNote> {
+    let now = OffsetDateTime::now_utc();
+    cadences
+        .iter()
+        .map(|(&id, cadence)| (id, CadenceNote::new(cadence, now)))
+        .collect()
+}
+
+/// Watched feeds for your own list's dogears, as the list shows them.
+async fn feed_notes(
+    state: &DogState,
+    user_id: i64,
+    dogears: &[Dogear],
+) -> sqlx::Result<HashMap<i64, FeedNote>> {
+    let mut feeds = state.db.feeds().for_user(user_id).await?;
+    Ok(dogears
+        .iter()
+        .filter_map(|d| feeds.remove(&d.id).map(|f| (d.id, FeedNote::new(&f, d))))
+        .collect())
+}
+
+/// The archive nudge for your own list, unless you've turned it off or
+/// nothing's stale.
+async fn stale_nudge(state: &DogState, auth: &AuthSession) -> sqlx::Result<Option<StaleNudge>> {
+    let Some(months) = auth.prefs.stale_after() else {
+        return Ok(None);
+    };
+    let ids = state.db.dogears().stale_ids(auth.user.id, months).await?;
+    Ok((!ids.is_empty()).then_some(StaleNudge { months, ids }))
+}
+
+/// Archive everything the nudge is nudging about, then back to the index.
+#[tracing::instrument(skip_all)]
+pub async fn post_archive_stale(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<CsrfOnlyParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"That archive button was stale, or had been tampered with.
+                Go back to the home page and try again."#
+                .to_string(),
+        ));
+    }
+    // If the nudge is off, nothing counts as stale.
+    if let Some(months) = auth.prefs.stale_after() {
+        state
+            .db
+            .dogears()
+            .archive_stale(auth.user.id, months)
+            .await?;
+    }
+    Ok(Redirect::to("/"))
+}
+
+/// Your archived dogears. Requires logged-in.
+#[tracing::instrument(skip_all)]
+pub async fn archived_page(
+    State(state): State<DogState>,
+    Query(query): Query<PaginationQuery>,
+    Query(display_query): Query<ListDisplayQuery>,
+    auth: AuthSession,
+) -> WebResult<Html<String>> {
+    let list = IndexData::load_archived(
+        &state.db,
+        auth.user.id,
+        query.page(),
+        query.size(auth.prefs.default_page_size()),
+    )
+    .await?;
+    let cadences = HashMap::new();
+    let feeds = HashMap::new();
+    let page = later_page(&query);
+    let common = Common {
+        page,
+        canonical_url: Some(paged_url(&state.config.public_url, "/archived", None, page)),
+        ..auth.common_args("Archived dogears")
+    };
+    let dogears_list = DogearsList {
+        dogears: &list.dogears,
+        notes: &list.notes,
+        tags: &list.tags,
+        cadences: &cadences,
+        feeds: &feeds,
+        feed_polling: state.config.feed_polling,
+        pagination: list.meta.to_pagination(auth.prefs.default_page_size()),
+        shared_from: None,
+        filter: None,
+        display: display_query.resolve(&auth.prefs),
+        stale: None,
+        archived: true,
+    };
+    let ctx = context! {common, dogears_list};
+    Ok(Html(state.render_view("archived.html.j2", ctx)?))
+}
+
+/// The archive's list by itself, for paging and for refreshing after a
+/// button.
+#[tracing::instrument(skip_all)]
+pub async fn fragment_archived(
+    State(state): State<DogState>,
+    Query(query): Query<PaginationQuery>,
+    Query(display_query): Query<ListDisplayQuery>,
+    auth: AuthSession,
+) -> WebResult<Response> {
+    let list = IndexData::load_archived(
+        &state.db,
+        auth.user.id,
+        query.page(),
+        query.size(auth.prefs.default_page_size()),
+    )
+    .await?;
+    let cadences = HashMap::new();
+    let feeds = HashMap::new();
+    let dogears_list = DogearsList {
+        dogears: &list.dogears,
+        notes: &list.notes,
+        tags: &list.tags,
+        cadences: &cadences,
+        feeds: &feeds,
+        feed_polling: state.config.feed_polling,
+        pagination: list.meta.to_pagination(auth.prefs.default_page_size()),
+        shared_from: None,
+        filter: None,
+        display: display_query.resolve(&auth.prefs),
+        stale: None,
+        archived: true,
+    };
+    let ctx = context! {dogears_list};
+    Ok(fragment_response(Html(
+        state.render_view("fragment.dogears.html.j2", ctx)?,
+    )))
+}
+
+/// The "probably has new chapters" page: your unpaused dogears that have
+/// gone longer than usual without a mark, longest-overdue first. Requires
+/// logged-in.
+#[tracing::instrument(skip_all)]
+pub async fn new_chapters(
+    State(state): State<DogState>,
+    auth: AuthSession,
+) -> WebResult<Html<String>> {
+    let cadences = state.cadences.get(&state.db, auth.user.id).await?;
+    let now = OffsetDateTime::now_utc();
+    let entries: Vec<NewChapter> = guessed_dogears(&state.db, &cadences, auth.user.id)
+        .await?
+        .into_iter()
+        .filter(|(dogear, cadence)| !dogear.paused && cadence.probably_new(now))
+        .map(|(dogear, cadence)| NewChapter {
+            dogear,
+            cadence: CadenceNote::new(&cadence, now),
+        })
+        .collect();
+    let common = Common {
+        canonical_url: Some(own_url(&state.config.public_url, "/new_chapters")),
+        ..auth.common_args("Probably new chapters")
+    };
+    let new_chapters = NewChapters { entries: &entries };
+    let ctx = context! {common, new_chapters};
+    Ok(Html(state.render_view("new_chapters.html.j2", ctx)?))
+}
+
+/// How many moved dogears to name in the front page's "what's new" banner.
+const WHATS_NEW_LIMIT: u32 = 3;
+
+/// Dismiss the "what's new" banner, by pretending you visited just now.
+#[tracing::instrument(skip_all)]
+pub async fn post_dismiss_whats_new(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<CsrfOnlyParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"That dismiss button was stale, or had been tampered with.
+                Go back to the home page and try again."#
+                .to_string(),
+        ));
+    }
+    state
+        .db
+        .sessions()
+        .set_index_visit(&auth.session.id, OffsetDateTime::now_utc())
+        .await?;
+    Ok(Redirect::to("/"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CsrfOnlyParams {
+    pub csrf_token: String,
+}
+
 /// Kind of like the index page, except 1. no login form, 2. therefore auth required.
 #[tracing::instrument(skip_all)]
 pub async fn fragment_dogears(
     State(state): State<DogState>,
     Query(query): Query<PaginationQuery>,
+    Query(shared_query): Query<SharedViewQuery>,
+    Query(display_query): Query<ListDisplayQuery>,
+    Query(filter_query): Query<ListFilterQuery>,
     auth: AuthSession,
-) -> WebResult<Html<String>> {
+    headers: HeaderMap,
+) -> WebResult<Response> {
+    let shared = shared_view_grant(&state, &auth, &shared_query).await?;
+    let filter = filter_query.filter()?;
+    let owner_id = shared.as_ref().map_or(auth.user.id, |g| g.owner_id);
     let (dogears, meta) = state
         .db
         .dogears()
-        .list(auth.user.id, query.page(), query.size())
+        .list_filtered(
+            owner_id,
+            filter,
+            query.page(),
+            query.size(auth.prefs.default_page_size()),
+        )
         .await?;
+    let notes = state.db.dogears().current_notes(owner_id).await?;
+    let tags = state.db.dogears().tags(owner_id).await?;
+    let (cadences, stale, feeds) = match &shared {
+        Some(_) => (HashMap::new(), None, HashMap::new()),
+        None => (
+            cadence_notes(&*state.cadences.get(&state.db, owner_id).await?),
+            stale_nudge(&state, &auth).await?,
+            feed_notes(&state, owner_id, &dogears).await?,
+        ),
+    };
     let dogears_list = DogearsList {
         dogears: &dogears,
-        pagination: meta.to_pagination(),
+        notes: &notes,
+        tags: &tags,
+        cadences: &cadences,
+        feeds: &feeds,
+        feed_polling: state.config.feed_polling,
+        pagination: meta.to_pagination(auth.prefs.default_page_size()),
+        shared_from: shared.as_ref().map(|g| g.owner_username.as_str()),
+        filter,
+        display: display_query.resolve(&auth.prefs),
+        stale: stale.as_ref(),
+        archived: false,
     };
+    if accepts_json(&headers) {
+        // Same as fragment.dogears.html.j2 builds it.
+        let mut extra_query = String::new();
+        if let Some(owner) = dogears_list.shared_from {
+            extra_query.push_str(&format!("view={}&", encode_uri_component(owner)));
+        }
+        if let Some(filter) = dogears_list.filter {
+            extra_query.push_str(&format!("q={}&", encode_uri_component(filter)));
+        }
+        extra_query.push_str(&dogears_list.display.query);
+        let pagination_links = PaginationLinks::new(
+            &dogears_list.pagination,
+            "/",
+            "/fragments/dogears",
+            &extra_query,
+        );
+        return Ok(fragment_response(Json(FragmentJson {
+            list: dogears_list,
+            pagination_links,
+        })));
+    }
     let ctx = context! {dogears_list};
-    Ok(Html(state.render_view("fragment.dogears.html.j2", ctx)?))
+    Ok(fragment_response(Html(
+        state.render_view("fragment.dogears.html.j2", ctx)?,
+    )))
+}
+
+/// Fragments come in HTML and JSON flavors from the same URL, so anything in
+/// between needs to know that the Accept header matters.
+pub fn fragment_response(body: impl IntoResponse) -> Response {
+    ([(header::VARY, "Accept")], body).into_response()
+}
+
+/// Give the session a fresh anti-CSRF token after a sensitive action goes
+/// through, and return it. Anything rendered afterwards has to use the new
+/// one; fragments and fetches send it back in the CSRF_TOKEN_HEADER. (If
+/// the session vanished mid-request, its old token's dead anyway.)
+async fn rotate_csrf(state: &DogState, auth: &AuthSession) -> sqlx::Result<String> {
+    let rotated = state.db.sessions().rotate_csrf(&auth.session.id).await?;
+    Ok(rotated.unwrap_or_else(|| auth.session.csrf_token.clone()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkQuery {
+    /// Suggested name for the site, if this ends up making a new dogear.
+    title: Option<String>,
+    /// Note for the history, if this ends up updating a dogear.
+    note: Option<String>,
 }
 
 /// The mark-some-url page. One of:
@@ -115,26 +918,51 @@ pub async fn mark_url(
     cookies: Cookies,
     own_uri: Uri,
     Path(url): Path<String>,
+    Query(query): Query<MarkQuery>,
 ) -> WebResult<Html<String>> {
     let Some(auth) = maybe_auth else {
         let path = own_uri.to_string();
         return login_form(state, cookies, &path).await;
     };
+    let note = clean_note(query.note.as_deref())?;
     let dogears = state.db.dogears();
-    match dogears.update(auth.user.id, &url).await? {
+    match dogears.update(auth.user.id, &url, note).await? {
         Some(res) => {
+            let (paused, updated): (Vec<&DogearUpdate>, Vec<&DogearUpdate>) =
+                res.iter().partition(|u| u.dogear.paused);
+            let paused: Vec<&Dogear> = paused.into_iter().map(|u| &u.dogear).collect();
+            let updated: Vec<MarkedDogear> = updated
+                .into_iter()
+                .map(|u| MarkedDogear {
+                    dogear: &u.dogear,
+                    chapter_delta: chapter_delta(&u.previous, &u.dogear.current),
+                })
+                .collect();
             let marked_page = MarkedPage {
-                updated_dogears: &res,
+                updated_dogears: &updated,
+                paused_dogears: &paused,
                 bookmarked_url: &url,
                 slowmode: true,
             };
-            let common = auth.common_args("Saved your place");
+            let title = if updated.is_empty() {
+                "Dogear is paused"
+            } else {
+                "Saved your place"
+            };
+            let common = auth.common_args(title);
             let ctx = context! {marked_page, common};
             Ok(Html(state.render_view("marked.html.j2", ctx)?))
         }
         None => {
+            let suggested_prefix = state.suggest_prefix(&url).await?;
             let create_page = CreatePage {
-                bookmarked_url: &url,
+                near_misses: &[],
+                form: forms::create_form(
+                    &url,
+                    suggested_prefix.as_deref(),
+                    clean_optional_form_field(query.title.as_deref()),
+                    None,
+                ),
             };
             let common = auth.common_args("Dogear this?");
             let ctx = context! {create_page, common};
@@ -143,6 +971,41 @@ pub async fn mark_url(
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ShareQuery {
+    url: Option<String>,
+    title: Option<String>,
+    text: Option<String>,
+}
+
+/// The share target from the web app manifest, for "Share → Eardogger" on
+/// phones. Browsers and apps are inconsistent about where they put the
+/// link (lots of Android apps stuff it into `text` along with a blurb), so
+/// take the first http(s) URL we can find, then hand off to the regular
+/// mark page, which takes care of login, updates, and creates.
+#[tracing::instrument(skip_all)]
+pub async fn share(Query(query): Query<ShareQuery>) -> WebResult<Redirect> {
+    let Some(url) = shared_url(&query) else {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            "Whatever you shared didn't have a web address in it, so there's nothing to dogear."
+                .to_string(),
+        ));
+    };
+    let mut dest = format!("/mark/{}", encode_uri_component(url));
+    if let Some(title) = clean_optional_form_field(query.title.as_deref()) {
+        dest.push_str("?title=");
+        dest.push_str(&encode_uri_component(title).to_string());
+    }
+    Ok(Redirect::to(&dest))
+}
+
+fn shared_url(query: &ShareQuery) -> Option<&str> {
+    let is_web_url = |s: &&str| Url::parse(s).is_ok_and(|u| matches!(u.scheme(), "http" | "https"));
+    let from_url = query.url.as_deref().map(str::trim).filter(is_web_url);
+    from_url.or_else(|| query.text.as_deref()?.split_whitespace().find(is_web_url))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateParams {
     // Dogears::create will normalize the Some("") case.
@@ -159,7 +1022,7 @@ pub async fn post_mark(
     State(state): State<DogState>,
     auth: AuthSession,
     Form(params): Form<CreateParams>,
-) -> WebResult<Html<String>> {
+) -> WebResult<Response> {
     if params.csrf_token != auth.session.csrf_token {
         return Err(WebError::new(
             StatusCode::BAD_REQUEST,
@@ -169,30 +1032,100 @@ pub async fn post_mark(
         ));
     }
 
-    let res = state
+    let (prefix, current) = state
+        .canonical_new_dogear(&params.prefix, &params.current)
+        .await;
+    let created = state
         .db
         .dogears()
         .create(
             auth.user.id,
-            &params.prefix,
-            &params.current,
+            &prefix,
+            &current,
             params.display_name.as_deref(),
         )
-        .await?;
+        .await;
+    let res = match created {
+        Ok(res) => res,
+        // Problems with the prefix go back to the form, so they can fix it.
+        // So do names that are too long or have junk in them.
+        Err(MixedError::User(
+            e @ (UserError::DogearNonMatching { .. }
+            | UserError::DogearExists { .. }
+            | UserError::TooLong { .. }
+            | UserError::BadCharacters { .. }),
+        )) => {
+            let status = match e {
+                UserError::DogearExists { .. } => StatusCode::CONFLICT,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            let field = match e {
+                UserError::TooLong {
+                    field: Field::DisplayName,
+                    ..
+                }
+                | UserError::BadCharacters {
+                    field: Field::DisplayName,
+                    ..
+                } => "display_name",
+                _ => "prefix",
+            };
+            let error = FieldError::new(field, &e);
+            let create_page = CreatePage {
+                near_misses: &[],
+                form: forms::create_form(
+                    &params.current,
+                    Some(&params.prefix),
+                    params.display_name.as_deref(),
+                    Some(&error),
+                ),
+            };
+            let common = auth.common_args("Dogear this?");
+            let ctx = context! {create_page, common};
+            let page = state.render_view("create.html.j2", ctx)?;
+            return Ok((status, Html(page)).into_response());
+        }
+        Err(e) => return Err(e.into()),
+    };
     let marked_page = MarkedPage {
-        updated_dogears: &[res],
-        bookmarked_url: &params.current,
+        updated_dogears: &[MarkedDogear {
+            dogear: &res,
+            chapter_delta: None,
+        }],
+        paused_dogears: &[],
+        bookmarked_url: &current,
         slowmode: false,
     };
     let common = auth.common_args("Saved your place");
     let ctx = context! {marked_page, common};
-    Ok(Html(state.render_view("marked.html.j2", ctx)?))
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, dogear_location(res.id))],
+        Html(state.render_view("marked.html.j2", ctx)?),
+    )
+        .into_response())
 }
 
-/// Given a URL, do one of the following:
+/// The API resource for a dogear, for Location and Link headers.
+fn dogear_location(id: i64) -> String {
+    format!("/api/v1/dogear/{}", id)
+}
+
+/// How many same-site dogears to suggest when a resume comes up empty.
+const NEAR_MISS_LIMIT: u32 = 5;
+
+#[derive(Deserialize, Debug)]
+pub struct ResumeQuery {
+    /// Set when another instance sent us here, so we don't send it back.
+    handed_off: Option<String>,
+}
+
+/// Given a URL, do one of the following:
 /// - If there's an existing dogear, redirect straight to the currently marked page for it.
 /// - If not, render the create page.
-/// - If logged out, show the login page.
+/// - If logged out, but this browser says its account lives on one of our peer
+///   instances, redirect to the same resume over there.
+/// - If logged out otherwise, show the login page.
 /// Since this might be a Redirect OR a page, we can't return `impl IntoResponse`; gotta
 /// manually convert first and return Response.
 #[tracing::instrument(skip_all)]
@@ -200,10 +1133,21 @@ pub async fn resume(
     State(state): State<DogState>,
     maybe_auth: Option<AuthSession>,
     Path(url): Path<String>,
+    Query(query): Query<ResumeQuery>,
     own_uri: Uri,
     cookies: Cookies,
 ) -> WebResult<Response> {
     let Some(auth) = maybe_auth else {
+        let hint = cookies.get(COOKIE_HOME_INSTANCE);
+        if let (None, Some(peer)) = (
+            &query.handed_off,
+            home_peer(
+                &state.config.peer_instances,
+                hint.as_ref().map(|c| c.value()),
+            ),
+        ) {
+            return Ok(Redirect::to(&handoff_url(peer, &url)).into_response());
+        }
         let path = own_uri.to_string();
         return Ok(login_form(state, cookies, &path).await?.into_response());
     };
@@ -215,8 +1159,15 @@ pub async fn resume(
     {
         Some(current) => Ok(Redirect::to(&current).into_response()),
         None => {
+            let near_misses = state
+                .db
+                .dogears()
+                .same_host(auth.user.id, &url, NEAR_MISS_LIMIT)
+                .await?;
+            let suggested_prefix = state.suggest_prefix(&url).await?;
             let create_page = CreatePage {
-                bookmarked_url: &url,
+                near_misses: &near_misses,
+                form: forms::create_form(&url, suggested_prefix.as_deref(), None, None),
             };
             let common = auth.common_args("Dogear this?");
             let ctx = context! {create_page, common};
@@ -231,30 +1182,40 @@ pub async fn resume(
 pub async fn faq(
     State(state): State<DogState>,
     maybe_auth: Option<AuthSession>,
+    theme: Theme,
 ) -> WebResult<Html<String>> {
     let title = "About Eardogger";
-    let common = match maybe_auth {
-        Some(ref auth) => auth.common_args(title),
-        None => Common::anonymous(title),
+    let common = Common {
+        canonical_url: Some(own_url(&state.config.public_url, "/faq")),
+        theme,
+        ..match maybe_auth {
+            Some(ref auth) => auth.common_args(title),
+            None => Common::anonymous(title),
+        }
     };
     let ctx = context! {common};
     Ok(Html(state.render_view("faq.html.j2", ctx)?))
 }
 
 // Due to how I'm handling fragment fetch POSTs in the client-side JS,
-// this comes in as query params rather than a form-urlencoded body.
+// this comes in as query params rather than a form-urlencoded body. (The
+// csrf token rides in a header instead; see CsrfHeader.)
 #[derive(Debug, Deserialize)]
 pub struct PersonalMarkParams {
-    csrf_token: String,
+    /// Whether the bookmarklet should ask for a note each time.
+    #[serde(default)]
+    prompt_note: bool,
 }
 
 #[tracing::instrument(skip_all)]
 pub async fn post_fragment_personalmark(
     State(state): State<DogState>,
     auth: AuthSession,
+    source: AuditSource,
+    CsrfHeader(csrf_token): CsrfHeader,
     Query(params): Query<PersonalMarkParams>,
-) -> WebResult<(StatusCode, Html<String>)> {
-    if params.csrf_token != auth.session.csrf_token {
+) -> WebResult<Response> {
+    if csrf_token != auth.session.csrf_token {
         return Err(WebError::new(
             StatusCode::BAD_REQUEST,
             r#"The bookmarklet generate button was stale or mangled.
@@ -262,43 +1223,265 @@ pub async fn post_fragment_personalmark(
                 .to_string(),
         ));
     }
-    // Skip an alloc w/ format_into:
-    let mut comment_bytes: Vec<u8> = "Personal bookmarklet created ".into();
-    OffsetDateTime::now_utc()
-        .format_into(&mut comment_bytes, SHORT_DATE)
-        .map_err(|_| UserError::Impossible("time format_into vec failed"))?;
-    let comment = String::from_utf8(comment_bytes)
-        .map_err(|_| UserError::Impossible("statically known utf8 wasn't utf8"))?;
+    let comment = dated_comment("Personal bookmarklet created ")?;
+    let kind = if params.prompt_note {
+        Bookmarklet::PromptNote
+    } else {
+        Bookmarklet::Plain
+    };
     // New token:
-    let (_, token_cleartext) = state
+    let (token, token_cleartext) = state
         .db
         .tokens()
-        .create(auth.user.id, TokenScope::WriteDogears, Some(&comment))
+        .create_bookmarklet(auth.user.id, kind, Some(&comment))
         .await?;
+    let detail = token_detail(&token);
+    state
+        .audit(&auth.user, AuditKind::TokenCreated, Some(&detail), &source)
+        .await;
     // Build bookmarklet URL:
-    let bookmarklet_url = state.render_bookmarklet("mark.js.j2", Some(&token_cleartext))?;
+    let bookmarklet_url =
+        state.render_bookmarklet("mark.js.j2", Some(&token_cleartext), params.prompt_note)?;
     // Render html fragment:
     let personal_mark = PersonalMark {
         bookmarklet_url: &bookmarklet_url,
     };
     let ctx = context! { personal_mark };
+    let csrf_token = rotate_csrf(&state, &auth).await?;
     Ok((
         StatusCode::CREATED,
+        [(CSRF_TOKEN_HEADER, csrf_token)],
         Html(state.render_view("fragment.personalmark.html.j2", ctx)?),
-    ))
+    )
+        .into_response())
+}
+
+/// The bookmarklet management page. Requires logged-in.
+#[tracing::instrument(skip_all)]
+pub async fn bookmarklets_page(
+    State(state): State<DogState>,
+    auth: AuthSession,
+) -> WebResult<Html<String>> {
+    let tokens = state.db.tokens().bookmarklets(auth.user.id).await?;
+    let bookmarklets_list = BookmarkletsList {
+        tokens: &tokens,
+        csrf_token: &auth.session.csrf_token,
+        fresh: None,
+    };
+    let common = Common {
+        breadcrumbs: ACCOUNT_CRUMBS,
+        canonical_url: Some(own_url(&state.config.public_url, "/bookmarklets")),
+        ..auth.common_args("Bookmarklets")
+    };
+    let ctx = context! {common, bookmarklets_list};
+    Ok(Html(state.render_view("bookmarklets.html.j2", ctx)?))
+}
+
+/// Kind of like the bookmarklets page.
+#[tracing::instrument(skip_all)]
+pub async fn fragment_bookmarklets(
+    State(state): State<DogState>,
+    auth: AuthSession,
+) -> WebResult<Response> {
+    let tokens = state.db.tokens().bookmarklets(auth.user.id).await?;
+    let bookmarklets_list = BookmarkletsList {
+        tokens: &tokens,
+        csrf_token: &auth.session.csrf_token,
+        fresh: None,
+    };
+    let ctx = context! {bookmarklets_list};
+    Ok(fragment_response(Html(
+        state.render_view("fragment.bookmarklets.html.j2", ctx)?,
+    )))
+}
+
+/// The regenerate button on the bookmarklets page: rotate a bookmarklet's
+/// token, and send back the list with the new bookmarklet in it. Same
+/// X-CSRF-Token header deal as generating one.
+#[tracing::instrument(skip_all)]
+pub async fn post_fragment_regenerate_bookmarklet(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    source: AuditSource,
+    Path(id): Path<i64>,
+    CsrfHeader(csrf_token): CsrfHeader,
+) -> WebResult<Response> {
+    if csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The bookmarklet regenerate button was stale or mangled.
+                Refresh the page and try regenerating again."#
+                .to_string(),
+        ));
+    }
+    let tokens = state.db.tokens();
+    let kind = tokens
+        .bookmarklets(auth.user.id)
+        .await?
+        .into_iter()
+        .find(|t| t.id == id)
+        .and_then(|t| t.bookmarklet());
+    let not_found = || {
+        WebError::new(
+            StatusCode::NOT_FOUND,
+            "That bookmarklet's already gone. Refresh the page to see the ones you've got."
+                .to_string(),
+        )
+    };
+    let Some(kind) = kind else {
+        return Err(not_found());
+    };
+    let Some((token, token_cleartext)) = tokens.rotate(id, auth.user.id).await? else {
+        return Err(not_found());
+    };
+    let detail = format!("{} (replacing #{})", token_detail(&token), id);
+    state
+        .audit(&auth.user, AuditKind::TokenCreated, Some(&detail), &source)
+        .await;
+    let url = state.render_bookmarklet(
+        "mark.js.j2",
+        Some(&token_cleartext),
+        kind.prompts_for_note(),
+    )?;
+    let all = tokens.bookmarklets(auth.user.id).await?;
+    let csrf_token = rotate_csrf(&state, &auth).await?;
+    let bookmarklets_list = BookmarkletsList {
+        tokens: &all,
+        csrf_token: &csrf_token,
+        fresh: Some(FreshBookmarklet {
+            token_id: token.id,
+            url: &url,
+        }),
+    };
+    let ctx = context! {bookmarklets_list};
+    let html = Html(state.render_view("fragment.bookmarklets.html.j2", ctx)?);
+    Ok(fragment_response(([(CSRF_TOKEN_HEADER, csrf_token)], html)))
+}
+
+/// A token comment like "Personal bookmarklet created 2024-3-22".
+fn dated_comment(label: &str) -> Result<String, UserError> {
+    // Skip an alloc w/ format_into:
+    let mut comment_bytes: Vec<u8> = label.into();
+    OffsetDateTime::now_utc()
+        .format_into(&mut comment_bytes, SHORT_DATE)
+        .map_err(|_| UserError::Impossible("time format_into vec failed"))?;
+    String::from_utf8(comment_bytes)
+        .map_err(|_| UserError::Impossible("statically known utf8 wasn't utf8"))
+}
+
+/// How the audit log names a token, like "write_dogears token #12".
+fn token_detail(token: &Token) -> String {
+    let scope: &str = token.scope().into();
+    format!("{} token #{}", scope, token.id)
+}
+
+/// Same deal as the personal bookmarklet, but for a quickmark URL: makes
+/// a quickmark-only token and shows the URL to paste into a shortcut.
+#[tracing::instrument(skip_all)]
+pub async fn post_fragment_quickmark(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    source: AuditSource,
+    CsrfHeader(csrf_token): CsrfHeader,
+) -> WebResult<Response> {
+    if csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The quickmark generate button was stale or mangled.
+                Refresh the page and try generating again."#
+                .to_string(),
+        ));
+    }
+    let comment = dated_comment("Quickmark URL created ")?;
+    let (token, token_cleartext) = state
+        .db
+        .tokens()
+        .create(auth.user.id, TokenScope::Quickmark, Some(&comment))
+        .await?;
+    let detail = token_detail(&token);
+    state
+        .audit(&auth.user, AuditKind::TokenCreated, Some(&detail), &source)
+        .await;
+    let quickmark_url = format!(
+        "{}api/v1/quickmark?token={}&url=",
+        state.config.public_url,
+        encode_uri_component(&token_cleartext)
+    );
+    let quickmark = Quickmark {
+        quickmark_url: &quickmark_url,
+    };
+    let ctx = context! { quickmark };
+    let csrf_token = rotate_csrf(&state, &auth).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(CSRF_TOKEN_HEADER, csrf_token)],
+        Html(state.render_view("fragment.quickmark.html.j2", ctx)?),
+    )
+        .into_response())
+}
+
+/// Same deal again, but for KOReader's progress sync: makes a kosync token
+/// and shows everything you need to type into the plugin.
+#[tracing::instrument(skip_all)]
+pub async fn post_fragment_kosync(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    source: AuditSource,
+    CsrfHeader(csrf_token): CsrfHeader,
+) -> WebResult<Response> {
+    if csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The KOReader password generate button was stale or mangled.
+                Refresh the page and try generating again."#
+                .to_string(),
+        ));
+    }
+    let comment = dated_comment("KOReader sync password created ")?;
+    let (token, token_cleartext) = state
+        .db
+        .tokens()
+        .create(auth.user.id, TokenScope::Kosync, Some(&comment))
+        .await?;
+    let detail = token_detail(&token);
+    state
+        .audit(&auth.user, AuditKind::TokenCreated, Some(&detail), &source)
+        .await;
+    let server_url = format!("{}kosync", state.config.public_url);
+    let kosync_login = KosyncLogin {
+        server_url: &server_url,
+        username: &auth.user.username,
+        password: &token_cleartext,
+    };
+    let ctx = context! { kosync_login };
+    let csrf_token = rotate_csrf(&state, &auth).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(CSRF_TOKEN_HEADER, csrf_token)],
+        Html(state.render_view("fragment.kosync.html.j2", ctx)?),
+    )
+        .into_response())
 }
 
+/// The install page. Its only bookmarklet is the token-less "where was I",
+/// so loading it never writes anything; personal bookmarklets get their
+/// tokens on demand, from the generate button's fragment POST.
 #[tracing::instrument(skip_all)]
 pub async fn install(
     State(state): State<DogState>,
     maybe_auth: Option<AuthSession>,
+    theme: Theme,
 ) -> WebResult<Html<String>> {
     let title = "Install";
-    let common = match maybe_auth {
-        Some(ref auth) => auth.common_args(title),
-        None => Common::anonymous(title),
+    let common = Common {
+        canonical_url: Some(own_url(&state.config.public_url, "/install")),
+        theme,
+        ..match maybe_auth {
+            Some(ref auth) => auth.common_args(title),
+            None => Common::anonymous(title),
+        }
     };
-    let where_was = state.render_bookmarklet("where.js.j2", None)?;
+    let where_was = state.render_bookmarklet("where.js.j2", None, false)?;
     let install_page = InstallPage {
         where_was_i_bookmarklet_url: &where_was,
     };
@@ -313,6 +1496,20 @@ pub async fn account(
     auth: AuthSession,
     Query(query): Query<PaginationQuery>,
 ) -> WebResult<Html<String>> {
+    Ok(Html(account_page(&state, &auth, &query, None).await?))
+}
+
+/// How many audit events to show in the account page's recent activity.
+const RECENT_ACTIVITY_LIMIT: u32 = 20;
+
+/// Render the account page, maybe with a problem from the change password
+/// form (which opens that form back up).
+async fn account_page(
+    state: &DogState,
+    auth: &AuthSession,
+    query: &PaginationQuery,
+    password_error: Option<&FieldError>,
+) -> WebResult<String> {
     // Okay, so it's kind of weird that the pagination query applies to
     // BOTH the tokens and the sessions, but they can nav independently
     // in the in-page JS. But in my defense, pagination for these
@@ -322,25 +1519,82 @@ pub async fn account(
     let (tokens, token_meta) = state
         .db
         .tokens()
-        .list(auth.user.id, query.page(), query.size())
+        .list(
+            auth.user.id,
+            query.page(),
+            query.size(auth.prefs.default_page_size()),
+        )
         .await?;
     let (sessions, session_meta) = state
         .db
         .sessions()
-        .list(auth.user.id, query.page(), query.size())
+        .list(
+            auth.user.id,
+            query.page(),
+            query.size(auth.prefs.default_page_size()),
+        )
         .await?;
-    let common = auth.common_args("Manage account");
+    let page = later_page(query);
+    let common = Common {
+        page,
+        canonical_url: Some(paged_url(&state.config.public_url, "/account", None, page)),
+        ..auth.common_args("Manage account")
+    };
     let tokens_list = TokensList {
         tokens: &tokens,
-        pagination: token_meta.to_pagination(),
+        pagination: token_meta.to_pagination(auth.prefs.default_page_size()),
     };
     let sessions_list = SessionsList {
         current_session_id: auth.session.external_id,
         sessions: &sessions,
-        pagination: session_meta.to_pagination(),
+        pagination: session_meta.to_pagination(auth.prefs.default_page_size()),
+        standalone: false,
+    };
+    let grants = state.db.grants().list_given(auth.user.id).await?;
+    let grants_list = GrantsList { grants: &grants };
+    let invitations = state.db.grants().list_invitations(auth.user.id).await?;
+    let invitations_list = GrantsList {
+        grants: &invitations,
+    };
+    let received_grants = state.db.grants().list_received(auth.user.id).await?;
+    let shared_with_me = GrantsList {
+        grants: &received_grants,
+    };
+    let hooks = state.db.hooks().list(auth.user.id).await?;
+    let hooks_list = HooksList { hooks: &hooks };
+    let prefs = state.db.prefs().get(auth.user.id).await?;
+    let can_send_mail = state.config.mail.is_some();
+    let pending_email = state.db.email_changes().pending(auth.user.id).await?;
+    let account_forms = forms::AccountForms::new(state.config.passwords.min_length, password_error);
+    let site_history_months = state.config.retention.history_months;
+    let token_comment_max_length = TOKEN_COMMENT_MAX_LENGTH;
+    let token_lifetime_days = state.config.token_lifetime_days;
+    let api_examples = api_examples(&state.config.public_url);
+    let backup_schedule = state.db.backups().get(auth.user.id).await?;
+    let backup_runs = state.db.backups().runs(auth.user.id).await?;
+    let backup_webhooks = state.config.backup_webhooks;
+    let webhooks = state.db.webhooks().list(auth.user.id).await?;
+    let webhooks_list = WebhooksList {
+        webhooks: &webhooks,
     };
-    let ctx = context! {common, tokens_list, sessions_list};
-    Ok(Html(state.render_view("account.html.j2", ctx)?))
+    let webhooks_enabled = state.config.webhooks;
+    let max_webhooks = MAX_WEBHOOKS;
+    let default_stale_months = DEFAULT_STALE_MONTHS;
+    let default_page_size = PAGE_DEFAULT_SIZE;
+    let totp = totp_settings(state, &auth.user).await?;
+    let recent_activity = state
+        .db
+        .audit()
+        .recent(auth.user.id, RECENT_ACTIVITY_LIMIT)
+        .await?;
+    let flags = state
+        .db
+        .users()
+        .flags(auth.user.id)
+        .await?
+        .unwrap_or_default();
+    let ctx = context! {common, tokens_list, sessions_list, grants_list, invitations_list, shared_with_me, hooks_list, prefs, can_send_mail, pending_email, password_error, account_forms, flags, site_history_months, default_stale_months, default_page_size, token_comment_max_length, token_lifetime_days, api_examples, backup_schedule, backup_runs, backup_webhooks, webhooks_list, webhooks_enabled, max_webhooks, totp, recent_activity};
+    Ok(state.render_view("account.html.j2", ctx)?)
 }
 
 /// Kind of like the account page.
@@ -349,18 +1603,82 @@ pub async fn fragment_tokens(
     State(state): State<DogState>,
     auth: AuthSession,
     Query(query): Query<PaginationQuery>,
-) -> WebResult<Html<String>> {
+    headers: HeaderMap,
+) -> WebResult<Response> {
     let (tokens, meta) = state
         .db
         .tokens()
-        .list(auth.user.id, query.page(), query.size())
+        .list(
+            auth.user.id,
+            query.page(),
+            query.size(auth.prefs.default_page_size()),
+        )
         .await?;
     let tokens_list = TokensList {
         tokens: &tokens,
-        pagination: meta.to_pagination(),
+        pagination: meta.to_pagination(auth.prefs.default_page_size()),
+    };
+    if accepts_json(&headers) {
+        let pagination_links =
+            PaginationLinks::new(&tokens_list.pagination, "/account", "/fragments/tokens", "");
+        return Ok(fragment_response(Json(FragmentJson {
+            list: tokens_list,
+            pagination_links,
+        })));
+    }
+    let api_examples = api_examples(&state.config.public_url);
+    let ctx =
+        context! {tokens_list, api_examples, token_comment_max_length => TOKEN_COMMENT_MAX_LENGTH};
+    Ok(fragment_response(Html(
+        state.render_view("fragment.tokens.html.j2", ctx)?,
+    )))
+}
+
+/// Just your login sessions, with buttons to log them out remotely. Same
+/// list as the account page, minus everything else on the account page.
+#[tracing::instrument(skip_all)]
+pub async fn account_sessions(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Query(query): Query<PaginationQuery>,
+) -> WebResult<Html<String>> {
+    let (sessions, meta) = state
+        .db
+        .sessions()
+        .list(
+            auth.user.id,
+            query.page(),
+            query.size(auth.prefs.default_page_size()),
+        )
+        .await?;
+    let page = later_page(&query);
+    let common = Common {
+        page,
+        breadcrumbs: ACCOUNT_CRUMBS,
+        canonical_url: Some(paged_url(
+            &state.config.public_url,
+            "/account/sessions",
+            None,
+            page,
+        )),
+        ..auth.common_args("Login sessions")
+    };
+    let sessions_list = SessionsList {
+        current_session_id: auth.session.external_id,
+        sessions: &sessions,
+        pagination: meta.to_pagination(auth.prefs.default_page_size()),
+        standalone: true,
     };
-    let ctx = context! {tokens_list};
-    Ok(Html(state.render_view("fragment.tokens.html.j2", ctx)?))
+    let ctx = context! {common, sessions_list};
+    Ok(Html(state.render_view("account_sessions.html.j2", ctx)?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SessionsFragmentQuery {
+    /// Set when the fragment's going into /account/sessions instead of the
+    /// account page.
+    #[serde(default)]
+    standalone: bool,
 }
 
 /// Also kind of like the account page.
@@ -369,375 +1687,1729 @@ pub async fn fragment_sessions(
     State(state): State<DogState>,
     auth: AuthSession,
     Query(query): Query<PaginationQuery>,
+    Query(fragment_query): Query<SessionsFragmentQuery>,
 ) -> WebResult<Html<String>> {
     let (sessions, meta) = state
         .db
         .sessions()
-        .list(auth.user.id, query.page(), query.size())
+        .list(
+            auth.user.id,
+            query.page(),
+            query.size(auth.prefs.default_page_size()),
+        )
         .await?;
     let sessions_list = SessionsList {
         current_session_id: auth.session.external_id,
         sessions: &sessions,
-        pagination: meta.to_pagination(),
+        pagination: meta.to_pagination(auth.prefs.default_page_size()),
+        standalone: fragment_query.standalone,
     };
     let ctx = context! {sessions_list};
     Ok(Html(state.render_view("fragment.sessions.html.j2", ctx)?))
 }
 
-/// Handle DELETE for tokens. Effectively an API method, but since it's
-/// only valid for session users, it lives outside the api namespace.
+/// Also also kind of like the account page.
 #[tracing::instrument(skip_all)]
-pub async fn delete_token(
+pub async fn fragment_grants(
     State(state): State<DogState>,
     auth: AuthSession,
-    Path(id): Path<i64>,
-) -> StatusCode {
-    match state.db.tokens().destroy(id, auth.user.id).await {
-        Ok(Some(_)) => StatusCode::NO_CONTENT,       // success
-        Ok(None) => StatusCode::NOT_FOUND,           // failure
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR, // db splode
-    }
+) -> WebResult<Html<String>> {
+    let grants = state.db.grants().list_given(auth.user.id).await?;
+    let grants_list = GrantsList { grants: &grants };
+    let ctx = context! {grants_list};
+    Ok(Html(state.render_view("fragment.grants.html.j2", ctx)?))
 }
 
-/// Handle DELETE for sessions. Effectively an API method, but since it's
-/// only valid for session users, it lives outside the api namespace.
+/// Same again, for webhooks.
 #[tracing::instrument(skip_all)]
-pub async fn delete_session(
+pub async fn fragment_hooks(
     State(state): State<DogState>,
     auth: AuthSession,
-    Path(external_id): Path<i64>,
-) -> StatusCode {
-    match state
-        .db
-        .sessions()
-        .destroy_external(external_id, auth.user.id)
-        .await
-    {
-        Ok(Some(_)) => StatusCode::NO_CONTENT,       // success
-        Ok(None) => StatusCode::NOT_FOUND,           // failure
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR, // db splode
-    }
+) -> WebResult<Html<String>> {
+    let hooks = state.db.hooks().list(auth.user.id).await?;
+    let hooks_list = HooksList { hooks: &hooks };
+    let ctx = context! {hooks_list};
+    Ok(Html(state.render_view("fragment.hooks.html.j2", ctx)?))
 }
 
-/// Handle POSTs from the logout button. This redirects to /.
+/// And for outgoing webhooks.
 #[tracing::instrument(skip_all)]
-pub async fn post_logout(
+pub async fn fragment_webhooks(
     State(state): State<DogState>,
     auth: AuthSession,
-    cookies: Cookies,
-    Form(params): Form<LogoutParams>,
+) -> WebResult<Html<String>> {
+    let webhooks = state.db.webhooks().list(auth.user.id).await?;
+    let webhooks_list = WebhooksList {
+        webhooks: &webhooks,
+    };
+    let ctx = context! {webhooks_list};
+    Ok(Html(state.render_view("fragment.webhooks.html.j2", ctx)?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateGrantParams {
+    grantee_username: String,
+    csrf_token: String,
+}
+
+/// The share-your-dogears form, on the account page.
+#[tracing::instrument(skip_all)]
+pub async fn post_grant(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<CreateGrantParams>,
 ) -> WebResult<Redirect> {
-    // Destroy the session! Destroy the cookie! Well, first check the csrf token.
     if params.csrf_token != auth.session.csrf_token {
         return Err(WebError::new(
             StatusCode::BAD_REQUEST,
-            r#"Something was wrong with that log out button! Go back to the
-                home page and try logging out again."#
+            r#"The share-your-dogears form you tried to use was stale, or
+                had been tampered with. Go back to the account page and try
+                sharing again."#
                 .to_string(),
         ));
     }
-    // Session goes first; that way if something goes wrong and it's still alive,
-    // the user still has a cookie to try logging out with later.
-    let res = state.db.sessions().destroy(&auth.session.id).await?;
-    if res.is_none() {
-        error!(
-            logout.sessid = %auth.session.id,
-            logout.userid = %auth.user.id,
-            "Session not found for logout. This should be impossible, since we had a valid session!"
-        );
-    }
-    cookies.remove((COOKIE_SESSION, "").into());
-    Ok(Redirect::to("/"))
-}
-
-#[derive(Deserialize, Debug)]
-pub struct LogoutParams {
-    pub csrf_token: String,
+    // Same redirect whether or not there's anyone by that name; see
+    // Grants::invite.
+    state
+        .db
+        .grants()
+        .invite(auth.user.id, &params.grantee_username)
+        .await?;
+    Ok(Redirect::to("/account?changed=sharing"))
 }
 
 #[derive(Deserialize, Debug)]
-pub struct DeleteAccountParams {
-    password: String,
-    confirm_delete_account: String,
+pub struct GrantActionParams {
     csrf_token: String,
 }
 
-/// The delete account form, on the account page. It's kind of like the Final Logout.
+/// The buttons for someone else's grant, on the account page: accept an
+/// invitation, or decline one (which also works for a list you already
+/// accepted and don't want anymore).
 #[tracing::instrument(skip_all)]
-pub async fn post_delete_account(
+pub async fn post_grant_action(
     State(state): State<DogState>,
     auth: AuthSession,
-    cookies: Cookies,
-    Form(params): Form<DeleteAccountParams>,
+    Path((id, action)): Path<(i64, String)>,
+    Form(params): Form<GrantActionParams>,
 ) -> WebResult<Redirect> {
     if params.csrf_token != auth.session.csrf_token {
         return Err(WebError::new(
             StatusCode::BAD_REQUEST,
-            r#"The delete account form you tried to use was stale, or
-                had been tampered with. Go back to the account page and try
-                deleting your account again."#
+            r#"The sharing form you tried to use was stale, or had been
+                tampered with. Go back to the account page and try again."#
                 .to_string(),
         ));
     }
-    let users = state.db.users();
-    // authenticate the password, validate the confirm string, waste the
-    // session cookie, delete the user (which will cascade to all foreign key refs).
-    let Some(user) = users
-        .authenticate(&auth.user.username, &params.password)
-        .await?
-    else {
-        return Err(WebError::new(
-            StatusCode::BAD_REQUEST,
-            "Wrong password".to_string(),
-        ));
+    let grants = state.db.grants();
+    let done = match action.as_str() {
+        "accept" => grants.accept(id, auth.user.id).await?,
+        "decline" => grants.decline(id, auth.user.id).await?,
+        _ => None,
     };
-    if params.confirm_delete_account.trim() != DELETE_ACCOUNT_CONFIRM_STRING {
+    if done.is_none() {
         return Err(WebError::new(
-            StatusCode::BAD_REQUEST,
-            format!(
-                r#"Wrong delete confirmation string; you must type the exact phrase
-                "delete my account" (without the quotation marks) into the account delete
-                form, but you typed "{}" instead. Go back to the account page and try
-                deleting your account again."#,
-                &params.confirm_delete_account
-            ),
+            StatusCode::NOT_FOUND,
+            "There's nothing like that shared with you.".to_string(),
         ));
     }
-    // OK, at this point we're ready to party.
-    // The From impl on WebError uses ToString, so throwing a str is fine.
-    users.destroy(user.id).await?.ok_or(UserError::Impossible(
-        "User not found! That shouldn't be possible at this point??",
-    ))?;
-    cookies.remove(auth.session.as_ref().clone().into_cookie());
+    Ok(Redirect::to("/account?changed=sharing"))
+}
 
-    Ok(Redirect::to("/"))
+/// Handle DELETE for sharing grants. Like tokens, this is only valid for
+/// session users, and only the owner can revoke.
+#[tracing::instrument(skip_all)]
+pub async fn delete_grant(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Path(id): Path<i64>,
+) -> StatusCode {
+    match state.db.grants().destroy(id, auth.user.id).await {
+        Ok(Some(_)) => StatusCode::NO_CONTENT,       // success
+        Ok(None) => StatusCode::NOT_FOUND,           // failure
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR, // db splode
+    }
 }
 
 #[derive(Deserialize, Debug)]
-pub struct LoginParams {
-    pub username: String,
-    pub password: String,
-    pub login_csrf_token: String,
-    pub return_to: String,
+pub struct CreateWebhookParams {
+    url: String,
+    csrf_token: String,
 }
 
-/// Handle POSTs from the login form. The login form can appear on multiple pages,
-/// and includes a pointer back to the page the user was originally trying to reach.
-/// The form also includes a random token to be used in a signed double-submit
-/// CSRF-prevention scheme; compare it to the cookie to validate that the post came
-/// from a real login form, not a remote-site forgery.
+/// The add-a-webhook form, on the account page.
 #[tracing::instrument(skip_all)]
-pub async fn post_login(
+pub async fn post_webhook(
     State(state): State<DogState>,
-    cookies: Cookies,
-    req_headers: HeaderMap,
-    Form(params): Form<LoginParams>,
+    auth: AuthSession,
+    Form(params): Form<CreateWebhookParams>,
 ) -> WebResult<Redirect> {
-    // First, check the login CSRF cookie
-    let signed_cookies = cookies.signed(&state.cookie_key);
-    let Some(csrf_cookie) = signed_cookies.get(COOKIE_LOGIN_CSRF) else {
+    if params.csrf_token != auth.session.csrf_token {
         return Err(WebError::new(
             StatusCode::BAD_REQUEST,
-            r#"The login form you tried to use was broken.
-                Go back to the home page and try logging in again."#
+            r#"The add-a-webhook form you tried to use was stale, or had
+                been tampered with. Go back to the account page and try
+                again."#
                 .to_string(),
         ));
-    };
-    if csrf_cookie.value() != params.login_csrf_token {
+    }
+    if !state.config.webhooks {
         return Err(WebError::new(
             StatusCode::BAD_REQUEST,
-            r#"The login form you tried to use was stale or had been
-                tampered with. Go back to the home page and try logging in again."#
-                .to_string(),
+            "This site doesn't do outgoing webhooks.".to_string(),
         ));
     }
-    // Cool. 👍🏼 Waste the cookie, it's spent.
-    signed_cookies.remove(csrf_cookie);
-
-    // Sort out the redirect URL. If it's bad (illegible, off-site...),
-    // just go to the home page.
-    let mut redirect_to = state
-        .config
-        .public_url
-        .join(&params.return_to)
-        .unwrap_or_else(|_| state.config.public_url.clone());
-    if redirect_to.origin() != state.config.public_url.origin() {
-        redirect_to = state.config.public_url.clone();
+    let url = validate_url(
+        &params.url,
+        Schemes::HttpsInProduction,
+        state.config.production,
+    )
+    .await
+    .map_err(|e| WebError::new(StatusCode::BAD_REQUEST, e))?;
+    let created = state
+        .db
+        .webhooks()
+        .create(auth.user.id, url.as_str())
+        .await?;
+    if created.is_none() {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "You can only have {} webhooks. Delete one before adding another.",
+                MAX_WEBHOOKS
+            ),
+        ));
     }
+    Ok(Redirect::to("/account?changed=webhooks"))
+}
 
-    // then, authenticate user and tack on a session cookie.
-    if let Some(user) = state
-        .db
-        .users()
-        .authenticate(&params.username, &params.password)
-        .await?
-    {
-        let user_agent = req_headers
-            .get(header::USER_AGENT)
-            .and_then(|v| v.to_str().ok());
-        let session = state.db.sessions().create(user.id, user_agent).await?;
-        cookies.add(session.into_cookie());
-    }
-
-    // Finally, redirect. If the login failed, this will just show the login page again.
-    // TODO: I want to propagate the "last failed state" if you end up
-    // redirecting and then it shows the login page again, but I'm still
-    // mulling how to do that reliably. First thing that occurred to me was
-    // a query param, but I don't love it. Guess I could use a cookie too :thonk:
-    Ok(Redirect::to(redirect_to.as_str()))
+/// Handle DELETE for outgoing webhooks. Same deal as grants.
+#[tracing::instrument(skip_all)]
+pub async fn delete_webhook(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Path(id): Path<i64>,
+) -> StatusCode {
+    match state.db.webhooks().destroy(id, auth.user.id).await {
+        Ok(Some(_)) => StatusCode::NO_CONTENT,       // success
+        Ok(None) => StatusCode::NOT_FOUND,           // failure
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR, // db splode
+    }
 }
 
 #[derive(Deserialize, Debug)]
-pub struct SignupParams {
-    new_username: String,
-    new_password: String,
-    new_password_again: String,
-    // Really email'll always be present (and maybe blank), but downstream
-    // recipients expect an Option and will flatmap it to normalize.
-    email: Option<String>,
-    login_csrf_token: String,
+pub struct CreateTokenParams {
+    scope: String,
+    /// Blank gets a dated default.
+    comment: String,
+    csrf_token: String,
 }
 
-/// Handle POSTs from the signup form. This always appears alongside the login form.
-/// It has some of the same properties, but it always redirects to /.
+/// The make-an-API-token form, on the account page. Only write and manage
+/// tokens come from here; the other kinds have their own buttons on the
+/// install page. Returns a fragment with the cleartext, which is the only
+/// time anyone sees it, and rotates the session's CSRF token like the
+/// other token-making routes.
 #[tracing::instrument(skip_all)]
-pub async fn post_signup(
+pub async fn post_token(
     State(state): State<DogState>,
-    cookies: Cookies,
-    req_headers: HeaderMap,
-    maybe_auth: Option<AuthSession>,
-    Form(params): Form<SignupParams>,
-) -> WebResult<Redirect> {
-    // First, check the login CSRF cookie
-    let signed_cookies = cookies.signed(&state.cookie_key);
-    let Some(csrf_cookie) = signed_cookies.get(COOKIE_LOGIN_CSRF) else {
-        return Err(WebError::new(
-            StatusCode::BAD_REQUEST,
-            r#"The signup form you tried to use was broken.
-                Go back to the home page and try signing up again."#
-                .to_string(),
-        ));
-    };
-    if csrf_cookie.value() != params.login_csrf_token {
+    auth: AuthSession,
+    source: AuditSource,
+    Form(params): Form<CreateTokenParams>,
+) -> WebResult<Response> {
+    if params.csrf_token != auth.session.csrf_token {
         return Err(WebError::new(
             StatusCode::BAD_REQUEST,
-            r#"The signup form you tried to use was stale or had been
-                tampered with. Go back to the home page and try signing up again."#
+            r#"The create-a-token form you tried to use was stale, or had
+                been tampered with. Go back to the account page and try
+                again."#
                 .to_string(),
         ));
     }
-    // Cool. 👍🏼 Waste the cookie, it's spent.
-    signed_cookies.remove(csrf_cookie);
-
-    if maybe_auth.is_some() {
+    let scope = TokenScope::from(params.scope.as_str());
+    if !scope.is_switchable() {
         return Err(WebError::new(
-            StatusCode::FORBIDDEN,
-            "Can't sign up while you're still logged in. (How'd you even do that, by the way?)"
-                .to_string(),
+            StatusCode::BAD_REQUEST,
+            "Tokens from the account page can only mark spots, or manage dogears.".to_string(),
         ));
     }
-    if let Err(e) = check_new_password(&params.new_password, &params.new_password_again) {
-        return Err(WebError::new(StatusCode::BAD_REQUEST, e.to_string()));
-    }
-    let user = state
+    let comment = params.comment.trim();
+    validate(Field::TokenComment, comment)?;
+    let comment = match comment {
+        "" => dated_comment("API token created ")?,
+        c => c.to_string(),
+    };
+    let expires = state
+        .config
+        .token_lifetime_days
+        .map(|days| OffsetDateTime::now_utc() + time::Duration::days(days.into()));
+    let (token, token_cleartext) = state
         .db
-        .users()
-        .create(
-            &params.new_username,
-            &params.new_password,
-            params.email.as_deref(),
-        )
+        .tokens()
+        .create_expiring(auth.user.id, scope, Some(&comment), expires)
         .await?;
-    let user_agent = req_headers
-        .get(header::USER_AGENT)
-        .and_then(|v| v.to_str().ok());
-    let session = state.db.sessions().create(user.id, user_agent).await?;
-    cookies.add(session.into_cookie());
-    Ok(Redirect::to("/"))
+    let scope: &str = scope.into();
+    info!(target: "audit", username = %auth.user.username, token_id = token.id, scope, "token created");
+    let detail = token_detail(&token);
+    state
+        .audit(&auth.user, AuditKind::TokenCreated, Some(&detail), &source)
+        .await;
+    let new_token = NewToken {
+        cleartext: &token_cleartext,
+        scope,
+        comment: &comment,
+        expires: token.expires,
+    };
+    let ctx = context! { new_token };
+    let csrf_token = rotate_csrf(&state, &auth).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(CSRF_TOKEN_HEADER, csrf_token)],
+        Html(state.render_view("fragment.new_token.html.j2", ctx)?),
+    )
+        .into_response())
+}
+
+/// Handle DELETE for tokens. Effectively an API method, but since it's
+/// only valid for session users, it lives outside the api namespace.
+/// Rotates the session's CSRF token, same as making a token does.
+#[tracing::instrument(skip_all)]
+pub async fn delete_token(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    source: AuditSource,
+    Path(id): Path<i64>,
+) -> Response {
+    match state.db.tokens().destroy(id, auth.user.id).await {
+        // success
+        Ok(Some(_)) => {
+            let detail = format!("token #{}", id);
+            state
+                .audit(&auth.user, AuditKind::TokenDeleted, Some(&detail), &source)
+                .await;
+            match rotate_csrf(&state, &auth).await {
+                Ok(csrf_token) => {
+                    (StatusCode::NO_CONTENT, [(CSRF_TOKEN_HEADER, csrf_token)]).into_response()
+                }
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            }
+        }
+        Ok(None) => StatusCode::NOT_FOUND.into_response(), // failure
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(), // db splode
+    }
 }
 
 #[derive(Deserialize, Debug)]
-pub struct ChangeEmailParams {
-    password: String,
-    // Always present, but gonna flat-map and pass directly to set_email.
-    new_email: Option<String>,
-    csrf_token: String,
+pub struct PatchTokenParams {
+    scope: Option<String>,
+    /// Blank clears it.
+    comment: Option<String>,
 }
 
-/// The change email form, on the account page.
+/// Handle PATCH for tokens, which can rename a token and/or switch it
+/// between write and manage scope, without changing its cleartext.
+/// Session-only, like DELETE, and likewise not CSRF-vulnerable, since it
+/// takes a JSON body.
 #[tracing::instrument(skip_all)]
-pub async fn post_change_email(
+pub async fn patch_token(
     State(state): State<DogState>,
     auth: AuthSession,
-    Form(params): Form<ChangeEmailParams>,
+    Path(id): Path<i64>,
+    Json(params): Json<PatchTokenParams>,
+) -> StatusCode {
+    // Check everything before changing anything.
+    let scope = params.scope.as_deref().map(TokenScope::from);
+    if scope.is_some_and(|s| !s.is_switchable()) {
+        return StatusCode::BAD_REQUEST;
+    }
+    if let Some(comment) = &params.comment {
+        if validate(Field::TokenComment, comment.trim()).is_err() {
+            return StatusCode::BAD_REQUEST;
+        }
+    }
+    if scope.is_none() && params.comment.is_none() {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let tokens = state.db.tokens();
+    let res = async {
+        let mut found = None;
+        if let Some(comment) = &params.comment {
+            found = tokens.set_comment(id, auth.user.id, Some(comment)).await?;
+            if found.is_none() {
+                return Ok(None);
+            }
+        }
+        if let Some(scope) = scope {
+            found = tokens.set_scope(id, auth.user.id, scope).await?;
+            if let Some(token) = &found {
+                let scope: &str = scope.into();
+                info!(target: "audit", username = %auth.user.username, token_id = token.id, scope, "token scope changed");
+            }
+        }
+        Ok::<_, sqlx::Error>(found)
+    }
+    .await;
+    match res {
+        Ok(Some(_)) => StatusCode::NO_CONTENT,       // success
+        Ok(None) => StatusCode::NOT_FOUND,           // failure
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR, // db splode
+    }
+}
+
+/// Handle DELETE for sessions. Effectively an API method, but since it's
+/// only valid for session users, it lives outside the api namespace.
+#[tracing::instrument(skip_all)]
+pub async fn delete_session(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Path(external_id): Path<i64>,
+) -> StatusCode {
+    match state
+        .db
+        .sessions()
+        .destroy_external(external_id, auth.user.id)
+        .await
+    {
+        Ok(Some(_)) => StatusCode::NO_CONTENT,       // success
+        Ok(None) => StatusCode::NOT_FOUND,           // failure
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR, // db splode
+    }
+}
+
+/// Handle POSTs from the logout button. This redirects to /.
+#[tracing::instrument(skip_all)]
+pub async fn post_logout(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    cookies: Cookies,
+    Form(params): Form<LogoutParams>,
 ) -> WebResult<Redirect> {
+    // Destroy the session! Destroy the cookie! Well, first check the csrf token.
     if params.csrf_token != auth.session.csrf_token {
         return Err(WebError::new(
             StatusCode::BAD_REQUEST,
-            r#"The change email form you tried to use was stale, or
-                had been tampered with. Go back to the account page and try
-                changing your email again."#
+            r#"Something was wrong with that log out button! Go back to the
+                home page and try logging out again."#
                 .to_string(),
         ));
     }
-    let users = state.db.users();
-    let Some(user) = users
-        .authenticate(&auth.user.username, &params.password)
-        .await?
-    else {
-        return Err(WebError::new(
-            StatusCode::BAD_REQUEST,
-            "Wrong password".to_string(),
-        ));
-    };
-    let new_email = clean_optional_form_field(params.new_email.as_deref());
-    users.set_email(&user.username, new_email).await?;
-    Ok(Redirect::to("/account?changed=email"))
+    // Session goes first; that way if something goes wrong and it's still alive,
+    // the user still has a cookie to try logging out with later.
+    let res = state.db.sessions().destroy(&auth.session.id).await?;
+    if res.is_none() {
+        error!(
+            logout.sessid = %auth.session.id,
+            logout.userid = %auth.user.id,
+            "Session not found for logout. This should be impossible, since we had a valid session!"
+        );
+    }
+    cookies.remove((COOKIE_SESSION, "").into());
+    Ok(Redirect::to("/"))
 }
 
-/// Change password form args
 #[derive(Deserialize, Debug)]
-pub struct ChangePasswordParams {
+pub struct LogoutParams {
+    pub csrf_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteAccountParams {
     password: String,
-    new_password: String,
-    new_password_again: String,
+    confirm_delete_account: String,
     csrf_token: String,
 }
 
-/// The change password form, on the account page. Acts a little like the signup form.
+/// The delete account form, on the account page. It's kind of like the Final Logout.
 #[tracing::instrument(skip_all)]
-pub async fn post_changepassword(
+pub async fn post_delete_account(
     State(state): State<DogState>,
     auth: AuthSession,
-    Form(params): Form<ChangePasswordParams>,
+    source: AuditSource,
+    cookies: Cookies,
+    Form(params): Form<DeleteAccountParams>,
 ) -> WebResult<Redirect> {
     if params.csrf_token != auth.session.csrf_token {
         return Err(WebError::new(
             StatusCode::BAD_REQUEST,
-            r#"The change password form you tried to use was stale, or
+            r#"The delete account form you tried to use was stale, or
                 had been tampered with. Go back to the account page and try
-                changing your password again."#
+                deleting your account again."#
                 .to_string(),
         ));
     }
-    if let Err(e) = check_new_password(&params.new_password, &params.new_password_again) {
-        return Err(WebError::new(StatusCode::BAD_REQUEST, e.to_string()));
-    }
     let users = state.db.users();
+    // authenticate the password, validate the confirm string, waste the
+    // session cookie, delete the user (which will cascade to all foreign key refs).
     let Some(user) = users
         .authenticate(&auth.user.username, &params.password)
         .await?
     else {
         return Err(WebError::new(
             StatusCode::BAD_REQUEST,
-            "Wrong existing password".to_string(),
+            "Wrong password".to_string(),
         ));
     };
-    users
+    if params.confirm_delete_account.trim() != DELETE_ACCOUNT_CONFIRM_STRING {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                r#"Wrong delete confirmation string; you must type the exact phrase
+                "delete my account" (without the quotation marks) into the account delete
+                form, but you typed "{}" instead. Go back to the account page and try
+                deleting your account again."#,
+                &params.confirm_delete_account
+            ),
+        ));
+    }
+    // OK, at this point we're ready to party.
+    // The From impl on WebError uses ToString, so throwing a str is fine.
+    users.destroy(user.id).await?.ok_or(UserError::Impossible(
+        "User not found! That shouldn't be possible at this point??",
+    ))?;
+    state
+        .audit(&user, AuditKind::AccountDeleted, None, &source)
+        .await;
+    cookies.remove(auth.session.as_ref().clone().into_cookie());
+
+    Ok(Redirect::to("/"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LoginParams {
+    pub username: String,
+    pub password: String,
+    pub login_csrf_token: String,
+    pub return_to: String,
+}
+
+/// Handle POSTs from the login form. The login form can appear on multiple pages,
+/// and includes a pointer back to the page the user was originally trying to reach.
+/// The form also includes a random token to be used in a signed double-submit
+/// CSRF-prevention scheme; compare it to the cookie to validate that the post came
+/// from a real login form, not a remote-site forgery.
+#[tracing::instrument(skip_all)]
+pub async fn post_login(
+    State(state): State<DogState>,
+    cookies: Cookies,
+    req_headers: HeaderMap,
+    source: AuditSource,
+    Form(params): Form<LoginParams>,
+) -> WebResult<Response> {
+    // First, check the login CSRF cookie
+    let signed_cookies = cookies.signed(&state.cookie_key);
+    let Some(csrf_cookie) = signed_cookies.get(COOKIE_LOGIN_CSRF) else {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The login form you tried to use was broken.
+                Go back to the home page and try logging in again."#
+                .to_string(),
+        ));
+    };
+    if csrf_cookie.value() != params.login_csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The login form you tried to use was stale or had been
+                tampered with. Go back to the home page and try logging in again."#
+                .to_string(),
+        ));
+    }
+    // Cool. 👍🏼 Waste the cookie, it's spent.
+    signed_cookies.remove(csrf_cookie);
+
+    // Sort out the redirect URL. If it's bad (illegible, off-site...),
+    // just go to the home page.
+    let mut redirect_to = state
+        .config
+        .public_url
+        .join(&params.return_to)
+        .unwrap_or_else(|_| state.config.public_url.clone());
+    if redirect_to.origin() != state.config.public_url.origin() {
+        redirect_to = state.config.public_url.clone();
+    }
+
+    // A login that didn't take goes back to the form, saying why.
+    let retry = |status: StatusCode, message: &str| -> WebResult<Response> {
+        let error = FieldError::new("password", message);
+        let page = render_login_form(&state, &cookies, &params.return_to, None, Some(&error))?;
+        Ok((status, page).into_response())
+    };
+
+    // Same per-username limit as Basic auth, token exchange, and two-factor
+    // codes, so the web form isn't the easy way to guess a password.
+    let username = params.username.as_str();
+    let limiter = &state.basic_auth_limiter;
+    let exempt = state.config.rate_limit_allowlist.exempts(&req_headers);
+    if !exempt && limiter.is_limited(username) {
+        warn!(target: "audit", %username, "web login: rate limited");
+        return retry(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many failed logins for that username. Wait a while, then try again.",
+        );
+    }
+
+    // then, authenticate user and tack on a session cookie.
+    let Some(user) = state
+        .db
+        .users()
+        .authenticate(username, &params.password)
+        .await?
+    else {
+        if exempt {
+            warn!(target: "audit", %username, "web login: bad password (allowlisted client)");
+        } else {
+            let failures = limiter.record(username);
+            warn!(target: "audit", %username, failures, "web login: bad password");
+            if failures == limiter.max() {
+                warn!(target: "alerts", %username, failures, "possible password guessing: locking this username out for a while");
+            }
+        }
+        return retry(
+            StatusCode::UNAUTHORIZED,
+            "That username and password didn't work. Check them and try again.",
+        );
+    };
+    // ...unless they've got two-factor on, in which case that waits for
+    // their code. (That's also why the failure count doesn't reset until
+    // after this: a right password shouldn't wipe out a run of wrong codes.)
+    if state.db.totp().enabled(user.id).await? {
+        start_totp_login(&state, &cookies, &user, redirect_to.as_str());
+        return Ok(Redirect::to("/login/totp").into_response());
+    }
+    limiter.reset(username);
+    finish_login(&state, &cookies, &req_headers, &source, &user, None).await?;
+    if let Some(reset) = forced_reset_redirect(&state, &user).await? {
+        return Ok(reset.into_response());
+    }
+    Ok(Redirect::to(redirect_to.as_str()).into_response())
+}
+
+/// The end of any login that worked, however many steps it took: a session
+/// cookie, a check for new devices, and a line in their recent activity.
+pub async fn finish_login(
+    state: &DogState,
+    cookies: &Cookies,
+    req_headers: &HeaderMap,
+    source: &AuditSource,
+    user: &User,
+    detail: Option<&str>,
+) -> WebResult<()> {
+    let user_agent = req_headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok());
+    let session = state.db.sessions().create(user.id, user_agent).await?;
+    cookies.add(session.into_cookie());
+    state.check_login_device(user, req_headers).await;
+    state.audit(user, AuditKind::Login, detail, source).await;
+    Ok(())
+}
+
+/// If an admin's making this user pick a new password, their login goes to
+/// the change password page instead of wherever it was headed. (The session
+/// middleware would send them there anyway, but this skips a hop.)
+pub async fn forced_reset_redirect(state: &DogState, user: &User) -> WebResult<Option<Redirect>> {
+    Ok(must_reset_password(state, user.id)
+        .await?
+        .then(|| Redirect::to("/changepassword")))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HomeInstanceParams {
+    /// One of the configured peers, or blank to forget the hint.
+    instance: String,
+    login_csrf_token: String,
+    return_to: String,
+}
+
+/// Handle POSTs from the "my account's on another instance" form on the login
+/// page. Sets (or clears) the home-instance cookie that /resume checks before
+/// falling back to the login form; then goes back where you were headed, so
+/// if that was a /resume, it hands you off right away.
+#[tracing::instrument(skip_all)]
+pub async fn post_home_instance(
+    State(state): State<DogState>,
+    cookies: Cookies,
+    Form(params): Form<HomeInstanceParams>,
+) -> WebResult<Redirect> {
+    let signed_cookies = cookies.signed(&state.cookie_key);
+    let Some(csrf_cookie) = signed_cookies
+        .get(COOKIE_LOGIN_CSRF)
+        .filter(|c| c.value() == params.login_csrf_token)
+    else {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The form you tried to use was stale or had been
+                tampered with. Go back to the home page and try again."#
+                .to_string(),
+        ));
+    };
+    signed_cookies.remove(csrf_cookie);
+
+    if params.instance.is_empty() {
+        cookies.remove((COOKIE_HOME_INSTANCE, "").into());
+    } else {
+        let Some(peer) = home_peer(&state.config.peer_instances, Some(&params.instance)) else {
+            return Err(WebError::new(
+                StatusCode::BAD_REQUEST,
+                "That's not one of the instances this site knows about.".to_string(),
+            ));
+        };
+        // Lax, not strict, because the whole point is to read it on a
+        // bookmarklet's cross-site navigation to /resume.
+        let hint = Cookie::build((COOKIE_HOME_INSTANCE, peer.origin().ascii_serialization()))
+            .max_age(time::Duration::days(365))
+            .http_only(true)
+            .secure(true)
+            .same_site(tower_cookies::cookie::SameSite::Lax)
+            .build()
+            .into_owned();
+        cookies.add(hint);
+    }
+
+    // Same rules as the login form: on-site, or home.
+    let redirect_to = state
+        .config
+        .public_url
+        .join(&params.return_to)
+        .ok()
+        .filter(|u| u.origin() == state.config.public_url.origin())
+        .unwrap_or_else(|| state.config.public_url.clone());
+    Ok(Redirect::to(redirect_to.as_str()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ThemeParams {
+    theme: String,
+}
+
+/// Handle POSTs from the theme picker in the footer. The choice belongs to
+/// the browser, not the account, so it's just a cookie that the layout
+/// reads on every page. There's no anti-CSRF check, since the picker works
+/// logged out too and the worst a forged one could do is change your
+/// colors. Afterwards, it's back to the page you were on.
+#[tracing::instrument(skip_all)]
+pub async fn post_theme(
+    State(state): State<DogState>,
+    cookies: Cookies,
+    req_headers: HeaderMap,
+    Form(params): Form<ThemeParams>,
+) -> WebResult<Redirect> {
+    let Some(theme) = Theme::parse(&params.theme) else {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            "That's not one of the themes.".to_string(),
+        ));
+    };
+    if theme == Theme::Auto {
+        cookies.remove((COOKIE_THEME, "").into());
+    } else {
+        let cookie = Cookie::build((COOKIE_THEME, theme.as_str()))
+            .max_age(time::Duration::days(365))
+            .http_only(true)
+            .secure(true)
+            .same_site(tower_cookies::cookie::SameSite::Lax)
+            .build()
+            .into_owned();
+        cookies.add(cookie);
+    }
+
+    // Same rules as the login form's return_to: on-site, or home.
+    let redirect_to = req_headers
+        .get(header::REFERER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|r| state.config.public_url.join(r).ok())
+        .filter(|u| u.origin() == state.config.public_url.origin())
+        .unwrap_or_else(|| state.config.public_url.clone());
+    Ok(Redirect::to(redirect_to.as_str()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SignupParams {
+    new_username: String,
+    new_password: String,
+    new_password_again: String,
+    // Really email'll always be present (and maybe blank), but downstream
+    // recipients expect an Option and will flatmap it to normalize.
+    email: Option<String>,
+    login_csrf_token: String,
+    #[serde(flatten)]
+    guard: SignupGuardFields,
+}
+
+/// Handle POSTs from the signup form. This always appears alongside the login form.
+/// It has some of the same properties, but it always redirects to /.
+#[tracing::instrument(skip_all)]
+pub async fn post_signup(
+    State(state): State<DogState>,
+    cookies: Cookies,
+    req_headers: HeaderMap,
+    source: AuditSource,
+    maybe_auth: Option<AuthSession>,
+    Form(params): Form<SignupParams>,
+) -> WebResult<Response> {
+    // First, check the login CSRF cookie
+    let signed_cookies = cookies.signed(&state.cookie_key);
+    let Some(csrf_cookie) = signed_cookies.get(COOKIE_LOGIN_CSRF) else {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The signup form you tried to use was broken.
+                Go back to the home page and try signing up again."#
+                .to_string(),
+        ));
+    };
+    if csrf_cookie.value() != params.login_csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The signup form you tried to use was stale or had been
+                tampered with. Go back to the home page and try signing up again."#
+                .to_string(),
+        ));
+    }
+    // Cool. 👍🏼 Waste the cookie, it's spent.
+    signed_cookies.remove(csrf_cookie);
+
+    if maybe_auth.is_some() {
+        return Err(WebError::new(
+            StatusCode::FORBIDDEN,
+            "Can't sign up while you're still logged in. (How'd you even do that, by the way?)"
+                .to_string(),
+        ));
+    }
+    // Back to the form, with what they typed (minus the passwords).
+    let retry = |error: FieldError| -> WebResult<Response> {
+        let retry = SignupRetry {
+            error,
+            username: &params.new_username,
+            email: params.email.as_deref().unwrap_or_default(),
+        };
+        let page = render_login_form(&state, &cookies, "/", Some(retry), None)?;
+        Ok((StatusCode::BAD_REQUEST, page).into_response())
+    };
+    // Bots, before spending any effort on what they sent.
+    if let Err(e) = state
+        .signup_guard
+        .check(
+            &state.cookie_key,
+            &params.login_csrf_token,
+            &params.guard,
+            source.ip.as_deref(),
+        )
+        .await
+    {
+        state
+            .audit_rejected_signup(&params.new_username, &e, &source)
+            .await;
+        return retry(FieldError::new("signup", &e));
+    }
+    if let Err(e) = state
+        .check_new_password(
+            &params.new_password,
+            &params.new_password_again,
+            params.new_username.trim(),
+        )
+        .await
+    {
+        return retry(FieldError::new(e.field(), &e));
+    }
+    let email = match state.check_new_email(params.email.as_deref()).await {
+        Ok(email) => email,
+        Err(e) => return retry(FieldError::new("email", &e)),
+    };
+    // The account and its first session go in together, or not at all.
+    let tx = state.db.transaction();
+    let created = tx
+        .users()
+        .create_with_policy(
+            UsernamePolicy::from_config(state.config.unicode_usernames),
+            &params.new_username,
+            &params.new_password,
+            email.as_deref(),
+        )
+        .await;
+    let user = match created {
+        Ok(user) => user,
+        Err(MixedError::User(
+            e @ (UserError::BadUsername { .. }
+            | UserError::UsernameNotAllowed { .. }
+            | UserError::UserExists { .. }
+            | UserError::UsernameTooSimilar { .. }),
+        )) => {
+            // Let go of the write connection before doing anything else.
+            drop(tx);
+            return retry(FieldError::new("new_username", &e));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let user_agent = req_headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok());
+    let session = tx.sessions().create(user.id, user_agent).await?;
+    let welcome_kit = if state.config.signup.welcome {
+        Some(welcome_kit(&state, &tx, user.id).await?)
+    } else {
+        None
+    };
+    tx.commit().await?;
+    let csrf_token = session.csrf_token.clone();
+    cookies.add(session.into_cookie());
+    // Nothing to compare against yet, but this way their next login from
+    // the same place won't look new.
+    state.check_login_device(&user, &req_headers).await;
+    let Some((example, token, token_cleartext)) = welcome_kit else {
+        return Ok(Redirect::to("/").into_response());
+    };
+    let detail = token_detail(&token);
+    state
+        .audit(&user, AuditKind::TokenCreated, Some(&detail), &source)
+        .await;
+
+    // The welcome page comes straight back from the POST, since it's the
+    // only chance to show the bookmarklet's token.
+    let bookmarklet_url = state.render_bookmarklet("mark.js.j2", Some(&token_cleartext), false)?;
+    let welcome_page = WelcomePage {
+        bookmarklet_url: &bookmarklet_url,
+        example: &example,
+    };
+    let common = Common {
+        user: Some(&user),
+        csrf_token: &csrf_token,
+        theme: theme_from_cookies(&cookies),
+        ..Common::anonymous("Welcome to Eardogger")
+    };
+    let ctx = context! { common, welcome_page };
+    Ok(Html(state.render_view("welcome.html.j2", ctx)?).into_response())
+}
+
+/// Stock a new account (see `signup.welcome`): an example dogear for our
+/// own FAQ page, and a token for a starter bookmarklet.
+async fn welcome_kit(
+    state: &DogState,
+    tx: &DbTx<'_>,
+    user_id: i64,
+) -> Result<(Dogear, Token, String), MixedError<sqlx::Error>> {
+    let faq = own_url(&state.config.public_url, "/faq");
+    let example = tx
+        .dogears()
+        .create(
+            user_id,
+            &faq,
+            &format!("{}#how-to", faq),
+            Some("Eardogger FAQ (example dogear)"),
+        )
+        .await?;
+    let comment = dated_comment("Welcome bookmarklet created ")?;
+    let (token, token_cleartext) = tx
+        .tokens()
+        .create_bookmarklet(user_id, Bookmarklet::Plain, Some(&comment))
+        .await?;
+    Ok((example, token, token_cleartext))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChangeEmailParams {
+    password: String,
+    // Always present, but gonna flat-map and pass directly to set_email.
+    new_email: Option<String>,
+    csrf_token: String,
+}
+
+/// The change email form, on the account page.
+#[tracing::instrument(skip_all)]
+pub async fn post_change_email(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<ChangeEmailParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The change email form you tried to use was stale, or
+                had been tampered with. Go back to the account page and try
+                changing your email again."#
+                .to_string(),
+        ));
+    }
+    let users = state.db.users();
+    let Some(user) = users
+        .authenticate(&auth.user.username, &params.password)
+        .await?
+    else {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            "Wrong password".to_string(),
+        ));
+    };
+    // Everything past the password check goes through, so it's a fine
+    // time to retire the token that got us here.
+    rotate_csrf(&state, &auth).await?;
+    let new_email = state.check_new_email(params.new_email.as_deref()).await?;
+    let new_email = new_email.as_deref();
+    let old_email = user.email.as_deref();
+    if new_email == old_email {
+        return Ok(Redirect::to("/account?changed=email"));
+    }
+    let Some(mail) = &state.config.mail else {
+        // No mail means no way to confirm anything, but it also means
+        // nothing ever sends to the address, so just take it.
+        users.set_email(&user.username, new_email).await?;
+        return Ok(Redirect::to("/account?changed=email"));
+    };
+
+    // A new address doesn't count until it confirms; removing one has
+    // nobody to confirm it, so it's immediate. Either way, the old address
+    // hears about it and gets a way to undo it.
+    let change = state
+        .db
+        .email_changes()
+        .create(user.id, old_email, new_email, new_email.is_none())
+        .await?;
+    info!(target: "audit", username = %user.username, change_id = change.id, "email change requested");
+    if let Some(address) = new_email {
+        state.mail_later(
+            mail,
+            Email {
+                to: address.to_string(),
+                subject: "Confirm your new email for Eardogger".to_string(),
+                body: format!(
+                    "Hi {},\n\nTo start using this address for your Eardogger account at {}, confirm it here:\n\n  {}\n\nThat link works once, for the next {} days. If you didn't ask for this, you can ignore this email; nothing changes until someone uses the link.",
+                    &user.username,
+                    &state.config.public_url,
+                    state.email_link(EMAIL_LINK_CONFIRM, change.id),
+                    CONFIRM_WINDOW_DAYS,
+                ),
+                attachment: None,
+            },
+        );
+    }
+    if let Some(address) = old_email {
+        let what = match new_email {
+            Some(address) => format!(
+                "change your account's email from this address to {} (it switches over once that one confirms)",
+                address
+            ),
+            None => "remove this address from your account".to_string(),
+        };
+        state.mail_later(
+            mail,
+            Email {
+                to: address.to_string(),
+                subject: "Your Eardogger email is changing".to_string(),
+                body: format!(
+                    "Hi {},\n\nSomeone just logged into Eardogger at {} and used your password to {}.\n\nIf that was you, you're all set. If not, undo it here; that also logs out every session on your account:\n\n  {}\n\nThat link works once, for the next {} days. After undoing it, log in and change your password right away.",
+                    &user.username,
+                    &state.config.public_url,
+                    what,
+                    state.email_link(EMAIL_LINK_REVERT, change.id),
+                    REVERT_WINDOW_DAYS,
+                ),
+                attachment: None,
+            },
+        );
+    }
+    if new_email.is_some() {
+        Ok(Redirect::to("/account?changed=email_pending"))
+    } else {
+        Ok(Redirect::to("/account?changed=email"))
+    }
+}
+
+/// The two things an email change link can do.
+const EMAIL_LINK_CONFIRM: &str = "confirm";
+const EMAIL_LINK_REVERT: &str = "revert";
+
+/// Check an email change link, and look up the change it's for.
+async fn email_link_change(
+    state: &DogState,
+    action: &str,
+    token: &str,
+) -> WebResult<(EmailChange, String)> {
+    let not_found = || {
+        WebError::new(
+            StatusCode::NOT_FOUND,
+            "That link doesn't go anywhere. Make sure you copied the whole thing out of the email."
+                .to_string(),
+        )
+    };
+    if action != EMAIL_LINK_CONFIRM && action != EMAIL_LINK_REVERT {
+        return Err(not_found());
+    }
+    let Some(id) = verify_action_link(&state.cookie_key, action, token) else {
+        return Err(not_found());
+    };
+    state
+        .db
+        .email_changes()
+        .by_id(id)
+        .await?
+        .ok_or_else(not_found)
+}
+
+/// `GET /email/:action/:token`: where the links in email change messages
+/// go. Mail scanners love to follow links, so this only shows what the
+/// link would do, with a button to actually do it. No login needed; the
+/// link is the proof.
+#[tracing::instrument(skip_all)]
+pub async fn email_link(
+    State(state): State<DogState>,
+    Path((action, token)): Path<(String, String)>,
+    theme: Theme,
+) -> WebResult<Html<String>> {
+    let (change, username) = email_link_change(&state, &action, &token).await?;
+    let address = if action == EMAIL_LINK_CONFIRM {
+        change.new_email.as_deref()
+    } else {
+        change.old_email.as_deref()
+    };
+    let email_link = EmailLinkPage {
+        action: &action,
+        token: &token,
+        username: &username,
+        address,
+        done: false,
+    };
+    let common = Common {
+        theme,
+        ..Common::anonymous("Email change")
+    };
+    let ctx = context! {common, email_link};
+    Ok(Html(state.render_view("email_link.html.j2", ctx)?))
+}
+
+/// `POST /email/:action/:token`: use an email change link. Each one only
+/// works once.
+#[tracing::instrument(skip_all)]
+pub async fn post_email_link(
+    State(state): State<DogState>,
+    Path((action, token)): Path<(String, String)>,
+    theme: Theme,
+) -> WebResult<Html<String>> {
+    let (change, username) = email_link_change(&state, &action, &token).await?;
+    let changes = state.db.email_changes();
+    let used = if action == EMAIL_LINK_CONFIRM {
+        changes.confirm(change.id).await?
+    } else {
+        changes.revert(change.id).await?
+    };
+    let Some(change) = used else {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            "That link has already been used, or it's expired, or a newer email change replaced it.".to_string(),
+        ));
+    };
+    info!(target: "audit", %username, change_id = change.id, %action, "email change link used");
+    let address = if action == EMAIL_LINK_CONFIRM {
+        change.new_email.as_deref()
+    } else {
+        change.old_email.as_deref()
+    };
+    let email_link = EmailLinkPage {
+        action: &action,
+        token: &token,
+        username: &username,
+        address,
+        done: true,
+    };
+    let common = Common {
+        theme,
+        ..Common::anonymous("Email change")
+    };
+    let ctx = context! {common, email_link};
+    Ok(Html(state.render_view("email_link.html.j2", ctx)?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LoginAlertsParams {
+    // Checkbox, so it's only present when checked.
+    notify_new_device: Option<String>,
+    csrf_token: String,
+}
+
+/// The login alerts form, on the account page.
+#[tracing::instrument(skip_all)]
+pub async fn post_login_alerts(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<LoginAlertsParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The login alerts form you tried to use was stale, or
+                had been tampered with. Go back to the account page and try
+                again."#
+                .to_string(),
+        ));
+    }
+    state
+        .db
+        .prefs()
+        .set_notify_new_device(auth.user.id, params.notify_new_device.is_some())
+        .await?;
+    Ok(Redirect::to("/account?changed=login_alerts"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PublicProfileParams {
+    // Checkbox, so it's only present when checked.
+    public_profile: Option<String>,
+    csrf_token: String,
+}
+
+/// The public profile form, on the account page.
+#[tracing::instrument(skip_all)]
+pub async fn post_public_profile(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<PublicProfileParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The public profile form you tried to use was stale, or
+                had been tampered with. Go back to the account page and try
+                again."#
+                .to_string(),
+        ));
+    }
+    state
+        .db
+        .prefs()
+        .set_public_profile(auth.user.id, params.public_profile.is_some())
+        .await?;
+    Ok(Redirect::to("/account?changed=public_profile"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListDisplayParams {
+    // Checkboxes, so they're only present when checked.
+    compact_list: Option<String>,
+    show_prefix: Option<String>,
+    hide_dates: Option<String>,
+    csrf_token: String,
+}
+
+/// The dogears list display options form, on the account page.
+#[tracing::instrument(skip_all)]
+pub async fn post_list_display(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<ListDisplayParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The list display form you tried to use was stale, or
+                had been tampered with. Go back to the account page and try
+                again."#
+                .to_string(),
+        ));
+    }
+    state
+        .db
+        .prefs()
+        .set_list_display(
+            auth.user.id,
+            params.compact_list.is_some(),
+            params.show_prefix.is_some(),
+            params.hide_dates.is_some(),
+        )
+        .await?;
+    Ok(Redirect::to("/account?changed=list_display"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HistoryRetentionParams {
+    /// Months, or blank for the site default.
+    history_months: String,
+    csrf_token: String,
+}
+
+/// The history retention form, on the account page.
+#[tracing::instrument(skip_all)]
+pub async fn post_history_retention(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<HistoryRetentionParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The history retention form you tried to use was stale, or
+                had been tampered with. Go back to the account page and try
+                again."#
+                .to_string(),
+        ));
+    }
+    let months = match params.history_months.trim() {
+        "" => None,
+        m => match m.parse::<u32>() {
+            Ok(m) if (1..=MAX_RETENTION_MONTHS).contains(&m) => Some(m),
+            _ => {
+                return Err(WebError::new(
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "History retention has to be between 1 and {} months.",
+                        MAX_RETENTION_MONTHS
+                    ),
+                ))
+            }
+        },
+    };
+    state
+        .db
+        .prefs()
+        .set_history_months(auth.user.id, months)
+        .await?;
+    Ok(Redirect::to("/account?changed=history_retention"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PageSizeParams {
+    /// How many per page, or blank for the default.
+    page_size: String,
+    csrf_token: String,
+}
+
+/// The page size form, on the account page. Applies to every paginated
+/// list on the site (but not the API, which sticks to the site default).
+#[tracing::instrument(skip_all)]
+pub async fn post_page_size(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<PageSizeParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The page size form you tried to use was stale, or had been
+                tampered with. Go back to the account page and try again."#
+                .to_string(),
+        ));
+    }
+    let size = match params.page_size.trim() {
+        "" => None,
+        s => match s.parse::<u32>() {
+            Ok(s) if (1..=PAGE_MAX_SIZE).contains(&s) => Some(s),
+            _ => {
+                return Err(WebError::new(
+                    StatusCode::BAD_REQUEST,
+                    format!("Page sizes have to be between 1 and {}.", PAGE_MAX_SIZE),
+                ))
+            }
+        },
+    };
+    state.db.prefs().set_page_size(auth.user.id, size).await?;
+    Ok(Redirect::to("/account?changed=page_size"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StaleNudgeParams {
+    /// Months, "0" for never, or blank for the default.
+    stale_months: String,
+    csrf_token: String,
+}
+
+/// The archive nudge form, on the account page.
+#[tracing::instrument(skip_all)]
+pub async fn post_stale_nudge(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<StaleNudgeParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The archive suggestions form you tried to use was stale, or
+                had been tampered with. Go back to the account page and try
+                again."#
+                .to_string(),
+        ));
+    }
+    let months = match params.stale_months.trim() {
+        "" => None,
+        m => match m.parse::<u32>() {
+            Ok(m) if m <= MAX_STALE_MONTHS => Some(m),
+            _ => {
+                return Err(WebError::new(
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "Archive suggestions have to be between 1 and {} months (or 0 for never).",
+                        MAX_STALE_MONTHS
+                    ),
+                ))
+            }
+        },
+    };
+    state
+        .db
+        .prefs()
+        .set_stale_months(auth.user.id, months)
+        .await?;
+    Ok(Redirect::to("/account?changed=stale_nudge"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BackupsParams {
+    /// "email", "webhook", or blank for no backups.
+    destination: String,
+    webhook_url: Option<String>,
+    csrf_token: String,
+}
+
+/// The scheduled backups form, on the account page.
+#[tracing::instrument(skip_all)]
+pub async fn post_backups(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<BackupsParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The backups form you tried to use was stale, or had been
+                tampered with. Go back to the account page and try again."#
+                .to_string(),
+        ));
+    }
+    let backups = state.db.backups();
+    match params.destination.as_str() {
+        "" => {
+            backups.delete(auth.user.id).await?;
+        }
+        "email" => {
+            if state.config.mail.is_none() || auth.user.email.is_none() {
+                return Err(WebError::new(
+                    StatusCode::BAD_REQUEST,
+                    "Emailed backups need an email address on your account, and a site that can send mail.".to_string(),
+                ));
+            }
+            backups
+                .set(auth.user.id, BackupDestination::Email, None)
+                .await?;
+        }
+        "webhook" => {
+            if !state.config.backup_webhooks {
+                return Err(WebError::new(
+                    StatusCode::BAD_REQUEST,
+                    "This site doesn't do webhook backups.".to_string(),
+                ));
+            }
+            let url = validate_url(
+                params.webhook_url.as_deref().unwrap_or_default(),
+                Schemes::HttpsInProduction,
+                state.config.production,
+            )
+            .await
+            .map_err(|e| WebError::new(StatusCode::BAD_REQUEST, e))?;
+            backups
+                .set(auth.user.id, BackupDestination::Webhook, Some(url.as_str()))
+                .await?;
+        }
+        _ => {
+            return Err(WebError::new(
+                StatusCode::BAD_REQUEST,
+                "Backups go by email or webhook, or not at all.".to_string(),
+            ))
+        }
+    }
+    info!(target: "audit", username = %auth.user.username, destination = %params.destination, "backup schedule changed");
+    Ok(Redirect::to("/account?changed=backups"))
+}
+
+/// The custom CSS editor. Never applies the saved CSS to itself, so it's
+/// always usable for fixing a snippet that broke everything else.
+#[tracing::instrument(skip_all)]
+pub async fn custom_css_page(
+    State(state): State<DogState>,
+    auth: AuthSession,
+) -> WebResult<Html<String>> {
+    let common = Common {
+        custom_css: None,
+        breadcrumbs: ACCOUNT_CRUMBS,
+        canonical_url: Some(own_url(&state.config.public_url, "/account/custom_css")),
+        ..auth.common_args("Custom CSS")
+    };
+    let custom_css_page = CustomCssPage {
+        css: auth.prefs.custom_css.as_deref(),
+        previewing: false,
+    };
+    let ctx = context! {common, custom_css_page};
+    Ok(Html(state.render_view("custom_css.html.j2", ctx)?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CustomCssParams {
+    custom_css: Option<String>,
+    // "preview" or "save"
+    action: String,
+    csrf_token: String,
+}
+
+/// The custom CSS form: either show a preview, or save it and head back to
+/// the account page.
+#[tracing::instrument(skip_all)]
+pub async fn post_custom_css(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<CustomCssParams>,
+) -> WebResult<Response> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The custom CSS form you tried to use was stale, or
+                had been tampered with. Go back to the account page and try
+                again."#
+                .to_string(),
+        ));
+    }
+    let css = clean_custom_css(params.custom_css.as_deref())?;
+    if params.action == "preview" {
+        let common = Common {
+            custom_css: css,
+            breadcrumbs: ACCOUNT_CRUMBS,
+            ..auth.common_args("Custom CSS")
+        };
+        let custom_css_page = CustomCssPage {
+            css,
+            previewing: true,
+        };
+        let ctx = context! {common, custom_css_page};
+        return Ok(Html(state.render_view("custom_css.html.j2", ctx)?).into_response());
+    }
+    state.db.prefs().set_custom_css(auth.user.id, css).await?;
+    Ok(Redirect::to("/account?changed=custom_css").into_response())
+}
+
+/// The import page: pick a source, paste or load its export file.
+#[tracing::instrument(skip_all)]
+pub async fn import_page(
+    State(state): State<DogState>,
+    auth: AuthSession,
+) -> WebResult<Html<String>> {
+    let common = Common {
+        breadcrumbs: ACCOUNT_CRUMBS,
+        canonical_url: Some(own_url(&state.config.public_url, "/account/import")),
+        ..auth.common_args("Import dogears")
+    };
+    let import_page = ImportPage {
+        report: None,
+        source_name: None,
+        preview: None,
+        candidates: 0,
+        source: ImportSource::Pocket.form_value(),
+        trim: import::DEFAULT_TRIM,
+        data: "",
+        max_trim: import::MAX_TRIM,
+        migration_code: None,
+        migration_minutes: MIGRATION_CODE_MINUTES,
+        instance_url: state.config.public_url.as_str(),
+    };
+    let ctx = context! {common, import_page};
+    Ok(Html(state.render_view("import.html.j2", ctx)?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImportParams {
+    /// See `ImportSource::from_form`.
+    source: String,
+    data: String,
+    /// How many path segments to trim off each URL to get its prefix.
+    trim: Option<usize>,
+    /// "preview" to see the plan without making anything.
+    action: Option<String>,
+    csrf_token: String,
+}
+
+/// Do an import (or preview one), then show the import page again with
+/// how it went.
+#[tracing::instrument(skip_all)]
+pub async fn post_import(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<ImportParams>,
+) -> WebResult<Html<String>> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The import form you tried to use was stale, or had been
+                tampered with. Go back to the import page and try again."#
+                .to_string(),
+        ));
+    }
+    let Some(source) = ImportSource::from_form(&params.source) else {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            "Pick which app your export file came from.".to_string(),
+        ));
+    };
+    let trim = params
+        .trim
+        .unwrap_or(import::DEFAULT_TRIM)
+        .min(import::MAX_TRIM);
+    // A preview does all the same work, except for making the dogears.
+    let preview = params.action.as_deref() == Some("preview");
+    let (candidates, report) = if preview {
+        import::prepare(source, &params.data, trim)?
+    } else {
+        let report = import::run(&state.db, auth.user.id, source, &params.data, trim).await?;
+        (Vec::new(), report)
+    };
+    let shown = candidates.len().min(import::PREVIEW_MAX_CANDIDATES);
+    let common = Common {
+        breadcrumbs: ACCOUNT_CRUMBS,
+        ..auth.common_args("Import dogears")
+    };
+    let import_page = ImportPage {
+        report: Some(&report),
+        source_name: Some(source.name()),
+        preview: preview.then_some(&candidates[..shown]),
+        candidates: candidates.len(),
+        source: source.form_value(),
+        trim,
+        data: if preview { &params.data } else { "" },
+        max_trim: import::MAX_TRIM,
+        migration_code: None,
+        migration_minutes: MIGRATION_CODE_MINUTES,
+        instance_url: state.config.public_url.as_str(),
+    };
+    let ctx = context! {common, import_page};
+    Ok(Html(state.render_view("import.html.j2", ctx)?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MigrationCodeParams {
+    csrf_token: String,
+}
+
+/// Make a one-time code for moving an account here from another instance,
+/// and show the import page again with it. Replaces any earlier code.
+#[tracing::instrument(skip_all)]
+pub async fn post_migration_code(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<MigrationCodeParams>,
+) -> WebResult<Html<String>> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The migration code button was stale, or had been tampered
+                with. Go back to the import page and try again."#
+                .to_string(),
+        ));
+    }
+    let code = state.db.migration_codes().create(auth.user.id).await?;
+    info!(target: "audit", username = %auth.user.username, "migration code created");
+    let common = Common {
+        breadcrumbs: ACCOUNT_CRUMBS,
+        ..auth.common_args("Import dogears")
+    };
+    let import_page = ImportPage {
+        report: None,
+        source_name: None,
+        preview: None,
+        candidates: 0,
+        source: ImportSource::Pocket.form_value(),
+        trim: import::DEFAULT_TRIM,
+        data: "",
+        max_trim: import::MAX_TRIM,
+        migration_code: Some(&code),
+        migration_minutes: MIGRATION_CODE_MINUTES,
+        instance_url: state.config.public_url.as_str(),
+    };
+    let ctx = context! {common, import_page};
+    Ok(Html(state.render_view("import.html.j2", ctx)?))
+}
+
+/// Change password form args
+#[derive(Deserialize, Debug)]
+pub struct ChangePasswordParams {
+    password: String,
+    new_password: String,
+    new_password_again: String,
+    csrf_token: String,
+}
+
+/// The change password form, on the account page. Acts a little like the signup form.
+#[tracing::instrument(skip_all)]
+pub async fn post_changepassword(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    source: AuditSource,
+    Form(params): Form<ChangePasswordParams>,
+) -> WebResult<Response> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"The change password form you tried to use was stale, or
+                had been tampered with. Go back to the account page and try
+                changing your password again."#
+                .to_string(),
+        ));
+    }
+    if let Err(e) = state
+        .check_new_password(
+            &params.new_password,
+            &params.new_password_again,
+            &auth.user.username,
+        )
+        .await
+    {
+        // Back to the account page, with the form open and the problem on it.
+        // (Or back to the standalone form, if that's all they can see.)
+        let error = FieldError::new(e.field(), &e);
+        let page = if must_reset_password(&state, auth.user.id).await? {
+            change_password_view(&state, &auth, Some(&error)).await?
+        } else {
+            account_page(&state, &auth, &PaginationQuery::default(), Some(&error)).await?
+        };
+        return Ok((StatusCode::BAD_REQUEST, Html(page)).into_response());
+    }
+    let users = state.db.users();
+    let Some(user) = users
+        .authenticate(&auth.user.username, &params.password)
+        .await?
+    else {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            "Wrong existing password".to_string(),
+        ));
+    };
+    users
         .set_password(&user.username, &params.new_password)
         .await?;
+    state
+        .audit(&user, AuditKind::PasswordChanged, None, &source)
+        .await;
+    rotate_csrf(&state, &auth).await?;
+
+    Ok(Redirect::to("/account?changed=password").into_response())
+}
+
+/// The change password form on a page of its own. A forced password reset
+/// sends people here, since it's the one page they can still reach.
+#[tracing::instrument(skip_all)]
+pub async fn change_password_page(
+    State(state): State<DogState>,
+    auth: AuthSession,
+) -> WebResult<Html<String>> {
+    Ok(Html(change_password_view(&state, &auth, None).await?))
+}
 
-    Ok(Redirect::to("/account?changed=password"))
+async fn change_password_view(
+    state: &DogState,
+    auth: &AuthSession,
+    password_error: Option<&FieldError>,
+) -> WebResult<String> {
+    let common = auth.common_args("Change password");
+    let account_forms = forms::AccountForms::new(state.config.passwords.min_length, password_error);
+    let flags = state
+        .db
+        .users()
+        .flags(auth.user.id)
+        .await?
+        .unwrap_or_default();
+    let ctx = context! {common, account_forms, flags};
+    Ok(state.render_view("change_password.html.j2", ctx)?)
 }
 
 /// Render the login form, including the anti-CSRF double-submit cookie.
@@ -746,17 +3418,62 @@ pub async fn post_changepassword(
 /// login_form if they hit that branch.
 #[tracing::instrument(skip(state, cookies))]
 async fn login_form(state: DogState, cookies: Cookies, return_to: &str) -> WebResult<Html<String>> {
+    render_login_form(&state, &cookies, return_to, None, None)
+}
+
+/// A signup that didn't take, headed back to the form.
+struct SignupRetry<'a> {
+    error: FieldError,
+    username: &'a str,
+    email: &'a str,
+}
+
+/// The guts of `login_form`, plus the option of re-showing a failed signup
+/// or login.
+fn render_login_form(
+    state: &DogState,
+    cookies: &Cookies,
+    return_to: &str,
+    signup: Option<SignupRetry>,
+    login_error: Option<&FieldError>,
+) -> WebResult<Html<String>> {
     let csrf_token = uuid_string();
+    let signup_stamp = state.signup_guard.stamp(&state.cookie_key, &csrf_token);
+    let hint = cookies.get(COOKIE_HOME_INSTANCE);
+    let peers = &state.config.peer_instances;
     // Render the html string first, so we can get some use out of the owned string
     // before consuming it to build the cookie. 👍🏼
+    let challenge = state.signup_guard.widget();
+    let signup_form = forms::signup_form(
+        state.config.passwords.min_length,
+        state.config.unicode_usernames,
+        &signup_stamp,
+        challenge.as_ref(),
+        forms::SignupValues {
+            username: signup.as_ref().map(|s| s.username).unwrap_or_default(),
+            email: signup.as_ref().map(|s| s.email).unwrap_or_default(),
+            error: signup.as_ref().map(|s| &s.error),
+        },
+    );
     let login_page = LoginPage {
         return_to,
-        previously_failed: false, // TODO
+        peers: peers.iter().map(Url::as_str).collect(),
+        home_instance: home_peer(peers, hint.as_ref().map(|c| c.value())).map(Url::as_str),
+        login_form: forms::login_form(return_to, login_error),
+        signup_form,
+        challenge,
     };
     let common = Common {
         title: "Welcome to Eardogger",
         user: None,
         csrf_token: &csrf_token,
+        custom_css: None,
+        features: &NO_FEATURES,
+        breadcrumbs: &[],
+        page: None,
+        canonical_url: None,
+        last_login: None,
+        theme: theme_from_cookies(cookies),
     };
     let ctx = context! { login_page, common };
     let page = state.render_view("login.html.j2", ctx)?;
@@ -773,172 +3490,691 @@ async fn login_form(state: DogState, cookies: Cookies, return_to: &str) -> WebRe
     Ok(Html(page))
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ApiMeta {
-    pub pagination: Pagination,
+/// `GET /api/v1/list`. Sends an ETag, and answers a matching If-None-Match
+/// with an empty 304, so clients that poll don't have to re-download a list
+/// that hasn't changed. The tag comes from the whole response body, since
+/// pausing or publishing changes a dogear without touching its `updated`.
+#[tracing::instrument(skip_all)]
+pub async fn api_list(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    headers: HeaderMap,
+    Query(params): Query<PaginationQuery>,
+    Query(filter_query): Query<ListFilterQuery>,
+) -> ApiResult<Response> {
+    // Requires manage
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    let filter = filter_query.filter()?;
+    // Sessions already loaded the prefs; token and basic auth have to ask.
+    let default_size = match &auth {
+        AuthAny::Session { prefs, .. } => prefs.default_page_size(),
+        _ => state
+            .db
+            .prefs()
+            .get(auth.user().id)
+            .await?
+            .default_page_size(),
+    };
+    let (dogears, meta) = state
+        .db
+        .dogears()
+        .list_filtered(
+            auth.user().id,
+            filter,
+            params.page(),
+            params.size(default_size),
+        )
+        .await?;
+    let list = ApiDogearsList::new(dogears, meta.to_pagination(default_size));
+    let body = serde_json::to_vec(&list).map_err(anyhow::Error::from)?;
+    let tag = etag(&body);
+    // Somebody's own list, so only their own client gets to keep a copy,
+    // and it has to check back every time.
+    let res_headers = [
+        (header::ETAG, tag.clone()),
+        (header::CACHE_CONTROL, "private, no-cache".to_string()),
+    ];
+    if if_none_match(&headers, &tag) {
+        return Ok((StatusCode::NOT_MODIFIED, res_headers).into_response());
+    }
+    Ok((
+        res_headers,
+        [(header::CONTENT_TYPE, "application/json")],
+        body,
+    )
+        .into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn api_delete(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<StatusCode> {
+    // Requires manage
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    if state
+        .db
+        .dogears()
+        .destroy(id, auth.user().id)
+        .await?
+        .is_some()
+    {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "dogear not found".to_string(),
+        ))
+    }
+}
+
+/// `GET /api/v1/dogear/:id`: just the one dogear. Where the Location header
+/// from a create points.
+#[tracing::instrument(skip_all)]
+pub async fn api_dogear(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<Dogear>> {
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    match state.db.dogears().by_id(id, auth.user().id).await? {
+        Some(dogear) => Ok(Json(dogear)),
+        None => Err(UserError::Dogear404.into()),
+    }
+}
+
+/// `PATCH /api/v1/dogear/:id`: change a dogear's prefix and/or display
+/// name, without deleting and recreating it (and losing its history).
+#[tracing::instrument(skip(state, auth))]
+pub async fn api_edit(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+    Json(payload): Json<ApiEditPayload>,
+) -> ApiResult<Json<Dogear>> {
+    // Requires manage
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    if payload.prefix.is_none() && payload.display_name.is_none() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "nothing to change; send a prefix and/or display_name".to_string(),
+        ));
+    }
+    match state
+        .db
+        .dogears()
+        .edit(
+            id,
+            auth.user().id,
+            payload.prefix.as_deref(),
+            payload.display_name.as_deref(),
+        )
+        .await?
+    {
+        Some(dogear) => Ok(Json(dogear)),
+        None => Err(UserError::Dogear404.into()),
+    }
+}
+
+/// Shared guts of the pause and unpause endpoints.
+async fn api_set_paused(
+    state: DogState,
+    auth: AuthAny,
+    id: i64,
+    paused: bool,
+) -> ApiResult<Json<Dogear>> {
+    // Requires manage
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    match state
+        .db
+        .dogears()
+        .set_paused(id, auth.user().id, paused)
+        .await?
+    {
+        Some(dogear) => Ok(Json(dogear)),
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "dogear not found".to_string(),
+        )),
+    }
+}
+
+async fn api_set_public(
+    state: DogState,
+    auth: AuthAny,
+    id: i64,
+    public: bool,
+) -> ApiResult<Json<Dogear>> {
+    // Requires manage
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    match state
+        .db
+        .dogears()
+        .set_public(id, auth.user().id, public)
+        .await?
+    {
+        Some(dogear) => Ok(Json(dogear)),
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "dogear not found".to_string(),
+        )),
+    }
+}
+
+/// List a dogear on your public profile.
+#[tracing::instrument(skip_all)]
+pub async fn api_publish(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<Dogear>> {
+    api_set_public(state, auth, id, true).await
+}
+
+/// Take a dogear back off your public profile.
+#[tracing::instrument(skip_all)]
+pub async fn api_unpublish(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<Dogear>> {
+    api_set_public(state, auth, id, false).await
+}
+
+async fn api_set_archived(
+    state: DogState,
+    auth: AuthAny,
+    id: i64,
+    archived: bool,
+) -> ApiResult<Json<Dogear>> {
+    // Requires manage
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    match state
+        .db
+        .dogears()
+        .set_archived(id, auth.user().id, archived)
+        .await?
+    {
+        Some(dogear) => Ok(Json(dogear)),
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "dogear not found".to_string(),
+        )),
+    }
+}
+
+/// Move a dogear off your main list and into the archive.
+#[tracing::instrument(skip_all)]
+pub async fn api_archive(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<Dogear>> {
+    api_set_archived(state, auth, id, true).await
+}
+
+/// Bring a dogear back out of the archive.
+#[tracing::instrument(skip_all)]
+pub async fn api_unarchive(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<Dogear>> {
+    api_set_archived(state, auth, id, false).await
+}
+
+/// Where a dogear has been, newest first, with any notes.
+#[tracing::instrument(skip_all)]
+pub async fn api_history(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<Vec<DogearHistoryEntry>>> {
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    match state.db.dogears().history(id, auth.user().id).await? {
+        Some(entries) => Ok(Json(entries)),
+        None => Err(UserError::Dogear404.into()),
+    }
+}
+
+/// Stop a dogear from accepting updates (e.g. while re-reading from the top).
+#[tracing::instrument(skip_all)]
+pub async fn api_pause(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<Dogear>> {
+    api_set_paused(state, auth, id, true).await
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn api_unpause(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<Dogear>> {
+    api_set_paused(state, auth, id, false).await
+}
+
+#[tracing::instrument(skip(state, auth))]
+pub async fn api_create(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Json(payload): Json<ApiCreatePayload>,
+) -> ApiResult<(StatusCode, [(HeaderName, String); 1], Json<Dogear>)> {
+    // Both manage and write are ok
+    auth.allowed_scopes(&[TokenScope::WriteDogears, TokenScope::ManageDogears])?;
+    let (prefix, current) = state
+        .canonical_new_dogear(&payload.prefix, &payload.current)
+        .await;
+    let res = state
+        .db
+        .dogears()
+        .create(
+            auth.user().id,
+            &prefix,
+            &current,
+            payload.display_name.as_deref(),
+        )
+        .await?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, dogear_location(res.id))],
+        Json(res),
+    ))
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ApiDogearsList {
-    pub data: Vec<Dogear>,
-    pub meta: ApiMeta,
+#[derive(Deserialize, Debug)]
+pub struct SuggestQuery {
+    url: String,
 }
 
-impl ApiDogearsList {
-    fn new(dogears: Vec<Dogear>, list_meta: ListMeta) -> Self {
-        Self {
-            data: dogears,
-            meta: ApiMeta {
-                pagination: list_meta.to_pagination(),
-            },
-        }
+/// `GET /api/v1/suggest?url=...`: guess a prefix for a new dogear, same as
+/// the create form does. Doesn't look at (or touch) any dogears.
+#[tracing::instrument(skip_all)]
+pub async fn api_suggest(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Query(query): Query<SuggestQuery>,
+) -> ApiResult<Json<ApiPrefixSuggestion>> {
+    auth.allowed_scopes(&[TokenScope::WriteDogears, TokenScope::ManageDogears])?;
+    match state.suggest_prefix(&query.url).await? {
+        Some(prefix) => Ok(Json(ApiPrefixSuggestion { prefix })),
+        None => Err(UserError::DogearInvalidUrl { url: query.url }.into()),
     }
 }
 
+/// Swap the token you're authenticating with for a new one (same scope and
+/// comment), in one shot. Only makes sense for token auth; login sessions
+/// can just make new tokens on the account page.
 #[tracing::instrument(skip_all)]
-pub async fn api_list(
+pub async fn api_rotate_token(
     State(state): State<DogState>,
     auth: AuthAny,
-    Query(params): Query<PaginationQuery>,
-) -> ApiResult<Json<ApiDogearsList>> {
-    // Requires manage
-    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
-    let (dogears, meta) = state
-        .db
-        .dogears()
-        .list(auth.user().id, params.page(), params.size())
-        .await?;
-    Ok(Json(ApiDogearsList::new(dogears, meta)))
+    source: AuditSource,
+) -> ApiResult<Json<ApiRotatedToken>> {
+    auth.allowed_scopes(&[TokenScope::WriteDogears, TokenScope::ManageDogears])?;
+    let AuthAny::Token { user, token } = auth else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Token rotation needs token auth; it rotates the token you used to call it."
+                .to_string(),
+        ));
+    };
+    match state.db.tokens().rotate(token.id, user.id).await? {
+        Some((new_token, cleartext)) => {
+            let detail = format!("{} (replacing #{})", token_detail(&new_token), token.id);
+            state
+                .audit(&user, AuditKind::TokenCreated, Some(&detail), &source)
+                .await;
+            Ok(Json(ApiRotatedToken {
+                id: new_token.id,
+                token: cleartext,
+                scope: <&str>::from(new_token.scope()).to_string(),
+                comment: new_token.comment,
+                expires: new_token.expires,
+            }))
+        }
+        // Someone else rotated or deleted it out from under us.
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "token not found".to_string(),
+        )),
+    }
 }
 
+/// POST /api/v1/token: trade a username and password for a fresh write
+/// token, so a browser extension can set itself up without anyone
+/// scraping (or copying from) the account page. This is a password login
+/// by another name, so it's treated like one: wrong passwords count
+/// against the same per-username limit as API Basic auth (sharing its
+/// budget, so trying both doesn't buy extra guesses), new-device alerts
+/// go out, and everything lands in the audit log. Successes have their
+/// own limit too, since nobody needs a pile of these. Brings its own auth,
+/// so it lives outside the auth layers.
 #[tracing::instrument(skip_all)]
-pub async fn api_delete(
+pub async fn api_token_exchange(
     State(state): State<DogState>,
-    auth: AuthAny,
-    Path(id): Path<i64>,
-) -> ApiResult<StatusCode> {
-    // Requires manage
-    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
-    if state
+    req_headers: HeaderMap,
+    source: AuditSource,
+    Json(payload): Json<ApiTokenRequest>,
+) -> ApiResult<Json<ApiNewToken>> {
+    let username = payload.username.as_str();
+    let exempt = state.config.rate_limit_allowlist.exempts(&req_headers);
+    let limiter = &state.basic_auth_limiter;
+    if !exempt && limiter.is_limited(username) {
+        warn!(target: "audit", %username, "token exchange: rate limited");
+        return Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many failed login attempts for that user. Try again later.".to_string(),
+        ));
+    }
+    let Some(user) = state
         .db
-        .dogears()
-        .destroy(id, auth.user().id)
+        .users()
+        .authenticate(username, &payload.password)
         .await?
-        .is_some()
-    {
-        Ok(StatusCode::NO_CONTENT)
-    } else {
-        Err(ApiError::new(
-            StatusCode::NOT_FOUND,
-            "dogear not found".to_string(),
-        ))
+    else {
+        if exempt {
+            warn!(target: "audit", %username, "token exchange: bad password (allowlisted client)");
+        } else {
+            let failures = limiter.record(username);
+            warn!(target: "audit", %username, failures, "token exchange: bad password");
+        }
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "Wrong username or password.".to_string(),
+        ));
+    };
+    // A password alone isn't enough to log in to these accounts, so it
+    // shouldn't be enough to get a token either. (Or to reset the failure
+    // count, which wrong two-factor codes add to.)
+    if state.db.totp().enabled(user.id).await? {
+        warn!(target: "audit", username = %user.username, "token exchange: account uses two-factor");
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "This account uses two-factor authentication, so it can't trade a password for a token. Make one on the account page instead.".to_string(),
+        ));
+    }
+    limiter.reset(username);
+    if must_reset_password(&state, user.id).await? {
+        warn!(target: "audit", username = %user.username, "token exchange: password reset required");
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "A site admin has asked you to choose a new password. Change it on the website, then try again with the new one.".to_string(),
+        ));
+    }
+    if !exempt {
+        if state.token_exchange_limiter.is_limited(&user.username) {
+            warn!(target: "audit", username = %user.username, "token exchange: too many new tokens");
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many new tokens for this account lately. Try again later.".to_string(),
+            ));
+        }
+        state.token_exchange_limiter.record(&user.username);
     }
+
+    let comment = payload.comment.as_deref().unwrap_or_default().trim();
+    validate(Field::TokenComment, comment)?;
+    let comment = match comment {
+        "" => dated_comment("Token exchange ")?,
+        c => c.to_string(),
+    };
+    let expires = state
+        .config
+        .token_lifetime_days
+        .map(|days| OffsetDateTime::now_utc() + time::Duration::days(days.into()));
+    let (token, cleartext) = state
+        .db
+        .tokens()
+        .create_expiring(user.id, TokenScope::WriteDogears, Some(&comment), expires)
+        .await?;
+    info!(target: "audit", username = %user.username, token_id = token.id, "token exchange: success");
+    state.check_login_device(&user, &req_headers).await;
+    let detail = format!("{} (token exchange)", token_detail(&token));
+    state
+        .audit(&user, AuditKind::TokenCreated, Some(&detail), &source)
+        .await;
+    Ok(Json(ApiNewToken {
+        id: token.id,
+        token: cleartext,
+        scope: <&str>::from(token.scope()).to_string(),
+        comment: token.comment,
+        expires: token.expires,
+    }))
 }
 
-#[derive(Deserialize, Debug)]
-pub struct ApiCreatePayload {
-    prefix: String,
-    current: String,
-    display_name: Option<String>,
+#[derive(Deserialize, Debug, Default)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
 }
 
-#[tracing::instrument(skip(state, auth))]
-pub async fn api_create(
+/// GET /api/v1/export: all your dogears, as JSON lines (one Dogear object
+/// per line, oldest first), or as OPML with `?format=opml`. This is
+/// streamed a page at a time instead of buffered, so a giant account
+/// doesn't have to fit in memory all at once. The catch is that errors
+/// after the first page can only cut the response off early, so clients
+/// should sanity-check what they got. Requires manage.
+#[tracing::instrument(skip_all)]
+pub async fn api_export(
     State(state): State<DogState>,
     auth: AuthAny,
-    Json(payload): Json<ApiCreatePayload>,
-) -> ApiResult<(StatusCode, Json<Dogear>)> {
-    // Both manage and write are ok
-    auth.allowed_scopes(&[TokenScope::WriteDogears, TokenScope::ManageDogears])?;
-    let res = state
+    Query(query): Query<ExportQuery>,
+) -> ApiResult<Response> {
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    let user_id = auth.user().id;
+    // Get the first page before committing to a 200, so a broken db still
+    // gets a proper error response.
+    let first = state
         .db
         .dogears()
-        .create(
-            auth.user().id,
-            &payload.prefix,
-            &payload.current,
-            payload.display_name.as_deref(),
-        )
+        .export_page(user_id, 0, EXPORT_PAGE_SIZE)
         .await?;
-    Ok((StatusCode::CREATED, Json(res)))
-}
-
-// Mutates a HeaderMap in-place to set the necessary CORS headers for a given
-// origin. This is hardcoded for the needs of the /api/v1/update endpoint,
-// because it's literally the only thing we do that needs cors, so it's not
-// worth investing in tower-http's CorsLayer yet.
-fn set_cors_headers_for_api_update(headers: &mut HeaderMap, origin: &str) -> Result<(), UserError> {
-    headers.insert(header::VARY, HeaderValue::from_name(header::ORIGIN));
-    // First off, we no longer do cookie auth on CORS, it's tokens or the highway. So:
-    headers.insert(
-        header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
-        HeaderValue::from_static("false"),
-    );
-    // Whoever you are: u are valid. (Actually, I think we could get away
-    // with *, now that we're not accepting credentials. Nevertheless!)
-    // Don't mind the map_err; this basically can't happen if the http::Request
-    // made it this far.
-    headers.insert(
-        header::ACCESS_CONTROL_ALLOW_ORIGIN,
-        origin.parse().map_err(|_| UserError::HttpFucked)?,
-    );
-    headers.insert(
-        header::ACCESS_CONTROL_ALLOW_METHODS,
-        HeaderValue::from_static("POST"),
-    );
-    headers.insert(
-        header::ACCESS_CONTROL_ALLOW_HEADERS,
-        HeaderValue::from_static("Content-Type, Authorization, Content-Length, X-Requested-With"),
-    );
-    Ok(())
+    let chunks = export_stream(state.db.clone(), user_id, first, query.format);
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                query.format.content_type().to_string(),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", query.format.filename()),
+            ),
+        ],
+        Body::from_stream(chunks),
+    )
+        .into_response())
 }
 
+/// GET /api/v1/cadences: a guess at how often each of your dogears
+/// updates, for the ones with enough history (see ApiCadence). Requires
+/// manage.
 #[tracing::instrument(skip_all)]
-pub async fn api_update_cors_preflight(
+pub async fn api_cadences(
     State(state): State<DogState>,
-    req_headers: HeaderMap,
-) -> ApiResult<(StatusCode, HeaderMap)> {
-    // At this point, there might or might not be an API token or session auth in
-    // play. We're not really gonna care until we reach the actual POST, tho.
-    // Just answer _as though_ they were properly auth'd.
-    let mut res_headers = HeaderMap::new();
+    auth: AuthAny,
+) -> ApiResult<Json<Vec<ApiCadence>>> {
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    let user_id = auth.user().id;
+    let cadences = state.cadences.get(&state.db, user_id).await?;
+    let now = OffsetDateTime::now_utc();
+    let list = guessed_dogears(&state.db, &cadences, user_id)
+        .await?
+        .into_iter()
+        .map(|(dogear, cadence)| ApiCadence {
+            interval_secs: cadence.interval.whole_seconds(),
+            interval_text: cadence.describe(),
+            last_marked: cadence.last_marked,
+            expected_next: cadence.expected_next(),
+            probably_new: !dogear.paused && cadence.probably_new(now),
+            dogear,
+        })
+        .collect();
+    Ok(Json(list))
+}
 
-    if let Some(origin) = req_headers.get(header::ORIGIN) {
-        if let Ok(origin) = origin.to_str() {
-            if origin != state.config.public_url.origin().ascii_serialization() {
-                // Then it's a CORS-eligible cross-origin request! Tack on them headers.
-                set_cors_headers_for_api_update(&mut res_headers, origin)?
+/// POST /api/v1/import: make a batch of dogears from a JSON array (see
+/// ApiImportDogear), all in one transaction. Entries that can't be made,
+/// like ones whose prefix you already have, get skipped and listed in the
+/// response instead of sinking the batch. Requires manage.
+#[tracing::instrument(skip_all)]
+pub async fn api_import(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Json(entries): Json<Vec<ApiImportDogear>>,
+) -> ApiResult<Json<ApiBulkImportResult>> {
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    if entries.len() > IMPORT_MAX_ENTRIES {
+        return Err(UserError::ImportTooBig {
+            max: IMPORT_MAX_ENTRIES,
+        }
+        .into());
+    }
+    let user_id = auth.user().id;
+    let results = state.db.dogears().create_many(user_id, &entries).await?;
+    let mut report = ApiBulkImportResult::default();
+    for (index, (entry, result)) in entries.iter().zip(results).enumerate() {
+        match result {
+            Ok(_) => report.created += 1,
+            Err(e) => {
+                report.skipped += 1;
+                report.problems.push(ApiImportProblem {
+                    index,
+                    prefix: entry.prefix.clone(),
+                    error: e.to_string(),
+                });
             }
         }
     }
+    info!(target: "audit", user_id, created = report.created, skipped = report.skipped, "bulk import");
+    Ok(Json(report))
+}
 
-    Ok((StatusCode::NO_CONTENT, res_headers))
+/// POST /api/v1/migrate_out: move your dogears to another instance, given
+/// its base URL and a migration code from its import page. Problems with
+/// the request itself get a normal error response; once the move starts,
+/// it's a 200 that streams progress as JSON lines (see
+/// ApiMigrationProgress), and a failure shows up as the last line. Your
+/// dogears here are left as they were. Requires manage.
+#[tracing::instrument(skip_all)]
+pub async fn api_migrate_out(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Json(payload): Json<ApiMigrateOutPayload>,
+) -> ApiResult<Response> {
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    if !state.config.migrate_out {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "This site doesn't push accounts to other instances. Use /api/v1/export and import that instead.".to_string(),
+        ));
+    }
+    if payload.code.trim().is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Moving needs a migration code from the instance you're moving to.".to_string(),
+        ));
+    }
+    let target = migrate_in_url(&payload.target, state.config.production)
+        .await
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let user = auth.user();
+    info!(target: "audit", username = %user.username, instance = %target, "account migration started");
+    let migration = Migration::new(
+        state.db.clone(),
+        user.id,
+        target,
+        &payload.code,
+        state.config.production,
+    )
+    .map_err(anyhow::Error::from)?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(migration.run()),
+    )
+        .into_response())
 }
 
-#[derive(Deserialize, Debug)]
-pub struct ApiUpdatePayload {
-    current: String,
+/// POST /api/v1/migrate_in: take in an account's dogears from another
+/// instance (see api_migrate_out). There's no login here; the code in the
+/// Eardogger-Migration-Code header says whose account they go in, and it
+/// only works once. The body is an export, as from /api/v1/export.
+#[tracing::instrument(skip_all)]
+pub async fn api_migrate_in(
+    State(state): State<DogState>,
+    headers: HeaderMap,
+    body: String,
+) -> ApiResult<Json<ApiImportReport>> {
+    let Some(code) = headers
+        .get(MIGRATION_CODE_HEADER)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "Migrations need a migration code.".to_string(),
+        ));
+    };
+    // Read it first, so a mangled export doesn't use up the code.
+    let dogears = parse_export(&body)?;
+    let Some(user_id) = state.db.migration_codes().redeem(code.trim()).await? else {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "That migration code is wrong, expired, or already used. Get a fresh one from the import page on the instance you're moving to.".to_string(),
+        ));
+    };
+    let report = import_export(&state.db, user_id, &dogears).await?;
+    info!(target: "audit", user_id, created = report.created, "account migration received");
+    Ok(Json(report))
 }
 
+/// `POST /api/v1/update`: move every dogear that matches `current` there.
 #[tracing::instrument(skip_all)]
 pub async fn api_update(
     State(state): State<DogState>,
     req_headers: HeaderMap,
     auth: AuthAny,
     Json(payload): Json<ApiUpdatePayload>,
+) -> ApiResult<(HeaderMap, Json<Vec<Dogear>>)> {
+    update_dogears(&state, &req_headers, &auth, payload).await
+}
+
+/// `PUT /api/v1/dogear?current=...`: the same thing as `POST /api/v1/update`,
+/// with the body's fields (`current`, and optionally `note`) as query params
+/// instead. It's for clients that have a hard time sending JSON, like webhook
+/// services and shell one-liners. It's a PUT because doing it twice leaves
+/// your dogears right where doing it once did.
+#[tracing::instrument(skip_all)]
+pub async fn api_put_dogear(
+    State(state): State<DogState>,
+    req_headers: HeaderMap,
+    auth: AuthAny,
+    Query(payload): Query<ApiUpdatePayload>,
+) -> ApiResult<(HeaderMap, Json<Vec<Dogear>>)> {
+    update_dogears(&state, &req_headers, &auth, payload).await
+}
+
+/// The guts of both update routes, so they can't drift apart.
+async fn update_dogears(
+    state: &DogState,
+    req_headers: &HeaderMap,
+    auth: &AuthAny,
+    payload: ApiUpdatePayload,
 ) -> ApiResult<(HeaderMap, Json<Vec<Dogear>>)> {
     // Both write and manage tokens are ok here.
     auth.allowed_scopes(&[TokenScope::WriteDogears, TokenScope::ManageDogears])?;
 
     let mut res_headers = HeaderMap::new();
 
+    // The CORS headers come from the route group's CorsLayer, but this part
+    // is ours: requests from other sites may only update your bookmark on
+    // THAT site.
     if let Some(origin) = req_headers.get(header::ORIGIN) {
         if let Ok(origin) = origin.to_str() {
             if origin != state.config.public_url.origin().ascii_serialization() {
-                // Then it's a CORS-eligible cross-origin request!
-
-                // OK, first, CORS ACCESS CHECK.
-                // Requests from other sites may only update your bookmark on THAT site.
                 let Ok(to_bookmark) = Url::parse(&payload.current) else {
                     return Err(UserError::DogearInvalidUrl {
                         url: payload.current,
@@ -948,20 +4184,327 @@ pub async fn api_update(
                 if to_bookmark.origin().ascii_serialization() != origin {
                     return Err(UserError::Dogear404.into());
                 }
-
-                // Ok, looks like we're good to go. Tack on them headers.
-                set_cors_headers_for_api_update(&mut res_headers, origin)?
             }
         }
     }
 
+    let note = clean_note(payload.note.as_deref())?;
     match state
         .db
         .dogears()
-        .update(auth.user().id, &payload.current)
+        .update(auth.user().id, &payload.current, note)
         .await?
     {
-        Some(ds) => Ok((res_headers, Json(ds))),
+        Some(ds) => {
+            // One link per dogear that matched, in the same order as the body.
+            let links = ds
+                .iter()
+                .map(|u| format!("<{}>; rel=\"item\"", dogear_location(u.dogear.id)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            if let Ok(links) = HeaderValue::from_str(&links) {
+                res_headers.insert(header::LINK, links);
+            }
+            Ok((
+                res_headers,
+                Json(ds.into_iter().map(|u| u.dogear).collect()),
+            ))
+        }
+        None => Err(UserError::Dogear404.into()),
+    }
+}
+
+/// How long a wait_for_update waits by default, and at most. Long enough to
+/// save a lot of polling; short enough to get in under most proxies' and
+/// e-readers' idle timeouts.
+const WAIT_DEFAULT_SECS: u64 = 30;
+pub const WAIT_MAX_SECS: u64 = 55;
+/// How many dogears a wait_for_update hands back.
+const WAIT_RESULT_LIMIT: u32 = 50;
+
+#[derive(Deserialize, Debug)]
+pub struct WaitQuery {
+    #[serde(with = "time::serde::iso8601")]
+    since: OffsetDateTime,
+    /// Seconds to wait, capped at WAIT_MAX_SECS.
+    timeout: Option<u64>,
+}
+
+/// `GET /api/v1/wait_for_update?since=...`: a long-poll for e-reader plugins
+/// and other simple clients that want to hear about new positions soon
+/// without hammering the list endpoint. Answers right away if any dogears
+/// moved after `since`; otherwise holds the request open until one does or
+/// the timeout runs out, then answers either way. Plain JSON, no streaming.
+#[tracing::instrument(skip_all)]
+pub async fn api_wait_for_update(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Query(query): Query<WaitQuery>,
+) -> ApiResult<Json<ApiWaitResult>> {
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    let user_id = auth.user().id;
+    let wait = std::time::Duration::from_secs(
+        query
+            .timeout
+            .unwrap_or(WAIT_DEFAULT_SECS)
+            .clamp(1, WAIT_MAX_SECS),
+    );
+    let deadline = tokio::time::Instant::now() + wait;
+    // Listen first, so a change between the check and the wait still counts.
+    let mut listener = state.db.changes().subscribe();
+    loop {
+        let (count, dogears) = state
+            .db
+            .dogears()
+            .updated_since(user_id, query.since, WAIT_RESULT_LIMIT)
+            .await?;
+        if count > 0 {
+            let next_since = dogears
+                .iter()
+                .map(|d| d.updated)
+                .max()
+                .unwrap_or(query.since);
+            return Ok(Json(ApiWaitResult {
+                changed: true,
+                next_since,
+                dogears,
+            }));
+        }
+        let woke = tokio::select! {
+            _ = listener.changed(user_id) => true,
+            _ = tokio::time::sleep_until(deadline) => false,
+            // Don't hold up shutdown; they'll just ask again.
+            _ = state.cancel_token.cancelled() => false,
+        };
+        if !woke {
+            return Ok(Json(ApiWaitResult {
+                changed: false,
+                next_since: query.since,
+                dogears: Vec::new(),
+            }));
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct QuickmarkQuery {
+    token: String,
+    url: String,
+    note: Option<String>,
+}
+
+/// `GET /api/v1/quickmark?token=...&url=...`: mark your spot with a plain
+/// GET, for iOS Shortcuts and e-readers that can't do anything fancier.
+/// Same results as `/api/v1/update`. Since the token's right there in the
+/// URL, only quickmark tokens work here (and they don't work anywhere
+/// else), and each token gets a limited number of requests.
+#[tracing::instrument(skip_all)]
+pub async fn api_quickmark(
+    State(state): State<DogState>,
+    req_headers: HeaderMap,
+    Query(query): Query<QuickmarkQuery>,
+) -> ApiResult<(HeaderMap, Json<Vec<Dogear>>)> {
+    // Keep the URL away from caches and Referer headers.
+    let mut res_headers = HeaderMap::new();
+    res_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    res_headers.insert(
+        header::REFERRER_POLICY,
+        HeaderValue::from_static("no-referrer"),
+    );
+
+    // Key the limiter by hash, so we're not holding onto cleartext tokens.
+    let limiter_key = sha256sum(&query.token);
+    if !state.config.rate_limit_allowlist.exempts(&req_headers) {
+        if state.quickmark_limiter.is_limited(&limiter_key) {
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many quickmarks with that token. Try again later.".to_string(),
+            ));
+        }
+        state.quickmark_limiter.record(&limiter_key);
+    }
+
+    let Some((token, user)) = state.db.tokens().authenticate(&query.token).await? else {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "That quickmark token isn't valid. Make a new quickmark URL on the install page."
+                .to_string(),
+        ));
+    };
+    if token.scope() != TokenScope::Quickmark {
+        warn!(target: "audit", user = %user.username, token_id = token.id, "quickmark: wrong kind of token");
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "Only quickmark tokens work in a URL. Make a quickmark URL on the install page, and revoke this token if it's been out in the open.".to_string(),
+        ));
+    }
+
+    let note = clean_note(query.note.as_deref())?;
+    match state.db.dogears().update(user.id, &query.url, note).await? {
+        Some(ds) => Ok((
+            res_headers,
+            Json(ds.into_iter().map(|u| u.dogear).collect()),
+        )),
         None => Err(UserError::Dogear404.into()),
     }
 }
+
+/// Where a dogear's webhook lives, given its secret.
+fn hook_url(public_url: &Url, secret: &str) -> String {
+    own_url(public_url, &format!("/hooks/{}", secret))
+}
+
+/// `POST /api/v1/dogear/:id/hook`: give a dogear an inbound webhook, or a
+/// fresh URL for the one it has. The URL's only available this once.
+#[tracing::instrument(skip_all)]
+pub async fn api_create_hook(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<(StatusCode, Json<ApiHook>)> {
+    // Requires manage
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    let Some(secret) = state.db.hooks().create(id, auth.user().id).await? else {
+        return Err(UserError::Dogear404.into());
+    };
+    info!(target: "audit", user = %auth.user().username, dogear_id = id, "hook: created");
+    Ok((
+        StatusCode::CREATED,
+        Json(ApiHook {
+            dogear_id: id,
+            url: hook_url(&state.config.public_url, &secret),
+        }),
+    ))
+}
+
+/// `DELETE /api/v1/dogear/:id/hook`: turn off a dogear's webhook.
+#[tracing::instrument(skip_all)]
+pub async fn api_delete_hook(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<StatusCode> {
+    // Requires manage
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    if state.db.hooks().destroy(id, auth.user().id).await? {
+        info!(target: "audit", user = %auth.user().username, dogear_id = id, "hook: deleted");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "That dogear doesn't have a webhook.".to_string(),
+        ))
+    }
+}
+
+/// `PUT /api/v1/dogear/:id/feed`: have the feed poller watch an RSS/Atom
+/// feed for a dogear's new chapters, or change the feed it's watching.
+/// Only if the site has feed polling turned on.
+#[tracing::instrument(skip_all)]
+pub async fn api_set_feed(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+    Json(payload): Json<ApiFeedPayload>,
+) -> ApiResult<Json<DogearFeed>> {
+    // Requires manage
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    if !state.config.feed_polling {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "This site doesn't watch feeds.".to_string(),
+        ));
+    }
+    let feed_url = validate_feed_url(&payload.feed_url, state.config.production)
+        .await
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let Some(feed) = state
+        .db
+        .feeds()
+        .set(id, auth.user().id, feed_url.as_str(), payload.auto_advance)
+        .await?
+    else {
+        return Err(UserError::Dogear404.into());
+    };
+    info!(target: "audit", user = %auth.user().username, dogear_id = id, "feed: set");
+    state.db.changes().notify(auth.user().id);
+    Ok(Json(feed))
+}
+
+/// `DELETE /api/v1/dogear/:id/feed`: stop watching a dogear's feed.
+#[tracing::instrument(skip_all)]
+pub async fn api_clear_feed(
+    State(state): State<DogState>,
+    auth: AuthAny,
+    Path(id): Path<i64>,
+) -> ApiResult<StatusCode> {
+    // Requires manage
+    auth.allowed_scopes(&[TokenScope::ManageDogears])?;
+    if state.db.feeds().clear(id, auth.user().id).await? {
+        info!(target: "audit", user = %auth.user().username, dogear_id = id, "feed: cleared");
+        state.db.changes().notify(auth.user().id);
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "That dogear doesn't have a feed.".to_string(),
+        ))
+    }
+}
+
+/// `POST /hooks/:secret`: a site (or a feed service) telling us there's a
+/// new chapter. The secret in the URL is the whole auth, and it only ever
+/// moves the one dogear it was made for, only to a URL that matches its
+/// prefix, and never while it's paused. Each hook gets a limited number of
+/// calls, same as quickmark tokens.
+#[tracing::instrument(skip_all)]
+pub async fn hook_receiver(
+    State(state): State<DogState>,
+    req_headers: HeaderMap,
+    Path(secret): Path<String>,
+    Json(payload): Json<ApiHookPayload>,
+) -> ApiResult<Json<Dogear>> {
+    // Key the limiter by hash, so we're not holding onto cleartext secrets.
+    let limiter_key = sha256sum(&secret);
+    if !state.config.rate_limit_allowlist.exempts(&req_headers) {
+        if state.hook_limiter.is_limited(&limiter_key) {
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many calls to this webhook. Try again later.".to_string(),
+            ));
+        }
+        state.hook_limiter.record(&limiter_key);
+    }
+
+    let Some((dogear_id, user_id)) = state.db.hooks().authenticate(&secret).await? else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "That webhook URL isn't valid. It might have been replaced or turned off.".to_string(),
+        ));
+    };
+    let note = clean_note(payload.note.as_deref())?;
+    let dogears = state.db.dogears();
+    if let Some(dogear) = dogears
+        .update_one(dogear_id, user_id, &payload.url, note)
+        .await?
+    {
+        info!(dogear_id, "hook: marked");
+        return Ok(Json(dogear));
+    }
+    // Didn't move; sort out why.
+    let Some(dogear) = dogears.by_id(dogear_id, user_id).await? else {
+        return Err(UserError::Dogear404.into());
+    };
+    if dogear.paused {
+        Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "That dogear is paused, so it's not taking updates.".to_string(),
+        ))
+    } else {
+        Err(UserError::DogearNonMatching {
+            url: payload.url,
+            prefix: dogear.prefix,
+        }
+        .into())
+    }
+}
@@ -1,12 +1,24 @@
+use http::{header, HeaderMap};
 use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 use tower_cookies::Key;
+use tracing::{error, info};
 
-use crate::config::DogConfig;
-use crate::db::Db;
-use crate::util::make_bookmarklet;
+pub use super::index_cache::{IndexCache, IndexData};
+pub use super::metrics::Metrics;
+use crate::cadence::CadenceCache;
+use crate::config::{DogConfig, LiveConfig, MailConfig};
+use crate::db::{AuditKind, AuditSource, Db, Sighting, User};
+use crate::util::{
+    clean_email, client_ip, device_hash, features_for, live_rollouts, make_bookmarklet, send_mail,
+    sign_action_link, site_host, suggest_prefix, validate_new_password, Email, FailureCounts,
+    JobClock, MxChecker, NewPasswordError, PwnedChecker, RateLimiter, RedirectResolver,
+    SignupGuard, SignupRejection, UserError, USERNAME_MAX_LENGTH,
+};
 
 pub type DogState = Arc<DSInner>;
 
@@ -15,13 +27,129 @@ pub type DogState = Arc<DSInner>;
 pub struct DSInner {
     pub db: Db,
     pub config: DogConfig,
+    /// The parts of the config that can change without a restart.
+    pub live: LiveConfig,
     pub templates: minijinja::Environment<'static>,
     pub cookie_key: Key,
     pub task_tracker: TaskTracker,
     pub cancel_token: CancellationToken,
+    /// Failed password and two-factor attempts (API Basic auth, token
+    /// exchange, and web logins), by username.
+    pub basic_auth_limiter: RateLimiter,
+    /// Quickmark requests, by token hash.
+    pub quickmark_limiter: RateLimiter,
+    /// Inbound webhook calls, by secret hash.
+    pub hook_limiter: RateLimiter,
+    /// Tokens handed out by the token exchange, by username.
+    pub token_exchange_limiter: RateLimiter,
+    /// Failed Bearer token logins, by client IP.
+    pub bad_token_limiter: RateLimiter,
+    /// Running totals of the same, for the admin report.
+    pub bad_token_counts: FailureCounts,
+    /// When each background worker last finished a pass, for the status
+    /// report.
+    pub job_clock: JobClock,
+    /// Request counts and such, for /metrics.
+    pub metrics: Metrics,
+    /// For following redirects on create, if the config says to.
+    pub redirect_resolver: RedirectResolver,
+    /// For the optional breach check on new passwords.
+    pub pwned_checker: PwnedChecker,
+    /// For the optional check that new email addresses' domains take mail.
+    pub mx_checker: MxChecker,
+    /// The signup form's bot checks.
+    pub signup_guard: SignupGuard,
+    /// The index page's dogear list cache, if the config turns it on.
+    pub index_cache: Option<IndexCache>,
+    /// Update schedule guesses, by user.
+    pub cadences: CadenceCache,
+}
+
+/// The standard limiter for failed logins of every kind: five failed
+/// attempts per username per fifteen minutes.
+pub fn basic_auth_limiter() -> RateLimiter {
+    RateLimiter::new(5, Duration::from_secs(15 * 60))
+}
+
+/// The standard limiter for quickmarks: thirty per token per ten minutes,
+/// which is way more than anyone reads but not enough to be a toy.
+pub fn quickmark_limiter() -> RateLimiter {
+    RateLimiter::new(30, Duration::from_secs(10 * 60))
+}
+
+/// The standard limiter for inbound webhooks: twelve calls per hook per
+/// hour. Even a daily comic only needs one, but feed services like to
+/// retry, and some fire once per item when a site posts a bunch at once.
+pub fn hook_limiter() -> RateLimiter {
+    RateLimiter::new(12, Duration::from_secs(60 * 60))
+}
+
+/// The standard limiter for the token exchange's successes (its failures
+/// count against the Basic auth limiter): ten new tokens per username per
+/// hour. An extension sets itself up once per browser, so anything past
+/// that is a script gone wrong, or someone who shouldn't have the password.
+pub fn token_exchange_limiter() -> RateLimiter {
+    RateLimiter::new(10, Duration::from_secs(60 * 60))
+}
+
+/// The standard limiter for bad Bearer tokens: twenty per client IP per
+/// fifteen minutes. A real client with a revoked token gives up (or gets
+/// fixed) long before that; something guessing tokens doesn't.
+pub fn bad_token_limiter() -> RateLimiter {
+    RateLimiter::new(20, Duration::from_secs(15 * 60))
+}
+
+/// The parts of the app state that go ask other services things. The real
+/// server uses the real ones; tests hand in stubs.
+pub struct Checkers {
+    pub pwned_checker: PwnedChecker,
+    pub mx_checker: MxChecker,
+    pub signup_guard: SignupGuard,
 }
 
 impl DSInner {
+    /// Assemble the app state. Everything that isn't passed in gets its
+    /// standard setup: fresh limiters and counters, plus the redirect
+    /// resolver, index cache, and cadence cache the config and db call for.
+    pub fn new(
+        db: Db,
+        config: DogConfig,
+        templates: minijinja::Environment<'static>,
+        cookie_key: Key,
+        task_tracker: TaskTracker,
+        cancel_token: CancellationToken,
+        checkers: Checkers,
+    ) -> reqwest::Result<Self> {
+        let Checkers {
+            pwned_checker,
+            mx_checker,
+            signup_guard,
+        } = checkers;
+        Ok(Self {
+            live: LiveConfig::new(&config),
+            redirect_resolver: RedirectResolver::new(config.production)?,
+            index_cache: config.index_cache.as_ref().map(|c| IndexCache::new(c, &db)),
+            cadences: CadenceCache::new(&db),
+            db,
+            config,
+            templates,
+            cookie_key,
+            task_tracker,
+            cancel_token,
+            basic_auth_limiter: basic_auth_limiter(),
+            quickmark_limiter: quickmark_limiter(),
+            hook_limiter: hook_limiter(),
+            token_exchange_limiter: token_exchange_limiter(),
+            bad_token_limiter: bad_token_limiter(),
+            bad_token_counts: FailureCounts::default(),
+            job_clock: JobClock::default(),
+            metrics: Metrics::default(),
+            pwned_checker,
+            mx_checker,
+            signup_guard,
+        })
+    }
+
     #[tracing::instrument(skip(self, ctx))]
     pub fn render_view<S: Serialize + std::fmt::Debug>(
         &self,
@@ -31,19 +159,237 @@ impl DSInner {
         self.templates.get_template(name)?.render(ctx)
     }
 
-    /// Render a bookmarklet template into a `javascript:` URL.
+    /// Render a bookmarklet template into a `javascript:` URL. `prompt_note`
+    /// only matters to the mark bookmarklet.
     #[tracing::instrument(skip(self, token))]
     pub fn render_bookmarklet(
         &self,
         name: &str,
         token: Option<&str>,
+        prompt_note: bool,
     ) -> Result<String, minijinja::Error> {
         let ctx = minijinja::context! {
             own_origin => &self.config.public_url.origin().ascii_serialization(),
             token => token,
+            prompt_note => prompt_note,
         };
         Ok(make_bookmarklet(
             &self.templates.get_template(name)?.render(ctx)?,
         ))
     }
+
+    /// The (prefix, current) to save for a new dogear. That's just what the
+    /// user sent, unless `resolve_redirects` is on and the current URL
+    /// redirects somewhere else on the same site.
+    pub async fn canonical_new_dogear(&self, prefix: &str, current: &str) -> (String, String) {
+        if self.config.resolve_redirects {
+            self.redirect_resolver.canonicalize(prefix, current).await
+        } else {
+            (prefix.to_string(), current.to_string())
+        }
+    }
+
+    /// Check a new password (and its confirmation) against the configured
+    /// policy, including the breach list if that's on.
+    pub async fn check_new_password(
+        &self,
+        new1: &str,
+        new2: &str,
+        username: &str,
+    ) -> Result<(), NewPasswordError> {
+        validate_new_password(
+            new1,
+            new2,
+            username,
+            &self.config.passwords,
+            &self.pwned_checker,
+        )
+        .await
+    }
+
+    /// Clean up a new email address (blank means none), and if the mail
+    /// config says to, make sure its domain takes mail.
+    pub async fn check_new_email(&self, email: Option<&str>) -> Result<Option<String>, UserError> {
+        let Some(email) = clean_email(email)? else {
+            return Ok(None);
+        };
+        let check_mx = self.config.mail.as_ref().is_some_and(|m| m.check_mx);
+        if check_mx && !self.mx_checker.accepts_mail(&email).await {
+            let domain = email.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+            return Err(UserError::EmailUndeliverable {
+                domain: domain.to_string(),
+                email,
+            });
+        }
+        Ok(Some(email))
+    }
+
+    /// Every feature flag's live rollout percentage: the config file's,
+    /// unless an operator has overridden it. Skips the db if there aren't
+    /// any flags.
+    pub async fn flag_rollouts(&self) -> sqlx::Result<BTreeMap<String, u8>> {
+        let configured = self.live.features();
+        if configured.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        let overrides = self.db.flag_overrides().list().await?;
+        Ok(live_rollouts(
+            &configured,
+            overrides
+                .iter()
+                .map(|o| (o.name.as_str(), o.rollout.clamp(0, 100) as u8)),
+        ))
+    }
+
+    /// The feature flags that are on for a user.
+    pub async fn features_for(&self, user_id: i64) -> sqlx::Result<BTreeSet<String>> {
+        Ok(features_for(&self.flag_rollouts().await?, user_id))
+    }
+
+    /// Our best guess at a prefix for a new dogear on this page, going by
+    /// the site rules if there's one for the host, or the URL's shape if
+    /// not. None if it's not a web URL.
+    pub async fn suggest_prefix(&self, url: &str) -> sqlx::Result<Option<String>> {
+        let rule = match site_host(url) {
+            Some(host) => self.db.site_rules().for_host(&host).await?,
+            None => return Ok(None),
+        };
+        let segments = rule.map(|r| r.segments.max(0) as usize);
+        Ok(suggest_prefix(url, segments))
+    }
+
+    /// Remember the device behind a successful login or signup, and if it's
+    /// a new one for an existing account, log an audit event and (if they
+    /// asked for it) email the user about it. Never fails the login; any
+    /// problems just get logged.
+    #[tracing::instrument(skip_all)]
+    pub async fn check_login_device(&self, user: &User, headers: &HeaderMap) {
+        let sighting = match self
+            .db
+            .devices()
+            .record(user.id, &device_hash(headers))
+            .await
+        {
+            Ok(sighting) => sighting,
+            Err(e) => {
+                error!("couldn't record login device for {}: {}", &user.username, e);
+                return;
+            }
+        };
+        if sighting != Sighting::New {
+            return;
+        }
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("(unknown)");
+        let ip = client_ip(headers).unwrap_or("(unknown)");
+        info!(target: "audit", username = %user.username, %ip, %user_agent, "login from a new device");
+
+        let (Some(mail), Some(address)) = (&self.config.mail, &user.email) else {
+            return;
+        };
+        match self.db.prefs().get(user.id).await {
+            Ok(prefs) if prefs.notify_new_device => (),
+            Ok(_) => return,
+            Err(e) => {
+                error!("couldn't check prefs for {}: {}", &user.username, e);
+                return;
+            }
+        }
+        let email = Email {
+            to: address.clone(),
+            subject: "New login to your Eardogger account".to_string(),
+            body: format!(
+                "Hi {},\n\nSomeone just logged into your account at {} from a device we haven't seen before:\n\n  Browser: {}\n  IP address: {}\n\nIf that was you, you're all set. If not, change your password right away, and log out any sessions you don't recognize on your account page: {}account\n\n(You're getting this because you turned on login alerts. You can turn them off on your account page.)",
+                &user.username,
+                &self.config.public_url,
+                user_agent,
+                ip,
+                &self.config.public_url,
+            ),
+            attachment: None,
+        };
+        // Don't make the login wait on sendmail.
+        self.mail_later(mail, email);
+    }
+
+    /// Add an event to the user's audit log (the "recent activity" on their
+    /// account page). Like `check_login_device`, this never fails the
+    /// request; problems just get logged.
+    pub async fn audit(
+        &self,
+        user: &User,
+        kind: AuditKind,
+        detail: Option<&str>,
+        source: &AuditSource,
+    ) {
+        // An account that's being deleted is gone by the time we write
+        // this, so its last event doesn't hang onto the user ID.
+        let user_id = (kind != AuditKind::AccountDeleted).then_some(user.id);
+        if let Err(e) = self
+            .db
+            .audit()
+            .record(user_id, &user.username, kind, detail, source)
+            .await
+        {
+            error!(
+                "couldn't record {} audit event for {}: {}",
+                kind.as_str(),
+                &user.username,
+                e
+            );
+        }
+    }
+
+    /// Write down a signup the bot checks turned away, under whatever
+    /// username it asked for. Like `audit`, this never fails the request.
+    pub async fn audit_rejected_signup(
+        &self,
+        username: &str,
+        rejection: &SignupRejection,
+        source: &AuditSource,
+    ) {
+        // Bots send all kinds of junk; keep it to a username's worth.
+        let username: String = username.trim().chars().take(USERNAME_MAX_LENGTH).collect();
+        if let Err(e) = self
+            .db
+            .audit()
+            .record(
+                None,
+                &username,
+                AuditKind::SignupRejected,
+                Some(rejection.reason()),
+                source,
+            )
+            .await
+        {
+            error!("couldn't record a rejected signup for {}: {}", &username, e);
+        }
+    }
+
+    /// Send an email in the background, so the request that caused it
+    /// doesn't wait on sendmail. Failures just get logged.
+    pub fn mail_later(&self, mail: &MailConfig, email: Email) {
+        let mail = mail.clone();
+        self.task_tracker.spawn(async move {
+            if let Err(e) = send_mail(&mail, &email).await {
+                error!(
+                    "couldn't send \"{}\" to {}: {}",
+                    &email.subject, &email.to, e
+                );
+            }
+        });
+    }
+
+    /// The full URL for a signed email change link. `action` is "confirm"
+    /// or "revert".
+    pub fn email_link(&self, action: &str, change_id: i64) -> String {
+        format!(
+            "{}email/{}/{}",
+            &self.config.public_url,
+            action,
+            sign_action_link(&self.cookie_key, action, change_id)
+        )
+    }
 }
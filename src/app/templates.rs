@@ -1,11 +1,18 @@
 use crate::{
-    db::{Dogear, Session, Token, TokenScope, User},
-    util::{Pagination, SHORT_DATE},
+    cadence::Cadence,
+    db::{Dogear, DogearFeed, Grant, Hook, Session, Token, TokenScope, User, UserSummary, Webhook},
+    import::{ImportCandidate, ImportReport},
+    util::{url_encoding::encode_uri_component, ChallengeWidget, Pagination, SHORT_DATE},
 };
+use eardogger_rs::api_types::Freshness;
 use minijinja::{escape_formatter, Value};
 // ^^ always gonna qualify minijinja::Environment bc its name is confusing
 use serde::Serialize;
-use time::{format_description::well_known::Iso8601, OffsetDateTime};
+use std::collections::{BTreeSet, HashMap};
+use time::{format_description::well_known::Iso8601, serde::iso8601, OffsetDateTime};
+
+pub mod fixtures;
+pub mod forms;
 
 /// A template filter for turning an ISO8601 timestamp into a short date like 2024-03-22.
 /// If the timestamp can't parse or lacks date elements, we default to just displaying
@@ -18,15 +25,33 @@ fn short_date(date_str: &str) -> String {
         .unwrap_or_else(|_| date_str.to_string())
 }
 
+/// A template filter for turning an ISO8601 `updated` timestamp into its
+/// freshness bucket as of now (see `Freshness`), like "this_week". Blank if
+/// the timestamp can't parse.
+fn freshness(date_str: &str) -> &'static str {
+    match OffsetDateTime::parse(date_str, &Iso8601::DEFAULT) {
+        Ok(updated) => Freshness::of(updated, OffsetDateTime::now_utc()).as_str(),
+        Err(_) => "",
+    }
+}
+
 /// A template filter for translating token scopes to explanatory text.
 fn explain_scope(scope_str: &str) -> &'static str {
     match TokenScope::from(scope_str) {
         TokenScope::WriteDogears => "Can mark your spot.",
         TokenScope::ManageDogears => "Can view, update, and delete dogears.",
+        TokenScope::Quickmark => "Can mark your spot via a quickmark URL.",
+        TokenScope::Kosync => "Can sync reading progress from KOReader.",
         TokenScope::Invalid => "Cannot be used.",
     }
 }
 
+/// A template filter for building URLs with user-provided bits in them, like
+/// `/?view={{ name | encode_uri_component }}`.
+fn encode_uri_component_filter(component: &str) -> String {
+    encode_uri_component(component).to_string()
+}
+
 /// A replacement for minijinja's built-in `default` filter, which will
 /// replace an undefined value but doesn't usefully handle None values.
 /// This filter handles both kinds of nothing.
@@ -56,6 +81,82 @@ pub struct Common<'a> {
     pub title: &'a str,
     pub user: Option<&'a User>,
     pub csrf_token: &'a str,
+    /// The logged-in user's custom CSS, if any. Already vetted by
+    /// `clean_custom_css`, so the layout drops it in unescaped.
+    pub custom_css: Option<&'a str>,
+    /// Feature flags that are on for the logged-in user (see
+    /// `util::feature_flags`). Logged-out pages don't get any. Check with
+    /// `{% if "queue_mode" in common.features %}`.
+    pub features: &'a BTreeSet<String>,
+    /// The pages above this one, outermost first (not counting the home
+    /// page, which is always there). The layout shows them as a breadcrumb
+    /// trail and works them into the <title>, so history entries for deep
+    /// pages say where they were.
+    pub breadcrumbs: &'a [Crumb<'a>],
+    /// Which page of a paginated list this is, if it's past the first.
+    /// Also goes in the <title>.
+    pub page: Option<u32>,
+    /// The absolute URL this page should be known by, minus any query
+    /// params that only change how it looks. None for pages that are
+    /// really the result of an action, like the create and marked pages.
+    pub canonical_url: Option<String>,
+    /// The logged-in user's login before this session's, which the layout
+    /// shows in the header so a stranger's login stands out.
+    pub last_login: Option<LastLogin<'a>>,
+    /// This browser's color scheme choice. The layout puts it on the root
+    /// element as a class, so pages come out in the right colors on the
+    /// first paint instead of flashing the wrong ones.
+    pub theme: Theme,
+}
+
+/// Which colors to draw pages in. Set per browser (not per account) with
+/// the `eardogger.theme` cookie; `Auto` goes by the device's dark mode
+/// setting, same as before there was a choice.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    #[default]
+    Auto,
+    Light,
+    Dark,
+}
+
+impl Theme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Theme::Auto => "auto",
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        }
+    }
+
+    /// The theme named by a cookie or form value, or None if it's junk.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "auto" => Some(Theme::Auto),
+            "light" => Some(Theme::Light),
+            "dark" => Some(Theme::Dark),
+            _ => None,
+        }
+    }
+}
+
+/// When and from what browser someone last logged in (not counting the
+/// login that started the current session).
+#[derive(Serialize)]
+pub struct LastLogin<'a> {
+    #[serde(with = "iso8601")]
+    pub when: OffsetDateTime,
+    pub user_agent: Option<&'a str>,
+}
+
+impl<'a> LastLogin<'a> {
+    pub fn from_session(session: &'a Session) -> Option<Self> {
+        Some(Self {
+            when: session.previous_login?,
+            user_agent: session.previous_login_user_agent.as_deref(),
+        })
+    }
 }
 
 impl<'a> Common<'a> {
@@ -67,20 +168,279 @@ impl<'a> Common<'a> {
             title,
             user: None,
             csrf_token: "invalid",
+            custom_css: None,
+            features: &NO_FEATURES,
+            breadcrumbs: &[],
+            page: None,
+            canonical_url: None,
+            last_login: None,
+            theme: Theme::Auto,
         }
     }
 }
 
+/// Stands in for `Common::features` on pages with nobody logged in.
+pub static NO_FEATURES: BTreeSet<String> = BTreeSet::new();
+
+/// One link in a breadcrumb trail.
+#[derive(Serialize, Debug)]
+pub struct Crumb<'a> {
+    pub label: &'a str,
+    pub href: &'a str,
+}
+
+/// The trail for pages that hang off the account page.
+pub const ACCOUNT_CRUMBS: &[Crumb<'static>] = &[Crumb {
+    label: "Manage account",
+    href: "/account",
+}];
+
 #[derive(Serialize)]
 pub struct TokensList<'a> {
     pub tokens: &'a [Token],
     pub pagination: Pagination,
 }
 
+/// The JSON flavor of a list fragment, for when the front-end JS would
+/// rather render things itself: the same data the template gets, plus the
+/// prev/next links the pagination macro would have built.
+#[derive(Serialize)]
+pub struct FragmentJson<T: Serialize> {
+    #[serde(flatten)]
+    pub list: T,
+    pub pagination_links: PaginationLinks,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PaginationLinks {
+    pub prev: Option<PageLink>,
+    pub next: Option<PageLink>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PageLink {
+    pub page: u32,
+    /// The full-page URL, for the address bar.
+    pub url: String,
+    /// The fragment URL, for fetching the page in place.
+    pub fragment_url: String,
+}
+
+impl PaginationLinks {
+    /// Mirrors macro.pagination.html.j2. As there, `extra_query` goes in
+    /// front of the page param, so it should end with "&" if present.
+    pub fn new(pagination: &Pagination, url: &str, fragment_url: &str, extra_query: &str) -> Self {
+        let size = pagination
+            .page_size
+            .map(|s| format!("&size={}", s))
+            .unwrap_or_default();
+        let link = |page: u32| PageLink {
+            page,
+            url: format!("{}?{}page={}{}", url, extra_query, page, &size),
+            fragment_url: format!("{}?{}page={}{}", fragment_url, extra_query, page, &size),
+        };
+        Self {
+            prev: pagination.prev_page.map(link),
+            next: pagination.next_page.map(link),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct DogearsList<'a> {
     pub dogears: &'a [Dogear],
+    /// Notes from the last time each dogear was marked, by dogear ID.
+    pub notes: &'a HashMap<i64, String>,
+    /// Tags, by dogear ID.
+    pub tags: &'a HashMap<i64, Vec<String>>,
+    /// Update schedule guesses, by dogear ID. Empty for shared lists.
+    pub cadences: &'a HashMap<i64, CadenceNote>,
+    /// Watched feeds, by dogear ID. Empty for shared lists and the archive.
+    pub feeds: &'a HashMap<i64, FeedNote>,
+    /// Whether the site watches feeds, so whether to offer to.
+    pub feed_polling: bool,
     pub pagination: Pagination,
+    /// If we're looking at someone else's dogears via a sharing grant, this
+    /// is their username. Shared lists are read-only.
+    pub shared_from: Option<&'a str>,
+    /// The search the list is narrowed down to, if any.
+    pub filter: Option<&'a str>,
+    pub display: ListDisplay,
+    /// The archive nudge, for your own main list if anything's gone stale.
+    pub stale: Option<&'a StaleNudge>,
+    /// Whether this is the archive instead of the main list.
+    pub archived: bool,
+}
+
+/// Dogears that haven't moved in a while, so the list can suggest
+/// archiving them.
+#[derive(Serialize, Debug)]
+pub struct StaleNudge {
+    /// The user's threshold.
+    pub months: u32,
+    /// Their stale dogears' IDs, stalest first.
+    pub ids: Vec<i64>,
+}
+
+/// How to draw the dogears list: the user's saved prefs, plus any
+/// overrides from the query string.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ListDisplay {
+    pub compact: bool,
+    pub show_prefix: bool,
+    pub show_dates: bool,
+    /// The overrides, as query params for the pagination links to carry
+    /// along. Like `extra_query` there, ends with "&" if present.
+    pub query: String,
+}
+
+/// The custom CSS editor. `css` is whatever goes in the text box: the saved
+/// snippet, or the one being previewed.
+#[derive(Serialize)]
+pub struct ImportPage<'a> {
+    /// How the import you just did went, if you just did one.
+    pub report: Option<&'a ImportReport>,
+    /// Which source it was from.
+    pub source_name: Option<&'a str>,
+    /// What would get made, if you asked for a preview instead. Capped at
+    /// PREVIEW_MAX_CANDIDATES; `candidates` is the real total.
+    pub preview: Option<&'a [ImportCandidate]>,
+    pub candidates: usize,
+    /// What to fill the form back in with, so a preview can go straight
+    /// on to the real thing.
+    pub source: &'a str,
+    pub trim: usize,
+    pub data: &'a str,
+    pub max_trim: usize,
+    /// A fresh migration code, if you just asked for one. Only shown once.
+    pub migration_code: Option<&'a str>,
+    pub migration_minutes: i64,
+    /// Where the other instance should send the migration.
+    pub instance_url: &'a str,
+}
+
+#[derive(Serialize)]
+pub struct CustomCssPage<'a> {
+    pub css: Option<&'a str>,
+    pub previewing: bool,
+}
+
+/// Someone's public profile page, which anyone can see. Only ever gets the
+/// dogears they've marked public.
+#[derive(Serialize)]
+pub struct PublicProfile<'a> {
+    pub username: &'a str,
+    pub dogears: &'a [Dogear],
+    /// Link preview stuff, if the crawler config has seo turned on.
+    pub meta: Option<ProfileMeta>,
+}
+
+#[derive(Serialize)]
+pub struct ProfileMeta {
+    /// The profile's full public URL.
+    pub url: String,
+    pub description: String,
+}
+
+/// A dogear's update schedule guess, as the dogears list shows it.
+#[derive(Serialize, Debug)]
+pub struct CadenceNote {
+    /// Like "~5 days".
+    pub every: String,
+    pub probably_new: bool,
+}
+
+impl CadenceNote {
+    pub fn new(cadence: &Cadence, now: OffsetDateTime) -> Self {
+        Self {
+            every: cadence.describe(),
+            probably_new: cadence.probably_new(now),
+        }
+    }
+}
+
+/// A dogear's watched feed, as the dogears list shows it.
+#[derive(Serialize, Debug)]
+pub struct FeedNote {
+    pub feed_url: String,
+    pub auto_advance: bool,
+    /// A chapter the feed turned up that you haven't gotten to, if it
+    /// turned up since the dogear last moved.
+    pub new_chapter: Option<String>,
+    /// The last error, if the last few checks in a row didn't work.
+    pub failing: Option<String>,
+}
+
+/// How many checks in a row have to fail before the list says so. One-offs
+/// are just the internet.
+const FEED_FAILING_AFTER: i64 = 3;
+
+impl FeedNote {
+    pub fn new(feed: &DogearFeed, dogear: &Dogear) -> Self {
+        let new_chapter = match feed.new_chapter_found {
+            Some(found) if found > dogear.updated => feed.new_chapter.clone(),
+            _ => None,
+        };
+        let failing = (feed.failures >= FEED_FAILING_AFTER)
+            .then(|| feed.last_error.clone().unwrap_or_default());
+        Self {
+            feed_url: feed.feed_url.clone(),
+            auto_advance: feed.auto_advance,
+            new_chapter,
+            failing,
+        }
+    }
+}
+
+/// One dogear on the "probably has new chapters" page.
+#[derive(Serialize, Debug)]
+pub struct NewChapter {
+    pub dogear: Dogear,
+    pub cadence: CadenceNote,
+}
+
+/// The "probably has new chapters" page.
+#[derive(Serialize)]
+pub struct NewChapters<'a> {
+    /// Longest-overdue first.
+    pub entries: &'a [NewChapter],
+}
+
+/// The "what's new since you were last here" banner on the front page.
+#[derive(Serialize)]
+pub struct WhatsNew<'a> {
+    /// How many dogears moved in total.
+    pub count: u32,
+    /// The most recent few of them.
+    pub dogears: &'a [Dogear],
+}
+
+#[derive(Serialize)]
+pub struct GrantsList<'a> {
+    pub grants: &'a [Grant],
+}
+
+#[derive(Serialize)]
+pub struct HooksList<'a> {
+    pub hooks: &'a [Hook],
+}
+
+#[derive(Serialize)]
+pub struct WebhooksList<'a> {
+    pub webhooks: &'a [Webhook],
+}
+
+/// The account page's two-factor section. Everything's empty if they've
+/// never set it up.
+#[derive(Serialize, Default)]
+pub struct TotpSettings {
+    #[serde(with = "iso8601::option")]
+    pub enabled_since: Option<OffsetDateTime>,
+    pub recovery_codes_left: u32,
+    /// While they're setting it up: the secret to type into their app, and
+    /// the link that does it for them.
+    pub pending_secret: Option<String>,
+    pub provisioning_uri: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -88,6 +448,18 @@ pub struct SessionsList<'a> {
     pub current_session_id: i64,
     pub sessions: &'a [Session],
     pub pagination: Pagination,
+    /// Whether this is on its own page (/account/sessions) instead of the
+    /// account page, which changes where the page links go.
+    pub standalone: bool,
+}
+
+/// The accounts on the admin users page.
+#[derive(Serialize)]
+pub struct AdminUsersList<'a> {
+    pub users: &'a [UserSummary],
+    pub pagination: Pagination,
+    /// The admin who's looking, who doesn't get a button to disable themself.
+    pub current_user_id: i64,
 }
 
 #[derive(Serialize)]
@@ -95,27 +467,140 @@ pub struct PersonalMark<'a> {
     pub bookmarklet_url: &'a str,
 }
 
+#[derive(Serialize)]
+pub struct BookmarkletsList<'a> {
+    pub tokens: &'a [Token],
+    pub csrf_token: &'a str,
+    /// A bookmarklet that just got regenerated, to show in its list entry.
+    pub fresh: Option<FreshBookmarklet<'a>>,
+}
+
+#[derive(Serialize)]
+pub struct FreshBookmarklet<'a> {
+    pub token_id: i64,
+    pub url: &'a str,
+}
+
+#[derive(Serialize)]
+pub struct Quickmark<'a> {
+    /// Everything but the URL to mark, which goes on the end.
+    pub quickmark_url: &'a str,
+}
+
+/// A token someone just made on the account page, for showing the
+/// cleartext the one time it's available.
+#[derive(Serialize)]
+pub struct NewToken<'a> {
+    pub cleartext: &'a str,
+    /// As text, like Token's.
+    pub scope: &'a str,
+    pub comment: &'a str,
+    #[serde(with = "iso8601::option")]
+    pub expires: Option<OffsetDateTime>,
+}
+
+/// Everything you type into KOReader's sync settings.
+#[derive(Serialize)]
+pub struct KosyncLogin<'a> {
+    pub server_url: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+/// Where email change links go. `address` is the one the link would
+/// switch the account to.
+#[derive(Serialize)]
+pub struct EmailLinkPage<'a> {
+    pub action: &'a str,
+    pub token: &'a str,
+    pub username: &'a str,
+    pub address: Option<&'a str>,
+    pub done: bool,
+}
+
+/// Where a KOReader book's dogear points.
+#[derive(Serialize)]
+pub struct KosyncBookPage<'a> {
+    pub display_name: Option<&'a str>,
+    pub percent: u8,
+    pub device: &'a str,
+    #[serde(with = "iso8601")]
+    pub updated: OffsetDateTime,
+}
+
 #[derive(Serialize)]
 pub struct InstallPage<'a> {
     pub where_was_i_bookmarklet_url: &'a str,
 }
 
+/// The page right after signup, when `signup.welcome` is on.
+#[derive(Serialize)]
+pub struct WelcomePage<'a> {
+    /// The new account's starter bookmarklet. This is the only time anyone
+    /// sees its token.
+    pub bookmarklet_url: &'a str,
+    /// The example dogear we seeded their list with.
+    pub example: &'a Dogear,
+}
+
 #[derive(Serialize)]
 pub struct MarkedPage<'a> {
-    pub updated_dogears: &'a [Dogear],
+    pub updated_dogears: &'a [MarkedDogear<'a>],
+    // Matched, but didn't move.
+    pub paused_dogears: &'a [&'a Dogear],
     pub bookmarked_url: &'a str,
     pub slowmode: bool,
 }
 
+/// A freshly updated dogear, plus how many chapters it moved (if the URLs
+/// make that guessable).
+#[derive(Serialize)]
+pub struct MarkedDogear<'a> {
+    pub dogear: &'a Dogear,
+    pub chapter_delta: Option<i64>,
+}
+
 #[derive(Serialize)]
 pub struct CreatePage<'a> {
-    pub bookmarked_url: &'a str,
+    /// Existing dogears on the same site, in case one of them is what you
+    /// meant and the site just moved its URLs around.
+    pub near_misses: &'a [Dogear],
+    /// See `forms::create_form`.
+    pub form: forms::Form<'a>,
 }
 
 #[derive(Serialize)]
 pub struct LoginPage<'a> {
     pub return_to: &'a str,
-    pub previously_failed: bool,
+    /// Other instances this browser can say its account lives on.
+    pub peers: Vec<&'a str>,
+    /// The one it already said, if any.
+    pub home_instance: Option<&'a str>,
+    pub login_form: forms::Form<'a>,
+    pub signup_form: forms::Form<'a>,
+    /// The signup challenge to show, if the config asks for one.
+    pub challenge: Option<ChallengeWidget<'a>>,
+}
+
+/// A problem with one field of a submitted form, for when we send the form
+/// back instead of an error page. `field` is the field's id. Templates show
+/// the message in an alert region, and mark the field with aria-invalid
+/// (pointing back at the message) and autofocus, so screen reader users
+/// land right on it. See `macro.form_errors.html.j2`, and `forms` for the
+/// forms that use it.
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, message: impl ToString) -> Self {
+        Self {
+            field,
+            message: message.to_string(),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -144,19 +629,63 @@ pub fn load_templates() -> anyhow::Result<minijinja::Environment<'static>> {
         "account.html.j2",
         include_str!("../../templates/account.html.j2"),
     )?;
+    env.add_template(
+        "admin_users.html.j2",
+        include_str!("../../templates/admin_users.html.j2"),
+    )?;
+    env.add_template(
+        "archived.html.j2",
+        include_str!("../../templates/archived.html.j2"),
+    )?;
+    env.add_template(
+        "account_sessions.html.j2",
+        include_str!("../../templates/account_sessions.html.j2"),
+    )?;
+    env.add_template(
+        "bookmarklets.html.j2",
+        include_str!("../../templates/bookmarklets.html.j2"),
+    )?;
     env.add_template(
         "create.html.j2",
         include_str!("../../templates/create.html.j2"),
     )?;
+    env.add_template(
+        "custom_css.html.j2",
+        include_str!("../../templates/custom_css.html.j2"),
+    )?;
+    env.add_template(
+        "email_link.html.j2",
+        include_str!("../../templates/email_link.html.j2"),
+    )?;
     env.add_template(
         "error.html.j2",
         include_str!("../../templates/error.html.j2"),
     )?;
     env.add_template("faq.html.j2", include_str!("../../templates/faq.html.j2"))?;
+    env.add_template(
+        "fragment.bookmarklets.html.j2",
+        include_str!("../../templates/fragment.bookmarklets.html.j2"),
+    )?;
     env.add_template(
         "fragment.dogears.html.j2",
         include_str!("../../templates/fragment.dogears.html.j2"),
     )?;
+    env.add_template(
+        "fragment.grants.html.j2",
+        include_str!("../../templates/fragment.grants.html.j2"),
+    )?;
+    env.add_template(
+        "fragment.hooks.html.j2",
+        include_str!("../../templates/fragment.hooks.html.j2"),
+    )?;
+    env.add_template(
+        "fragment.webhooks.html.j2",
+        include_str!("../../templates/fragment.webhooks.html.j2"),
+    )?;
+    env.add_template(
+        "fragment.new_token.html.j2",
+        include_str!("../../templates/fragment.new_token.html.j2"),
+    )?;
     env.add_template(
         "fragment.tokens.html.j2",
         include_str!("../../templates/fragment.tokens.html.j2"),
@@ -181,10 +710,26 @@ pub fn load_templates() -> anyhow::Result<minijinja::Environment<'static>> {
         "login.html.j2",
         include_str!("../../templates/login.html.j2"),
     )?;
+    env.add_template(
+        "change_password.html.j2",
+        include_str!("../../templates/change_password.html.j2"),
+    )?;
+    env.add_template(
+        "login_totp.html.j2",
+        include_str!("../../templates/login_totp.html.j2"),
+    )?;
     env.add_template(
         "macro.bookmarklet.html.j2",
         include_str!("../../templates/macro.bookmarklet.html.j2"),
     )?;
+    env.add_template(
+        "macro.form.html.j2",
+        include_str!("../../templates/macro.form.html.j2"),
+    )?;
+    env.add_template(
+        "macro.form_errors.html.j2",
+        include_str!("../../templates/macro.form_errors.html.j2"),
+    )?;
     env.add_template(
         "macro.pagination.html.j2",
         include_str!("../../templates/macro.pagination.html.j2"),
@@ -193,8 +738,26 @@ pub fn load_templates() -> anyhow::Result<minijinja::Environment<'static>> {
         "marked.html.j2",
         include_str!("../../templates/marked.html.j2"),
     )?;
+    env.add_template(
+        "new_chapters.html.j2",
+        include_str!("../../templates/new_chapters.html.j2"),
+    )?;
+    env.add_template(
+        "public_profile.html.j2",
+        include_str!("../../templates/public_profile.html.j2"),
+    )?;
+    env.add_template(
+        "totp_recovery_codes.html.j2",
+        include_str!("../../templates/totp_recovery_codes.html.j2"),
+    )?;
+    env.add_template(
+        "welcome.html.j2",
+        include_str!("../../templates/welcome.html.j2"),
+    )?;
     env.add_filter("short_date", short_date);
+    env.add_filter("freshness", freshness);
     env.add_filter("explain_scope", explain_scope);
+    env.add_filter("encode_uri_component", encode_uri_component_filter);
     // It's actually possible to just replace `default` by name in the environment,
     // but I want to make sure the differing expectations are recorded for future
     // maintenance.
@@ -229,6 +792,23 @@ mod tests {
 
     // Using an embedded template to avoid brittleness with actual
     // template text that might change over time.
+    #[test]
+    fn freshness_filter() {
+        use time::{format_description::well_known::Rfc3339, Duration};
+        let ago = |days: i64| {
+            (OffsetDateTime::now_utc() - Duration::days(days) + Duration::minutes(1))
+                .format(&Rfc3339)
+                .unwrap()
+        };
+        assert_eq!(freshness(&ago(0)), "today");
+        assert_eq!(freshness(&ago(1)), "today");
+        assert_eq!(freshness(&ago(2)), "this_week");
+        assert_eq!(freshness(&ago(7)), "this_week");
+        assert_eq!(freshness(&ago(8)), "this_month");
+        assert_eq!(freshness(&ago(31)), "stale");
+        assert_eq!(freshness("whenever"), "");
+    }
+
     #[test]
     fn bookmarklet_escaping() {
         let mut env = load_templates().expect("loads ok");
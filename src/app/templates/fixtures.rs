@@ -0,0 +1,319 @@
+//! Made-up users and dogears for the `/dev/preview` pages, so template and
+//! CSS work can see a full page without a real account (or any database)
+//! behind it. Everything here is synthetic; nothing's ever saved.
+
+use super::{
+    CadenceNote, Common, DogearsList, FeedNote, GrantsList, ListDisplay, NewChapter, NewChapters,
+    StaleNudge, WelcomePage, WhatsNew,
+};
+use crate::db::{Dogear, User};
+use crate::util::Pagination;
+use minijinja::{context, Value};
+use std::collections::HashMap;
+use time::{Duration, OffsetDateTime};
+
+/// The pages you can preview, by the name the route takes.
+pub const PAGES: &[&str] = &["index", "archived", "new_chapters", "faq", "welcome"];
+
+/// The datasets you can preview them with. The first one's the default.
+pub const FIXTURES: &[&str] = &["typical", "empty", "long"];
+
+/// A synthetic user's whole world, as far as the templates can tell.
+struct Fixture {
+    user: User,
+    dogears: Vec<Dogear>,
+    notes: HashMap<i64, String>,
+    tags: HashMap<i64, Vec<String>>,
+    cadences: HashMap<i64, CadenceNote>,
+    feeds: HashMap<i64, FeedNote>,
+    stale: Option<StaleNudge>,
+    pagination: Pagination,
+}
+
+impl Fixture {
+    fn named(name: &str) -> Option<Self> {
+        let now = OffsetDateTime::now_utc();
+        let user = User {
+            id: 1,
+            username: "preview".to_string(),
+            email: Some("preview@eardogger.invalid".to_string()),
+            created: now - Duration::days(400),
+        };
+        let dogear = |id: i64, site: &str, name: Option<&str>, days_ago: i64| Dogear {
+            id,
+            user_id: 1,
+            prefix: format!("{}/", site),
+            current: format!("https://{}/chapter-{}", site, id * 7),
+            display_name: name.map(String::from),
+            updated: now - Duration::days(days_ago),
+            paused: false,
+            public: false,
+            archived: false,
+        };
+        let one_page = |count: u32| Pagination {
+            current_page: 1,
+            page_size: None,
+            prev_page: None,
+            next_page: None,
+            total_pages: 1,
+            total_count: count,
+        };
+
+        let fixture = match name {
+            "empty" => Self {
+                user,
+                dogears: Vec::new(),
+                notes: HashMap::new(),
+                tags: HashMap::new(),
+                cadences: HashMap::new(),
+                feeds: HashMap::new(),
+                stale: None,
+                pagination: one_page(0),
+            },
+            "typical" => {
+                let mut dogears = vec![
+                    dogear(1, "comic.example.com", Some("A Weekly Comic"), 2),
+                    dogear(2, "serial.example.net/story", Some("Some Web Serial"), 9),
+                    dogear(3, "example.org/fic/12345", None, 30),
+                    dogear(4, "longrunning.example.com", Some("The Long One"), 300),
+                ];
+                dogears[1].public = true;
+                dogears[2].paused = true;
+                let notes = HashMap::from([(1, "the one with the boat".to_string())]);
+                let tags = HashMap::from([
+                    (1, vec!["comics".to_string()]),
+                    (2, vec!["prose".to_string(), "ongoing".to_string()]),
+                ]);
+                let cadences = HashMap::from([
+                    (
+                        1,
+                        CadenceNote {
+                            every: "~7 days".to_string(),
+                            probably_new: false,
+                        },
+                    ),
+                    (
+                        2,
+                        CadenceNote {
+                            every: "~3 days".to_string(),
+                            probably_new: true,
+                        },
+                    ),
+                ]);
+                let feeds = HashMap::from([
+                    (
+                        2,
+                        FeedNote {
+                            feed_url: "https://serial.example.net/feed".to_string(),
+                            auto_advance: false,
+                            new_chapter: Some("https://serial.example.net/story/15".to_string()),
+                            failing: None,
+                        },
+                    ),
+                    (
+                        1,
+                        FeedNote {
+                            feed_url: "https://comic.example.com/rss".to_string(),
+                            auto_advance: true,
+                            new_chapter: None,
+                            failing: Some("HTTP 503".to_string()),
+                        },
+                    ),
+                ]);
+                Self {
+                    user,
+                    dogears,
+                    notes,
+                    tags,
+                    cadences,
+                    feeds,
+                    stale: Some(StaleNudge {
+                        months: 6,
+                        ids: vec![4],
+                    }),
+                    pagination: one_page(4),
+                }
+            }
+            "long" => {
+                let dogears = (1..=50)
+                    .map(|n| {
+                        let site = format!(
+                            "a-site-with-a-really-quite-long-name-{}.example.com/and/a/deep/path",
+                            n
+                        );
+                        let name = format!(
+                            "Serial number {} (which has a subtitle that goes on for a while, as they do)",
+                            n
+                        );
+                        dogear(n, &site, Some(name.as_str()), n)
+                    })
+                    .collect();
+                let tags = (1..=50)
+                    .map(|n| {
+                        let tags = ["one", "two", "three", "four", "five", "six"]
+                            .iter()
+                            .map(|t| format!("tag-{}", t))
+                            .collect();
+                        (n, tags)
+                    })
+                    .collect();
+                Self {
+                    user,
+                    dogears,
+                    notes: HashMap::new(),
+                    tags,
+                    cadences: HashMap::new(),
+                    feeds: HashMap::new(),
+                    stale: None,
+                    pagination: Pagination {
+                        current_page: 2,
+                        page_size: None,
+                        prev_page: Some(1),
+                        next_page: Some(3),
+                        total_pages: 5,
+                        total_count: 250,
+                    },
+                }
+            }
+            _ => return None,
+        };
+        Some(fixture)
+    }
+}
+
+/// The template and context for previewing a page with a fixture, or None
+/// if either name is unknown. The context carries a `preview` value naming
+/// both, which the layout turns into a banner.
+pub fn preview(page: &str, fixture: &str) -> Option<(&'static str, Value)> {
+    let f = Fixture::named(fixture)?;
+    let preview = context! {page, fixture, pages => PAGES, fixtures => FIXTURES};
+    let common = Common {
+        user: Some(&f.user),
+        csrf_token: "preview",
+        ..Common::anonymous("preview's Dogears")
+    };
+    let display = ListDisplay {
+        compact: false,
+        show_prefix: fixture == "long",
+        show_dates: true,
+        query: String::new(),
+    };
+    let (no_cadences, no_feeds) = (HashMap::new(), HashMap::new());
+
+    match page {
+        "index" => {
+            let live: Vec<Dogear> = f.dogears.iter().filter(|d| !d.archived).cloned().collect();
+            let recent: Vec<Dogear> = live.iter().take(3).cloned().collect();
+            let dogears_list = DogearsList {
+                dogears: &live,
+                notes: &f.notes,
+                tags: &f.tags,
+                cadences: &f.cadences,
+                feeds: &f.feeds,
+                feed_polling: true,
+                pagination: f.pagination,
+                shared_from: None,
+                filter: None,
+                display,
+                stale: f.stale.as_ref(),
+                archived: false,
+            };
+            let shared_with_me = GrantsList { grants: &[] };
+            let whats_new = (!recent.is_empty()).then_some(WhatsNew {
+                count: live.len() as u32,
+                dogears: &recent,
+            });
+            let ctx = context! {common, dogears_list, shared_with_me, whats_new, preview};
+            Some(("index.html.j2", ctx))
+        }
+        "archived" => {
+            let archived: Vec<Dogear> = f
+                .dogears
+                .iter()
+                .cloned()
+                .map(|d| Dogear {
+                    archived: true,
+                    ..d
+                })
+                .collect();
+            let common = Common {
+                title: "Archived dogears",
+                ..common
+            };
+            let dogears_list = DogearsList {
+                dogears: &archived,
+                notes: &f.notes,
+                tags: &f.tags,
+                cadences: &no_cadences,
+                feeds: &no_feeds,
+                feed_polling: true,
+                pagination: f.pagination,
+                shared_from: None,
+                filter: None,
+                display,
+                stale: None,
+                archived: true,
+            };
+            let ctx = context! {common, dogears_list, preview};
+            Some(("archived.html.j2", ctx))
+        }
+        "new_chapters" => {
+            let entries: Vec<NewChapter> = f
+                .dogears
+                .iter()
+                .map(|d| NewChapter {
+                    dogear: d.clone(),
+                    cadence: CadenceNote {
+                        every: f
+                            .cadences
+                            .get(&d.id)
+                            .map_or("~5 days", |c| c.every.as_str())
+                            .to_string(),
+                        probably_new: true,
+                    },
+                })
+                .collect();
+            let common = Common {
+                title: "Probably new chapters",
+                ..common
+            };
+            let new_chapters = NewChapters { entries: &entries };
+            let ctx = context! {common, new_chapters, preview};
+            Some(("new_chapters.html.j2", ctx))
+        }
+        "faq" => {
+            let common = Common {
+                title: "About Eardogger",
+                ..common
+            };
+            let ctx = context! {common, preview};
+            Some(("faq.html.j2", ctx))
+        }
+        "welcome" => {
+            // Same example dogear for every fixture, since it's what a
+            // brand new account gets.
+            let example = Dogear {
+                id: 1,
+                user_id: 1,
+                prefix: "eardogger.invalid/faq".to_string(),
+                current: "https://eardogger.invalid/faq#how-to".to_string(),
+                display_name: Some("Eardogger FAQ (example dogear)".to_string()),
+                updated: OffsetDateTime::now_utc(),
+                paused: false,
+                public: false,
+                archived: false,
+            };
+            let common = Common {
+                title: "Welcome to Eardogger",
+                ..common
+            };
+            let welcome_page = WelcomePage {
+                bookmarklet_url: "javascript:void(0)",
+                example: &example,
+            };
+            let ctx = context! {common, welcome_page, preview};
+            Some(("welcome.html.j2", ctx))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,396 @@
+//! The text-entry forms, described in Rust and drawn by `render_form` in
+//! `macro.form.html.j2`, so every one of them gets its CSRF field, sticky
+//! values, and error states (see `FieldError`) the same way. Forms that are
+//! all checkboxes, radios, or selects are still written out by hand.
+
+use super::FieldError;
+use crate::util::ChallengeWidget;
+use serde::Serialize;
+use std::borrow::Cow;
+
+/// How a field gets drawn.
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    Text,
+    Password,
+    Textarea,
+    /// Not editable: the value's shown as text and sent as a hidden input.
+    Shown,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FormField<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    pub label: Cow<'a, str>,
+    pub kind: FieldKind,
+    /// The value to start with. Password fields never get one.
+    pub value: Option<&'a str>,
+    pub maxlength: Option<usize>,
+    pub autocomplete: Option<&'a str>,
+    pub inputmode: Option<&'a str>,
+    /// Text for a "(huh?)" reveal button after the label.
+    pub help: Option<&'a str>,
+    /// Starts read-only, with a Customize button to unlock it (client.js).
+    pub locked: bool,
+    /// Focus this one on page load, unless the form has an error to point at.
+    pub autofocus: bool,
+}
+
+impl<'a> FormField<'a> {
+    pub fn new(
+        kind: FieldKind,
+        id: &'a str,
+        name: &'a str,
+        label: impl Into<Cow<'a, str>>,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            label: label.into(),
+            kind,
+            value: None,
+            maxlength: None,
+            autocomplete: None,
+            inputmode: None,
+            help: None,
+            locked: false,
+            autofocus: false,
+        }
+    }
+
+    /// The common case, where the name and id are the same.
+    pub fn text(name: &'a str, label: impl Into<Cow<'a, str>>) -> Self {
+        Self::new(FieldKind::Text, name, name, label)
+    }
+
+    pub fn password(name: &'a str, label: impl Into<Cow<'a, str>>) -> Self {
+        Self::new(FieldKind::Password, name, name, label)
+    }
+
+    pub fn value(mut self, value: Option<&'a str>) -> Self {
+        if self.kind != FieldKind::Password {
+            self.value = value;
+        }
+        self
+    }
+
+    pub fn maxlength(mut self, maxlength: usize) -> Self {
+        self.maxlength = Some(maxlength);
+        self
+    }
+
+    pub fn autocomplete(mut self, autocomplete: &'a str) -> Self {
+        self.autocomplete = Some(autocomplete);
+        self
+    }
+
+    pub fn inputmode(mut self, inputmode: &'a str) -> Self {
+        self.inputmode = Some(inputmode);
+        self
+    }
+
+    pub fn help(mut self, help: &'a str) -> Self {
+        self.help = Some(help);
+        self
+    }
+
+    pub fn locked(mut self) -> Self {
+        self.locked = true;
+        self
+    }
+
+    pub fn autofocus(mut self) -> Self {
+        self.autofocus = true;
+        self
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Form<'a> {
+    pub id: &'a str,
+    pub action: &'a str,
+    pub submit: &'a str,
+    pub submit_id: Option<&'a str>,
+    /// The id of the alert region for `error`.
+    pub error_id: Cow<'a, str>,
+    pub error: Option<&'a FieldError>,
+    pub fields: Vec<FormField<'a>>,
+    /// Hidden inputs other than the CSRF token, as (name, value).
+    pub hidden: Vec<(&'a str, &'a str)>,
+    /// Logged-out forms check `login_csrf_token` instead of the session's.
+    pub csrf_field: &'static str,
+    /// Extra attributes for the form element, as (name, value).
+    pub attrs: Vec<(&'static str, String)>,
+}
+
+impl<'a> Form<'a> {
+    pub fn new(id: &'a str, action: &'a str, submit: &'a str) -> Self {
+        Self {
+            id,
+            action,
+            submit,
+            submit_id: None,
+            error_id: format!("{}-error", id).into(),
+            error: None,
+            fields: Vec::new(),
+            hidden: Vec::new(),
+            csrf_field: "csrf_token",
+            attrs: Vec::new(),
+        }
+    }
+
+    pub fn field(mut self, field: FormField<'a>) -> Self {
+        self.fields.push(field);
+        self
+    }
+
+    pub fn hidden(mut self, name: &'a str, value: &'a str) -> Self {
+        self.hidden.push((name, value));
+        self
+    }
+
+    pub fn attr(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.attrs.push((name, value.to_string()));
+        self
+    }
+
+    /// An error, and the id of the alert region it shows up in.
+    pub fn error(mut self, error: Option<&'a FieldError>, error_id: &'a str) -> Self {
+        self.error = error;
+        self.error_id = error_id.into();
+        self
+    }
+
+    pub fn submit_id(mut self, submit_id: &'a str) -> Self {
+        self.submit_id = Some(submit_id);
+        self
+    }
+
+    pub fn logged_out(mut self) -> Self {
+        self.csrf_field = "login_csrf_token";
+        self
+    }
+}
+
+pub fn login_form<'a>(return_to: &'a str, error: Option<&'a FieldError>) -> Form<'a> {
+    Form::new("loginform", "/login", "Log in")
+        .logged_out()
+        .error(error, "login-error")
+        .field(FormField::text("username", "Username"))
+        .field(FormField::password("password", "Password"))
+        .hidden("return_to", return_to)
+}
+
+/// What someone typed into the signup form last time, and why it bounced.
+pub struct SignupValues<'a> {
+    pub username: &'a str,
+    pub email: &'a str,
+    pub error: Option<&'a FieldError>,
+}
+
+/// The signup form, minus the honeypot and challenge widgets, which the
+/// login template draws in the middle of it.
+pub fn signup_form<'a>(
+    password_min_length: usize,
+    unicode_usernames: bool,
+    stamp: &'a str,
+    challenge: Option<&ChallengeWidget>,
+    values: SignupValues<'a>,
+) -> Form<'a> {
+    let any_language = if unicode_usernames {
+        ", in any language"
+    } else {
+        ""
+    };
+    let mut form = Form::new("signupform", "/signup", "Sign up")
+        .logged_out()
+        .error(values.error, "signup-error")
+        .field(
+            FormField::text(
+                "new_username",
+                format!(
+                    "New username (can use letters, numbers, -, and _{})",
+                    any_language
+                ),
+            )
+            .value(Some(values.username)),
+        )
+        .field(FormField::password(
+            "new_password",
+            new_password_label(password_min_length),
+        ))
+        .field(FormField::password("new_password_again", "Confirm new password"))
+        .field(
+            FormField::text("email", "Email (optional)")
+                .value(Some(values.email))
+                .help("I don't actually want your email, tbh. But if you include it, I can help recover your password if you lose it. I might also send out warnings for downtime or major changes."),
+        )
+        .hidden("signup_stamp", stamp);
+    if let Some(ChallengeWidget {
+        provider: "proof_of_work",
+        difficulty: Some(difficulty),
+        ..
+    }) = challenge
+    {
+        form = form
+            .attr("data-pow-challenge", stamp)
+            .attr("data-pow-difficulty", difficulty);
+    }
+    form
+}
+
+/// The create-a-dogear form, for /mark and its friends. `suggested_prefix`
+/// is our guess from `suggest_prefix`; None if the URL's no good, in which
+/// case the form will just fail on submit. `display_name` is a suggested
+/// site name, if we got one (like the page title from a share).
+pub fn create_form<'a>(
+    bookmarked_url: &'a str,
+    suggested_prefix: Option<&'a str>,
+    display_name: Option<&'a str>,
+    error: Option<&'a FieldError>,
+) -> Form<'a> {
+    Form::new("create-dogear", "/mark", "Create new dogear")
+        .submit_id("submit-dogear")
+        .error(error, "create-error")
+        .field(
+            FormField::text("display_name", "Name of site (optional):")
+                .maxlength(200)
+                .value(display_name)
+                .autofocus(),
+        )
+        .field(
+            FormField::new(FieldKind::Shown, "current", "current", "Current page:")
+                .value(Some(bookmarked_url)),
+        )
+        .field(
+            FormField::new(
+                FieldKind::Textarea,
+                "prefix",
+                "prefix",
+                "These URLs always start with:",
+            )
+            .maxlength(300)
+            .value(Some(
+                suggested_prefix
+                    .filter(|p| !p.is_empty())
+                    .unwrap_or(bookmarked_url),
+            ))
+            .locked(),
+        )
+}
+
+/// The text-entry forms on the account page.
+#[derive(Serialize, Debug)]
+pub struct AccountForms<'a> {
+    pub change_password: Form<'a>,
+    pub change_email: Form<'a>,
+    pub totp_recovery_codes: Form<'a>,
+    pub totp_disable: Form<'a>,
+    pub totp_confirm: Form<'a>,
+    pub delete_account: Form<'a>,
+    pub create_grant: Form<'a>,
+    pub create_webhook: Form<'a>,
+}
+
+impl<'a> AccountForms<'a> {
+    pub fn new(password_min_length: usize, password_error: Option<&'a FieldError>) -> Self {
+        let password_confirmed =
+            |id: &'a str| FormField::new(FieldKind::Password, id, "password", "Password");
+        Self {
+            change_password: Form::new("changepasswordform", "/changepassword", "Change password")
+                .error(password_error, "password-error")
+                .field(FormField::password("password", "Current password"))
+                .field(FormField::password(
+                    "new_password",
+                    new_password_label(password_min_length),
+                ))
+                .field(FormField::password(
+                    "new_password_again",
+                    "Confirm new password",
+                )),
+            change_email: Form::new("change_email_form", "/change_email", "Change email")
+                .field(FormField::new(
+                    FieldKind::Text,
+                    "change_email_new_email",
+                    "new_email",
+                    "New email address",
+                ))
+                .field(password_confirmed("change_email_password")),
+            totp_recovery_codes: Form::new(
+                "totp_recovery_codes_form",
+                "/totp/recovery_codes",
+                "Make new recovery codes",
+            )
+            .field(password_confirmed("totp_recovery_codes_password")),
+            totp_disable: Form::new("totp_disable_form", "/totp/disable", "Turn off two-factor")
+                .field(password_confirmed("totp_disable_password")),
+            totp_confirm: Form::new("totp_confirm_form", "/totp/confirm", "Turn on two-factor")
+                .field(
+                    FormField::new(FieldKind::Text, "totp_confirm_code", "code", "Code")
+                        .inputmode("numeric")
+                        .autocomplete("one-time-code"),
+                ),
+            delete_account: Form::new("delete_account_form", "/delete_account", "DELETE ACCOUNT")
+                .field(FormField::text(
+                    "confirm_delete_account",
+                    "Type \"delete my account\" here",
+                ))
+                .field(password_confirmed("delete_account_password")),
+            create_grant: Form::new("create_grant_form", "/grants", "Share my dogears")
+                .field(FormField::text("grantee_username", "Their username")),
+            create_webhook: Form::new("create_webhook_form", "/webhooks", "Add webhook").field(
+                FormField::new(FieldKind::Text, "outgoing_webhook_url", "url", "URL"),
+            ),
+        }
+    }
+}
+
+fn new_password_label(min_length: usize) -> String {
+    format!(
+        "New password (at least {} characters, and not easy to guess)",
+        min_length
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builders() {
+        let error = FieldError::new("email", "nope");
+        let challenge = ChallengeWidget {
+            provider: "proof_of_work",
+            difficulty: Some(12),
+            site_key: None,
+        };
+        let values = SignupValues {
+            username: "somebody",
+            email: "a@b.c",
+            error: Some(&error),
+        };
+        let form = signup_form(8, false, "stamp", Some(&challenge), values);
+        assert_eq!(form.csrf_field, "login_csrf_token");
+        assert_eq!(form.error_id, "signup-error");
+        assert_eq!(
+            form.attrs,
+            vec![
+                ("data-pow-challenge", "stamp".to_string()),
+                ("data-pow-difficulty", "12".to_string())
+            ]
+        );
+        assert_eq!(form.fields[0].value, Some("somebody"));
+        assert!(form.fields[1].label.contains("at least 8"));
+
+        // Passwords don't stick, no matter what.
+        let field = FormField::password("password", "Password").value(Some("hunter2"));
+        assert_eq!(field.value, None);
+
+        // The default region id comes from the form's.
+        let form = Form::new("some_form", "/somewhere", "Go");
+        assert_eq!(form.error_id, "some_form-error");
+        assert_eq!(form.csrf_field, "csrf_token");
+    }
+}
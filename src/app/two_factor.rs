@@ -0,0 +1,367 @@
+//! Two-factor logins, with the six-digit codes from authenticator apps
+//! (see `util::totp`). It's optional, and set up from the account page: we
+//! make a secret, they add it to their app and type in a code to prove it
+//! took, and they get a batch of recovery codes for when the app's gone.
+//!
+//! After that, getting the password right at `/login` doesn't make a
+//! session; it gets a short-lived signed cookie saying whose code we're
+//! waiting for, and a trip to `/login/totp`. Wrong codes count against the
+//! same per-username limit as wrong API passwords, so six digits can't be
+//! brute-forced. And since a password alone shouldn't be enough to get
+//! into these accounts, API Basic auth and the token exchange turn them
+//! away too.
+
+use super::authentication::{theme_from_cookies, AuthSession};
+use super::routes::{finish_login, forced_reset_redirect};
+use super::state::DogState;
+use super::templates::*;
+use super::web_result::{WebError, WebResult};
+use crate::db::{AuditKind, AuditSource, TotpMethod, User};
+use crate::util::{provisioning_uri, COOKIE_TOTP_PENDING};
+
+use axum::{
+    extract::{Form, Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use http::HeaderMap;
+use minijinja::context;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tower_cookies::{Cookie, Cookies};
+use tracing::{info, warn};
+
+/// How long someone has to type their code after getting their password
+/// right, in minutes.
+const PENDING_MINUTES: i64 = 10;
+
+/// What the pending-login cookie says: who got their password right, until
+/// when, and where they were headed. Plus a CSRF token for the code form,
+/// since there's no session to keep one in yet.
+#[derive(Serialize, Deserialize, Debug)]
+struct PendingLogin {
+    user_id: i64,
+    username: String,
+    expires: i64,
+    csrf_token: String,
+    return_to: String,
+}
+
+/// Halfway through a login: the password was right, and now we want a code.
+/// Sets the cookie that `/login/totp` picks up from.
+pub fn start_totp_login(state: &DogState, cookies: &Cookies, user: &User, return_to: &str) {
+    let pending = PendingLogin {
+        user_id: user.id,
+        username: user.username.clone(),
+        expires: (OffsetDateTime::now_utc() + time::Duration::minutes(PENDING_MINUTES))
+            .unix_timestamp(),
+        csrf_token: crate::util::uuid_string(),
+        return_to: return_to.to_string(),
+    };
+    let value = URL_SAFE_NO_PAD
+        .encode(serde_json::to_vec(&pending).expect("a struct of strings always serializes"));
+    let cookie = Cookie::build((COOKIE_TOTP_PENDING, value))
+        .max_age(time::Duration::minutes(PENDING_MINUTES))
+        .http_only(true)
+        .secure(true)
+        .same_site(tower_cookies::cookie::SameSite::Strict)
+        .build()
+        .into_owned();
+    cookies.signed(&state.cookie_key).add(cookie);
+}
+
+/// The pending login, if there's one and it's still good.
+fn pending_login(state: &DogState, cookies: &Cookies) -> Option<PendingLogin> {
+    let cookie = cookies.signed(&state.cookie_key).get(COOKIE_TOTP_PENDING)?;
+    let bytes = URL_SAFE_NO_PAD.decode(cookie.value()).ok()?;
+    let pending: PendingLogin = serde_json::from_slice(&bytes).ok()?;
+    (pending.expires > OffsetDateTime::now_utc().unix_timestamp()).then_some(pending)
+}
+
+fn stale_login() -> WebError {
+    WebError::new(
+        StatusCode::BAD_REQUEST,
+        r#"That login took too long, or the form was tampered with. Go back
+            to the home page and log in again."#
+            .to_string(),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TotpLoginQuery {
+    #[serde(default)]
+    failed: bool,
+}
+
+/// The second step of a two-factor login: a form for the code. Without a
+/// pending login, there's nothing to do here, so it's back to the start.
+#[tracing::instrument(skip_all)]
+pub async fn login_totp(
+    State(state): State<DogState>,
+    cookies: Cookies,
+    Query(query): Query<TotpLoginQuery>,
+) -> WebResult<Response> {
+    let Some(pending) = pending_login(&state, &cookies) else {
+        return Ok(Redirect::to("/").into_response());
+    };
+    let common = Common {
+        csrf_token: &pending.csrf_token,
+        theme: theme_from_cookies(&cookies),
+        ..Common::anonymous("Two-factor login")
+    };
+    let failed = query.failed;
+    let ctx = context! {common, failed};
+    Ok(Html(state.render_view("login_totp.html.j2", ctx)?).into_response())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TotpLoginParams {
+    code: String,
+    csrf_token: String,
+}
+
+/// Handle POSTs from the code form. A good code (or recovery code) finishes
+/// the login like the password would have; a bad one counts against the
+/// username's login failures and goes back to the form.
+#[tracing::instrument(skip_all)]
+pub async fn post_login_totp(
+    State(state): State<DogState>,
+    cookies: Cookies,
+    req_headers: HeaderMap,
+    source: AuditSource,
+    Form(params): Form<TotpLoginParams>,
+) -> WebResult<Redirect> {
+    let Some(pending) = pending_login(&state, &cookies) else {
+        return Err(stale_login());
+    };
+    if params.csrf_token != pending.csrf_token {
+        return Err(stale_login());
+    }
+    let username = pending.username.as_str();
+    let limiter = &state.basic_auth_limiter;
+    if limiter.is_limited(username) {
+        warn!(target: "audit", %username, "two-factor login: rate limited");
+        return Err(WebError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many wrong codes. Wait a while, then log in again.".to_string(),
+        ));
+    }
+    // Make sure it's still the same account, in case it got deleted and
+    // someone took the name in the meantime, and that nobody disabled it
+    // since the password step.
+    let Some(user) = state
+        .db
+        .users()
+        .active_by_name(username)
+        .await?
+        .filter(|u| u.id == pending.user_id)
+    else {
+        return Err(stale_login());
+    };
+
+    let Some(method) = state.db.totp().verify(user.id, &params.code).await? else {
+        let failures = limiter.record(username);
+        warn!(target: "audit", %username, failures, "two-factor login: bad code");
+        if failures == limiter.max() {
+            warn!(target: "alerts", %username, failures, "possible two-factor guessing: locking this username out for a while");
+        }
+        return Ok(Redirect::to("/login/totp?failed=true"));
+    };
+    limiter.reset(username);
+    cookies.remove((COOKIE_TOTP_PENDING, "").into());
+    let detail = match method {
+        TotpMethod::Code => None,
+        TotpMethod::RecoveryCode => {
+            info!(target: "audit", %username, "two-factor login: used a recovery code");
+            Some("with a recovery code")
+        }
+    };
+    finish_login(&state, &cookies, &req_headers, &source, &user, detail).await?;
+    if let Some(reset) = forced_reset_redirect(&state, &user).await? {
+        return Ok(reset);
+    }
+    Ok(Redirect::to(&pending.return_to))
+}
+
+/// How the account page's two-factor section looks for someone.
+pub async fn totp_settings(state: &DogState, user: &User) -> WebResult<TotpSettings> {
+    let totp = state.db.totp();
+    let Some(secret) = totp.get(user.id).await? else {
+        return Ok(TotpSettings::default());
+    };
+    if secret.confirmed.is_some() {
+        return Ok(TotpSettings {
+            enabled_since: secret.confirmed,
+            recovery_codes_left: totp.recovery_codes_left(user.id).await?,
+            ..Default::default()
+        });
+    }
+    let issuer = state.config.public_url.host_str().unwrap_or("eardogger");
+    Ok(TotpSettings {
+        provisioning_uri: Some(provisioning_uri(issuer, &user.username, &secret.secret)),
+        pending_secret: Some(secret.secret),
+        ..Default::default()
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TotpSetupParams {
+    csrf_token: String,
+}
+
+/// The "set up two-factor" button, on the account page. Makes a secret and
+/// goes back to the account page, which shows it until they confirm it.
+#[tracing::instrument(skip_all)]
+pub async fn post_totp_setup(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    Form(params): Form<TotpSetupParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(stale_form("two-factor setup"));
+    }
+    if state.db.totp().begin_setup(auth.user.id).await?.is_none() {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            "You've already got two-factor on. Turn it off first to switch apps.".to_string(),
+        ));
+    }
+    Ok(Redirect::to("/account?changed=two_factor#two-factor"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TotpConfirmParams {
+    code: String,
+    csrf_token: String,
+}
+
+/// The "here's a code from my app" form, which finishes setting up. Shows
+/// their recovery codes, since this is the only chance to.
+#[tracing::instrument(skip_all)]
+pub async fn post_totp_confirm(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    source: AuditSource,
+    Form(params): Form<TotpConfirmParams>,
+) -> WebResult<Html<String>> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(stale_form("two-factor setup"));
+    }
+    let Some(recovery_codes) = state.db.totp().confirm(auth.user.id, &params.code).await? else {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            r#"That code didn't work. Make sure your app's clock is right and
+                you're using the newest code, then go back to the account
+                page and try again."#
+                .to_string(),
+        ));
+    };
+    state
+        .audit(&auth.user, AuditKind::TwoFactorEnabled, None, &source)
+        .await;
+    recovery_codes_page(&state, &auth, recovery_codes, true)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TotpPasswordParams {
+    password: String,
+    csrf_token: String,
+}
+
+/// Swap out the recovery codes for a fresh batch. Takes their password,
+/// since it hands out a way in.
+#[tracing::instrument(skip_all)]
+pub async fn post_totp_recovery_codes(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    source: AuditSource,
+    Form(params): Form<TotpPasswordParams>,
+) -> WebResult<Html<String>> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(stale_form("recovery codes"));
+    }
+    check_password(&state, &auth, &params.password).await?;
+    let Some(recovery_codes) = state
+        .db
+        .totp()
+        .regenerate_recovery_codes(auth.user.id)
+        .await?
+    else {
+        return Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            "You don't have two-factor on, so there's no need for recovery codes.".to_string(),
+        ));
+    };
+    state
+        .audit(
+            &auth.user,
+            AuditKind::RecoveryCodesRegenerated,
+            None,
+            &source,
+        )
+        .await;
+    recovery_codes_page(&state, &auth, recovery_codes, false)
+}
+
+/// Turn two-factor off, or give up on setting it up. Takes their password.
+#[tracing::instrument(skip_all)]
+pub async fn post_totp_disable(
+    State(state): State<DogState>,
+    auth: AuthSession,
+    source: AuditSource,
+    Form(params): Form<TotpPasswordParams>,
+) -> WebResult<Redirect> {
+    if params.csrf_token != auth.session.csrf_token {
+        return Err(stale_form("turn off two-factor"));
+    }
+    check_password(&state, &auth, &params.password).await?;
+    let was_enabled = state.db.totp().enabled(auth.user.id).await?;
+    state.db.totp().disable(auth.user.id).await?;
+    if was_enabled {
+        state
+            .audit(&auth.user, AuditKind::TwoFactorDisabled, None, &source)
+            .await;
+    }
+    Ok(Redirect::to("/account?changed=two_factor#two-factor"))
+}
+
+fn stale_form(name: &str) -> WebError {
+    WebError::new(
+        StatusCode::BAD_REQUEST,
+        format!(
+            r#"The {} form you tried to use was stale, or had been tampered
+                with. Go back to the account page and try again."#,
+            name
+        ),
+    )
+}
+
+async fn check_password(state: &DogState, auth: &AuthSession, password: &str) -> WebResult<()> {
+    match state
+        .db
+        .users()
+        .authenticate(&auth.user.username, password)
+        .await?
+    {
+        Some(_) => Ok(()),
+        None => Err(WebError::new(
+            StatusCode::BAD_REQUEST,
+            "Wrong password".to_string(),
+        )),
+    }
+}
+
+fn recovery_codes_page(
+    state: &DogState,
+    auth: &AuthSession,
+    recovery_codes: Vec<String>,
+    just_enabled: bool,
+) -> WebResult<Html<String>> {
+    let common = Common {
+        breadcrumbs: ACCOUNT_CRUMBS,
+        ..auth.common_args("Recovery codes")
+    };
+    let ctx = context! {common, recovery_codes, just_enabled};
+    Ok(Html(state.render_view("totp_recovery_codes.html.j2", ctx)?))
+}
@@ -24,6 +24,7 @@
 //! eyre::Report error type, so he can't just do a blanket impl for
 //! T: Error.
 
+use super::request_id::current_request_id;
 use crate::config::is_production;
 use crate::util::IntoHandlerError;
 use axum::{
@@ -31,7 +32,6 @@ use axum::{
     response::{Html, IntoResponse, Response},
     Json,
 };
-use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use tracing::error;
 
@@ -47,18 +47,15 @@ pub struct AppError {
     pub kind: AppErrorKind,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum AppErrorKind {
     Html,
     Json,
 }
 
-// A dumb Serialize wrapper for `{ "error":"blah blah" }` so I don't have to
-// use the dynamic json!() object macro.
-#[derive(Serialize, Deserialize, Debug)]
-pub struct RawJsonError {
-    pub error: Cow<'static, str>,
-}
+// The `{ "error":"blah blah" }` wrapper is an API wire type, so it lives
+// with the others where the client can get at it.
+pub use eardogger_rs::api_types::RawJsonError;
 
 impl AppError {
     pub fn new(status: StatusCode, message: String, kind: AppErrorKind) -> Self {
@@ -91,18 +88,30 @@ impl IntoResponse for AppError {
             Cow::from(message)
         };
 
+        let request_id = current_request_id();
+
         match kind {
             AppErrorKind::Html => {
                 let mut text = String::new();
                 text.push_str("<p>");
                 html_escape::encode_safe_to_string(&message, &mut text);
                 text.push_str("</p>");
+                if let Some(id) = &request_id {
+                    text.push_str(
+                        r#"<p class="request-id">If you report this, mention request ID <code>"#,
+                    );
+                    html_escape::encode_safe_to_string(id, &mut text);
+                    text.push_str("</code>.</p>");
+                }
 
                 let page = format!(include_str!("../../templates/_error.html"), &text);
                 (status, Html(page)).into_response()
             }
             AppErrorKind::Json => {
-                let body = RawJsonError { error: message };
+                let body = RawJsonError {
+                    error: message,
+                    request_id,
+                };
                 (status, Json(body)).into_response()
             }
         }
@@ -1,71 +1,297 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
-// For the time being, I want to keep this as stupidly simple as possible.
-// If it gets out of hand later, I can bring in clap or whatever.
+// This used to be a hand-rolled arg scanner, and the deal was that I'd
+// bring in clap once it got out of hand. Subcommands with their own flags
+// were the point where it got out of hand.
 
-/// Command-line options received at run-time.
+/// Command-line options received at run-time. With no subcommand, we
+/// serve the app (or do one of the db maintenance modes and exit).
+#[derive(Parser, Debug)]
+#[command(
+    about = "Eardogger: movable bookmarks for web serials",
+    disable_version_flag = true
+)]
 pub struct Options {
     /// `--config` lets you specify the path of the config file to use.
     /// It's optional; if omitted, we'll use eardogger.toml in the current
     /// working directory.
+    #[arg(long, value_name = "FILE")]
     pub config: Option<PathBuf>,
     /// `--migrate` runs any pending database migrations, and then exits instead
     /// of starting the server.
+    #[arg(long)]
     pub migrate: bool,
+    /// `--no-backup` skips the database snapshot that `--migrate` takes
+    /// before applying anything.
+    #[arg(long, requires = "migrate")]
+    pub no_backup: bool,
     /// `--status` (or `--check`) prints the current database migrations status and then exits.
+    // Originally chose --status, but kept typing --check by accident lol
+    #[arg(long, visible_alias = "check")]
     pub status: bool,
     /// `--version` prints the commit sha and build date, then exits.
+    #[arg(long)]
     pub version: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
 
-enum ParserState {
-    Scanning,
-    ConfigVal,
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Set up a new deployment: write a commented config file, and create the
+    /// data dir, cookie key, and an empty migrated database.
+    Init(InitArgs),
+    /// Hammer a running instance with concurrent simulated clients and
+    /// report latencies. Dev tool; needs the `client` feature.
+    Loadtest(LoadtestArgs),
+    /// Maintenance jobs for an existing database. Unlike the other
+    /// subcommands, these read the config file to find the db.
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Mark your spot at a URL on your Eardogger instance, like the mark
+    /// bookmarklet does. Needs the `client` feature and a saved token (see
+    /// `client-login`).
+    Mark(MarkArgs),
+    /// Look up where you left off on a site, and print it or open it in
+    /// your browser. Needs the `client` feature and a saved manage token.
+    Resume(ResumeArgs),
+    /// Save an instance URL and API token for `mark` and `resume`. Reads
+    /// the token from stdin.
+    ClientLogin(ClientLoginArgs),
+    /// Developer tools for working on Eardogger itself. These run from a
+    /// source checkout, not a deployment.
+    #[command(name = "self", subcommand)]
+    SelfCmd(SelfCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SelfCommand {
+    /// Build release binaries and bundle each one with the migrations,
+    /// assets, example config, and server config templates, as a tarball
+    /// ready to untar over a data dir.
+    Package(PackageArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Re-run the current prefix and URL normalization rules over every
+    /// dogear, and report the rows that have drifted (or no longer match
+    /// at all). Run this after upgrading to a version that changes the rules.
+    Normalize(NormalizeArgs),
+    /// Make an account without going through the website. Reads the
+    /// password from stdin (twice, if it's a terminal), and holds it to the
+    /// same `[passwords]` policy as signups do.
+    CreateUser(CreateUserArgs),
+    /// Apply the `[retention]` limits now, instead of waiting for the
+    /// server's daily cleanup.
+    Purge,
+    /// Find one user's overlapping dogears (where one prefix just extends
+    /// another, like `example.com/comic` and `example.com/comic/book-2`),
+    /// and merge each bunch into the one with the shortest prefix, keeping
+    /// the most recent spot. Asks about each bunch at a terminal; otherwise
+    /// only reports them, unless you pass `--yes`.
+    Dedupe(DedupeArgs),
+    /// Make someone a site admin, which gets them the /admin/users page.
+    /// `--revoke` takes it back.
+    Admin(AdminArgs),
+    /// Write a SQLCipher-encrypted copy of a plaintext database, using the
+    /// configured key. Needs the `sqlcipher` feature. Swap the copy in with
+    /// the server stopped.
+    Encrypt(EncryptArgs),
+}
+
+/// Options for `db normalize`.
+#[derive(Args, Debug)]
+pub struct NormalizeArgs {
+    /// Rewrite the out-of-date rows, instead of only reporting them.
+    #[arg(long)]
+    pub fix: bool,
+}
+
+/// Options for `db dedupe`.
+#[derive(Args, Debug)]
+pub struct DedupeArgs {
+    /// Whose dogears to check.
+    pub username: String,
+    /// Merge every bunch without asking.
+    #[arg(long)]
+    pub yes: bool,
+}
+
+/// Options for `db admin`.
+#[derive(Args, Debug)]
+pub struct AdminArgs {
+    /// Who to make an admin.
+    pub username: String,
+    /// Stop them being an admin instead.
+    #[arg(long)]
+    pub revoke: bool,
+}
+
+/// Options for `db encrypt`.
+#[derive(Args, Debug)]
+pub struct EncryptArgs {
+    /// Where to write the encrypted copy. Must not exist yet.
+    #[arg(long, value_name = "FILE")]
+    pub out: PathBuf,
+}
+
+/// Options for `self package`.
+#[derive(Args, Debug)]
+pub struct PackageArgs {
+    /// A target triple to build for. Repeat it to build several. Defaults to
+    /// what prod runs on.
+    #[arg(
+        long = "target",
+        value_name = "TRIPLE",
+        default_value = "x86_64-unknown-linux-gnu"
+    )]
+    pub targets: Vec<String>,
+    /// Where to write the tarballs.
+    #[arg(long, value_name = "DIR", default_value = "target/package")]
+    pub out: PathBuf,
+    /// Cargo features to build with, comma-separated, like `sqlcipher`.
+    #[arg(long)]
+    pub features: Option<String>,
+    /// Skip `cargo build`, and bundle whatever's already in target/.
+    #[arg(long)]
+    pub no_build: bool,
+}
+
+/// Options for `db create-user`.
+#[derive(Args, Debug)]
+pub struct CreateUserArgs {
+    /// The new account's username.
+    pub username: String,
+    /// An email address for the account.
+    #[arg(long)]
+    pub email: Option<String>,
+}
+
+/// Options for `loadtest`. This talks to an instance over HTTP like any
+/// other client, so it doesn't read the config file or touch the db.
+#[derive(Args, Debug)]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub struct LoadtestArgs {
+    /// Base URL of the instance to test, like `http://localhost:3000`.
+    #[arg(long, value_name = "URL")]
+    pub target: String,
+    /// A manage_dogears API token for the account to test with.
+    #[arg(long, required_unless_present = "provision")]
+    pub token: Option<String>,
+    /// A login session ID (the `eardogger.sessid` cookie) for the same
+    /// account. Only needed if the mix includes resume.
+    #[arg(long, value_name = "SESSID")]
+    pub session: Option<String>,
+    /// Instead of --token and --session, make a fresh account to test with,
+    /// using the target's `/dev/users` endpoint. Only works on instances
+    /// built with the `dev_tools` feature and with `dev_tools` turned on.
+    #[arg(long, conflicts_with_all = ["token", "session"])]
+    pub provision: bool,
+    /// How many simulated clients to run at once.
+    #[arg(long, default_value_t = 8)]
+    pub clients: u32,
+    /// How many requests each client makes before stopping.
+    #[arg(long, default_value_t = 100)]
+    pub requests: u32,
+    /// Relative weights for each kind of request, as `op=weight` pairs.
+    /// Ops are mark, resume, and list; omitted ops get zero.
+    #[arg(long, default_value = "mark=70,list=30")]
+    pub mix: String,
+}
+
+/// Options for `init`. Anything you don't pass on the command line, we ask
+/// about (if there's a terminal to ask on) or fill in with a default.
+#[derive(Args, Debug)]
+pub struct InitArgs {
+    /// The directory to set up. The config file goes here, and the data
+    /// dir goes inside it.
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    pub dir: PathBuf,
+    /// The site's public-facing base URL, like `https://eardogger.com`.
+    #[arg(long, value_name = "URL")]
+    pub public_url: Option<String>,
+    /// Whether to serve over plain HTTP or FastCGI.
+    #[arg(long, value_enum)]
+    pub mode: Option<InitMode>,
+    /// The port to listen on, in http mode.
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Whether this is a production deployment (hides 500 error details).
+    #[arg(long, value_name = "BOOL")]
+    pub production: Option<bool>,
+    /// The directory with the static assets, absolute or relative to `--dir`.
+    #[arg(long, value_name = "DIR", default_value = "public")]
+    pub assets_dir: String,
+    /// Replace an existing config file. (Never touches an existing db or key file.)
+    #[arg(long)]
+    pub force: bool,
+    /// Don't ask questions; use defaults for anything not passed as a flag.
+    #[arg(long)]
+    pub no_input: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitMode {
+    Http,
+    Fcgi,
+}
+
+/// Where `mark` and `resume` send their requests. Each of these overrides
+/// the saved settings from `client-login`.
+#[derive(Args, Debug)]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub struct RemoteArgs {
+    /// Base URL of your instance, like `https://eardogger.com`.
+    #[arg(long, value_name = "URL")]
+    pub instance: Option<String>,
+    /// An API token for your account.
+    #[arg(long)]
+    pub token: Option<String>,
+}
+
+/// Options for `mark`.
+#[derive(Args, Debug)]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub struct MarkArgs {
+    /// The page you're on.
+    pub url: String,
+    /// A short note about where you left off.
+    #[arg(long)]
+    pub note: Option<String>,
+    /// If none of your dogears match, make a new one instead of failing.
+    #[arg(long)]
+    pub create: bool,
+    /// The prefix for a new dogear, if `--create` makes one. Defaults to
+    /// the server's suggestion.
+    #[arg(long, requires = "create")]
+    pub prefix: Option<String>,
+    #[command(flatten)]
+    pub remote: RemoteArgs,
+}
+
+/// Options for `resume`.
+#[derive(Args, Debug)]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub struct ResumeArgs {
+    /// Any page on the site you're reading.
+    pub url: String,
+    /// Open where you left off in your browser, instead of just printing it.
+    #[arg(long)]
+    pub open: bool,
+    #[command(flatten)]
+    pub remote: RemoteArgs,
+}
+
+/// Options for `client-login`.
+#[derive(Args, Debug)]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub struct ClientLoginArgs {
+    /// Base URL of your instance, like `https://eardogger.com`.
+    #[arg(long, value_name = "URL")]
+    pub instance: String,
 }
 
 pub fn cli_options() -> Options {
-    let mut config = None;
-    let mut migrate = false;
-    let mut status = false;
-    let mut version = false;
-
-    let mut state = ParserState::Scanning;
-    for arg in std::env::args() {
-        match state {
-            ParserState::Scanning => {
-                // I think the correct thing would be to do a tokenization pass
-                // first and then do an exhaustive match on token kind. But once I'm
-                // considering that, it's time to roll in clap.
-                if arg == "--migrate" {
-                    migrate = true;
-                } else if arg == "--config" {
-                    state = ParserState::ConfigVal;
-                } else if arg == "--status" || arg == "--check" {
-                    // Originally chose --status, but kept typing --check by accident lol
-                    status = true;
-                } else if arg == "--version" {
-                    version = true;
-                }
-                // otherwise ignore.
-            }
-            ParserState::ConfigVal => {
-                config = Some(PathBuf::from(arg));
-                state = ParserState::Scanning;
-            }
-        }
-    }
-    // cleanup, once all args are consumed
-    match state {
-        ParserState::Scanning => (),
-        ParserState::ConfigVal => {
-            // This runs before we have a tracing subscriber, so we have to log rudely.
-            println!("Startup: received --config without a config path; ignoring!");
-        }
-    }
-
-    Options {
-        config,
-        migrate,
-        status,
-        version,
-    }
+    Options::parse()
 }
@@ -0,0 +1,406 @@
+//! Personal exports: all of someone's dogears as JSON lines (one Dogear
+//! object per line, oldest first). The API streams these on demand, and the
+//! backup scheduler sends them off once a week to anyone who's asked, by
+//! email or webhook. The site operator's database backups are no help to
+//! someone who loses their account, so this is theirs to keep. The API can
+//! also stream them as OPML, for feed readers and outliners, but that's
+//! lossy (no ids or flags), so backups and migrations stick to JSON lines.
+
+use crate::config::{DogConfig, MailConfig};
+use crate::db::{BackupDestination, Db, Dogear, DueBackup};
+use crate::outbound::{self, validate_url, Schemes};
+use crate::util::{send_mail, Attachment, Email};
+use anyhow::{anyhow, bail};
+use axum::body::Bytes;
+use axum::BoxError;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use reqwest::header;
+use serde::Deserialize;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use tracing::{error, info};
+use url::Url;
+
+/// How many dogears to pull from the db for each chunk of an export.
+pub const EXPORT_PAGE_SIZE: u32 = 500;
+/// How many due backups to pick up per pass. The rest wait for the next one.
+const BACKUPS_PER_PASS: u32 = 50;
+/// How long a webhook gets to take a backup.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+/// What backups are called when they arrive somewhere.
+const BACKUP_FILENAME: &str = "eardogger-backup.jsonl";
+pub const EXPORT_CONTENT_TYPE: &str = "application/x-ndjson";
+pub const OPML_CONTENT_TYPE: &str = "text/x-opml";
+
+/// What shape an export comes in.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One Dogear object per line. Everything there is to know about them.
+    #[default]
+    Jsonl,
+    /// An OPML 2.0 outline, with one link outline per dogear. Its `text` is
+    /// the display name (or the prefix, if there isn't one), its `url` is
+    /// the current page, and `prefix` and `updated` (RFC 3339) ride along
+    /// as extra attributes.
+    Opml,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Jsonl => EXPORT_CONTENT_TYPE,
+            Self::Opml => OPML_CONTENT_TYPE,
+        }
+    }
+
+    pub fn filename(&self) -> &'static str {
+        match self {
+            Self::Jsonl => "eardogger-export.jsonl",
+            Self::Opml => "eardogger-export.opml",
+        }
+    }
+
+    /// Whatever goes before the first dogear.
+    fn head(&self) -> Option<Bytes> {
+        match self {
+            Self::Jsonl => None,
+            Self::Opml => Some(Bytes::from_static(
+                b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">\n  <head>\n    <title>Eardogger dogears</title>\n  </head>\n  <body>\n",
+            )),
+        }
+    }
+
+    /// Whatever goes after the last dogear.
+    fn tail(&self) -> Option<Bytes> {
+        match self {
+            Self::Jsonl => None,
+            Self::Opml => Some(Bytes::from_static(b"  </body>\n</opml>\n")),
+        }
+    }
+
+    fn write_dogear(&self, chunk: &mut Vec<u8>, dogear: &Dogear) -> Result<(), BoxError> {
+        match self {
+            Self::Jsonl => {
+                serde_json::to_writer(&mut *chunk, dogear)?;
+                chunk.push(b'\n');
+            }
+            Self::Opml => {
+                let text = dogear.display_name.as_deref().unwrap_or(&dogear.prefix);
+                let line = format!(
+                    "    <outline type=\"link\" text=\"{}\" url=\"{}\" prefix=\"{}\" updated=\"{}\"/>\n",
+                    html_escape::encode_double_quoted_attribute(text),
+                    html_escape::encode_double_quoted_attribute(&dogear.current),
+                    html_escape::encode_double_quoted_attribute(&dogear.prefix),
+                    dogear.updated.format(&Rfc3339)?,
+                );
+                chunk.extend_from_slice(line.as_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An export as a stream of chunks, given its first page (which the caller
+/// fetches, so it can still bail with a proper error if that fails). Errors
+/// after the first page can only cut the stream off early, before any
+/// closing tags.
+pub fn export_stream(
+    db: Db,
+    user_id: i64,
+    first: Vec<Dogear>,
+    format: ExportFormat,
+) -> impl Stream<Item = Result<Bytes, BoxError>> + Send + 'static {
+    let head = futures_util::stream::iter(format.head().map(Ok));
+    let tail = futures_util::stream::iter(format.tail().map(Ok));
+    let body = futures_util::stream::try_unfold(Some(first), move |page| {
+        next_export_chunk(db.clone(), user_id, page, format)
+    });
+    head.chain(body).chain(tail)
+}
+
+/// Turn one page of an export into a chunk of output, and fetch the page
+/// after it. Ends the stream on an empty page.
+async fn next_export_chunk(
+    db: Db,
+    user_id: i64,
+    page: Option<Vec<Dogear>>,
+    format: ExportFormat,
+) -> Result<Option<(Bytes, Option<Vec<Dogear>>)>, BoxError> {
+    let Some(page) = page.filter(|p| !p.is_empty()) else {
+        return Ok(None);
+    };
+    let mut chunk = Vec::new();
+    for dogear in page.iter() {
+        format.write_dogear(&mut chunk, dogear)?;
+    }
+    // A short page means that was the last one, so skip the extra query.
+    let next = if page.len() < EXPORT_PAGE_SIZE as usize {
+        None
+    } else {
+        let after_id = page[page.len() - 1].id;
+        let next = db
+            .dogears()
+            .export_page(user_id, after_id, EXPORT_PAGE_SIZE)
+            .await
+            .map_err(|e| {
+                error!("export stream died partway: {}", e);
+                e
+            })?;
+        Some(next)
+    };
+    Ok(Some((Bytes::from(chunk), next)))
+}
+
+/// A whole export in memory, and how many dogears are in it. Backups (and
+/// migrations) have to go out in one piece anyway.
+pub async fn export_all(db: &Db, user_id: i64) -> anyhow::Result<(Vec<u8>, i64)> {
+    let first = db
+        .dogears()
+        .export_page(user_id, 0, EXPORT_PAGE_SIZE)
+        .await?;
+    let mut data = Vec::new();
+    let mut chunks = std::pin::pin!(export_stream(
+        db.clone(),
+        user_id,
+        first,
+        ExportFormat::Jsonl
+    ));
+    while let Some(chunk) = chunks.try_next().await.map_err(|e| anyhow!(e))? {
+        data.extend_from_slice(&chunk);
+    }
+    let count = data.iter().filter(|&&b| b == b'\n').count() as i64;
+    Ok((data, count))
+}
+
+/// What one pass of the backup scheduler did.
+#[derive(Debug, Default, PartialEq)]
+pub struct BackupReport {
+    pub sent: u32,
+    pub failed: u32,
+}
+
+/// Sends out scheduled backups. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct BackupSender {
+    db: Db,
+    mail: Option<MailConfig>,
+    webhooks: bool,
+    production: bool,
+    public_url: Url,
+    http: reqwest::Client,
+}
+
+impl BackupSender {
+    pub fn new(db: Db, config: &DogConfig) -> reqwest::Result<Self> {
+        let http = outbound::client(WEBHOOK_TIMEOUT, 0, config.production)?;
+        Ok(Self {
+            db,
+            mail: config.mail.clone(),
+            webhooks: config.backup_webhooks,
+            production: config.production,
+            public_url: config.public_url.clone(),
+            http,
+        })
+    }
+
+    /// Send every backup that's due (up to BACKUPS_PER_PASS of them), and
+    /// record how each one went. A failed backup still counts as a run, so
+    /// it gets tried again next week instead of every pass.
+    #[tracing::instrument(skip_all)]
+    pub async fn run_due(&self) -> sqlx::Result<BackupReport> {
+        let backups = self.db.backups();
+        let mut report = BackupReport::default();
+        for due in backups.due(BACKUPS_PER_PASS).await? {
+            let (count, error) = match self.send(&due).await {
+                Ok(count) => {
+                    report.sent += 1;
+                    (count, None)
+                }
+                Err(e) => {
+                    report.failed += 1;
+                    info!(username = %due.username, "backup didn't go out: {}", e);
+                    (0, Some(e.to_string()))
+                }
+            };
+            backups
+                .record_run(due.user_id, due.destination(), count, error.as_deref())
+                .await?;
+        }
+        Ok(report)
+    }
+
+    /// Export one user's dogears and send them where they asked. Returns
+    /// how many went out.
+    async fn send(&self, due: &DueBackup) -> anyhow::Result<i64> {
+        // Check the destination before doing the export, so a backup that
+        // can't go anywhere doesn't cost anything.
+        match due.destination() {
+            BackupDestination::Email => {
+                let Some(mail) = &self.mail else {
+                    bail!("this site isn't set up to send email");
+                };
+                let Some(address) = &due.email else {
+                    bail!("there's no email address on your account");
+                };
+                let (data, count) = export_all(&self.db, due.user_id).await?;
+                let email = Email {
+                    to: address.clone(),
+                    subject: "Your weekly Eardogger backup".to_string(),
+                    body: format!(
+                        "Hi {},\n\nHere's this week's backup of your dogears at {} ({} of them), attached as JSON lines: one dogear per line.\n\n(You're getting this because you turned on scheduled backups. You can turn them off on your account page: {}account)",
+                        &due.username, &self.public_url, count, &self.public_url,
+                    ),
+                    attachment: Some(Attachment {
+                        filename: BACKUP_FILENAME.to_string(),
+                        content_type: EXPORT_CONTENT_TYPE.to_string(),
+                        data,
+                    }),
+                };
+                send_mail(mail, &email).await?;
+                Ok(count)
+            }
+            BackupDestination::Webhook => {
+                if !self.webhooks {
+                    bail!("this site doesn't allow webhook backups");
+                }
+                let Some(url) = &due.webhook_url else {
+                    bail!("there's no webhook URL on your backup schedule");
+                };
+                // The rules might have tightened since they saved it.
+                let url = validate_url(url, Schemes::HttpsInProduction, self.production)
+                    .await
+                    .map_err(|e| anyhow!(e))?;
+                let (data, count) = export_all(&self.db, due.user_id).await?;
+                let resp = self
+                    .http
+                    .post(url)
+                    .header(header::CONTENT_TYPE, EXPORT_CONTENT_TYPE)
+                    .header(
+                        header::CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"{}\"", BACKUP_FILENAME),
+                    )
+                    .body(data)
+                    .send()
+                    .await?;
+                if !resp.status().is_success() {
+                    bail!("the webhook answered {}", resp.status());
+                }
+                Ok(count)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Router};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    async fn sender_with(db: &Db, tweak: impl FnOnce(&mut DogConfig)) -> BackupSender {
+        let mut config = DogConfig::test_config().unwrap();
+        tweak(&mut config);
+        BackupSender::new(db.clone(), &config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn webhook_backups() {
+        let db = Db::new_test_db().await;
+        let user = db.test_user("whoever").await.unwrap();
+        let user_id = db.users().by_name(&user.name).await.unwrap().unwrap().id;
+
+        // A webhook that hands over whatever it gets.
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let app = Router::new().route(
+            "/hook",
+            post(move |body: String| async move {
+                tx.send(body).unwrap();
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hook = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        db.backups()
+            .set(user_id, BackupDestination::Webhook, Some(&hook))
+            .await
+            .unwrap();
+
+        // Webhooks off: it fails, and says so.
+        let sender = sender_with(&db, |_| {}).await;
+        let report = sender.run_due().await.unwrap();
+        assert_eq!(report, BackupReport { sent: 0, failed: 1 });
+        let runs = db.backups().runs(user_id).await.unwrap();
+        assert_eq!(runs.len(), 1);
+        assert!(runs[0].error.as_ref().unwrap().contains("webhook"));
+        // ...and isn't due again right away.
+        assert_eq!(sender.run_due().await.unwrap(), BackupReport::default());
+
+        // Webhooks on: the whole export arrives.
+        // (Turn the clock back so it's due again.)
+        let sender = sender_with(&db, |c| c.backup_webhooks = true).await;
+        sqlx::query!(
+            r#"
+                UPDATE backup_schedules SET last_run = NULL;
+            "#
+        )
+        .execute(&db.write_pool)
+        .await
+        .unwrap();
+        let report = sender.run_due().await.unwrap();
+        assert_eq!(report, BackupReport { sent: 1, failed: 0 });
+        let body = rx.recv().await.unwrap();
+        // The test user has two dogears.
+        assert_eq!(body.lines().count(), 2);
+        for line in body.lines() {
+            let dogear: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(dogear["user_id"], user_id);
+        }
+        let runs = db.backups().runs(user_id).await.unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].dogears, 2);
+        assert_eq!(runs[0].error, None);
+    }
+
+    /// A fake sendmail that saves whatever it gets.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn email_backups() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let db = Db::new_test_db().await;
+        let user = db.test_user("whoever").await.unwrap();
+        let user_id = db.users().by_name(&user.name).await.unwrap().unwrap().id;
+        db.backups()
+            .set(user_id, BackupDestination::Email, None)
+            .await
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("sendmail");
+        let outbox = dir.path().join("outbox.eml");
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\ncat > {:?}\n", outbox.to_str().unwrap()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let sender = sender_with(&db, |c| {
+            c.mail = Some(MailConfig {
+                sendmail: script,
+                from: "dogs@example.com".to_string(),
+                check_mx: false,
+            })
+        })
+        .await;
+
+        let report = sender.run_due().await.unwrap();
+        assert_eq!(report, BackupReport { sent: 1, failed: 0 });
+        let sent = std::fs::read_to_string(&outbox).unwrap();
+        assert!(sent.contains("To: whoever@example.com\n"));
+        assert!(sent.contains(&format!("filename=\"{}\"", BACKUP_FILENAME)));
+        assert_eq!(db.backups().runs(user_id).await.unwrap()[0].dogears, 2);
+    }
+}
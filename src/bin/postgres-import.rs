@@ -176,10 +176,11 @@ impl V1User {
     where
         E: Executor<'a, Database = Sqlite>,
     {
+        // v1 usernames were all ASCII, so their key is just lower().
         query_scalar::<_, i64>(
             r#"
-                INSERT INTO users (username, password_hash, email, created)
-                VALUES (?1, ?2, ?3, ?4)
+                INSERT INTO users (username, password_hash, email, created, username_key)
+                VALUES (?1, ?2, ?3, ?4, lower(?1))
                 ON CONFLICT(username) DO UPDATE
                     SET password_hash = ?2, email = ?3, created = ?4
                 RETURNING id;
@@ -0,0 +1,274 @@
+//! Guessing how often a dogear's serial updates, from when it gets marked.
+//! Someone who's caught up on a serial marks it about as often as new
+//! chapters come out, so the typical gap between their reading sittings is
+//! a decent stand-in for its schedule, and a dogear that's gone longer than
+//! that without a mark probably has something new waiting. It's only ever
+//! a guess (it can't tell a weekly comic from a weekly reading habit), so
+//! the UI words it that way.
+
+use crate::db::{ChangeListener, Db, Dogear};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use time::{Duration, OffsetDateTime};
+
+/// How many recent marks per dogear to base a guess on. Enough to ride
+/// out a few odd weeks, few enough to notice a schedule change.
+pub const MARKS_PER_DOGEAR: u32 = 30;
+/// Marks closer together than this are one reading sitting (like working
+/// through a backlog), not separate updates.
+const SITTING: Duration = Duration::hours(6);
+/// The fewest gaps between sittings it takes to make a guess.
+const MIN_GAPS: usize = 3;
+/// Past this many intervals without a mark, it's more likely you stopped
+/// reading than that it's waiting for you, so it stops counting as
+/// probably new.
+const GIVE_UP_INTERVALS: i32 = 4;
+/// Once the cache has this many users in it, start over.
+const CACHE_MAX_USERS: usize = 10_000;
+
+/// A guess at one dogear's update schedule.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cadence {
+    /// The typical (median) time between reading sittings.
+    pub interval: Duration,
+    /// The start of the latest sitting.
+    pub last_marked: OffsetDateTime,
+}
+
+impl Cadence {
+    /// Make a guess from a dogear's mark times, oldest first. None if
+    /// there aren't enough sittings to go on.
+    pub fn from_marks(marks: &[OffsetDateTime]) -> Option<Self> {
+        let mut sittings: Vec<OffsetDateTime> = Vec::new();
+        for &mark in marks {
+            match sittings.last() {
+                Some(&start) if mark - start < SITTING => {}
+                _ => sittings.push(mark),
+            }
+        }
+        if sittings.len() < MIN_GAPS + 1 {
+            return None;
+        }
+        let mut gaps: Vec<Duration> = sittings.windows(2).map(|w| w[1] - w[0]).collect();
+        gaps.sort();
+        let middle = gaps.len() / 2;
+        let interval = if gaps.len() % 2 == 0 {
+            (gaps[middle - 1] + gaps[middle]) / 2
+        } else {
+            gaps[middle]
+        };
+        Some(Self {
+            interval,
+            last_marked: *sittings.last()?,
+        })
+    }
+
+    /// When the next update's due, if the guess is right.
+    pub fn expected_next(&self) -> OffsetDateTime {
+        self.last_marked + self.interval
+    }
+
+    /// Whether it's been long enough that there's probably a new chapter,
+    /// but not so long that you've probably stopped reading.
+    pub fn probably_new(&self, now: OffsetDateTime) -> bool {
+        let since = now - self.last_marked;
+        since >= self.interval && since < self.interval * GIVE_UP_INTERVALS
+    }
+
+    /// The interval in words, like "~5 days" or "~2 weeks".
+    pub fn describe(&self) -> String {
+        let hours = (self.interval.as_seconds_f64() / 3600.0).round().max(1.0) as i64;
+        let days = (self.interval.as_seconds_f64() / 86400.0).round() as i64;
+        let (count, unit) = if hours < 24 {
+            (hours, "hour")
+        } else if days < 14 {
+            (days, "day")
+        } else if days < 60 {
+            ((days as f64 / 7.0).round() as i64, "week")
+        } else {
+            ((days as f64 / 30.0).round() as i64, "month")
+        };
+        if count == 1 {
+            format!("~1 {}", unit)
+        } else {
+            format!("~{} {}s", count, unit)
+        }
+    }
+}
+
+/// Guesses for each of a user's dogears that has enough history, by ID.
+pub async fn cadences_for(db: &Db, user_id: i64) -> sqlx::Result<HashMap<i64, Cadence>> {
+    let times = db.dogears().mark_times(user_id, MARKS_PER_DOGEAR).await?;
+    Ok(times
+        .into_iter()
+        .filter_map(|(id, marks)| Cadence::from_marks(&marks).map(|c| (id, c)))
+        .collect())
+}
+
+/// A user's dogears that have guesses, paired with them, soonest-due (or
+/// longest-overdue) first. Archived ones are left out.
+pub async fn guessed_dogears(
+    db: &Db,
+    cadences: &HashMap<i64, Cadence>,
+    user_id: i64,
+) -> sqlx::Result<Vec<(Dogear, Cadence)>> {
+    let mut found = Vec::new();
+    let mut after_id = 0;
+    loop {
+        let page = db.dogears().export_page(user_id, after_id, 500).await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after_id = last.id;
+        for dogear in page.into_iter().filter(|d| !d.archived) {
+            if let Some(&cadence) = cadences.get(&dogear.id) {
+                found.push((dogear, cadence));
+            }
+        }
+    }
+    found.sort_by_key(|(_, cadence)| cadence.expected_next());
+    Ok(found)
+}
+
+/// Each user's guesses, kept until their dogears move. They only change
+/// when someone marks something, so there's no expiry; a change notice
+/// throws the user's entry out.
+#[derive(Clone, Debug)]
+pub struct CadenceCache {
+    entries: Arc<Mutex<CacheEntries>>,
+}
+
+#[derive(Debug)]
+struct CacheEntries {
+    map: HashMap<i64, Arc<HashMap<i64, Cadence>>>,
+    changes: ChangeListener,
+    /// Same deal as the index cache's: goes up with every invalidation, so
+    /// a read that raced a write doesn't get kept.
+    epoch: u64,
+}
+
+impl CacheEntries {
+    fn catch_up(&mut self) {
+        match self.changes.drain() {
+            Some(moved) if moved.is_empty() => {}
+            Some(moved) => {
+                for user_id in moved {
+                    self.map.remove(&user_id);
+                }
+                self.epoch += 1;
+            }
+            None => {
+                self.map.clear();
+                self.epoch += 1;
+            }
+        }
+    }
+}
+
+impl CadenceCache {
+    pub fn new(db: &Db) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(CacheEntries {
+                map: HashMap::new(),
+                changes: db.changes().subscribe(),
+                epoch: 0,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheEntries> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A user's guesses, from the cache if nothing's moved since.
+    pub async fn get(&self, db: &Db, user_id: i64) -> sqlx::Result<Arc<HashMap<i64, Cadence>>> {
+        let epoch = {
+            let mut entries = self.lock();
+            entries.catch_up();
+            if let Some(cadences) = entries.map.get(&user_id) {
+                return Ok(cadences.clone());
+            }
+            entries.epoch
+        };
+        let cadences = Arc::new(cadences_for(db, user_id).await?);
+        let mut entries = self.lock();
+        entries.catch_up();
+        if entries.epoch == epoch {
+            if entries.map.len() >= CACHE_MAX_USERS {
+                entries.map.clear();
+            }
+            entries.map.insert(user_id, cadences.clone());
+        }
+        Ok(cadences)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marks(start: OffsetDateTime, offsets_hours: &[i64]) -> Vec<OffsetDateTime> {
+        offsets_hours
+            .iter()
+            .map(|&h| start + Duration::hours(h))
+            .collect()
+    }
+
+    #[test]
+    fn guessing() {
+        let start = OffsetDateTime::UNIX_EPOCH;
+        // Not enough to go on.
+        assert!(Cadence::from_marks(&[]).is_none());
+        assert!(Cadence::from_marks(&marks(start, &[0, 120, 240])).is_none());
+
+        // Every five days, with a binge that counts as one sitting, and
+        // one late week that the median shrugs off.
+        let cadence =
+            Cadence::from_marks(&marks(start, &[0, 1, 2, 3, 120, 240, 360, 600, 720])).unwrap();
+        assert_eq!(cadence.interval, Duration::days(5));
+        assert_eq!(cadence.last_marked, start + Duration::hours(720));
+        assert_eq!(cadence.describe(), "~5 days");
+        assert_eq!(cadence.expected_next(), start + Duration::hours(840));
+
+        // Probably new once the interval's up, until it's been ages.
+        assert!(!cadence.probably_new(start + Duration::hours(800)));
+        assert!(cadence.probably_new(start + Duration::hours(850)));
+        assert!(!cadence.probably_new(start + Duration::hours(720 + 4 * 120)));
+    }
+
+    #[test]
+    fn describing() {
+        let describe = |interval| {
+            Cadence {
+                interval,
+                last_marked: OffsetDateTime::UNIX_EPOCH,
+            }
+            .describe()
+        };
+        assert_eq!(describe(Duration::hours(8)), "~8 hours");
+        assert_eq!(describe(Duration::hours(23)), "~23 hours");
+        assert_eq!(describe(Duration::hours(26)), "~1 day");
+        assert_eq!(describe(Duration::days(7)), "~7 days");
+        assert_eq!(describe(Duration::days(14)), "~2 weeks");
+        assert_eq!(describe(Duration::days(90)), "~3 months");
+    }
+
+    #[tokio::test]
+    async fn cache_follows_marks() {
+        let db = Db::new_test_db().await;
+        let user = db.test_user("whoever").await.unwrap();
+        let cache = CadenceCache::new(&db);
+
+        let first = cache.get(&db, user.id).await.unwrap();
+        assert!(first.is_empty());
+        assert!(Arc::ptr_eq(&first, &cache.get(&db, user.id).await.unwrap()));
+
+        // A mark moves the dogear, so the user's entry gets thrown out.
+        db.dogears()
+            .update(user.id, "https://example.com/comic/25", None)
+            .await
+            .unwrap();
+        let second = cache.get(&db, user.id).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+    }
+}
@@ -0,0 +1,191 @@
+//! Database encryption at rest, via SQLCipher. With the `sqlcipher` feature,
+//! sqlx's sqlite gets swapped for a SQLCipher build, and a configured key
+//! turns into a `PRAGMA key` on every new connection. Without a key (or
+//! without the feature), the db is plain sqlite like always.
+//!
+//! SQLCipher can't encrypt a db in place, so `db encrypt` writes an
+//! encrypted copy for the operator to swap in while the server's stopped.
+
+use crate::config::DogConfig;
+use anyhow::{anyhow, bail};
+use sqlx::{sqlite::SqliteConnectOptions, Connection, SqliteConnection, SqliteExecutor};
+use std::{env::VarError, path::Path};
+
+/// The env var that beats `db_key_file`, for hosts where env is easier to
+/// keep secret than a file.
+pub const DB_KEY_ENV: &str = "EARDOGGER_DB_KEY";
+
+/// sqlite's "file is not a database" error code, which is what you get for
+/// a wrong key, a missing key, or a key for a db that isn't encrypted.
+const SQLITE_NOTADB: &str = "26";
+
+/// A SQLCipher passphrase. Debug-prints as a placeholder, so it can't leak
+/// into logs.
+#[derive(Clone)]
+pub struct DbKey(String);
+
+impl std::fmt::Debug for DbKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DbKey(<redacted>)")
+    }
+}
+
+impl DbKey {
+    /// The configured key, if any: the env var if it's set, or else the
+    /// contents of `db_key_file`. It's an error to have one in a build
+    /// that can't use it, since quietly ignoring it would leave the db in
+    /// plaintext.
+    pub fn from_config(config: &DogConfig) -> anyhow::Result<Option<Self>> {
+        let key = match std::env::var(DB_KEY_ENV) {
+            Ok(key) => key,
+            Err(VarError::NotPresent) => match &config.db_key_file {
+                Some(path) => std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("couldn't read db_key_file {:?}: {}", path, e))?,
+                None => return Ok(None),
+            },
+            Err(e) => bail!("{} isn't usable: {}", DB_KEY_ENV, e),
+        };
+        if cfg!(not(feature = "sqlcipher")) {
+            bail!(
+                "there's a database key configured, but this build has no SQLCipher; rebuild with `--features sqlcipher`."
+            );
+        }
+        Self::new(key).map(Some)
+    }
+
+    /// Trailing newlines come along for free with most editors, so they
+    /// don't count; anything else does.
+    fn new(key: String) -> anyhow::Result<Self> {
+        let key = key.trim_end_matches(['\r', '\n']);
+        if key.is_empty() {
+            bail!("the database key is empty.");
+        }
+        Ok(Self(key.to_string()))
+    }
+
+    /// The key as a quoted SQL string, for `PRAGMA key`. sqlx pastes pragma
+    /// values straight into the statement, so the quoting is on us.
+    fn sql_literal(&self) -> String {
+        format!("'{}'", self.0.replace('\'', "''"))
+    }
+
+    /// Set the key on some connection options. sqlx always sends the key
+    /// pragma first, which SQLCipher requires.
+    pub fn apply(&self, opts: SqliteConnectOptions) -> SqliteConnectOptions {
+        opts.pragma("key", self.sql_literal())
+    }
+}
+
+/// Make the "file is not a database" error say something useful, since
+/// it's what a bad or missing key looks like.
+pub fn explain(err: sqlx::Error, keyed: bool) -> anyhow::Error {
+    let notadb =
+        matches!(&err, sqlx::Error::Database(e) if e.code().as_deref() == Some(SQLITE_NOTADB));
+    if !notadb {
+        return err.into();
+    }
+    if keyed {
+        anyhow!(
+            "couldn't open the database with the configured key ({}). Either the key is wrong, or the db isn't encrypted yet; see `eardogger-rs db encrypt`.",
+            err
+        )
+    } else {
+        anyhow!(
+            "couldn't open the database ({}). If it's encrypted, set db_key_file in the config or {} in the environment.",
+            err,
+            DB_KEY_ENV
+        )
+    }
+}
+
+/// Make sure the sqlite we're linked against is really SQLCipher. Plain
+/// sqlite ignores `PRAGMA key` without a peep, which would be a nasty way
+/// to find out the feature didn't take.
+pub async fn require_cipher<'e>(conn: impl SqliteExecutor<'e>) -> anyhow::Result<()> {
+    let version: Option<String> = sqlx::query_scalar("PRAGMA cipher_version;")
+        .fetch_optional(conn)
+        .await?;
+    match version {
+        Some(_) => Ok(()),
+        None => bail!("this build's sqlite isn't SQLCipher, so it can't use a database key."),
+    }
+}
+
+/// Write an encrypted copy of a plaintext db to a new file. The original
+/// stays as it was. Stop the server before swapping the copy in, or any
+/// writes in between get lost.
+pub async fn encrypt(db_file: &Path, out_file: &Path, key: &DbKey) -> anyhow::Result<()> {
+    if out_file.exists() {
+        bail!(
+            "{:?} already exists, and db encrypt won't overwrite anything.",
+            out_file
+        );
+    }
+    let opts = SqliteConnectOptions::new().filename(db_file);
+    let mut conn = SqliteConnection::connect_with(&opts)
+        .await
+        .map_err(|e| explain(e, false))?;
+    require_cipher(&mut conn).await?;
+    sqlx::query("ATTACH DATABASE ?1 AS encrypted KEY ?2;")
+        .bind(out_file.to_string_lossy().into_owned())
+        .bind(&key.0)
+        .execute(&mut conn)
+        .await?;
+    sqlx::query("SELECT sqlcipher_export('encrypted');")
+        .execute(&mut conn)
+        .await?;
+    sqlx::query("DETACH DATABASE encrypted;")
+        .execute(&mut conn)
+        .await?;
+    conn.close().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        let key = DbKey::new("it's a secret\n".to_string()).unwrap();
+        assert_eq!(key.sql_literal(), "'it''s a secret'");
+        assert_eq!(format!("{:?}", key), "DbKey(<redacted>)");
+        assert!(DbKey::new("\r\n".to_string()).is_err());
+    }
+
+    /// The real round trip needs the real SQLCipher.
+    #[cfg(feature = "sqlcipher")]
+    #[tokio::test]
+    async fn encrypt_and_reopen() {
+        use crate::db::Db;
+        use tokio_util::task::TaskTracker;
+
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("plain.db");
+        let secret = dir.path().join("secret.db");
+        std::fs::File::create(&plain).unwrap();
+        let pool = crate::db_pool(&plain, 1, None).await.unwrap();
+        let db = Db::new(pool.clone(), pool, TaskTracker::new());
+        db.migrations().run().await.unwrap();
+        db.users().create("encrypted", "pass", None).await.unwrap();
+        db.close().await;
+
+        let key = DbKey::new("correct horse".to_string()).unwrap();
+        encrypt(&plain, &secret, &key).await.unwrap();
+        // No clobbering.
+        assert!(encrypt(&plain, &secret, &key).await.is_err());
+
+        // Right key: everything's there.
+        let pool = crate::db_pool(&secret, 1, Some(&key)).await.unwrap();
+        let db = Db::new(pool.clone(), pool, TaskTracker::new());
+        assert!(db.users().by_name("encrypted").await.unwrap().is_some());
+        db.close().await;
+
+        // Wrong key, or none: a useful error.
+        let wrong = DbKey::new("battery staple".to_string()).unwrap();
+        let err = crate::db_pool(&secret, 1, Some(&wrong)).await.unwrap_err();
+        assert!(err.to_string().contains("Either the key is wrong"));
+        let err = crate::db_pool(&secret, 1, None).await.unwrap_err();
+        assert!(err.to_string().contains(DB_KEY_ENV));
+    }
+}
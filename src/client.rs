@@ -0,0 +1,390 @@
+//! A typed async client for Eardogger's JSON API, built on reqwest. Requires
+//! the `client` feature. It speaks in the same [crate::api_types] that the
+//! server's route handlers use, so a script using this can't get out of sync
+//! with the payloads the server actually expects.
+//!
+//! Authentication is always by API token (`Authorization: Bearer ...`), same
+//! as the bookmarklets. Keep in mind that the list and delete methods need a
+//! `manage_dogears` token; a `write_dogears` token can only create and update.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), eardogger_rs::client::ClientError> {
+//! use eardogger_rs::client::Client;
+//!
+//! let client = Client::new("https://eardogger.com", "eardoggerv1.blahblahblah")?;
+//! let list = client.list(1, 50).await?;
+//! for dogear in list.data {
+//!     println!("{}: {}", dogear.prefix, dogear.current);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::api_types::{
+    ApiChangelog, ApiCreatePayload, ApiDogearsList, ApiEditPayload, ApiFeedPayload, ApiHook,
+    ApiPrefixSuggestion, ApiRotatedToken, ApiUpdatePayload, ApiWaitResult, Dogear, DogearFeed,
+    DogearHistoryEntry, InstanceMetadata, RawJsonError,
+};
+use reqwest::{header, Method, RequestBuilder, Response, StatusCode};
+use thiserror::Error;
+use time::{format_description::well_known::Iso8601, OffsetDateTime};
+use url::Url;
+
+/// Everything that can go wrong while talking to the API.
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Couldn't build an API URL: {0}")]
+    Url(#[from] url::ParseError),
+
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Couldn't format a timestamp: {0}")]
+    Time(#[from] time::error::Format),
+
+    /// The server answered, but with an error status. The message is the
+    /// `error` field from the JSON error body if there was one (plus its
+    /// request ID, for quoting back to the site's operator), or the raw
+    /// response text if not.
+    #[error("API error ({status}): {message}")]
+    Api { status: StatusCode, message: String },
+}
+
+impl ClientError {
+    /// The HTTP status the server returned, if it got that far.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Api { status, .. } => Some(*status),
+            Self::Http(e) => e.status(),
+            Self::Url(_) | Self::Time(_) => None,
+        }
+    }
+}
+
+/// An API client for one user on one Eardogger instance. Cheap to clone,
+/// since the underlying reqwest client is reference-counted.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+    token: String,
+}
+
+impl Client {
+    /// Make a client for the instance at `base_url` (like
+    /// `https://eardogger.com`), authenticating with the provided token
+    /// cleartext.
+    pub fn new(base_url: &str, token: &str) -> Result<Self, ClientError> {
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("eardogger-rs-client/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Self::with_http_client(http, base_url, token)
+    }
+
+    /// Like [Client::new], but bring your own reqwest client (for custom
+    /// timeouts or whatever).
+    pub fn with_http_client(
+        http: reqwest::Client,
+        base_url: &str,
+        token: &str,
+    ) -> Result<Self, ClientError> {
+        let mut base_url = Url::parse(base_url)?;
+        // Url::join treats a path without a trailing slash as a file and
+        // replaces its last segment, which would be bad news for instances
+        // mounted under a subdirectory. So force it to be a directory.
+        if !base_url.path().ends_with('/') {
+            let dir_path = format!("{}/", base_url.path());
+            base_url.set_path(&dir_path);
+        }
+        Ok(Self {
+            http,
+            base_url,
+            token: token.to_string(),
+        })
+    }
+
+    /// The instance's base URL, normalized to end with a slash.
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    // Takes a relative path like "api/v1/list" (no leading slash).
+    fn endpoint(&self, path: &str) -> Result<Url, ClientError> {
+        Ok(self.base_url.join(path)?)
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        self.http
+            .request(method, url)
+            .bearer_auth(&self.token)
+            .header(header::ACCEPT, "application/json")
+    }
+
+    /// `GET /.well-known/eardogger.json`: what the instance is and what it
+    /// supports. Doesn't need a valid token, so it's handy for checking an
+    /// instance out before asking someone for one.
+    pub async fn instance_metadata(&self) -> Result<InstanceMetadata, ClientError> {
+        let url = self.endpoint(".well-known/eardogger.json")?;
+        let resp = self
+            .http
+            .get(url)
+            .header(header::ACCEPT, "application/json")
+            .send()
+            .await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `GET /api/changelog`: what's changed in the API, and which endpoints
+    /// are deprecated. Doesn't need a valid token either.
+    pub async fn changelog(&self) -> Result<ApiChangelog, ClientError> {
+        let url = self.endpoint("api/changelog")?;
+        let resp = self
+            .http
+            .get(url)
+            .header(header::ACCEPT, "application/json")
+            .send()
+            .await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `GET /api/v1/list`: one page of dogears, most recently updated first.
+    /// Needs a manage token.
+    pub async fn list(&self, page: u32, size: u32) -> Result<ApiDogearsList, ClientError> {
+        let url = self.endpoint("api/v1/list")?;
+        let resp = self
+            .request(Method::GET, url)
+            .query(&[("page", page), ("size", size)])
+            .send()
+            .await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `GET /api/v1/dogear/:id`: one dogear. Needs a manage token.
+    pub async fn get(&self, id: i64) -> Result<Dogear, ClientError> {
+        let url = self.endpoint(&format!("api/v1/dogear/{}", id))?;
+        let resp = self.request(Method::GET, url).send().await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `POST /api/v1/create`: make a new dogear.
+    pub async fn create(&self, payload: &ApiCreatePayload) -> Result<Dogear, ClientError> {
+        let url = self.endpoint("api/v1/create")?;
+        let resp = self.request(Method::POST, url).json(payload).send().await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `PATCH /api/v1/dogear/:id`: change a dogear's prefix and/or display
+    /// name. Needs a manage token.
+    pub async fn edit(&self, id: i64, payload: &ApiEditPayload) -> Result<Dogear, ClientError> {
+        let url = self.endpoint(&format!("api/v1/dogear/{}", id))?;
+        let resp = self
+            .request(Method::PATCH, url)
+            .json(payload)
+            .send()
+            .await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `GET /api/v1/suggest`: the server's guess at a good prefix for a new
+    /// dogear on this page.
+    pub async fn suggest_prefix(&self, page_url: &str) -> Result<String, ClientError> {
+        let url = self.endpoint("api/v1/suggest")?;
+        let resp = self
+            .request(Method::GET, url)
+            .query(&[("url", page_url)])
+            .send()
+            .await?;
+        let suggestion: ApiPrefixSuggestion = check_status(resp).await?.json().await?;
+        Ok(suggestion.prefix)
+    }
+
+    /// `POST /api/v1/update`: mark your spot at a new URL. Returns every
+    /// dogear that matched, and a 404 error if none did. Paused dogears are
+    /// included but not moved; check their `paused` field.
+    pub async fn update(&self, current: &str) -> Result<Vec<Dogear>, ClientError> {
+        self.update_with_note(current, None).await
+    }
+
+    /// Like [Client::update], but with a note to save in the history of
+    /// each dogear that moves.
+    pub async fn update_with_note(
+        &self,
+        current: &str,
+        note: Option<&str>,
+    ) -> Result<Vec<Dogear>, ClientError> {
+        let url = self.endpoint("api/v1/update")?;
+        let payload = ApiUpdatePayload {
+            current: current.to_string(),
+            note: note.map(String::from),
+        };
+        let resp = self
+            .request(Method::POST, url)
+            .json(&payload)
+            .send()
+            .await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `GET /api/v1/wait_for_update`: wait (up to `timeout_secs`, which the
+    /// server caps at under a minute) for any dogear to move after `since`.
+    /// Feed the result's `next_since` into the next call. Needs a manage
+    /// token. Note that reqwest's default client has no timeout, but if you
+    /// set one, make it longer than the wait.
+    pub async fn wait_for_update(
+        &self,
+        since: OffsetDateTime,
+        timeout_secs: u64,
+    ) -> Result<ApiWaitResult, ClientError> {
+        let url = self.endpoint("api/v1/wait_for_update")?;
+        let since = since.format(&Iso8601::DEFAULT)?;
+        let resp = self
+            .request(Method::GET, url)
+            .query(&[("since", since), ("timeout", timeout_secs.to_string())])
+            .send()
+            .await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `POST /api/v1/dogear/:id/pause` or `.../unpause`: stop or restart
+    /// updates for a dogear. Needs a manage token.
+    pub async fn set_paused(&self, id: i64, paused: bool) -> Result<Dogear, ClientError> {
+        let action = if paused { "pause" } else { "unpause" };
+        let url = self.endpoint(&format!("api/v1/dogear/{}/{}", id, action))?;
+        let resp = self.request(Method::POST, url).send().await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `POST /api/v1/dogear/:id/publish` or `.../unpublish`: show or hide a
+    /// dogear on your public profile. Needs a manage token.
+    pub async fn set_public(&self, id: i64, public: bool) -> Result<Dogear, ClientError> {
+        let action = if public { "publish" } else { "unpublish" };
+        let url = self.endpoint(&format!("api/v1/dogear/{}/{}", id, action))?;
+        let resp = self.request(Method::POST, url).send().await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `POST /api/v1/dogear/:id/archive` or `.../unarchive`: move a dogear
+    /// off your main list, or bring it back. Needs a manage token.
+    pub async fn set_archived(&self, id: i64, archived: bool) -> Result<Dogear, ClientError> {
+        let action = if archived { "archive" } else { "unarchive" };
+        let url = self.endpoint(&format!("api/v1/dogear/{}/{}", id, action))?;
+        let resp = self.request(Method::POST, url).send().await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `POST /api/v1/dogear/:id/hook`: give a dogear an inbound webhook, or
+    /// a fresh URL for the one it has (the old one stops working). The URL
+    /// is only available this once. Needs a manage token.
+    pub async fn create_hook(&self, id: i64) -> Result<ApiHook, ClientError> {
+        let url = self.endpoint(&format!("api/v1/dogear/{}/hook", id))?;
+        let resp = self.request(Method::POST, url).send().await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `DELETE /api/v1/dogear/:id/hook`: turn off a dogear's webhook.
+    /// Needs a manage token.
+    pub async fn delete_hook(&self, id: i64) -> Result<(), ClientError> {
+        let url = self.endpoint(&format!("api/v1/dogear/{}/hook", id))?;
+        let resp = self.request(Method::DELETE, url).send().await?;
+        check_status(resp).await?;
+        Ok(())
+    }
+
+    /// `PUT /api/v1/dogear/:id/feed`: have the site watch a feed for a
+    /// dogear's new chapters, moving it along on its own if `auto_advance`.
+    /// Needs a manage token, and a site with feed polling turned on.
+    pub async fn set_feed(
+        &self,
+        id: i64,
+        feed_url: &str,
+        auto_advance: bool,
+    ) -> Result<DogearFeed, ClientError> {
+        let url = self.endpoint(&format!("api/v1/dogear/{}/feed", id))?;
+        let payload = ApiFeedPayload {
+            feed_url: feed_url.to_string(),
+            auto_advance,
+        };
+        let resp = self.request(Method::PUT, url).json(&payload).send().await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `DELETE /api/v1/dogear/:id/feed`: stop watching a dogear's feed.
+    /// Needs a manage token.
+    pub async fn clear_feed(&self, id: i64) -> Result<(), ClientError> {
+        let url = self.endpoint(&format!("api/v1/dogear/{}/feed", id))?;
+        let resp = self.request(Method::DELETE, url).send().await?;
+        check_status(resp).await?;
+        Ok(())
+    }
+
+    /// `GET /api/v1/dogear/:id/history`: where a dogear has been, newest
+    /// first. Needs a manage token.
+    pub async fn history(&self, id: i64) -> Result<Vec<DogearHistoryEntry>, ClientError> {
+        let url = self.endpoint(&format!("api/v1/dogear/{}/history", id))?;
+        let resp = self.request(Method::GET, url).send().await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+
+    /// `DELETE /api/v1/dogear/:id`. Needs a manage token.
+    pub async fn delete(&self, id: i64) -> Result<(), ClientError> {
+        let url = self.endpoint(&format!("api/v1/dogear/{}", id))?;
+        let resp = self.request(Method::DELETE, url).send().await?;
+        check_status(resp).await?;
+        Ok(())
+    }
+
+    /// `POST /api/v1/tokens/rotate`: trade this client's token for a fresh
+    /// one with the same scope and comment. The old token dies immediately,
+    /// so this client is useless afterwards; make a new one with the
+    /// returned cleartext.
+    pub async fn rotate_token(&self) -> Result<ApiRotatedToken, ClientError> {
+        let url = self.endpoint("api/v1/tokens/rotate")?;
+        let resp = self.request(Method::POST, url).send().await?;
+        Ok(check_status(resp).await?.json().await?)
+    }
+}
+
+/// Pass successful responses through, and turn the rest into ClientError::Api
+/// with whatever message the server sent.
+async fn check_status(resp: Response) -> Result<Response, ClientError> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let text = resp.text().await.unwrap_or_default();
+    let message = match serde_json::from_str::<RawJsonError>(&text) {
+        Ok(RawJsonError {
+            error,
+            request_id: Some(id),
+        }) => format!("{} (request ID {})", error, id),
+        Ok(body) => body.error.into_owned(),
+        Err(_) => text,
+    };
+    Err(ClientError::Api { status, message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_urls() {
+        // Plain origin
+        let client = Client::new("https://eardogger.com", "token").unwrap();
+        assert_eq!(
+            client.endpoint("api/v1/list").unwrap().as_str(),
+            "https://eardogger.com/api/v1/list"
+        );
+        // Mounted in a subdirectory, with or without trailing slash
+        for base in ["https://example.com/dogs", "https://example.com/dogs/"] {
+            let client = Client::new(base, "token").unwrap();
+            assert_eq!(client.base_url().as_str(), "https://example.com/dogs/");
+            assert_eq!(
+                client.endpoint("api/v1/dogear/5").unwrap().as_str(),
+                "https://example.com/dogs/api/v1/dogear/5"
+            );
+        }
+        // Garbage
+        assert!(Client::new("not a url", "token").is_err());
+    }
+}
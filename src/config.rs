@@ -1,10 +1,15 @@
+use crate::util::{IpRange, RateLimitAllowlist};
 use serde::Deserialize;
 use std::{
+    collections::BTreeMap,
     num::NonZeroUsize,
     path::{Path, PathBuf},
     sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, RwLock},
 };
 use thiserror::Error;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing_subscriber::EnvFilter;
 use url::Url;
 
 static IS_PRODUCTION: AtomicBool = AtomicBool::new(false);
@@ -23,8 +28,44 @@ pub enum ConfError {
     // The generated code for returning an error is cheaper than maybe panicking.
     #[error("a prior check guaranteed that this error would never happen.")]
     Impossible,
+    /// Everything wrong with an otherwise parseable config file, so you can
+    /// fix it all in one go instead of playing whack-a-mole with restarts.
+    #[error("found {} problem(s) in the config file:\n{}", .0.len(), bullet_list(.0))]
+    Invalid(Vec<String>),
 }
 
+/// Feature flag names go in URLs and template checks, so keep them tame.
+pub fn is_flag_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn bullet_list(items: &[String]) -> String {
+    items
+        .iter()
+        .map(|item| format!("  - {}", item))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Thread counts above this are almost certainly a typo.
+const MAX_THREADS: usize = 256;
+
+/// Anything shorter than this isn't much of a secret.
+const MIN_ADMIN_TOKEN_LEN: usize = 32;
+/// A century is plenty long for a retention limit; past that, just leave
+/// it out.
+pub const MAX_RETENTION_MONTHS: u32 = 1200;
+/// The index page cache is for shaving off a few queries, not for showing
+/// people where they were five minutes ago.
+const MAX_INDEX_CACHE_SECS: u64 = 300;
+/// Nobody's form-filling is slow enough to need a longer signup time trap.
+const MAX_SIGNUP_MIN_SECONDS: u64 = 60;
+/// Past this, proof-of-work signups take minutes on a phone.
+const MAX_POW_DIFFICULTY: u8 = 24;
+
 /// Settings for running the app server.
 #[derive(Debug, Deserialize, Clone)]
 pub enum ServeMode {
@@ -57,6 +98,254 @@ pub struct LogFileConfig {
     pub days: usize,
 }
 
+/// Settings for sending email. We hand messages to a sendmail-compatible
+/// program rather than speaking SMTP ourselves.
+#[derive(Debug, Deserialize, Clone)]
+pub struct MailConfig {
+    /// The program to pipe messages to. It gets called like `sendmail -t -i`.
+    pub sendmail: PathBuf,
+    /// The From: address for outgoing mail.
+    pub from: String,
+    /// Whether to look up new email addresses' domains in DNS, and turn
+    /// away ones where nothing takes mail. Off by default.
+    #[serde(default)]
+    pub check_mx: bool,
+}
+
+/// Settings for the operator routes that export and import site rules
+/// bundles. Without these, those routes don't exist.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SiteRulesConfig {
+    /// The bearer token for /admin/site_rules. A long random secret.
+    pub admin_token: String,
+    /// Public keys (base64, like in any exported bundle) whose bundles
+    /// we'll import. Our own key is always trusted.
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+}
+
+/// Settings for /.well-known/security.txt (RFC 9116), so people who find a
+/// security problem know who to tell.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SecurityTxtConfig {
+    /// Where to send reports: `mailto:`, `https:`, or `tel:` URIs.
+    pub contact: Vec<String>,
+    /// When the file should be considered stale, as an RFC 3339 timestamp.
+    /// The RFC says to keep this less than a year out.
+    pub expires: String,
+    /// A link to a disclosure policy, if you have one.
+    pub policy: Option<String>,
+    /// Languages you can take reports in, like "en, de".
+    pub preferred_languages: Option<String>,
+}
+
+/// Clients that skip rate limiting, as written in the config file. The
+/// finished version is a `RateLimitAllowlist`.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+struct RateLimitsConfig {
+    /// IP addresses or CIDR ranges, like "192.0.2.0/24".
+    allow_ips: Vec<String>,
+    /// Substrings of User-Agent headers, like "UptimeRobot".
+    allow_user_agents: Vec<String>,
+}
+
+/// Rules for new passwords, at signup and when changing one. Shows up in
+/// the instance metadata too, so clients can warn people before they try.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    /// Shortest allowed password, in characters.
+    pub min_length: usize,
+    /// Lowest allowed zxcvbn strength score, from 0 (anything goes) to 4
+    /// (very hard to guess).
+    pub min_strength: u8,
+    /// Passwords nobody gets to use, like the site's own name. Case doesn't
+    /// matter, and passwords built out of these count as weak.
+    pub banned: Vec<String>,
+    /// Whether to turn away passwords that show up in Have I Been Pwned's
+    /// breach list. Sends the first few characters of each new password's
+    /// SHA-1 to api.pwnedpasswords.com, and lets passwords through if it's
+    /// unreachable.
+    pub check_breached: bool,
+}
+
+impl Default for PasswordPolicy {
+    /// Turns away the really guessable stuff without being a pain about it.
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            min_strength: 2,
+            banned: Vec::new(),
+            check_breached: false,
+        }
+    }
+}
+
+/// Bot resistance for the public signup form (see `util::antispam`), and
+/// what new accounts start out with.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SignupConfig {
+    /// Fewest seconds between loading the signup form and sending it.
+    /// People take longer than this just to make up a password. 0 turns
+    /// the check off.
+    pub min_seconds: u64,
+    /// An extra challenge to pass, if any.
+    pub challenge: Option<SignupChallenge>,
+    /// Whether new accounts get a welcome kit: an example dogear for our
+    /// own FAQ, so the index isn't empty, and a ready-made bookmarklet on a
+    /// welcome page right after signup. On by default.
+    pub welcome: bool,
+}
+
+impl Default for SignupConfig {
+    fn default() -> Self {
+        Self {
+            min_seconds: 3,
+            challenge: None,
+            welcome: true,
+        }
+    }
+}
+
+/// The optional signup challenge, by provider.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum SignupChallenge {
+    /// A hashing puzzle that client.js solves in the background. Each bit
+    /// of `difficulty` doubles the work. No third parties involved, but
+    /// nobody can sign up without JavaScript.
+    ProofOfWork {
+        #[serde(default = "default_pow_difficulty")]
+        difficulty: u8,
+    },
+    /// Cloudflare Turnstile.
+    Turnstile {
+        site_key: String,
+        secret_key: String,
+    },
+    /// hCaptcha.
+    Hcaptcha {
+        site_key: String,
+        secret_key: String,
+    },
+}
+
+/// About a second of hashing on a phone.
+fn default_pow_difficulty() -> u8 {
+    16
+}
+
+/// How long to keep the records that pile up over time, in months. Each
+/// one left out is kept forever. The daily cleanup job enforces these.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Old spots in each dogear's history. Users can pick a shorter limit
+    /// for their own on the account page. Every dogear keeps its latest
+    /// entry regardless.
+    pub history_months: Option<u32>,
+    /// The devices people have logged in from (for new-device alerts),
+    /// counting from the last login on each.
+    pub devices_months: Option<u32>,
+    /// Email change records, including the old and new addresses.
+    pub email_changes_months: Option<u32>,
+    /// Account activity records (logins, password and token changes),
+    /// including IP addresses and user agents.
+    pub audit_events_months: Option<u32>,
+}
+
+/// Serving the index page's dogear list from an in-process cache. A list
+/// younger than `fresh_secs` is served as-is; one up to `stale_secs` older
+/// than that is served while a background refresh replaces it; past that,
+/// the page waits for the db like usual. Any write to someone's dogears
+/// throws out their cached lists right away, but only in the process that
+/// made the write, so with several FastCGI processes, a change can take up
+/// to the full fresh_secs + stale_secs to show up everywhere.
+#[derive(Debug, Deserialize, Clone)]
+pub struct IndexCacheConfig {
+    pub fresh_secs: u64,
+    pub stale_secs: u64,
+}
+
+/// Whether search engines are welcome on some part of the site.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CrawlPolicy {
+    Allow,
+    Deny,
+}
+
+/// What robots.txt and the X-Robots-Tag headers tell crawlers. The public
+/// pages (profiles at /u/:username) and the app itself (the login page,
+/// FAQ, and everything behind a login) get separate policies.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct CrawlerConfig {
+    pub public_pages: CrawlPolicy,
+    pub app: CrawlPolicy,
+    /// Whether to help search engines along with a /sitemap.xml of the
+    /// public profiles, and OpenGraph tags on them. Validation makes sure
+    /// public_pages is allow if this is on.
+    pub seo: bool,
+}
+
+impl Default for CrawlerConfig {
+    /// Public profiles exist to be found; the app doesn't. But nobody gets
+    /// a sitemap unless they ask for one.
+    fn default() -> Self {
+        Self {
+            public_pages: CrawlPolicy::Allow,
+            app: CrawlPolicy::Deny,
+            seo: false,
+        }
+    }
+}
+
+/// Which other sites can call one group of routes from their scripts. Our
+/// own origin never needs listing; it's not cross-origin. Cookies never go
+/// along cross-origin, so these requests need an API token.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct CorsRule {
+    /// Bare origins, like "https://example.com", or "*" for anybody. Empty
+    /// means nobody. Validation normalizes these to how browsers send them.
+    pub origins: Vec<String>,
+    /// The HTTP methods those origins can use, like "POST".
+    pub methods: Vec<String>,
+}
+
+/// Cross-origin access to the API, by route group.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// `/api/v1/update` (and its query-param twin, `PUT /api/v1/dogear`),
+    /// which the bookmarklets call from whatever page you're reading.
+    /// Cross-origin updates can only move dogears on the calling site, no
+    /// matter what this says.
+    pub update: CorsRule,
+    /// The rest of the token-authenticated API.
+    pub api: CorsRule,
+}
+
+impl Default for CorsConfig {
+    /// The bookmarklets have to work everywhere, but nothing else needs to
+    /// work anywhere but here.
+    fn default() -> Self {
+        Self {
+            update: CorsRule {
+                origins: vec!["*".to_string()],
+                methods: vec!["POST".to_string(), "PUT".to_string()],
+            },
+            api: CorsRule {
+                origins: Vec::new(),
+                methods: vec!["GET".to_string(), "POST".to_string(), "DELETE".to_string()],
+            },
+        }
+    }
+}
+
 /// Stuff the app needs that's sourced from configuration.
 #[derive(Clone, Debug)]
 pub struct DogConfig {
@@ -75,13 +364,154 @@ pub struct DogConfig {
     pub public_url: Url,
     /// The location of the database file.
     pub db_file: PathBuf,
+    /// A file holding the passphrase to open the database with, if it's
+    /// encrypted with SQLCipher. The `EARDOGGER_DB_KEY` env var beats this;
+    /// see [DogConfig::db_key]. Needs the `sqlcipher` feature.
+    pub db_key_file: Option<PathBuf>,
+    /// Where `--migrate` puts the snapshot it takes before applying
+    /// anything. Defaults to the db file's own directory.
+    pub migration_backup_dir: PathBuf,
     /// The directory with static CSS/JS/image assets.
     pub assets_dir: PathBuf,
     /// Location of the binary key file for signing cookies. We'll auto-create this if it
     /// doesn't exist already.
     pub key_file: PathBuf,
-    /// Settings for application logging via Tracing subscriber layers.
+    /// Settings for application logging via Tracing subscriber layers. The
+    /// filter can change on a SIGHUP, so read the current one from
+    /// [LiveConfig].
     pub log: LogConfig,
+    /// Whether to accept `Authorization: Basic` (username and password) on
+    /// the JSON API, for clients that can't do bearer tokens. Off by default.
+    pub api_basic_auth: bool,
+    /// How many days API tokens made on the account page work for. None
+    /// (the default) means they don't expire. Bookmarklet, quickmark, and
+    /// KOReader tokens never expire, since they live in places that can't
+    /// easily pick up a new one.
+    pub token_lifetime_days: Option<u32>,
+    /// Whether to follow same-host redirects when creating a dogear, and
+    /// save the URL they end up at. Off by default, since it means making
+    /// requests to whatever URLs users send us.
+    pub resolve_redirects: bool,
+    /// Whether people can have their scheduled backups POSTed to a webhook
+    /// URL of their choosing. Off by default, for the same reason as
+    /// resolve_redirects. Emailed backups only need `mail`.
+    pub backup_webhooks: bool,
+    /// Whether people can push their whole account to another instance
+    /// with `/api/v1/migrate_out`. Off by default, for the same reason as
+    /// resolve_redirects. Receiving a migration doesn't need this.
+    pub migrate_out: bool,
+    /// Whether people can give their dogears RSS/Atom feeds for a
+    /// background job to watch for new chapters. Off by default, for the
+    /// same reason as resolve_redirects.
+    pub feed_polling: bool,
+    /// Whether people can register outgoing webhooks, which get a POST
+    /// whenever one of their dogears is made or moves. Off by default, for
+    /// the same reason as resolve_redirects.
+    pub webhooks: bool,
+    /// Whether new usernames can use letters and numbers from any script,
+    /// instead of just ASCII. Off by default. See `util::usernames`.
+    pub unicode_usernames: bool,
+    /// Whether to serve the `/dev` test-data endpoints, which make users,
+    /// dogears, and sessions for anyone who asks (in builds with the
+    /// `dev_tools` feature). Only for throwaway instances; it's an error to
+    /// turn this on in production.
+    pub dev_tools: bool,
+    /// How to send email, if at all. Without this, anything that would send
+    /// mail (like new-device login alerts) quietly doesn't.
+    pub mail: Option<MailConfig>,
+    /// What to tell search engines. Allows public profiles and denies the
+    /// rest, unless the config says otherwise.
+    pub crawlers: CrawlerConfig,
+    /// Which other sites' scripts can use which parts of the API. Just the
+    /// update endpoint, for anybody, unless the config says otherwise.
+    pub cors: CorsConfig,
+    /// What to put in security.txt. Without this, there isn't one.
+    pub security_txt: Option<SecurityTxtConfig>,
+    /// Other Eardogger instances (as bare origins) that we'll hand a
+    /// logged-out /resume off to, if the visitor's browser says that's where
+    /// their account lives. Nobody gets redirected anywhere that isn't on
+    /// this list. Empty by default.
+    pub peer_instances: Vec<Url>,
+    /// Operator access to site rules bundles, if at all.
+    pub site_rules: Option<SiteRulesConfig>,
+    /// The bearer token for /status/details (the JSON report on db pools
+    /// and background jobs) and /metrics (the same and more, for
+    /// Prometheus). Without it, those routes don't exist.
+    pub status_token: Option<String>,
+    /// What counts as a good enough new password.
+    pub passwords: PasswordPolicy,
+    /// How hard the signup form makes things for bots.
+    pub signup: SignupConfig,
+    /// Who doesn't get rate limited.
+    pub rate_limit_allowlist: RateLimitAllowlist,
+    /// How long old records stick around.
+    pub retention: RetentionConfig,
+    /// Feature flags, by name, with the percent of users (0 to 100) each
+    /// one is on for. Only flags listed here exist; an operator can change
+    /// their rollouts live through `/admin/features`, which beats this.
+    /// These are as of startup; a SIGHUP can change them, so read the
+    /// current ones from [LiveConfig].
+    pub features: BTreeMap<String, u8>,
+    /// Whether to cache the index page's dogear list in memory, and for
+    /// how long. Off unless the config says otherwise.
+    pub index_cache: Option<IndexCacheConfig>,
+}
+
+/// The parts of the config that a running server can pick up from a
+/// SIGHUP (see `reload`), without a restart. Cheap to clone; clones share
+/// the same settings.
+#[derive(Clone, Debug)]
+pub struct LiveConfig {
+    // std RwLock is fine, since nobody holds it across an await.
+    settings: Arc<RwLock<LiveSettings>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LiveSettings {
+    /// Same as `log.filter`.
+    pub log_filter: String,
+    /// Same as `features`.
+    pub features: BTreeMap<String, u8>,
+}
+
+impl LiveSettings {
+    pub fn from_config(config: &DogConfig) -> Self {
+        Self {
+            log_filter: config.log.filter.clone(),
+            features: config.features.clone(),
+        }
+    }
+}
+
+impl LiveConfig {
+    pub fn new(config: &DogConfig) -> Self {
+        Self {
+            settings: Arc::new(RwLock::new(LiveSettings::from_config(config))),
+        }
+    }
+
+    /// The config's feature flags and their rollouts, as of the last reload.
+    pub fn features(&self) -> BTreeMap<String, u8> {
+        self.settings
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .features
+            .clone()
+    }
+
+    /// Everything, as of the last reload.
+    pub fn get(&self) -> LiveSettings {
+        self.settings
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Swap in new settings, and get back the old ones.
+    pub fn replace(&self, new: LiveSettings) -> LiveSettings {
+        let mut settings = self.settings.write().unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(&mut *settings, new)
+    }
 }
 
 /// The intermediate struct used for deserializing the config file and
@@ -98,11 +528,54 @@ struct PreDogConfig {
     db_file: String,
     assets_dir: String,
     key_file: String,
+    db_key_file: Option<String>,
+    migration_backup_dir: Option<String>,
     log: LogConfig,
+    #[serde(default)]
+    api_basic_auth: bool,
+    token_lifetime_days: Option<u32>,
+    #[serde(default)]
+    resolve_redirects: bool,
+    #[serde(default)]
+    backup_webhooks: bool,
+    #[serde(default)]
+    migrate_out: bool,
+    #[serde(default)]
+    feed_polling: bool,
+    #[serde(default)]
+    webhooks: bool,
+    #[serde(default)]
+    unicode_usernames: bool,
+    #[serde(default)]
+    dev_tools: bool,
+    mail: Option<MailConfig>,
+    #[serde(default)]
+    crawlers: CrawlerConfig,
+    #[serde(default)]
+    cors: CorsConfig,
+    security_txt: Option<SecurityTxtConfig>,
+    #[serde(default)]
+    peer_instances: Vec<String>,
+    site_rules: Option<SiteRulesConfig>,
+    status_token: Option<String>,
+    #[serde(default)]
+    passwords: PasswordPolicy,
+    #[serde(default)]
+    signup: SignupConfig,
+    #[serde(default)]
+    rate_limits: RateLimitsConfig,
+    #[serde(default)]
+    retention: RetentionConfig,
+    #[serde(default)]
+    features: BTreeMap<String, u8>,
+    index_cache: Option<IndexCacheConfig>,
 }
 
 impl PreDogConfig {
-    fn finalize(self, base_dir: &Path) -> anyhow::Result<DogConfig> {
+    /// Check all the settings for bad values and conflicts, and either
+    /// return a usable DogConfig or a list of every problem we found. This
+    /// doesn't touch the filesystem; see `path_problems` for that.
+    fn finalize(self, base_dir: &Path) -> Result<DogConfig, ConfError> {
         // Destructure yourself
         let Self {
             production,
@@ -114,17 +587,436 @@ impl PreDogConfig {
             db_file,
             assets_dir,
             key_file,
+            db_key_file,
+            migration_backup_dir,
             mut log,
+            api_basic_auth,
+            token_lifetime_days,
+            resolve_redirects,
+            backup_webhooks,
+            migrate_out,
+            feed_polling,
+            webhooks,
+            unicode_usernames,
+            dev_tools,
+            mail,
+            crawlers,
+            mut cors,
+            security_txt,
+            peer_instances,
+            site_rules,
+            status_token,
+            passwords,
+            signup,
+            rate_limits,
+            retention,
+            features,
+            index_cache,
         } = self;
+        let mut problems: Vec<String> = Vec::new();
+
+        // Thread counts
+        for (name, count) in [
+            ("runtime_threads", runtime_threads),
+            ("reader_threads", reader_threads as usize),
+        ] {
+            if count == 0 {
+                problems.push(format!("{} must be at least 1.", name));
+            } else if count > MAX_THREADS {
+                problems.push(format!(
+                    "{} = {} is way too many; the max is {}.",
+                    name, count, MAX_THREADS
+                ));
+            }
+        }
+
+        // Dev tools give out accounts to anybody, so keep them off real sites.
+        if dev_tools && production {
+            problems.push(
+                "dev_tools can't be on in production; it lets anyone make accounts and tokens."
+                    .to_string(),
+            );
+        }
+
+        // The URL. Everything in the app lives at the root of the domain, so
+        // a path would just make for broken links.
+        let public_url = match Url::parse(&public_url) {
+            Ok(url) => {
+                if !matches!(url.scheme(), "http" | "https") {
+                    problems.push(format!(
+                        "public_url {:?} needs an http:// or https:// scheme.",
+                        url.as_str()
+                    ));
+                } else if url.host().is_none() {
+                    problems.push(format!("public_url {:?} has no host.", url.as_str()));
+                }
+                if url.path() != "/" || url.query().is_some() || url.fragment().is_some() {
+                    problems.push(format!(
+                        "public_url {:?} must be a bare origin like \"https://example.com\", with no path; Eardogger has to run at the root of its domain.",
+                        url.as_str()
+                    ));
+                }
+                Some(url)
+            }
+            Err(e) => {
+                problems.push(format!(
+                    "public_url {:?} isn't a valid URL ({}).",
+                    public_url, e
+                ));
+                None
+            }
+        };
+
+        // Peers. Same rules as our own URL, since we build links to them
+        // the same way. A peer that's actually us would just be a loop.
+        let peer_instances: Vec<Url> = peer_instances
+            .iter()
+            .filter_map(|peer| {
+                let Ok(url) = Url::parse(peer) else {
+                    problems.push(format!(
+                        "peer_instances entry {:?} isn't a valid URL.",
+                        peer
+                    ));
+                    return None;
+                };
+                if !matches!(url.scheme(), "http" | "https")
+                    || url.host().is_none()
+                    || url.path() != "/"
+                    || url.query().is_some()
+                    || url.fragment().is_some()
+                {
+                    problems.push(format!(
+                        "peer_instances entry {:?} must be a bare http:// or https:// origin, like \"https://example.com\".",
+                        peer
+                    ));
+                    return None;
+                }
+                if public_url.as_ref().is_some_and(|u| u.origin() == url.origin()) {
+                    problems.push(format!(
+                        "peer_instances entry {:?} is this instance's own public_url.",
+                        peer
+                    ));
+                    return None;
+                }
+                Some(url)
+            })
+            .collect();
+
+        // Logging
+        if let Err(e) = EnvFilter::try_new(&log.filter) {
+            problems.push(format!("log.filter {:?} isn't valid ({}).", &log.filter, e));
+        }
+        if let Some(logfile) = &log.file {
+            if logfile.name.is_empty() {
+                problems.push("log.file.name can't be empty.".to_string());
+            }
+            if logfile.days == 0 {
+                problems.push("log.file.days must be at least 1.".to_string());
+            }
+        }
+
+        // Mail
+        if let Some(mail) = &mail {
+            if !mail.from.contains('@') || mail.from.contains(['\r', '\n']) {
+                problems.push(format!(
+                    "mail.from {:?} needs to be a single email address, like \"Eardogger <eardogger@example.com>\".",
+                    &mail.from
+                ));
+            }
+            if mail.sendmail.as_os_str().is_empty() {
+                problems.push("mail.sendmail can't be empty.".to_string());
+            }
+        }
+
+        // security.txt
+        if let Some(security_txt) = &security_txt {
+            if security_txt.contact.is_empty() {
+                problems.push("security_txt.contact needs at least one address.".to_string());
+            }
+            for contact in &security_txt.contact {
+                let ok = Url::parse(contact)
+                    .is_ok_and(|u| matches!(u.scheme(), "mailto" | "https" | "tel"));
+                if !ok {
+                    problems.push(format!(
+                        "security_txt.contact {:?} needs to be a mailto:, https://, or tel: URI.",
+                        contact
+                    ));
+                }
+            }
+            if OffsetDateTime::parse(&security_txt.expires, &Rfc3339).is_err() {
+                problems.push(format!(
+                    "security_txt.expires {:?} needs to be an RFC 3339 timestamp, like \"2030-01-01T00:00:00Z\".",
+                    &security_txt.expires
+                ));
+            }
+            if let Some(policy) = &security_txt.policy {
+                if !Url::parse(policy).is_ok_and(|u| u.scheme() == "https") {
+                    problems.push(format!(
+                        "security_txt.policy {:?} needs to be an https:// URL.",
+                        policy
+                    ));
+                }
+            }
+            if let Some(languages) = &security_txt.preferred_languages {
+                if languages.contains(['\r', '\n']) {
+                    problems
+                        .push("security_txt.preferred_languages has to be one line.".to_string());
+                }
+            }
+        }
+
+        // CORS
+        for (name, rule) in [("update", &mut cors.update), ("api", &mut cors.api)] {
+            for origin in rule.origins.iter_mut().filter(|o| *o != "*") {
+                match Url::parse(origin) {
+                    Ok(url)
+                        if matches!(url.scheme(), "http" | "https")
+                            && url.host().is_some()
+                            && url.path() == "/"
+                            && url.query().is_none()
+                            && url.fragment().is_none() =>
+                    {
+                        *origin = url.origin().ascii_serialization();
+                    }
+                    _ => problems.push(format!(
+                        "cors.{}.origins entry {:?} must be \"*\" or a bare http:// or https:// origin, like \"https://example.com\".",
+                        name, origin
+                    )),
+                }
+            }
+            if !rule.origins.is_empty() && rule.methods.is_empty() {
+                problems.push(format!(
+                    "cors.{}.methods is empty, so its origins can't do anything; list some methods or drop the origins.",
+                    name
+                ));
+            }
+            for method in rule.methods.iter_mut() {
+                // Methods are case-sensitive, and nobody means "get".
+                method.make_ascii_uppercase();
+                if method.parse::<http::Method>().is_err() {
+                    problems.push(format!(
+                        "cors.{}.methods entry {:?} isn't an HTTP method.",
+                        name, method
+                    ));
+                }
+            }
+        }
+
+        // Site rules
+        if let Some(site_rules) = &site_rules {
+            if site_rules.admin_token.len() < MIN_ADMIN_TOKEN_LEN {
+                problems.push(format!(
+                    "site_rules.admin_token needs to be at least {} characters; use a long random string.",
+                    MIN_ADMIN_TOKEN_LEN
+                ));
+            }
+            for key in &site_rules.trusted_keys {
+                if crate::site_rules::parse_public_key(key).is_none() {
+                    problems.push(format!(
+                        "site_rules.trusted_keys entry {:?} isn't a public key; copy the public_key from an exported bundle.",
+                        key
+                    ));
+                }
+            }
+        }
+
+        // Status report
+        if let Some(status_token) = &status_token {
+            if status_token.len() < MIN_ADMIN_TOKEN_LEN {
+                problems.push(format!(
+                    "status_token needs to be at least {} characters; use a long random string.",
+                    MIN_ADMIN_TOKEN_LEN
+                ));
+            }
+        }
+
+        // Password policy
+        if passwords.min_length == 0 {
+            problems.push("passwords.min_length must be at least 1.".to_string());
+        }
+        if passwords.min_strength > 4 {
+            problems.push(format!(
+                "passwords.min_strength = {} is off the scale; it goes from 0 to 4.",
+                passwords.min_strength
+            ));
+        }
+
+        // Signup antispam
+        if signup.min_seconds > MAX_SIGNUP_MIN_SECONDS {
+            problems.push(format!(
+                "signup.min_seconds = {} would turn away slow typists too; keep it to {} or less.",
+                signup.min_seconds, MAX_SIGNUP_MIN_SECONDS
+            ));
+        }
+        match &signup.challenge {
+            Some(SignupChallenge::ProofOfWork { difficulty }) => {
+                if !(1..=MAX_POW_DIFFICULTY).contains(difficulty) {
+                    problems.push(format!(
+                        "signup.challenge.difficulty = {} is out of range; it goes from 1 to {}.",
+                        difficulty, MAX_POW_DIFFICULTY
+                    ));
+                }
+            }
+            Some(
+                SignupChallenge::Turnstile {
+                    site_key,
+                    secret_key,
+                }
+                | SignupChallenge::Hcaptcha {
+                    site_key,
+                    secret_key,
+                },
+            ) => {
+                if site_key.trim().is_empty() || secret_key.trim().is_empty() {
+                    problems.push(
+                        "signup.challenge needs both a site_key and a secret_key from the captcha provider.".to_string(),
+                    );
+                }
+            }
+            None => {}
+        }
+
+        // Rate limit allowlist
+        let rate_limit_allowlist = RateLimitAllowlist {
+            ips: rate_limits
+                .allow_ips
+                .iter()
+                .filter_map(|ip| {
+                    let range = IpRange::parse(ip);
+                    if range.is_none() {
+                        problems.push(format!(
+                            "rate_limits.allow_ips entry {:?} isn't an IP address or CIDR range, like \"192.0.2.0/24\".",
+                            ip
+                        ));
+                    }
+                    range
+                })
+                .collect(),
+            user_agents: rate_limits.allow_user_agents,
+        };
+        if rate_limit_allowlist
+            .user_agents
+            .iter()
+            .any(|ua| ua.trim().is_empty())
+        {
+            problems.push(
+                "rate_limits.allow_user_agents can't have blank entries; they'd match everybody."
+                    .to_string(),
+            );
+        }
+
+        // Retention
+        for (name, months) in [
+            ("history_months", retention.history_months),
+            ("devices_months", retention.devices_months),
+            ("email_changes_months", retention.email_changes_months),
+            ("audit_events_months", retention.audit_events_months),
+        ] {
+            match months {
+                Some(0) => problems.push(format!(
+                    "retention.{} = 0 would delete everything; to keep things forever, leave it out.",
+                    name
+                )),
+                Some(m) if m > MAX_RETENTION_MONTHS => problems.push(format!(
+                    "retention.{} = {} is longer than anything needs; the max is {}, or leave it out to keep things forever.",
+                    name, m, MAX_RETENTION_MONTHS
+                )),
+                _ => {}
+            }
+        }
+
+        // Feature flags
+        for (name, rollout) in &features {
+            if !is_flag_name(name) {
+                problems.push(format!(
+                    "features entry {:?} needs a name made of lowercase letters, digits, and underscores, like \"queue_mode\".",
+                    name
+                ));
+            }
+            if *rollout > 100 {
+                problems.push(format!(
+                    "features.{} = {} is more than everybody; rollouts go from 0 to 100 percent.",
+                    name, rollout
+                ));
+            }
+        }
+
+        // Index cache
+        if let Some(cache) = &index_cache {
+            if cache.fresh_secs == 0 {
+                problems.push(
+                    "index_cache.fresh_secs must be at least 1; to turn the cache off, leave [index_cache] out."
+                        .to_string(),
+                );
+            }
+            let total = cache.fresh_secs.saturating_add(cache.stale_secs);
+            if total > MAX_INDEX_CACHE_SECS {
+                problems.push(format!(
+                    "index_cache.fresh_secs + stale_secs = {} is too stale to be worth it; the max is {}.",
+                    total, MAX_INDEX_CACHE_SECS
+                ));
+            }
+        }
+
+        // Conflicts
+        if production && peer_instances.iter().any(|u| u.scheme() != "https") {
+            problems.push(
+                "peer_instances have to be https in production, since we send people there with their reading list URLs.".to_string(),
+            );
+        }
+        if crawlers.seo && crawlers.public_pages == CrawlPolicy::Deny {
+            problems.push(
+                "crawlers.seo makes a sitemap of pages that crawlers.public_pages = \"deny\" tells crawlers to stay out of. Turn one of them off.".to_string(),
+            );
+        }
+        if cfg!(not(unix)) && matches!(mode, ServeMode::Fcgi { .. }) {
+            problems.push("fcgi mode only works on unix; use http mode here.".to_string());
+        }
+        if matches!(mode, ServeMode::Fcgi { .. }) && log.stdout {
+            problems.push(
+                "log.stdout can't be true in fcgi mode, because mod_fcgid dumps it into the server's main ErrorLog. Use log.file instead.".to_string(),
+            );
+        }
+        if production
+            && api_basic_auth
+            && public_url.as_ref().is_some_and(|u| u.scheme() != "https")
+        {
+            problems.push(
+                "api_basic_auth sends passwords in the clear unless public_url is https. Either switch to https or turn it off.".to_string(),
+            );
+        }
+
+        // Database encryption
+        if db_key_file.is_some() && cfg!(not(feature = "sqlcipher")) {
+            problems.push(
+                "db_key_file needs SQLCipher; rebuild with `--features sqlcipher`, or leave it out."
+                    .to_string(),
+            );
+        }
+
+        if token_lifetime_days == Some(0) {
+            problems.push(
+                "token_lifetime_days must be at least 1; to make tokens last forever, leave it out."
+                    .to_string(),
+            );
+        }
+
+        let public_url = match public_url {
+            Some(url) if problems.is_empty() => url,
+            _ => return Err(ConfError::Invalid(problems)),
+        };
 
         // Publish IS_PRODUCTION
         IS_PRODUCTION.store(production, Ordering::Relaxed);
-        // Parse the URL (only fallible bit for now)
-        let public_url = Url::parse(&public_url)?;
         // Join the file paths
         let db_file = base_dir.join(db_file);
         let assets_dir = base_dir.join(assets_dir);
         let key_file = base_dir.join(key_file);
+        let db_key_file = db_key_file.map(|f| base_dir.join(f));
+        let migration_backup_dir = match migration_backup_dir {
+            Some(dir) => base_dir.join(dir),
+            None => db_file.parent().unwrap_or(base_dir).to_path_buf(),
+        };
         if let Some(logfile) = &mut log.file {
             logfile.directory = base_dir.join(&logfile.directory);
         }
@@ -138,9 +1030,97 @@ impl PreDogConfig {
             db_file,
             assets_dir,
             key_file,
+            db_key_file,
+            migration_backup_dir,
             log,
+            api_basic_auth,
+            token_lifetime_days,
+            resolve_redirects,
+            backup_webhooks,
+            migrate_out,
+            feed_polling,
+            webhooks,
+            unicode_usernames,
+            dev_tools,
+            mail,
+            crawlers,
+            cors,
+            security_txt,
+            peer_instances,
+            site_rules,
+            status_token,
+            passwords,
+            signup,
+            rate_limit_allowlist,
+            retention,
+            features,
+            index_cache,
         })
     }
+
+    /// Check that the files and directories we need either exist already,
+    /// or are things we'll create at startup and *can* create.
+    fn path_problems(&self, base_dir: &Path) -> Vec<String> {
+        let mut problems = Vec::new();
+        let db_file = base_dir.join(&self.db_file);
+        if !db_file.is_file() {
+            problems.push(format!(
+                "db_file {:?} doesn't exist or isn't a file. To start fresh, use `eardogger-rs init`.",
+                db_file
+            ));
+        }
+        let assets_dir = base_dir.join(&self.assets_dir);
+        if !assets_dir.is_dir() {
+            problems.push(format!(
+                "assets_dir {:?} doesn't exist or isn't a directory.",
+                assets_dir
+            ));
+        }
+        // We write a new key file if there isn't one, so its directory has to be there.
+        let key_file = base_dir.join(&self.key_file);
+        if key_file.exists() {
+            if !key_file.is_file() {
+                problems.push(format!("key_file {:?} isn't a file.", key_file));
+            }
+        } else if !key_file.parent().is_some_and(Path::is_dir) {
+            problems.push(format!(
+                "key_file {:?} doesn't exist, and can't be created because its directory doesn't exist either.",
+                key_file
+            ));
+        }
+        // Unlike the cookie key, we can't just make up a db key.
+        if let Some(db_key_file) = &self.db_key_file {
+            let db_key_file = base_dir.join(db_key_file);
+            if !db_key_file.is_file() {
+                problems.push(format!(
+                    "db_key_file {:?} doesn't exist or isn't a file.",
+                    db_key_file
+                ));
+            }
+        }
+        // Same deal for the migration backups.
+        if let Some(dir) = &self.migration_backup_dir {
+            let dir = base_dir.join(dir);
+            if let Some(blocker) = dir.ancestors().find(|p| p.exists() && !p.is_dir()) {
+                problems.push(format!(
+                    "migration_backup_dir {:?} can't be created, because {:?} is a file.",
+                    dir, blocker
+                ));
+            }
+        }
+        // The log appender does a mkdir -p, so the directory just has to not
+        // be something else.
+        if let Some(logfile) = &self.log.file {
+            let directory = base_dir.join(&logfile.directory);
+            if let Some(blocker) = directory.ancestors().find(|p| p.exists() && !p.is_dir()) {
+                problems.push(format!(
+                    "log.file.directory {:?} can't be created, because {:?} is a file.",
+                    directory, blocker
+                ));
+            }
+        }
+        problems
+    }
 }
 
 impl DogConfig {
@@ -151,10 +1131,37 @@ impl DogConfig {
         let abs_path = cwd.join(path.as_ref());
         // This runs before we have a tracing subscriber, so we have to log rudely.
         println!("Startup: loading config file from {:?}", &abs_path);
+        Self::read(&abs_path)
+    }
+
+    /// Like `load`, but for a server that's already running (see `reload`):
+    /// no startup chatter, and it leaves `is_production()` how startup found
+    /// it, since that's not something to flip mid-flight.
+    pub fn reread(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let abs_path = std::env::current_dir()?.join(path.as_ref());
+        let production = is_production();
+        let result = Self::read(&abs_path);
+        IS_PRODUCTION.store(production, Ordering::Relaxed);
+        result
+    }
+
+    /// The part of `load` and `reread` that actually reads the file.
+    fn read(abs_path: &Path) -> anyhow::Result<Self> {
         let base_dir = abs_path.parent().ok_or(ConfError::Impossible)?;
-        let conf_text = std::fs::read_to_string(&abs_path)?;
+        let conf_text = std::fs::read_to_string(abs_path)?;
+        // Syntax and type errors bail out on the first one, since serde can't
+        // keep going after those. But past that point, collect everything.
         let pre: PreDogConfig = toml::from_str(&conf_text)?;
-        pre.finalize(base_dir)
+        let path_problems = pre.path_problems(base_dir);
+        match pre.finalize(base_dir) {
+            Ok(config) if path_problems.is_empty() => Ok(config),
+            Ok(_) => Err(ConfError::Invalid(path_problems).into()),
+            Err(ConfError::Invalid(mut problems)) => {
+                problems.extend(path_problems);
+                Err(ConfError::Invalid(problems).into())
+            }
+            Err(e) => Err(e.into()),
+        }
     }
 
     #[cfg(test)]
@@ -173,20 +1180,242 @@ impl DogConfig {
             db_file: "ignore_me".to_string(),
             assets_dir: "public".to_string(),
             key_file: "cookie_key.bin".to_string(),
+            db_key_file: None,
+            migration_backup_dir: None,
             log: LogConfig {
                 filter: "info".to_string(),
                 stdout: true,
                 file: None,
             },
+            api_basic_auth: false,
+            token_lifetime_days: None,
+            resolve_redirects: false,
+            backup_webhooks: false,
+            migrate_out: false,
+            feed_polling: false,
+            webhooks: false,
+            unicode_usernames: false,
+            dev_tools: false,
+            mail: None,
+            crawlers: CrawlerConfig::default(),
+            cors: CorsConfig::default(),
+            security_txt: None,
+            peer_instances: Vec::new(),
+            site_rules: None,
+            status_token: None,
+            passwords: PasswordPolicy::default(),
+            // Tests post forms a lot faster than people can, and mostly
+            // want to start from an empty account.
+            signup: SignupConfig {
+                min_seconds: 0,
+                challenge: None,
+                welcome: false,
+            },
+            rate_limits: RateLimitsConfig::default(),
+            retention: RetentionConfig::default(),
+            features: BTreeMap::new(),
+            index_cache: None,
         };
         let cwd = std::env::current_dir()?;
-        pre.finalize(&cwd)
+        Ok(pre.finalize(&cwd)?)
     }
 }
 
 #[cfg(test)]
-#[test]
-fn valid_example_config_file() {
-    DogConfig::load("eardogger.example.toml")
-        .expect("example config file is valid and up-to-date with impl");
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_example_config_file() {
+        // Skip the path checks, since the example's dev.db and logs dir only
+        // exist once you've actually run the thing.
+        let conf_text = std::fs::read_to_string("eardogger.example.toml").unwrap();
+        let pre: PreDogConfig = toml::from_str(&conf_text).unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        pre.finalize(&cwd)
+            .expect("example config file is valid and up-to-date with impl");
+    }
+
+    #[test]
+    fn cors_settings_are_normalized() {
+        let mut pre: PreDogConfig = toml::from_str(
+            r#"
+production = false
+runtime_threads = 2
+reader_threads = 2
+validate_migrations = false
+public_url = "http://localhost:3000"
+db_file = "dev.db"
+assets_dir = "public"
+key_file = "cookie_key.bin"
+
+[log]
+filter = "info"
+stdout = true
+
+[cors.api]
+origins = ["HTTPS://Example.com:443/", "http://localhost:8080"]
+methods = ["get", "Delete"]
+
+[mode.http]
+port = 3000
+"#,
+        )
+        .unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        let config = pre.finalize(&cwd).unwrap();
+        assert_eq!(
+            config.cors.api.origins,
+            vec!["https://example.com", "http://localhost:8080"]
+        );
+        assert_eq!(config.cors.api.methods, vec!["GET", "DELETE"]);
+        // Leaving a group out keeps its defaults.
+        assert_eq!(config.cors.update.origins, vec!["*"]);
+        assert_eq!(config.cors.update.methods, vec!["POST", "PUT"]);
+
+        pre = toml::from_str(&std::fs::read_to_string("eardogger.example.toml").unwrap()).unwrap();
+        pre.cors.update.origins = vec!["example.com".to_string()];
+        pre.cors.update.methods = vec!["P O S T".to_string()];
+        let Err(ConfError::Invalid(problems)) = pre.finalize(&cwd) else {
+            panic!("expected bad cors settings to be invalid");
+        };
+        assert_eq!(problems.len(), 2);
+    }
+
+    #[test]
+    fn config_problems_are_aggregated() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("logs"), "not a dir").unwrap();
+        let conf_path = dir.path().join("eardogger.toml");
+        std::fs::write(
+            &conf_path,
+            r#"
+production = true
+runtime_threads = 0
+reader_threads = 9000
+validate_migrations = true
+public_url = "http://example.com/dogs"
+db_file = "missing.db"
+assets_dir = "public"
+key_file = "nope/cookie_key.bin"
+api_basic_auth = true
+token_lifetime_days = 0
+dev_tools = true
+peer_instances = ["https://example.com/dogs", "http://other.example.com"]
+status_token = "hunter2"
+
+[log]
+filter = "info,eardogger=loud"
+stdout = true
+
+[log.file]
+directory = "logs/eardogger"
+name = "eardogger"
+days = 0
+
+[mail]
+sendmail = "/usr/sbin/sendmail"
+from = "Eardogger"
+
+[crawlers]
+public_pages = "deny"
+seo = true
+
+[cors.update]
+origins = ["https://example.com/dogs"]
+methods = ["POST"]
+
+[cors.api]
+origins = ["https://example.org"]
+methods = []
+
+[security_txt]
+contact = []
+expires = "next tuesday"
+
+[site_rules]
+admin_token = "hunter2"
+trusted_keys = ["not a key"]
+
+[passwords]
+min_length = 0
+min_strength = 5
+
+[signup]
+min_seconds = 600
+
+[signup.challenge]
+provider = "proof_of_work"
+difficulty = 40
+
+[rate_limits]
+allow_ips = ["192.0.2.0/24", "10.0.0.300"]
+allow_user_agents = ["UptimeRobot", " "]
+
+[retention]
+history_months = 0
+devices_months = 12
+
+[features]
+queue_mode = 150
+
+[index_cache]
+fresh_secs = 0
+stale_secs = 30
+
+[mode.fcgi]
+max_connections = 50
+"#,
+        )
+        .unwrap();
+        let err = DogConfig::load(&conf_path).unwrap_err();
+        let Some(ConfError::Invalid(problems)) = err.downcast_ref::<ConfError>() else {
+            panic!("expected ConfError::Invalid, got {:?}", err);
+        };
+        for expected in [
+            "runtime_threads must be at least 1",
+            "reader_threads = 9000",
+            "must be a bare origin",
+            "log.filter",
+            "log.file.days",
+            "log.stdout can't be true in fcgi mode",
+            "api_basic_auth sends passwords",
+            "token_lifetime_days",
+            "dev_tools can't be on in production",
+            "mail.from",
+            "crawlers.seo",
+            "cors.update.origins entry \"https://example.com/dogs\"",
+            "cors.api.methods is empty",
+            "security_txt.contact",
+            "security_txt.expires",
+            "peer_instances entry \"https://example.com/dogs\"",
+            "peer_instances have to be https",
+            "site_rules.admin_token",
+            "site_rules.trusted_keys",
+            "status_token",
+            "passwords.min_length",
+            "passwords.min_strength",
+            "signup.min_seconds = 600",
+            "signup.challenge.difficulty = 40",
+            "rate_limits.allow_ips entry \"10.0.0.300\"",
+            "rate_limits.allow_user_agents",
+            "retention.history_months = 0",
+            "features.queue_mode = 150",
+            "index_cache.fresh_secs must be at least 1",
+            "db_file",
+            "assets_dir",
+            "key_file",
+            "log.file.directory",
+        ] {
+            assert!(
+                problems.iter().any(|p| p.contains(expected)),
+                "no problem mentioning {:?} in {:#?}",
+                expected,
+                problems
+            );
+        }
+        assert_eq!(problems.len(), 28);
+        // And the display version lists them all
+        assert_eq!(err.to_string().lines().count(), 29);
+    }
 }
@@ -0,0 +1,139 @@
+use super::core::Db;
+use serde::Serialize;
+use sqlx::{query, query_as, SqlitePool};
+use time::{serde::iso8601, OffsetDateTime};
+
+/// A query helper type for operating on [AuditEvent]s. Usually rented from
+/// a [Db].
+#[derive(Debug)]
+pub struct Audit<'a> {
+    db: &'a Db,
+}
+
+/// The account events worth keeping a record of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditKind {
+    Login,
+    PasswordChanged,
+    TokenCreated,
+    TokenDeleted,
+    AccountDeleted,
+    /// A signup the antispam checks turned away. There's no account, so
+    /// these only ever have a username.
+    SignupRejected,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    RecoveryCodesRegenerated,
+}
+
+impl AuditKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditKind::Login => "login",
+            AuditKind::PasswordChanged => "password_changed",
+            AuditKind::TokenCreated => "token_created",
+            AuditKind::TokenDeleted => "token_deleted",
+            AuditKind::AccountDeleted => "account_deleted",
+            AuditKind::SignupRejected => "signup_rejected",
+            AuditKind::TwoFactorEnabled => "two_factor_enabled",
+            AuditKind::TwoFactorDisabled => "two_factor_disabled",
+            AuditKind::RecoveryCodesRegenerated => "recovery_codes_regenerated",
+        }
+    }
+}
+
+/// Where the request behind an event came from, as best we could tell.
+#[derive(Debug, Clone, Default)]
+pub struct AuditSource {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Record struct for one account event. `kind` is an [AuditKind] string;
+/// `detail` is whatever else is worth saying, like which token.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub kind: String,
+    pub detail: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    #[serde(with = "iso8601")]
+    pub created: OffsetDateTime,
+}
+
+// record, recent, delete_old
+impl<'a> Audit<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// Write down an event. `user_id` is None for an account that's already
+    /// gone, which keeps the record from going with it.
+    #[tracing::instrument(skip(self))]
+    pub async fn record(
+        &self,
+        user_id: Option<i64>,
+        username: &str,
+        kind: AuditKind,
+        detail: Option<&str>,
+        source: &AuditSource,
+    ) -> sqlx::Result<()> {
+        let kind = kind.as_str();
+        query!(
+            r#"
+                INSERT INTO audit_events (user_id, username, kind, detail, ip, user_agent)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6);
+            "#,
+            user_id,
+            username,
+            kind,
+            detail,
+            source.ip,
+            source.user_agent,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(())
+    }
+
+    /// A user's most recent events, newest first.
+    #[tracing::instrument(skip(self))]
+    pub async fn recent(&self, user_id: i64, limit: u32) -> sqlx::Result<Vec<AuditEvent>> {
+        query_as!(
+            AuditEvent,
+            r#"
+                SELECT id, kind, detail, ip, user_agent, created
+                FROM audit_events
+                WHERE user_id = ?1
+                ORDER BY created DESC, id DESC
+                LIMIT ?2;
+            "#,
+            user_id,
+            limit,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+
+    /// Delete events older than `months`.
+    #[tracing::instrument(skip(self))]
+    pub async fn delete_old(&self, months: u32) -> sqlx::Result<u64> {
+        let cutoff = format!("-{} months", months);
+        query!(
+            r#"
+                DELETE FROM audit_events WHERE created < datetime('now', ?);
+            "#,
+            cutoff,
+        )
+        .execute(self.write_pool())
+        .await
+        .map(|v| v.rows_affected())
+    }
+}
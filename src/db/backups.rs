@@ -0,0 +1,255 @@
+use super::core::Db;
+use serde::Serialize;
+use sqlx::{query, query_as, SqlitePool};
+use time::{serde::iso8601, OffsetDateTime};
+
+/// How often a scheduled backup goes out, in days.
+pub const BACKUP_INTERVAL_DAYS: i64 = 7;
+/// How many past runs we remember per user.
+pub const BACKUP_RUNS_KEPT: i64 = 10;
+
+/// A query helper type for operating on [BackupSchedule]s and their
+/// [BackupRun]s. Usually rented from a [Db].
+#[derive(Debug)]
+pub struct Backups<'a> {
+    db: &'a Db,
+}
+
+/// Where a scheduled backup goes. Stored as text, like token scopes.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum BackupDestination {
+    /// Text: `email`. Mailed as an attachment to the account's address.
+    Email,
+    /// Text: `webhook`. POSTed as the request body to the schedule's URL.
+    Webhook,
+}
+
+impl From<&str> for BackupDestination {
+    fn from(value: &str) -> Self {
+        match value {
+            "webhook" => Self::Webhook,
+            _ => Self::Email,
+        }
+    }
+}
+
+impl From<BackupDestination> for &'static str {
+    fn from(value: BackupDestination) -> Self {
+        match value {
+            BackupDestination::Email => "email",
+            BackupDestination::Webhook => "webhook",
+        }
+    }
+}
+
+/// Record struct for a user's backup schedule. Users without one don't get
+/// backups.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct BackupSchedule {
+    pub user_id: i64,
+    pub destination: String, // see .destination().
+    pub webhook_url: Option<String>,
+    #[serde(with = "iso8601::option")]
+    pub last_run: Option<OffsetDateTime>,
+}
+
+impl BackupSchedule {
+    pub fn destination(&self) -> BackupDestination {
+        self.destination.as_str().into()
+    }
+}
+
+/// A schedule that's due to run, plus the bits of its user the run needs.
+#[derive(Debug, Clone)]
+pub struct DueBackup {
+    pub user_id: i64,
+    pub username: String,
+    pub email: Option<String>,
+    pub destination: String, // see .destination().
+    pub webhook_url: Option<String>,
+}
+
+impl DueBackup {
+    pub fn destination(&self) -> BackupDestination {
+        self.destination.as_str().into()
+    }
+}
+
+/// Record struct for how one backup run went.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct BackupRun {
+    pub id: i64,
+    pub user_id: i64,
+    pub destination: String,
+    #[serde(with = "iso8601")]
+    pub ran: OffsetDateTime,
+    /// How many dogears were in it.
+    pub dogears: i64,
+    /// What went wrong, or None if it went out.
+    pub error: Option<String>,
+}
+
+// get, set, delete, due, record_run, runs
+impl<'a> Backups<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// A user's backup schedule, if they have one.
+    #[tracing::instrument(skip(self))]
+    pub async fn get(&self, user_id: i64) -> sqlx::Result<Option<BackupSchedule>> {
+        query_as!(
+            BackupSchedule,
+            r#"
+                SELECT user_id, destination, webhook_url, last_run
+                FROM backup_schedules WHERE user_id = ?;
+            "#,
+            user_id,
+        )
+        .fetch_optional(self.read_pool())
+        .await
+    }
+
+    /// Start or change a user's backup schedule. Changing where it goes
+    /// doesn't reset the clock on the next run.
+    #[tracing::instrument(skip(self))]
+    pub async fn set(
+        &self,
+        user_id: i64,
+        destination: BackupDestination,
+        webhook_url: Option<&str>,
+    ) -> sqlx::Result<BackupSchedule> {
+        let destination: &str = destination.into();
+        query_as!(
+            BackupSchedule,
+            r#"
+                INSERT INTO backup_schedules (user_id, destination, webhook_url)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT (user_id) DO UPDATE SET
+                    destination = excluded.destination,
+                    webhook_url = excluded.webhook_url
+                RETURNING user_id, destination, webhook_url, last_run;
+            "#,
+            user_id,
+            destination,
+            webhook_url,
+        )
+        .fetch_one(self.write_pool())
+        .await
+    }
+
+    /// Turn off a user's backups. Their run history sticks around. Returns
+    /// whether there was anything to turn off.
+    #[tracing::instrument(skip(self))]
+    pub async fn delete(&self, user_id: i64) -> sqlx::Result<bool> {
+        query!(
+            r#"
+                DELETE FROM backup_schedules WHERE user_id = ?;
+            "#,
+            user_id,
+        )
+        .execute(self.write_pool())
+        .await
+        .map(|v| v.rows_affected() > 0)
+    }
+
+    /// Schedules that have never run, or haven't in BACKUP_INTERVAL_DAYS,
+    /// oldest first.
+    #[tracing::instrument(skip(self))]
+    pub async fn due(&self, limit: u32) -> sqlx::Result<Vec<DueBackup>> {
+        let cutoff = format!("-{} days", BACKUP_INTERVAL_DAYS);
+        query_as!(
+            DueBackup,
+            r#"
+                SELECT backup_schedules.user_id, users.username, users.email,
+                    backup_schedules.destination, backup_schedules.webhook_url
+                FROM backup_schedules
+                    JOIN users ON users.id = backup_schedules.user_id
+                WHERE (backup_schedules.last_run IS NULL
+                    OR backup_schedules.last_run < datetime('now', ?1))
+                    AND NOT users.disabled
+                ORDER BY backup_schedules.last_run ASC
+                LIMIT ?2;
+            "#,
+            cutoff,
+            limit,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+
+    /// Note how a run went, start the clock on the next one, and forget
+    /// all but the latest BACKUP_RUNS_KEPT runs.
+    #[tracing::instrument(skip(self))]
+    pub async fn record_run(
+        &self,
+        user_id: i64,
+        destination: BackupDestination,
+        dogears: i64,
+        error: Option<&str>,
+    ) -> sqlx::Result<BackupRun> {
+        let destination: &str = destination.into();
+        let mut tx = self.write_pool().begin().await?;
+        let run = query_as!(
+            BackupRun,
+            r#"
+                INSERT INTO backup_runs (user_id, destination, dogears, error)
+                VALUES (?1, ?2, ?3, ?4)
+                RETURNING id, user_id, destination, ran, dogears, error;
+            "#,
+            user_id,
+            destination,
+            dogears,
+            error,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        query!(
+            r#"
+                UPDATE backup_schedules SET last_run = current_timestamp
+                WHERE user_id = ?;
+            "#,
+            user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        query!(
+            r#"
+                DELETE FROM backup_runs
+                WHERE user_id = ?1 AND id NOT IN (
+                    SELECT id FROM backup_runs WHERE user_id = ?1
+                    ORDER BY id DESC LIMIT ?2
+                );
+            "#,
+            user_id,
+            BACKUP_RUNS_KEPT,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(run)
+    }
+
+    /// A user's recent backup runs, newest first.
+    #[tracing::instrument(skip(self))]
+    pub async fn runs(&self, user_id: i64) -> sqlx::Result<Vec<BackupRun>> {
+        query_as!(
+            BackupRun,
+            r#"
+                SELECT id, user_id, destination, ran, dogears, error
+                FROM backup_runs WHERE user_id = ?1
+                ORDER BY id DESC LIMIT ?2;
+            "#,
+            user_id,
+            BACKUP_RUNS_KEPT,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+}
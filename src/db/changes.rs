@@ -0,0 +1,137 @@
+//! In-process notices that someone's dogears just moved, for anything that
+//! wants to react right away instead of polling the database (like the
+//! long-poll endpoint). Write helpers send a notice after their transaction
+//! commits; nothing here is durable, so a listener that misses one (or
+//! lags behind and gets dropped notices) should just re-check the db. Every
+//! write that changes what a user's dogear list looks like sends one, since
+//! the index page cache counts on them too.
+
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+
+/// How many notices a slow listener can fall behind by before it starts
+/// missing some. Notices are just a user ID, so this is cheap.
+const CAPACITY: usize = 256;
+
+/// A broadcast channel of user IDs. Cheap to clone; every clone shares the
+/// same channel.
+#[derive(Clone, Debug)]
+pub struct DogearChanges {
+    tx: broadcast::Sender<i64>,
+}
+
+impl DogearChanges {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        Self { tx }
+    }
+
+    /// Tell listeners that a user's dogears moved. Nobody listening is fine.
+    pub fn notify(&self, user_id: i64) {
+        let _ = self.tx.send(user_id);
+    }
+
+    /// Start listening. Subscribe *before* checking the db, so nothing can
+    /// slip in between the check and the wait.
+    pub fn subscribe(&self) -> ChangeListener {
+        ChangeListener {
+            rx: self.tx.subscribe(),
+        }
+    }
+}
+
+/// One listener's end of the channel.
+#[derive(Debug)]
+pub struct ChangeListener {
+    rx: broadcast::Receiver<i64>,
+}
+
+impl ChangeListener {
+    /// Wait until something might have changed for this user: either a
+    /// notice for them, or a gap where we don't know what we missed. Never
+    /// resolves once the channel's closed, so race it against a timeout.
+    pub async fn changed(&mut self, user_id: i64) {
+        loop {
+            match self.rx.recv().await {
+                Ok(id) if id == user_id => return,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => return,
+                Err(RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
+
+    /// Wait until anyone's dogears might have changed. Same deal as
+    /// `changed` about closed channels.
+    pub async fn any_changed(&mut self) {
+        match self.rx.recv().await {
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => std::future::pending().await,
+        }
+    }
+
+    /// Everyone whose dogears moved since the last call, without waiting.
+    /// Returns None if notices got dropped in the meantime, which means
+    /// anyone's might have.
+    pub fn drain(&mut self) -> Option<Vec<i64>> {
+        let mut moved = Vec::new();
+        let mut lagged = false;
+        loop {
+            match self.rx.try_recv() {
+                Ok(id) => moved.push(id),
+                // Keep going, so the next call starts caught up.
+                Err(TryRecvError::Lagged(_)) => lagged = true,
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+        (!lagged).then_some(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn only_wakes_for_the_right_user() {
+        let changes = DogearChanges::new();
+        let mut listener = changes.subscribe();
+        changes.notify(2);
+        let wait = Duration::from_millis(50);
+        assert!(timeout(wait, listener.changed(1)).await.is_err());
+        changes.notify(1);
+        assert!(timeout(wait, listener.changed(1)).await.is_ok());
+
+        // Or for anyone, if you're not picky.
+        assert!(timeout(wait, listener.any_changed()).await.is_err());
+        changes.notify(2);
+        assert!(timeout(wait, listener.any_changed()).await.is_ok());
+
+        // Falling way behind counts as a maybe.
+        for _ in 0..(CAPACITY * 2) {
+            changes.notify(2);
+        }
+        assert!(timeout(wait, listener.changed(1)).await.is_ok());
+    }
+
+    #[test]
+    fn drain_catches_up() {
+        let changes = DogearChanges::new();
+        let mut listener = changes.subscribe();
+        assert_eq!(listener.drain(), Some(vec![]));
+        changes.notify(2);
+        changes.notify(1);
+        assert_eq!(listener.drain(), Some(vec![2, 1]));
+        assert_eq!(listener.drain(), Some(vec![]));
+
+        for _ in 0..(CAPACITY * 2) {
+            changes.notify(2);
+        }
+        assert_eq!(listener.drain(), None);
+        assert_eq!(listener.drain(), Some(vec![]));
+    }
+}
@@ -1,8 +1,24 @@
+use super::audit::Audit;
+use super::backups::Backups;
+use super::changes::DogearChanges;
+use super::devices::Devices;
 use super::dogears::Dogears;
+use super::email_changes::EmailChanges;
+use super::feeds::Feeds;
+use super::flag_overrides::FlagOverrides;
+use super::grants::Grants;
+use super::hooks::Hooks;
+use super::kosync::Kosync;
+use super::migration_codes::MigrationCodes;
 use super::migrations::Migrations;
+use super::prefs::Prefs;
 use super::sessions::Sessions;
+use super::site_rules::SiteRules;
 use super::tokens::Tokens;
+use super::totp::Totp;
+use super::tx::DbTx;
 use super::users::Users;
+use super::webhooks::Webhooks;
 use sqlx::SqlitePool;
 use tokio_util::task::TaskTracker;
 
@@ -15,6 +31,8 @@ pub struct Db {
     pub write_pool: SqlitePool,
     // Query helpers may spawn SHORT-LIVED async tasks, so need a tracker but not a cancel token.
     pub task_tracker: TaskTracker,
+    // Shared by every clone, so a write through one wakes listeners on any.
+    changes: DogearChanges,
 }
 
 impl Db {
@@ -24,9 +42,17 @@ impl Db {
             read_pool,
             write_pool,
             task_tracker,
+            changes: DogearChanges::new(),
         }
     }
 
+    /// Start a write transaction for an operation that spans several query
+    /// helpers (see [DbTx]). It doesn't actually touch the database until
+    /// its first query.
+    pub fn transaction(&self) -> DbTx<'_> {
+        DbTx::new(self)
+    }
+
     /// Close all database connections in preparation for shutdown.
     pub async fn close(&self) {
         tokio::join!(self.write_pool.close(), self.read_pool.close());
@@ -48,9 +74,70 @@ impl Db {
         Sessions::new(self)
     }
 
+    pub fn email_changes(&self) -> EmailChanges {
+        EmailChanges::new(self)
+    }
+
+    pub fn grants(&self) -> Grants {
+        Grants::new(self)
+    }
+
+    pub fn prefs(&self) -> Prefs {
+        Prefs::new(self)
+    }
+
+    pub fn devices(&self) -> Devices {
+        Devices::new(self)
+    }
+
+    pub fn kosync(&self) -> Kosync {
+        Kosync::new(self)
+    }
+
+    pub fn site_rules(&self) -> SiteRules {
+        SiteRules::new(self)
+    }
+
+    pub fn backups(&self) -> Backups {
+        Backups::new(self)
+    }
+
+    pub fn migration_codes(&self) -> MigrationCodes {
+        MigrationCodes::new(self)
+    }
+
+    pub fn flag_overrides(&self) -> FlagOverrides {
+        FlagOverrides::new(self)
+    }
+
     pub fn migrations(&self) -> Migrations {
         Migrations::new(self)
     }
+
+    pub fn hooks(&self) -> Hooks {
+        Hooks::new(self)
+    }
+
+    pub fn feeds(&self) -> Feeds {
+        Feeds::new(self)
+    }
+
+    pub fn audit(&self) -> Audit {
+        Audit::new(self)
+    }
+
+    pub fn webhooks(&self) -> Webhooks {
+        Webhooks::new(self)
+    }
+
+    pub fn totp(&self) -> Totp {
+        Totp::new(self)
+    }
+
+    /// Notices about dogears moving, for anyone who wants to wait on them.
+    pub fn changes(&self) -> &DogearChanges {
+        &self.changes
+    }
 }
 
 // Test stuff, kept a lil separate from the main stuff.
@@ -100,21 +187,25 @@ impl Db {
         self.task_tracker.reopen();
     }
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "dev_tools"))]
     pub const TEST_PASSWORD: &'static str = "aoeuhtns";
 
-    /// Test helper. Create a new user with:
+    /// Test helper (also behind `/dev/users` in `dev_tools` builds, so dev
+    /// instances get the same users the tests do). Create a new user with:
     /// - Provided name
     /// - Password "aoeuhtns"
     /// - A write token and a manage token
     /// - An active login session
     /// - Two bookmarks
-    #[cfg(test)]
+    ///
+    /// All in one transaction, so a half-made user never sticks around.
+    #[cfg(any(test, feature = "dev_tools"))]
     pub async fn test_user(&self, name: &str) -> anyhow::Result<TestUser> {
         use super::tokens::TokenScope;
 
+        let tx = self.transaction();
         let (users, tokens, sessions, dogears) =
-            (self.users(), self.tokens(), self.sessions(), self.dogears());
+            (tx.users(), tx.tokens(), tx.sessions(), tx.dogears());
         let email = format!("{}@example.com", name);
 
         let user = users
@@ -151,8 +242,10 @@ impl Db {
                 Some("Example Serial"),
             )
             .await?;
+        tx.commit().await?;
 
         Ok(TestUser {
+            id: user.id,
             name: user.username,
             write_token,
             manage_token,
@@ -164,8 +257,9 @@ impl Db {
 
 /// Test data helper. Credentials for a user who has two dogears
 /// (example.com/comic and /serial).
-#[cfg(test)]
+#[cfg(any(test, feature = "dev_tools"))]
 pub struct TestUser {
+    pub id: i64,
     pub name: String,
     pub write_token: String,
     pub manage_token: String,
@@ -9,13 +9,16 @@
 //! if you can get it to compile it's generally gonna work as expected.
 //! Still, porting the tests is a good way to verify that my port is accurate.
 
-use sqlx::query_scalar;
+use sqlx::{query, query_scalar};
 use time::{Duration, OffsetDateTime};
 
-use crate::util::{ListMeta, MixedError, UserError};
+use crate::util::{
+    Field, ListMeta, MixedError, UserError, UsernamePolicy, DISPLAY_NAME_MAX_LENGTH,
+    PAGE_DEFAULT_SIZE,
+};
 
-use super::tokens::TokenScope;
-use super::Db;
+use super::tokens::{Bookmarklet, TokenScope};
+use super::{AuditKind, AuditSource, Db, Sighting, DEFAULT_STALE_MONTHS};
 
 #[tokio::test]
 async fn cascading_delete() {
@@ -69,6 +72,69 @@ async fn cascading_delete() {
     assert!(dogears.list(user1.id, 1, 50).await.unwrap().0.is_empty());
 }
 
+#[tokio::test]
+async fn transactions() {
+    let db = Db::new_test_db().await;
+
+    // Dropped without a commit: none of it sticks.
+    {
+        let tx = db.transaction();
+        let user = tx.users().create("ghost", "pass1", None).await.unwrap();
+        tx.sessions().create(user.id, None).await.unwrap();
+        tx.dogears()
+            .create(
+                user.id,
+                "example.com/comic",
+                "https://example.com/comic/1",
+                None,
+            )
+            .await
+            .unwrap();
+        // Reads in the transaction see its own writes.
+        assert!(tx.users().by_name("ghost").await.unwrap().is_some());
+        let (list, _meta) = tx.dogears().list(user.id, 1, 50).await.unwrap();
+        assert_eq!(list.len(), 1);
+    }
+    assert!(db.users().by_name("ghost").await.unwrap().is_none());
+
+    // Committed: all of it sticks.
+    let tx = db.transaction();
+    let user = tx.users().create("real", "pass1", None).await.unwrap();
+    let session = tx.sessions().create(user.id, None).await.unwrap();
+    tx.dogears()
+        .create(
+            user.id,
+            "example.com/comic",
+            "https://example.com/comic/1",
+            None,
+        )
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+    assert!(db
+        .sessions()
+        .authenticate(&session.id)
+        .await
+        .unwrap()
+        .is_some());
+    let (list, _meta) = db.dogears().list(user.id, 1, 50).await.unwrap();
+    assert_eq!(list.len(), 1);
+
+    // One failed statement doesn't spoil the rest of the transaction.
+    let tx = db.transaction();
+    let dupe = tx.users().create("real", "pass1", None).await;
+    assert!(matches!(
+        dupe,
+        Err(MixedError::User(UserError::UserExists { .. }))
+    ));
+    tx.users().create("real2", "pass1", None).await.unwrap();
+    tx.commit().await.unwrap();
+    assert!(db.users().by_name("real2").await.unwrap().is_some());
+
+    // Never started, nothing to commit.
+    db.transaction().commit().await.unwrap();
+}
+
 #[tokio::test]
 async fn session_lifetime_modifier() {
     // hardcoded assumption:
@@ -142,6 +208,17 @@ async fn session_lifetime_modifier() {
     assert!(new_stored_delta > Duration::days(89));
     assert!(new_stored_delta < Duration::days(91));
 
+    // Rotating the csrf token sticks, and whiffs once the session's gone.
+    let rotated = db
+        .sessions()
+        .rotate_csrf(sessid)
+        .await
+        .unwrap()
+        .expect("session exists");
+    assert_ne!(rotated, session.csrf_token);
+    let (fresh, _) = db.sessions().authenticate(sessid).await.unwrap().unwrap();
+    assert_eq!(fresh.csrf_token, rotated);
+
     // Now let's list and destroy some things.
     let (list, meta) = db.sessions().list(session_user.id, 1, 50).await.unwrap();
     assert_eq!(meta.count, 1);
@@ -152,6 +229,12 @@ async fn session_lifetime_modifier() {
     // re-destroy whiffs
     let gone = db.sessions().destroy(&doomed_id).await.expect("no db err");
     assert!(gone.is_none());
+    assert!(db
+        .sessions()
+        .rotate_csrf(&doomed_id)
+        .await
+        .unwrap()
+        .is_none());
 }
 
 #[tokio::test]
@@ -203,6 +286,121 @@ async fn token_create_auth_destroy() {
     .await
     .expect("db read err");
     assert!(last.is_some());
+    // ROTATE
+    // safety switch: user_id needs to match
+    assert!(tokens
+        .rotate(right_token.id, wrong_user.id)
+        .await
+        .expect("no err")
+        .is_none());
+    let (rotated, rotated_cleartext) = tokens
+        .rotate(right_token.id, right_user.id)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert_ne!(rotated.id, right_token.id);
+    assert_ne!(rotated_cleartext, right_cleartext);
+    assert_eq!(rotated.scope(), TokenScope::WriteDogears);
+    assert_eq!(rotated.comment.as_deref(), Some("comment"));
+    // old one's dead, new one works
+    assert!(tokens
+        .authenticate(&right_cleartext)
+        .await
+        .expect("no err")
+        .is_none());
+    let (auth_token, _) = tokens
+        .authenticate(&rotated_cleartext)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert_eq!(auth_token.id, rotated.id);
+    // can't rotate a rotated-away token
+    assert!(tokens
+        .rotate(right_token.id, right_user.id)
+        .await
+        .expect("no err")
+        .is_none());
+    // SET SCOPE
+    // same safety switch
+    assert!(tokens
+        .set_scope(rotated.id, wrong_user.id, TokenScope::ManageDogears)
+        .await
+        .expect("no err")
+        .is_none());
+    let upgraded = tokens
+        .set_scope(rotated.id, right_user.id, TokenScope::ManageDogears)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert_eq!(upgraded.id, rotated.id);
+    assert_eq!(upgraded.scope(), TokenScope::ManageDogears);
+    // same cleartext, new powers
+    let (auth_token, _) = tokens
+        .authenticate(&rotated_cleartext)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert_eq!(auth_token.scope(), TokenScope::ManageDogears);
+    // can't switch into or out of the special-purpose scopes
+    assert!(tokens
+        .set_scope(rotated.id, right_user.id, TokenScope::Quickmark)
+        .await
+        .expect("no err")
+        .is_none());
+    let (quickmark, _) = tokens
+        .create(right_user.id, TokenScope::Quickmark, None)
+        .await
+        .expect("token create err");
+    assert!(tokens
+        .set_scope(quickmark.id, right_user.id, TokenScope::WriteDogears)
+        .await
+        .expect("no err")
+        .is_none());
+    // SET COMMENT
+    assert!(tokens
+        .set_comment(rotated.id, wrong_user.id, Some("mine now"))
+        .await
+        .expect("no err")
+        .is_none());
+    let renamed = tokens
+        .set_comment(rotated.id, right_user.id, Some("  phone bookmarklet "))
+        .await
+        .expect("no err")
+        .expect("some");
+    assert_eq!(renamed.comment.as_deref(), Some("phone bookmarklet"));
+    assert_eq!(renamed.scope(), TokenScope::ManageDogears);
+    let cleared = tokens
+        .set_comment(rotated.id, right_user.id, Some(""))
+        .await
+        .expect("no err")
+        .expect("some");
+    assert!(cleared.comment.is_none());
+    let right_token = rotated;
+    let right_cleartext = rotated_cleartext;
+
+    // BOOKMARKLETS
+    // Plain tokens aren't bookmarklets, but bookmarklet tokens are, and
+    // rotating one keeps its kind.
+    assert!(right_token.bookmarklet().is_none());
+    let (marky, _) = tokens
+        .create_bookmarklet(right_user.id, Bookmarklet::PromptNote, Some("phone"))
+        .await
+        .expect("token create err");
+    assert_eq!(marky.scope(), TokenScope::WriteDogears);
+    assert_eq!(marky.bookmarklet(), Some(Bookmarklet::PromptNote));
+    tokens
+        .create_bookmarklet(wrong_user.id, Bookmarklet::Plain, None)
+        .await
+        .expect("token create err");
+    let (remarky, _) = tokens
+        .rotate(marky.id, right_user.id)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert_eq!(remarky.bookmarklet(), Some(Bookmarklet::PromptNote));
+    let listed = tokens.bookmarklets(right_user.id).await.expect("no err");
+    assert_eq!(listed, vec![remarky]);
+
     // DESTROY
     let wrong_destroy = tokens.destroy(right_token.id, wrong_user.id).await;
     // 404
@@ -220,6 +418,159 @@ async fn token_create_auth_destroy() {
     assert!(gone_auth.is_none());
 }
 
+#[tokio::test]
+async fn token_expiry() {
+    let db = Db::new_test_db().await;
+    let tokens = db.tokens();
+    let user = db.test_user("expiring").await.unwrap();
+    let now = OffsetDateTime::now_utc();
+
+    // Fresh ones work, stale ones don't, and plain ones never expire.
+    let (fresh, fresh_cleartext) = tokens
+        .create_expiring(
+            user.id,
+            TokenScope::WriteDogears,
+            None,
+            Some(now + Duration::days(30)),
+        )
+        .await
+        .unwrap();
+    let (_, stale_cleartext) = tokens
+        .create_expiring(
+            user.id,
+            TokenScope::ManageDogears,
+            None,
+            Some(now - Duration::days(1)),
+        )
+        .await
+        .unwrap();
+    // Expiry gets compared as a string in the db, so this one's the
+    // regression check for timestamp formats: a token that ran out a few
+    // minutes ago, on the same day.
+    let (_, just_expired_cleartext) = tokens
+        .create_expiring(
+            user.id,
+            TokenScope::WriteDogears,
+            None,
+            Some(now - Duration::minutes(5)),
+        )
+        .await
+        .unwrap();
+    assert!(fresh.expires.is_some());
+    assert!(tokens
+        .authenticate(&fresh_cleartext)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(tokens
+        .authenticate(&stale_cleartext)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(tokens
+        .authenticate(&just_expired_cleartext)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(tokens
+        .authenticate(&user.write_token)
+        .await
+        .unwrap()
+        .is_some());
+
+    // Rotating keeps the expiry.
+    let (rotated, _) = tokens.rotate(fresh.id, user.id).await.unwrap().unwrap();
+    assert_eq!(rotated.expires, fresh.expires);
+
+    // Cleanup only gets the expired ones.
+    let (_, before) = tokens.list(user.id, 1, 50).await.unwrap();
+    assert_eq!(tokens.delete_expired().await.unwrap(), 2);
+    let (listed, after) = tokens.list(user.id, 1, 50).await.unwrap();
+    assert_eq!(after.count, before.count - 2);
+    assert!(listed.iter().any(|t| t.id == rotated.id));
+}
+
+#[tokio::test]
+async fn kosync_tokens_and_documents() {
+    use super::KosyncPosition;
+    use crate::util::md5sum;
+
+    let db = Db::new_test_db().await;
+    let user = db
+        .users()
+        .create("reader", "password123", None)
+        .await
+        .unwrap();
+    let tokens = db.tokens();
+
+    // Kosync tokens authenticate by the md5 of the cleartext, including
+    // after a rotation, and the plain cleartext gets nothing.
+    let (token, cleartext) = tokens
+        .create(user.id, TokenScope::Kosync, None)
+        .await
+        .unwrap();
+    assert!(tokens.authenticate(&cleartext).await.unwrap().is_none());
+    let (found, _) = tokens
+        .authenticate(&md5sum(&cleartext))
+        .await
+        .unwrap()
+        .expect("md5 works");
+    assert_eq!(found.id, token.id);
+    let (rotated, rotated_cleartext) = tokens.rotate(token.id, user.id).await.unwrap().unwrap();
+    assert_eq!(rotated.scope(), TokenScope::Kosync);
+    assert!(tokens
+        .authenticate(&md5sum(&rotated_cleartext))
+        .await
+        .unwrap()
+        .is_some());
+
+    // Documents: put is an upsert, and they're per-user.
+    let kosync = db.kosync();
+    assert!(kosync.get(user.id, "abc").await.unwrap().is_none());
+    let dogear = db
+        .dogears()
+        .create(
+            user.id,
+            "example.com/kosync/books/abc",
+            "https://example.com/kosync/books/abc",
+            None,
+        )
+        .await
+        .unwrap();
+    let mut position = KosyncPosition {
+        progress: "/body/p[1]",
+        percentage: 0.1,
+        device: "Kobo",
+        device_id: "ABC",
+    };
+    kosync
+        .put(user.id, "abc", Some(dogear.id), position)
+        .await
+        .unwrap();
+    position.progress = "/body/p[9]";
+    position.percentage = 0.9;
+    let saved = kosync
+        .put(user.id, "abc", Some(dogear.id), position)
+        .await
+        .unwrap();
+    assert_eq!(saved.progress, "/body/p[9]");
+    let got = kosync.get(user.id, "abc").await.unwrap().unwrap();
+    assert_eq!(got, saved);
+    assert_eq!(got.dogear_id, Some(dogear.id));
+    let other = db
+        .users()
+        .create("other", "password123", None)
+        .await
+        .unwrap();
+    assert!(kosync.get(other.id, "abc").await.unwrap().is_none());
+
+    // Deleting the dogear keeps the position, minus the link.
+    db.dogears().destroy(dogear.id, user.id).await.unwrap();
+    let got = kosync.get(user.id, "abc").await.unwrap().unwrap();
+    assert_eq!(got.dogear_id, None);
+    assert_eq!(got.percentage, 0.9);
+}
+
 #[tokio::test]
 async fn user_password_auth() {
     let db = Db::new_test_db().await;
@@ -333,6 +684,61 @@ async fn user_password_auth() {
     }
 }
 
+#[tokio::test]
+async fn unicode_usernames() {
+    let db = Db::new_test_db().await;
+    let users = db.users();
+    let create = |policy, name| users.create_with_policy(policy, name, "pass", None);
+    let too_similar = |res: Result<_, MixedError<sqlx::Error>>| {
+        matches!(
+            res,
+            Err(MixedError::User(UserError::UsernameTooSimilar { .. }))
+        )
+    };
+
+    // Saved NFKC-normalized, and found by any equivalent spelling.
+    let zoe = create(UsernamePolicy::Unicode, " zoe\u{308} ")
+        .await
+        .unwrap();
+    assert_eq!(zoe.username, "zoë");
+    for spelling in ["zoë", "zoe\u{308}", " zoë "] {
+        assert!(users
+            .authenticate(spelling, "pass")
+            .await
+            .unwrap()
+            .is_some());
+    }
+    // Grants find people the same way.
+    let owner = create(UsernamePolicy::Ascii, "owner").await.unwrap();
+    db.grants().invite(owner.id, "zoe\u{308}").await.unwrap();
+    assert_eq!(db.grants().list_invitations(zoe.id).await.unwrap().len(), 1);
+
+    // Case and lookalike letters count as the same name...
+    create(UsernamePolicy::Unicode, "paypal").await.unwrap();
+    assert!(too_similar(create(UsernamePolicy::Unicode, "ZOË").await));
+    assert!(too_similar(create(UsernamePolicy::Unicode, "раураl").await));
+    assert!(too_similar(
+        create(UsernamePolicy::Unicode, "ＰａｙＰａｌ").await
+    ));
+    // ...but exact duplicates get the usual error.
+    let dup = create(UsernamePolicy::Unicode, "paypal").await;
+    let Err(MixedError::User(UserError::UserExists { .. })) = dup else {
+        panic!("must return UserExists");
+    };
+    // Other scripts' own names are fine, and so are accents.
+    assert!(create(UsernamePolicy::Unicode, "дмитрий").await.is_ok());
+    assert!(create(UsernamePolicy::Unicode, "zoe").await.is_ok());
+
+    // The ASCII policy keeps its old rules: no lookalike check, and
+    // nothing outside ASCII.
+    assert!(create(UsernamePolicy::Ascii, "PayPal").await.is_ok());
+    let Err(MixedError::User(UserError::BadUsername { .. })) =
+        create(UsernamePolicy::Ascii, "zoë").await
+    else {
+        panic!("must return BadUsername");
+    };
+}
+
 #[tokio::test]
 async fn dogears() {
     let db = Db::new_test_db().await;
@@ -399,6 +805,44 @@ async fn dogears() {
         MixedError::User(UserError::DogearExists { .. }) => (),
         _ => panic!("wrong error: {} (should be DogearExists)", err),
     };
+    // Overlong or junky names and URLs don't go in at all.
+    let long_name = "x".repeat(DISPLAY_NAME_MAX_LENGTH + 1);
+    let long_url = format!("https://example.com/long/{}", "x".repeat(2000));
+    for (prefix, current, name, field) in [
+        (
+            "example.com/long/",
+            "https://example.com/long/1",
+            Some(long_name.as_str()),
+            Field::DisplayName,
+        ),
+        (
+            "example.com/long/",
+            "https://example.com/long/1",
+            Some("Line\nbreak"),
+            Field::DisplayName,
+        ),
+        ("example.com/long/", long_url.as_str(), None, Field::Url),
+        (long_url.as_str(), long_url.as_str(), None, Field::Prefix),
+    ] {
+        let err = dogears
+            .create(user.id, prefix, current, name)
+            .await
+            .expect_err("must error");
+        match err {
+            MixedError::User(
+                UserError::TooLong { field: f, .. } | UserError::BadCharacters { field: f, .. },
+            ) if f == field => (),
+            _ => panic!("wrong error: {} (should be about {:?})", err, field),
+        };
+    }
+    // And moving onto an overlong URL is just a no-match, even under a
+    // prefix you have.
+    let long_comic = format!("https://example.com/comic/{}", "x".repeat(2000));
+    assert!(dogears
+        .update(user.id, &long_comic, None)
+        .await
+        .unwrap()
+        .is_none());
     // LIST: now there's three
     let (list, meta) = dogears.list(user.id, 1, 50).await.expect("no err");
     assert_eq!(list.len(), 3);
@@ -407,6 +851,49 @@ async fn dogears() {
     let (list, _) = dogears.list(wrong_user.id, 1, 50).await.expect("no err");
     assert_eq!(list.len(), 0);
 
+    // FILTERED LIST: substring of name, prefix, or current URL, any case.
+    for (filter, expected) in [
+        ("example comic", vec!["example.com/comic/"]),
+        ("STORY", vec!["example.com/story/"]),
+        ("turnarounds", vec!["example.com/extras/"]),
+    ] {
+        let (list, meta) = dogears
+            .list_filtered(user.id, Some(filter), 1, 50)
+            .await
+            .expect("no err");
+        let prefixes: Vec<&str> = list.iter().map(|d| d.prefix.as_str()).collect();
+        assert_eq!(prefixes, expected, "{}", filter);
+        assert_eq!(meta.count, 1);
+    }
+    // No filter is the same as the plain list.
+    let (list, _) = dogears
+        .list_filtered(user.id, None, 1, 50)
+        .await
+        .expect("no err");
+    assert_eq!(list.len(), 3);
+    // LIKE wildcards in the filter are just characters.
+    for filter in ["%", "_", "com_c", "\\"] {
+        let (list, meta) = dogears
+            .list_filtered(user.id, Some(filter), 1, 50)
+            .await
+            .expect("no err");
+        assert!(list.is_empty(), "{}", filter);
+        assert_eq!(meta.count, 0);
+    }
+    // Counts cover the whole match, not just the page.
+    let (list, meta) = dogears
+        .list_filtered(user.id, Some("/"), 2, 2)
+        .await
+        .expect("no err");
+    assert_eq!(list.len(), 1);
+    assert_eq!(meta.count, 3);
+    // Not somebody else's, though.
+    let (list, _) = dogears
+        .list_filtered(wrong_user.id, Some("example"), 1, 50)
+        .await
+        .expect("no err");
+    assert!(list.is_empty());
+
     // CURRENTLY
     let earlier = &dogear.current;
     // Difference from eardogger 1: Used to be able to check currently on a
@@ -431,6 +918,31 @@ async fn dogears() {
         .expect("no err")
         .is_none());
 
+    // SAME HOST: the near misses for that non-match
+    let near = dogears
+        .same_host(user.id, "https://www.example.com/commie", 5)
+        .await
+        .expect("no err");
+    assert_eq!(near.len(), 3);
+    let near = dogears
+        .same_host(user.id, "https://example.com/commie", 2)
+        .await
+        .expect("no err");
+    assert_eq!(near.len(), 2);
+    // Look-alike hosts and other users don't count
+    for (user_id, url) in [
+        (user.id, "https://example.com.horse/comic/1"),
+        (user.id, "https://example.co/comic/1"),
+        (user.id, "not a url"),
+        (wrong_user.id, "https://example.com/comic/1"),
+    ] {
+        assert!(dogears
+            .same_host(user_id, url, 5)
+            .await
+            .expect("no err")
+            .is_empty());
+    }
+
     // UPDATE
     // Difference from eardogger 1: used to strip whitespace from input URLs, but
     // not anymore.
@@ -440,13 +952,164 @@ async fn dogears() {
         "http://www.example.com/comic/243",
     ] {
         let updated = dogears
-            .update(user.id, url)
+            .update(user.id, url, None)
             .await
             .expect("no err")
             .expect("some");
         assert_eq!(updated.len(), 1);
-        assert_eq!(updated[0].current.as_str(), url);
+        assert_eq!(updated[0].dogear.current.as_str(), url);
     }
+    // Knows where it came from
+    {
+        let updated = dogears
+            .update(user.id, "https://example.com/comic/245", None)
+            .await
+            .expect("no err")
+            .expect("some");
+        assert_eq!(
+            updated[0].previous.as_str(),
+            "http://www.example.com/comic/243"
+        );
+    }
+
+    // PAUSE
+    assert!(!dogear.paused);
+    // safety switch: user_id needs to match
+    assert!(dogears
+        .set_paused(dogear.id, wrong_user.id, true)
+        .await
+        .expect("no err")
+        .is_none());
+    let paused = dogears
+        .set_paused(dogear.id, user.id, true)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert!(paused.paused);
+    // Paused dogears still show up in update results, but don't move.
+    let skipped = dogears
+        .update(user.id, "https://example.com/comic/1", None)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert_eq!(skipped.len(), 1);
+    assert!(skipped[0].dogear.paused);
+    assert_eq!(
+        skipped[0].dogear.current.as_str(),
+        "https://example.com/comic/245"
+    );
+    assert_eq!(skipped[0].previous, skipped[0].dogear.current);
+    // Resume still goes to the real spot.
+    assert_eq!(
+        dogears
+            .current_for_site(user.id, "https://example.com/comic/1")
+            .await
+            .expect("no err")
+            .expect("some"),
+        "https://example.com/comic/245"
+    );
+    // Unpause, and updates work again.
+    let unpaused = dogears
+        .set_paused(dogear.id, user.id, false)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert!(!unpaused.paused);
+    let updated = dogears
+        .update(user.id, "https://example.com/comic/246", None)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert!(!updated[0].dogear.paused);
+    assert_eq!(
+        updated[0].dogear.current.as_str(),
+        "https://example.com/comic/246"
+    );
+
+    // HISTORY: every move gets an entry, but the paused non-move didn't.
+    let history = dogears
+        .history(dogear.id, user.id)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert_eq!(history[0].url, "https://example.com/comic/246");
+    assert_eq!(history[1].url, "https://example.com/comic/245");
+    assert!(history.iter().all(|h| h.note.is_none()));
+    assert!(dogears
+        .history(dogear.id, wrong_user.id)
+        .await
+        .expect("no err")
+        .is_none());
+    // Notes show up as current until the dogear moves on
+    assert!(dogears.current_notes(user.id).await.unwrap().is_empty());
+    dogears
+        .update(user.id, "https://example.com/comic/247", Some("mid-scene"))
+        .await
+        .expect("no err")
+        .expect("some");
+    let notes = dogears.current_notes(user.id).await.unwrap();
+    assert_eq!(notes.get(&dogear.id).map(String::as_str), Some("mid-scene"));
+    assert!(dogears
+        .current_notes(wrong_user.id)
+        .await
+        .unwrap()
+        .is_empty());
+    dogears
+        .update(user.id, "https://example.com/comic/248", None)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert!(dogears.current_notes(user.id).await.unwrap().is_empty());
+    // Tags: repeats are fine, and they're only visible to the owner
+    let tags = |t: &[&str]| t.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    dogears
+        .add_tags(dogear.id, &tags(&["zines", "comics"]))
+        .await
+        .unwrap();
+    dogears
+        .add_tags(dogear.id, &tags(&["comics"]))
+        .await
+        .unwrap();
+    let found = dogears.tags(user.id).await.unwrap();
+    assert_eq!(found.get(&dogear.id), Some(&tags(&["comics", "zines"])));
+    assert!(dogears.tags(wrong_user.id).await.unwrap().is_empty());
+    let history = dogears
+        .history(dogear.id, user.id)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert_eq!(history[1].note.as_deref(), Some("mid-scene"));
+
+    // PUBLIC: private by default, only the owner can flip it, and only
+    // public ones get listed.
+    assert!(!dogear.public);
+    assert!(dogears.list_public(user.id, 10).await.unwrap().is_empty());
+    assert!(dogears
+        .set_public(dogear.id, wrong_user.id, true)
+        .await
+        .expect("no err")
+        .is_none());
+    let published = dogears
+        .set_public(dogear.id, user.id, true)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert!(published.public);
+    let listed = dogears.list_public(user.id, 10).await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].id, dogear.id);
+    assert!(dogears
+        .list_public(wrong_user.id, 10)
+        .await
+        .unwrap()
+        .is_empty());
+    dogears
+        .set_public(dogear.id, user.id, false)
+        .await
+        .expect("no err")
+        .expect("some");
+    assert!(dogears.list_public(user.id, 10).await.unwrap().is_empty());
+
     // Non-matching url
     assert!(dogears
         .current_for_site(user.id, "https://example.com/com/not-dogeared")
@@ -471,8 +1134,29 @@ async fn dogears() {
     // Unchanged:
     assert_eq!(list.len(), 3);
 
-    // DESTROY
-    // safety switch: user_id needs to match
+    // EXPORT: pages by id, picking up after the last one
+    let first = dogears.export_page(user.id, 0, 2).await.expect("no err");
+    assert_eq!(first.len(), 2);
+    assert!(first[0].id < first[1].id);
+    let rest = dogears
+        .export_page(user.id, first[1].id, 2)
+        .await
+        .expect("no err");
+    assert_eq!(rest.len(), 1);
+    assert!(dogears
+        .export_page(user.id, rest[0].id, 2)
+        .await
+        .expect("no err")
+        .is_empty());
+    // Only yours
+    assert!(dogears
+        .export_page(wrong_user.id, 0, 50)
+        .await
+        .expect("no err")
+        .is_empty());
+
+    // DESTROY
+    // safety switch: user_id needs to match
     assert!(dogears
         .destroy(second.id, wrong_user.id)
         .await
@@ -494,3 +1178,1197 @@ async fn migrations_test() {
     // By definition, this just had the migrations run on it. So:
     db.migrations().validate().await.expect("migrations valid");
 }
+
+#[tokio::test]
+async fn grants() {
+    let db = Db::new_test_db().await;
+    let grants = db.grants();
+    let owner = db.users().create("owner", "pass", None).await.unwrap();
+    let partner = db.users().create("partner", "pass", None).await.unwrap();
+    let rando = db.users().create("rando", "pass", None).await.unwrap();
+
+    // Nothing shared yet
+    assert!(grants.list_given(owner.id).await.unwrap().is_empty());
+    assert!(grants.find("owner", partner.id).await.unwrap().is_none());
+
+    // INVITE
+    grants.invite(owner.id, "partner").await.expect("no err");
+    // Doubling up and inviting nobody look just the same as the real thing
+    grants.invite(owner.id, "partner").await.expect("no err");
+    grants.invite(owner.id, "nobody").await.expect("no err");
+    // Can't share w/ yourself
+    match grants.invite(owner.id, "owner").await {
+        Err(MixedError::User(UserError::GrantSelf)) => (),
+        other => panic!("should be GrantSelf, got {:?}", other),
+    }
+    // Nothing's shared until the grantee says so. The owner sees the one
+    // real invitation as pending, so they can take it back.
+    assert!(grants.find("owner", partner.id).await.unwrap().is_none());
+    assert!(grants.list_received(partner.id).await.unwrap().is_empty());
+    let invitations = grants.list_invitations(partner.id).await.unwrap();
+    assert_eq!(invitations.len(), 1);
+    let invitation = invitations[0].clone();
+    assert_eq!(invitation.owner_id, owner.id);
+    assert_eq!(invitation.grantee_id, partner.id);
+    assert_eq!(invitation.owner_username.as_str(), "owner");
+    assert_eq!(invitation.grantee_username.as_str(), "partner");
+    assert!(invitation.accepted.is_none());
+    assert_eq!(
+        grants.list_given(owner.id).await.unwrap(),
+        vec![invitation.clone()]
+    );
+    let grant = invitation;
+
+    // ACCEPT: only the grantee, and only once
+    let invitation_id = grant.id;
+    assert!(grants.accept(grant.id, owner.id).await.unwrap().is_none());
+    assert!(grants.accept(grant.id, partner.id).await.unwrap().is_some());
+    assert!(grants.accept(grant.id, partner.id).await.unwrap().is_none());
+    assert!(grants
+        .list_invitations(partner.id)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // FIND: one-way only
+    let grant = grants
+        .find("owner", partner.id)
+        .await
+        .unwrap()
+        .expect("accepted grant");
+    assert_eq!(grant.id, invitation_id);
+    assert!(grant.accepted.is_some());
+    assert!(grants.find("partner", owner.id).await.unwrap().is_none());
+    assert!(grants.find("owner", rando.id).await.unwrap().is_none());
+
+    // LISTS
+    assert_eq!(
+        grants.list_given(owner.id).await.unwrap(),
+        vec![grant.clone()]
+    );
+    assert_eq!(
+        grants.list_received(partner.id).await.unwrap(),
+        vec![grant.clone()]
+    );
+    assert!(grants.list_received(owner.id).await.unwrap().is_empty());
+
+    // DESTROY: only the owner can revoke
+    assert!(grants
+        .destroy(grant.id, partner.id)
+        .await
+        .expect("no err")
+        .is_none());
+    assert!(grants
+        .destroy(grant.id, owner.id)
+        .await
+        .expect("no err")
+        .is_some());
+    assert!(grants.find("owner", partner.id).await.unwrap().is_none());
+
+    // ...including an invitation nobody's accepted yet.
+    grants.invite(owner.id, "partner").await.unwrap();
+    let pending = grants.list_given(owner.id).await.unwrap()[0].clone();
+    assert!(grants
+        .destroy(pending.id, owner.id)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(grants
+        .list_invitations(partner.id)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // DECLINE: the grantee's way out, before or after accepting
+    grants.invite(owner.id, "partner").await.unwrap();
+    let grant = grants.list_invitations(partner.id).await.unwrap()[0].clone();
+    assert!(grants.decline(grant.id, owner.id).await.unwrap().is_none());
+    assert!(grants
+        .decline(grant.id, partner.id)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(grants
+        .list_invitations(partner.id)
+        .await
+        .unwrap()
+        .is_empty());
+    grants.invite(owner.id, "partner").await.unwrap();
+    let grant = grants.list_invitations(partner.id).await.unwrap()[0].clone();
+    grants.accept(grant.id, partner.id).await.unwrap();
+    assert!(grants
+        .decline(grant.id, partner.id)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(grants.find("owner", partner.id).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn prefs_and_devices() {
+    let db = Db::new_test_db().await;
+    let (prefs, devices) = (db.prefs(), db.devices());
+    let user = db.users().create("user", "pass", None).await.unwrap();
+    let other = db.users().create("other", "pass", None).await.unwrap();
+
+    // PREFS: defaults until set, and set is an upsert
+    assert!(!prefs.get(user.id).await.unwrap().notify_new_device);
+    prefs.set_notify_new_device(user.id, true).await.unwrap();
+    assert!(prefs.get(user.id).await.unwrap().notify_new_device);
+    assert!(!prefs.get(other.id).await.unwrap().notify_new_device);
+    prefs.set_notify_new_device(user.id, false).await.unwrap();
+    assert!(!prefs.get(user.id).await.unwrap().notify_new_device);
+    // Separate prefs don't step on each other
+    prefs.set_public_profile(user.id, true).await.unwrap();
+    prefs.set_notify_new_device(user.id, true).await.unwrap();
+    let got = prefs.get(user.id).await.unwrap();
+    assert!(got.public_profile && got.notify_new_device);
+    let profiles = prefs.public_profiles(10).await.unwrap();
+    assert_eq!(profiles.len(), 1);
+    assert_eq!(profiles[0].username, "user");
+    assert!(profiles[0].last_read.is_none());
+    prefs
+        .set_custom_css(user.id, Some("body { color: red; }"))
+        .await
+        .unwrap();
+    let got = prefs.get(user.id).await.unwrap();
+    assert_eq!(got.custom_css.as_deref(), Some("body { color: red; }"));
+    assert!(got.public_profile);
+    prefs.set_custom_css(user.id, None).await.unwrap();
+    assert_eq!(prefs.get(user.id).await.unwrap().custom_css, None);
+    prefs
+        .set_list_display(user.id, true, true, false)
+        .await
+        .unwrap();
+    let got = prefs.get(user.id).await.unwrap();
+    assert!(got.compact_list && got.show_prefix && !got.hide_dates);
+    assert!(got.public_profile);
+    assert_eq!(got.default_page_size(), PAGE_DEFAULT_SIZE);
+    prefs.set_page_size(user.id, Some(10)).await.unwrap();
+    assert_eq!(prefs.get(user.id).await.unwrap().default_page_size(), 10);
+    prefs.set_page_size(user.id, None).await.unwrap();
+    assert_eq!(
+        prefs.get(user.id).await.unwrap().default_page_size(),
+        PAGE_DEFAULT_SIZE
+    );
+
+    // DEVICES: first one's free, repeats are known, others are new
+    assert_eq!(
+        devices.record(user.id, "laptop").await.unwrap(),
+        Sighting::First
+    );
+    assert_eq!(
+        devices.record(user.id, "laptop").await.unwrap(),
+        Sighting::Known
+    );
+    assert_eq!(
+        devices.record(user.id, "phone").await.unwrap(),
+        Sighting::New
+    );
+    assert_eq!(
+        devices.record(user.id, "phone").await.unwrap(),
+        Sighting::Known
+    );
+    // Per user
+    assert_eq!(
+        devices.record(other.id, "phone").await.unwrap(),
+        Sighting::First
+    );
+
+    // Both go away with the user
+    db.users().destroy(user.id).await.unwrap();
+    let leftovers = query_scalar!(
+        r#"
+            SELECT count(*) AS 'count: u32' FROM seen_devices WHERE user_id = ?;
+        "#,
+        user.id
+    )
+    .fetch_one(&db.read_pool)
+    .await
+    .unwrap();
+    assert_eq!(leftovers, 0);
+}
+
+#[tokio::test]
+async fn email_changes() {
+    let db = Db::new_test_db().await;
+    let user = db.test_user("whoever").await.unwrap();
+    let user_id = db.users().by_name("whoever").await.unwrap().unwrap().id;
+    let changes = db.email_changes();
+    let email = {
+        let db = &db;
+        move || async move { db.users().by_name("whoever").await.unwrap().unwrap().email }
+    };
+    assert_eq!(changes.pending(user_id).await.unwrap(), None);
+
+    // A pending change doesn't touch the address until it's confirmed
+    let first = changes
+        .create(
+            user_id,
+            Some("whoever@example.com"),
+            Some("first@example.com"),
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(email().await.as_deref(), Some("whoever@example.com"));
+    assert_eq!(
+        changes.pending(user_id).await.unwrap().as_deref(),
+        Some("first@example.com")
+    );
+    let (found, username) = changes.by_id(first.id).await.unwrap().unwrap();
+    assert_eq!(found, first);
+    assert_eq!(username, "whoever");
+
+    // A newer change replaces it, so the older confirm link is dead
+    let second = changes
+        .create(
+            user_id,
+            Some("whoever@example.com"),
+            Some("second@example.com"),
+            false,
+        )
+        .await
+        .unwrap();
+    assert!(changes.confirm(first.id).await.unwrap().is_none());
+    assert!(changes.confirm(second.id).await.unwrap().is_some());
+    assert_eq!(email().await.as_deref(), Some("second@example.com"));
+    assert_eq!(changes.pending(user_id).await.unwrap(), None);
+    // Once only
+    assert!(changes.confirm(second.id).await.unwrap().is_none());
+
+    // Removing takes effect right away
+    let third = changes
+        .create(user_id, Some("second@example.com"), None, true)
+        .await
+        .unwrap();
+    assert_eq!(email().await, None);
+
+    // Reverting the second change puts the original back, cancels the
+    // third, and logs everybody out.
+    assert!(db
+        .sessions()
+        .authenticate(&user.session_id)
+        .await
+        .unwrap()
+        .is_some());
+    let reverted = changes.revert(second.id).await.unwrap().unwrap();
+    assert_eq!(reverted.old_email.as_deref(), Some("whoever@example.com"));
+    assert_eq!(email().await.as_deref(), Some("whoever@example.com"));
+    assert!(db
+        .sessions()
+        .authenticate(&user.session_id)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(changes.revert(third.id).await.unwrap().is_none());
+    // Once only
+    assert!(changes.revert(second.id).await.unwrap().is_none());
+
+    // Old links expire
+    let old = changes
+        .create(
+            user_id,
+            Some("whoever@example.com"),
+            Some("late@example.com"),
+            false,
+        )
+        .await
+        .unwrap();
+    let long_ago = OffsetDateTime::now_utc() - Duration::days(30);
+    query!(
+        r#"
+            UPDATE email_changes SET created = datetime(?1)
+            WHERE id = ?2;
+        "#,
+        long_ago,
+        old.id,
+    )
+    .execute(&db.write_pool)
+    .await
+    .unwrap();
+    assert_eq!(changes.pending(user_id).await.unwrap(), None);
+    assert!(changes.confirm(old.id).await.unwrap().is_none());
+    assert!(changes.revert(old.id).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn retention() {
+    let db = Db::new_test_db().await;
+    let dogears = db.dogears();
+    let keeper = db.users().create("keeper", "pass", None).await.unwrap();
+    let tidy = db.users().create("tidy", "pass", None).await.unwrap();
+    db.prefs()
+        .set_history_months(tidy.id, Some(3))
+        .await
+        .unwrap();
+    assert_eq!(
+        db.prefs().get(tidy.id).await.unwrap().history_months,
+        Some(3)
+    );
+
+    // Three old moves apiece, plus one dogear that hasn't moved since
+    let mut ids = Vec::new();
+    for user_id in [keeper.id, tidy.id] {
+        let dogear = dogears
+            .create(
+                user_id,
+                "example.com/comic",
+                "https://example.com/comic/1",
+                None,
+            )
+            .await
+            .unwrap();
+        for page in 2..=4 {
+            dogears
+                .update(
+                    user_id,
+                    &format!("https://example.com/comic/{}", page),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+        ids.push((dogear.id, user_id));
+    }
+    let stale = dogears
+        .create(
+            keeper.id,
+            "example.com/stale",
+            "https://example.com/stale/1",
+            None,
+        )
+        .await
+        .unwrap();
+    dogears
+        .update(keeper.id, "https://example.com/stale/2", None)
+        .await
+        .unwrap();
+    query!(
+        r#"
+            UPDATE dogear_history SET created = datetime('now', '-10 months');
+        "#
+    )
+    .execute(&db.write_pool)
+    .await
+    .unwrap();
+    // ...and one fresh move each on the comics
+    for (_, user_id) in &ids {
+        dogears
+            .update(*user_id, "https://example.com/comic/5", None)
+            .await
+            .unwrap();
+    }
+    let history_len = |(dogear_id, user_id): (i64, i64)| {
+        let dogears = &dogears;
+        async move {
+            dogears
+                .history(dogear_id, user_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .len()
+        }
+    };
+    assert_eq!(history_len(ids[0]).await, 4);
+
+    // No site limit: only the user who asked gets trimmed
+    assert_eq!(dogears.delete_old_history(None).await.unwrap(), 3);
+    assert_eq!(history_len(ids[0]).await, 4);
+    assert_eq!(history_len(ids[1]).await, 1);
+    // A longer site limit doesn't catch anything new...
+    assert_eq!(dogears.delete_old_history(Some(12)).await.unwrap(), 0);
+    // ...but a shorter one does, except each dogear's latest spot.
+    dogears.delete_old_history(Some(6)).await.unwrap();
+    assert_eq!(history_len(ids[0]).await, 1);
+    assert_eq!(history_len((stale.id, keeper.id)).await, 1);
+
+    // Devices go by last login
+    let devices = db.devices();
+    devices.record(keeper.id, "old-laptop").await.unwrap();
+    query!(
+        r#"
+            UPDATE seen_devices SET last_seen = datetime('now', '-13 months');
+        "#
+    )
+    .execute(&db.write_pool)
+    .await
+    .unwrap();
+    devices.record(keeper.id, "new-phone").await.unwrap();
+    assert_eq!(devices.delete_stale(12).await.unwrap(), 1);
+    assert_eq!(
+        devices.record(keeper.id, "new-phone").await.unwrap(),
+        Sighting::Known
+    );
+    assert_eq!(
+        devices.record(keeper.id, "old-laptop").await.unwrap(),
+        Sighting::New
+    );
+
+    // Email changes go by age
+    let changes = db.email_changes();
+    changes
+        .create(keeper.id, None, Some("old@example.com"), true)
+        .await
+        .unwrap();
+    query!(
+        r#"
+            UPDATE email_changes SET created = datetime('now', '-2 months');
+        "#
+    )
+    .execute(&db.write_pool)
+    .await
+    .unwrap();
+    changes
+        .create(
+            keeper.id,
+            Some("old@example.com"),
+            Some("new@example.com"),
+            true,
+        )
+        .await
+        .unwrap();
+    assert_eq!(changes.delete_old(1).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn archive() {
+    let db = Db::new_test_db().await;
+    let dogears = db.dogears();
+    let user = db.test_user("whoever").await.unwrap();
+    let other_user = db.test_user("someone_else").await.unwrap();
+    let (list, meta) = dogears.list(user.id, 1, 50).await.unwrap();
+    assert_eq!(meta.count, 2);
+    let comic = list
+        .iter()
+        .find(|d| d.prefix == "example.com/comic")
+        .unwrap()
+        .clone();
+    assert!(!comic.archived);
+    query!(
+        r#"
+            UPDATE dogears SET updated = datetime('now', '-8 months') WHERE id = ?;
+        "#,
+        comic.id,
+    )
+    .execute(&db.write_pool)
+    .await
+    .unwrap();
+
+    // Stale by a six-month threshold, but not by a year.
+    assert_eq!(dogears.stale_ids(user.id, 6).await.unwrap(), vec![comic.id]);
+    assert!(dogears.stale_ids(user.id, 12).await.unwrap().is_empty());
+    assert!(dogears
+        .stale_ids(other_user.id, 6)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Archiving: user_id needs to match, and then it's off the main list.
+    assert!(dogears
+        .set_archived(comic.id, other_user.id, true)
+        .await
+        .unwrap()
+        .is_none());
+    let archived = dogears
+        .set_archived(comic.id, user.id, true)
+        .await
+        .unwrap()
+        .expect("some");
+    assert!(archived.archived);
+    let (list, meta) = dogears.list(user.id, 1, 50).await.unwrap();
+    assert_eq!(meta.count, 1);
+    assert!(list.iter().all(|d| d.id != comic.id));
+    let (list, meta) = dogears.list_archived(user.id, 1, 50).await.unwrap();
+    assert_eq!(meta.count, 1);
+    assert_eq!(list[0].id, comic.id);
+    // It's no longer nagging, but resume can still find it.
+    assert!(dogears.stale_ids(user.id, 6).await.unwrap().is_empty());
+    assert_eq!(
+        dogears
+            .current_for_site(user.id, "https://example.com/comic/1")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("https://example.com/comic/24")
+    );
+
+    // Marking it brings it back out.
+    let updated = dogears
+        .update(user.id, "https://example.com/comic/25", None)
+        .await
+        .unwrap()
+        .expect("some");
+    assert!(!updated[0].dogear.archived);
+    assert_eq!(dogears.list(user.id, 1, 50).await.unwrap().1.count, 2);
+
+    // Archive all stale: only this user's, and only once.
+    query!(
+        r#"
+            UPDATE dogears SET updated = datetime('now', '-8 months') WHERE user_id = ?;
+        "#,
+        user.id,
+    )
+    .execute(&db.write_pool)
+    .await
+    .unwrap();
+    assert_eq!(dogears.archive_stale(user.id, 6).await.unwrap(), 2);
+    assert_eq!(dogears.archive_stale(user.id, 6).await.unwrap(), 0);
+    assert_eq!(dogears.list(user.id, 1, 50).await.unwrap().1.count, 0);
+    assert_eq!(dogears.list(other_user.id, 1, 50).await.unwrap().1.count, 2);
+
+    // The threshold pref: default, custom, off, and back to default.
+    let prefs = db.prefs();
+    let stale_after = || {
+        let prefs = &prefs;
+        async move { prefs.get(user.id).await.unwrap().stale_after() }
+    };
+    assert_eq!(stale_after().await, Some(DEFAULT_STALE_MONTHS));
+    prefs.set_stale_months(user.id, Some(12)).await.unwrap();
+    assert_eq!(stale_after().await, Some(12));
+    prefs.set_stale_months(user.id, Some(0)).await.unwrap();
+    assert_eq!(stale_after().await, None);
+    prefs.set_stale_months(user.id, None).await.unwrap();
+    assert_eq!(stale_after().await, Some(DEFAULT_STALE_MONTHS));
+}
+
+#[tokio::test]
+async fn backups() {
+    use super::backups::BACKUP_RUNS_KEPT;
+    use super::BackupDestination;
+
+    let db = Db::new_test_db().await;
+    let backups = db.backups();
+    let user = db
+        .users()
+        .create("backer", "pass", Some("backer@example.com"))
+        .await
+        .unwrap();
+    let other = db.users().create("other", "pass", None).await.unwrap();
+
+    // Nothing scheduled, nothing due
+    assert!(backups.get(user.id).await.unwrap().is_none());
+    assert!(backups.due(10).await.unwrap().is_empty());
+
+    // A new schedule is due right away
+    let schedule = backups
+        .set(user.id, BackupDestination::Email, None)
+        .await
+        .unwrap();
+    assert_eq!(schedule.destination(), BackupDestination::Email);
+    assert_eq!(schedule.last_run, None);
+    backups
+        .set(
+            other.id,
+            BackupDestination::Webhook,
+            Some("https://example.com/hook"),
+        )
+        .await
+        .unwrap();
+    let due = backups.due(10).await.unwrap();
+    assert_eq!(due.len(), 2);
+    let mine = due.iter().find(|d| d.user_id == user.id).unwrap();
+    assert_eq!(mine.username, "backer");
+    assert_eq!(mine.email.as_deref(), Some("backer@example.com"));
+    assert_eq!(backups.due(1).await.unwrap().len(), 1);
+
+    // A run (good or bad) starts the clock, and only the latest few stick
+    for n in 0..(BACKUP_RUNS_KEPT + 3) {
+        let error = (n % 2 == 0).then_some("nope");
+        backups
+            .record_run(user.id, BackupDestination::Email, n, error)
+            .await
+            .unwrap();
+    }
+    let due = backups.due(10).await.unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].user_id, other.id);
+    let runs = backups.runs(user.id).await.unwrap();
+    assert_eq!(runs.len() as i64, BACKUP_RUNS_KEPT);
+    assert_eq!(runs[0].dogears, BACKUP_RUNS_KEPT + 2);
+    assert_eq!(runs[0].error.as_deref(), Some("nope"));
+    assert!(backups.runs(other.id).await.unwrap().is_empty());
+    let last_run = backups.get(user.id).await.unwrap().unwrap().last_run;
+    assert!(last_run.is_some());
+
+    // Changing the destination doesn't reset the clock
+    let schedule = backups
+        .set(
+            user.id,
+            BackupDestination::Webhook,
+            Some("https://example.com/mine"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(schedule.last_run, last_run);
+    assert_eq!(
+        schedule.webhook_url.as_deref(),
+        Some("https://example.com/mine")
+    );
+
+    // Comes due again after a week
+    query!(
+        r#"
+            UPDATE backup_schedules SET last_run = datetime('now', '-8 days');
+        "#
+    )
+    .execute(&db.write_pool)
+    .await
+    .unwrap();
+    assert_eq!(backups.due(10).await.unwrap().len(), 2);
+
+    // Turning it off keeps the history; deleting the user takes everything
+    assert!(backups.delete(user.id).await.unwrap());
+    assert!(!backups.delete(user.id).await.unwrap());
+    assert!(backups.get(user.id).await.unwrap().is_none());
+    assert_eq!(
+        backups.runs(user.id).await.unwrap().len() as i64,
+        BACKUP_RUNS_KEPT
+    );
+    db.users().destroy(user.id).await.unwrap();
+    assert!(backups.runs(user.id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn migration_codes() {
+    let db = Db::new_test_db().await;
+    let codes = db.migration_codes();
+    let user = db.users().create("mover", "pass", None).await.unwrap();
+    let other = db.users().create("other", "pass", None).await.unwrap();
+
+    // Works once
+    let code = codes.create(user.id).await.unwrap();
+    assert!(codes.redeem("eardoggermove1.nope").await.unwrap().is_none());
+    assert_eq!(codes.redeem(&code).await.unwrap(), Some(user.id));
+    assert!(codes.redeem(&code).await.unwrap().is_none());
+
+    // A new code replaces the old one
+    let first = codes.create(user.id).await.unwrap();
+    let second = codes.create(user.id).await.unwrap();
+    let others = codes.create(other.id).await.unwrap();
+    assert!(codes.redeem(&first).await.unwrap().is_none());
+    assert_eq!(codes.redeem(&second).await.unwrap(), Some(user.id));
+
+    // Expired codes don't work, and get cleaned up
+    let stale = codes.create(user.id).await.unwrap();
+    query!(
+        r#"
+            UPDATE migration_codes SET created = datetime('now', '-2 hours')
+            WHERE user_id = ?;
+        "#,
+        user.id,
+    )
+    .execute(&db.write_pool)
+    .await
+    .unwrap();
+    assert_eq!(codes.delete_expired().await.unwrap(), 1);
+    assert!(codes.redeem(&stale).await.unwrap().is_none());
+    assert_eq!(codes.redeem(&others).await.unwrap(), Some(other.id));
+}
+
+#[tokio::test]
+async fn flag_overrides() {
+    let db = Db::new_test_db().await;
+    let flags = db.flag_overrides();
+    assert!(flags.list().await.unwrap().is_empty());
+
+    let set = flags.set("queue_mode", 25).await.unwrap();
+    assert_eq!(set.rollout, 25);
+    flags.set("sse_refresh", 100).await.unwrap();
+    // Setting it again replaces it
+    let reset = flags.set("queue_mode", 50).await.unwrap();
+    assert_eq!(reset.rollout, 50);
+    let list = flags.list().await.unwrap();
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].name, "queue_mode");
+    assert_eq!(list[0].rollout, 50);
+
+    assert!(flags.clear("queue_mode").await.unwrap());
+    assert!(!flags.clear("queue_mode").await.unwrap());
+    let list = flags.list().await.unwrap();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].name, "sse_refresh");
+}
+
+#[tokio::test]
+async fn admin_flags() {
+    let db = Db::new_test_db().await;
+    let boss = db.test_user("boss").await.unwrap();
+    let peon = db.test_user("peon").await.unwrap();
+    let users = db.users();
+
+    // Nobody's an admin to start with.
+    let flags = users.flags(boss.id).await.unwrap().unwrap();
+    assert_eq!(flags, Default::default());
+    assert!(users.flags(9999).await.unwrap().is_none());
+    assert!(users.set_admin("boss", true).await.unwrap().is_some());
+    assert!(users.set_admin("nobody", true).await.unwrap().is_none());
+    assert!(users.flags(boss.id).await.unwrap().unwrap().is_admin);
+
+    // The list has everyone, with their stuff counted up.
+    let (list, meta) = users.list_for_admin(1, 50).await.unwrap();
+    assert_eq!(meta.count, 2);
+    assert_eq!(list[0].username, "boss");
+    assert!(list[0].is_admin);
+    assert_eq!(list[1].username, "peon");
+    assert_eq!(list[1].dogears, 2);
+    assert_eq!(list[1].sessions, 1);
+
+    // Disabling: no login, no sessions, no tokens.
+    assert!(users.set_disabled(peon.id, true).await.unwrap().is_some());
+    assert!(users
+        .authenticate("peon", Db::TEST_PASSWORD)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(db
+        .sessions()
+        .authenticate(&peon.session_id)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(db
+        .tokens()
+        .authenticate(&peon.write_token)
+        .await
+        .unwrap()
+        .is_none());
+    // Nor do any sessions or tokens that turn up afterwards (like from a
+    // two-factor login that was already halfway done).
+    let late_session = db.sessions().create(peon.id, None).await.unwrap();
+    let (_, late_token) = db
+        .tokens()
+        .create(peon.id, TokenScope::WriteDogears, None)
+        .await
+        .unwrap();
+    assert!(db
+        .sessions()
+        .authenticate(&late_session.id)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(db
+        .tokens()
+        .authenticate(&late_token)
+        .await
+        .unwrap()
+        .is_none());
+    // Enabling lets them back in, but what's gone is gone.
+    assert!(users.set_disabled(peon.id, false).await.unwrap().is_some());
+    assert!(users
+        .authenticate("peon", Db::TEST_PASSWORD)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(users.set_disabled(9999, true).await.unwrap().is_none());
+
+    // Forced reset: logged out, but tokens and logins still work, until a
+    // new password clears it.
+    assert!(users.force_password_reset(boss.id).await.unwrap().is_some());
+    assert!(
+        users
+            .flags(boss.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .must_reset_password
+    );
+    assert!(db
+        .sessions()
+        .authenticate(&boss.session_id)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(db
+        .tokens()
+        .authenticate(&boss.write_token)
+        .await
+        .unwrap()
+        .is_some());
+    users
+        .set_password("boss", "a whole new password")
+        .await
+        .unwrap();
+    assert!(
+        !users
+            .flags(boss.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .must_reset_password
+    );
+    assert!(users.force_password_reset(9999).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn hooks() {
+    let db = Db::new_test_db().await;
+    let hooks = db.hooks();
+    let user = db.test_user("hooked").await.unwrap();
+    let other = db.test_user("other").await.unwrap();
+    let (dogears, _) = db.dogears().list(user.id, 1, 50).await.unwrap();
+    let comic = dogears
+        .iter()
+        .find(|d| d.prefix == "example.com/comic")
+        .unwrap();
+    let serial = dogears
+        .iter()
+        .find(|d| d.prefix == "example.com/serial")
+        .unwrap();
+
+    // Only for your own dogears
+    assert!(hooks.create(comic.id, other.id).await.unwrap().is_none());
+    assert!(hooks.list(other.id).await.unwrap().is_empty());
+
+    let secret = hooks.create(comic.id, user.id).await.unwrap().unwrap();
+    assert!(hooks
+        .authenticate("eardoggerhook1.nope")
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        hooks.authenticate(&secret).await.unwrap(),
+        Some((comic.id, user.id))
+    );
+    let list = hooks.list(user.id).await.unwrap();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].prefix, "example.com/comic");
+    assert!(list[0].last_used.is_some());
+
+    // A new one replaces the old one
+    let fresh = hooks.create(comic.id, user.id).await.unwrap().unwrap();
+    assert!(hooks.authenticate(&secret).await.unwrap().is_none());
+    assert_eq!(
+        hooks.authenticate(&fresh).await.unwrap(),
+        Some((comic.id, user.id))
+    );
+    assert_eq!(hooks.list(user.id).await.unwrap().len(), 1);
+
+    // update_one only moves its own dogear, and only to a matching URL
+    let dogears = db.dogears();
+    let moved = dogears
+        .update_one(comic.id, user.id, "https://example.com/comic/25", None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(moved.current, "https://example.com/comic/25");
+    assert!(dogears
+        .update_one(comic.id, user.id, "https://example.com/serial/5", None)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(dogears
+        .update_one(serial.id, other.id, "https://example.com/serial/5", None)
+        .await
+        .unwrap()
+        .is_none());
+    dogears.set_paused(comic.id, user.id, true).await.unwrap();
+    assert!(dogears
+        .update_one(comic.id, user.id, "https://example.com/comic/26", None)
+        .await
+        .unwrap()
+        .is_none());
+    let history = dogears.history(comic.id, user.id).await.unwrap().unwrap();
+    assert_eq!(history[0].url, "https://example.com/comic/25");
+
+    // Gone when you turn it off, or when the dogear goes
+    assert!(hooks.destroy(comic.id, user.id).await.unwrap());
+    assert!(!hooks.destroy(comic.id, user.id).await.unwrap());
+    assert!(hooks.authenticate(&fresh).await.unwrap().is_none());
+    let last = hooks.create(serial.id, user.id).await.unwrap().unwrap();
+    dogears.destroy(serial.id, user.id).await.unwrap();
+    assert!(hooks.authenticate(&last).await.unwrap().is_none());
+    assert!(hooks.list(user.id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn feeds() {
+    let db = Db::new_test_db().await;
+    let feeds = db.feeds();
+    let user = db.test_user("subscriber").await.unwrap();
+    let other = db.test_user("other").await.unwrap();
+    let (dogears, _) = db.dogears().list(user.id, 1, 50).await.unwrap();
+    let comic = dogears
+        .iter()
+        .find(|d| d.prefix == "example.com/comic")
+        .unwrap();
+    let url = "https://example.com/comic/feed.xml";
+
+    // Only for your own dogears
+    assert!(feeds
+        .set(comic.id, other.id, url, false)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(!feeds.clear(comic.id, other.id).await.unwrap());
+
+    let feed = feeds
+        .set(comic.id, user.id, url, false)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(feed.feed_url, url);
+    assert!(feed.latest_seen.is_none());
+    assert_eq!(feed.failures, 0);
+    // New feeds are due right away.
+    let due = feeds.due(10).await.unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].prefix, "example.com/comic");
+    assert_eq!(due[0].current, comic.current);
+
+    // Checks push the next one out.
+    feeds
+        .record_check(
+            comic.id,
+            Some("https://example.com/comic/24"),
+            None,
+            "+60 minutes",
+        )
+        .await
+        .unwrap();
+    assert!(feeds.due(10).await.unwrap().is_empty());
+    let feed = &feeds.for_user(user.id).await.unwrap()[&comic.id];
+    assert_eq!(
+        feed.latest_seen.as_deref(),
+        Some("https://example.com/comic/24")
+    );
+    assert!(feed.last_checked.is_some());
+    assert!(feed.new_chapter.is_none());
+
+    // Flagged chapters stick around through later checks.
+    feeds
+        .record_check(
+            comic.id,
+            Some("https://example.com/comic/25"),
+            Some("https://example.com/comic/25"),
+            "-1 minutes",
+        )
+        .await
+        .unwrap();
+    feeds
+        .record_check(comic.id, None, None, "-1 minutes")
+        .await
+        .unwrap();
+    let feed = &feeds.for_user(user.id).await.unwrap()[&comic.id];
+    assert_eq!(
+        feed.new_chapter.as_deref(),
+        Some("https://example.com/comic/25")
+    );
+    assert!(feed.new_chapter_found.is_some());
+    assert_eq!(feeds.due(10).await.unwrap().len(), 1);
+
+    // Failures count up until something works.
+    feeds
+        .record_failure(comic.id, "nope", "-1 minutes")
+        .await
+        .unwrap();
+    feeds
+        .record_failure(comic.id, "still nope", "+120 minutes")
+        .await
+        .unwrap();
+    let feed = &feeds.for_user(user.id).await.unwrap()[&comic.id];
+    assert_eq!(feed.failures, 2);
+    assert_eq!(feed.last_error.as_deref(), Some("still nope"));
+    assert!(feeds.due(10).await.unwrap().is_empty());
+
+    // Flipping auto_advance keeps the bookkeeping; a new URL starts over.
+    let feed = feeds
+        .set(comic.id, user.id, url, true)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(feed.auto_advance);
+    assert_eq!(feed.failures, 2);
+    assert!(feed.new_chapter.is_some());
+    let other_url = "https://example.com/comic/rss";
+    let feed = feeds
+        .set(comic.id, user.id, other_url, true)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(feed.feed_url, other_url);
+    assert_eq!(feed.failures, 0);
+    assert!(feed.latest_seen.is_none() && feed.new_chapter.is_none());
+    assert_eq!(feeds.due(10).await.unwrap().len(), 1);
+
+    // Gone when you turn it off, or when the dogear goes
+    assert!(feeds.clear(comic.id, user.id).await.unwrap());
+    assert!(feeds.for_user(user.id).await.unwrap().is_empty());
+    feeds.set(comic.id, user.id, url, false).await.unwrap();
+    db.dogears().destroy(comic.id, user.id).await.unwrap();
+    assert!(feeds.for_user(user.id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn audit() {
+    let db = Db::new_test_db().await;
+    let audit = db.audit();
+    let user = db.test_user("whoever").await.unwrap();
+    let other_user = db.test_user("someone_else").await.unwrap();
+    let source = AuditSource {
+        ip: Some("192.0.2.1".to_string()),
+        user_agent: Some("Tester/1.0".to_string()),
+    };
+
+    audit
+        .record(
+            Some(user.id),
+            &user.username,
+            AuditKind::Login,
+            None,
+            &source,
+        )
+        .await
+        .unwrap();
+    audit
+        .record(
+            Some(user.id),
+            &user.username,
+            AuditKind::TokenCreated,
+            Some("write_dogears token #1"),
+            &AuditSource::default(),
+        )
+        .await
+        .unwrap();
+    audit
+        .record(
+            Some(other_user.id),
+            &other_user.username,
+            AuditKind::PasswordChanged,
+            None,
+            &source,
+        )
+        .await
+        .unwrap();
+
+    // Newest first, and only your own
+    let events = audit.recent(user.id, 10).await.unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].kind, "token_created");
+    assert_eq!(events[0].detail.as_deref(), Some("write_dogears token #1"));
+    assert!(events[0].ip.is_none());
+    assert_eq!(events[1].kind, "login");
+    assert_eq!(events[1].ip.as_deref(), Some("192.0.2.1"));
+    assert_eq!(events[1].user_agent.as_deref(), Some("Tester/1.0"));
+    assert_eq!(audit.recent(user.id, 1).await.unwrap().len(), 1);
+
+    // New sessions remember the last login; the first ever has none.
+    let session = db.sessions().create(user.id, None).await.unwrap();
+    assert_eq!(session.previous_login, Some(events[1].created));
+    assert_eq!(
+        session.previous_login_user_agent.as_deref(),
+        Some("Tester/1.0")
+    );
+    let session = db.sessions().create(other_user.id, None).await.unwrap();
+    assert!(session.previous_login.is_none());
+
+    // Deleting an account takes its events along, but the record of the
+    // deletion itself (which has no user id) sticks around.
+    db.users().destroy(other_user.id).await.unwrap();
+    audit
+        .record(
+            None,
+            &other_user.username,
+            AuditKind::AccountDeleted,
+            None,
+            &source,
+        )
+        .await
+        .unwrap();
+    let count = query_scalar!(
+        r#"
+            SELECT COUNT(*) FROM audit_events;
+        "#
+    )
+    .fetch_one(&db.read_pool)
+    .await
+    .unwrap();
+    assert_eq!(count, 3);
+
+    // Old ones go away
+    query!(
+        r#"
+            UPDATE audit_events SET created = datetime('now', '-13 months') WHERE kind = 'login';
+        "#
+    )
+    .execute(&db.write_pool)
+    .await
+    .unwrap();
+    assert_eq!(audit.delete_old(12).await.unwrap(), 1);
+    assert_eq!(audit.recent(user.id, 10).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn totp() {
+    use super::TotpMethod;
+    use crate::util::current_totp;
+
+    let db = Db::new_test_db().await;
+    let totp = db.totp();
+    let user = db.test_user("careful").await.unwrap();
+
+    // Nothing until they ask, and nothing to check codes against.
+    assert!(totp.get(user.id).await.unwrap().is_none());
+    assert!(!totp.enabled(user.id).await.unwrap());
+    assert!(totp.verify(user.id, "123456").await.unwrap().is_none());
+
+    // Setting up doesn't turn it on, and can start over.
+    let first = totp.begin_setup(user.id).await.unwrap().unwrap();
+    let secret = totp.begin_setup(user.id).await.unwrap().unwrap();
+    assert_ne!(first, secret);
+    assert!(!totp.enabled(user.id).await.unwrap());
+    assert!(totp
+        .confirm(user.id, &current_totp(&first))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(totp
+        .regenerate_recovery_codes(user.id)
+        .await
+        .unwrap()
+        .is_none());
+
+    // A good code turns it on and hands out recovery codes.
+    let codes = totp
+        .confirm(user.id, &current_totp(&secret))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(codes.len(), crate::util::RECOVERY_CODE_COUNT);
+    assert!(totp.enabled(user.id).await.unwrap());
+    assert_eq!(totp.recovery_codes_left(user.id).await.unwrap(), 10);
+    assert!(totp.begin_setup(user.id).await.unwrap().is_none());
+
+    // That code's spent, even for logging in.
+    assert!(totp
+        .verify(user.id, &current_totp(&secret))
+        .await
+        .unwrap()
+        .is_none());
+    // Recovery codes work once, however you type them.
+    let shouty = codes[0].to_uppercase().replace('-', " ");
+    assert_eq!(
+        totp.verify(user.id, &shouty).await.unwrap(),
+        Some(TotpMethod::RecoveryCode)
+    );
+    assert!(totp.verify(user.id, &codes[0]).await.unwrap().is_none());
+    assert_eq!(totp.recovery_codes_left(user.id).await.unwrap(), 9);
+
+    // New batch, old ones are out.
+    let fresh = totp
+        .regenerate_recovery_codes(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(totp.verify(user.id, &codes[1]).await.unwrap().is_none());
+    assert_eq!(
+        totp.verify(user.id, &fresh[1]).await.unwrap(),
+        Some(TotpMethod::RecoveryCode)
+    );
+
+    // Off means off.
+    assert!(totp.disable(user.id).await.unwrap().is_some());
+    assert!(!totp.enabled(user.id).await.unwrap());
+    assert_eq!(totp.recovery_codes_left(user.id).await.unwrap(), 0);
+    assert!(totp.disable(user.id).await.unwrap().is_none());
+}
@@ -0,0 +1,102 @@
+use super::core::Db;
+use sqlx::{query, query_scalar, SqlitePool};
+
+/// A query helper type for keeping track of the devices each user logs in
+/// from. Usually rented from a [Db].
+#[derive(Debug)]
+pub struct Devices<'a> {
+    db: &'a Db,
+}
+
+/// What we made of a login's device, compared to the user's history.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sighting {
+    /// The user had no known devices at all (they just signed up, or haven't
+    /// logged in since we started keeping track). Nothing to compare against.
+    First,
+    /// Never seen this one before, but we've seen others.
+    New,
+    /// Same as a previous login.
+    Known,
+}
+
+// record, delete_stale
+impl<'a> Devices<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// Note that a user just logged in from a device (identified by an opaque
+    /// hash), and report whether it's one we've seen before.
+    #[tracing::instrument(skip(self))]
+    pub async fn record(&self, user_id: i64, device_hash: &str) -> sqlx::Result<Sighting> {
+        // Reads and a write that have to agree, so it's a transaction on the writer.
+        let mut tx = self.write_pool().begin().await?;
+
+        let existing = query_scalar!(
+            r#"
+                SELECT id FROM seen_devices WHERE user_id = ?1 AND device_hash = ?2;
+            "#,
+            user_id,
+            device_hash,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(id) = existing {
+            query!(
+                r#"
+                    UPDATE seen_devices SET last_seen = current_timestamp WHERE id = ?;
+                "#,
+                id,
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            return Ok(Sighting::Known);
+        }
+
+        let known_count = query_scalar!(
+            r#"
+                SELECT count(*) AS 'count: u32' FROM seen_devices WHERE user_id = ?;
+            "#,
+            user_id,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        query!(
+            r#"
+                INSERT INTO seen_devices (user_id, device_hash) VALUES (?1, ?2);
+            "#,
+            user_id,
+            device_hash,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        if known_count == 0 {
+            Ok(Sighting::First)
+        } else {
+            Ok(Sighting::New)
+        }
+    }
+
+    /// Forget devices nobody's logged in from for `months`. (A login from
+    /// one of them later counts as new.)
+    #[tracing::instrument(skip(self))]
+    pub async fn delete_stale(&self, months: u32) -> sqlx::Result<u64> {
+        let cutoff = format!("-{} months", months);
+        query!(
+            r#"
+                DELETE FROM seen_devices WHERE last_seen < datetime('now', ?);
+            "#,
+            cutoff,
+        )
+        .execute(self.write_pool())
+        .await
+        .map(|v| v.rows_affected())
+    }
+}
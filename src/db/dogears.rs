@@ -1,41 +1,78 @@
-use super::core::Db;
+use super::tx::Handle;
 use crate::util::{
-    clean_optional_form_field, matchable_from_url, normalize_prefix_matcher, sqlite_offset,
-    ListMeta, MixedError, UserError,
+    clean_field, matchable_from_url, normalize_current_url, normalize_prefix_matcher,
+    sqlite_offset, validate, Field, ListMeta, MixedError, UserError,
 };
 
-use serde::{Deserialize, Serialize};
-use sqlx::{error::ErrorKind, query, query_as, query_scalar, SqlitePool};
-use time::{serde::iso8601, OffsetDateTime};
+use sqlx::{error::ErrorKind, query, query_as, query_scalar, Connection};
+use std::collections::HashMap;
+use time::OffsetDateTime;
 
-/// A query helper type for operating on [Dogears]. Usually rented from a [Db].
+/// A query helper type for operating on [Dogears]. Usually rented from a
+/// [Db](super::Db), or a [DbTx](super::DbTx) to run in a transaction.
 #[derive(Debug)]
 pub struct Dogears<'a> {
-    db: &'a Db,
+    db: Handle<'a>,
 }
 
-/// A record struct for user web serial bookmarks.
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Dogear {
-    pub id: i64,
-    pub user_id: i64,
-    pub prefix: String,
-    pub current: String,
-    pub display_name: Option<String>,
-    #[serde(with = "iso8601")]
-    pub updated: OffsetDateTime,
-}
+// The record struct for user web serial bookmarks doubles as an API wire
+// type, so it's defined over in the library half of the crate.
+use eardogger_rs::api_types::ApiImportDogear;
+pub use eardogger_rs::api_types::{Dogear, DogearHistoryEntry};
 
-// create, update, list, destroy, current_for_site
-impl<'a> Dogears<'a> {
-    pub fn new(db: &'a Db) -> Self {
-        Self { db }
+/// Normalize a new dogear's prefix and current URL, and check that both
+/// pass validation and the URL is valid and matches the prefix.
+fn checked_location<'a, 'b>(
+    prefix: &'a str,
+    current: &'b str,
+) -> Result<(&'a str, &'b str), UserError> {
+    let normalized_prefix = normalize_prefix_matcher(prefix);
+    let current = normalize_current_url(current);
+    validate(Field::Prefix, normalized_prefix)?;
+    validate(Field::Url, current)?;
+    let matchable_current = matchable_from_url(current)?;
+    if !matchable_current.starts_with(normalized_prefix) {
+        return Err(UserError::DogearNonMatching {
+            url: current.to_string(),
+            prefix: prefix.to_string(),
+        });
     }
-    fn read_pool(&self) -> &SqlitePool {
-        &self.db.read_pool
+    Ok((normalized_prefix, current))
+}
+
+/// A LIKE pattern for "contains `text`", with LIKE's own wildcards (and
+/// the escape character) escaped. Goes with `ESCAPE '\'`.
+fn like_contains(text: &str) -> String {
+    let mut pattern = String::with_capacity(text.len() + 2);
+    pattern.push('%');
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
     }
-    fn write_pool(&self) -> &SqlitePool {
-        &self.db.write_pool
+    pattern.push('%');
+    pattern
+}
+
+/// How many history entries `Dogears::history` hands back.
+pub const HISTORY_LIMIT: u32 = 50;
+
+/// One dogear's result from [Dogears::update]: its new state, plus where it
+/// was before. For paused dogears, which don't move, those are the same.
+#[derive(Debug, Clone)]
+pub struct DogearUpdate {
+    pub dogear: Dogear,
+    pub previous: String,
+}
+
+// create, create_many, update, update_one, edit, set_paused, set_public, set_archived, stale_ids,
+// archive_stale, by_id, list, list_filtered, list_archived, list_public, current_notes,
+// add_tags, tags, mark_times, history, updated_since, export_page, destroy, current_for_site,
+// same_host, list_everyones, set_location, merge, delete_old_history
+impl<'a> Dogears<'a> {
+    pub fn new(db: impl Into<Handle<'a>>) -> Self {
+        Self { db: db.into() }
     }
 
     /// Make a new dogear!
@@ -47,31 +84,22 @@ impl<'a> Dogears<'a> {
         current: &str,
         display_name: Option<&str>,
     ) -> Result<Dogear, MixedError<sqlx::Error>> {
-        let normalized_prefix = normalize_prefix_matcher(prefix);
-        // Confirm that the current URL is valid and matches the prefix
-        let matchable_current = matchable_from_url(current)?;
-        if !matchable_current.starts_with(normalized_prefix) {
-            return Err(UserError::DogearNonMatching {
-                url: current.to_string(),
-                prefix: prefix.to_string(),
-            }
-            .into());
-        }
-        let normalized_display_name = clean_optional_form_field(display_name);
+        let (normalized_prefix, current) = checked_location(prefix, current)?;
+        let normalized_display_name = clean_field(Field::DisplayName, display_name)?;
 
-        query_as!(
+        let dogear = query_as!(
             Dogear,
             r#"
                 INSERT INTO dogears (user_id, prefix, current, display_name)
                 VALUES (?1, ?2, ?3, ?4)
-                RETURNING id, user_id, prefix, current, display_name, updated;
+                RETURNING id, user_id, prefix, current, display_name, updated, paused, public, archived;
             "#,
             user_id,
             normalized_prefix,
             current,
             normalized_display_name
         )
-        .fetch_one(self.write_pool())
+        .fetch_one(&mut *self.db.writer().await?)
         .await
         .map_err(|e| {
             // Need to catch unique constraint violation and return friendly error; any
@@ -85,7 +113,64 @@ impl<'a> Dogears<'a> {
                 }
                 _ => e.into(),
             }
-        })
+        })?;
+        self.db.notify(user_id);
+        Ok(dogear)
+    }
+
+    /// Make a batch of new dogears in one transaction, keeping their paused,
+    /// public, and archived flags. Entries that can't be made (bad URLs, or
+    /// a prefix the user already has, including earlier in the same batch)
+    /// are skipped with a UserError in their slot, and the rest still go in.
+    #[tracing::instrument(skip_all)]
+    pub async fn create_many(
+        &self,
+        user_id: i64,
+        entries: &[ApiImportDogear],
+    ) -> sqlx::Result<Vec<Result<Dogear, UserError>>> {
+        let mut results = Vec::with_capacity(entries.len());
+        let mut conn = self.db.writer().await?;
+        let mut tx = conn.begin().await?;
+        for entry in entries {
+            let checked = checked_location(&entry.prefix, &entry.current).and_then(|location| {
+                clean_field(Field::DisplayName, entry.display_name.as_deref())
+                    .map(|name| (location, name))
+            });
+            let ((prefix, current), display_name) = match checked {
+                Ok(checked) => checked,
+                Err(e) => {
+                    results.push(Err(e));
+                    continue;
+                }
+            };
+            // OR IGNORE, because the table's own conflict clause is
+            // ROLLBACK, which would take the whole batch down with it.
+            let dogear = query_as!(
+                Dogear,
+                r#"
+                    INSERT OR IGNORE INTO dogears (user_id, prefix, current, display_name, paused, public, archived)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                    RETURNING id, user_id, prefix, current, display_name, updated, paused, public, archived;
+                "#,
+                user_id,
+                prefix,
+                current,
+                display_name,
+                entry.paused,
+                entry.public,
+                entry.archived,
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            results.push(dogear.ok_or_else(|| UserError::DogearExists {
+                prefix: prefix.to_string(),
+            }));
+        }
+        tx.commit().await?;
+        if results.iter().any(|r| r.is_ok()) {
+            self.db.notify(user_id);
+        }
+        Ok(results)
     }
 
     /// Given a user and a current URL, update the corresponding dogear to
@@ -94,31 +179,114 @@ impl<'a> Dogears<'a> {
     /// dogears at once. That's kind of fine, though; it's some minor jank
     /// that saves us a bunch of bullshit elsewhere in the system. If you
     /// got your personal dogears into a weird situation, just delete some.
+    /// Paused dogears that match get left alone, but they're still included
+    /// in the results (with `paused: true` and their old `current`) so the
+    /// caller can tell the user why nothing moved.
+    /// Each dogear that moves gets a history entry, with the note if any,
+    /// and comes out of the archive if it was in there.
     /// Returns None if no dogears matched.
     #[tracing::instrument(skip_all)]
-    pub async fn update(&self, user_id: i64, current: &str) -> sqlx::Result<Option<Vec<Dogear>>> {
+    pub async fn update(
+        &self,
+        user_id: i64,
+        current: &str,
+        note: Option<&str>,
+    ) -> sqlx::Result<Option<Vec<DogearUpdate>>> {
+        let current = normalize_current_url(current);
         // If the URL is bad, we just return None. This is because a failed update
         // usually diverts you onto the more verbose create flow, which has better
         // affordances available for telling you about the problem.
+        if validate(Field::Url, current).is_err() {
+            return Ok(None);
+        }
         let Ok(matchable) = matchable_from_url(current) else {
             return Ok(None);
         };
-        let res = query_as!(
+        // Read the old positions, write, then read the skipped ones, all on
+        // the same connection.
+        let mut conn = self.db.writer().await?;
+        let mut tx = conn.begin().await?;
+        let previous: HashMap<i64, String> = query!(
+            r#"
+                SELECT id, current
+                FROM dogears
+                WHERE
+                    user_id = ?1 AND
+                    ?2 LIKE prefix || '%' AND
+                    paused = false;
+            "#,
+            user_id,
+            matchable,
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|r| (r.id, r.current))
+        .collect();
+        let updated = query_as!(
             Dogear,
             r#"
                 UPDATE dogears
-                SET current = ?1, updated = current_timestamp
+                SET current = ?1, updated = current_timestamp, archived = false
                 WHERE
                     user_id = ?2 AND
-                    ?3 LIKE prefix || '%'
-                RETURNING id, user_id, prefix, current, display_name, updated;
+                    ?3 LIKE prefix || '%' AND
+                    paused = false
+                RETURNING id, user_id, prefix, current, display_name, updated, paused, public, archived;
             "#,
             current,
             user_id,
             matchable,
         )
-        .fetch_all(self.write_pool())
+        .fetch_all(&mut *tx)
+        .await?;
+        for dogear in &updated {
+            query!(
+                r#"
+                    INSERT INTO dogear_history (dogear_id, url, note)
+                    VALUES (?1, ?2, ?3);
+                "#,
+                dogear.id,
+                current,
+                note,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        let skipped = query_as!(
+            Dogear,
+            r#"
+                SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
+                FROM dogears
+                WHERE
+                    user_id = ?1 AND
+                    ?2 LIKE prefix || '%' AND
+                    paused = true;
+            "#,
+            user_id,
+            matchable,
+        )
+        .fetch_all(&mut *tx)
         .await?;
+        tx.commit().await?;
+        if !updated.is_empty() {
+            self.db.notify(user_id);
+        }
+
+        let res: Vec<DogearUpdate> = updated
+            .into_iter()
+            .map(|dogear| {
+                let previous = previous
+                    .get(&dogear.id)
+                    .cloned()
+                    .unwrap_or_else(|| dogear.current.clone());
+                DogearUpdate { dogear, previous }
+            })
+            .chain(skipped.into_iter().map(|dogear| DogearUpdate {
+                previous: dogear.current.clone(),
+                dogear,
+            }))
+            .collect();
         if res.is_empty() {
             Ok(None)
         } else {
@@ -126,6 +294,268 @@ impl<'a> Dogears<'a> {
         }
     }
 
+    /// Like `update`, but for exactly one dogear, by ID: for webhooks,
+    /// which are set up for one dogear and shouldn't drag along any others
+    /// that happen to overlap it. Moves it only if the URL matches its
+    /// prefix and it isn't paused, with a history entry and the note if
+    /// any. Returns the moved dogear, or Ok(None) if it didn't move.
+    #[tracing::instrument(skip_all)]
+    pub async fn update_one(
+        &self,
+        id: i64,
+        user_id: i64,
+        current: &str,
+        note: Option<&str>,
+    ) -> sqlx::Result<Option<Dogear>> {
+        let current = normalize_current_url(current);
+        if validate(Field::Url, current).is_err() {
+            return Ok(None);
+        }
+        let Ok(matchable) = matchable_from_url(current) else {
+            return Ok(None);
+        };
+        let mut conn = self.db.writer().await?;
+        let mut tx = conn.begin().await?;
+        let Some(updated) = query_as!(
+            Dogear,
+            r#"
+                UPDATE dogears
+                SET current = ?1, updated = current_timestamp, archived = false
+                WHERE
+                    id = ?2 AND
+                    user_id = ?3 AND
+                    ?4 LIKE prefix || '%' AND
+                    paused = false
+                RETURNING id, user_id, prefix, current, display_name, updated, paused, public, archived;
+            "#,
+            current,
+            id,
+            user_id,
+            matchable,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        query!(
+            r#"
+                INSERT INTO dogear_history (dogear_id, url, note)
+                VALUES (?1, ?2, ?3);
+            "#,
+            updated.id,
+            current,
+            note,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.db.notify(user_id);
+        Ok(Some(updated))
+    }
+
+    /// Change a dogear's prefix and/or display name, leaving anything passed
+    /// as None alone (a blank display name clears it). The new prefix gets
+    /// normalized like a new dogear's, and the current URL still has to
+    /// match it. Returns the updated dogear, or Ok(None) if it doesn't exist
+    /// or belongs to someone else.
+    #[tracing::instrument(skip_all)]
+    pub async fn edit(
+        &self,
+        id: i64,
+        user_id: i64,
+        prefix: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Result<Option<Dogear>, MixedError<sqlx::Error>> {
+        // Hold the writer throughout, so the current URL can't move between
+        // the check and the update.
+        let mut conn = self.db.writer().await?;
+        let Some(dogear) = query_as!(
+            Dogear,
+            r#"
+                SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
+                FROM dogears
+                WHERE id = ?1 AND user_id = ?2;
+            "#,
+            id,
+            user_id,
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let new_prefix = match prefix {
+            Some(prefix) => checked_location(prefix, &dogear.current)?.0,
+            None => dogear.prefix.as_str(),
+        };
+        let new_display_name = match display_name {
+            Some(display_name) => clean_field(Field::DisplayName, Some(display_name))?,
+            None => dogear.display_name.as_deref(),
+        };
+
+        let res = query_as!(
+            Dogear,
+            r#"
+                UPDATE dogears
+                SET prefix = ?3, display_name = ?4
+                WHERE id = ?1 AND user_id = ?2
+                RETURNING id, user_id, prefix, current, display_name, updated, paused, public, archived;
+            "#,
+            id,
+            user_id,
+            new_prefix,
+            new_display_name,
+        )
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(dbe) if dbe.kind() == ErrorKind::UniqueViolation => {
+                UserError::DogearExists {
+                    prefix: new_prefix.to_string(),
+                }
+                .into()
+            }
+            _ => e.into(),
+        })?;
+        drop(conn);
+        if res.is_some() {
+            self.db.notify(user_id);
+        }
+        Ok(res)
+    }
+
+    /// Pause or unpause a dogear. Returns the updated dogear, or Ok(None) if
+    /// it doesn't exist or belongs to someone else.
+    #[tracing::instrument(skip_all)]
+    pub async fn set_paused(
+        &self,
+        id: i64,
+        user_id: i64,
+        paused: bool,
+    ) -> sqlx::Result<Option<Dogear>> {
+        let res = query_as!(
+            Dogear,
+            r#"
+                UPDATE dogears
+                SET paused = ?1
+                WHERE id = ?2 AND user_id = ?3
+                RETURNING id, user_id, prefix, current, display_name, updated, paused, public, archived;
+            "#,
+            paused,
+            id,
+            user_id,
+        )
+        .fetch_optional(&mut *self.db.writer().await?)
+        .await?;
+        if res.is_some() {
+            self.db.notify(user_id);
+        }
+        Ok(res)
+    }
+
+    /// Show or hide a dogear on the owner's public profile. Returns the
+    /// updated dogear, or Ok(None) if it doesn't exist or belongs to someone else.
+    #[tracing::instrument(skip_all)]
+    pub async fn set_public(
+        &self,
+        id: i64,
+        user_id: i64,
+        public: bool,
+    ) -> sqlx::Result<Option<Dogear>> {
+        let res = query_as!(
+            Dogear,
+            r#"
+                UPDATE dogears
+                SET public = ?1
+                WHERE id = ?2 AND user_id = ?3
+                RETURNING id, user_id, prefix, current, display_name, updated, paused, public, archived;
+            "#,
+            public,
+            id,
+            user_id,
+        )
+        .fetch_optional(&mut *self.db.writer().await?)
+        .await?;
+        if res.is_some() {
+            self.db.notify(user_id);
+        }
+        Ok(res)
+    }
+
+    /// Move a dogear into or out of the archive. Returns the updated dogear,
+    /// or Ok(None) if it doesn't exist or belongs to someone else.
+    #[tracing::instrument(skip_all)]
+    pub async fn set_archived(
+        &self,
+        id: i64,
+        user_id: i64,
+        archived: bool,
+    ) -> sqlx::Result<Option<Dogear>> {
+        let res = query_as!(
+            Dogear,
+            r#"
+                UPDATE dogears
+                SET archived = ?1
+                WHERE id = ?2 AND user_id = ?3
+                RETURNING id, user_id, prefix, current, display_name, updated, paused, public, archived;
+            "#,
+            archived,
+            id,
+            user_id,
+        )
+        .fetch_optional(&mut *self.db.writer().await?)
+        .await?;
+        if res.is_some() {
+            self.db.notify(user_id);
+        }
+        Ok(res)
+    }
+
+    /// IDs of the user's unarchived dogears that haven't moved in `months`
+    /// months, stalest first.
+    #[tracing::instrument(skip(self))]
+    pub async fn stale_ids(&self, user_id: i64, months: u32) -> sqlx::Result<Vec<i64>> {
+        query_scalar!(
+            r#"
+                SELECT id FROM dogears
+                WHERE
+                    user_id = ?1 AND
+                    archived = false AND
+                    updated < datetime('now', '-' || ?2 || ' months')
+                ORDER BY updated ASC;
+            "#,
+            user_id,
+            months,
+        )
+        .fetch_all(&mut *self.db.reader().await?)
+        .await
+    }
+
+    /// Archive all of the user's dogears that `stale_ids` would list.
+    /// Returns how many went.
+    #[tracing::instrument(skip(self))]
+    pub async fn archive_stale(&self, user_id: i64, months: u32) -> sqlx::Result<u64> {
+        let res = query!(
+            r#"
+                UPDATE dogears
+                SET archived = true
+                WHERE
+                    user_id = ?1 AND
+                    archived = false AND
+                    updated < datetime('now', '-' || ?2 || ' months');
+            "#,
+            user_id,
+            months,
+        )
+        .execute(&mut *self.db.writer().await?)
+        .await?;
+        if res.rows_affected() > 0 {
+            self.db.notify(user_id);
+        }
+        Ok(res.rows_affected())
+    }
+
     /// Given a URL and a user, return the currently bookmarked page on that site.
     /// (or None.) This partially acknowledges the "overlapping prefixes" loophole
     /// by returning the result with the *longest* matching prefix.
@@ -148,11 +578,68 @@ impl<'a> Dogears<'a> {
             user_id,
             matchable,
         )
-        .fetch_optional(self.read_pool())
+        .fetch_optional(&mut *self.db.reader().await?)
         .await?;
         Ok(res.map(|r| r.current))
     }
 
+    /// One of a user's dogears, by ID. Returns Ok(None) if it doesn't exist
+    /// or belongs to someone else.
+    #[tracing::instrument(skip(self))]
+    pub async fn by_id(&self, id: i64, user_id: i64) -> sqlx::Result<Option<Dogear>> {
+        query_as!(
+            Dogear,
+            r#"
+                SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
+                FROM dogears
+                WHERE id = ?1 AND user_id = ?2;
+            "#,
+            id,
+            user_id,
+        )
+        .fetch_optional(&mut *self.db.reader().await?)
+        .await
+    }
+
+    /// A user's dogears on the same host as a URL, most recently read first,
+    /// up to `limit`. This is for "did you mean...?" suggestions when
+    /// `current_for_site` comes up empty, which usually means the site moved
+    /// things around (like /comics/ to /comic/) out from under a prefix.
+    /// Same rules as always for the host: `www.` and `m.` don't count.
+    #[tracing::instrument(skip(self))]
+    pub async fn same_host(
+        &self,
+        user_id: i64,
+        url: &str,
+        limit: u32,
+    ) -> sqlx::Result<Vec<Dogear>> {
+        let Ok(matchable) = matchable_from_url(url) else {
+            return Ok(Vec::new());
+        };
+        let host = matchable.split('/').next().unwrap_or(matchable);
+        // Exactly the host, or the host plus a path, but not some longer
+        // host that happens to start the same.
+        let host_slash = format!("{}/", host);
+        query_as!(
+            Dogear,
+            r#"
+                SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
+                FROM dogears
+                WHERE
+                    user_id = ?1 AND
+                    (prefix = ?2 OR substr(prefix, 1, length(?3)) = ?3)
+                ORDER BY updated DESC
+                LIMIT ?4;
+            "#,
+            user_id,
+            host,
+            host_slash,
+            limit,
+        )
+        .fetch_all(&mut *self.db.reader().await?)
+        .await
+    }
+
     /// yeah. Returns Ok(Some) on success, Ok(None) on not-found.
     pub async fn destroy(&self, id: i64, user_id: i64) -> sqlx::Result<Option<()>> {
         let res = query!(
@@ -163,48 +650,321 @@ impl<'a> Dogears<'a> {
             id,
             user_id,
         )
-        .execute(self.write_pool())
+        .execute(&mut *self.db.writer().await?)
         .await?;
         if res.rows_affected() == 1 {
+            self.db.notify(user_id);
             Ok(Some(()))
         } else {
             Ok(None)
         }
     }
 
-    /// List some of the user's dogears, with an adjustable page size.
+    /// Every dogear in the db, for maintenance tasks. Not for use by the
+    /// web app, since it's not scoped to a user.
     #[tracing::instrument(skip_all)]
+    pub async fn list_everyones(&self) -> sqlx::Result<Vec<Dogear>> {
+        query_as!(
+            Dogear,
+            r#"
+                SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
+                FROM dogears
+                ORDER BY id;
+            "#
+        )
+        .fetch_all(&mut *self.db.reader().await?)
+        .await
+    }
+
+    /// Overwrite a dogear's prefix and current URL, without touching its
+    /// updated date or history. For maintenance tasks, which are on the
+    /// hook for validating the new values. Errors with DogearExists if the
+    /// user already has another dogear with that prefix.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_location(
+        &self,
+        id: i64,
+        prefix: &str,
+        current: &str,
+    ) -> Result<(), MixedError<sqlx::Error>> {
+        query!(
+            r#"
+                UPDATE dogears
+                SET prefix = ?2, current = ?3
+                WHERE id = ?1;
+            "#,
+            id,
+            prefix,
+            current,
+        )
+        .execute(&mut *self.db.writer().await?)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(dbe) if dbe.kind() == ErrorKind::UniqueViolation => {
+                UserError::DogearExists {
+                    prefix: prefix.to_string(),
+                }
+                .into()
+            }
+            _ => e.into(),
+        })?;
+        Ok(())
+    }
+
+    /// Fold some of a user's dogears into another one, for cleaning up
+    /// duplicates. `keep` takes the most recently updated `current` (and
+    /// updated date) of the bunch, plus a display name if it had none, and
+    /// inherits their history, tags, and KOReader books; then the others
+    /// get deleted, along with their feeds and hooks. It keeps its own
+    /// prefix, so the caller's on the hook for making sure that matches
+    /// everyone's current URLs. IDs that don't exist (or aren't the user's)
+    /// are skipped. Returns the merged dogear, or Ok(None) if `keep` doesn't
+    /// exist or belongs to someone else.
+    #[tracing::instrument(skip(self))]
+    pub async fn merge(
+        &self,
+        user_id: i64,
+        keep: i64,
+        others: &[i64],
+    ) -> sqlx::Result<Option<Dogear>> {
+        let mut conn = self.db.writer().await?;
+        let mut tx = conn.begin().await?;
+        let mut members = Vec::with_capacity(others.len() + 1);
+        for &id in std::iter::once(&keep).chain(others) {
+            let dogear = query_as!(
+                Dogear,
+                r#"
+                    SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
+                    FROM dogears
+                    WHERE id = ?1 AND user_id = ?2;
+                "#,
+                id,
+                user_id,
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            match dogear {
+                Some(d) => members.push(d),
+                None if id == keep => return Ok(None),
+                None => {}
+            }
+        }
+        // The first one's `keep`, and max_by_key takes the last of any ties,
+        // so ties go to whichever's listed last.
+        let newest = members
+            .iter()
+            .max_by_key(|d| d.updated)
+            .expect("keep is in there");
+        let display_name = members[0]
+            .display_name
+            .as_deref()
+            .or(newest.display_name.as_deref())
+            .or(members.iter().find_map(|d| d.display_name.as_deref()));
+
+        let merged = query_as!(
+            Dogear,
+            r#"
+                UPDATE dogears
+                SET
+                    current = ?3,
+                    display_name = ?4,
+                    updated = (SELECT updated FROM dogears WHERE id = ?5)
+                WHERE id = ?1 AND user_id = ?2
+                RETURNING id, user_id, prefix, current, display_name, updated, paused, public, archived;
+            "#,
+            keep,
+            user_id,
+            newest.current,
+            display_name,
+            newest.id,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        for other in members.iter().skip(1) {
+            query!(
+                r#"
+                    UPDATE dogear_history SET dogear_id = ?1 WHERE dogear_id = ?2;
+                "#,
+                keep,
+                other.id,
+            )
+            .execute(&mut *tx)
+            .await?;
+            query!(
+                r#"
+                    INSERT OR IGNORE INTO dogear_tags (dogear_id, tag)
+                    SELECT ?1, tag FROM dogear_tags WHERE dogear_id = ?2;
+                "#,
+                keep,
+                other.id,
+            )
+            .execute(&mut *tx)
+            .await?;
+            // Otherwise the next sync would make the duplicate all over again.
+            query!(
+                r#"
+                    UPDATE kosync_documents SET dogear_id = ?1 WHERE dogear_id = ?2;
+                "#,
+                keep,
+                other.id,
+            )
+            .execute(&mut *tx)
+            .await?;
+            query!(
+                r#"
+                    DELETE FROM dogears WHERE id = ?1 AND user_id = ?2;
+                "#,
+                other.id,
+                user_id,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        drop(conn);
+        self.db.notify(user_id);
+        Ok(Some(merged))
+    }
+
+    /// List some of the user's dogears, with an adjustable page size.
+    /// Archived ones aren't included; see `list_archived`.
     pub async fn list(
         &self,
         user_id: i64,
         page: u32,
         size: u32,
     ) -> Result<(Vec<Dogear>, ListMeta), MixedError<sqlx::Error>> {
+        self.list_filtered(user_id, None, page, size).await
+    }
+
+    /// Like `list`, but only the dogears whose prefix, current URL, or
+    /// display name contains `filter` (ignoring ASCII case). None means
+    /// all of them.
+    #[tracing::instrument(skip_all)]
+    pub async fn list_filtered(
+        &self,
+        user_id: i64,
+        filter: Option<&str>,
+        page: u32,
+        size: u32,
+    ) -> Result<(Vec<Dogear>, ListMeta), MixedError<sqlx::Error>> {
+        let pattern = filter.map(like_contains);
         // Do multiple reads in a transaction, so count and list see the
         // same causal slice.
-        let mut tx = self.read_pool().begin().await?;
+        let mut conn = self.db.reader().await?;
+        let mut tx = conn.begin().await?;
 
         // Count first, as a separate query. Note the sqlx "type coersion inside
         // the column name" thing, sigh.
+        let count = match &pattern {
+            None => {
+                query_scalar!(
+                    r#"
+                        SELECT count(id) AS 'count: u32' FROM dogears
+                        WHERE user_id = ? AND archived = false;
+                    "#,
+                    user_id,
+                )
+                .fetch_one(&mut *tx)
+                .await?
+            }
+            Some(pattern) => {
+                query_scalar!(
+                    r#"
+                        SELECT count(id) AS 'count: u32' FROM dogears
+                        WHERE user_id = ?1 AND archived = false AND (
+                            prefix LIKE ?2 ESCAPE '\' OR
+                            current LIKE ?2 ESCAPE '\' OR
+                            display_name LIKE ?2 ESCAPE '\'
+                        );
+                    "#,
+                    user_id,
+                    pattern,
+                )
+                .fetch_one(&mut *tx)
+                .await?
+            }
+        };
+
+        let meta = ListMeta { count, page, size };
+
+        let offset = sqlite_offset(page, size)?;
+        let list = match &pattern {
+            None => {
+                query_as!(
+                    Dogear,
+                    r#"
+                        SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
+                        FROM dogears
+                        WHERE user_id = ?1 AND archived = false
+                        ORDER BY updated DESC
+                        LIMIT ?2
+                        OFFSET ?3;
+                    "#,
+                    user_id,
+                    size,
+                    offset,
+                )
+                .fetch_all(&mut *tx)
+                .await?
+            }
+            Some(pattern) => {
+                query_as!(
+                    Dogear,
+                    r#"
+                        SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
+                        FROM dogears
+                        WHERE user_id = ?1 AND archived = false AND (
+                            prefix LIKE ?4 ESCAPE '\' OR
+                            current LIKE ?4 ESCAPE '\' OR
+                            display_name LIKE ?4 ESCAPE '\'
+                        )
+                        ORDER BY updated DESC
+                        LIMIT ?2
+                        OFFSET ?3;
+                    "#,
+                    user_id,
+                    size,
+                    offset,
+                    pattern,
+                )
+                .fetch_all(&mut *tx)
+                .await?
+            }
+        };
+
+        tx.commit().await?;
+
+        Ok((list, meta))
+    }
+
+    /// List some of the user's archived dogears, same as `list` otherwise.
+    #[tracing::instrument(skip_all)]
+    pub async fn list_archived(
+        &self,
+        user_id: i64,
+        page: u32,
+        size: u32,
+    ) -> Result<(Vec<Dogear>, ListMeta), MixedError<sqlx::Error>> {
+        let mut conn = self.db.reader().await?;
+        let mut tx = conn.begin().await?;
         let count = query_scalar!(
             r#"
                 SELECT count(id) AS 'count: u32' FROM dogears
-                WHERE user_id = ?;
+                WHERE user_id = ? AND archived = true;
             "#,
             user_id,
         )
         .fetch_one(&mut *tx)
         .await?;
-
         let meta = ListMeta { count, page, size };
-
         let offset = sqlite_offset(page, size)?;
         let list = query_as!(
             Dogear,
             r#"
-                SELECT id, user_id, prefix, current, display_name, updated
+                SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
                 FROM dogears
-                WHERE user_id = ?1
+                WHERE user_id = ?1 AND archived = true
                 ORDER BY updated DESC
                 LIMIT ?2
                 OFFSET ?3;
@@ -215,9 +975,280 @@ impl<'a> Dogears<'a> {
         )
         .fetch_all(&mut *tx)
         .await?;
+        tx.commit().await?;
+        Ok((list, meta))
+    }
+
+    /// The user's public dogears, most recently read first, up to `limit`.
+    /// This is for showing to strangers, so it's on the caller to check that
+    /// the user's profile is actually public.
+    #[tracing::instrument(skip_all)]
+    pub async fn list_public(&self, user_id: i64, limit: u32) -> sqlx::Result<Vec<Dogear>> {
+        query_as!(
+            Dogear,
+            r#"
+                SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
+                FROM dogears
+                WHERE user_id = ?1 AND public = true
+                ORDER BY updated DESC
+                LIMIT ?2;
+            "#,
+            user_id,
+            limit,
+        )
+        .fetch_all(&mut *self.db.reader().await?)
+        .await
+    }
+
+    /// Notes for a user's dogears, keyed by dogear ID. A dogear only has a
+    /// note here if its latest history entry has one AND that entry is still
+    /// where it's at (i.e. nobody's edited or re-created it since).
+    #[tracing::instrument(skip_all)]
+    pub async fn current_notes(&self, user_id: i64) -> sqlx::Result<HashMap<i64, String>> {
+        let notes = query!(
+            r#"
+                SELECT dogears.id AS "id!", dogear_history.note AS "note!"
+                FROM dogears
+                JOIN dogear_history ON dogear_history.id = (
+                    SELECT max(id) FROM dogear_history WHERE dogear_id = dogears.id
+                )
+                WHERE
+                    dogears.user_id = ?1 AND
+                    dogear_history.url = dogears.current AND
+                    dogear_history.note IS NOT NULL;
+            "#,
+            user_id,
+        )
+        .fetch_all(&mut *self.db.reader().await?)
+        .await?
+        .into_iter()
+        .map(|r| (r.id, r.note))
+        .collect();
+        Ok(notes)
+    }
+
+    /// Tag a dogear. Tags it already has are skipped. Doesn't check whose
+    /// dogear it is, so only call it on one you just made or looked up.
+    #[tracing::instrument(skip(self))]
+    pub async fn add_tags(&self, id: i64, tags: &[String]) -> sqlx::Result<()> {
+        let mut conn = self.db.writer().await?;
+        let mut tx = conn.begin().await?;
+        for tag in tags {
+            query!(
+                r#"
+                    INSERT OR IGNORE INTO dogear_tags (dogear_id, tag) VALUES (?1, ?2);
+                "#,
+                id,
+                tag,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    /// Tags for a user's dogears, keyed by dogear ID, each list sorted.
+    /// Untagged dogears aren't in the map.
+    #[tracing::instrument(skip_all)]
+    pub async fn tags(&self, user_id: i64) -> sqlx::Result<HashMap<i64, Vec<String>>> {
+        let rows = query!(
+            r#"
+                SELECT dogear_tags.dogear_id, dogear_tags.tag
+                FROM dogear_tags JOIN dogears ON dogear_tags.dogear_id = dogears.id
+                WHERE dogears.user_id = ?
+                ORDER BY dogear_tags.tag;
+            "#,
+            user_id,
+        )
+        .fetch_all(&mut *self.db.reader().await?)
+        .await?;
+        let mut tags: HashMap<i64, Vec<String>> = HashMap::new();
+        for row in rows {
+            tags.entry(row.dogear_id).or_default().push(row.tag);
+        }
+        Ok(tags)
+    }
+
+    /// When each of a user's dogears was last marked, up to `per_dogear`
+    /// times apiece, oldest first, by dogear ID. Dogears with no history
+    /// are left out.
+    #[tracing::instrument(skip(self))]
+    pub async fn mark_times(
+        &self,
+        user_id: i64,
+        per_dogear: u32,
+    ) -> sqlx::Result<HashMap<i64, Vec<OffsetDateTime>>> {
+        let rows = query!(
+            r#"
+                SELECT dogear_id AS "dogear_id!", created AS "created!: OffsetDateTime"
+                FROM (
+                    SELECT dogear_history.dogear_id, dogear_history.created,
+                        row_number() OVER (
+                            PARTITION BY dogear_history.dogear_id
+                            ORDER BY dogear_history.id DESC
+                        ) AS recency
+                    FROM dogear_history JOIN dogears ON dogear_history.dogear_id = dogears.id
+                    WHERE dogears.user_id = ?1
+                )
+                WHERE recency <= ?2
+                ORDER BY dogear_id, created;
+            "#,
+            user_id,
+            per_dogear,
+        )
+        .fetch_all(&mut *self.db.reader().await?)
+        .await?;
+        let mut times: HashMap<i64, Vec<OffsetDateTime>> = HashMap::new();
+        for row in rows {
+            times.entry(row.dogear_id).or_default().push(row.created);
+        }
+        Ok(times)
+    }
+
+    /// A dogear's history, newest first, up to HISTORY_LIMIT entries.
+    /// Returns None if the dogear doesn't exist or isn't yours.
+    #[tracing::instrument(skip_all)]
+    pub async fn history(
+        &self,
+        id: i64,
+        user_id: i64,
+    ) -> sqlx::Result<Option<Vec<DogearHistoryEntry>>> {
+        let mut conn = self.db.reader().await?;
+        let mut tx = conn.begin().await?;
+        let owned = query_scalar!(
+            r#"
+                SELECT count(id) AS 'count: u32' FROM dogears
+                WHERE id = ?1 AND user_id = ?2;
+            "#,
+            id,
+            user_id,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if owned == 0 {
+            return Ok(None);
+        }
+        let entries = query_as!(
+            DogearHistoryEntry,
+            r#"
+                SELECT url, note, created
+                FROM dogear_history
+                WHERE dogear_id = ?1
+                ORDER BY id DESC
+                LIMIT ?2;
+            "#,
+            id,
+            HISTORY_LIMIT,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(entries))
+    }
 
+    /// Dogears that moved after the provided time, most recent first. Returns
+    /// the total count, plus up to `limit` of the dogears themselves.
+    #[tracing::instrument(skip_all)]
+    pub async fn updated_since(
+        &self,
+        user_id: i64,
+        since: OffsetDateTime,
+        limit: u32,
+    ) -> sqlx::Result<(u32, Vec<Dogear>)> {
+        // Same deal as list: one read transaction, so count and list agree.
+        let mut conn = self.db.reader().await?;
+        let mut tx = conn.begin().await?;
+        let count = query_scalar!(
+            r#"
+                SELECT count(id) AS 'count: u32' FROM dogears
+                WHERE user_id = ?1 AND updated > datetime(?2);
+            "#,
+            user_id,
+            since,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let dogears = query_as!(
+            Dogear,
+            r#"
+                SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
+                FROM dogears
+                WHERE user_id = ?1 AND updated > datetime(?2)
+                ORDER BY updated DESC
+                LIMIT ?3;
+            "#,
+            user_id,
+            since,
+            limit,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
         tx.commit().await?;
+        Ok((count, dogears))
+    }
 
-        Ok((list, meta))
+    /// One page of a user's dogears for a bulk export, in id order, starting
+    /// after the provided id. (Pass 0 for the first page.) Unlike `list`,
+    /// this pages by id instead of by offset, so a page boundary stays put
+    /// even if the user marks something halfway through a long export.
+    #[tracing::instrument(skip_all)]
+    pub async fn export_page(
+        &self,
+        user_id: i64,
+        after_id: i64,
+        limit: u32,
+    ) -> sqlx::Result<Vec<Dogear>> {
+        query_as!(
+            Dogear,
+            r#"
+                SELECT id, user_id, prefix, current, display_name, updated, paused, public, archived
+                FROM dogears
+                WHERE user_id = ?1 AND id > ?2
+                ORDER BY id ASC
+                LIMIT ?3;
+            "#,
+            user_id,
+            after_id,
+            limit,
+        )
+        .fetch_all(&mut *self.db.reader().await?)
+        .await
+    }
+
+    /// Delete history entries older than each user's retention limit: the
+    /// shorter of `site_months` and their own `history_months` pref, or
+    /// whichever one's set if only one is. Each dogear keeps its latest
+    /// entry no matter what, since that's where its current note lives.
+    /// Returns how many entries went.
+    #[tracing::instrument(skip(self))]
+    pub async fn delete_old_history(&self, site_months: Option<u32>) -> sqlx::Result<u64> {
+        // sqlite's two-argument min() is NULL if either is, hence the coalesce.
+        // A NULL limit makes a NULL cutoff, which never compares true.
+        query!(
+            r#"
+                DELETE FROM dogear_history WHERE id IN (
+                    SELECT dogear_history.id
+                    FROM dogear_history
+                        JOIN dogears ON dogears.id = dogear_history.dogear_id
+                        LEFT JOIN user_prefs ON user_prefs.user_id = dogears.user_id
+                    WHERE dogear_history.created < datetime(
+                            'now',
+                            '-' || coalesce(
+                                min(user_prefs.history_months, ?1),
+                                user_prefs.history_months,
+                                ?1
+                            ) || ' months'
+                        )
+                        AND dogear_history.id < (
+                            SELECT max(latest.id) FROM dogear_history AS latest
+                            WHERE latest.dogear_id = dogear_history.dogear_id
+                        )
+                );
+            "#,
+            site_months,
+        )
+        .execute(&mut *self.db.writer().await?)
+        .await
+        .map(|v| v.rows_affected())
     }
 }
@@ -0,0 +1,245 @@
+use super::core::Db;
+use sqlx::{query, query_as, SqlitePool};
+use time::OffsetDateTime;
+
+/// How long the new address has to confirm a change.
+pub const CONFIRM_WINDOW_DAYS: i64 = 2;
+/// How long the old address has to undo a change.
+pub const REVERT_WINDOW_DAYS: i64 = 7;
+
+/// A query helper type for operating on [EmailChange]s. Usually rented
+/// from a [Db].
+#[derive(Debug)]
+pub struct EmailChanges<'a> {
+    db: &'a Db,
+}
+
+/// Record struct for one change to a user's email address. A change is
+/// pending until the new address confirms it; a revert puts the old
+/// address back, whether or not it was ever confirmed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmailChange {
+    pub id: i64,
+    pub user_id: i64,
+    pub old_email: Option<String>,
+    pub new_email: Option<String>,
+}
+
+// create, by_id, pending, confirm, revert, delete_old
+impl<'a> EmailChanges<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// Record a change. If `confirmed` is true, it takes effect right away
+    /// (like removing an address, which there's nobody to confirm);
+    /// otherwise, the user's email stays as-is until `confirm`.
+    #[tracing::instrument(skip_all)]
+    pub async fn create(
+        &self,
+        user_id: i64,
+        old_email: Option<&str>,
+        new_email: Option<&str>,
+        confirmed: bool,
+    ) -> sqlx::Result<EmailChange> {
+        let mut tx = self.write_pool().begin().await?;
+        let change = query_as!(
+            EmailChange,
+            r#"
+                INSERT INTO email_changes (user_id, old_email, new_email, confirmed)
+                VALUES (?1, ?2, ?3, CASE WHEN ?4 THEN current_timestamp END)
+                RETURNING id, user_id, old_email, new_email;
+            "#,
+            user_id,
+            old_email,
+            new_email,
+            confirmed,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if confirmed {
+            query!(
+                r#"
+                    UPDATE users SET email = ?1
+                    WHERE id = ?2;
+                "#,
+                new_email,
+                user_id,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(change)
+    }
+
+    /// Fetch a change, plus the username it belongs to (for showing on
+    /// the page a link goes to).
+    #[tracing::instrument(skip(self))]
+    pub async fn by_id(&self, id: i64) -> sqlx::Result<Option<(EmailChange, String)>> {
+        let res = query!(
+            r#"
+                SELECT
+                    email_changes.id, email_changes.user_id, email_changes.old_email,
+                    email_changes.new_email, users.username
+                FROM email_changes
+                    JOIN users ON users.id = email_changes.user_id
+                WHERE email_changes.id = ?;
+            "#,
+            id,
+        )
+        .fetch_optional(self.read_pool())
+        .await?;
+        Ok(res.map(|r| {
+            let change = EmailChange {
+                id: r.id,
+                user_id: r.user_id,
+                old_email: r.old_email,
+                new_email: r.new_email,
+            };
+            (change, r.username)
+        }))
+    }
+
+    /// The address a user's waiting to confirm, if any. Only their latest
+    /// change counts, and only until its confirm link expires.
+    #[tracing::instrument(skip(self))]
+    pub async fn pending(&self, user_id: i64) -> sqlx::Result<Option<String>> {
+        let cutoff = OffsetDateTime::now_utc() - time::Duration::days(CONFIRM_WINDOW_DAYS);
+        let res = query!(
+            r#"
+                SELECT new_email
+                FROM email_changes
+                WHERE user_id = ?1
+                    AND confirmed IS NULL AND reverted IS NULL
+                    AND created > datetime(?2)
+                    AND id = (SELECT max(id) FROM email_changes WHERE user_id = ?1);
+            "#,
+            user_id,
+            cutoff,
+        )
+        .fetch_optional(self.read_pool())
+        .await?;
+        Ok(res.and_then(|r| r.new_email))
+    }
+
+    /// Use a change's confirm link: switch the user over to the new
+    /// address. Returns None if the link's used up, expired, or superseded
+    /// by a later change.
+    #[tracing::instrument(skip(self))]
+    pub async fn confirm(&self, id: i64) -> sqlx::Result<Option<EmailChange>> {
+        let cutoff = OffsetDateTime::now_utc() - time::Duration::days(CONFIRM_WINDOW_DAYS);
+        let mut tx = self.write_pool().begin().await?;
+        let Some(change) = query_as!(
+            EmailChange,
+            r#"
+                UPDATE email_changes SET confirmed = current_timestamp
+                WHERE id = ?1
+                    AND confirmed IS NULL AND reverted IS NULL
+                    AND created > datetime(?2)
+                    AND id = (
+                        SELECT max(id) FROM email_changes AS later
+                        WHERE later.user_id = email_changes.user_id
+                    )
+                RETURNING id, user_id, old_email, new_email;
+            "#,
+            id,
+            cutoff,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        query!(
+            r#"
+                UPDATE users SET email = ?1
+                WHERE id = ?2;
+            "#,
+            change.new_email,
+            change.user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(change))
+    }
+
+    /// Use a change's revert link: put the old address back, cancel any
+    /// later changes (confirmed or not), and log out every session, since
+    /// whoever made the change might still be logged in. Returns None if
+    /// the link's used up or expired.
+    #[tracing::instrument(skip(self))]
+    pub async fn revert(&self, id: i64) -> sqlx::Result<Option<EmailChange>> {
+        let cutoff = OffsetDateTime::now_utc() - time::Duration::days(REVERT_WINDOW_DAYS);
+        let mut tx = self.write_pool().begin().await?;
+        let Some(change) = query_as!(
+            EmailChange,
+            r#"
+                UPDATE email_changes SET reverted = current_timestamp
+                WHERE id = ?1 AND reverted IS NULL AND created > datetime(?2)
+                RETURNING id, user_id, old_email, new_email;
+            "#,
+            id,
+            cutoff,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        query!(
+            r#"
+                UPDATE email_changes SET reverted = current_timestamp
+                WHERE user_id = ?1 AND id > ?2 AND reverted IS NULL;
+            "#,
+            change.user_id,
+            change.id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        query!(
+            r#"
+                UPDATE users SET email = ?1
+                WHERE id = ?2;
+            "#,
+            change.old_email,
+            change.user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        query!(
+            r#"
+                DELETE FROM sessions
+                WHERE user_id = ?;
+            "#,
+            change.user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(change))
+    }
+
+    /// Delete change records (and the addresses in them) older than
+    /// `months`. Their links are long dead by then.
+    #[tracing::instrument(skip(self))]
+    pub async fn delete_old(&self, months: u32) -> sqlx::Result<u64> {
+        let cutoff = format!("-{} months", months);
+        query!(
+            r#"
+                DELETE FROM email_changes WHERE created < datetime('now', ?);
+            "#,
+            cutoff,
+        )
+        .execute(self.write_pool())
+        .await
+        .map(|v| v.rows_affected())
+    }
+}
@@ -0,0 +1,208 @@
+use super::core::Db;
+use sqlx::{query, query_as, SqlitePool};
+use std::collections::HashMap;
+
+// The record struct doubles as an API wire type, same as Dogear.
+pub use eardogger_rs::api_types::DogearFeed;
+
+/// A query helper type for operating on [DogearFeed]s. Usually rented from
+/// a [Db].
+#[derive(Debug)]
+pub struct Feeds<'a> {
+    db: &'a Db,
+}
+
+/// A feed that's due for a check, with what the poller needs to know about
+/// its dogear.
+#[derive(Debug, Clone)]
+pub struct DueFeed {
+    pub dogear_id: i64,
+    pub user_id: i64,
+    pub feed_url: String,
+    pub auto_advance: bool,
+    pub latest_seen: Option<String>,
+    pub failures: i64,
+    pub prefix: String,
+    pub current: String,
+}
+
+// set, clear, for_user, due, record_check, record_failure
+impl<'a> Feeds<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// Give one of a user's dogears a feed, or change the one it has. A new
+    /// URL starts its bookkeeping over and gets checked on the next pass;
+    /// just flipping auto_advance leaves it be. Returns the feed, or
+    /// Ok(None) if the dogear doesn't exist or belongs to someone else.
+    /// The caller's on the hook for validating the URL.
+    #[tracing::instrument(skip(self))]
+    pub async fn set(
+        &self,
+        dogear_id: i64,
+        user_id: i64,
+        feed_url: &str,
+        auto_advance: bool,
+    ) -> sqlx::Result<Option<DogearFeed>> {
+        query_as!(
+            DogearFeed,
+            r#"
+                INSERT INTO dogear_feeds (dogear_id, user_id, feed_url, auto_advance)
+                SELECT id, user_id, ?3, ?4 FROM dogears
+                WHERE id = ?1 AND user_id = ?2
+                ON CONFLICT (dogear_id) DO UPDATE SET
+                    auto_advance = excluded.auto_advance,
+                    latest_seen = iif(feed_url = excluded.feed_url, latest_seen, NULL),
+                    new_chapter = iif(feed_url = excluded.feed_url, new_chapter, NULL),
+                    new_chapter_found = iif(feed_url = excluded.feed_url, new_chapter_found, NULL),
+                    last_checked = iif(feed_url = excluded.feed_url, last_checked, NULL),
+                    next_check = iif(feed_url = excluded.feed_url, next_check, current_timestamp),
+                    failures = iif(feed_url = excluded.feed_url, failures, 0),
+                    last_error = iif(feed_url = excluded.feed_url, last_error, NULL),
+                    feed_url = excluded.feed_url
+                RETURNING dogear_id, user_id, feed_url, auto_advance, latest_seen, new_chapter,
+                    new_chapter_found, last_checked, failures, last_error;
+            "#,
+            dogear_id,
+            user_id,
+            feed_url,
+            auto_advance,
+        )
+        .fetch_optional(self.write_pool())
+        .await
+    }
+
+    /// Stop watching a dogear's feed. Returns whether it had one (that
+    /// belonged to this user).
+    #[tracing::instrument(skip(self))]
+    pub async fn clear(&self, dogear_id: i64, user_id: i64) -> sqlx::Result<bool> {
+        query!(
+            r#"
+                DELETE FROM dogear_feeds
+                WHERE dogear_id = ?1 AND user_id = ?2;
+            "#,
+            dogear_id,
+            user_id,
+        )
+        .execute(self.write_pool())
+        .await
+        .map(|v| v.rows_affected() > 0)
+    }
+
+    /// All of a user's feeds, by dogear ID.
+    #[tracing::instrument(skip(self))]
+    pub async fn for_user(&self, user_id: i64) -> sqlx::Result<HashMap<i64, DogearFeed>> {
+        let feeds = query_as!(
+            DogearFeed,
+            r#"
+                SELECT dogear_id, user_id, feed_url, auto_advance, latest_seen, new_chapter,
+                    new_chapter_found, last_checked, failures, last_error
+                FROM dogear_feeds
+                WHERE user_id = ?1;
+            "#,
+            user_id,
+        )
+        .fetch_all(self.read_pool())
+        .await?;
+        Ok(feeds.into_iter().map(|f| (f.dogear_id, f)).collect())
+    }
+
+    /// Feeds whose next check has come up, most overdue first, up to
+    /// `limit`.
+    #[tracing::instrument(skip(self))]
+    pub async fn due(&self, limit: u32) -> sqlx::Result<Vec<DueFeed>> {
+        query_as!(
+            DueFeed,
+            r#"
+                SELECT
+                    dogear_feeds.dogear_id,
+                    dogear_feeds.user_id,
+                    dogear_feeds.feed_url,
+                    dogear_feeds.auto_advance,
+                    dogear_feeds.latest_seen,
+                    dogear_feeds.failures,
+                    dogears.prefix,
+                    dogears.current
+                FROM dogear_feeds
+                    JOIN dogears ON dogear_feeds.dogear_id = dogears.id
+                    JOIN users ON dogear_feeds.user_id = users.id
+                WHERE dogear_feeds.next_check <= current_timestamp
+                    AND NOT users.disabled
+                ORDER BY dogear_feeds.next_check ASC
+                LIMIT ?1;
+            "#,
+            limit,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+
+    /// Record a check that worked: the newest link it saw, the new chapter
+    /// it's flagging (if any; otherwise any old flag stays put), and when
+    /// to check next, as a sqlite datetime modifier like "+60 minutes".
+    #[tracing::instrument(skip(self))]
+    pub async fn record_check(
+        &self,
+        dogear_id: i64,
+        latest_seen: Option<&str>,
+        new_chapter: Option<&str>,
+        next_check: &str,
+    ) -> sqlx::Result<()> {
+        query!(
+            r#"
+                UPDATE dogear_feeds
+                SET
+                    latest_seen = coalesce(?2, latest_seen),
+                    new_chapter = coalesce(?3, new_chapter),
+                    new_chapter_found = iif(?3 IS NULL, new_chapter_found, current_timestamp),
+                    last_checked = current_timestamp,
+                    next_check = datetime('now', ?4),
+                    failures = 0,
+                    last_error = NULL
+                WHERE dogear_id = ?1;
+            "#,
+            dogear_id,
+            latest_seen,
+            new_chapter,
+            next_check,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Record a check that didn't work, and when to try again (same
+    /// format as `record_check`).
+    #[tracing::instrument(skip(self))]
+    pub async fn record_failure(
+        &self,
+        dogear_id: i64,
+        error: &str,
+        next_check: &str,
+    ) -> sqlx::Result<()> {
+        query!(
+            r#"
+                UPDATE dogear_feeds
+                SET
+                    last_checked = current_timestamp,
+                    next_check = datetime('now', ?3),
+                    failures = failures + 1,
+                    last_error = ?2
+                WHERE dogear_id = ?1;
+            "#,
+            dogear_id,
+            error,
+            next_check,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use super::core::Db;
+use sqlx::{query, query_as, SqlitePool};
+use time::OffsetDateTime;
+
+/// A query helper type for operating on [FlagOverride]s. Usually rented
+/// from a [Db].
+#[derive(Debug)]
+pub struct FlagOverrides<'a> {
+    db: &'a Db,
+}
+
+/// Record struct for a feature flag's live rollout, which beats whatever
+/// the config file says until someone clears it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlagOverride {
+    pub name: String,
+    /// Percent of users who get the feature, 0 to 100.
+    pub rollout: i64,
+    pub updated: OffsetDateTime,
+}
+
+// list, set, clear
+impl<'a> FlagOverrides<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// All of them, by name.
+    #[tracing::instrument(skip_all)]
+    pub async fn list(&self) -> sqlx::Result<Vec<FlagOverride>> {
+        query_as!(
+            FlagOverride,
+            r#"
+                SELECT name, rollout, updated
+                FROM flag_overrides
+                ORDER BY name;
+            "#,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+
+    /// Set a flag's rollout, replacing any earlier override.
+    #[tracing::instrument(skip(self))]
+    pub async fn set(&self, name: &str, rollout: u8) -> sqlx::Result<FlagOverride> {
+        query_as!(
+            FlagOverride,
+            r#"
+                INSERT INTO flag_overrides (name, rollout)
+                VALUES (?1, ?2)
+                ON CONFLICT (name) DO UPDATE SET
+                    rollout = excluded.rollout,
+                    updated = current_timestamp
+                RETURNING name, rollout, updated;
+            "#,
+            name,
+            rollout,
+        )
+        .fetch_one(self.write_pool())
+        .await
+    }
+
+    /// Drop a flag's override, so it goes back to the config file's
+    /// rollout. Returns whether there was one.
+    #[tracing::instrument(skip(self))]
+    pub async fn clear(&self, name: &str) -> sqlx::Result<bool> {
+        let res = query!(
+            r#"
+                DELETE FROM flag_overrides WHERE name = ?;
+            "#,
+            name,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+}
@@ -0,0 +1,275 @@
+use super::core::Db;
+use crate::util::{normalize_username, MixedError, UserError};
+use serde::Serialize;
+use sqlx::{query, query_as, query_scalar, SqlitePool};
+use time::{serde::iso8601, OffsetDateTime};
+
+/// A query helper type for operating on [Grant]s. Usually rented from a [Db].
+#[derive(Debug)]
+pub struct Grants<'a> {
+    db: &'a Db,
+}
+
+/// Record struct for "household" sharing. A grant lets one user (the grantee)
+/// view another user's (the owner's) dogears list in read-only mode, once
+/// the grantee accepts it; until then it's just an invitation. Grants only
+/// go one way; if two people want to see each other's stuff, they each make
+/// one. The usernames get joined in on the way out, since basically every
+/// consumer of these wants to display them.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Grant {
+    pub id: i64,
+    pub owner_id: i64,
+    pub owner_username: String,
+    pub grantee_id: i64,
+    pub grantee_username: String,
+    #[serde(with = "iso8601")]
+    pub created: OffsetDateTime,
+    /// None while it's still an invitation.
+    #[serde(with = "iso8601::option")]
+    pub accepted: Option<OffsetDateTime>,
+}
+
+// invite, accept, decline, find, destroy, list_given, list_received, list_invitations
+impl<'a> Grants<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// Invite another user (by name) to see the owner's dogears. Nothing's
+    /// shared until they accept. This succeeds the same way whether or not
+    /// the other user exists (or was already invited), so the share form
+    /// can't be used to find out who has an account; the only user-facing
+    /// error is trying to invite yourself.
+    #[tracing::instrument(skip_all)]
+    pub async fn invite(
+        &self,
+        owner_id: i64,
+        grantee_username: &str,
+    ) -> Result<(), MixedError<sqlx::Error>> {
+        let grantee_username = normalize_username(grantee_username);
+        let grantee_username = grantee_username.as_ref();
+        // Couple reads and a write; these need to agree with each other, so
+        // it's a transaction on the writer.
+        let mut tx = self.write_pool().begin().await?;
+
+        let Some(grantee_id) = query_scalar!(
+            r#"
+                SELECT id FROM users WHERE username = ? AND NOT disabled;
+            "#,
+            grantee_username,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(());
+        };
+        if grantee_id == owner_id {
+            return Err(UserError::GrantSelf.into());
+        }
+
+        query!(
+            r#"
+                INSERT INTO grants (owner_id, grantee_id)
+                VALUES (?1, ?2)
+                ON CONFLICT (owner_id, grantee_id) DO NOTHING;
+            "#,
+            owner_id,
+            grantee_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Accept an invitation, which turns the grant on. Only the grantee gets
+    /// to do this, so provide their user ID from a trusted source. Returns
+    /// Ok(None) if there was no pending invitation to accept.
+    #[tracing::instrument(skip_all)]
+    pub async fn accept(&self, id: i64, grantee_id: i64) -> sqlx::Result<Option<()>> {
+        let res = query!(
+            r#"
+                UPDATE grants SET accepted = current_timestamp
+                WHERE id = ?1 AND grantee_id = ?2 AND accepted IS NULL;
+            "#,
+            id,
+            grantee_id,
+        )
+        .execute(self.write_pool())
+        .await?;
+        if res.rows_affected() == 1 {
+            Ok(Some(()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The grantee's version of destroy: turn down an invitation, or stop
+    /// seeing a list they'd accepted. Returns Ok(None) if there was nothing
+    /// to delete.
+    #[tracing::instrument(skip_all)]
+    pub async fn decline(&self, id: i64, grantee_id: i64) -> sqlx::Result<Option<()>> {
+        let res = query!(
+            r#"
+                DELETE FROM grants
+                WHERE id = ?1 AND grantee_id = ?2;
+            "#,
+            id,
+            grantee_id,
+        )
+        .execute(self.write_pool())
+        .await?;
+        if res.rows_affected() == 1 {
+            Ok(Some(()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Check whether the owner (by name) has shared their dogears with the
+    /// grantee (by ID), and return the grant if so. This is the permission
+    /// check for viewing someone else's list, so unaccepted invitations and
+    /// a disabled owner's grants don't count.
+    #[tracing::instrument(skip_all)]
+    pub async fn find(&self, owner_username: &str, grantee_id: i64) -> sqlx::Result<Option<Grant>> {
+        let owner_username = normalize_username(owner_username);
+        let owner_username = owner_username.as_ref();
+        query_as!(
+            Grant,
+            r#"
+                SELECT
+                    grants.id          AS id,
+                    grants.owner_id    AS owner_id,
+                    owners.username    AS owner_username,
+                    grants.grantee_id  AS grantee_id,
+                    grantees.username  AS grantee_username,
+                    grants.created     AS created,
+                    grants.accepted    AS accepted
+                FROM grants
+                    JOIN users AS owners ON grants.owner_id = owners.id
+                    JOIN users AS grantees ON grants.grantee_id = grantees.id
+                WHERE owners.username = ?1 AND grants.grantee_id = ?2
+                    AND grants.accepted IS NOT NULL AND NOT owners.disabled;
+            "#,
+            owner_username,
+            grantee_id,
+        )
+        .fetch_optional(self.read_pool())
+        .await
+    }
+
+    /// Revoke a grant. Only the owner gets to do this, so provide their
+    /// user ID from a trusted source. Returns Ok(None) if there was nothing
+    /// to delete.
+    #[tracing::instrument(skip_all)]
+    pub async fn destroy(&self, id: i64, owner_id: i64) -> sqlx::Result<Option<()>> {
+        let res = query!(
+            r#"
+                DELETE FROM grants
+                WHERE id = ?1 AND owner_id = ?2;
+            "#,
+            id,
+            owner_id,
+        )
+        .execute(self.write_pool())
+        .await?;
+        if res.rows_affected() == 1 {
+            Ok(Some(()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// List the grants a user has handed out (i.e. who can see their stuff).
+    /// Not paginated: you can't share with more people than there are users,
+    /// and in practice this is a household-sized list. Invitations nobody's
+    /// accepted yet are in it too (with no `accepted` date), so the owner can
+    /// take back a mistaken one. That doesn't tell them which names have
+    /// accounts, since inviting a name nobody has doesn't make a row.
+    #[tracing::instrument(skip_all)]
+    pub async fn list_given(&self, owner_id: i64) -> sqlx::Result<Vec<Grant>> {
+        query_as!(
+            Grant,
+            r#"
+                SELECT
+                    grants.id          AS id,
+                    grants.owner_id    AS owner_id,
+                    owners.username    AS owner_username,
+                    grants.grantee_id  AS grantee_id,
+                    grantees.username  AS grantee_username,
+                    grants.created     AS created,
+                    grants.accepted    AS accepted
+                FROM grants
+                    JOIN users AS owners ON grants.owner_id = owners.id
+                    JOIN users AS grantees ON grants.grantee_id = grantees.id
+                WHERE grants.owner_id = ?
+                ORDER BY grantees.username ASC;
+            "#,
+            owner_id,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+
+    /// List the grants a user has received (i.e. whose stuff they can see).
+    #[tracing::instrument(skip_all)]
+    pub async fn list_received(&self, grantee_id: i64) -> sqlx::Result<Vec<Grant>> {
+        query_as!(
+            Grant,
+            r#"
+                SELECT
+                    grants.id          AS id,
+                    grants.owner_id    AS owner_id,
+                    owners.username    AS owner_username,
+                    grants.grantee_id  AS grantee_id,
+                    grantees.username  AS grantee_username,
+                    grants.created     AS created,
+                    grants.accepted    AS accepted
+                FROM grants
+                    JOIN users AS owners ON grants.owner_id = owners.id
+                    JOIN users AS grantees ON grants.grantee_id = grantees.id
+                WHERE grants.grantee_id = ? AND grants.accepted IS NOT NULL
+                    AND NOT owners.disabled
+                ORDER BY owners.username ASC;
+            "#,
+            grantee_id,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+
+    /// List the invitations waiting on a user's answer.
+    #[tracing::instrument(skip_all)]
+    pub async fn list_invitations(&self, grantee_id: i64) -> sqlx::Result<Vec<Grant>> {
+        query_as!(
+            Grant,
+            r#"
+                SELECT
+                    grants.id          AS id,
+                    grants.owner_id    AS owner_id,
+                    owners.username    AS owner_username,
+                    grants.grantee_id  AS grantee_id,
+                    grantees.username  AS grantee_username,
+                    grants.created     AS created,
+                    grants.accepted    AS accepted
+                FROM grants
+                    JOIN users AS owners ON grants.owner_id = owners.id
+                    JOIN users AS grantees ON grants.grantee_id = grantees.id
+                WHERE grants.grantee_id = ? AND grants.accepted IS NULL
+                    AND NOT owners.disabled
+                ORDER BY owners.username ASC;
+            "#,
+            grantee_id,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+}
@@ -0,0 +1,130 @@
+use super::core::Db;
+use crate::util::{sha256sum, uuid_string};
+use serde::Serialize;
+use sqlx::{query, query_as, SqlitePool};
+use time::{serde::iso8601, OffsetDateTime};
+
+/// A query helper type for operating on [Hook]s. Usually rented from a [Db].
+#[derive(Debug)]
+pub struct Hooks<'a> {
+    db: &'a Db,
+}
+
+/// Record struct for a dogear's inbound webhook: a secret URL that a site
+/// (or an RSS-to-webhook service) can POST new chapter URLs to, so the
+/// dogear keeps up without anyone clicking a bookmarklet. The secret is
+/// the whole auth, so like tokens, only its hash gets stored. The dogear's
+/// name and prefix get joined in on the way out, for the account page.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Hook {
+    pub dogear_id: i64,
+    pub user_id: i64,
+    pub prefix: String,
+    pub display_name: Option<String>,
+    #[serde(with = "iso8601")]
+    pub created: OffsetDateTime,
+    #[serde(with = "iso8601::option")]
+    pub last_used: Option<OffsetDateTime>,
+}
+
+// create, authenticate, destroy, list
+impl<'a> Hooks<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// Make a fresh secret for one of a user's dogears, replacing any it
+    /// already had (so this doubles as rotate). Returns the cleartext,
+    /// which is only available this once, or Ok(None) if the dogear
+    /// doesn't exist or belongs to someone else.
+    #[tracing::instrument(skip(self))]
+    pub async fn create(&self, dogear_id: i64, user_id: i64) -> sqlx::Result<Option<String>> {
+        let cleartext = format!("eardoggerhook1.{}", uuid_string());
+        let secret_hash = sha256sum(&cleartext);
+        let res = query!(
+            r#"
+                INSERT INTO dogear_hooks (dogear_id, user_id, secret_hash)
+                SELECT id, user_id, ?3 FROM dogears
+                WHERE id = ?1 AND user_id = ?2
+                ON CONFLICT (dogear_id) DO UPDATE SET
+                    secret_hash = excluded.secret_hash,
+                    created = current_timestamp,
+                    last_used = NULL;
+            "#,
+            dogear_id,
+            user_id,
+            secret_hash,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok((res.rows_affected() > 0).then_some(cleartext))
+    }
+
+    /// Look up a webhook secret, and mark it as used. Returns the dogear
+    /// and user it belongs to, or None if it's not a real one (or its
+    /// owner's account is disabled).
+    #[tracing::instrument(skip_all)]
+    pub async fn authenticate(&self, cleartext: &str) -> sqlx::Result<Option<(i64, i64)>> {
+        let secret_hash = sha256sum(cleartext);
+        let res = query!(
+            r#"
+                UPDATE dogear_hooks
+                SET last_used = current_timestamp
+                WHERE secret_hash = ?1
+                    AND NOT (SELECT disabled FROM users WHERE users.id = dogear_hooks.user_id)
+                RETURNING dogear_id, user_id;
+            "#,
+            secret_hash,
+        )
+        .fetch_optional(self.write_pool())
+        .await?;
+        Ok(res.map(|r| (r.dogear_id, r.user_id)))
+    }
+
+    /// Turn off a dogear's webhook. Returns whether there was one to turn
+    /// off (that belonged to this user).
+    #[tracing::instrument(skip(self))]
+    pub async fn destroy(&self, dogear_id: i64, user_id: i64) -> sqlx::Result<bool> {
+        query!(
+            r#"
+                DELETE FROM dogear_hooks
+                WHERE dogear_id = ?1 AND user_id = ?2;
+            "#,
+            dogear_id,
+            user_id,
+        )
+        .execute(self.write_pool())
+        .await
+        .map(|v| v.rows_affected() > 0)
+    }
+
+    /// All of a user's webhooks, most recently made first. There's at most
+    /// one per dogear, so no pagination.
+    #[tracing::instrument(skip(self))]
+    pub async fn list(&self, user_id: i64) -> sqlx::Result<Vec<Hook>> {
+        query_as!(
+            Hook,
+            r#"
+                SELECT
+                    dogear_hooks.dogear_id,
+                    dogear_hooks.user_id,
+                    dogears.prefix,
+                    dogears.display_name,
+                    dogear_hooks.created,
+                    dogear_hooks.last_used
+                FROM dogear_hooks JOIN dogears ON dogear_hooks.dogear_id = dogears.id
+                WHERE dogear_hooks.user_id = ?1
+                ORDER BY dogear_hooks.created DESC, dogear_hooks.dogear_id DESC;
+            "#,
+            user_id,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+}
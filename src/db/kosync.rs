@@ -0,0 +1,105 @@
+use super::core::Db;
+use sqlx::{query_as, SqlitePool};
+use time::OffsetDateTime;
+
+/// A query helper type for operating on [KosyncDocument]s. Usually rented
+/// from a [Db].
+#[derive(Debug)]
+pub struct Kosync<'a> {
+    db: &'a Db,
+}
+
+/// Record struct for a book's last synced position, as KOReader reported it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KosyncDocument {
+    pub user_id: i64,
+    /// KOReader's hash of the book (or of its filename, depending on the
+    /// plugin's settings). Opaque to us.
+    pub document: String,
+    /// The book's dogear, unless the user deleted it since the last sync.
+    pub dogear_id: Option<i64>,
+    /// KOReader's own position string (an xpointer, or a page number for
+    /// fixed-layout books). Handed back verbatim.
+    pub progress: String,
+    /// 0.0 to 1.0.
+    pub percentage: f64,
+    pub device: String,
+    pub device_id: String,
+    pub updated: OffsetDateTime,
+}
+
+/// The parts of a sync that come from the reader.
+#[derive(Debug, Clone, Copy)]
+pub struct KosyncPosition<'b> {
+    pub progress: &'b str,
+    pub percentage: f64,
+    pub device: &'b str,
+    pub device_id: &'b str,
+}
+
+// get, put
+impl<'a> Kosync<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// Get the last synced position for one of a user's books.
+    #[tracing::instrument(skip(self))]
+    pub async fn get(&self, user_id: i64, document: &str) -> sqlx::Result<Option<KosyncDocument>> {
+        query_as!(
+            KosyncDocument,
+            r#"
+                SELECT user_id, document, dogear_id, progress, percentage, device, device_id, updated
+                FROM kosync_documents
+                WHERE user_id = ?1 AND document = ?2;
+            "#,
+            user_id,
+            document,
+        )
+        .fetch_optional(self.read_pool())
+        .await
+    }
+
+    /// Save a book's new position (and which dogear it's tracked by),
+    /// replacing whatever was there.
+    #[tracing::instrument(skip(self))]
+    pub async fn put(
+        &self,
+        user_id: i64,
+        document: &str,
+        dogear_id: Option<i64>,
+        position: KosyncPosition<'_>,
+    ) -> sqlx::Result<KosyncDocument> {
+        query_as!(
+            KosyncDocument,
+            r#"
+                INSERT INTO kosync_documents
+                    (user_id, document, dogear_id, progress, percentage, device, device_id)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT (user_id, document) DO UPDATE SET
+                    dogear_id = excluded.dogear_id,
+                    progress = excluded.progress,
+                    percentage = excluded.percentage,
+                    device = excluded.device,
+                    device_id = excluded.device_id,
+                    updated = current_timestamp
+                RETURNING user_id, document, dogear_id, progress, percentage, device, device_id, updated;
+            "#,
+            user_id,
+            document,
+            dogear_id,
+            position.progress,
+            position.percentage,
+            position.device,
+            position.device_id,
+        )
+        .fetch_one(self.write_pool())
+        .await
+    }
+}
@@ -0,0 +1,82 @@
+use super::core::Db;
+use crate::util::{sha256sum, uuid_string};
+use sqlx::{query, query_scalar, SqlitePool};
+
+/// How long a migration code stays good, in minutes. Long enough to go log
+/// in on the old instance, short enough that a forgotten one goes stale.
+pub const MIGRATION_CODE_MINUTES: i64 = 60;
+
+/// A query helper type for operating on migration codes: the one-time
+/// codes that let another instance push an account's dogears into this
+/// one. Usually rented from a [Db]. Like tokens, only the hash gets stored.
+#[derive(Debug)]
+pub struct MigrationCodes<'a> {
+    db: &'a Db,
+}
+
+// create, redeem, delete_expired
+impl<'a> MigrationCodes<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// Make a fresh code for a user, replacing any they already had.
+    /// Returns the cleartext, which is only available this once.
+    #[tracing::instrument(skip(self))]
+    pub async fn create(&self, user_id: i64) -> sqlx::Result<String> {
+        let cleartext = format!("eardoggermove1.{}", uuid_string());
+        let code_hash = sha256sum(&cleartext);
+        query!(
+            r#"
+                INSERT INTO migration_codes (user_id, code_hash)
+                VALUES (?1, ?2)
+                ON CONFLICT (user_id) DO UPDATE SET
+                    code_hash = excluded.code_hash,
+                    created = current_timestamp;
+            "#,
+            user_id,
+            code_hash,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(cleartext)
+    }
+
+    /// Use up a code. Returns the user it belongs to, or None if it's
+    /// wrong, expired, or already used.
+    #[tracing::instrument(skip_all)]
+    pub async fn redeem(&self, cleartext: &str) -> sqlx::Result<Option<i64>> {
+        let code_hash = sha256sum(cleartext);
+        let cutoff = format!("-{} minutes", MIGRATION_CODE_MINUTES);
+        query_scalar!(
+            r#"
+                DELETE FROM migration_codes
+                WHERE code_hash = ?1 AND created > datetime('now', ?2)
+                RETURNING user_id;
+            "#,
+            code_hash,
+            cutoff,
+        )
+        .fetch_optional(self.write_pool())
+        .await
+    }
+
+    /// Clean out codes that expired without getting used. Returns how many.
+    #[tracing::instrument(skip(self))]
+    pub async fn delete_expired(&self) -> sqlx::Result<u64> {
+        let cutoff = format!("-{} minutes", MIGRATION_CODE_MINUTES);
+        query!(
+            r#"
+                DELETE FROM migration_codes
+                WHERE created <= datetime('now', ?1);
+            "#,
+            cutoff,
+        )
+        .execute(self.write_pool())
+        .await
+        .map(|v| v.rows_affected())
+    }
+}
@@ -3,7 +3,7 @@ use sqlx::{
     migrate::{Migrate, Migrator},
     query_scalar, SqlitePool,
 };
-use std::{borrow::Cow, collections::HashMap, fmt::Display};
+use std::{borrow::Cow, collections::HashMap, fmt::Display, path::Path};
 use thiserror::Error;
 use tracing::{debug, warn};
 
@@ -112,6 +112,27 @@ impl<'a> Migrations<'a> {
         MIGRATOR.run(self.write_pool()).await
     }
 
+    /// How many known migrations haven't been applied yet.
+    #[tracing::instrument(skip_all)]
+    pub async fn pending(&self) -> sqlx::Result<usize> {
+        let statuses = self.info().await?;
+        Ok(statuses
+            .iter()
+            .filter(|s| matches!(s, Status::Pending { .. }))
+            .count())
+    }
+
+    /// Write a consistent copy of the whole database to a new file at
+    /// `path`, with VACUUM INTO. The file can't already exist.
+    #[tracing::instrument(skip(self))]
+    pub async fn backup_into(&self, path: &Path) -> sqlx::Result<()> {
+        sqlx::query("VACUUM INTO ?1;")
+            .bind(path.to_string_lossy().into_owned())
+            .execute(self.write_pool())
+            .await?;
+        Ok(())
+    }
+
     /// Check whether the database migrations are in a usable state. For background
     /// on the logic in here, consult the source of the sqlx CLI:
     /// <https://github.com/launchbadge/sqlx/blob/5d6c33ed65cc2/sqlx-cli/src/migrate.rs>
@@ -80,20 +80,60 @@
 //!   being "last used" timestamps. These kinds of incidental writes can be offloaded
 //!   to a spawned task, so we can return the useful part of the query without having
 //!   to await a connection from the write pool.
+//! - Operations that span several helpers (signup's new user plus their first
+//!   session, say) can share one write transaction via `Db::transaction`. The
+//!   helpers that take part in those (`Users`, `Tokens`, `Sessions`,
+//!   `Dogears`) get their connections from a `Handle` instead of straight from
+//!   the pools, so the same methods work either way. See the `tx` module for
+//!   the rules of the road.
 
+mod audit;
+mod backups;
+mod changes;
 mod core;
 mod db_tests;
+mod devices;
 mod dogears;
+mod email_changes;
+mod feeds;
+mod flag_overrides;
+mod grants;
+mod hooks;
+mod kosync;
+mod migration_codes;
 mod migrations;
+mod prefs;
 mod sessions;
+mod site_rules;
 mod tokens;
+mod totp;
+mod tx;
 mod users;
+mod webhooks;
 
 // Publicize the record types, they're the star of the show
-pub use self::dogears::Dogear;
+pub use self::audit::{AuditEvent, AuditKind, AuditSource};
+pub use self::backups::{BackupDestination, DueBackup};
+pub use self::changes::ChangeListener;
+pub use self::devices::Sighting;
+pub use self::dogears::{Dogear, DogearHistoryEntry, DogearUpdate};
+pub use self::email_changes::{EmailChange, CONFIRM_WINDOW_DAYS, REVERT_WINDOW_DAYS};
+pub use self::feeds::{DogearFeed, DueFeed};
+pub use self::flag_overrides::FlagOverride;
+pub use self::grants::Grant;
+pub use self::hooks::Hook;
+pub use self::kosync::{KosyncDocument, KosyncPosition};
+pub use self::migration_codes::MIGRATION_CODE_MINUTES;
+pub use self::prefs::{UserPrefs, DEFAULT_STALE_MONTHS, MAX_STALE_MONTHS};
 pub use self::sessions::Session;
-pub use self::tokens::{Token, TokenScope};
-pub use self::users::User;
+pub use self::site_rules::SiteRule;
+pub use self::tokens::{Bookmarklet, Token, TokenScope};
+pub use self::totp::{TotpMethod, TotpSecret};
+pub use self::tx::DbTx;
+pub use self::users::{User, UserFlags, UserSummary};
+pub use self::webhooks::{DueDelivery, Webhook, MAX_WEBHOOKS};
 
 // And the main wrapper type
 pub use self::core::Db;
+#[cfg(any(test, feature = "dev_tools"))]
+pub use self::core::TestUser;
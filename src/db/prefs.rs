@@ -0,0 +1,261 @@
+use super::core::Db;
+use crate::util::PAGE_DEFAULT_SIZE;
+use serde::Serialize;
+use sqlx::{query, query_as, SqlitePool};
+use time::OffsetDateTime;
+
+/// How many months a dogear can sit still before the index page suggests
+/// archiving it, for users who haven't picked their own.
+pub const DEFAULT_STALE_MONTHS: u32 = 6;
+/// The longest threshold anyone can pick.
+pub const MAX_STALE_MONTHS: u32 = 120;
+
+/// A query helper type for operating on [UserPrefs]. Usually rented from a [Db].
+#[derive(Debug)]
+pub struct Prefs<'a> {
+    db: &'a Db,
+}
+
+/// Record struct for per-user settings. Users who've never changed anything
+/// don't have a row, and get the Default.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct UserPrefs {
+    /// Whether to email the user when someone logs in from a device we
+    /// haven't seen before.
+    pub notify_new_device: bool,
+    /// Whether /u/:username shows anything. Even when it's on, only dogears
+    /// marked public are listed.
+    pub public_profile: bool,
+    /// A CSS snippet to add to every page for this user's own sessions.
+    /// Already checked by `clean_custom_css` on the way in.
+    pub custom_css: Option<String>,
+    /// Dogears list display options: tighter rows, the prefix on each
+    /// dogear, and no "last read" dates. These are the saved defaults;
+    /// the list routes can override them per-request.
+    pub compact_list: bool,
+    pub show_prefix: bool,
+    pub hide_dates: bool,
+    /// How many months of dogear history to keep, if less than the site's
+    /// retention default. None means the site default.
+    pub history_months: Option<i64>,
+    /// How many months a dogear can go without moving before the index page
+    /// suggests archiving it. None means DEFAULT_STALE_MONTHS, and 0 means
+    /// never suggest it.
+    pub stale_months: Option<i64>,
+    /// How many items a page of a list shows when the URL doesn't say.
+    /// None means PAGE_DEFAULT_SIZE.
+    pub page_size: Option<i64>,
+}
+
+impl UserPrefs {
+    /// The archive nudge threshold in months, or None if it's turned off.
+    pub fn stale_after(&self) -> Option<u32> {
+        match self.stale_months {
+            None => Some(DEFAULT_STALE_MONTHS),
+            Some(m) if m > 0 => u32::try_from(m).ok(),
+            Some(_) => None,
+        }
+    }
+
+    /// The page size for lists that didn't ask for one.
+    pub fn default_page_size(&self) -> u32 {
+        self.page_size
+            .and_then(|s| u32::try_from(s).ok())
+            .filter(|&s| s > 0)
+            .unwrap_or(PAGE_DEFAULT_SIZE)
+    }
+}
+
+/// One entry in the list of public profiles, for the sitemap.
+#[derive(Debug, Clone)]
+pub struct PublicProfileEntry {
+    pub username: String,
+    /// The last time one of their public dogears moved, if they have any.
+    pub last_read: Option<OffsetDateTime>,
+}
+
+// get, set_notify_new_device, set_public_profile, set_custom_css,
+// set_list_display, set_history_months, set_stale_months, set_page_size,
+// public_profiles
+impl<'a> Prefs<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// Get a user's prefs, or the defaults if they've never set any.
+    #[tracing::instrument(skip(self))]
+    pub async fn get(&self, user_id: i64) -> sqlx::Result<UserPrefs> {
+        let prefs = query_as!(
+            UserPrefs,
+            r#"
+                SELECT notify_new_device, public_profile, custom_css, compact_list, show_prefix, hide_dates,
+                    history_months, stale_months, page_size
+                FROM user_prefs WHERE user_id = ?;
+            "#,
+            user_id,
+        )
+        .fetch_optional(self.read_pool())
+        .await?;
+        Ok(prefs.unwrap_or_default())
+    }
+
+    /// Turn new-device login alerts on or off.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_notify_new_device(&self, user_id: i64, enabled: bool) -> sqlx::Result<()> {
+        query!(
+            r#"
+                INSERT INTO user_prefs (user_id, notify_new_device)
+                VALUES (?1, ?2)
+                ON CONFLICT (user_id) DO UPDATE SET notify_new_device = excluded.notify_new_device;
+            "#,
+            user_id,
+            enabled,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Turn the public profile page on or off.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_public_profile(&self, user_id: i64, enabled: bool) -> sqlx::Result<()> {
+        query!(
+            r#"
+                INSERT INTO user_prefs (user_id, public_profile)
+                VALUES (?1, ?2)
+                ON CONFLICT (user_id) DO UPDATE SET public_profile = excluded.public_profile;
+            "#,
+            user_id,
+            enabled,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Save (or with None, clear) the user's custom CSS. Callers should run
+    /// it through `clean_custom_css` first.
+    #[tracing::instrument(skip(self, css))]
+    pub async fn set_custom_css(&self, user_id: i64, css: Option<&str>) -> sqlx::Result<()> {
+        query!(
+            r#"
+                INSERT INTO user_prefs (user_id, custom_css)
+                VALUES (?1, ?2)
+                ON CONFLICT (user_id) DO UPDATE SET custom_css = excluded.custom_css;
+            "#,
+            user_id,
+            css,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Save the dogears list display options.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_list_display(
+        &self,
+        user_id: i64,
+        compact_list: bool,
+        show_prefix: bool,
+        hide_dates: bool,
+    ) -> sqlx::Result<()> {
+        query!(
+            r#"
+                INSERT INTO user_prefs (user_id, compact_list, show_prefix, hide_dates)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (user_id) DO UPDATE SET
+                    compact_list = excluded.compact_list,
+                    show_prefix = excluded.show_prefix,
+                    hide_dates = excluded.hide_dates;
+            "#,
+            user_id,
+            compact_list,
+            show_prefix,
+            hide_dates,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Save (or with None, clear) the user's own history retention limit.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_history_months(&self, user_id: i64, months: Option<u32>) -> sqlx::Result<()> {
+        query!(
+            r#"
+                INSERT INTO user_prefs (user_id, history_months)
+                VALUES (?1, ?2)
+                ON CONFLICT (user_id) DO UPDATE SET history_months = excluded.history_months;
+            "#,
+            user_id,
+            months,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Save (or with None, go back to the default) the archive nudge
+    /// threshold. Some(0) turns the nudge off.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_stale_months(&self, user_id: i64, months: Option<u32>) -> sqlx::Result<()> {
+        query!(
+            r#"
+                INSERT INTO user_prefs (user_id, stale_months)
+                VALUES (?1, ?2)
+                ON CONFLICT (user_id) DO UPDATE SET stale_months = excluded.stale_months;
+            "#,
+            user_id,
+            months,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Save (or with None, go back to the default) how many items a page of
+    /// a list shows.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_page_size(&self, user_id: i64, size: Option<u32>) -> sqlx::Result<()> {
+        query!(
+            r#"
+                INSERT INTO user_prefs (user_id, page_size)
+                VALUES (?1, ?2)
+                ON CONFLICT (user_id) DO UPDATE SET page_size = excluded.page_size;
+            "#,
+            user_id,
+            size,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Everyone who has their public profile turned on, alphabetically.
+    #[tracing::instrument(skip(self))]
+    pub async fn public_profiles(&self, limit: u32) -> sqlx::Result<Vec<PublicProfileEntry>> {
+        query_as!(
+            PublicProfileEntry,
+            r#"
+                SELECT users.username, max(dogears.updated) AS 'last_read?: OffsetDateTime'
+                FROM user_prefs
+                    JOIN users ON users.id = user_prefs.user_id
+                    LEFT JOIN dogears ON dogears.user_id = users.id AND dogears.public = true
+                WHERE user_prefs.public_profile = true AND NOT users.disabled
+                GROUP BY users.id
+                ORDER BY users.username
+                LIMIT ?;
+            "#,
+            limit,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+}
@@ -1,8 +1,8 @@
-use super::{core::Db, users::User};
+use super::{core::Db, tx::Handle, users::User};
 use crate::util::{sqlite_offset, ListMeta, MixedError};
 use crate::util::{uuid_string, COOKIE_SESSION};
 use serde::Serialize;
-use sqlx::{query, query_as, query_scalar, SqlitePool};
+use sqlx::{query, query_as, query_scalar, Connection};
 use time::{serde::iso8601, Duration, OffsetDateTime};
 use tower_cookies::cookie::{Cookie, SameSite};
 use tracing::error;
@@ -15,7 +15,7 @@ pub const SESSION_LIFETIME_DAYS: i64 = 90;
 /// A query helper type for operating on [Session]s.
 #[derive(Debug)]
 pub struct Sessions<'a> {
-    db: &'a Db,
+    db: Handle<'a>,
 }
 
 /// A record struct for user login sessions.
@@ -39,6 +39,16 @@ pub struct Session {
     #[serde(with = "iso8601")]
     pub expires: OffsetDateTime,
     pub user_agent: Option<String>,
+    /// When this session last loaded its own dogears list. The "what's new"
+    /// banner on the front page covers everything that moved since then.
+    #[serde(with = "iso8601::option")]
+    pub last_index_visit: Option<OffsetDateTime>,
+    /// When the user logged in before this session started, and with what
+    /// browser, for the "last login" line in the page header. Copied from
+    /// the audit log at creation time, so it doesn't cost a query per page.
+    #[serde(with = "iso8601::option")]
+    pub previous_login: Option<OffsetDateTime>,
+    pub previous_login_user_agent: Option<String>,
 }
 
 impl Session {
@@ -56,16 +66,10 @@ impl Session {
     }
 }
 
-// create, authenticate, destroy, delete_expired
+// create, authenticate, destroy, delete_expired, touch_index_visit, set_index_visit, rotate_csrf
 impl<'a> Sessions<'a> {
-    pub fn new(db: &'a Db) -> Self {
-        Self { db }
-    }
-    fn read_pool(&self) -> &SqlitePool {
-        &self.db.read_pool
-    }
-    fn write_pool(&self) -> &SqlitePool {
-        &self.db.write_pool
+    pub fn new(db: impl Into<Handle<'a>>) -> Self {
+        Self { db: db.into() }
     }
 
     /// Delete all expired sessions from the database. This is a low-priority
@@ -83,12 +87,13 @@ impl<'a> Sessions<'a> {
                 DELETE FROM sessions WHERE expires < datetime('now');
             "#
         )
-        .execute(self.write_pool())
+        .execute(&mut *self.db.writer().await?)
         .await
         .map(|v| v.rows_affected())
     }
 
-    /// Make a new user login session
+    /// Make a new user login session. Call this before recording the login
+    /// in the audit log, so the session picks up the *previous* login.
     #[tracing::instrument(skip(self))]
     pub async fn create(&self, user_id: i64, user_agent: Option<&str>) -> sqlx::Result<Session> {
         let sessid = uuid_string();
@@ -97,9 +102,21 @@ impl<'a> Sessions<'a> {
         query_as!(
             Session,
             r#"
-                INSERT INTO sessions (id, user_id, csrf_token, expires, user_agent)
-                VALUES (?1, ?2, ?3, datetime(?4), ?5)
-                RETURNING external_id, id, user_id, csrf_token, expires, user_agent;
+                INSERT INTO sessions (
+                    id, user_id, csrf_token, expires, user_agent,
+                    previous_login, previous_login_user_agent
+                )
+                VALUES (
+                    ?1, ?2, ?3, datetime(?4), ?5,
+                    (SELECT created FROM audit_events
+                        WHERE user_id = ?2 AND kind = 'login'
+                        ORDER BY created DESC, id DESC LIMIT 1),
+                    (SELECT user_agent FROM audit_events
+                        WHERE user_id = ?2 AND kind = 'login'
+                        ORDER BY created DESC, id DESC LIMIT 1)
+                )
+                RETURNING external_id, id, user_id, csrf_token, expires, user_agent, last_index_visit,
+                    previous_login, previous_login_user_agent;
             "#,
             sessid,
             user_id,
@@ -107,7 +124,7 @@ impl<'a> Sessions<'a> {
             new_expires,
             user_agent,
         )
-        .fetch_one(self.write_pool())
+        .fetch_one(&mut *self.db.writer().await?)
         .await
     }
 
@@ -123,7 +140,7 @@ impl<'a> Sessions<'a> {
             "#,
             sessid,
         )
-        .execute(self.write_pool())
+        .execute(&mut *self.db.writer().await?)
         .await?;
         if res.rows_affected() == 1 {
             Ok(Some(()))
@@ -149,7 +166,7 @@ impl<'a> Sessions<'a> {
             external_id,
             user_id,
         )
-        .execute(self.write_pool())
+        .execute(&mut *self.db.writer().await?)
         .await?;
         if res.rows_affected() == 1 {
             Ok(Some(()))
@@ -159,7 +176,7 @@ impl<'a> Sessions<'a> {
     }
 
     /// Find the user and session for a given session ID (IF the session is
-    /// still valid). As a side-effect, updates the session's expiration date
+    /// still valid and the account isn't disabled). As a side-effect, updates the session's expiration date
     /// to maintain the rolling window.
     #[tracing::instrument(skip_all)]
     pub async fn authenticate(&self, sessid: &str) -> sqlx::Result<Option<(Session, User)>> {
@@ -174,15 +191,19 @@ impl<'a> Sessions<'a> {
                     sessions.user_id    AS user_id,
                     sessions.csrf_token AS session_csrf_token,
                     sessions.user_agent AS session_user_agent,
+                    sessions.last_index_visit AS session_last_index_visit,
+                    sessions.previous_login AS session_previous_login,
+                    sessions.previous_login_user_agent AS session_previous_login_user_agent,
                     users.username      AS user_username,
                     users.email         AS user_email,
                     users.created       AS user_created
                 FROM sessions JOIN users ON sessions.user_id = users.id
-                WHERE sessions.id = ?1 AND sessions.expires > datetime('now');
+                WHERE sessions.id = ?1 AND sessions.expires > datetime('now')
+                    AND NOT users.disabled;
             "#,
             sessid,
         )
-        .fetch_optional(self.read_pool())
+        .fetch_optional(&mut *self.db.reader().await?)
         .await?;
 
         // Early out if we got nuthin; this also skips the async update.
@@ -193,7 +214,7 @@ impl<'a> Sessions<'a> {
         // Then, do a fire-and-forget update; we don't need to see the result in
         // our read. This lets us skip waiting for the single
         // writer thread in the warm path of "doing literally anything logged in."
-        let write_pool = self.write_pool().clone();
+        let write_pool = self.db.write_pool.clone();
         let owned_sessid = sessid.to_string();
         self.db.task_tracker.spawn(async move {
             let q_res = query!(
@@ -231,10 +252,68 @@ impl<'a> Sessions<'a> {
             csrf_token: stuff.session_csrf_token,
             expires: new_expires,
             user_agent: stuff.session_user_agent,
+            last_index_visit: stuff.session_last_index_visit,
+            previous_login: stuff.session_previous_login,
+            previous_login_user_agent: stuff.session_previous_login_user_agent,
         };
         Ok(Some((session, user)))
     }
 
+    /// Record that this session is looking at its dogears list right now.
+    /// This is a fire-and-forget write, like the expiry bump in
+    /// `authenticate`, since the page doesn't need to wait for it.
+    pub fn touch_index_visit(&self, sessid: &str) {
+        let db = Db::clone(&self.db);
+        let owned_sessid = sessid.to_string();
+        self.db.task_tracker.spawn(async move {
+            let now = OffsetDateTime::now_utc();
+            if let Err(e) = db.sessions().set_index_visit(&owned_sessid, now).await {
+                error!(
+                    name: "Sessions::touch_index_visit",
+                    "DB write failed for async update of last index visit: {}",
+                    e,
+                );
+            }
+        });
+    }
+
+    /// Set when this session last looked at its dogears list, and wait for
+    /// it to land. Dismissing the "what's new" banner sets this to now.
+    #[tracing::instrument(skip_all)]
+    pub async fn set_index_visit(&self, sessid: &str, when: OffsetDateTime) -> sqlx::Result<()> {
+        query!(
+            r#"
+                UPDATE sessions SET last_index_visit = datetime(?1)
+                WHERE id = ?2;
+            "#,
+            when,
+            sessid,
+        )
+        .execute(&mut *self.db.writer().await?)
+        .await?;
+        Ok(())
+    }
+
+    /// Swap a session's CSRF token for a fresh one, and return it. Sensitive
+    /// forms call this after they succeed, so a token that got captured
+    /// somewhere along the way can't be replayed for the rest of a
+    /// months-long session. Returns Ok(None) if the session's gone.
+    #[tracing::instrument(skip_all)]
+    pub async fn rotate_csrf(&self, sessid: &str) -> sqlx::Result<Option<String>> {
+        let csrf_token = uuid_string();
+        query_scalar!(
+            r#"
+                UPDATE sessions SET csrf_token = ?1
+                WHERE id = ?2
+                RETURNING csrf_token;
+            "#,
+            csrf_token,
+            sessid,
+        )
+        .fetch_optional(&mut *self.db.writer().await?)
+        .await
+    }
+
     /// List all sessions for a user, so they can log out of a forgotten session remotely.
     #[tracing::instrument(skip_all)]
     pub async fn list(
@@ -245,7 +324,8 @@ impl<'a> Sessions<'a> {
     ) -> Result<(Vec<Session>, ListMeta), MixedError<sqlx::Error>> {
         // Do multiple reads in a transaction, so count and list see the
         // same causal slice.
-        let mut tx = self.read_pool().begin().await?;
+        let mut conn = self.db.reader().await?;
+        let mut tx = conn.begin().await?;
 
         // Get count first, as a separate query. For some reason sqlx tries
         // by default to return the value of COUNT() as an i32, which I
@@ -266,7 +346,8 @@ impl<'a> Sessions<'a> {
         let list = query_as!(
             Session,
             r#"
-                SELECT external_id, id, user_id, csrf_token, expires, user_agent
+                SELECT external_id, id, user_id, csrf_token, expires, user_agent, last_index_visit,
+                    previous_login, previous_login_user_agent
                 FROM sessions
                 WHERE user_id = ?1
                 ORDER BY expires DESC, id DESC
@@ -0,0 +1,86 @@
+use super::core::Db;
+use sqlx::{query, query_as, SqlitePool};
+
+/// A query helper type for operating on [SiteRule]s. Usually rented from
+/// a [Db].
+#[derive(Debug)]
+pub struct SiteRules<'a> {
+    db: &'a Db,
+}
+
+/// Record struct for what we know about one site's URLs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SiteRule {
+    /// Spelled like a prefix's host: no `www.` or `m.`, port if any.
+    pub host: String,
+    /// How many path segments (after the host) name a story.
+    pub segments: i64,
+    /// Where the rule came from or why it's there, for the humans.
+    pub note: Option<String>,
+}
+
+// list, for_host, put
+impl<'a> SiteRules<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// All of them, by host.
+    #[tracing::instrument(skip_all)]
+    pub async fn list(&self) -> sqlx::Result<Vec<SiteRule>> {
+        query_as!(
+            SiteRule,
+            r#"
+                SELECT host, segments, note
+                FROM site_rules
+                ORDER BY host;
+            "#,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn for_host(&self, host: &str) -> sqlx::Result<Option<SiteRule>> {
+        query_as!(
+            SiteRule,
+            r#"
+                SELECT host, segments, note
+                FROM site_rules
+                WHERE host = ?;
+            "#,
+            host,
+        )
+        .fetch_optional(self.read_pool())
+        .await
+    }
+
+    /// Add a rule, or replace the one for the same host. Returns whether
+    /// anything actually changed.
+    #[tracing::instrument(skip(self))]
+    pub async fn put(&self, host: &str, segments: i64, note: Option<&str>) -> sqlx::Result<bool> {
+        let res = query!(
+            r#"
+                INSERT INTO site_rules (host, segments, note)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT (host) DO UPDATE SET
+                    segments = excluded.segments,
+                    note = excluded.note,
+                    updated = current_timestamp
+                WHERE segments != excluded.segments OR note IS NOT excluded.note;
+            "#,
+            host,
+            segments,
+            note,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+}
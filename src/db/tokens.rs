@@ -1,14 +1,17 @@
-use super::{core::Db, users::User};
-use crate::util::{sha256sum, sqlite_offset, uuid_string, ListMeta, MixedError};
+use super::{tx::Handle, users::User};
+use crate::util::{
+    clean_optional_form_field, md5sum, sha256sum, sqlite_offset, uuid_string, ListMeta, MixedError,
+};
 use serde::Serialize;
-use sqlx::{query, query_as, query_scalar, SqlitePool};
+use sqlx::{query, query_as, query_scalar, Connection};
 use time::{serde::iso8601, OffsetDateTime};
 use tracing::error;
 
-/// A query helper type for operating on [Token]s. Usually rented from a [Db].
+/// A query helper type for operating on [Token]s. Usually rented from a
+/// [Db](super::Db), or a [DbTx](super::DbTx) to run in a transaction.
 #[derive(Debug)]
 pub struct Tokens<'a> {
-    db: &'a Db,
+    db: Handle<'a>,
 }
 
 /// Record struct for API authentication tokens associated with a [User].
@@ -27,6 +30,10 @@ pub struct Token {
     #[serde(with = "iso8601::option")]
     pub last_used: Option<OffsetDateTime>,
     pub comment: Option<String>,
+    bookmarklet: Option<String>, // private, use .bookmarklet().
+    /// When it stops working, if ever.
+    #[serde(with = "iso8601::option")]
+    pub expires: Option<OffsetDateTime>,
     // notably excluded: token_hash and also the temporary cleartext.
 }
 
@@ -34,6 +41,11 @@ impl Token {
     pub fn scope(&self) -> TokenScope {
         self.scope.as_str().into()
     }
+
+    /// What kind of bookmarklet this token was made for, if any.
+    pub fn bookmarklet(&self) -> Option<Bookmarklet> {
+        self.bookmarklet.as_deref().map(Bookmarklet::from)
+    }
 }
 
 impl PartialEq for Token {
@@ -44,6 +56,42 @@ impl PartialEq for Token {
             && self.scope == other.scope
             && self.created == other.created
             && self.comment == other.comment
+            && self.bookmarklet == other.bookmarklet
+            && self.expires == other.expires
+    }
+}
+
+/// The kinds of personal bookmarklet a token can back. Like scopes, these
+/// are stored as text.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Bookmarklet {
+    /// Text: `plain`. Marks your spot, no questions asked.
+    Plain,
+    /// Text: `prompt_note`. Asks for a note each time.
+    PromptNote,
+}
+
+impl Bookmarklet {
+    pub fn prompts_for_note(&self) -> bool {
+        *self == Self::PromptNote
+    }
+}
+
+impl From<&str> for Bookmarklet {
+    fn from(value: &str) -> Self {
+        match value {
+            "prompt_note" => Self::PromptNote,
+            _ => Self::Plain,
+        }
+    }
+}
+
+impl From<Bookmarklet> for &'static str {
+    fn from(value: Bookmarklet) -> Self {
+        match value {
+            Bookmarklet::Plain => "plain",
+            Bookmarklet::PromptNote => "prompt_note",
+        }
     }
 }
 
@@ -60,15 +108,35 @@ pub enum TokenScope {
     /// Can GET `/api/v1/list`.
     /// Can DELETE `/api/v1/dogear/:id`.
     ManageDogears,
+    /// Text: `quickmark`.
+    /// Can GET `/api/v1/quickmark`, and nothing else. This is the only
+    /// kind of token that's allowed in a query string, since URLs end up in
+    /// logs and browser history; if one leaks, all it can do is mark spots.
+    Quickmark,
+    /// Text: `kosync`.
+    /// The "password" for the KOReader sync endpoints under `/kosync`, and
+    /// good for nothing else. KOReader only ever sends the md5 of it, so
+    /// that's what gets hashed for storage (see `Tokens::create`).
+    Kosync,
     /// Can't do shit!!
     Invalid,
 }
 
+impl TokenScope {
+    /// Whether a token with this scope can be switched to the other
+    /// switchable scope by `Tokens::set_scope`.
+    pub fn is_switchable(&self) -> bool {
+        matches!(self, Self::WriteDogears | Self::ManageDogears)
+    }
+}
+
 impl From<&str> for TokenScope {
     fn from(value: &str) -> Self {
         match value {
             "write_dogears" => Self::WriteDogears,
             "manage_dogears" => Self::ManageDogears,
+            "quickmark" => Self::Quickmark,
+            "kosync" => Self::Kosync,
             _ => Self::Invalid,
         }
     }
@@ -79,55 +147,221 @@ impl From<TokenScope> for &'static str {
         match value {
             TokenScope::WriteDogears => "write_dogears",
             TokenScope::ManageDogears => "manage_dogears",
+            TokenScope::Quickmark => "quickmark",
+            TokenScope::Kosync => "kosync",
             TokenScope::Invalid => "INVALID",
         }
     }
 }
 
-// create, authenticate, destroy, list
-impl<'a> Tokens<'a> {
-    pub fn new(db: &'a Db) -> Self {
-        Self { db }
+/// What goes in the token_hash column for a new token's cleartext. Kosync
+/// tokens are the odd one out: KOReader only ever sends the md5 of its
+/// password, so we store the sha256 of *that*, and authenticate the md5.
+fn stored_hash(scope: TokenScope, cleartext: &str) -> String {
+    match scope {
+        TokenScope::Kosync => sha256sum(&md5sum(cleartext)),
+        _ => sha256sum(cleartext),
     }
-    fn read_pool(&self) -> &SqlitePool {
-        &self.db.read_pool
-    }
-    fn write_pool(&self) -> &SqlitePool {
-        &self.db.write_pool
+}
+
+// create, create_expiring, create_bookmarklet, rotate, set_scope, set_comment,
+// authenticate, delete_expired, destroy, list, bookmarklets
+impl<'a> Tokens<'a> {
+    pub fn new(db: impl Into<Handle<'a>>) -> Self {
+        Self { db: db.into() }
     }
 
     /// Create a token, and return it along with the *actual token cleartext.*
     /// This is the only time the cleartext is ever available.
+    /// (For kosync tokens, `authenticate` wants the md5 of the cleartext.)
     #[tracing::instrument(skip_all)]
     pub async fn create(
         &self,
         user_id: i64,
         scope: TokenScope,
         comment: Option<&str>,
+    ) -> sqlx::Result<(Token, String)> {
+        self.insert(user_id, scope, comment, None, None).await
+    }
+
+    /// Like `create`, but the token stops working at `expires`, if
+    /// provided.
+    #[tracing::instrument(skip_all)]
+    pub async fn create_expiring(
+        &self,
+        user_id: i64,
+        scope: TokenScope,
+        comment: Option<&str>,
+        expires: Option<OffsetDateTime>,
+    ) -> sqlx::Result<(Token, String)> {
+        self.insert(user_id, scope, comment, None, expires).await
+    }
+
+    /// Create a write token for a personal bookmarklet, remembering which
+    /// kind, so `rotate` can keep track and the bookmarklet can be rebuilt.
+    /// Same deal with the cleartext as `create`.
+    #[tracing::instrument(skip_all)]
+    pub async fn create_bookmarklet(
+        &self,
+        user_id: i64,
+        kind: Bookmarklet,
+        comment: Option<&str>,
+    ) -> sqlx::Result<(Token, String)> {
+        self.insert(user_id, TokenScope::WriteDogears, comment, Some(kind), None)
+            .await
+    }
+
+    async fn insert(
+        &self,
+        user_id: i64,
+        scope: TokenScope,
+        comment: Option<&str>,
+        bookmarklet: Option<Bookmarklet>,
+        expires: Option<OffsetDateTime>,
     ) -> sqlx::Result<(Token, String)> {
         let token_cleartext = format!("eardoggerv1.{}", uuid_string());
-        let token_hash = sha256sum(&token_cleartext);
+        let token_hash = stored_hash(scope, &token_cleartext);
         let scope_str: &str = scope.into();
+        let bookmarklet_str: Option<&str> = bookmarklet.map(Into::into);
         let token = query_as!(
             Token,
             r#"
-                INSERT INTO tokens (user_id, token_hash, scope, comment)
-                VALUES (?1, ?2, ?3, ?4)
-                RETURNING id, user_id, scope, created, last_used, comment;
+                INSERT INTO tokens (user_id, token_hash, scope, comment, bookmarklet, expires)
+                VALUES (?1, ?2, ?3, ?4, ?5, datetime(?6))
+                RETURNING id, user_id, scope, created, last_used, comment, bookmarklet, expires;
             "#,
             user_id,
             token_hash,
             scope_str,
-            comment
+            comment,
+            bookmarklet_str,
+            expires,
         )
-        .fetch_one(self.write_pool())
+        .fetch_one(&mut *self.db.writer().await?)
         .await?;
 
         Ok((token, token_cleartext))
     }
 
+    /// Replace a token with a fresh one that has the same scope, comment,
+    /// bookmarklet kind, and expiry (rotating doesn't buy more time),
+    /// deleting the old one in the same transaction, so there's never a
+    /// moment where both or neither work. Returns the new token and its
+    /// cleartext (only available this once), or Ok(None) if the old token
+    /// doesn't exist or belongs to someone else.
+    #[tracing::instrument(skip_all)]
+    pub async fn rotate(&self, id: i64, user_id: i64) -> sqlx::Result<Option<(Token, String)>> {
+        let mut conn = self.db.writer().await?;
+        let mut tx = conn.begin().await?;
+
+        let Some(old) = query!(
+            r#"
+                SELECT scope, comment, bookmarklet, expires
+                FROM tokens
+                WHERE id = ?1 AND user_id = ?2;
+            "#,
+            id,
+            user_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let token_cleartext = format!("eardoggerv1.{}", uuid_string());
+        let token_hash = stored_hash(TokenScope::from(old.scope.as_str()), &token_cleartext);
+        let token = query_as!(
+            Token,
+            r#"
+                INSERT INTO tokens (user_id, token_hash, scope, comment, bookmarklet, expires)
+                VALUES (?1, ?2, ?3, ?4, ?5, datetime(?6))
+                RETURNING id, user_id, scope, created, last_used, comment, bookmarklet, expires;
+            "#,
+            user_id,
+            token_hash,
+            old.scope,
+            old.comment,
+            old.bookmarklet,
+            old.expires,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        query!(
+            r#"
+                DELETE FROM tokens
+                WHERE id = ?;
+            "#,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Some((token, token_cleartext)))
+    }
+
+    /// Switch a token between write and manage scope, keeping its
+    /// cleartext. Other scopes can't be switched, since their tokens get
+    /// used (and sometimes hashed) differently. Returns Ok(None) if the
+    /// token doesn't exist, belongs to someone else, or isn't switchable.
+    #[tracing::instrument(skip_all)]
+    pub async fn set_scope(
+        &self,
+        id: i64,
+        user_id: i64,
+        scope: TokenScope,
+    ) -> sqlx::Result<Option<Token>> {
+        if !scope.is_switchable() {
+            return Ok(None);
+        }
+        let scope_str: &str = scope.into();
+        query_as!(
+            Token,
+            r#"
+                UPDATE tokens SET scope = ?3
+                WHERE id = ?1 AND user_id = ?2
+                    AND scope IN ('write_dogears', 'manage_dogears')
+                RETURNING id, user_id, scope, created, last_used, comment, bookmarklet, expires;
+            "#,
+            id,
+            user_id,
+            scope_str,
+        )
+        .fetch_optional(&mut *self.db.writer().await?)
+        .await
+    }
+
+    /// Rename a token. None (or blank) clears the comment; callers should
+    /// check it with `validate(Field::TokenComment, ..)` first. Returns Ok(None) if the token
+    /// doesn't exist or belongs to someone else.
+    #[tracing::instrument(skip_all)]
+    pub async fn set_comment(
+        &self,
+        id: i64,
+        user_id: i64,
+        comment: Option<&str>,
+    ) -> sqlx::Result<Option<Token>> {
+        let comment = clean_optional_form_field(comment);
+        query_as!(
+            Token,
+            r#"
+                UPDATE tokens SET comment = ?3
+                WHERE id = ?1 AND user_id = ?2
+                RETURNING id, user_id, scope, created, last_used, comment, bookmarklet, expires;
+            "#,
+            id,
+            user_id,
+            comment,
+        )
+        .fetch_optional(&mut *self.db.writer().await?)
+        .await
+    }
+
     /// Use the provided token cleartext to look up a token and its associated user.
-    /// Returns Ok(None) if the token doesn't match anything.
+    /// Returns Ok(None) if the token doesn't match anything, has expired, or
+    /// belongs to a disabled account.
     #[tracing::instrument(skip_all)]
     pub async fn authenticate(&self, token_cleartext: &str) -> sqlx::Result<Option<(Token, User)>> {
         let token_hash = sha256sum(token_cleartext);
@@ -145,15 +379,20 @@ impl<'a> Tokens<'a> {
                     tokens.scope     AS token_scope,
                     tokens.created   AS token_created,
                     tokens.comment   AS token_comment,
+                    tokens.bookmarklet AS token_bookmarklet,
+                    tokens.expires   AS token_expires,
                     users.username   AS user_username,
                     users.email      AS user_email,
                     users.created    AS user_created
                 FROM tokens JOIN users ON tokens.user_id = users.id
-                WHERE tokens.token_hash = ? LIMIT 1;
+                WHERE tokens.token_hash = ?
+                    AND (tokens.expires IS NULL OR tokens.expires > datetime('now'))
+                    AND NOT users.disabled
+                LIMIT 1;
             "#,
             th
         )
-        .fetch_optional(self.read_pool())
+        .fetch_optional(&mut *self.db.reader().await?)
         .await?;
 
         // Early out if we got nuthin; this also skips the async update.
@@ -166,7 +405,7 @@ impl<'a> Tokens<'a> {
         // the single writer thread.
         //
         // This goes after the read so we can steal the token_hash string and avoid a clone.
-        let owned_write_pool = self.write_pool().clone();
+        let owned_write_pool = self.db.write_pool.clone();
         self.db.task_tracker.spawn(async move {
             let q_res = query!(
                 r#"
@@ -197,6 +436,8 @@ impl<'a> Tokens<'a> {
             created: stuff.token_created,
             last_used: Some(current_timestamp),
             comment: stuff.token_comment,
+            bookmarklet: stuff.token_bookmarklet,
+            expires: stuff.token_expires,
         };
         let user = User {
             id: stuff.user_id,
@@ -207,6 +448,21 @@ impl<'a> Tokens<'a> {
         Ok(Some((token, user)))
     }
 
+    /// Delete tokens that have expired, since they can't do anything
+    /// anymore. Returns how many. Like expired sessions, this is for the
+    /// daily cleanup job.
+    #[tracing::instrument(skip_all)]
+    pub async fn delete_expired(&self) -> sqlx::Result<u64> {
+        query!(
+            r#"
+                DELETE FROM tokens WHERE expires < datetime('now');
+            "#
+        )
+        .execute(&mut *self.db.writer().await?)
+        .await
+        .map(|v| v.rows_affected())
+    }
+
     /// Delete a token. To double-check the permissions, get the token's
     /// user ID from a trusted source and provide it when calling this.
     /// Returns Err on database problems, Ok(None) if db's ok but there's
@@ -221,7 +477,7 @@ impl<'a> Tokens<'a> {
             id,
             user_id,
         )
-        .execute(self.write_pool())
+        .execute(&mut *self.db.writer().await?)
         .await?;
         if res.rows_affected() == 1 {
             Ok(Some(()))
@@ -240,7 +496,8 @@ impl<'a> Tokens<'a> {
     ) -> Result<(Vec<Token>, ListMeta), MixedError<sqlx::Error>> {
         // Do multiple reads in a transaction, so count and list see the
         // same causal slice.
-        let mut tx = self.read_pool().begin().await?;
+        let mut conn = self.db.reader().await?;
+        let mut tx = conn.begin().await?;
 
         // Get count first, as a separate query. For some reason sqlx tries
         // by default to return the value of COUNT() as an i32, which I
@@ -261,7 +518,7 @@ impl<'a> Tokens<'a> {
         let list = query_as!(
             Token,
             r#"
-                SELECT id, user_id, scope, created, last_used, comment
+                SELECT id, user_id, scope, created, last_used, comment, bookmarklet, expires
                 FROM tokens
                 WHERE user_id = ?1
                 ORDER BY last_used DESC NULLS LAST, id DESC
@@ -279,4 +536,22 @@ impl<'a> Tokens<'a> {
 
         Ok((list, meta))
     }
+
+    /// All of a user's bookmarklet tokens, newest first. Unpaginated, since
+    /// nobody has more than a handful of browsers.
+    #[tracing::instrument(skip_all)]
+    pub async fn bookmarklets(&self, user_id: i64) -> sqlx::Result<Vec<Token>> {
+        query_as!(
+            Token,
+            r#"
+                SELECT id, user_id, scope, created, last_used, comment, bookmarklet, expires
+                FROM tokens
+                WHERE user_id = ? AND bookmarklet IS NOT NULL
+                ORDER BY id DESC;
+            "#,
+            user_id,
+        )
+        .fetch_all(&mut *self.db.reader().await?)
+        .await
+    }
 }
@@ -0,0 +1,254 @@
+use super::core::Db;
+use crate::util::{
+    check_totp, new_recovery_codes, new_totp_secret, normalize_recovery_code, sha256sum, totp_step,
+};
+use serde::Serialize;
+use sqlx::{query, query_as, query_scalar, SqliteConnection, SqlitePool};
+use time::{serde::iso8601, OffsetDateTime};
+
+/// A query helper type for operating on users' two-factor settings: their
+/// [TotpSecret] and recovery codes. Usually rented from a [Db].
+#[derive(Debug)]
+pub struct Totp<'a> {
+    db: &'a Db,
+}
+
+/// Record struct for a user's TOTP secret. Until `confirmed` is set, it's
+/// just a setup in progress, and logins don't ask for codes.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TotpSecret {
+    pub user_id: i64,
+    pub secret: String,
+    #[serde(with = "iso8601::option")]
+    pub confirmed: Option<OffsetDateTime>,
+    /// The time step of the last code that worked.
+    pub last_step: Option<i64>,
+    #[serde(with = "iso8601")]
+    pub created: OffsetDateTime,
+}
+
+/// How someone got past the second step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TotpMethod {
+    /// A code from their authenticator app.
+    Code,
+    /// One of their recovery codes, which is now used up.
+    RecoveryCode,
+}
+
+// begin_setup, get, enabled, confirm, verify, regenerate_recovery_codes,
+// recovery_codes_left, disable
+impl<'a> Totp<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// Start (or restart) setting up two-factor auth, with a fresh secret
+    /// for their app. Returns the secret, or Ok(None) if they've already
+    /// got it turned on; turn it off first to switch apps.
+    #[tracing::instrument(skip(self))]
+    pub async fn begin_setup(&self, user_id: i64) -> sqlx::Result<Option<String>> {
+        let secret = new_totp_secret();
+        let res = query!(
+            r#"
+                INSERT INTO totp_secrets (user_id, secret) VALUES (?1, ?2)
+                ON CONFLICT (user_id) DO UPDATE SET
+                    secret = excluded.secret,
+                    last_step = NULL,
+                    created = current_timestamp
+                WHERE totp_secrets.confirmed IS NULL;
+            "#,
+            user_id,
+            secret,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok((res.rows_affected() > 0).then_some(secret))
+    }
+
+    /// A user's secret, set up or not.
+    #[tracing::instrument(skip(self))]
+    pub async fn get(&self, user_id: i64) -> sqlx::Result<Option<TotpSecret>> {
+        query_as!(
+            TotpSecret,
+            r#"
+                SELECT user_id, secret, confirmed, last_step, created
+                FROM totp_secrets WHERE user_id = ?;
+            "#,
+            user_id,
+        )
+        .fetch_optional(self.read_pool())
+        .await
+    }
+
+    /// Whether logging in as this user takes a code.
+    #[tracing::instrument(skip(self))]
+    pub async fn enabled(&self, user_id: i64) -> sqlx::Result<bool> {
+        Ok(self
+            .get(user_id)
+            .await?
+            .is_some_and(|s| s.confirmed.is_some()))
+    }
+
+    /// Finish setting up: if the code's good for their pending secret,
+    /// turn two-factor on and hand back a batch of recovery codes (the only
+    /// time their cleartext is available). Ok(None) if the code's wrong or
+    /// there's nothing pending.
+    #[tracing::instrument(skip_all)]
+    pub async fn confirm(&self, user_id: i64, code: &str) -> sqlx::Result<Option<Vec<String>>> {
+        let Some(pending) = self.get(user_id).await?.filter(|s| s.confirmed.is_none()) else {
+            return Ok(None);
+        };
+        let now = totp_step(OffsetDateTime::now_utc());
+        let Some(step) = check_totp(&pending.secret, code, now, pending.last_step) else {
+            return Ok(None);
+        };
+        let mut tx = self.write_pool().begin().await?;
+        let res = query!(
+            r#"
+                UPDATE totp_secrets
+                SET confirmed = current_timestamp, last_step = ?3
+                WHERE user_id = ?1 AND secret = ?2 AND confirmed IS NULL;
+            "#,
+            user_id,
+            pending.secret,
+            step,
+        )
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            // Someone restarted the setup in the meantime.
+            return Ok(None);
+        }
+        let codes = replace_recovery_codes(&mut tx, user_id).await?;
+        tx.commit().await?;
+        Ok(Some(codes))
+    }
+
+    /// Check the second step of a login: either a current code from their
+    /// app (which can't be used again), or one of their recovery codes
+    /// (which gets used up). Ok(None) if it's neither, or if they don't
+    /// have two-factor on at all.
+    #[tracing::instrument(skip_all)]
+    pub async fn verify(&self, user_id: i64, code: &str) -> sqlx::Result<Option<TotpMethod>> {
+        let Some(secret) = self.get(user_id).await?.filter(|s| s.confirmed.is_some()) else {
+            return Ok(None);
+        };
+        let now = totp_step(OffsetDateTime::now_utc());
+        if let Some(step) = check_totp(&secret.secret, code, now, secret.last_step) {
+            // Only the first one through gets to use it.
+            let res = query!(
+                r#"
+                    UPDATE totp_secrets SET last_step = ?2
+                    WHERE user_id = ?1 AND (last_step IS NULL OR last_step < ?2);
+                "#,
+                user_id,
+                step,
+            )
+            .execute(self.write_pool())
+            .await?;
+            return Ok((res.rows_affected() > 0).then_some(TotpMethod::Code));
+        }
+        let code_hash = sha256sum(&normalize_recovery_code(code));
+        let res = query!(
+            r#"
+                DELETE FROM totp_recovery_codes WHERE user_id = ? AND code_hash = ?;
+            "#,
+            user_id,
+            code_hash,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok((res.rows_affected() > 0).then_some(TotpMethod::RecoveryCode))
+    }
+
+    /// Toss a user's recovery codes and make a new batch. Returns the
+    /// cleartext, or Ok(None) if they don't have two-factor on.
+    #[tracing::instrument(skip(self))]
+    pub async fn regenerate_recovery_codes(
+        &self,
+        user_id: i64,
+    ) -> sqlx::Result<Option<Vec<String>>> {
+        if !self.enabled(user_id).await? {
+            return Ok(None);
+        }
+        let mut tx = self.write_pool().begin().await?;
+        let codes = replace_recovery_codes(&mut tx, user_id).await?;
+        tx.commit().await?;
+        Ok(Some(codes))
+    }
+
+    /// How many unused recovery codes a user has.
+    #[tracing::instrument(skip(self))]
+    pub async fn recovery_codes_left(&self, user_id: i64) -> sqlx::Result<u32> {
+        query_scalar!(
+            r#"
+                SELECT count(id) AS 'count: u32' FROM totp_recovery_codes WHERE user_id = ?;
+            "#,
+            user_id,
+        )
+        .fetch_one(self.read_pool())
+        .await
+    }
+
+    /// Turn two-factor off (or give up on setting it up), recovery codes
+    /// and all. Returns Ok(None) if there was nothing to turn off.
+    #[tracing::instrument(skip(self))]
+    pub async fn disable(&self, user_id: i64) -> sqlx::Result<Option<()>> {
+        let mut tx = self.write_pool().begin().await?;
+        query!(
+            r#"
+                DELETE FROM totp_recovery_codes WHERE user_id = ?;
+            "#,
+            user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        let res = query!(
+            r#"
+                DELETE FROM totp_secrets WHERE user_id = ?;
+            "#,
+            user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok((res.rows_affected() > 0).then_some(()))
+    }
+}
+
+/// Swap out a user's recovery codes for a new batch, inside someone else's
+/// transaction. Returns the cleartext.
+async fn replace_recovery_codes(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+) -> sqlx::Result<Vec<String>> {
+    query!(
+        r#"
+            DELETE FROM totp_recovery_codes WHERE user_id = ?;
+        "#,
+        user_id,
+    )
+    .execute(&mut *conn)
+    .await?;
+    let codes = new_recovery_codes();
+    for code in &codes {
+        let code_hash = sha256sum(&normalize_recovery_code(code));
+        query!(
+            r#"
+                INSERT INTO totp_recovery_codes (user_id, code_hash) VALUES (?, ?);
+            "#,
+            user_id,
+            code_hash,
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(codes)
+}
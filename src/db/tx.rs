@@ -0,0 +1,198 @@
+//! Running several query helpers' writes in one transaction. Normally a
+//! helper sends reads to the read pool and writes to the write pool, one
+//! statement (or one helper-sized transaction) at a time. That's fine until
+//! some operation needs, say, a new user AND their first session: if the
+//! second write fails, the first one's already in, and now there's an
+//! account nobody's logged into.
+//!
+//! So the helpers that take part in bigger operations (`Users`, `Tokens`,
+//! `Sessions`, and `Dogears`) don't hold a `&Db` directly; they hold a
+//! [Handle], which is either the plain Db or a Db plus an open [DbTx].
+//! Their methods ask the handle for a `reader()` or `writer()` connection
+//! and never need to know which kind they got. A helper's own internal
+//! transactions become savepoints inside the big one.
+//!
+//! Some care required, since the write pool has exactly one connection:
+//!
+//! - While a DbTx is open, it's holding that connection (once it's done its
+//!   first query, anyway -- it starts lazily, so slow prep work like bcrypt
+//!   doesn't hold up everyone else's writes). Do ALL of the operation's
+//!   queries through the DbTx's helpers, or they'll wait on it forever.
+//! - A helper method shouldn't keep a connection around while it calls
+//!   another helper method, for the same reason. Take one per statement.
+//! - Dogear change notices wait for the commit, so nobody goes looking for
+//!   a change that might still get rolled back.
+//! - The dogears table's unique constraint is `ON CONFLICT ROLLBACK`, so a
+//!   `Dogears::create` that hits an existing prefix takes the WHOLE
+//!   transaction down with it. Batches that expect some duplicates should
+//!   use `create_many`, which skips them instead.
+
+use super::core::Db;
+use super::dogears::Dogears;
+use super::sessions::Sessions;
+use super::tokens::Tokens;
+use super::users::Users;
+use sqlx::{pool::PoolConnection, Sqlite, SqliteConnection, Transaction};
+use std::fmt::Debug;
+use std::ops::{Deref, DerefMut};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// One write transaction that several query helpers can share. Get one from
+/// [Db::transaction], rent helpers from it just like from a Db, and call
+/// [DbTx::commit] at the end. Dropping it without committing rolls back
+/// everything its helpers did.
+pub struct DbTx<'a> {
+    db: &'a Db,
+    // None until the first query.
+    tx: Mutex<Option<Transaction<'static, Sqlite>>>,
+    // Users whose dogears changed, to notify about after the commit.
+    changed: std::sync::Mutex<Vec<i64>>,
+}
+
+// sqlx's Transaction doesn't do Debug.
+impl Debug for DbTx<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DbTx").finish_non_exhaustive()
+    }
+}
+
+impl<'a> DbTx<'a> {
+    pub(super) fn new(db: &'a Db) -> Self {
+        Self {
+            db,
+            tx: Mutex::new(None),
+            changed: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn users(&self) -> Users<'_> {
+        Users::new(self)
+    }
+
+    pub fn tokens(&self) -> Tokens<'_> {
+        Tokens::new(self)
+    }
+
+    pub fn sessions(&self) -> Sessions<'_> {
+        Sessions::new(self)
+    }
+
+    pub fn dogears(&self) -> Dogears<'_> {
+        Dogears::new(self)
+    }
+
+    /// Make it all stick, then send any dogear change notices.
+    pub async fn commit(self) -> sqlx::Result<()> {
+        if let Some(tx) = self.tx.into_inner() {
+            tx.commit().await?;
+        }
+        let changed = self.changed.into_inner().unwrap_or_else(|e| e.into_inner());
+        for user_id in changed {
+            self.db.changes().notify(user_id);
+        }
+        Ok(())
+    }
+
+    /// The transaction's connection, starting the transaction if this is
+    /// the first query.
+    async fn conn(&self) -> sqlx::Result<Conn<'_>> {
+        let mut guard = self.tx.lock().await;
+        if guard.is_none() {
+            *guard = Some(self.db.write_pool.begin().await?);
+        }
+        Ok(Conn::Tx(guard))
+    }
+}
+
+/// Where a query helper sends its queries: straight to the Db's pools, or
+/// into an open transaction. Derefs to the Db for everything else (task
+/// tracker and so on). Cheap to copy.
+#[derive(Clone, Copy, Debug)]
+pub struct Handle<'a> {
+    db: &'a Db,
+    tx: Option<&'a DbTx<'a>>,
+}
+
+impl<'a> From<&'a Db> for Handle<'a> {
+    fn from(db: &'a Db) -> Self {
+        Self { db, tx: None }
+    }
+}
+
+impl<'a> From<&'a DbTx<'a>> for Handle<'a> {
+    fn from(tx: &'a DbTx<'a>) -> Self {
+        Self {
+            db: tx.db,
+            tx: Some(tx),
+        }
+    }
+}
+
+impl Deref for Handle<'_> {
+    type Target = Db;
+    fn deref(&self) -> &Db {
+        self.db
+    }
+}
+
+impl<'a> Handle<'a> {
+    /// A connection for reads. In a transaction, that's the transaction's,
+    /// so reads can see its writes.
+    pub async fn reader(&self) -> sqlx::Result<Conn<'a>> {
+        match self.tx {
+            Some(tx) => tx.conn().await,
+            None => Ok(Conn::Pooled(self.db.read_pool.acquire().await?)),
+        }
+    }
+
+    /// A connection for writes.
+    pub async fn writer(&self) -> sqlx::Result<Conn<'a>> {
+        match self.tx {
+            Some(tx) => tx.conn().await,
+            None => Ok(Conn::Pooled(self.db.write_pool.acquire().await?)),
+        }
+    }
+
+    /// Tell anyone waiting on a user's dogears that they moved. In a
+    /// transaction, that waits for the commit.
+    pub fn notify(&self, user_id: i64) {
+        match self.tx {
+            Some(tx) => tx
+                .changed
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(user_id),
+            None => self.db.changes().notify(user_id),
+        }
+    }
+}
+
+/// A connection from a [Handle]. Use it as `&mut *conn` wherever sqlx
+/// wants an executor. Holding one blocks other queries on the same handle
+/// (and, for a writer, the whole write pool), so don't keep it around.
+pub enum Conn<'a> {
+    Pooled(PoolConnection<Sqlite>),
+    // Always Some; DbTx::conn fills it in before handing it out.
+    Tx(MutexGuard<'a, Option<Transaction<'static, Sqlite>>>),
+}
+
+const STARTED: &str = "DbTx::conn starts the transaction first";
+
+impl Deref for Conn<'_> {
+    type Target = SqliteConnection;
+    fn deref(&self) -> &SqliteConnection {
+        match self {
+            Self::Pooled(conn) => conn,
+            Self::Tx(tx) => tx.as_ref().expect(STARTED),
+        }
+    }
+}
+
+impl DerefMut for Conn<'_> {
+    fn deref_mut(&mut self) -> &mut SqliteConnection {
+        match self {
+            Self::Pooled(conn) => conn,
+            Self::Tx(tx) => tx.as_mut().expect(STARTED),
+        }
+    }
+}
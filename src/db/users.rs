@@ -1,18 +1,19 @@
-use super::core::Db;
-use crate::util::{clean_optional_form_field, MixedError, UserError};
+use super::tx::Handle;
+use crate::util::{
+    clean_email, clean_username, normalize_username, sqlite_offset, username_key, ListMeta,
+    MixedError, UserError, UsernamePolicy,
+};
 
-use lazy_static::lazy_static;
-use regex::Regex;
 use serde::Serialize;
-use sqlx::{error::ErrorKind, query, query_as, SqlitePool};
+use sqlx::{error::ErrorKind, query, query_as, query_scalar, Connection};
 use time::OffsetDateTime;
 use tracing::error;
 
 /// A query helper type for operating on [User]s. Usually you rent this from
-/// a [Db].
+/// a [Db](super::Db), or a [DbTx](super::DbTx) to run in a transaction.
 #[derive(Debug)]
 pub struct Users<'a> {
-    db: &'a Db,
+    db: Handle<'a>,
 }
 
 /// Record struct for user accounts.
@@ -24,6 +25,31 @@ pub struct User {
     pub created: OffsetDateTime,
 }
 
+/// The account switches that only admins can flip. These stay off of
+/// [User], since hardly anything besides login and the admin pages cares.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize)]
+pub struct UserFlags {
+    pub is_admin: bool,
+    pub disabled: bool,
+    pub must_reset_password: bool,
+}
+
+/// One row of the admin users list: the account, its flags, and how much
+/// stuff it's got.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct UserSummary {
+    pub id: i64,
+    pub username: String,
+    pub email: Option<String>,
+    pub created: OffsetDateTime,
+    pub is_admin: bool,
+    pub disabled: bool,
+    pub must_reset_password: bool,
+    pub dogears: i64,
+    /// Unexpired login sessions.
+    pub sessions: i64,
+}
+
 // Private struct for type-checked queries
 struct UserWithPasswordHash {
     id: i64,
@@ -31,6 +57,7 @@ struct UserWithPasswordHash {
     email: Option<String>,
     created: OffsetDateTime,
     password_hash: String,
+    disabled: bool,
 }
 
 impl From<UserWithPasswordHash> for User {
@@ -46,22 +73,6 @@ impl From<UserWithPasswordHash> for User {
 
 // Some helpers!
 
-/// Trim whitespace and validate allowed username characters.
-/// Ascii letters/numbers/joiners is too restrictive, but now's not the
-/// time to loosen it. Maybe later.
-fn clean_username(username: &str) -> Result<&str, UserError> {
-    lazy_static! {
-        static ref USERNAME_REGEX: Regex = Regex::new(r#"\A[a-zA-Z0-9_-]{1,80}\z"#).unwrap();
-    }
-    let username = username.trim();
-    if USERNAME_REGEX.is_match(username) {
-        Ok(username)
-    } else {
-        Err(UserError::BadUsername {
-            name: username.to_string(),
-        })
-    }
-}
 fn valid_password(password: &str) -> Result<&str, UserError> {
     if password.is_empty() {
         Err(UserError::BlankPassword)
@@ -70,57 +81,82 @@ fn valid_password(password: &str) -> Result<&str, UserError> {
     }
 }
 
-// create, authenticate, set_password, change_password, set_email, destroy
+// create, authenticate, set_password, change_password, set_email, destroy,
+// flags, set_admin, set_disabled, force_password_reset, list_for_admin
 impl<'a> Users<'a> {
-    pub fn new(db: &'a Db) -> Self {
-        Self { db }
-    }
-    fn read_pool(&self) -> &SqlitePool {
-        &self.db.read_pool
+    pub fn new(db: impl Into<Handle<'a>>) -> Self {
+        Self { db: db.into() }
     }
-    fn write_pool(&self) -> &SqlitePool {
-        &self.db.write_pool
+
+    /// Create a new user account, under the default (ASCII) username policy.
+    pub async fn create(
+        &self,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> Result<User, MixedError<sqlx::Error>> {
+        self.create_with_policy(UsernamePolicy::Ascii, username, password, email)
+            .await
     }
 
-    /// Create a new user account.
+    /// Create a new user account. Under the Unicode policy, a name that's
+    /// too easy to mistake for an existing one is turned away too.
     #[tracing::instrument(skip_all)]
-    pub async fn create(
+    pub async fn create_with_policy(
         &self,
+        policy: UsernamePolicy,
         username: &str,
         password: &str,
         email: Option<&str>,
     ) -> Result<User, MixedError<sqlx::Error>> {
-        let username = clean_username(username)?;
-        let email = clean_optional_form_field(email);
+        let username = clean_username(policy, username)?;
+        let username = username.as_ref();
+        let key = username_key(username);
+        let check_lookalikes = policy == UsernamePolicy::Unicode;
+        let email = clean_email(email)?;
         let password = valid_password(password)?;
         let password_hash = bcrypt::hash(password, 12).map_err(|_| {
             UserError::Impossible("bcrypt hash of statically-known cost had illegal cost")
         })?;
 
-        query_as!(
+        // The lookalike check rides along in the insert, so nobody can
+        // sneak in between a separate check and write. An exact duplicate
+        // skips it and hits the unique constraint instead, for the usual
+        // error.
+        let created = query_as!(
             User,
             r#"
-                INSERT INTO users (username, password_hash, email)
-                VALUES (?1, ?2, ?3)
+                INSERT INTO users (username, password_hash, email, username_key)
+                SELECT ?1, ?2, ?3, ?4
+                WHERE NOT ?5 OR NOT EXISTS (
+                    SELECT 1 FROM users WHERE username_key = ?4 AND username != ?1
+                )
                 RETURNING id, username, email, created;
             "#,
             username,
             password_hash,
             email,
+            key,
+            check_lookalikes,
         )
-        .fetch_one(self.write_pool())
-        .await
-        .map_err(|e| match e {
+        .fetch_optional(&mut *self.db.writer().await?)
+        .await;
+        match created {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) => Err(UserError::UsernameTooSimilar {
+                name: username.to_string(),
+            }
+            .into()),
             // Need to catch unique constraint violation and return friendly error; any
             // other sqlx errors are 500s in this case.
-            sqlx::Error::Database(dbe) if dbe.kind() == ErrorKind::UniqueViolation => {
-                UserError::UserExists {
+            Err(sqlx::Error::Database(dbe)) if dbe.kind() == ErrorKind::UniqueViolation => {
+                Err(UserError::UserExists {
                     name: username.to_string(),
                 }
-                .into()
+                .into())
             }
-            _ => e.into(),
-        })
+            Err(e) => Err(e.into()),
+        }
     }
 
     /// Fetch a user and their password hash, by name. Deliberately not public API.
@@ -129,23 +165,24 @@ impl<'a> Users<'a> {
         &self,
         username: &str,
     ) -> sqlx::Result<Option<UserWithPasswordHash>> {
-        let username = username.trim();
+        let username = normalize_username(username);
+        let username = username.as_ref();
 
         query_as!(
             UserWithPasswordHash,
             r#"
-                SELECT id, username, email, created, password_hash
+                SELECT id, username, email, created, password_hash, disabled
                 FROM users WHERE username = ?;
             "#,
             username
         )
-        .fetch_optional(self.read_pool()) // NICE!!!!
+        .fetch_optional(&mut *self.db.reader().await?) // NICE!!!!
         .await
     }
 
-    /// Test helper: Just fetch a user. App logic should always find users
-    /// via the `authenticate` methods on Users / Sessions / Tokens.
-    #[cfg(test)]
+    /// Just fetch a user, by name. Logins should always find users via the
+    /// `authenticate` methods on Users / Sessions / Tokens; this is for
+    /// looking up someone who isn't the one asking.
     pub async fn by_name(&self, username: &str) -> sqlx::Result<Option<User>> {
         Ok(self
             .by_name_with_password_hash(username)
@@ -153,15 +190,30 @@ impl<'a> Users<'a> {
             .map(|u| u.into()))
     }
 
+    /// Like `by_name`, but disabled accounts don't count. This is for
+    /// anything that shows or shares someone's stuff with other people, or
+    /// that logs someone in.
+    pub async fn active_by_name(&self, username: &str) -> sqlx::Result<Option<User>> {
+        Ok(self
+            .by_name_with_password_hash(username)
+            .await?
+            .filter(|u| !u.disabled)
+            .map(|u| u.into()))
+    }
+
     /// Authenticate a user by username and password. Only returns Some if the
-    /// user exists and the password matches.
+    /// user exists, the password matches, and an admin hasn't disabled them.
     #[tracing::instrument(skip_all)]
     pub async fn authenticate(
         &self,
         username: &str,
         password: &str,
     ) -> anyhow::Result<Option<User>> {
-        if let Some(user) = self.by_name_with_password_hash(username).await? {
+        if let Some(user) = self
+            .by_name_with_password_hash(username)
+            .await?
+            .filter(|u| !u.disabled)
+        {
             // Reason this function has to return an anyhow is bc there's
             // several unlikely reasons bcrypt::verify can fail and they're
             // all worthy of 500 errors.
@@ -172,7 +224,8 @@ impl<'a> Users<'a> {
         Ok(None)
     }
 
-    /// Hard-set a user's password. IMPORTANT: assumes you've already validated the inputs!
+    /// Hard-set a user's password, which also satisfies a forced reset.
+    /// IMPORTANT: assumes you've already validated the inputs!
     #[tracing::instrument(skip_all)]
     pub async fn set_password(
         &self,
@@ -185,13 +238,13 @@ impl<'a> Users<'a> {
 
         let res = query!(
             r#"
-                UPDATE users SET password_hash = ?1
+                UPDATE users SET password_hash = ?1, must_reset_password = false
                 WHERE username = ?2;
             "#,
             password_hash,
             username,
         )
-        .execute(self.write_pool())
+        .execute(&mut *self.db.writer().await?)
         .await?;
         if res.rows_affected() != 1 {
             error!(%username, "unable to find logged-in user");
@@ -210,7 +263,7 @@ impl<'a> Users<'a> {
         username: &str,
         email: Option<&str>,
     ) -> Result<(), MixedError<sqlx::Error>> {
-        let email = clean_optional_form_field(email);
+        let email = clean_email(email)?;
 
         let res = query!(
             r#"
@@ -220,7 +273,7 @@ impl<'a> Users<'a> {
             email,
             username,
         )
-        .execute(self.write_pool())
+        .execute(&mut *self.db.writer().await?)
         .await?;
         if res.rows_affected() != 1 {
             error!(%username, "unable to find logged-in user");
@@ -239,7 +292,7 @@ impl<'a> Users<'a> {
             "#,
             id,
         )
-        .execute(self.write_pool())
+        .execute(&mut *self.db.writer().await?)
         .await?;
         if res.rows_affected() == 1 {
             Ok(Some(()))
@@ -247,4 +300,172 @@ impl<'a> Users<'a> {
             Ok(None)
         }
     }
+
+    /// The admin-only switches on an account. Ok(None) if there's no such user.
+    #[tracing::instrument(skip_all)]
+    pub async fn flags(&self, id: i64) -> sqlx::Result<Option<UserFlags>> {
+        query_as!(
+            UserFlags,
+            r#"
+                SELECT is_admin, disabled, must_reset_password
+                FROM users WHERE id = ?;
+            "#,
+            id,
+        )
+        .fetch_optional(&mut *self.db.reader().await?)
+        .await
+    }
+
+    /// Make someone an admin, or stop. This takes a username, because it's
+    /// for the `db admin` command. Returns Ok(None) on not-found.
+    #[tracing::instrument(skip_all)]
+    pub async fn set_admin(&self, username: &str, is_admin: bool) -> sqlx::Result<Option<()>> {
+        let username = normalize_username(username);
+        let username = username.as_ref();
+        let res = query!(
+            r#"
+                UPDATE users SET is_admin = ?1
+                WHERE username = ?2;
+            "#,
+            is_admin,
+            username,
+        )
+        .execute(&mut *self.db.writer().await?)
+        .await?;
+        if res.rows_affected() == 1 {
+            Ok(Some(()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Disable or re-enable an account. Disabling also deletes its login
+    /// sessions and tokens, so it's locked out right away instead of
+    /// whenever those run out (and session and token auth check the flag
+    /// too, in case any new ones sneak in). Everything else that acts for the account or
+    /// shows its stuff to other people (its public profile, inbound hooks,
+    /// grants, outgoing webhooks, feed polling, backups) checks the flag
+    /// itself, so re-enabling picks all that back up where it was. Returns
+    /// Ok(None) on not-found.
+    #[tracing::instrument(skip_all)]
+    pub async fn set_disabled(&self, id: i64, disabled: bool) -> sqlx::Result<Option<()>> {
+        let mut conn = self.db.writer().await?;
+        let mut tx = conn.begin().await?;
+        let res = query!(
+            r#"
+                UPDATE users SET disabled = ?1
+                WHERE id = ?2;
+            "#,
+            disabled,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() != 1 {
+            return Ok(None);
+        }
+        if disabled {
+            query!(
+                r#"
+                    DELETE FROM sessions WHERE user_id = ?;
+                "#,
+                id,
+            )
+            .execute(&mut *tx)
+            .await?;
+            query!(
+                r#"
+                    DELETE FROM tokens WHERE user_id = ?;
+                "#,
+                id,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(Some(()))
+    }
+
+    /// Make someone choose a new password. This logs out all their login
+    /// sessions, and until they change it, any new session only reaches the
+    /// change password form (and logout), and their old password won't pass
+    /// Basic auth or a token exchange. Their tokens keep working, since those
+    /// don't know the password anyway. Returns Ok(None) on not-found.
+    #[tracing::instrument(skip_all)]
+    pub async fn force_password_reset(&self, id: i64) -> sqlx::Result<Option<()>> {
+        let mut conn = self.db.writer().await?;
+        let mut tx = conn.begin().await?;
+        let res = query!(
+            r#"
+                UPDATE users SET must_reset_password = true
+                WHERE id = ?;
+            "#,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() != 1 {
+            return Ok(None);
+        }
+        query!(
+            r#"
+                DELETE FROM sessions WHERE user_id = ?;
+            "#,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(()))
+    }
+
+    /// A page of every account on the site, oldest first, for admins.
+    #[tracing::instrument(skip_all)]
+    pub async fn list_for_admin(
+        &self,
+        page: u32,
+        size: u32,
+    ) -> Result<(Vec<UserSummary>, ListMeta), MixedError<sqlx::Error>> {
+        // Same deal as the other lists: count and list in one read
+        // transaction, so they agree.
+        let mut conn = self.db.reader().await?;
+        let mut tx = conn.begin().await?;
+
+        let count = query_scalar!(
+            r#"
+                SELECT COUNT(id) AS 'count: u32' FROM users;
+            "#
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let meta = ListMeta { count, page, size };
+
+        let offset = sqlite_offset(page, size)?;
+        let list = query_as!(
+            UserSummary,
+            r#"
+                SELECT
+                    users.id, users.username, users.email, users.created,
+                    users.is_admin, users.disabled, users.must_reset_password,
+                    (SELECT COUNT(*) FROM dogears WHERE dogears.user_id = users.id)
+                        AS 'dogears!: i64',
+                    (SELECT COUNT(*) FROM sessions
+                        WHERE sessions.user_id = users.id AND sessions.expires > datetime('now'))
+                        AS 'sessions!: i64'
+                FROM users
+                ORDER BY users.id
+                LIMIT ?1
+                OFFSET ?2;
+            "#,
+            size,
+            offset,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok((list, meta))
+    }
 }
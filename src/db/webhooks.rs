@@ -0,0 +1,264 @@
+use super::core::Db;
+use super::dogears::Dogear;
+use crate::util::uuid_string;
+use eardogger_rs::api_types::{DogearEvent, DOGEAR_EVENT_VERSION};
+use serde::Serialize;
+use sqlx::{query, query_as, SqlitePool};
+use time::{serde::iso8601, OffsetDateTime};
+
+/// How many outgoing webhooks one user can have.
+pub const MAX_WEBHOOKS: i64 = 5;
+/// How many tries a delivery gets before we give up on it.
+pub const WEBHOOK_MAX_ATTEMPTS: i64 = 8;
+/// How long an undelivered delivery can hang around, in days, before the
+/// daily cleanup tosses it. Mostly for sites that turned webhooks off
+/// while some were still registered, since nothing's sending those.
+pub const WEBHOOK_DELIVERY_DAYS: i64 = 7;
+
+/// A query helper type for operating on [Webhook]s and their pending
+/// deliveries. Usually rented from a [Db].
+#[derive(Debug)]
+pub struct Webhooks<'a> {
+    db: &'a Db,
+}
+
+/// Record struct for an outgoing webhook: a URL that gets a signed JSON
+/// POST whenever one of its owner's dogears changes. The secret's for
+/// checking signatures, so the owner can see it whenever they like.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Webhook {
+    pub id: i64,
+    pub user_id: i64,
+    pub url: String,
+    pub secret: String,
+    #[serde(with = "iso8601")]
+    pub created: OffsetDateTime,
+    #[serde(with = "iso8601::option")]
+    pub last_delivered: Option<OffsetDateTime>,
+    /// What went wrong last time, cleared by the next delivery that works.
+    pub last_error: Option<String>,
+}
+
+/// A delivery that's due to go out, with where it's going and the dogear
+/// snapshots the triggers took when the change happened.
+#[derive(Debug, Clone)]
+pub struct DueDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event: String,
+    pub attempts: i64,
+    pub url: String,
+    pub secret: String,
+    pub dogear_id: i64,
+    pub created: OffsetDateTime,
+    /// JSON, in the API's Dogear shape.
+    pub before_snapshot: Option<String>,
+    pub after_snapshot: Option<String>,
+}
+
+impl DueDelivery {
+    /// The change this delivery is about, as a versioned event.
+    pub fn change(&self) -> serde_json::Result<DogearEvent> {
+        let snapshot = |json: &Option<String>| -> serde_json::Result<Option<Dogear>> {
+            json.as_deref().map(serde_json::from_str).transpose()
+        };
+        Ok(DogearEvent {
+            version: DOGEAR_EVENT_VERSION,
+            event: serde_json::from_value(serde_json::Value::String(self.event.clone()))?,
+            dogear_id: self.dogear_id,
+            occurred: self.created,
+            before: snapshot(&self.before_snapshot)?,
+            after: snapshot(&self.after_snapshot)?,
+        })
+    }
+}
+
+// create, list, destroy, due, record_delivered, record_failure, delete_stale_deliveries
+impl<'a> Webhooks<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db }
+    }
+    fn read_pool(&self) -> &SqlitePool {
+        &self.db.read_pool
+    }
+    fn write_pool(&self) -> &SqlitePool {
+        &self.db.write_pool
+    }
+
+    /// Register a new webhook for a user, with a fresh secret. Returns
+    /// Ok(None) if they're already at MAX_WEBHOOKS. The caller's on the
+    /// hook for validating the URL.
+    #[tracing::instrument(skip(self))]
+    pub async fn create(&self, user_id: i64, url: &str) -> sqlx::Result<Option<Webhook>> {
+        let secret = format!("eardoggerwebhook1.{}", uuid_string());
+        query_as!(
+            Webhook,
+            r#"
+                INSERT INTO webhooks (user_id, url, secret)
+                SELECT ?1, ?2, ?3
+                WHERE (SELECT count(id) FROM webhooks WHERE user_id = ?1) < ?4
+                RETURNING id, user_id, url, secret, created, last_delivered, last_error;
+            "#,
+            user_id,
+            url,
+            secret,
+            MAX_WEBHOOKS,
+        )
+        .fetch_optional(self.write_pool())
+        .await
+    }
+
+    /// A user's webhooks, oldest first.
+    #[tracing::instrument(skip(self))]
+    pub async fn list(&self, user_id: i64) -> sqlx::Result<Vec<Webhook>> {
+        query_as!(
+            Webhook,
+            r#"
+                SELECT id, user_id, url, secret, created, last_delivered, last_error
+                FROM webhooks WHERE user_id = ?
+                ORDER BY id ASC;
+            "#,
+            user_id,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+
+    /// Delete one of a user's webhooks, along with anything it had waiting
+    /// to go out. Returns Ok(None) if it doesn't exist or belongs to
+    /// someone else.
+    #[tracing::instrument(skip(self))]
+    pub async fn destroy(&self, id: i64, user_id: i64) -> sqlx::Result<Option<()>> {
+        let res = query!(
+            r#"
+                DELETE FROM webhooks WHERE id = ? AND user_id = ?;
+            "#,
+            id,
+            user_id,
+        )
+        .execute(self.write_pool())
+        .await?;
+        Ok((res.rows_affected() > 0).then_some(()))
+    }
+
+    /// Deliveries whose next try has come around, in the order the changes
+    /// happened. A failed delivery holds up everything after it for the
+    /// same webhook until it goes out or gets dropped, so the retry backoff
+    /// applies to the whole webhook and its events never arrive out of order.
+    #[tracing::instrument(skip(self))]
+    pub async fn due(&self, limit: u32) -> sqlx::Result<Vec<DueDelivery>> {
+        query_as!(
+            DueDelivery,
+            r#"
+                SELECT
+                    webhook_deliveries.id, webhook_deliveries.webhook_id,
+                    webhook_deliveries.event, webhook_deliveries.attempts,
+                    webhooks.url, webhooks.secret,
+                    webhook_deliveries.dogear_id, webhook_deliveries.created,
+                    webhook_deliveries.before_snapshot, webhook_deliveries.after_snapshot
+                FROM webhook_deliveries
+                    JOIN webhooks ON webhooks.id = webhook_deliveries.webhook_id
+                    JOIN users ON users.id = webhooks.user_id
+                WHERE webhook_deliveries.next_attempt <= current_timestamp
+                    AND NOT users.disabled
+                    AND NOT EXISTS (
+                        SELECT 1 FROM webhook_deliveries AS earlier
+                        WHERE earlier.webhook_id = webhook_deliveries.webhook_id
+                            AND earlier.id < webhook_deliveries.id
+                            AND earlier.next_attempt > current_timestamp
+                    )
+                ORDER BY webhook_deliveries.id ASC
+                LIMIT ?;
+            "#,
+            limit,
+        )
+        .fetch_all(self.read_pool())
+        .await
+    }
+
+    /// A delivery went out: forget it, and note that its webhook's working.
+    #[tracing::instrument(skip(self))]
+    pub async fn record_delivered(&self, delivery_id: i64, webhook_id: i64) -> sqlx::Result<()> {
+        let mut tx = self.write_pool().begin().await?;
+        query!(
+            r#"
+                DELETE FROM webhook_deliveries WHERE id = ?;
+            "#,
+            delivery_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        query!(
+            r#"
+                UPDATE webhooks
+                SET last_delivered = current_timestamp, last_error = NULL
+                WHERE id = ?;
+            "#,
+            webhook_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+
+    /// A delivery didn't go out: try it again after `next_attempt` (a
+    /// sqlite datetime modifier, like "+5 minutes"), unless it's used up
+    /// its WEBHOOK_MAX_ATTEMPTS, in which case drop it. Either way, keep
+    /// the error for the owner to see. (While it waits, so does the rest of
+    /// its webhook's queue; see `due`.)
+    #[tracing::instrument(skip(self))]
+    pub async fn record_failure(
+        &self,
+        delivery_id: i64,
+        webhook_id: i64,
+        error: &str,
+        next_attempt: &str,
+    ) -> sqlx::Result<()> {
+        let mut tx = self.write_pool().begin().await?;
+        query!(
+            r#"
+                UPDATE webhook_deliveries
+                SET attempts = attempts + 1, next_attempt = datetime('now', ?2)
+                WHERE id = ?1;
+            "#,
+            delivery_id,
+            next_attempt,
+        )
+        .execute(&mut *tx)
+        .await?;
+        query!(
+            r#"
+                DELETE FROM webhook_deliveries WHERE id = ?1 AND attempts >= ?2;
+            "#,
+            delivery_id,
+            WEBHOOK_MAX_ATTEMPTS,
+        )
+        .execute(&mut *tx)
+        .await?;
+        query!(
+            r#"
+                UPDATE webhooks SET last_error = ?2 WHERE id = ?1;
+            "#,
+            webhook_id,
+            error,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+
+    /// Toss deliveries older than WEBHOOK_DELIVERY_DAYS. Returns how many.
+    #[tracing::instrument(skip(self))]
+    pub async fn delete_stale_deliveries(&self) -> sqlx::Result<u64> {
+        let cutoff = format!("-{} days", WEBHOOK_DELIVERY_DAYS);
+        query!(
+            r#"
+                DELETE FROM webhook_deliveries WHERE created < datetime('now', ?);
+            "#,
+            cutoff,
+        )
+        .execute(self.write_pool())
+        .await
+        .map(|v| v.rows_affected())
+    }
+}
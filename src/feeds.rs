@@ -0,0 +1,522 @@
+//! Watching dogears' RSS and Atom feeds for new chapters. Anyone can give a
+//! dogear a feed (if the site's `feed_polling` is on), and a background job
+//! checks the ones that are due every few minutes. A new chapter is the one
+//! right after your spot, but only once something new has shown up in the
+//! feed since the last check; the first check just takes notes. If the
+//! dogear's set to auto-advance and you were caught up, it moves there;
+//! otherwise the dogears list flags it until you mark something.
+//!
+//! There's no XML parser in here, just enough pattern matching to find the
+//! link in each `<item>` or `<entry>`. Feeds that are too weird for that
+//! don't turn up any links, which is the same as nothing new.
+
+use crate::config::DogConfig;
+use crate::db::{Db, DueFeed};
+use crate::outbound::{self, validate_url, Schemes};
+use crate::util::{matchable_from_url, normalize_current_url};
+use anyhow::bail;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::time::Duration;
+use tracing::info;
+use url::Url;
+
+/// How many due feeds to check per pass. The rest wait for the next one.
+const FEEDS_PER_PASS: u32 = 50;
+/// How long between checks of a feed that's working.
+const CHECK_MINUTES: i64 = 60;
+/// The longest a failing feed waits between tries.
+const MAX_RETRY_MINUTES: i64 = 7 * 24 * 60;
+/// How long a feed gets to answer.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(20);
+/// How many redirects a feed fetch follows. Feeds move around more than
+/// webhooks do.
+const MAX_REDIRECTS: usize = 5;
+/// The biggest feed we'll read. Plenty for a serial's last few dozen
+/// posts, even with full text in them.
+const MAX_FEED_BYTES: usize = 2 * 1024 * 1024;
+
+lazy_static! {
+    static ref ITEM: Regex = Regex::new(r"(?is)<item\b.*?</item>|<entry\b.*?</entry>").unwrap();
+    static ref RSS_LINK: Regex = Regex::new(r"(?is)<link>(.*?)</link>").unwrap();
+    static ref ATOM_LINK: Regex = Regex::new(r"(?is)<link\b([^>]*)/?>").unwrap();
+    static ref HREF: Regex = Regex::new(r#"(?i)\bhref\s*=\s*["']([^"']*)["']"#).unwrap();
+    static ref REL: Regex = Regex::new(r#"(?i)\brel\s*=\s*["']([^"']*)["']"#).unwrap();
+}
+
+/// Check a feed URL someone wants watched. Same rules as any other outbound
+/// URL, except plain http is fine even in production, since plenty of feeds
+/// are still on it and there's nothing private going out.
+pub async fn validate_feed_url(url: &str, production: bool) -> Result<Url, String> {
+    validate_url(url, Schemes::Any, production).await
+}
+
+/// The link from each item in a feed, in the feed's order (which is
+/// newest first, for every feed worth reading). Relative links get
+/// resolved against the feed's own URL.
+pub fn feed_links(xml: &str, base: &Url) -> Vec<String> {
+    ITEM.find_iter(xml)
+        .filter_map(|item| item_link(item.as_str()))
+        .filter_map(|link| base.join(&link).ok())
+        .map(|url| url.to_string())
+        .collect()
+}
+
+fn item_link(item: &str) -> Option<String> {
+    // RSS: <link>https://...</link>
+    if let Some(text) = RSS_LINK.captures(item).and_then(|c| c.get(1)) {
+        let text = text.as_str().trim();
+        let text = text
+            .strip_prefix("<![CDATA[")
+            .and_then(|t| t.strip_suffix("]]>"))
+            .unwrap_or(text);
+        if !text.is_empty() {
+            return Some(html_escape::decode_html_entities(text.trim()).into_owned());
+        }
+    }
+    // Atom: <link rel="alternate" href="https://..."/>, where no rel means
+    // alternate.
+    ATOM_LINK.captures_iter(item).find_map(|c| {
+        let attrs = c.get(1)?.as_str();
+        let rel = REL.captures(attrs).and_then(|r| r.get(1));
+        if rel.is_some_and(|r| !r.as_str().eq_ignore_ascii_case("alternate")) {
+            return None;
+        }
+        let href = HREF.captures(attrs)?.get(1)?.as_str();
+        Some(html_escape::decode_html_entities(href).into_owned())
+    })
+}
+
+/// Whether a URL belongs to a dogear, the same way updates decide.
+fn matches_prefix(url: &str, prefix: &str) -> bool {
+    matchable_from_url(url).is_ok_and(|m| {
+        m.to_ascii_lowercase()
+            .starts_with(&prefix.to_ascii_lowercase())
+    })
+}
+
+/// What a check turned up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NewChapter<'a> {
+    /// The next chapter after your spot.
+    pub url: &'a str,
+    /// Whether your spot was the newest chapter as of the last check, so
+    /// moving the dogear won't skip anything.
+    pub caught_up: bool,
+}
+
+/// Given a dogear's links from a feed (newest first), its spot, and the
+/// newest link from the last check, the new chapter, if there is one. It
+/// only counts if the newest link changed since last time, and it has to
+/// be able to find where you are (or where it was last time) to know
+/// which one's next.
+pub fn new_chapter<'a>(
+    links: &'a [String],
+    current: &str,
+    latest_seen: Option<&str>,
+) -> Option<NewChapter<'a>> {
+    let latest_seen = latest_seen?;
+    let newest = links.first()?;
+    if newest == latest_seen {
+        return None;
+    }
+    let next_after = |spot: &str| {
+        let i = links.iter().position(|l| l == spot)?;
+        i.checked_sub(1).map(|i| links[i].as_str())
+    };
+    let url = next_after(current).or_else(|| next_after(latest_seen))?;
+    Some(NewChapter {
+        url,
+        caught_up: current == latest_seen,
+    })
+}
+
+/// How long to wait after a feed fails for the nth time in a row: double
+/// the usual wait each time, up to a week.
+pub fn retry_minutes(failures: i64) -> i64 {
+    (CHECK_MINUTES << failures.clamp(1, 10)).min(MAX_RETRY_MINUTES)
+}
+
+/// What one pass of the poller did.
+#[derive(Debug, Default, PartialEq)]
+pub struct FeedReport {
+    pub checked: u32,
+    /// Dogears that moved to a new chapter.
+    pub advanced: u32,
+    /// New chapters flagged on the list instead.
+    pub flagged: u32,
+    pub failed: u32,
+}
+
+/// Checks feeds that are due. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct FeedPoller {
+    db: Db,
+    enabled: bool,
+    production: bool,
+    http: reqwest::Client,
+}
+
+impl FeedPoller {
+    pub fn new(db: Db, config: &DogConfig) -> reqwest::Result<Self> {
+        let http = outbound::client(FETCH_TIMEOUT, MAX_REDIRECTS, config.production)?;
+        Ok(Self {
+            db,
+            enabled: config.feed_polling,
+            production: config.production,
+            http,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Check every feed that's due (up to FEEDS_PER_PASS of them), and
+    /// record how each one went. Does nothing if the config says not to.
+    #[tracing::instrument(skip_all)]
+    pub async fn run_due(&self) -> sqlx::Result<FeedReport> {
+        let mut report = FeedReport::default();
+        if !self.enabled {
+            return Ok(report);
+        }
+        let feeds = self.db.feeds();
+        for due in feeds.due(FEEDS_PER_PASS).await? {
+            report.checked += 1;
+            let xml = match self.fetch(&due.feed_url).await {
+                Ok(xml) => xml,
+                Err(e) => {
+                    report.failed += 1;
+                    let failures = due.failures + 1;
+                    info!(
+                        dogear_id = due.dogear_id,
+                        failures, "feed check failed: {}", e
+                    );
+                    let retry = format!("+{} minutes", retry_minutes(failures));
+                    feeds
+                        .record_failure(due.dogear_id, &e.to_string(), &retry)
+                        .await?;
+                    continue;
+                }
+            };
+            // Feeds often carry more than the one serial, so only links
+            // under the dogear's prefix count.
+            let links: Vec<String> = match Url::parse(&due.feed_url) {
+                Ok(base) => feed_links(&xml, &base)
+                    .into_iter()
+                    .filter(|l| matches_prefix(l, &due.prefix))
+                    .collect(),
+                Err(_) => Vec::new(),
+            };
+            let mut latest_seen = links.first().map(String::as_str);
+            let mut flagged = None;
+            let current = normalize_current_url(&due.current);
+            if let Some(found) = new_chapter(&links, current, due.latest_seen.as_deref()) {
+                if self.advance(&due, found).await? {
+                    report.advanced += 1;
+                    // Pretend that's all we saw, so if more than one came out
+                    // at once, the next check moves it along again.
+                    latest_seen = Some(found.url);
+                } else {
+                    report.flagged += 1;
+                    flagged = Some(found.url);
+                }
+            }
+            let next_check = format!("+{} minutes", CHECK_MINUTES);
+            feeds
+                .record_check(due.dogear_id, latest_seen, flagged, &next_check)
+                .await?;
+        }
+        Ok(report)
+    }
+
+    /// Move the dogear to a new chapter, if it's set to auto-advance and
+    /// you were caught up. Returns whether it moved; paused dogears don't.
+    async fn advance(&self, due: &DueFeed, found: NewChapter<'_>) -> sqlx::Result<bool> {
+        if !(due.auto_advance && found.caught_up) {
+            return Ok(false);
+        }
+        let moved = self
+            .db
+            .dogears()
+            .update_one(due.dogear_id, due.user_id, found.url, None)
+            .await?;
+        Ok(moved.is_some())
+    }
+
+    /// Fetch a feed's text, within reason.
+    async fn fetch(&self, url: &str) -> anyhow::Result<String> {
+        // The rules might have tightened since they saved it.
+        let url = validate_feed_url(url, self.production)
+            .await
+            .map_err(anyhow::Error::msg)?;
+        let mut resp = self.http.get(url).send().await?;
+        if !resp.status().is_success() {
+            bail!("the feed answered {}", resp.status());
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_FEED_BYTES {
+                bail!("the feed is over {} bytes", MAX_FEED_BYTES);
+            }
+        }
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    const RSS: &str = r#"<?xml version="1.0"?>
+<rss version="2.0"><channel>
+  <title>Example Comic</title>
+  <link>https://example.com/comic</link>
+  <item><title>Page 26</title><link>https://example.com/comic/26</link></item>
+  <item><title>Page 25</title><link><![CDATA[https://example.com/comic/25]]></link></item>
+  <item><title>Blog post</title><link>https://example.com/blog/hiatus?a=1&amp;b=2</link></item>
+  <item><title>Page 24</title><link>/comic/24</link></item>
+</channel></rss>"#;
+
+    const ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <link rel="self" href="https://example.com/serial/feed.xml"/>
+  <entry>
+    <link rel="replies" href="https://example.com/serial/5#comments"/>
+    <link rel="alternate" href="https://example.com/serial/5"/>
+  </entry>
+  <entry><link href='https://example.com/serial/4' /></entry>
+</feed>"#;
+
+    fn strings(links: &[&str]) -> Vec<String> {
+        links.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn feed_urls() {
+        assert!(validate_feed_url("https://example.com/feed.xml", false)
+            .await
+            .is_ok());
+        assert!(validate_feed_url("http://example.com/rss", false)
+            .await
+            .is_ok());
+        assert!(validate_feed_url("ftp://example.com/rss", false)
+            .await
+            .is_err());
+        assert!(validate_feed_url("example.com/rss", false).await.is_err());
+        // Plain http's fine in production, but private hosts aren't.
+        assert!(validate_feed_url("http://93.184.215.14/rss", true)
+            .await
+            .is_ok());
+        assert!(validate_feed_url("http://192.168.0.10/rss", true)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn parsing_feeds() {
+        let base = Url::parse("https://example.com/feed.xml").unwrap();
+        assert_eq!(
+            feed_links(RSS, &base),
+            strings(&[
+                "https://example.com/comic/26",
+                "https://example.com/comic/25",
+                "https://example.com/blog/hiatus?a=1&b=2",
+                "https://example.com/comic/24",
+            ])
+        );
+        assert_eq!(
+            feed_links(ATOM, &base),
+            strings(&[
+                "https://example.com/serial/5",
+                "https://example.com/serial/4"
+            ])
+        );
+        assert!(feed_links("<html>not a feed</html>", &base).is_empty());
+        assert!(matches_prefix(
+            "https://www.example.com/comic/26",
+            "example.com/comic"
+        ));
+        assert!(!matches_prefix(
+            "https://example.com/blog/1",
+            "example.com/comic"
+        ));
+    }
+
+    #[test]
+    fn finding_new_chapters() {
+        let links = strings(&["c/4", "c/3", "c/2", "c/1"]);
+        // First check just takes notes.
+        assert_eq!(new_chapter(&links, "c/2", None), None);
+        // Nothing new since last time.
+        assert_eq!(new_chapter(&links, "c/2", Some("c/4")), None);
+        assert_eq!(new_chapter(&[], "c/2", Some("c/4")), None);
+        // Caught up, then something new.
+        assert_eq!(
+            new_chapter(&links, "c/3", Some("c/3")),
+            Some(NewChapter {
+                url: "c/4",
+                caught_up: true
+            })
+        );
+        // Behind, but the feed knows where you are.
+        assert_eq!(
+            new_chapter(&links, "c/1", Some("c/3")),
+            Some(NewChapter {
+                url: "c/2",
+                caught_up: false
+            })
+        );
+        // Way behind; the first one new since last time will do.
+        assert_eq!(
+            new_chapter(&links, "c/0", Some("c/2")),
+            Some(NewChapter {
+                url: "c/3",
+                caught_up: false
+            })
+        );
+        // Lost track of everything.
+        assert_eq!(new_chapter(&links, "c/0", Some("x/9")), None);
+    }
+
+    #[test]
+    fn backoff() {
+        assert_eq!(retry_minutes(1), 120);
+        assert_eq!(retry_minutes(2), 240);
+        assert_eq!(retry_minutes(50), MAX_RETRY_MINUTES);
+    }
+
+    #[tokio::test]
+    async fn polling() {
+        let db = Db::new_test_db().await;
+        let user = db.test_user("whoever").await.unwrap();
+        let comic_id = db
+            .dogears()
+            .list(user.id, 1, 50)
+            .await
+            .unwrap()
+            .0
+            .into_iter()
+            .find(|d| d.prefix == "example.com/comic")
+            .unwrap()
+            .id;
+
+        // A feed whose contents the test can swap out.
+        let body = Arc::new(Mutex::new(String::new()));
+        let served = body.clone();
+        let app = Router::new()
+            .route(
+                "/feed.xml",
+                get(move || async move { served.lock().unwrap().clone() }),
+            )
+            .route("/gone.xml", get(|| async { http::StatusCode::NOT_FOUND }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let feed_url = format!("http://{}/feed.xml", addr);
+        let item = |n: u32| format!("<item><link>https://example.com/comic/{}</link></item>", n);
+        let set_feed = |items: &[u32]| {
+            let items: String = items.iter().map(|n| item(*n)).collect();
+            *body.lock().unwrap() = format!("<rss><channel>{}</channel></rss>", items);
+        };
+        // Make everything due again, without waiting an hour.
+        let rewind = || async {
+            sqlx::query("UPDATE dogear_feeds SET next_check = '2000-01-01 00:00:00';")
+                .execute(&db.write_pool)
+                .await
+                .unwrap();
+        };
+
+        // Off unless the config says otherwise.
+        let poller = FeedPoller::new(db.clone(), &DogConfig::test_config().unwrap()).unwrap();
+        db.feeds()
+            .set(comic_id, user.id, &feed_url, true)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(poller.run_due().await.unwrap(), FeedReport::default());
+
+        let mut config = DogConfig::test_config().unwrap();
+        config.feed_polling = true;
+        let poller = FeedPoller::new(db.clone(), &config).unwrap();
+
+        // First check just takes notes.
+        set_feed(&[24, 23]);
+        let report = poller.run_due().await.unwrap();
+        assert_eq!(report.checked, 1);
+        assert_eq!(report.advanced + report.flagged, 0);
+        // Not due again yet.
+        assert_eq!(poller.run_due().await.unwrap().checked, 0);
+
+        // Caught up and set to auto-advance: moves along.
+        set_feed(&[25, 24, 23]);
+        rewind().await;
+        let report = poller.run_due().await.unwrap();
+        assert_eq!(report.advanced, 1);
+        let comic = db
+            .dogears()
+            .by_id(comic_id, user.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(comic.current, "https://example.com/comic/25");
+
+        // Two at once: one per check, so nothing gets skipped.
+        set_feed(&[27, 26, 25]);
+        rewind().await;
+        poller.run_due().await.unwrap();
+        rewind().await;
+        assert_eq!(poller.run_due().await.unwrap().advanced, 1);
+        let comic = db
+            .dogears()
+            .by_id(comic_id, user.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(comic.current, "https://example.com/comic/27");
+
+        // Without auto-advance, it gets flagged instead.
+        db.feeds()
+            .set(comic_id, user.id, &feed_url, false)
+            .await
+            .unwrap();
+        set_feed(&[28, 27]);
+        rewind().await;
+        assert_eq!(poller.run_due().await.unwrap().flagged, 1);
+        let feed = db
+            .feeds()
+            .for_user(user.id)
+            .await
+            .unwrap()
+            .remove(&comic_id)
+            .unwrap();
+        assert_eq!(
+            feed.new_chapter.as_deref(),
+            Some("https://example.com/comic/28")
+        );
+        assert!(feed.new_chapter_found.is_some());
+
+        // Broken feeds back off and keep track of what went wrong.
+        let gone = format!("http://{}/gone.xml", addr);
+        db.feeds()
+            .set(comic_id, user.id, &gone, false)
+            .await
+            .unwrap();
+        let report = poller.run_due().await.unwrap();
+        assert_eq!(report.failed, 1);
+        let feed = db
+            .feeds()
+            .for_user(user.id)
+            .await
+            .unwrap()
+            .remove(&comic_id)
+            .unwrap();
+        assert_eq!(feed.failures, 1);
+        assert!(feed.last_error.unwrap().contains("404"));
+        assert_eq!(poller.run_due().await.unwrap().checked, 0);
+    }
+}
@@ -0,0 +1,139 @@
+//! The Netscape bookmarks file that every browser exports (and imports):
+//! nested `<DL>` lists, where an `<H3>` names the folder whose list comes
+//! right after it, and each bookmark is an `<A>` with an `ADD_DATE` in unix
+//! seconds. Like Pocket's, it's machine-written, so we tokenize it with a
+//! regex and keep a stack of open folders.
+//!
+//! Folders become tags, all the way down, except for the browsers' built-in
+//! ones ("Bookmarks Toolbar", "Other Bookmarks"), which don't mean anything.
+
+use super::ImportEntry;
+use lazy_static::lazy_static;
+use regex::Regex;
+use time::OffsetDateTime;
+
+lazy_static! {
+    static ref TOKEN: Regex = Regex::new(
+        r#"(?is)<h3\b([^>]*)>(.*?)</h3\s*>|<dl\b[^>]*>|</dl\s*>|<a\s([^>]*)>(.*?)</a\s*>"#
+    )
+    .unwrap();
+    static ref HREF: Regex = Regex::new(r#"(?i)\bhref\s*=\s*"([^"]*)""#).unwrap();
+    static ref ADD_DATE: Regex = Regex::new(r#"(?i)\badd_date\s*=\s*"(\d+)""#).unwrap();
+    static ref BUILT_IN: Regex =
+        Regex::new(r#"(?i)\b(personal_toolbar_folder|unfiled_bookmarks_folder)\s*="#).unwrap();
+    static ref TAGS: Regex = Regex::new(r#"(?s)<[^>]*>"#).unwrap();
+}
+
+/// Returns None if it doesn't look like a bookmarks file at all.
+pub fn parse(html: &str) -> Option<Vec<ImportEntry>> {
+    let lower = html.to_ascii_lowercase();
+    if !lower.contains("netscape-bookmark-file") && !lower.contains("<dl") {
+        return None;
+    }
+    // One slot per open <DL>; None for lists that don't get a tag (the
+    // top level, and built-in folders).
+    let mut folders: Vec<Option<String>> = Vec::new();
+    // The last <H3> we saw, waiting for its <DL>.
+    let mut pending: Option<String> = None;
+    let mut entries = Vec::new();
+    for token in TOKEN.captures_iter(html) {
+        if let (Some(attrs), Some(name)) = (token.get(1), token.get(2)) {
+            let name = decode(&TAGS.replace_all(name.as_str(), ""));
+            pending = (!BUILT_IN.is_match(attrs.as_str()) && !name.is_empty()).then_some(name);
+        } else if let (Some(attrs), Some(text)) = (token.get(3), token.get(4)) {
+            let attrs = attrs.as_str();
+            let Some(href) = HREF.captures(attrs) else {
+                continue;
+            };
+            let url = decode(&href[1]);
+            let added = ADD_DATE
+                .captures(attrs)
+                .and_then(|t| t[1].parse::<i64>().ok())
+                .and_then(|secs| OffsetDateTime::from_unix_timestamp(secs).ok());
+            let title = decode(&TAGS.replace_all(text.as_str(), ""));
+            let title = (!title.is_empty() && title != url).then_some(title);
+            let tags = folders.iter().flatten().cloned().collect();
+            entries.push(ImportEntry {
+                url,
+                title,
+                added,
+                tags,
+            });
+        } else if token[0].starts_with("</") {
+            folders.pop();
+        } else {
+            folders.push(pending.take());
+        }
+    }
+    Some(entries)
+}
+
+fn decode(s: &str) -> String {
+    html_escape::decode_html_entities(s).trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT: &str = r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<!-- This is an automatically generated file.
+     It will be read and overwritten.
+     DO NOT EDIT! -->
+<META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=UTF-8">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks Menu</H1>
+
+<DL><p>
+    <DT><A HREF="https://example.com/loose/1" ADD_DATE="1700000000">Loose</A>
+    <DT><H3 ADD_DATE="1600000000" LAST_MODIFIED="1700000000" PERSONAL_TOOLBAR_FOLDER="true">Bookmarks Toolbar</H3>
+    <DL><p>
+        <DT><A HREF="https://example.org/toolbar/thing" ADD_DATE="1650000000" ICON="data:image/png;base64,AAAA">Toolbar thing</A>
+        <DT><H3 ADD_DATE="1600000000">Serials &amp; Such</H3>
+        <DL><p>
+            <DT><A HREF="https://example.com/comic/24" ADD_DATE="1710000000">Comic, page 24</A>
+            <DT><H3>Fantasy</H3>
+            <DL><p>
+                <DT><A HREF="https://example.net/novel/chapter-3?a=1&amp;b=2">https://example.net/novel/chapter-3?a=1&amp;b=2</A>
+            </DL><p>
+            <DT><A HREF="https://example.horse/blog/neigh" ADD_DATE="junk">Neigh</A>
+        </DL><p>
+    </DL><p>
+    <HR>
+    <DT><A NAME="not-a-bookmark">Nope</A>
+</DL>
+"#;
+
+    #[test]
+    fn reads_an_export() {
+        let entries = parse(EXPORT).unwrap();
+        assert_eq!(entries.len(), 5);
+        let tags = |i: usize| entries[i].tags.join("/");
+
+        assert_eq!(entries[0].url, "https://example.com/loose/1");
+        assert_eq!(entries[0].added.unwrap().unix_timestamp(), 1700000000);
+        assert_eq!(tags(0), "");
+        // Built-in folders don't count
+        assert_eq!(entries[1].title.as_deref(), Some("Toolbar thing"));
+        assert_eq!(tags(1), "");
+        // Nested folders stack up, and come back off
+        assert_eq!(entries[2].title.as_deref(), Some("Comic, page 24"));
+        assert_eq!(tags(2), "Serials & Such");
+        assert_eq!(
+            entries[3].url,
+            "https://example.net/novel/chapter-3?a=1&b=2"
+        );
+        assert_eq!(entries[3].title, None);
+        assert_eq!(entries[3].added, None);
+        assert_eq!(tags(3), "Serials & Such/Fantasy");
+        assert_eq!(entries[4].added, None);
+        assert_eq!(tags(4), "Serials & Such");
+    }
+
+    #[test]
+    fn not_an_export() {
+        assert!(parse("just some words").is_none());
+        assert!(parse(r#"[{"url": "https://example.com"}]"#).is_none());
+        assert_eq!(parse("<DL><p></DL>").unwrap().len(), 0);
+    }
+}
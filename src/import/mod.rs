@@ -0,0 +1,466 @@
+//! Importing dogears from other services' export files. Each source gets its
+//! own little parsing module, which turns an export into a flat list of
+//! saved URLs; then the shared stuff here works out prefixes, collapses
+//! entries that would land on the same dogear, and creates what's left.
+//!
+//! Read-later apps and bookmark folders save individual pages, not serials,
+//! so the prefix is a guess: the URL's "directory" (everything but the last
+//! path segment, or the last few, if you ask). When several saved pages
+//! share a guess, the most recently saved one becomes the dogear's current
+//! position, and the dogear gets all of their tags.
+//!
+//! Since that guess can go wrong in bulk, the import page can show you the
+//! plan first (`prepare`) before anything gets made (`run`).
+
+mod bookmarks;
+mod pocket;
+mod wallabag;
+
+use crate::db::Db;
+use crate::util::{normalize_prefix_matcher, MixedError, UserError};
+use eardogger_rs::api_types::ApiImportDogear;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use time::OffsetDateTime;
+use url::Url;
+
+/// The most entries we'll take from one export. Anything much bigger than
+/// this is a whole bookmark archive, not a reading list, and would blow
+/// through the request time budget anyway.
+pub const IMPORT_MAX_ENTRIES: usize = 5000;
+
+/// The most characters of a page title we'll use as a display name.
+const DISPLAY_NAME_MAX_LENGTH: usize = 120;
+
+/// The most characters of a tag (folder name, usually) we'll keep.
+const TAG_MAX_LENGTH: usize = 50;
+
+/// How many trailing path segments `guess_prefix` drops, unless told
+/// otherwise, and the most it'll drop.
+pub const DEFAULT_TRIM: usize = 1;
+pub const MAX_TRIM: usize = 3;
+
+/// The most candidates the preview shows. It still tells you the totals.
+pub const PREVIEW_MAX_CANDIDATES: usize = 200;
+
+/// The services we know how to read exports from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportSource {
+    /// Pocket's "Export HTML file" (a `<ul>` of links per list).
+    Pocket,
+    /// Wallabag's JSON export (an array of entry objects).
+    Wallabag,
+    /// A browser's "Export bookmarks" HTML file.
+    Bookmarks,
+}
+
+impl ImportSource {
+    /// From the import form's value.
+    pub fn from_form(value: &str) -> Option<Self> {
+        match value {
+            "pocket" => Some(Self::Pocket),
+            "wallabag" => Some(Self::Wallabag),
+            "bookmarks" => Some(Self::Bookmarks),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Pocket => "Pocket",
+            Self::Wallabag => "Wallabag",
+            Self::Bookmarks => "browser bookmarks",
+        }
+    }
+
+    /// The import form's value; the reverse of `from_form`.
+    pub fn form_value(&self) -> &'static str {
+        match self {
+            Self::Pocket => "pocket",
+            Self::Wallabag => "wallabag",
+            Self::Bookmarks => "bookmarks",
+        }
+    }
+
+    /// Read an export into a list of saved pages.
+    pub fn parse(&self, data: &str) -> Result<Vec<ImportEntry>, UserError> {
+        let entries = match self {
+            Self::Pocket => pocket::parse(data),
+            Self::Wallabag => wallabag::parse(data).ok(),
+            Self::Bookmarks => bookmarks::parse(data),
+        };
+        match entries {
+            Some(entries) if !entries.is_empty() => Ok(entries),
+            _ => Err(UserError::ImportUnreadable {
+                format: self.name().to_string(),
+            }),
+        }
+    }
+}
+
+/// One saved page from an export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportEntry {
+    pub url: String,
+    pub title: Option<String>,
+    /// When it was saved, if the export says.
+    pub added: Option<OffsetDateTime>,
+    /// Tags or folder names, if the export has them.
+    pub tags: Vec<String>,
+}
+
+/// A dogear we're about to make.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImportCandidate {
+    pub prefix: String,
+    pub current: String,
+    pub display_name: Option<String>,
+    /// Every tag from every page that went into it, sorted.
+    pub tags: Vec<String>,
+}
+
+/// How an import went.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ImportReport {
+    /// Saved pages in the export.
+    pub entries: usize,
+    /// New dogears.
+    pub created: usize,
+    /// Pages that folded into another page's dogear.
+    pub merged: usize,
+    /// Dogears you already had.
+    pub existing: usize,
+    /// Pages we couldn't make a dogear from (weird URLs, mostly).
+    pub invalid: usize,
+}
+
+/// Guess a prefix for a saved page: its host (minus `www.` or `m.`) plus
+/// its path, minus the last `trim` segments. Returns None for non-http(s)
+/// URLs.
+pub fn guess_prefix(url: &str, trim: usize) -> Option<String> {
+    let parsed = Url::parse(url.trim()).ok()?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return None;
+    }
+    let mut prefix = parsed.host_str()?.to_string();
+    if let Some(port) = parsed.port() {
+        prefix.push_str(&format!(":{}", port));
+    }
+    let segments: Vec<&str> = parsed
+        .path_segments()
+        .map(|s| s.filter(|seg| !seg.is_empty()).collect())
+        .unwrap_or_default();
+    for segment in &segments[..segments.len().saturating_sub(trim)] {
+        prefix.push('/');
+        prefix.push_str(segment);
+    }
+    Some(normalize_prefix_matcher(&prefix).to_string())
+}
+
+fn display_name(title: Option<&str>) -> Option<String> {
+    let title = title?.trim();
+    if title.is_empty() {
+        return None;
+    }
+    Some(title.chars().take(DISPLAY_NAME_MAX_LENGTH).collect())
+}
+
+fn tag_name(tag: &str) -> Option<String> {
+    let tag = tag.trim();
+    if tag.is_empty() {
+        return None;
+    }
+    Some(tag.chars().take(TAG_MAX_LENGTH).collect())
+}
+
+/// Work out which dogears to make from a list of saved pages. Pages that
+/// guess the same prefix become one dogear, positioned at whichever was
+/// saved most recently (or the earliest in the file, if there's no telling).
+/// Returns the candidates in the order their prefixes first showed up, plus
+/// a partial report.
+pub fn plan(entries: &[ImportEntry], trim: usize) -> (Vec<ImportCandidate>, ImportReport) {
+    let mut report = ImportReport {
+        entries: entries.len(),
+        ..Default::default()
+    };
+    let mut order: Vec<String> = Vec::new();
+    let mut winners: HashMap<String, &ImportEntry> = HashMap::new();
+    let mut tags: HashMap<String, BTreeSet<String>> = HashMap::new();
+    for entry in entries {
+        let Some(prefix) = guess_prefix(&entry.url, trim) else {
+            report.invalid += 1;
+            continue;
+        };
+        tags.entry(prefix.clone())
+            .or_default()
+            .extend(entry.tags.iter().filter_map(|t| tag_name(t)));
+        match winners.get(&prefix) {
+            Some(winner) => {
+                report.merged += 1;
+                if entry.added > winner.added {
+                    winners.insert(prefix, entry);
+                }
+            }
+            None => {
+                order.push(prefix.clone());
+                winners.insert(prefix, entry);
+            }
+        }
+    }
+    let candidates = order
+        .into_iter()
+        .map(|prefix| {
+            let winner = winners[&prefix];
+            ImportCandidate {
+                current: winner.url.trim().to_string(),
+                display_name: display_name(winner.title.as_deref()),
+                tags: tags
+                    .remove(&prefix)
+                    .map(|t| t.into_iter().collect())
+                    .unwrap_or_default(),
+                prefix,
+            }
+        })
+        .collect();
+    (candidates, report)
+}
+
+/// Parse an export and work out what it would make, without making it.
+pub fn prepare(
+    source: ImportSource,
+    data: &str,
+    trim: usize,
+) -> Result<(Vec<ImportCandidate>, ImportReport), UserError> {
+    let entries = source.parse(data)?;
+    if entries.len() > IMPORT_MAX_ENTRIES {
+        return Err(UserError::ImportTooBig {
+            max: IMPORT_MAX_ENTRIES,
+        });
+    }
+    Ok(plan(&entries, trim))
+}
+
+/// Parse an export and make dogears for everything in it. Dogears the user
+/// already has are left alone (tags and all). It's all one transaction, so
+/// if something goes wrong partway, nothing's left half-imported.
+#[tracing::instrument(skip(db, data))]
+pub async fn run(
+    db: &Db,
+    user_id: i64,
+    source: ImportSource,
+    data: &str,
+    trim: usize,
+) -> Result<ImportReport, MixedError<sqlx::Error>> {
+    let (candidates, mut report) = prepare(source, data, trim)?;
+    let entries: Vec<ApiImportDogear> = candidates
+        .iter()
+        .map(|candidate| ApiImportDogear {
+            prefix: candidate.prefix.clone(),
+            current: candidate.current.clone(),
+            display_name: candidate.display_name.clone(),
+            paused: false,
+            public: false,
+            archived: false,
+        })
+        .collect();
+    let tx = db.transaction();
+    let dogears = tx.dogears();
+    let results = dogears.create_many(user_id, &entries).await?;
+    for (candidate, result) in candidates.iter().zip(results) {
+        match result {
+            Ok(dogear) => {
+                report.created += 1;
+                if !candidate.tags.is_empty() {
+                    dogears.add_tags(dogear.id, &candidate.tags).await?;
+                }
+            }
+            Err(UserError::DogearExists { .. }) => report.existing += 1,
+            Err(_) => report.invalid += 1,
+        }
+    }
+    tx.commit().await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn entry(url: &str, title: &str, added: Option<OffsetDateTime>) -> ImportEntry {
+        ImportEntry {
+            url: url.to_string(),
+            title: Some(title.to_string()),
+            added,
+            tags: Vec::new(),
+        }
+    }
+
+    fn tagged(mut entry: ImportEntry, tags: &[&str]) -> ImportEntry {
+        entry.tags = tags.iter().map(|t| t.to_string()).collect();
+        entry
+    }
+
+    #[test]
+    fn prefix_guessing() {
+        let cases = [
+            ("https://example.com/blog/my-post", Some("example.com/blog")),
+            (
+                "https://www.example.com/comic/24/",
+                Some("example.com/comic"),
+            ),
+            (
+                "http://m.example.com/a/b/c?page=2#top",
+                Some("example.com/a/b"),
+            ),
+            ("https://example.com/lonely-post", Some("example.com")),
+            ("https://example.com", Some("example.com")),
+            ("http://localhost:3000/x/y", Some("localhost:3000/x")),
+            ("ftp://example.com/files/thing", None),
+            ("not a url", None),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(
+                guess_prefix(url, DEFAULT_TRIM).as_deref(),
+                expected,
+                "{}",
+                url
+            );
+        }
+        // Trimming deeper, or not at all
+        let cases = [
+            ("https://example.com/a/b/c", 0, "example.com/a/b/c"),
+            ("https://example.com/a/b/c", 2, "example.com/a"),
+            ("https://example.com/a/b/c", 3, "example.com"),
+            ("https://example.com/a/b/c", 9, "example.com"),
+        ];
+        for (url, trim, expected) in cases {
+            assert_eq!(
+                guess_prefix(url, trim).as_deref(),
+                Some(expected),
+                "{}",
+                trim
+            );
+        }
+    }
+
+    #[test]
+    fn planning_merges_by_prefix() {
+        let entries = [
+            tagged(
+                entry(
+                    "https://example.com/serial/1",
+                    "Chapter 1",
+                    Some(datetime!(2024-01-01 0:00 UTC)),
+                ),
+                &["fiction", " Serials "],
+            ),
+            entry("https://example.horse/blog/neigh", "Neigh", None),
+            entry(
+                "https://example.com/serial/3",
+                "Chapter 3",
+                Some(datetime!(2024-03-01 0:00 UTC)),
+            ),
+            tagged(
+                entry(
+                    "https://example.com/serial/2",
+                    "Chapter 2",
+                    Some(datetime!(2024-02-01 0:00 UTC)),
+                ),
+                &["Serials", ""],
+            ),
+            entry("javascript:alert(1)", "nope", None),
+            entry("https://example.horse/blog/whinny", "   ", None),
+        ];
+        let (candidates, report) = plan(&entries, DEFAULT_TRIM);
+        assert_eq!(
+            report,
+            ImportReport {
+                entries: 6,
+                merged: 3,
+                invalid: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            candidates,
+            vec![
+                ImportCandidate {
+                    prefix: "example.com/serial".to_string(),
+                    current: "https://example.com/serial/3".to_string(),
+                    display_name: Some("Chapter 3".to_string()),
+                    tags: vec!["Serials".to_string(), "fiction".to_string()],
+                },
+                // No dates: the first one wins.
+                ImportCandidate {
+                    prefix: "example.horse/blog".to_string(),
+                    current: "https://example.horse/blog/neigh".to_string(),
+                    display_name: Some("Neigh".to_string()),
+                    tags: vec![],
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn run_creates_and_skips() {
+        let db = Db::new_test_db().await;
+        let user = db
+            .users()
+            .create("reader", "password123", None)
+            .await
+            .unwrap();
+        db.dogears()
+            .create(
+                user.id,
+                "example.com/blog",
+                "https://example.com/blog/1",
+                None,
+            )
+            .await
+            .unwrap();
+        let data = r#"[
+            {"url": "https://example.com/blog/2", "title": "Two", "tags": ["mine"]},
+            {"url": "https://example.com/serial/9", "title": "Nine", "tags": ["fiction"]},
+            {"url": "https://example.org/essays/long-one", "title": "An essay"}
+        ]"#;
+        // Preparing doesn't make anything
+        let (candidates, _) = prepare(ImportSource::Wallabag, data, DEFAULT_TRIM).unwrap();
+        assert_eq!(candidates.len(), 3);
+        assert_eq!(db.dogears().list(user.id, 1, 50).await.unwrap().0.len(), 1);
+
+        let report = run(&db, user.id, ImportSource::Wallabag, data, DEFAULT_TRIM)
+            .await
+            .unwrap();
+        assert_eq!((report.created, report.existing), (2, 1));
+        let (dogears, _) = db.dogears().list(user.id, 1, 50).await.unwrap();
+        assert_eq!(dogears.len(), 3);
+        // The existing one didn't move.
+        let blog = dogears
+            .iter()
+            .find(|d| d.prefix == "example.com/blog")
+            .unwrap();
+        assert_eq!(blog.current, "https://example.com/blog/1");
+        // New ones got their tags; the existing one didn't.
+        let tags = db.dogears().tags(user.id).await.unwrap();
+        let serial = dogears
+            .iter()
+            .find(|d| d.prefix == "example.com/serial")
+            .unwrap();
+        assert_eq!(tags[&serial.id], vec!["fiction"]);
+        assert!(!tags.contains_key(&blog.id));
+
+        // Garbage in: user error.
+        assert!(matches!(
+            run(
+                &db,
+                user.id,
+                ImportSource::Pocket,
+                "what is this",
+                DEFAULT_TRIM
+            )
+            .await,
+            Err(MixedError::User(UserError::ImportUnreadable { .. }))
+        ));
+    }
+}
@@ -0,0 +1,115 @@
+//! Pocket's HTML export: a page with one `<ul>` per list ("Unread", "Read
+//! Archive"), where each saved page is an `<a>` with a `time_added`
+//! attribute in unix seconds (and a comma-separated `tags` attribute). It's generated, not hand-written, so a couple
+//! of regexes do the job without dragging in a whole HTML parser.
+
+use super::ImportEntry;
+use lazy_static::lazy_static;
+use regex::Regex;
+use time::OffsetDateTime;
+
+lazy_static! {
+    static ref LINK: Regex = Regex::new(r#"(?is)<a\s([^>]*)>(.*?)</a>"#).unwrap();
+    static ref HREF: Regex = Regex::new(r#"(?i)\bhref\s*=\s*"([^"]*)""#).unwrap();
+    static ref TIME_ADDED: Regex = Regex::new(r#"(?i)\btime_added\s*=\s*"(\d+)""#).unwrap();
+    static ref TAG_LIST: Regex = Regex::new(r#"(?i)\btags\s*=\s*"([^"]*)""#).unwrap();
+    static ref TAGS: Regex = Regex::new(r#"(?s)<[^>]*>"#).unwrap();
+}
+
+/// Returns None if it doesn't look like a Pocket export at all.
+pub fn parse(html: &str) -> Option<Vec<ImportEntry>> {
+    if !html.contains("<a") && !html.contains("<A") {
+        return None;
+    }
+    let entries = LINK
+        .captures_iter(html)
+        .filter_map(|link| {
+            let attrs = &link[1];
+            let url = decode(HREF.captures(attrs)?.get(1)?.as_str());
+            let added = TIME_ADDED
+                .captures(attrs)
+                .and_then(|t| t[1].parse::<i64>().ok())
+                .and_then(|secs| OffsetDateTime::from_unix_timestamp(secs).ok());
+            let title = decode(&TAGS.replace_all(&link[2], ""));
+            let title = (!title.trim().is_empty() && title != url).then_some(title);
+            let tags = TAG_LIST
+                .captures(attrs)
+                .map(|t| {
+                    decode(&t[1])
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|tag| !tag.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default();
+            Some(ImportEntry {
+                url,
+                title,
+                added,
+                tags,
+            })
+        })
+        .collect();
+    Some(entries)
+}
+
+fn decode(s: &str) -> String {
+    html_escape::decode_html_entities(s).trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT: &str = r#"<!DOCTYPE html>
+<html>
+	<!--So long and thanks for all the fish-->
+	<head>
+		<meta http-equiv="Content-Type" content="text/html; charset=UTF-8" />
+		<title>Pocket Export</title>
+	</head>
+	<body>
+		<h1>Unread</h1>
+		<ul>
+			<li><a href="https://example.com/serial/chapter-12" time_added="1700000000" tags="fiction, serials">Chapter 12: Fish &amp; Chips</a></li>
+			<li><a href="https://example.org/essay?a=1&amp;b=2" time_added="1600000000" tags="">https://example.org/essay?a=1&amp;b=2</a></li>
+		</ul>
+
+		<h1>Read Archive</h1>
+		<ul>
+			<li><a href="https://example.net/old/post" time_added="nope" tags=""><b>Bold</b> title</a></li>
+			<li><a name="no-href">Not a link</a></li>
+		</ul>
+	</body>
+</html>"#;
+
+    #[test]
+    fn reads_an_export() {
+        let entries = parse(EXPORT).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].url, "https://example.com/serial/chapter-12");
+        assert_eq!(
+            entries[0].title.as_deref(),
+            Some("Chapter 12: Fish & Chips")
+        );
+        assert_eq!(entries[0].added.unwrap().unix_timestamp(), 1700000000);
+        assert_eq!(entries[0].tags, vec!["fiction", "serials"]);
+        // Untitled pages use the URL as the link text; that's no title.
+        assert_eq!(entries[1].url, "https://example.org/essay?a=1&b=2");
+        assert_eq!(entries[1].title, None);
+        assert!(entries[1].tags.is_empty());
+        // Junk timestamps are just missing; markup in titles goes.
+        assert_eq!(entries[2].added, None);
+        assert_eq!(entries[2].title.as_deref(), Some("Bold title"));
+    }
+
+    #[test]
+    fn not_an_export() {
+        assert!(parse("just some words").is_none());
+        assert_eq!(
+            parse("<p>no links <a name=x>here</a></p>").unwrap().len(),
+            0
+        );
+    }
+}
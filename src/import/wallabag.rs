@@ -0,0 +1,83 @@
+//! Wallabag's JSON export: an array of entry objects, with a lot more in
+//! them than we care about (the whole article text, for one).
+
+use super::ImportEntry;
+use serde::Deserialize;
+use time::format_description::well_known::Iso8601;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+#[derive(Deserialize, Debug)]
+struct WallabagEntry {
+    url: Option<String>,
+    title: Option<String>,
+    created_at: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+pub fn parse(json: &str) -> Result<Vec<ImportEntry>, serde_json::Error> {
+    let raw: Vec<WallabagEntry> = serde_json::from_str(json)?;
+    let entries = raw
+        .into_iter()
+        .filter_map(|e| {
+            Some(ImportEntry {
+                url: e.url?,
+                title: e.title,
+                added: e.created_at.as_deref().and_then(parse_date),
+                tags: e.tags,
+            })
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// Wallabag writes its dates like `2024-03-18T09:49:13+0100`, with no colon
+/// in the offset, which isn't quite what the ISO 8601 parser wants.
+fn parse_date(date: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(date, &Iso8601::DEFAULT)
+        .or_else(|_| {
+            OffsetDateTime::parse(
+                date,
+                format_description!(
+                    "[year]-[month]-[day]T[hour]:[minute]:[second][offset_hour sign:mandatory][offset_minute]"
+                ),
+            )
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_an_export() {
+        let json = r#"[
+            {
+                "is_archived": 0,
+                "is_starred": 1,
+                "tags": ["fiction"],
+                "title": "Chapter 12",
+                "url": "https://example.com/serial/chapter-12",
+                "content": "<p>Long ago...</p>",
+                "created_at": "2024-03-18T09:49:13+0100",
+                "updated_at": "2024-03-19T09:49:13+0100",
+                "domain_name": "example.com"
+            },
+            {"title": "No URL, somehow"},
+            {"url": "https://example.org/essay", "created_at": "2024-01-02T03:04:05+00:00"}
+        ]"#;
+        let entries = parse(json).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].url, "https://example.com/serial/chapter-12");
+        assert_eq!(entries[0].title.as_deref(), Some("Chapter 12"));
+        assert_eq!(entries[0].added.unwrap().unix_timestamp(), 1710751753);
+        assert_eq!(entries[0].tags, vec!["fiction"]);
+        assert_eq!(entries[1].title, None);
+        assert_eq!(entries[1].added.unwrap().unix_timestamp(), 1704164645);
+
+        assert!(parse("<html>").is_err());
+        assert!(parse(r#"{"url": "https://example.com"}"#).is_err());
+    }
+}
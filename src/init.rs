@@ -0,0 +1,296 @@
+//! `eardogger-rs init`: set up a new deployment in one go. Writes a config
+//! file (the example config, with your answers filled in, so it keeps all
+//! the comments), then creates the data dir, the cookie key, and an empty
+//! database with all the migrations run.
+//!
+//! It never overwrites an existing db or key file, and only overwrites an
+//! existing config file with `--force`.
+
+use crate::args::{InitArgs, InitMode};
+use crate::db::Db;
+use anyhow::{anyhow, bail};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+use tokio_util::task::TaskTracker;
+
+/// The example config is the canonical docs for the config file, so the
+/// generated one is a copy of it with the blanks filled in.
+const EXAMPLE_CONFIG: &str = include_str!("../eardogger.example.toml");
+
+const CONFIG_FILE: &str = "eardogger.toml";
+const DATA_DIR: &str = "data";
+const DB_FILE: &str = "data/eardogger.db";
+const KEY_FILE: &str = "data/cookie_key.bin";
+
+/// The answers to everything init needs to know.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitSettings {
+    pub public_url: String,
+    pub mode: InitMode,
+    pub port: u16,
+    pub production: bool,
+}
+
+impl InitSettings {
+    /// Fill in the settings from the command line, asking about anything
+    /// that's missing if there's someone around to answer.
+    pub fn gather(args: &InitArgs) -> anyhow::Result<Self> {
+        let interactive = !args.no_input && std::io::stdin().is_terminal();
+        let ask = |question: &str, default: &str| -> anyhow::Result<String> {
+            if interactive {
+                prompt(question, default)
+            } else {
+                Ok(default.to_string())
+            }
+        };
+
+        let mode = match args.mode {
+            Some(mode) => mode,
+            None => match ask("Serve over http or fcgi?", "http")?.as_str() {
+                "http" => InitMode::Http,
+                "fcgi" => InitMode::Fcgi,
+                other => bail!("unknown mode '{}'; expected http or fcgi", other),
+            },
+        };
+        let port = match (args.port, mode) {
+            (Some(port), _) => port,
+            (None, InitMode::Http) => ask("Port to listen on?", "3000")?
+                .parse()
+                .map_err(|_| anyhow!("that's not a port number"))?,
+            // Unused in fcgi mode, but the config keeps a commented-out one.
+            (None, InitMode::Fcgi) => 3000,
+        };
+        let public_url = match &args.public_url {
+            Some(url) => url.clone(),
+            None => ask(
+                "Public URL of the site?",
+                &format!("http://localhost:{}", port),
+            )?,
+        };
+        let production = match args.production {
+            Some(production) => production,
+            None => match ask("Is this a production deployment? (yes/no)", "no")?.as_str() {
+                "y" | "yes" | "true" => true,
+                "n" | "no" | "false" => false,
+                other => bail!("expected yes or no, got '{}'", other),
+            },
+        };
+
+        Ok(Self {
+            public_url,
+            mode,
+            port,
+            production,
+        })
+    }
+}
+
+/// Ask a question on stdout and read a line from stdin. An empty answer
+/// takes the default.
+fn prompt(question: &str, default: &str) -> anyhow::Result<String> {
+    print!("{} [{}] ", question, default);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+    if answer.is_empty() {
+        Ok(default.to_string())
+    } else {
+        Ok(answer.to_string())
+    }
+}
+
+/// Replace one exact bit of the example config, or complain loudly if it's
+/// not there (meaning someone edited the example without updating this).
+fn swap(text: &mut String, old: &str, new: &str) -> anyhow::Result<()> {
+    if !text.contains(old) {
+        bail!(
+            "init is out of date with eardogger.example.toml (can't find {:?})",
+            old
+        );
+    }
+    *text = text.replacen(old, new, 1);
+    Ok(())
+}
+
+fn toml_string(s: &str) -> String {
+    toml::Value::String(s.to_string()).to_string()
+}
+
+/// Build the text of the new config file.
+pub fn render_config(settings: &InitSettings, assets_dir: &str) -> anyhow::Result<String> {
+    let mut text = format!(
+        "# Generated by `eardogger-rs init`, from the example config file.\n\n{}",
+        EXAMPLE_CONFIG
+    );
+    swap(
+        &mut text,
+        "production = false",
+        &format!("production = {}", settings.production),
+    )?;
+    swap(
+        &mut text,
+        r#"public_url = "http://localhost:3000""#,
+        &format!("public_url = {}", toml_string(&settings.public_url)),
+    )?;
+    swap(
+        &mut text,
+        r#"db_file = "dev.db""#,
+        &format!("db_file = {}", toml_string(DB_FILE)),
+    )?;
+    swap(
+        &mut text,
+        r#"assets_dir = "public""#,
+        &format!("assets_dir = {}", toml_string(assets_dir)),
+    )?;
+    swap(
+        &mut text,
+        r#"key_file = "cookie_key.bin""#,
+        &format!("key_file = {}", toml_string(KEY_FILE)),
+    )?;
+    let http_block = "[mode.http]\n# The port to listen on.\nport = 3000";
+    match settings.mode {
+        InitMode::Http => {
+            swap(
+                &mut text,
+                http_block,
+                &format!(
+                    "[mode.http]\n# The port to listen on.\nport = {}",
+                    settings.port
+                ),
+            )?;
+        }
+        InitMode::Fcgi => {
+            // Flip which mode block is commented out, and stop logging to
+            // stdout, which fcgi mode doesn't allow.
+            swap(
+                &mut text,
+                http_block,
+                &format!(
+                    "# [mode.http]\n# The port to listen on.\n# port = {}",
+                    settings.port
+                ),
+            )?;
+            swap(&mut text, "# [mode.fcgi]", "[mode.fcgi]")?;
+            swap(&mut text, "# max_connections = 50", "max_connections = 50")?;
+            swap(&mut text, "stdout = true", "stdout = false")?;
+        }
+    }
+    Ok(text)
+}
+
+/// Do the whole setup.
+pub async fn run(args: &InitArgs, settings: &InitSettings) -> anyhow::Result<()> {
+    let dir = &args.dir;
+    let config_file = dir.join(CONFIG_FILE);
+    if config_file.exists() && !args.force {
+        bail!(
+            "{:?} already exists; pass --force to overwrite it",
+            config_file
+        );
+    }
+    let config_text = render_config(settings, &args.assets_dir)?;
+
+    tokio::fs::create_dir_all(dir.join(DATA_DIR)).await?;
+    tokio::fs::write(&config_file, config_text).await?;
+    println!("init: wrote config file {:?}", &config_file);
+
+    let key_file = dir.join(KEY_FILE);
+    if key_file.exists() {
+        println!("init: leaving existing cookie key {:?} alone", &key_file);
+    } else {
+        crate::load_cookie_key(&key_file).await?;
+        println!("init: created cookie key {:?}", &key_file);
+    }
+
+    let db_file = dir.join(DB_FILE);
+    if db_file.exists() {
+        println!(
+            "init: leaving existing database {:?} alone (use --migrate if it's behind)",
+            &db_file
+        );
+    } else {
+        // An empty file is a valid empty sqlite db, and our pool options
+        // won't create a missing one.
+        tokio::fs::File::create(&db_file).await?;
+        migrate(&db_file).await?;
+        println!("init: created and migrated database {:?}", &db_file);
+    }
+
+    if !dir.join(&args.assets_dir).is_dir() {
+        println!(
+            "init: heads up, the assets dir {:?} doesn't exist yet. Copy the `public` dir from the release there, or fix assets_dir in the config.",
+            dir.join(&args.assets_dir)
+        );
+    }
+    println!(
+        "init: all set! Start the server with `eardogger-rs --config {:?}`",
+        &config_file
+    );
+    Ok(())
+}
+
+async fn migrate(db_file: &Path) -> anyhow::Result<()> {
+    // Nothing else is using this db yet, so one connection does for both pools.
+    let pool = crate::db_pool(db_file, 1, None).await?;
+    let db = Db::new(pool.clone(), pool, TaskTracker::new());
+    let result = db.migrations().run().await;
+    db.close().await;
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DogConfig, ServeMode};
+
+    fn test_args(dir: &Path, mode: InitMode) -> InitArgs {
+        InitArgs {
+            dir: dir.to_path_buf(),
+            public_url: Some("https://dogs.example.com".to_string()),
+            mode: Some(mode),
+            port: Some(4000),
+            production: Some(false),
+            assets_dir: "public".to_string(),
+            force: false,
+            no_input: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn init_makes_a_working_setup() {
+        for mode in [InitMode::Http, InitMode::Fcgi] {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::create_dir(dir.path().join("public")).unwrap();
+            let args = test_args(dir.path(), mode);
+            let settings = InitSettings::gather(&args).unwrap();
+            run(&args, &settings).await.expect("init worked");
+
+            // The result passes validation...
+            let config = DogConfig::load(dir.path().join(CONFIG_FILE)).expect("config is valid");
+            assert_eq!(config.public_url.as_str(), "https://dogs.example.com/");
+            match (mode, &config.mode) {
+                (InitMode::Http, ServeMode::Http { port }) => assert_eq!(*port, 4000),
+                (InitMode::Fcgi, ServeMode::Fcgi { .. }) => assert!(!config.log.stdout),
+                (_, other) => panic!("wrong mode: {:?}", other),
+            }
+            // ...with a real key and a fully migrated db.
+            assert_eq!(std::fs::read(&config.key_file).unwrap().len(), 64);
+            let pool = crate::db_pool(&config.db_file, 1, None).await.unwrap();
+            let db = Db::new(pool.clone(), pool, TaskTracker::new());
+            db.migrations()
+                .validate()
+                .await
+                .expect("migrations are current");
+            db.close().await;
+
+            // Second go refuses to clobber the config, unless forced.
+            assert!(run(&args, &settings).await.is_err());
+            let forced = InitArgs {
+                force: true,
+                ..test_args(dir.path(), mode)
+            };
+            run(&forced, &settings).await.expect("forced init worked");
+        }
+    }
+}
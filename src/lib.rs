@@ -0,0 +1,11 @@
+//! Eardogger is mostly a server binary, but this library target holds the
+//! stuff that's useful from the outside:
+//!
+//! - [api_types]: the JSON API's request and response bodies. The server's
+//!   route handlers use these exact types, so they can't drift.
+//! - [client] (with the `client` feature): a typed async client for the JSON
+//!   API, suitable for scripts and sync tools.
+
+pub mod api_types;
+#[cfg(feature = "client")]
+pub mod client;
@@ -0,0 +1,394 @@
+//! `eardogger-rs loadtest`: spin up a pile of concurrent simulated clients
+//! against a running instance, then report latency percentiles and errors.
+//! The thing I actually want to know before a deploy is how the single
+//! writer connection holds up when a bunch of marks land at once, so the
+//! default mix is write-heavy.
+//!
+//! Everything happens in a throwaway dogear with a unique prefix, which gets
+//! deleted at the end. So it's safe-ish to point at a real account, but
+//! please use a test one anyway. (Against an instance with `dev_tools` on,
+//! `--provision` makes one for you.)
+
+use crate::args::LoadtestArgs;
+use crate::util::{url_encoding::encode_uri_component, uuid_string, COOKIE_SESSION};
+use anyhow::{anyhow, bail};
+use eardogger_rs::api_types::{ApiCreatePayload, ApiDevUser, ApiDevUserPayload};
+use eardogger_rs::client::Client;
+use rand::Rng;
+use reqwest::{header, redirect};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Op {
+    Mark,
+    Resume,
+    List,
+}
+
+impl Op {
+    const ALL: [Op; 3] = [Op::Mark, Op::Resume, Op::List];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Op::Mark => "mark",
+            Op::Resume => "resume",
+            Op::List => "list",
+        }
+    }
+}
+
+/// Relative weights for each op, parsed from `--mix`.
+#[derive(Debug, Clone, PartialEq)]
+struct Mix {
+    mark: u32,
+    resume: u32,
+    list: u32,
+}
+
+impl Mix {
+    /// Parse `op=weight` pairs like "mark=70,resume=20,list=10".
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut mix = Self {
+            mark: 0,
+            resume: 0,
+            list: 0,
+        };
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (op, weight) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("bad --mix entry '{}'; expected op=weight", pair))?;
+            let weight: u32 = weight
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("bad --mix weight in '{}'", pair))?;
+            match op.trim() {
+                "mark" => mix.mark = weight,
+                "resume" => mix.resume = weight,
+                "list" => mix.list = weight,
+                other => bail!("unknown --mix op '{}'; try mark, resume, or list", other),
+            }
+        }
+        if mix.total() == 0 {
+            bail!("--mix needs at least one op with a nonzero weight");
+        }
+        Ok(mix)
+    }
+
+    fn total(&self) -> u32 {
+        self.mark + self.resume + self.list
+    }
+
+    /// Turn a roll in `0..total()` into an op.
+    fn pick(&self, roll: u32) -> Op {
+        if roll < self.mark {
+            Op::Mark
+        } else if roll < self.mark + self.resume {
+            Op::Resume
+        } else {
+            Op::List
+        }
+    }
+}
+
+/// Nearest-rank percentile of an already-sorted list.
+fn percentile(sorted: &[Duration], pct: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// One finished request.
+struct Sample {
+    op: Op,
+    elapsed: Duration,
+    error: Option<String>,
+}
+
+/// Everything a simulated client needs. Cheap to clone, since both
+/// clients are refcounted on the inside.
+#[derive(Clone)]
+struct Sim {
+    client: Client,
+    http: reqwest::Client,
+    session: Option<String>,
+    site: String,
+    mix: Mix,
+}
+
+impl Sim {
+    async fn run(self, client_num: u32, requests: u32) -> Vec<Sample> {
+        let mut samples = Vec::with_capacity(requests as usize);
+        for n in 0..requests {
+            // Don't hold the thread_rng across an await, it's not Send.
+            let roll = rand::thread_rng().gen_range(0..self.mix.total());
+            let op = self.mix.pick(roll);
+            let start = Instant::now();
+            let error = match op {
+                Op::Mark => self.mark(client_num, n).await,
+                Op::Resume => self.resume().await,
+                Op::List => self.list().await,
+            }
+            .err();
+            samples.push(Sample {
+                op,
+                elapsed: start.elapsed(),
+                error,
+            });
+        }
+        samples
+    }
+
+    async fn mark(&self, client_num: u32, n: u32) -> Result<(), String> {
+        let current = format!("{}/page/{}-{}", &self.site, client_num, n);
+        self.client
+            .update(&current)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn list(&self) -> Result<(), String> {
+        self.client
+            .list(1, 50)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Resume is a browser route, so this uses the session cookie instead of
+    /// the token. A redirect means it found the dogear; anything else (like
+    /// a login page) is a failure.
+    async fn resume(&self) -> Result<(), String> {
+        let session = self.session.as_deref().unwrap_or_default();
+        let path = format!("resume/{}", encode_uri_component(&self.site));
+        let url = self
+            .client
+            .base_url()
+            .join(&path)
+            .map_err(|e| e.to_string())?;
+        let resp = self
+            .http
+            .get(url)
+            .header(header::COOKIE, format!("{}={}", COOKIE_SESSION, session))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if resp.status().is_redirection() {
+            Ok(())
+        } else {
+            Err(format!(
+                "resume didn't redirect ({}); is --session valid?",
+                resp.status()
+            ))
+        }
+    }
+}
+
+/// Do the whole loadtest and print a report. Returns an error if setup
+/// failed or if any requests failed, so scripts can check the exit status.
+pub async fn run(args: LoadtestArgs) -> anyhow::Result<()> {
+    let mix = Mix::parse(&args.mix)?;
+    if mix.resume > 0 && args.session.is_none() && !args.provision {
+        bail!("the resume op needs a login session; pass --session or drop resume from --mix");
+    }
+    if args.clients == 0 || args.requests == 0 {
+        bail!("--clients and --requests both need to be at least 1");
+    }
+
+    // No redirects, because a resume's redirect is the point, and following
+    // it would just go load some fake page.
+    let http = reqwest::Client::builder()
+        .user_agent(concat!("eardogger-rs-loadtest/", env!("CARGO_PKG_VERSION")))
+        .redirect(redirect::Policy::none())
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let (token, session) = if args.provision {
+        let user = provision(&http, &args.target).await?;
+        println!("loadtest: provisioned user {}", &user.name);
+        (user.manage_token, Some(user.session_id))
+    } else {
+        (args.token.unwrap_or_default(), args.session)
+    };
+    let client = Client::with_http_client(http.clone(), &args.target, &token)?;
+
+    // Setup: a fresh dogear to beat up on.
+    let prefix = format!("loadtest.eardogger.invalid/{}", uuid_string());
+    let site = format!("https://{}", &prefix);
+    let payload = ApiCreatePayload {
+        prefix,
+        current: format!("{}/page/start", &site),
+        display_name: Some("Eardogger loadtest (safe to delete)".to_string()),
+    };
+    let dogear = client
+        .create(&payload)
+        .await
+        .map_err(|e| anyhow!("couldn't create the loadtest dogear: {}", e))?;
+
+    println!(
+        "loadtest: {} clients x {} requests against {} (mark={}, resume={}, list={})",
+        args.clients,
+        args.requests,
+        client.base_url(),
+        mix.mark,
+        mix.resume,
+        mix.list
+    );
+
+    let sim = Sim {
+        client: client.clone(),
+        http,
+        session,
+        site,
+        mix,
+    };
+    let start = Instant::now();
+    let handles: Vec<_> = (0..args.clients)
+        .map(|client_num| tokio::spawn(sim.clone().run(client_num, args.requests)))
+        .collect();
+    let mut samples = Vec::with_capacity(args.clients as usize * args.requests as usize);
+    for handle in handles {
+        samples.extend(handle.await?);
+    }
+    let wall_time = start.elapsed();
+
+    // Cleanup, before reporting; if this fails, say so but still report.
+    if let Err(e) = client.delete(dogear.id).await {
+        println!(
+            "warning: couldn't delete loadtest dogear {} ({}); clean it up by hand",
+            dogear.id, e
+        );
+    }
+
+    let error_count = report(&samples, wall_time);
+    if error_count > 0 {
+        bail!("{} of {} requests failed", error_count, samples.len());
+    }
+    Ok(())
+}
+
+/// Make a throwaway account to test with, on an instance with dev_tools on.
+async fn provision(http: &reqwest::Client, target: &str) -> anyhow::Result<ApiDevUser> {
+    // Borrow the client's base URL cleanup; the token doesn't matter here.
+    let base = Client::with_http_client(http.clone(), target, "")?;
+    let url = base.base_url().join("dev/users")?;
+    let resp = http
+        .post(url)
+        .json(&ApiDevUserPayload::default())
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        bail!(
+            "couldn't provision a user ({}); does the target have dev_tools on?",
+            resp.status()
+        );
+    }
+    Ok(resp.json().await?)
+}
+
+/// Print the results table, and return the total error count.
+fn report(samples: &[Sample], wall_time: Duration) -> usize {
+    println!();
+    println!(
+        "{:<8} {:>7} {:>7} {:>9} {:>9} {:>9} {:>9}",
+        "op", "count", "errors", "p50", "p90", "p99", "max"
+    );
+    let mut rows: Vec<(&str, Vec<&Sample>)> = Op::ALL
+        .iter()
+        .map(|op| (op.name(), samples.iter().filter(|s| s.op == *op).collect()))
+        .collect();
+    rows.push(("total", samples.iter().collect()));
+    for (name, op_samples) in rows {
+        if op_samples.is_empty() {
+            continue;
+        }
+        let mut latencies: Vec<Duration> = op_samples.iter().map(|s| s.elapsed).collect();
+        latencies.sort();
+        let errors = op_samples.iter().filter(|s| s.error.is_some()).count();
+        println!(
+            "{:<8} {:>7} {:>7} {:>9} {:>9} {:>9} {:>9}",
+            name,
+            op_samples.len(),
+            errors,
+            ms(percentile(&latencies, 50.0)),
+            ms(percentile(&latencies, 90.0)),
+            ms(percentile(&latencies, 99.0)),
+            ms(latencies[latencies.len() - 1]),
+        );
+    }
+    println!();
+    println!(
+        "{} requests in {:.2}s ({:.1} req/s)",
+        samples.len(),
+        wall_time.as_secs_f64(),
+        samples.len() as f64 / wall_time.as_secs_f64()
+    );
+
+    // Group identical error messages, since they tend to come in bunches.
+    let mut errors: BTreeMap<(Op, &str), usize> = BTreeMap::new();
+    for s in samples {
+        if let Some(e) = &s.error {
+            *errors.entry((s.op, e.as_str())).or_default() += 1;
+        }
+    }
+    if !errors.is_empty() {
+        println!();
+        println!("errors:");
+        for ((op, message), count) in errors.iter() {
+            println!("{:>7}x {}: {}", count, op.name(), message);
+        }
+    }
+    errors.values().sum()
+}
+
+fn ms(d: Duration) -> String {
+    format!("{:.1}ms", d.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mix_parsing() {
+        let mix = Mix::parse("mark=70, resume=20,list=10").unwrap();
+        assert_eq!(
+            mix,
+            Mix {
+                mark: 70,
+                resume: 20,
+                list: 10
+            }
+        );
+        assert_eq!(mix.pick(0), Op::Mark);
+        assert_eq!(mix.pick(69), Op::Mark);
+        assert_eq!(mix.pick(70), Op::Resume);
+        assert_eq!(mix.pick(89), Op::Resume);
+        assert_eq!(mix.pick(90), Op::List);
+        assert_eq!(mix.pick(99), Op::List);
+        // Omitted ops are zero and never get picked
+        let mix = Mix::parse("list=1").unwrap();
+        assert_eq!(mix.total(), 1);
+        assert_eq!(mix.pick(0), Op::List);
+        // Junk
+        assert!(Mix::parse("mark").is_err());
+        assert!(Mix::parse("mark=lots").is_err());
+        assert!(Mix::parse("delete=5").is_err());
+        assert!(Mix::parse("mark=0,list=0").is_err());
+        assert!(Mix::parse("").is_err());
+    }
+
+    #[test]
+    fn percentiles() {
+        let ds: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&ds, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&ds, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&ds, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&ds, 0.0), Duration::from_millis(1));
+        let one = [Duration::from_millis(7)];
+        assert_eq!(percentile(&one, 90.0), Duration::from_millis(7));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+}
@@ -1,11 +1,29 @@
 mod app;
 mod args;
+mod backups;
+mod cadence;
+mod cipher;
 mod config;
 mod db;
+mod feeds;
+mod import;
+mod init;
+#[cfg(feature = "client")]
+mod loadtest;
+mod maintenance;
+mod migration;
+mod outbound;
+mod package;
+mod reload;
+#[cfg(feature = "client")]
+mod remote;
+mod shutdown;
+mod site_rules;
 mod util;
 mod version;
+mod webhooks;
 
-use db::Db;
+use db::{ChangeListener, Db};
 use sqlx::{
     pool::PoolOptions,
     sqlite::{Sqlite, SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous},
@@ -25,27 +43,52 @@ use tracing_appender::{
     rolling::{RollingFileAppender, Rotation},
 };
 use tracing_subscriber::{
-    fmt::layer as fmt_layer, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+    fmt::layer as fmt_layer, layer::SubscriberExt, reload::Layer as ReloadLayer,
+    util::SubscriberInitExt, EnvFilter,
 };
 
 use crate::app::{eardogger_app, load_templates, state::*};
+use crate::backups::BackupSender;
+use crate::cipher::DbKey;
 use crate::config::*;
+use crate::feeds::FeedPoller;
+use crate::util::{JobClock, MxChecker, PwnedChecker, SignupGuard};
+use crate::webhooks::WebhookSender;
+
+/// Where the config file is, unless `--config` says otherwise.
+const DEFAULT_CONFIG_FILE: &str = "eardogger.toml";
 
 // Only responsible for spinning up the runtime and spawning real_main
 // on it... but in order to do that, we need our args and config.
 fn main() -> anyhow::Result<()> {
     // Get args
-    let options = args::cli_options();
+    let mut options = args::cli_options();
     if options.version {
         println!("Built {}", version::build_date());
         println!("from commit {}", version::commit_sha());
         return Ok(());
     }
 
+    // Most subcommands are standalone tools that don't need our config.
+    match options.command.take() {
+        Some(args::Command::Init(init_args)) => return init_main(init_args),
+        Some(args::Command::Loadtest(lt_args)) => return loadtest_main(lt_args),
+        Some(args::Command::SelfCmd(args::SelfCommand::Package(package_args))) => {
+            return package_main(package_args)
+        }
+        Some(
+            command @ (args::Command::Mark(_)
+            | args::Command::Resume(_)
+            | args::Command::ClientLogin(_)),
+        ) => return remote_main(command),
+        // The db maintenance jobs do, so they happen in real_main.
+        other => options.command = other,
+    }
+
     // Get the config
     let config = match &options.config {
         Some(path) => DogConfig::load(path)?,
-        None => DogConfig::load("eardogger.toml")?,
+        None => DogConfig::load(DEFAULT_CONFIG_FILE)?,
     };
 
     // Build the runtime
@@ -58,6 +101,53 @@ fn main() -> anyhow::Result<()> {
     runtime.block_on(real_main(options, config))
 }
 
+/// Init asks its questions before there's a runtime, then only needs a
+/// small one for the db setup.
+fn init_main(init_args: args::InitArgs) -> anyhow::Result<()> {
+    let settings = init::InitSettings::gather(&init_args)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(init::run(&init_args, &settings))
+}
+
+/// Packaging is all files and child processes, so it doesn't need a runtime.
+fn package_main(package_args: args::PackageArgs) -> anyhow::Result<()> {
+    package::run(&package_args)
+}
+
+/// The loadtest is just an HTTP client, so it gets a plain default runtime
+/// and no logging setup.
+#[cfg(feature = "client")]
+fn loadtest_main(lt_args: args::LoadtestArgs) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(loadtest::run(lt_args))
+}
+
+#[cfg(not(feature = "client"))]
+fn loadtest_main(_lt_args: args::LoadtestArgs) -> anyhow::Result<()> {
+    anyhow::bail!("loadtest needs the API client; rebuild with `--features client`")
+}
+
+/// The client commands make a request or two and quit, so they get the
+/// smallest runtime there is.
+#[cfg(feature = "client")]
+fn remote_main(command: args::Command) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(remote::run(command))
+}
+
+#[cfg(not(feature = "client"))]
+fn remote_main(_command: args::Command) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "mark, resume, and client-login need the API client; rebuild with `--features client`"
+    )
+}
+
 // NOW we can get the party started! This is the primary future we spawn on the
 // async runtime.
 async fn real_main(options: args::Options, config: DogConfig) -> anyhow::Result<()> {
@@ -92,9 +182,12 @@ async fn real_main(options: args::Options, config: DogConfig) -> anyhow::Result<
         None
     };
 
+    // The filter's behind a reload layer, so a SIGHUP can swap it out.
+    let (filter_layer, filter_handle) = ReloadLayer::new(EnvFilter::new(&config.log.filter));
+
     // Ok, there we go. Beyond this point, we can now log with tracing!
     tracing_subscriber::registry()
-        .with(EnvFilter::new(&config.log.filter))
+        .with(filter_layer)
         .with(stdout_layer)
         .with(logrotate_layer)
         .init();
@@ -105,16 +198,55 @@ async fn real_main(options: args::Options, config: DogConfig) -> anyhow::Result<
 
     // Set up the database connection pool
     debug!("using db file at {:?}", &config.db_file);
+    let db_key = DbKey::from_config(&config)?;
+
+    // Encrypting reads the db as plaintext, so it can't use the keyed pools.
+    if let Some(args::Command::Db(args::DbCommand::Encrypt(encrypt_args))) = &options.command {
+        let key = db_key.as_ref().ok_or_else(|| {
+            anyhow::anyhow!(
+                "db encrypt needs a key; set db_key_file in the config or {} in the environment.",
+                cipher::DB_KEY_ENV
+            )
+        })?;
+        cipher::encrypt(&config.db_file, &encrypt_args.out, key).await?;
+        println!(
+            "db encrypt: wrote an encrypted copy to {:?}. Stop the server, move it into place at {:?} (deleting any old -wal and -shm files), and start back up with the key configured.",
+            &encrypt_args.out, &config.db_file
+        );
+        return Ok(());
+    }
+
     let max_readers = config.reader_threads;
-    let read_pool = db_pool(&config.db_file, max_readers).await?;
-    let write_pool = db_pool(&config.db_file, 1).await?;
+    let read_pool = db_pool(&config.db_file, max_readers, db_key.as_ref()).await?;
+    let write_pool = db_pool(&config.db_file, 1, db_key.as_ref()).await?;
     let db = Db::new(read_pool, write_pool, tracker.clone());
 
     // If we're in one of our "do migrations" modes instead of our normal mode,
     // do the deed now and exit early.
     if options.migrate {
+        let backup = if options.no_backup {
+            println!("--migrate: --no-backup, so skipping the pre-migration snapshot.");
+            None
+        } else {
+            let backup = maintenance::backup_before_migrate(&db, &config).await?;
+            match &backup {
+                Some(path) => println!("--migrate: saved a pre-migration snapshot at {:?}", path),
+                None => println!("--migrate: nothing pending, so no snapshot needed."),
+            }
+            backup
+        };
         println!("--migrate: running database migrations now.");
-        db.migrations().run().await?;
+        if let Err(e) = db.migrations().run().await {
+            db.close().await;
+            eprintln!("--migrate: migrations failed: {}", e);
+            if let Some(path) = &backup {
+                eprintln!(
+                    "--migrate: to roll back, stop anything else using the db, then run:\n    {}",
+                    maintenance::restore_command(path, &config.db_file)
+                );
+            }
+            return Err(e.into());
+        }
         println!("--migrate: finished migrations. see u, space cowboy.");
 
         db.close().await;
@@ -128,6 +260,10 @@ async fn real_main(options: args::Options, config: DogConfig) -> anyhow::Result<
 
         db.close().await;
         return Ok(());
+    } else if let Some(args::Command::Db(db_command)) = &options.command {
+        let result = maintenance::run(&db, &config, db_command).await;
+        db.close().await;
+        return result;
     }
 
     // We're in normal mode, but maybe check the migrations.
@@ -141,25 +277,70 @@ async fn real_main(options: args::Options, config: DogConfig) -> anyhow::Result<
 
     // Build the app state
     let templates = load_templates()?;
-    let inner = DSInner {
-        db: db.clone(),
+    let checkers = Checkers {
+        pwned_checker: PwnedChecker::new()?,
+        mx_checker: MxChecker::new()?,
+        signup_guard: SignupGuard::new(&config.signup)?,
+    };
+    let inner = DSInner::new(
+        db.clone(),
         config,
         templates,
-        cookie_key: key,
-        task_tracker: tracker.clone(),
-        cancel_token: cancel_token.clone(),
-    };
+        key,
+        tracker.clone(),
+        cancel_token.clone(),
+        checkers,
+    )?;
     let state: DogState = Arc::new(inner);
 
     // ok, ok,...
     let app = eardogger_app(state.clone());
 
     // Spawn the shutdown signal listener, outside the tracker
-    tokio::spawn(cancel_on_terminate(cancel_token.clone()));
+    tokio::spawn(shutdown::cancel_on_terminate(cancel_token.clone()));
+
+    // And the config reloader, same deal
+    #[cfg(unix)]
+    tokio::spawn(reload::reload_on_hangup(
+        options
+            .config
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CONFIG_FILE.into()),
+        state.live.clone(),
+        filter_handle,
+    ));
+    // (Nothing sends SIGHUP anywhere else.)
+    #[cfg(not(unix))]
+    let _ = filter_handle;
 
-    // Spawn the stale session pruning worker, in the tracker
-    tracker.spawn(prune_stale_sessions_worker(
+    // Spawn the daily cleanup worker, in the tracker
+    tracker.spawn(daily_cleanup_worker(
         db.clone(),
+        state.config.retention.clone(),
+        state.job_clock.clone(),
+        state.metrics.clone(),
+        cancel_token.clone(),
+    ));
+
+    // And the backup scheduler
+    tracker.spawn(backup_worker(
+        BackupSender::new(db.clone(), &state.config)?,
+        state.job_clock.clone(),
+        cancel_token.clone(),
+    ));
+
+    // And the feed poller
+    tracker.spawn(feed_worker(
+        FeedPoller::new(db.clone(), &state.config)?,
+        state.job_clock.clone(),
+        cancel_token.clone(),
+    ));
+
+    // And the outgoing webhook sender
+    tracker.spawn(webhook_worker(
+        WebhookSender::new(db.clone(), &state.config)?,
+        db.changes().subscribe(),
+        state.job_clock.clone(),
         cancel_token.clone(),
     ));
 
@@ -172,6 +353,7 @@ async fn real_main(options: args::Options, config: DogConfig) -> anyhow::Result<
                 .with_graceful_shutdown(cancel_token.clone().cancelled_owned())
                 .await
         }
+        #[cfg(unix)]
         ServeMode::Fcgi { max_connections } => {
             info!("starting main FastCGI server loop");
             busride_rs::serve_fcgid_with_graceful_shutdown(
@@ -181,6 +363,12 @@ async fn real_main(options: args::Options, config: DogConfig) -> anyhow::Result<
             )
             .await
         }
+        // Config validation already refuses this, so it's just for the compiler.
+        #[cfg(not(unix))]
+        ServeMode::Fcgi { .. } => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "FastCGI mode only works on unix",
+        )),
     };
 
     // Clean up:
@@ -226,7 +414,8 @@ async fn load_cookie_key(path: impl AsRef<Path>) -> tokio::io::Result<Key> {
 async fn db_pool(
     db_file: impl AsRef<Path>,
     max_connections: u32,
-) -> Result<SqlitePool, sqlx::Error> {
+    key: Option<&DbKey>,
+) -> anyhow::Result<SqlitePool> {
     let db_opts = SqliteConnectOptions::new();
     let db_opts = db_opts
         .filename(db_file)
@@ -236,45 +425,23 @@ async fn db_pool(
         .optimize_on_close(true, 400)
         .synchronous(SqliteSynchronous::Normal) // usually fine w/ wal
         .foreign_keys(true);
+    let db_opts = match key {
+        Some(key) => key.apply(db_opts),
+        None => db_opts,
+    };
     let pool_opts: PoolOptions<Sqlite> = PoolOptions::new()
         .max_connections(max_connections) // default's 10, but we'll be explicit.
         .min_connections(1)
         // boss makes a dollar, db thread makes a dime, that's why I fish crab on company time
         .max_lifetime(Duration::from_secs(60 * 60 * 4));
-    pool_opts.connect_with(db_opts).await
-}
-
-/// Waits until the program receives an external instruction to terminate
-/// via either SIGINT (ctrl-c) or SIGTERM (kill), then cancels the provided
-/// CancellationToken. This can be spawned as an independent task, and then
-/// the main logic can just await the cancellation token.
-#[tracing::instrument(skip_all)]
-async fn cancel_on_terminate(cancel_token: CancellationToken) {
-    use tokio::signal::{
-        ctrl_c,
-        unix::{signal, SignalKind},
-    };
-    let Ok(mut terminate) = signal(SignalKind::terminate()) else {
-        // If we can't listen for the signal, bail immediately
-        error!("couldn't even establish SIGTERM signal listener; taking my ball and going home");
-        cancel_token.cancel();
-        return;
-    };
-    // Wait indefinitely until we hear a shutdown signal.
-    // The ctrl_c function listens for SIGINT, the other one listens for SIGTERM
-    // (aka `kill`/`killall` with no flags).
-    select! {
-        _ = ctrl_c() => {
-            // don't care if Ok or Err
-            info!("received SIGINT, starting shutdown");
-        },
-        _ = terminate.recv() => {
-            // don't care if Some or None
-            info!("received SIGTERM, starting shutdown");
-        },
+    let pool = pool_opts
+        .connect_with(db_opts)
+        .await
+        .map_err(|e| cipher::explain(e, key.is_some()))?;
+    if key.is_some() {
+        cipher::require_cipher(&pool).await?;
     }
-    // Ok, spread the news
-    cancel_token.cancel();
+    Ok(pool)
 }
 
 /// Long-running job to purge expired login sessions from the database,
@@ -282,12 +449,20 @@ async fn cancel_on_terminate(cancel_token: CancellationToken) {
 /// important enough to block any other interesting work (the queries
 /// all exclude expired sessions, so they're already functionally
 /// gone), but you want to do it often enough that it's always fast.
+/// While it's at it, it enforces the `[retention]` limits, which are the
+/// same kind of chore on the same kind of schedule.
 /// About the timing: if our process is owned by a web server, we're gonna
 /// need to serve requests immediately upon wakeup, and some of them may
 /// want the db writer. So we want to delay the first purge for several seconds.
 #[tracing::instrument(skip_all)]
-async fn prune_stale_sessions_worker(db: Db, cancel_token: CancellationToken) {
-    info!("starting up session pruning worker; pausing before first purge");
+async fn daily_cleanup_worker(
+    db: Db,
+    retention: RetentionConfig,
+    job_clock: JobClock,
+    metrics: Metrics,
+    cancel_token: CancellationToken,
+) {
+    info!("starting up daily cleanup worker; pausing before first purge");
     let a_day = Duration::from_secs(60 * 60 * 24);
     // Initial delay (or fast-track it on cancel)
     select! {
@@ -298,7 +473,8 @@ async fn prune_stale_sessions_worker(db: Db, cancel_token: CancellationToken) {
         info!("purging stale sessions...");
         match db.sessions().delete_expired().await {
             Ok(count) => {
-                info!("purged {} sessions, going back to sleep", count);
+                info!("purged {} sessions", count);
+                metrics.sessions_pruned(count);
             }
             Err(e) => {
                 error!(
@@ -307,6 +483,60 @@ async fn prune_stale_sessions_worker(db: Db, cancel_token: CancellationToken) {
                 );
             }
         }
+        // And tokens that ran out their lifetime.
+        match db.tokens().delete_expired().await {
+            Ok(count) => {
+                info!("purged {} expired tokens", count);
+            }
+            Err(e) => {
+                error!(
+                    "db write error while purging tokens: {}; better luck next time",
+                    e
+                );
+            }
+        }
+        // Same story for migration codes nobody used.
+        match db.migration_codes().delete_expired().await {
+            Ok(count) => {
+                info!("purged {} expired migration codes", count);
+            }
+            Err(e) => {
+                error!(
+                    "db write error while purging migration codes: {}; better luck next time",
+                    e
+                );
+            }
+        }
+        // And webhook deliveries that were never going anywhere.
+        match db.webhooks().delete_stale_deliveries().await {
+            Ok(count) => {
+                info!("purged {} stale webhook deliveries", count);
+            }
+            Err(e) => {
+                error!(
+                    "db write error while purging webhook deliveries: {}; better luck next time",
+                    e
+                );
+            }
+        }
+        match maintenance::apply_retention(&db, &retention).await {
+            Ok(report) => {
+                info!(
+                    history = report.history,
+                    devices = report.devices,
+                    email_changes = report.email_changes,
+                    audit_events = report.audit_events,
+                    "applied retention limits"
+                );
+            }
+            Err(e) => {
+                error!(
+                    "db write error while applying retention limits: {}; better luck next time",
+                    e
+                );
+            }
+        }
+        job_clock.ran("daily_cleanup");
         select! {
             // We don't really need to do this more than once a day.
             _ = tokio::time::sleep(a_day) => {}, // keep loopin'
@@ -316,5 +546,135 @@ async fn prune_stale_sessions_worker(db: Db, cancel_token: CancellationToken) {
             }
         }
     }
-    info!("shutting down session pruning worker");
+    info!("shutting down daily cleanup worker");
+}
+
+/// Long-running job to send out scheduled personal backups. Each one only
+/// comes due weekly, but people turn them on whenever, so check hourly.
+/// Same deal as the cleanup worker about waiting a bit before the first
+/// pass.
+#[tracing::instrument(skip_all)]
+async fn backup_worker(sender: BackupSender, job_clock: JobClock, cancel_token: CancellationToken) {
+    info!("starting up backup worker; pausing before first pass");
+    let an_hour = Duration::from_secs(60 * 60);
+    select! {
+        _ = tokio::time::sleep(Duration::from_secs(30)) => {},
+        _ = cancel_token.cancelled() => {},
+    }
+    while !cancel_token.is_cancelled() {
+        match sender.run_due().await {
+            Ok(report) => {
+                if report.sent + report.failed > 0 {
+                    info!(
+                        sent = report.sent,
+                        failed = report.failed,
+                        "sent scheduled backups"
+                    );
+                }
+            }
+            Err(e) => {
+                error!(
+                    "db error while sending backups: {}; better luck next time",
+                    e
+                );
+            }
+        }
+        job_clock.ran("backups");
+        select! {
+            _ = tokio::time::sleep(an_hour) => {},
+            _ = cancel_token.cancelled() => {},
+        }
+    }
+    info!("shutting down backup worker");
+}
+
+/// Long-running job to check dogears' feeds for new chapters. Each feed
+/// only comes due hourly (or less, if it's been failing), but they come
+/// due at all different times, so check every five minutes. If feed
+/// polling's off in the config, this just clocks out.
+#[tracing::instrument(skip_all)]
+async fn feed_worker(poller: FeedPoller, job_clock: JobClock, cancel_token: CancellationToken) {
+    if !poller.enabled() {
+        info!("feed polling is off; skipping feed worker");
+        return;
+    }
+    info!("starting up feed worker; pausing before first pass");
+    let five_minutes = Duration::from_secs(5 * 60);
+    select! {
+        _ = tokio::time::sleep(Duration::from_secs(45)) => {},
+        _ = cancel_token.cancelled() => {},
+    }
+    while !cancel_token.is_cancelled() {
+        match poller.run_due().await {
+            Ok(report) => {
+                if report.checked > 0 {
+                    info!(
+                        checked = report.checked,
+                        advanced = report.advanced,
+                        flagged = report.flagged,
+                        failed = report.failed,
+                        "checked feeds"
+                    );
+                }
+            }
+            Err(e) => {
+                error!(
+                    "db error while checking feeds: {}; better luck next time",
+                    e
+                );
+            }
+        }
+        job_clock.ran("feeds");
+        select! {
+            _ = tokio::time::sleep(five_minutes) => {},
+            _ = cancel_token.cancelled() => {},
+        }
+    }
+    info!("shutting down feed worker");
+}
+
+/// Long-running job to send outgoing webhook deliveries. Deliveries get
+/// queued by the database itself, so this just sends whatever's due right
+/// after every dogear change notice, and once a minute anyway to catch
+/// retries (and any notices it missed). If webhooks are off in the config,
+/// this just clocks out.
+#[tracing::instrument(skip_all)]
+async fn webhook_worker(
+    sender: WebhookSender,
+    mut changes: ChangeListener,
+    job_clock: JobClock,
+    cancel_token: CancellationToken,
+) {
+    if !sender.enabled() {
+        info!("webhooks are off; skipping webhook worker");
+        return;
+    }
+    info!("starting up webhook worker");
+    let a_minute = Duration::from_secs(60);
+    while !cancel_token.is_cancelled() {
+        match sender.run_due().await {
+            Ok(report) => {
+                if report.sent + report.failed > 0 {
+                    info!(
+                        sent = report.sent,
+                        failed = report.failed,
+                        "sent webhook deliveries"
+                    );
+                }
+            }
+            Err(e) => {
+                error!(
+                    "db error while sending webhooks: {}; better luck next time",
+                    e
+                );
+            }
+        }
+        job_clock.ran("webhooks");
+        select! {
+            _ = changes.any_changed() => {},
+            _ = tokio::time::sleep(a_minute) => {},
+            _ = cancel_token.cancelled() => {},
+        }
+    }
+    info!("shutting down webhook worker");
 }
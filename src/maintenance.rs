@@ -0,0 +1,727 @@
+//! `eardogger-rs db ...`: maintenance jobs for an existing database. These
+//! run against the configured db file (like `--migrate` does) and then exit.
+//! The snapshot `--migrate` takes beforehand lives here too.
+
+use crate::args::{CreateUserArgs, DbCommand};
+use crate::config::{DogConfig, PasswordPolicy, RetentionConfig};
+use crate::db::{Db, Dogear, User};
+use crate::util::{
+    matchable_from_url, normalize_current_url, normalize_prefix_matcher, validate_new_password,
+    MixedError, PwnedChecker, UsernamePolicy, SHORT_DATE,
+};
+use anyhow::anyhow;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
+
+/// For naming migration backups. Sorts right, and has no colons to upset
+/// anybody's filesystem.
+const BACKUP_STAMP: &[FormatItem] =
+    format_description!("[year][month][day]T[hour][minute][second]Z");
+
+pub async fn run(db: &Db, config: &DogConfig, command: &DbCommand) -> anyhow::Result<()> {
+    match command {
+        DbCommand::Normalize(args) => {
+            let report = normalize(db, args.fix).await?;
+            report.print(args.fix);
+            Ok(())
+        }
+        DbCommand::CreateUser(args) => {
+            let (password, password_again) = read_password()?;
+            let pwned = PwnedChecker::new()?;
+            let user = create_user(
+                db,
+                &config.passwords,
+                UsernamePolicy::from_config(config.unicode_usernames),
+                &pwned,
+                args,
+                &password,
+                &password_again,
+            )
+            .await?;
+            println!("created user {} (id {})", user.username, user.id);
+            Ok(())
+        }
+        DbCommand::Purge => {
+            let report = apply_retention(db, &config.retention).await?;
+            println!(
+                "purged {} history entries, {} devices, {} email changes, {} audit events",
+                report.history, report.devices, report.email_changes, report.audit_events
+            );
+            Ok(())
+        }
+        DbCommand::Dedupe(args) => {
+            let user = db
+                .users()
+                .by_name(&args.username)
+                .await?
+                .ok_or_else(|| anyhow!("there's no user named {:?}.", args.username))?;
+            let interactive = std::io::stdin().is_terminal();
+            let report = dedupe(db, user.id, |group| {
+                if args.yes {
+                    Ok(true)
+                } else if interactive {
+                    confirm(&format!("Merge these into dogear {}? [y/N] ", group[0].id))
+                } else {
+                    Ok(false)
+                }
+            })
+            .await?;
+            println!(
+                "db dedupe: found {} bunches of overlapping dogears; merged {}.",
+                report.found, report.merged
+            );
+            if report.found > report.merged && !interactive && !args.yes {
+                println!("db dedupe: run again with --yes (or at a terminal) to merge them.");
+            }
+            Ok(())
+        }
+        DbCommand::Admin(args) => {
+            db.users()
+                .set_admin(&args.username, !args.revoke)
+                .await?
+                .ok_or_else(|| anyhow!("there's no user named {:?}.", args.username))?;
+            if args.revoke {
+                println!("{} isn't an admin anymore.", args.username);
+            } else {
+                println!("{} is an admin now.", args.username);
+            }
+            Ok(())
+        }
+        // This one has to read the db without a key, so real_main does it
+        // before opening the pools.
+        DbCommand::Encrypt(_) => unreachable!("db encrypt never gets this far"),
+    }
+}
+
+/// What a retention run deleted.
+#[derive(Debug, Default, PartialEq)]
+pub struct RetentionReport {
+    pub history: u64,
+    pub devices: u64,
+    pub email_changes: u64,
+    pub audit_events: u64,
+}
+
+/// Delete whatever's outlived the retention limits. The server does this
+/// daily; `db purge` does it on demand.
+pub async fn apply_retention(
+    db: &Db,
+    retention: &RetentionConfig,
+) -> sqlx::Result<RetentionReport> {
+    let mut report = RetentionReport {
+        // Users can set their own limit even when the site doesn't have one.
+        history: db
+            .dogears()
+            .delete_old_history(retention.history_months)
+            .await?,
+        ..Default::default()
+    };
+    if let Some(months) = retention.devices_months {
+        report.devices = db.devices().delete_stale(months).await?;
+    }
+    if let Some(months) = retention.email_changes_months {
+        report.email_changes = db.email_changes().delete_old(months).await?;
+    }
+    if let Some(months) = retention.audit_events_months {
+        report.audit_events = db.audit().delete_old(months).await?;
+    }
+    Ok(report)
+}
+
+/// Before `--migrate` applies anything, snapshot the db into
+/// `migration_backup_dir`, so a botched migration has a way back. Returns
+/// the snapshot's path, or None if nothing's pending (so nothing to back
+/// up from).
+pub async fn backup_before_migrate(db: &Db, config: &DogConfig) -> anyhow::Result<Option<PathBuf>> {
+    if db.migrations().pending().await? == 0 {
+        return Ok(None);
+    }
+    tokio::fs::create_dir_all(&config.migration_backup_dir).await?;
+    let stem = config
+        .db_file
+        .file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or("eardogger".into());
+    let stamp = OffsetDateTime::now_utc().format(BACKUP_STAMP)?;
+    let path = config
+        .migration_backup_dir
+        .join(format!("{}-premigrate-{}.db", stem, stamp));
+    db.migrations().backup_into(&path).await?;
+    Ok(Some(path))
+}
+
+/// The shell command that puts a migration backup back in place. The old
+/// -wal and -shm files have to go too, or sqlite would replay the botched
+/// migration's leftovers right on top of the restored copy.
+pub fn restore_command(backup: &Path, db_file: &Path) -> String {
+    let db_file = db_file.to_string_lossy();
+    format!(
+        "cp {:?} {:?} && rm -f {:?} {:?}",
+        backup.to_string_lossy(),
+        db_file,
+        format!("{}-wal", db_file),
+        format!("{}-shm", db_file)
+    )
+}
+
+/// Read a new password from stdin. At a terminal we ask twice, like the
+/// signup form does; piped in, once is plenty.
+fn read_password() -> anyhow::Result<(String, String)> {
+    let stdin = std::io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut read_line = |prompt: &str| -> anyhow::Result<String> {
+        if interactive {
+            eprint!("{}", prompt);
+            std::io::stderr().flush()?;
+        }
+        let mut line = String::new();
+        stdin.lock().read_line(&mut line)?;
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    };
+    let password = read_line("New password: ")?;
+    let again = if interactive {
+        read_line("Confirm new password: ")?
+    } else {
+        password.clone()
+    };
+    Ok((password, again))
+}
+
+/// Ask a yes-or-no question at the terminal. Anything but yes is no.
+fn confirm(prompt: &str) -> anyhow::Result<bool> {
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(matches!(line.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Make an account, if the username and password pass muster.
+async fn create_user(
+    db: &Db,
+    policy: &PasswordPolicy,
+    usernames: UsernamePolicy,
+    pwned: &PwnedChecker,
+    args: &CreateUserArgs,
+    password: &str,
+    password_again: &str,
+) -> anyhow::Result<User> {
+    let username = args.username.trim();
+    validate_new_password(password, password_again, username, policy, pwned).await?;
+    Ok(db
+        .users()
+        .create_with_policy(usernames, username, password, args.email.as_deref())
+        .await?)
+}
+
+/// What the current normalization rules make of a stored dogear.
+#[derive(Debug, PartialEq)]
+enum Checkup {
+    /// Already normalized, and the current URL matches the prefix.
+    Fine,
+    /// Normalizing changes it, and the result holds together.
+    Drifted { prefix: String, current: String },
+    /// Even after normalizing, the current URL is invalid or doesn't match
+    /// the prefix. Somebody has to look at it.
+    Broken { reason: String },
+}
+
+fn checkup(dogear: &Dogear) -> Checkup {
+    let prefix = normalize_prefix_matcher(&dogear.prefix);
+    let current = normalize_current_url(&dogear.current);
+    let matchable = match matchable_from_url(current) {
+        Ok(m) => m,
+        Err(e) => {
+            return Checkup::Broken {
+                reason: e.to_string(),
+            }
+        }
+    };
+    if !matchable.starts_with(prefix) {
+        return Checkup::Broken {
+            reason: format!("current URL doesn't match prefix {:?}", prefix),
+        };
+    }
+    if prefix == dogear.prefix && current == dogear.current {
+        Checkup::Fine
+    } else {
+        Checkup::Drifted {
+            prefix: prefix.to_string(),
+            current: current.to_string(),
+        }
+    }
+}
+
+/// Tallies from a normalize run.
+#[derive(Debug, Default, PartialEq)]
+struct NormalizeReport {
+    checked: usize,
+    drifted: usize,
+    broken: usize,
+    fixed: usize,
+    /// Drifted rows we couldn't fix, because the user already has a dogear
+    /// with the normalized prefix.
+    conflicts: usize,
+}
+
+impl NormalizeReport {
+    fn print(&self, fix: bool) {
+        println!(
+            "db normalize: checked {} dogears; {} out of date, {} broken.",
+            self.checked, self.drifted, self.broken
+        );
+        if fix {
+            println!(
+                "db normalize: fixed {}; {} conflicted with another dogear and were left alone.",
+                self.fixed, self.conflicts
+            );
+        } else if self.drifted > 0 {
+            println!("db normalize: run again with --fix to rewrite the out-of-date ones.");
+        }
+    }
+}
+
+/// Re-run the normalization rules over every dogear, print a line about
+/// each one that comes out different (or doesn't hold together at all),
+/// and with `fix`, rewrite the ones we can. Broken rows are only reported,
+/// since there's no telling what they were supposed to be.
+async fn normalize(db: &Db, fix: bool) -> anyhow::Result<NormalizeReport> {
+    let dogears = db.dogears().list_everyones().await?;
+    let mut report = NormalizeReport {
+        checked: dogears.len(),
+        ..Default::default()
+    };
+    for dogear in &dogears {
+        let label = format!("dogear {} (user {})", dogear.id, dogear.user_id);
+        match checkup(dogear) {
+            Checkup::Fine => {}
+            Checkup::Broken { reason } => {
+                report.broken += 1;
+                println!("{}: needs a human: {}", label, reason);
+            }
+            Checkup::Drifted { prefix, current } => {
+                report.drifted += 1;
+                if prefix != dogear.prefix {
+                    println!("{}: prefix {:?} => {:?}", label, dogear.prefix, prefix);
+                }
+                if current != dogear.current {
+                    println!("{}: current {:?} => {:?}", label, dogear.current, current);
+                }
+                if !fix {
+                    continue;
+                }
+                match db
+                    .dogears()
+                    .set_location(dogear.id, &prefix, &current)
+                    .await
+                {
+                    Ok(()) => report.fixed += 1,
+                    Err(MixedError::User(e)) => {
+                        report.conflicts += 1;
+                        println!("{}: couldn't fix: {}", label, e);
+                    }
+                    Err(MixedError::Server(e)) => return Err(e.into()),
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Whether `prefix` is `root` plus a little more, at a word boundary.
+/// `example.com/comic` and `example.com/comics` are probably two different
+/// things, even though one does technically match the other's pages.
+fn extends(prefix: &str, root: &str) -> bool {
+    let Some(rest) = prefix.strip_prefix(root) else {
+        return false;
+    };
+    let boundary = |c: char| !c.is_alphanumeric();
+    !rest.is_empty() && (rest.starts_with(boundary) || root.ends_with(boundary))
+}
+
+/// Sort one user's dogears into bunches that overlap: each bunch is led by
+/// the shortest prefix, followed by everything that extends it. Dogears
+/// that don't overlap anything are left out.
+fn overlap_groups(mut dogears: Vec<Dogear>) -> Vec<Vec<Dogear>> {
+    dogears.sort_by(|a, b| (a.prefix.len(), &a.prefix).cmp(&(b.prefix.len(), &b.prefix)));
+    let mut groups: Vec<Vec<Dogear>> = Vec::new();
+    for dogear in dogears {
+        match groups
+            .iter_mut()
+            .find(|g| extends(&dogear.prefix, &g[0].prefix))
+        {
+            Some(group) => group.push(dogear),
+            None => groups.push(vec![dogear]),
+        }
+    }
+    groups.retain(|g| g.len() > 1);
+    groups
+}
+
+/// Tallies from a dedupe run.
+#[derive(Debug, Default, PartialEq)]
+struct DedupeReport {
+    found: usize,
+    merged: usize,
+}
+
+/// Find a user's overlapping dogears, print each bunch, and merge the ones
+/// `decide` says yes to. See `Dogears::merge` for what a merge keeps.
+async fn dedupe(
+    db: &Db,
+    user_id: i64,
+    mut decide: impl FnMut(&[Dogear]) -> anyhow::Result<bool>,
+) -> anyhow::Result<DedupeReport> {
+    let dogears = db
+        .dogears()
+        .list_everyones()
+        .await?
+        .into_iter()
+        .filter(|d| d.user_id == user_id)
+        .collect();
+    let groups = overlap_groups(dogears);
+    let mut report = DedupeReport {
+        found: groups.len(),
+        ..Default::default()
+    };
+    for group in &groups {
+        println!();
+        for dogear in group {
+            println!(
+                "dogear {}: {} => {} (last read {})",
+                dogear.id,
+                dogear.prefix,
+                dogear.current,
+                dogear.updated.format(SHORT_DATE)?
+            );
+        }
+        if !decide(group)? {
+            continue;
+        }
+        let others: Vec<i64> = group[1..].iter().map(|d| d.id).collect();
+        if let Some(merged) = db.dogears().merge(user_id, group[0].id, &others).await? {
+            report.merged += 1;
+            println!(
+                "merged into dogear {}, now at {}",
+                merged.id, merged.current
+            );
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::StubRange;
+
+    /// Make a dogear, then overwrite it with whatever old rules (or hand
+    /// edits) might have left behind. set_location doesn't normalize
+    /// anything, which is handy here.
+    async fn plant(db: &Db, user_id: i64, prefix: &str, current: &str) -> i64 {
+        let placeholder = format!("example.com/placeholder/{}", prefix);
+        let dogear = db
+            .dogears()
+            .create(
+                user_id,
+                &placeholder,
+                &format!("https://{}/1", &placeholder),
+                None,
+            )
+            .await
+            .unwrap();
+        db.dogears()
+            .set_location(dogear.id, prefix, current)
+            .await
+            .unwrap();
+        dogear.id
+    }
+
+    #[tokio::test]
+    async fn migration_backups() {
+        use tokio_util::task::TaskTracker;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_file = dir.path().join("dogs.db");
+        std::fs::File::create(&db_file).unwrap();
+        let pool = crate::db_pool(&db_file, 1, None).await.unwrap();
+        let db = Db::new(pool.clone(), pool, TaskTracker::new());
+        let mut config = DogConfig::test_config().unwrap();
+        config.db_file = db_file.clone();
+        config.migration_backup_dir = dir.path().join("backups");
+
+        // A fresh file has everything pending, so it gets a snapshot.
+        let backup = backup_before_migrate(&db, &config)
+            .await
+            .unwrap()
+            .expect("should have backed up");
+        assert!(backup.is_file());
+        assert_eq!(backup.parent(), Some(config.migration_backup_dir.as_path()));
+        assert!(backup
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("dogs-premigrate-"));
+
+        // Once it's migrated, there's nothing to back up for.
+        db.migrations().run().await.unwrap();
+        assert!(backup_before_migrate(&db, &config).await.unwrap().is_none());
+
+        // The copy's a whole working db, and won't clobber anything.
+        db.users().create("copied", "pass", None).await.unwrap();
+        let copy = dir.path().join("copy.db");
+        db.migrations().backup_into(&copy).await.unwrap();
+        assert!(db.migrations().backup_into(&copy).await.is_err());
+        db.close().await;
+        let pool = crate::db_pool(&copy, 1, None).await.unwrap();
+        let copied = Db::new(pool.clone(), pool, TaskTracker::new());
+        assert_eq!(copied.migrations().pending().await.unwrap(), 0);
+        assert!(copied.users().by_name("copied").await.unwrap().is_some());
+        copied.close().await;
+
+        let command = restore_command(Path::new("/b/dogs-premigrate.db"), Path::new("/d/dogs.db"));
+        assert_eq!(
+            command,
+            r#"cp "/b/dogs-premigrate.db" "/d/dogs.db" && rm -f "/d/dogs.db-wal" "/d/dogs.db-shm""#
+        );
+    }
+
+    #[tokio::test]
+    async fn normalize_reports_then_fixes() {
+        let db = Db::new_test_db().await;
+        let user = db.users().create("user", "pass", None).await.unwrap();
+        db.dogears()
+            .create(
+                user.id,
+                "example.com/fine",
+                "https://example.com/fine/1",
+                None,
+            )
+            .await
+            .unwrap();
+        let drifted = plant(
+            &db,
+            user.id,
+            "www.example.com/comic.",
+            " https://example.com/comic/2\n",
+        )
+        .await;
+        let broken = plant(
+            &db,
+            user.id,
+            "example.com/serial",
+            "https://example.com/elsewhere/3",
+        )
+        .await;
+        // Normalizes to the same prefix as the fine one, so it can't be fixed.
+        let conflicted = plant(
+            &db,
+            user.id,
+            "m.example.com/fine",
+            "https://example.com/fine/2",
+        )
+        .await;
+        let get = |id: i64| {
+            let db = &db;
+            async move {
+                db.dogears()
+                    .list_everyones()
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .find(|d| d.id == id)
+                    .unwrap()
+            }
+        };
+
+        // Report only: nothing changes
+        let report = normalize(&db, false).await.unwrap();
+        assert_eq!(
+            report,
+            NormalizeReport {
+                checked: 4,
+                drifted: 2,
+                broken: 1,
+                fixed: 0,
+                conflicts: 0,
+            }
+        );
+        assert_eq!(get(drifted).await.prefix, "www.example.com/comic.");
+
+        // Fix: the fixable one gets fixed, the rest get left alone
+        let report = normalize(&db, true).await.unwrap();
+        assert_eq!((report.fixed, report.conflicts), (1, 1));
+        let fixed = get(drifted).await;
+        assert_eq!(fixed.prefix, "example.com/comic");
+        assert_eq!(fixed.current, "https://example.com/comic/2");
+        assert_eq!(get(broken).await.current, "https://example.com/elsewhere/3");
+        assert_eq!(get(conflicted).await.prefix, "m.example.com/fine");
+
+        // And a second run only finds the leftovers
+        let report = normalize(&db, true).await.unwrap();
+        assert_eq!((report.drifted, report.broken, report.fixed), (1, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn create_user_checks_the_policy() {
+        let db = Db::new_test_db().await;
+        let policy = PasswordPolicy {
+            check_breached: true,
+            ..Default::default()
+        };
+        let pwned = PwnedChecker::with_source(StubRange::new(&["vivid-Otter-lamp-97"]));
+        let args = CreateUserArgs {
+            username: " cli_person ".to_string(),
+            email: Some("cli@example.com".to_string()),
+        };
+        for (password, again) in [
+            ("password1", "password1"),
+            ("cli_person1", "cli_person1"),
+            (
+                "correct horse battery staple",
+                "correct horse battery stable",
+            ),
+            ("vivid-Otter-lamp-97", "vivid-Otter-lamp-97"),
+        ] {
+            assert!(create_user(
+                &db,
+                &policy,
+                UsernamePolicy::Ascii,
+                &pwned,
+                &args,
+                password,
+                again
+            )
+            .await
+            .is_err());
+        }
+        assert!(db.users().by_name("cli_person").await.unwrap().is_none());
+
+        let pw = "correct horse battery staple";
+        let user = create_user(&db, &policy, UsernamePolicy::Ascii, &pwned, &args, pw, pw)
+            .await
+            .unwrap();
+        assert_eq!(user.username, "cli_person");
+        assert_eq!(user.email.as_deref(), Some("cli@example.com"));
+    }
+
+    #[test]
+    fn extending_prefixes() {
+        assert!(extends("example.com/comic/book-2", "example.com/comic"));
+        assert!(extends("example.com/comic-extras", "example.com/comic"));
+        assert!(extends("example.com/comic/2", "example.com/comic/"));
+        assert!(!extends("example.com/comics", "example.com/comic"));
+        assert!(!extends("example.com/comic", "example.com/comic"));
+        assert!(!extends("example.com/serial", "example.com/comic"));
+    }
+
+    #[tokio::test]
+    async fn dedupe_merges_overlaps() {
+        let db = Db::new_test_db().await;
+        let user = db.users().create("user", "pass", None).await.unwrap();
+        let other_user = db.users().create("other", "pass", None).await.unwrap();
+        let make = |user_id: i64, prefix: &'static str, current: &'static str| {
+            let db = &db;
+            async move {
+                db.dogears()
+                    .create(user_id, prefix, current, None)
+                    .await
+                    .unwrap()
+            }
+        };
+        // The longer one comes first, so marking it doesn't move the other.
+        let dupe = make(
+            user.id,
+            "example.com/comic/book-2",
+            "https://example.com/comic/book-2/1",
+        )
+        .await;
+        db.dogears()
+            .update(
+                user.id,
+                "https://example.com/comic/book-2/3",
+                Some("good part"),
+            )
+            .await
+            .unwrap();
+        db.dogears()
+            .add_tags(dupe.id, &["manga".to_string()])
+            .await
+            .unwrap();
+        let root = make(user.id, "example.com/comic", "https://example.com/comic/5").await;
+        sqlx::query("UPDATE dogears SET updated = datetime('now', '-1 month') WHERE id = ?;")
+            .bind(root.id)
+            .execute(&db.write_pool)
+            .await
+            .unwrap();
+        make(
+            user.id,
+            "example.com/comics",
+            "https://example.com/comics/1",
+        )
+        .await;
+        make(
+            other_user.id,
+            "example.com/comic/book-2",
+            "https://example.com/comic/book-2/1",
+        )
+        .await;
+
+        // Saying no leaves everything alone.
+        let mut asked = Vec::new();
+        let report = dedupe(&db, user.id, |group| {
+            asked.push(group.iter().map(|d| d.id).collect::<Vec<_>>());
+            Ok(false)
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            report,
+            DedupeReport {
+                found: 1,
+                merged: 0
+            }
+        );
+        assert_eq!(asked, vec![vec![root.id, dupe.id]]);
+
+        // Saying yes folds the longer one into the shorter one, at the
+        // newer spot, with its history and tags.
+        let report = dedupe(&db, user.id, |_| Ok(true)).await.unwrap();
+        assert_eq!(
+            report,
+            DedupeReport {
+                found: 1,
+                merged: 1
+            }
+        );
+        let merged = db.dogears().by_id(root.id, user.id).await.unwrap().unwrap();
+        assert_eq!(merged.prefix, "example.com/comic");
+        assert_eq!(merged.current, "https://example.com/comic/book-2/3");
+        assert!(db
+            .dogears()
+            .by_id(dupe.id, user.id)
+            .await
+            .unwrap()
+            .is_none());
+        let tags = db.dogears().tags(user.id).await.unwrap();
+        assert_eq!(tags.get(&root.id), Some(&vec!["manga".to_string()]));
+        let notes = db.dogears().current_notes(user.id).await.unwrap();
+        assert_eq!(notes.get(&root.id).map(String::as_str), Some("good part"));
+        // Nobody else's dogears got touched.
+        assert_eq!(
+            db.dogears()
+                .list_everyones()
+                .await
+                .unwrap()
+                .iter()
+                .filter(|d| d.user_id == other_user.id)
+                .count(),
+            1
+        );
+
+        // And there's nothing left to do.
+        let report = dedupe(&db, user.id, |_| Ok(true)).await.unwrap();
+        assert_eq!(report, DedupeReport::default());
+    }
+}
@@ -0,0 +1,279 @@
+//! Moving an account between instances, without anyone having to download
+//! and re-upload an export file. The instance you're moving to hands out a
+//! one-time migration code (on its import page), and the instance you're
+//! leaving packs up your export and POSTs it straight to the new one's
+//! `/api/v1/migrate_in` along with that code. The payload is the same JSON
+//! lines as `/api/v1/export`, so the prefixes come across exactly as they
+//! were, with no guessing.
+
+use crate::backups::{export_all, EXPORT_CONTENT_TYPE};
+use crate::db::{Db, Dogear};
+use crate::import::IMPORT_MAX_ENTRIES;
+use crate::outbound::{self, validate_url, Schemes};
+use crate::util::UserError;
+use anyhow::bail;
+use axum::body::Bytes;
+use axum::BoxError;
+use eardogger_rs::api_types::{
+    ApiImportDogear, ApiImportReport, ApiMigrationProgress, RawJsonError,
+};
+use futures_util::Stream;
+use reqwest::header;
+use std::time::Duration;
+use tracing::info;
+use url::Url;
+
+/// Where an instance takes migrations, relative to its base URL.
+pub const MIGRATE_IN_PATH: &str = "api/v1/migrate_in";
+/// The header the migration code rides in. It's not an API token, so it
+/// stays out of `Authorization`.
+pub const MIGRATION_CODE_HEADER: &str = "eardogger-migration-code";
+/// How long the other instance gets to take a migration. Matches our own
+/// budget for bulk routes, which is presumably what theirs is too.
+const MIGRATION_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Read an export: JSON lines, one Dogear per line. An empty one is fine;
+/// that's just an account with nothing in it yet.
+pub fn parse_export(data: &str) -> Result<Vec<Dogear>, UserError> {
+    let mut dogears = Vec::new();
+    for line in data.lines().filter(|l| !l.trim().is_empty()) {
+        if dogears.len() == IMPORT_MAX_ENTRIES {
+            return Err(UserError::ImportTooBig {
+                max: IMPORT_MAX_ENTRIES,
+            });
+        }
+        let dogear: Dogear =
+            serde_json::from_str(line).map_err(|_| UserError::ImportUnreadable {
+                format: "Eardogger".to_string(),
+            })?;
+        dogears.push(dogear);
+    }
+    Ok(dogears)
+}
+
+/// Make dogears from an export, keeping their prefixes, and keeping them
+/// paused, public, or archived if they were. Dogears the user already has are left
+/// alone. It's all one transaction, so a failure partway through doesn't
+/// leave a half-moved account.
+#[tracing::instrument(skip(db, dogears))]
+pub async fn import_export(
+    db: &Db,
+    user_id: i64,
+    dogears: &[Dogear],
+) -> sqlx::Result<ApiImportReport> {
+    let mut report = ApiImportReport {
+        entries: dogears.len(),
+        ..Default::default()
+    };
+    let entries: Vec<ApiImportDogear> = dogears
+        .iter()
+        .map(|dogear| ApiImportDogear {
+            prefix: dogear.prefix.clone(),
+            current: dogear.current.clone(),
+            display_name: dogear.display_name.clone(),
+            paused: dogear.paused,
+            public: dogear.public,
+            archived: dogear.archived,
+        })
+        .collect();
+    let tx = db.transaction();
+    for result in tx.dogears().create_many(user_id, &entries).await? {
+        match result {
+            Ok(_) => report.created += 1,
+            Err(UserError::DogearExists { .. }) => report.existing += 1,
+            Err(_) => report.invalid += 1,
+        }
+    }
+    tx.commit().await?;
+    Ok(report)
+}
+
+/// Work out where to send a migration, given the base URL of the instance
+/// someone's moving to. Same rules as backup webhooks, since it's the same
+/// kind of trip.
+pub async fn migrate_in_url(target: &str, production: bool) -> Result<Url, String> {
+    let mut base = validate_url(target, Schemes::HttpsInProduction, production).await?;
+    // Join replaces the last path segment unless there's a trailing slash.
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    base.join(MIGRATE_IN_PATH).map_err(|e| e.to_string())
+}
+
+/// One account's move to another instance. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct Migration {
+    db: Db,
+    user_id: i64,
+    /// The other instance's migrate_in endpoint; see `migrate_in_url`.
+    target: Url,
+    code: String,
+    http: reqwest::Client,
+}
+
+/// Where a migration's at, between progress reports.
+enum Step {
+    Start,
+    Package,
+    Send(Vec<u8>),
+    Finished,
+}
+
+impl Migration {
+    pub fn new(
+        db: Db,
+        user_id: i64,
+        target: Url,
+        code: &str,
+        production: bool,
+    ) -> reqwest::Result<Self> {
+        let http = outbound::client(MIGRATION_TIMEOUT, 0, production)?;
+        Ok(Self {
+            db,
+            user_id,
+            target,
+            code: code.trim().to_string(),
+            http,
+        })
+    }
+
+    /// Do the move, as a stream of progress reports (JSON lines). Nothing
+    /// happens until something polls it, and it always ends with `done` or
+    /// `failed`, unless it gets dropped partway.
+    pub fn run(self) -> impl Stream<Item = Result<Bytes, BoxError>> + Send + 'static {
+        futures_util::stream::unfold(Step::Start, move |step| {
+            let migration = self.clone();
+            async move {
+                let (progress, next) = match step {
+                    Step::Start => (
+                        ApiMigrationProgress::Started {
+                            target: migration.target.to_string(),
+                        },
+                        Step::Package,
+                    ),
+                    Step::Package => match export_all(&migration.db, migration.user_id).await {
+                        Ok((data, dogears)) => (
+                            ApiMigrationProgress::Packaged {
+                                dogears,
+                                bytes: data.len(),
+                            },
+                            Step::Send(data),
+                        ),
+                        Err(e) => (
+                            ApiMigrationProgress::Failed {
+                                error: e.to_string(),
+                            },
+                            Step::Finished,
+                        ),
+                    },
+                    Step::Send(data) => match migration.send(data).await {
+                        Ok(report) => (ApiMigrationProgress::Done { report }, Step::Finished),
+                        Err(e) => {
+                            info!(instance = %migration.target, "migration didn't go through: {}", e);
+                            (
+                                ApiMigrationProgress::Failed {
+                                    error: e.to_string(),
+                                },
+                                Step::Finished,
+                            )
+                        }
+                    },
+                    Step::Finished => return None,
+                };
+                Some((progress_line(&progress), next))
+            }
+        })
+    }
+
+    /// Hand the export over, and find out what the other side made of it.
+    async fn send(&self, data: Vec<u8>) -> anyhow::Result<ApiImportReport> {
+        let resp = self
+            .http
+            .post(self.target.clone())
+            .header(header::CONTENT_TYPE, EXPORT_CONTENT_TYPE)
+            .header(MIGRATION_CODE_HEADER, &self.code)
+            .body(data)
+            .send()
+            .await?;
+        let status = resp.status();
+        if !status.is_success() {
+            // Another eardogger says what went wrong; anything else, we
+            // just pass along the status.
+            match resp.json::<RawJsonError>().await {
+                Ok(e) => bail!("the other instance answered {}: {}", status, e.error),
+                Err(_) => bail!("the other instance answered {}", status),
+            }
+        }
+        Ok(resp.json().await?)
+    }
+}
+
+fn progress_line(progress: &ApiMigrationProgress) -> Result<Bytes, BoxError> {
+    let mut line = serde_json::to_vec(progress)?;
+    line.push(b'\n');
+    Ok(Bytes::from(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn target_urls() {
+        let cases = [
+            (
+                "https://example.com",
+                "https://example.com/api/v1/migrate_in",
+            ),
+            (
+                "https://example.com/",
+                "https://example.com/api/v1/migrate_in",
+            ),
+            (
+                "https://example.com/dogears",
+                "https://example.com/dogears/api/v1/migrate_in",
+            ),
+            (
+                "https://example.com/dogears/",
+                "https://example.com/dogears/api/v1/migrate_in",
+            ),
+        ];
+        for (target, expected) in cases {
+            assert_eq!(
+                migrate_in_url(target, false).await.unwrap().as_str(),
+                expected
+            );
+        }
+        assert!(migrate_in_url("http://example.com", true).await.is_err());
+        assert!(migrate_in_url("http://localhost:3000", false).await.is_ok());
+        assert!(migrate_in_url("https://localhost:3000", true)
+            .await
+            .is_err());
+        assert!(migrate_in_url("https://10.0.0.7", true).await.is_err());
+        assert!(migrate_in_url("example.com", false).await.is_err());
+    }
+
+    #[test]
+    fn reading_exports() {
+        let data = r#"{"id":4,"user_id":1,"prefix":"example.com/comic","current":"https://example.com/comic/24","display_name":"Example Comic","updated":"2024-03-15T17:09:14.000000000Z","paused":true,"public":false}
+
+{"id":9,"user_id":1,"prefix":"example.com/serial","current":"https://example.com/serial/4","display_name":null,"updated":"2024-03-15T17:09:14.000000000Z"}
+"#;
+        let dogears = parse_export(data).unwrap();
+        assert_eq!(dogears.len(), 2);
+        assert!(dogears[0].paused);
+        // Older exports didn't have the flags.
+        assert!(!dogears[1].paused && !dogears[1].public);
+
+        assert!(parse_export("").unwrap().is_empty());
+        assert!(matches!(
+            parse_export("[1, 2, 3]"),
+            Err(UserError::ImportUnreadable { .. })
+        ));
+        let too_many = format!("{}\n", data.lines().next().unwrap()).repeat(IMPORT_MAX_ENTRIES + 1);
+        assert!(matches!(
+            parse_export(&too_many),
+            Err(UserError::ImportTooBig { .. })
+        ));
+    }
+}
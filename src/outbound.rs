@@ -0,0 +1,270 @@
+//! Requests to URLs that users hand us: backup and event webhooks, feeds,
+//! account migrations, and redirect chasing. They all build their clients
+//! and check their URLs here, so they all play by the same rules about
+//! where they're allowed to go.
+//!
+//! In production, that means nowhere private: no loopback, private-range,
+//! link-local, or unspecified addresses (or the handful of other reserved
+//! ranges), since otherwise anyone with an account could point the server
+//! at its own admin ports or the cloud metadata service and read the
+//! answers back out of an error message. URLs get checked when they're
+//! saved, and again at connect time, where the clients use a DNS resolver
+//! that drops private addresses and a redirect policy that won't hop to a
+//! private IP literal; so a hostname that starts pointing somewhere else
+//! after it passed inspection doesn't get anywhere either. Outside
+//! production, anything goes, since dev servers and tests live on
+//! localhost.
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use url::{Host, Url};
+
+/// What we call ourselves when we come knocking.
+pub const USER_AGENT: &str = concat!("eardogger-rs/", env!("CARGO_PKG_VERSION"));
+
+/// Which schemes a destination can use.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schemes {
+    /// http or https, always. For things that only fetch public stuff,
+    /// like feeds.
+    Any,
+    /// https in production, since what goes out is private (backups,
+    /// reading activity, migration codes).
+    HttpsInProduction,
+}
+
+/// Whether an address is somewhere on the public internet. (std's
+/// `is_global` would do, but it isn't stable.)
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_documentation()
+                || v4.is_multicast()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            let first = v6.segments()[0];
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                // Unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Whether a URL's host is one we can rule out without asking DNS: a
+/// private IP literal, or localhost by name.
+fn obviously_private(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Ipv4(ip)) => !is_public_ip(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => !is_public_ip(IpAddr::V6(ip)),
+        Some(Host::Domain(name)) => {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            name == "localhost" || name.ends_with(".localhost")
+        }
+        None => true,
+    }
+}
+
+/// Check the parts of a URL that don't need the network: it's http(s) with
+/// a host, it's https if it has to be, and (in production) its host isn't
+/// obviously private. `validate_url` does this and then asks DNS.
+pub fn check_url(url: &str, schemes: Schemes, production: bool) -> Result<Url, String> {
+    let Ok(parsed) = Url::parse(url.trim()) else {
+        return Err(format!("{:?} isn't a valid URL.", url));
+    };
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host().is_none() {
+        return Err(format!("{:?} has to be an http:// or https:// URL.", url));
+    }
+    if production && schemes == Schemes::HttpsInProduction && parsed.scheme() != "https" {
+        return Err(format!("{:?} has to be an https:// URL.", url));
+    }
+    if production && obviously_private(&parsed) {
+        return Err(format!("{:?} points somewhere private.", url));
+    }
+    Ok(parsed)
+}
+
+/// Check a URL someone wants us to send requests to. On top of
+/// `check_url`, in production this looks up the host and turns it down if
+/// any of its addresses are private (or if it doesn't have any).
+pub async fn validate_url(url: &str, schemes: Schemes, production: bool) -> Result<Url, String> {
+    let parsed = check_url(url, schemes, production)?;
+    if production {
+        if let Some(Host::Domain(name)) = parsed.host() {
+            let port = parsed.port_or_known_default().unwrap_or(443);
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name, port))
+                .await
+                .map_err(|_| format!("We couldn't look up {:?}.", name))?
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("We couldn't look up {:?}.", name));
+            }
+            if addrs.iter().any(|a| !is_public_ip(a.ip())) {
+                return Err(format!("{:?} points somewhere private.", url));
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+/// A DNS resolver that only hands reqwest public addresses, so the check
+/// happens on the lookup the connection actually uses.
+#[derive(Debug)]
+struct PublicOnly;
+
+impl Resolve for PublicOnly {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|a| is_public_ip(a.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public addresses", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// A reqwest client for talking to user-supplied URLs. With
+/// `max_redirects` at 0, redirects come back as-is instead of getting
+/// followed, which is what most callers want: the URL they gave us is the
+/// only place the request goes. In production, the client won't connect
+/// to private addresses, however it ends up at them.
+pub fn client(
+    timeout: Duration,
+    max_redirects: usize,
+    production: bool,
+) -> reqwest::Result<reqwest::Client> {
+    let policy = redirect::Policy::custom(move |attempt| {
+        if max_redirects == 0 {
+            attempt.stop()
+        } else if attempt.previous().len() >= max_redirects {
+            attempt.error("too many redirects")
+        } else if production && obviously_private(attempt.url()) {
+            attempt.error("redirected somewhere private")
+        } else {
+            attempt.follow()
+        }
+    });
+    let mut builder = reqwest::Client::builder()
+        .redirect(policy)
+        .timeout(timeout)
+        .user_agent(USER_AGENT);
+    if production {
+        builder = builder.dns_resolver(Arc::new(PublicOnly));
+    }
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_ips() {
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(private.parse().unwrap()), "{}", private);
+        }
+        for public in ["93.184.215.14", "1.1.1.1", "2606:4700::1111"] {
+            assert!(is_public_ip(public.parse().unwrap()), "{}", public);
+        }
+    }
+
+    #[test]
+    fn checking_urls() {
+        use Schemes::*;
+        assert!(check_url("https://example.com/hook", HttpsInProduction, true).is_ok());
+        assert!(check_url("http://example.com/hook", HttpsInProduction, true).is_err());
+        assert!(check_url("http://example.com/feed", Any, true).is_ok());
+        assert!(check_url("ftp://example.com/hook", Any, false).is_err());
+        assert!(check_url("example.com/hook", Any, false).is_err());
+        // Private hosts are only a problem in production.
+        for private in [
+            "https://127.0.0.1/hook",
+            "https://169.254.169.254/latest/meta-data/",
+            "https://10.0.0.5:8080/",
+            "https://[::1]/hook",
+            "https://localhost/hook",
+            "https://api.localhost./hook",
+        ] {
+            assert!(check_url(private, Any, true).is_err(), "{}", private);
+            assert!(check_url(private, Any, false).is_ok(), "{}", private);
+        }
+    }
+
+    #[tokio::test]
+    async fn validating_urls() {
+        // No DNS needed for these, so this works offline.
+        assert!(validate_url(
+            "https://93.184.215.14/hook",
+            Schemes::HttpsInProduction,
+            true
+        )
+        .await
+        .is_ok());
+        assert!(validate_url("https://127.0.0.1/hook", Schemes::Any, true)
+            .await
+            .is_err());
+        assert!(validate_url(
+            "http://localhost:9999/hook",
+            Schemes::HttpsInProduction,
+            false
+        )
+        .await
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn clients_stay_public() {
+        use axum::{routing::get, Router};
+        use tokio::net::TcpListener;
+
+        let app = Router::new().route("/", get(|| async { "hi" }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        // Outside production, localhost is fine...
+        let dev = client(Duration::from_secs(5), 0, false).unwrap();
+        let url = format!("http://localhost:{}/", port);
+        assert!(dev.get(&url).send().await.is_ok());
+        // ...but in production, the resolver won't hand it over, even if
+        // the URL got past validation somehow.
+        let prod = client(Duration::from_secs(5), 0, true).unwrap();
+        assert!(prod.get(&url).send().await.is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! `eardogger-rs self package`: build release binaries and bundle each one
+//! up with everything a deployment needs, one tarball per target. This is
+//! the old release.sh plus the bits of the deploy checklist that were
+//! "remember to also copy...". It's a dev tool, so it runs from a source
+//! checkout and shells out to cargo and tar like a person would.
+
+use crate::args::PackageArgs;
+use anyhow::{anyhow, bail, Context};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Plain files from the checkout that go in every bundle, next to the
+/// binary. The htaccess and systemd files are templates for fcgi and http
+/// mode respectively; you only need whichever one you're using.
+pub const BUNDLE_FILES: &[&str] = &[
+    "README.md",
+    "VERSION.txt",
+    "eardogger.example.toml",
+    "htaccess-example",
+    "systemd-example.service",
+];
+
+/// Directories from the checkout that go in every bundle, whole.
+pub const BUNDLE_DIRS: &[&str] = &["public", "migrations"];
+
+pub fn run(args: &PackageArgs) -> anyhow::Result<()> {
+    let source = std::env::current_dir()?;
+    if !source.join("Cargo.toml").is_file() || !source.join("migrations").is_dir() {
+        bail!("self package has to run from the root of an eardogger-rs source checkout.");
+    }
+    for target in &args.targets {
+        if !args.no_build {
+            build(target, args.features.as_deref())?;
+        }
+        let binary = source
+            .join("target")
+            .join(target)
+            .join("release")
+            .join(binary_name(target));
+        if !binary.is_file() {
+            bail!(
+                "no release binary at {:?}; build it first, or drop --no-build.",
+                binary
+            );
+        }
+        // build.rs rewrites this on every build, so it matches the binary.
+        let version = fs::read_to_string(source.join("VERSION.txt"))?;
+        let name = bundle_name(target, &version);
+        let staging = args.out.join(&name);
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        stage(&source, &binary, &staging)?;
+        let tarball = args.out.join(format!("{}.tar.gz", name));
+        tar(&staging, &tarball)?;
+        fs::remove_dir_all(&staging)?;
+        println!("self package: wrote {:?}", tarball);
+    }
+    Ok(())
+}
+
+/// `cargo build --release` for one target. Cross-compiling needs the right
+/// linker, which cargo takes from `CARGO_TARGET_<TRIPLE>_LINKER` in the env;
+/// we pass the env straight through.
+fn build(target: &str, features: Option<&str>) -> anyhow::Result<()> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut cmd = Command::new(cargo);
+    cmd.args(["build", "--release", "--target", target]);
+    if let Some(features) = features {
+        cmd.args(["--features", features]);
+    }
+    println!("self package: building for {}", target);
+    let status = cmd.status().context("couldn't run cargo")?;
+    if !status.success() {
+        bail!("cargo build for {} failed ({}).", target, status);
+    }
+    Ok(())
+}
+
+/// Windows wants its .exe.
+pub fn binary_name(target: &str) -> &'static str {
+    if target.contains("windows") {
+        "eardogger-rs.exe"
+    } else {
+        "eardogger-rs"
+    }
+}
+
+/// Like `eardogger-rs-1a2b3c4d-x86_64-unknown-linux-gnu`, from the target
+/// and the contents of VERSION.txt (whose first line is the commit sha).
+pub fn bundle_name(target: &str, version: &str) -> String {
+    let sha = version.lines().next().unwrap_or("").trim();
+    let short = sha.get(..8).unwrap_or(sha);
+    if short.is_empty() {
+        format!("eardogger-rs-{}", target)
+    } else {
+        format!("eardogger-rs-{}-{}", short, target)
+    }
+}
+
+/// Lay out a bundle in `staging`: the binary, the files, and the dirs, all
+/// at the top level, so it can be untarred right over an existing data dir.
+pub fn stage(source: &Path, binary: &Path, staging: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(staging)?;
+    let binary_dest = staging.join(
+        binary
+            .file_name()
+            .ok_or_else(|| anyhow!("{:?} isn't a file", binary))?,
+    );
+    fs::copy(binary, binary_dest).with_context(|| format!("couldn't copy {:?}", binary))?;
+    for file in BUNDLE_FILES {
+        fs::copy(source.join(file), staging.join(file))
+            .with_context(|| format!("couldn't copy {}", file))?;
+    }
+    for dir in BUNDLE_DIRS {
+        copy_dir(&source.join(dir), &staging.join(dir))?;
+    }
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from).with_context(|| format!("couldn't read {:?}", from))? {
+        let entry = entry?;
+        let dest: PathBuf = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dest)?;
+        } else {
+            fs::copy(entry.path(), dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// tar's -C flag changes to a dir before processing remaining files.
+fn tar(staging: &Path, tarball: &Path) -> anyhow::Result<()> {
+    let status = Command::new("tar")
+        .arg("-czf")
+        .arg(tarball)
+        .arg("-C")
+        .arg(staging)
+        .arg(".")
+        .status()
+        .context("couldn't run tar")?;
+    if !status.success() {
+        bail!("tar failed ({}).", status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let version = "1a2b3c4d5e6f\nThu Oct 15 12:00:00 PDT 2026\n";
+        assert_eq!(
+            bundle_name("x86_64-unknown-linux-gnu", version),
+            "eardogger-rs-1a2b3c4d-x86_64-unknown-linux-gnu"
+        );
+        assert_eq!(
+            bundle_name("aarch64-apple-darwin", ""),
+            "eardogger-rs-aarch64-apple-darwin"
+        );
+        assert_eq!(binary_name("x86_64-pc-windows-msvc"), "eardogger-rs.exe");
+        assert_eq!(binary_name("aarch64-unknown-linux-gnu"), "eardogger-rs");
+    }
+
+    #[test]
+    fn stage_has_everything() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("eardogger-rs");
+        fs::write(&binary, "not really a binary").unwrap();
+        let staging = dir.path().join("bundle");
+        let source = std::env::current_dir().unwrap();
+        stage(&source, &binary, &staging).unwrap();
+
+        assert!(staging.join("eardogger-rs").is_file());
+        for file in BUNDLE_FILES {
+            assert!(staging.join(file).is_file(), "missing {}", file);
+        }
+        // Every migration made it.
+        let migrations = fs::read_dir(source.join("migrations")).unwrap().count();
+        assert_eq!(
+            fs::read_dir(staging.join("migrations")).unwrap().count(),
+            migrations
+        );
+        assert!(staging.join("public").is_dir());
+    }
+}
@@ -0,0 +1,89 @@
+//! Picking up config changes without a restart. On SIGHUP, we re-read the
+//! config file and swap in the parts that are safe to change under a running
+//! server: the log filter and the feature flags. Everything else (ports,
+//! paths, the db, mail, limits...) got baked into something at startup, so
+//! it still takes a restart. (Default page sizes are per-user prefs, not
+//! config, so there's nothing to reload for those.)
+//!
+//! A config file that won't load changes nothing, so a typo can't take
+//! down a running server; the log says what was wrong with it.
+
+use crate::config::{DogConfig, LiveConfig, LiveSettings};
+use std::path::PathBuf;
+use tracing::{error, info};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// The handle for swapping out the log filter, from main's subscriber setup.
+pub type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Waits for SIGHUPs and reloads the config each time, forever. This can be
+/// spawned as an independent task, like the shutdown listener.
+#[cfg(unix)]
+#[tracing::instrument(skip_all)]
+pub async fn reload_on_hangup(path: PathBuf, live: LiveConfig, filter: FilterHandle) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!(
+                "couldn't establish SIGHUP signal listener ({}); config reloads won't work",
+                e
+            );
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("received SIGHUP, reloading config from {:?}", &path);
+        match DogConfig::reread(&path) {
+            Ok(config) => {
+                if let Err(e) = apply(&config, &live, &filter) {
+                    error!("couldn't apply the reloaded config: {:#}", e);
+                }
+            }
+            Err(e) => error!("config reload failed, so nothing changed: {:#}", e),
+        }
+    }
+}
+
+/// Swap in a freshly loaded config's live settings, and log what changed.
+pub fn apply(config: &DogConfig, live: &LiveConfig, filter: &FilterHandle) -> anyhow::Result<()> {
+    let new = LiveSettings::from_config(config);
+    // Validation already tried this filter, so this is just a formality.
+    filter.reload(EnvFilter::try_new(&new.log_filter)?)?;
+    let old = live.replace(new.clone());
+    if old == new {
+        info!("config reloaded; nothing that can change live did");
+        return Ok(());
+    }
+    if old.log_filter != new.log_filter {
+        info!(old = %old.log_filter, new = %new.log_filter, "config reloaded: log filter changed");
+    }
+    if old.features != new.features {
+        info!(features = ?new.features, "config reloaded: feature flags changed");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn apply_swaps_live_settings() {
+        let mut config = DogConfig::test_config().unwrap();
+        let live = LiveConfig::new(&config);
+        let (_layer, filter) = reload::Layer::<EnvFilter, Registry>::new(EnvFilter::new("info"));
+        assert!(live.features().is_empty());
+
+        config.log.filter = "debug".to_string();
+        config.features = BTreeMap::from([("queue_mode".to_string(), 50)]);
+        apply(&config, &live, &filter).unwrap();
+        let settings = live.get();
+        assert_eq!(settings.log_filter, "debug");
+        assert_eq!(settings.features.get("queue_mode"), Some(&50));
+        // Clones see it too
+        assert_eq!(live.clone().features().get("queue_mode"), Some(&50));
+        assert_eq!(filter.with_current(|f| f.to_string()).unwrap(), "debug");
+    }
+}
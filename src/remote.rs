@@ -0,0 +1,285 @@
+//! The `mark`, `resume`, and `client-login` commands: a little terminal
+//! client for your own account, for people who live in a shell (and for
+//! poking at a fresh deployment). They're built on the typed API client, so
+//! they need the `client` feature. The instance URL and token come from
+//! `eardogger/client.toml` in the XDG config dir (`~/.config` by default),
+//! which `client-login` writes, or from `--instance` and `--token`.
+
+use crate::args::{ClientLoginArgs, Command, MarkArgs, RemoteArgs};
+use crate::util::matchable_from_url;
+use anyhow::{anyhow, bail, Context};
+use eardogger_rs::api_types::{ApiCreatePayload, Dogear};
+use eardogger_rs::client::Client;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// How many dogears to fetch at a time when looking through all of them.
+/// (The server's max page size.)
+const LIST_PAGE_SIZE: u32 = 500;
+
+/// What `client-login` saves.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ClientSettings {
+    pub instance: Option<String>,
+    pub token: Option<String>,
+}
+
+impl ClientSettings {
+    /// Where the settings live: `$XDG_CONFIG_HOME/eardogger/client.toml`,
+    /// falling back to `~/.config` (or `%APPDATA%` on Windows).
+    pub fn path() -> anyhow::Result<PathBuf> {
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                if cfg!(windows) {
+                    std::env::var_os("APPDATA").map(PathBuf::from)
+                } else {
+                    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+                }
+            })
+            .ok_or_else(|| anyhow!("can't find a config directory; set XDG_CONFIG_HOME"))?;
+        Ok(base.join("eardogger").join("client.toml"))
+    }
+
+    /// Read the saved settings. No file is fine; that's just nothing saved.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).with_context(|| format!("couldn't read {:?}", path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the settings, making their directory if need be. There's a
+    /// token in there, so on unix, only you get to read it.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(path)?
+            .write_all(toml::to_string(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// An API client for the saved instance and token, with any command
+    /// line flags taking their place.
+    pub fn client(self, remote: &RemoteArgs) -> anyhow::Result<Client> {
+        let instance = remote.instance.clone().or(self.instance).ok_or_else(|| {
+            anyhow!("no instance to talk to; pass --instance or run `client-login` first")
+        })?;
+        let token = remote
+            .token
+            .clone()
+            .or(self.token)
+            .ok_or_else(|| anyhow!("no API token; pass --token or run `client-login` first"))?;
+        Ok(Client::new(&instance, &token)?)
+    }
+}
+
+/// Do one of the client commands.
+pub async fn run(command: Command) -> anyhow::Result<()> {
+    let path = ClientSettings::path()?;
+    match command {
+        Command::Mark(args) => {
+            let client = ClientSettings::load(&path)?.client(&args.remote)?;
+            for dogear in mark(&client, &args).await? {
+                let name = dogear.display_name.as_deref().unwrap_or(&dogear.prefix);
+                if dogear.paused {
+                    println!("Skipped {} (it's paused)", name);
+                } else {
+                    println!("Marked {} at {}", name, &dogear.current);
+                }
+            }
+        }
+        Command::Resume(args) => {
+            let client = ClientSettings::load(&path)?.client(&args.remote)?;
+            let Some(dogear) = find_dogear(&client, &args.url).await? else {
+                bail!("none of your dogears match {}", &args.url);
+            };
+            println!("{}", &dogear.current);
+            if args.open {
+                open_in_browser(&dogear.current)?;
+            }
+        }
+        Command::ClientLogin(args) => client_login(&path, &args).await?,
+        other => bail!("{:?} isn't a client command", other),
+    }
+    Ok(())
+}
+
+/// Mark your spot, the same as the bookmarklet. If nothing matches and
+/// `--create` is on, make a new dogear there instead.
+pub async fn mark(client: &Client, args: &MarkArgs) -> anyhow::Result<Vec<Dogear>> {
+    let err = match client
+        .update_with_note(&args.url, args.note.as_deref())
+        .await
+    {
+        Ok(dogears) => return Ok(dogears),
+        Err(e) => e,
+    };
+    if err.status() != Some(StatusCode::NOT_FOUND) {
+        return Err(err.into());
+    }
+    if !args.create {
+        bail!(
+            "none of your dogears match {}; pass --create to make one",
+            &args.url
+        );
+    }
+    let prefix = match &args.prefix {
+        Some(prefix) => prefix.clone(),
+        None => client.suggest_prefix(&args.url).await?,
+    };
+    let payload = ApiCreatePayload {
+        prefix,
+        current: args.url.clone(),
+        display_name: None,
+    };
+    let dogear = client.create(&payload).await?;
+    // Creating doesn't take a note, but marking the same spot again does.
+    if args.note.is_some() {
+        return Ok(client
+            .update_with_note(&args.url, args.note.as_deref())
+            .await?);
+    }
+    Ok(vec![dogear])
+}
+
+/// Find the dogear for a URL the same way the server's /resume does: the
+/// longest prefix that matches. There's no API for that, so this reads the
+/// whole list, which needs a manage token.
+pub async fn find_dogear(client: &Client, url: &str) -> anyhow::Result<Option<Dogear>> {
+    // The server matches with sqlite's LIKE, which ignores ASCII case.
+    let matchable = matchable_from_url(url)?.to_ascii_lowercase();
+    let mut best: Option<Dogear> = None;
+    let mut page = 1;
+    loop {
+        let list = client.list(page, LIST_PAGE_SIZE).await?;
+        for dogear in list.data {
+            let longer = best
+                .as_ref()
+                .map_or(true, |b| dogear.prefix.len() > b.prefix.len());
+            if longer && matchable.starts_with(&dogear.prefix.to_ascii_lowercase()) {
+                best = Some(dogear);
+            }
+        }
+        match list.meta.pagination.next_page {
+            Some(next) => page = next,
+            None => return Ok(best),
+        }
+    }
+}
+
+/// Check the instance is really an Eardogger, then save it and a token.
+async fn client_login(path: &Path, args: &ClientLoginArgs) -> anyhow::Result<()> {
+    let token = read_token()?;
+    if token.is_empty() {
+        bail!("no token; make one on your account page and paste it in");
+    }
+    let client = Client::new(&args.instance, &token)?;
+    let metadata = client
+        .instance_metadata()
+        .await
+        .with_context(|| format!("couldn't get instance info from {}", client.base_url()))?;
+    let settings = ClientSettings {
+        instance: Some(client.base_url().to_string()),
+        token: Some(token),
+    };
+    settings.save(path)?;
+    println!(
+        "Saved {} ({} {}) to {:?}",
+        client.base_url(),
+        &metadata.software,
+        &metadata.version,
+        path
+    );
+    Ok(())
+}
+
+/// Read a token from stdin, asking for it if there's someone to ask.
+fn read_token() -> anyhow::Result<String> {
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        eprint!("API token: ");
+        std::io::stderr().flush()?;
+    }
+    let mut line = String::new();
+    stdin.lock().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+/// Hand a URL to the desktop's default browser.
+fn open_in_browser(url: &str) -> anyhow::Result<()> {
+    let mut opener = if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else if cfg!(windows) {
+        let mut cmd = std::process::Command::new("cmd");
+        cmd.args(["/C", "start", ""]);
+        cmd
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    let status = opener
+        .arg(url)
+        .status()
+        .context("couldn't start a browser")?;
+    if !status.success() {
+        bail!("the browser opener quit with {}", status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("eardogger").join("client.toml");
+
+        // Nothing saved yet: fine, but not enough to make a client.
+        let empty = ClientSettings::load(&path).unwrap();
+        assert_eq!(empty, ClientSettings::default());
+        let no_flags = RemoteArgs {
+            instance: None,
+            token: None,
+        };
+        assert!(empty.client(&no_flags).is_err());
+
+        let saved = ClientSettings {
+            instance: Some("https://example.com/dogs/".to_string()),
+            token: Some("eardoggerv1.blah".to_string()),
+        };
+        saved.save(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let loaded = ClientSettings::load(&path).unwrap();
+        assert_eq!(loaded, saved);
+        let client = loaded.client(&no_flags).unwrap();
+        assert_eq!(client.base_url().as_str(), "https://example.com/dogs/");
+
+        // Flags win.
+        let flags = RemoteArgs {
+            instance: Some("https://eardogger.com".to_string()),
+            token: None,
+        };
+        let client = ClientSettings::load(&path).unwrap().client(&flags).unwrap();
+        assert_eq!(client.base_url().as_str(), "https://eardogger.com/");
+    }
+}
@@ -0,0 +1,77 @@
+//! Listening for the outside world to tell us to stop. Ctrl-c works
+//! everywhere; beyond that, each platform has its own way of asking
+//! politely, so those bits are behind cfgs.
+
+use tokio::select;
+use tokio::signal::ctrl_c;
+use tokio_util::sync::CancellationToken;
+#[cfg(any(unix, windows))]
+use tracing::error;
+use tracing::info;
+
+/// Waits until the program receives an external instruction to terminate,
+/// then cancels the provided CancellationToken. This can be spawned as an
+/// independent task, and then the main logic can just await the
+/// cancellation token.
+///
+/// - Everywhere: ctrl-c (SIGINT on unix).
+/// - Unix: SIGTERM (aka `kill`/`killall` with no flags).
+/// - Windows: ctrl-break, closing the console window, and system shutdown.
+///   (Windows only gives us a few seconds after a close or shutdown before
+///   it kills the process, but that's usually plenty.)
+#[tracing::instrument(skip_all)]
+pub async fn cancel_on_terminate(cancel_token: CancellationToken) {
+    // Wait indefinitely until we hear a shutdown signal.
+    select! {
+        _ = ctrl_c() => {
+            // don't care if Ok or Err
+            info!("received ctrl-c, starting shutdown");
+        },
+        name = platform_terminate() => {
+            info!("received {}, starting shutdown", name);
+        },
+    }
+    // Ok, spread the news
+    cancel_token.cancel();
+}
+
+/// Resolves when the platform-specific termination signal arrives, with a
+/// name for the logs. If we can't listen for it, resolves immediately so the
+/// app shuts down instead of becoming unkillable.
+#[cfg(unix)]
+async fn platform_terminate() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            // don't care if Some or None
+            terminate.recv().await;
+            "SIGTERM"
+        }
+        Err(e) => {
+            error!("couldn't even establish SIGTERM signal listener ({}); taking my ball and going home", e);
+            "no SIGTERM listener"
+        }
+    }
+}
+
+#[cfg(windows)]
+async fn platform_terminate() -> &'static str {
+    use tokio::signal::windows::{ctrl_break, ctrl_close, ctrl_shutdown};
+    let (Ok(mut brk), Ok(mut close), Ok(mut shutdown)) =
+        (ctrl_break(), ctrl_close(), ctrl_shutdown())
+    else {
+        error!("couldn't establish console event listeners; taking my ball and going home");
+        return "no console event listeners";
+    };
+    select! {
+        _ = brk.recv() => "ctrl-break",
+        _ = close.recv() => "console close",
+        _ = shutdown.recv() => "system shutdown",
+    }
+}
+
+/// Somewhere exotic: ctrl-c is all we get.
+#[cfg(not(any(unix, windows)))]
+async fn platform_terminate() -> &'static str {
+    std::future::pending().await
+}
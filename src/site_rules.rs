@@ -0,0 +1,317 @@
+//! Site rules bundles: the instance's site knowledge (see `db::SiteRule`)
+//! as a signed JSON file, so a curated set of rules can go from one
+//! instance to another without anybody poking at the db by hand.
+//!
+//! Bundles are signed with ed25519. Each instance's signing key is derived
+//! from its cookie key, so there's no extra key file to manage, and the
+//! public half rides along in every export. An instance only imports
+//! bundles signed by its own key or one listed in `site_rules.trusted_keys`,
+//! and the signature covers the rules exactly as serialized, so any edit
+//! after signing breaks it.
+
+use crate::db::{Db, SiteRule};
+use crate::util::{normalize_prefix_matcher, MixedError, UserError};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tower_cookies::Key;
+
+/// The bundle format we write and read.
+pub const BUNDLE_VERSION: u32 = 1;
+/// Rules deeper than this are somebody's typo.
+const MAX_SEGMENTS: i64 = 10;
+/// The most characters of a note we'll keep.
+const NOTE_MAX_LENGTH: usize = 200;
+
+/// One rule, as it appears in a bundle.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BundleRule {
+    pub host: String,
+    pub segments: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl From<SiteRule> for BundleRule {
+    fn from(rule: SiteRule) -> Self {
+        Self {
+            host: rule.host,
+            segments: rule.segments,
+            note: rule.note,
+        }
+    }
+}
+
+/// The part of a bundle the signature covers.
+#[derive(Serialize)]
+struct SignedPart<'a> {
+    version: u32,
+    rules: &'a [BundleRule],
+}
+
+impl SignedPart<'_> {
+    fn bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("serializing plain structs can't fail")
+    }
+}
+
+/// A signed set of site rules.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SiteRulesBundle {
+    pub version: u32,
+    pub rules: Vec<BundleRule>,
+    /// Base64 ed25519 public key of whoever signed it.
+    pub public_key: String,
+    /// Base64 signature over the JSON of `version` and `rules`.
+    pub signature: String,
+}
+
+impl SiteRulesBundle {
+    pub fn sign(rules: Vec<BundleRule>, key: &SigningKey) -> Self {
+        let signature = key.sign(
+            &SignedPart {
+                version: BUNDLE_VERSION,
+                rules: &rules,
+            }
+            .bytes(),
+        );
+        Self {
+            version: BUNDLE_VERSION,
+            rules,
+            public_key: public_key_string(&key.verifying_key()),
+            signature: STANDARD.encode(signature.to_bytes()),
+        }
+    }
+
+    /// Check that the bundle's signed by a key we trust, and hasn't been
+    /// touched since.
+    pub fn verify(&self, trusted: &[VerifyingKey]) -> Result<(), UserError> {
+        if self.version != BUNDLE_VERSION {
+            return Err(UserError::SiteRulesVersion {
+                version: self.version,
+                supported: BUNDLE_VERSION,
+            });
+        }
+        let Some(key) = parse_public_key(&self.public_key) else {
+            return Err(UserError::SiteRulesBadSignature);
+        };
+        if !trusted.contains(&key) {
+            return Err(UserError::SiteRulesUntrusted {
+                public_key: self.public_key.clone(),
+            });
+        }
+        let signature = STANDARD
+            .decode(&self.signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(UserError::SiteRulesBadSignature)?;
+        let signed = SignedPart {
+            version: self.version,
+            rules: &self.rules,
+        };
+        key.verify(&signed.bytes(), &signature)
+            .map_err(|_| UserError::SiteRulesBadSignature)
+    }
+}
+
+/// This instance's bundle signing key.
+pub fn signing_key(cookie_key: &Key) -> SigningKey {
+    let mut hasher = Sha256::new();
+    hasher.update(b"eardogger site rules v1");
+    hasher.update(cookie_key.master());
+    SigningKey::from_bytes(&hasher.finalize().into())
+}
+
+pub fn public_key_string(key: &VerifyingKey) -> String {
+    STANDARD.encode(key.to_bytes())
+}
+
+/// Read a base64 public key, like from a bundle or the config file.
+pub fn parse_public_key(key: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = STANDARD.decode(key.trim()).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+/// Tidy up a rule from a bundle, or None if it's no good.
+fn clean_rule(rule: &BundleRule) -> Option<(String, i64, Option<String>)> {
+    let host = normalize_prefix_matcher(&rule.host.to_lowercase()).to_string();
+    if host.is_empty() || host.contains(['/', ' ', '?', '#']) {
+        return None;
+    }
+    if !(0..=MAX_SEGMENTS).contains(&rule.segments) {
+        return None;
+    }
+    let note = rule
+        .note
+        .as_deref()
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(|n| n.chars().take(NOTE_MAX_LENGTH).collect());
+    Some((host, rule.segments, note))
+}
+
+/// How an import went.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct SiteRulesReport {
+    /// Rules in the bundle.
+    pub rules: usize,
+    /// New rules, or ones that replaced a different rule for the same host.
+    pub changed: usize,
+    /// Rules we already had exactly.
+    pub unchanged: usize,
+    /// Rules we couldn't use (bad hosts, silly segment counts).
+    pub invalid: usize,
+}
+
+/// Sign up everything this instance knows.
+#[tracing::instrument(skip_all)]
+pub async fn export(db: &Db, key: &SigningKey) -> sqlx::Result<SiteRulesBundle> {
+    let rules = db
+        .site_rules()
+        .list()
+        .await?
+        .into_iter()
+        .map(BundleRule::from)
+        .collect();
+    Ok(SiteRulesBundle::sign(rules, key))
+}
+
+/// Check a bundle, then add or replace every rule in it. Rules for hosts
+/// that aren't in the bundle are left alone.
+#[tracing::instrument(skip_all)]
+pub async fn import(
+    db: &Db,
+    bundle: &SiteRulesBundle,
+    trusted: &[VerifyingKey],
+) -> Result<SiteRulesReport, MixedError<sqlx::Error>> {
+    bundle.verify(trusted)?;
+    let mut report = SiteRulesReport {
+        rules: bundle.rules.len(),
+        ..Default::default()
+    };
+    for rule in &bundle.rules {
+        let Some((host, segments, note)) = clean_rule(rule) else {
+            report.invalid += 1;
+            continue;
+        };
+        if db
+            .site_rules()
+            .put(&host, segments, note.as_deref())
+            .await?
+        {
+            report.changed += 1;
+        } else {
+            report.unchanged += 1;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(host: &str, segments: i64) -> BundleRule {
+        BundleRule {
+            host: host.to_string(),
+            segments,
+            note: None,
+        }
+    }
+
+    #[test]
+    fn signing_and_verifying() {
+        let ours = signing_key(&Key::generate());
+        let theirs = signing_key(&Key::generate());
+        let trusted = [ours.verifying_key()];
+        let bundle = SiteRulesBundle::sign(vec![rule("example.com", 2)], &ours);
+        assert!(bundle.verify(&trusted).is_ok());
+        // Survives a round trip through JSON
+        let json = serde_json::to_string(&bundle).unwrap();
+        let parsed: SiteRulesBundle = serde_json::from_str(&json).unwrap();
+        assert!(parsed.verify(&trusted).is_ok());
+
+        // Tampering breaks it
+        let mut tampered: SiteRulesBundle = serde_json::from_str(&json).unwrap();
+        tampered.rules[0].segments = 3;
+        assert!(matches!(
+            tampered.verify(&trusted),
+            Err(UserError::SiteRulesBadSignature)
+        ));
+        tampered.signature = "garbage".to_string();
+        assert!(matches!(
+            tampered.verify(&trusted),
+            Err(UserError::SiteRulesBadSignature)
+        ));
+        // Strangers' bundles don't count, even when they're legit
+        let stranger = SiteRulesBundle::sign(vec![rule("example.com", 2)], &theirs);
+        assert!(matches!(
+            stranger.verify(&trusted),
+            Err(UserError::SiteRulesUntrusted { .. })
+        ));
+        // Same cookie key, same signing key
+        let key = Key::generate();
+        assert_eq!(
+            public_key_string(&signing_key(&key).verifying_key()),
+            public_key_string(&signing_key(&key).verifying_key())
+        );
+        assert_eq!(
+            parse_public_key(&bundle.public_key),
+            Some(ours.verifying_key())
+        );
+        assert!(parse_public_key("bm9wZQ==").is_none());
+    }
+
+    #[tokio::test]
+    async fn import_and_export() {
+        let db = Db::new_test_db().await;
+        let key = signing_key(&Key::generate());
+        let trusted = [key.verifying_key()];
+        let mut rules = vec![
+            rule("www.RoyalRoad.com", 3),
+            rule("example.com/oops", 1),
+            rule("example.org", 99),
+            rule("archiveofourown.org", 2),
+        ];
+        rules[0].note = Some("  fiction/:id/:slug  ".to_string());
+        let bundle = SiteRulesBundle::sign(rules, &key);
+        let report = import(&db, &bundle, &trusted).await.unwrap();
+        assert_eq!(
+            report,
+            SiteRulesReport {
+                rules: 4,
+                changed: 2,
+                unchanged: 0,
+                invalid: 2,
+            }
+        );
+        let royal = db
+            .site_rules()
+            .for_host("royalroad.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(royal.segments, 3);
+        assert_eq!(royal.note.as_deref(), Some("fiction/:id/:slug"));
+
+        // Again: nothing new
+        let report = import(&db, &bundle, &trusted).await.unwrap();
+        assert_eq!((report.changed, report.unchanged), (0, 2));
+
+        // Export has what we kept, tidied up, and it imports elsewhere
+        let exported = export(&db, &key).await.unwrap();
+        let hosts: Vec<&str> = exported.rules.iter().map(|r| r.host.as_str()).collect();
+        assert_eq!(hosts, vec!["archiveofourown.org", "royalroad.com"]);
+        let elsewhere = Db::new_test_db().await;
+        let report = import(&elsewhere, &exported, &trusted).await.unwrap();
+        assert_eq!(report.changed, 2);
+
+        // Nope
+        let untrusted = [signing_key(&Key::generate()).verifying_key()];
+        assert!(matches!(
+            import(&db, &bundle, &untrusted).await,
+            Err(MixedError::User(UserError::SiteRulesUntrusted { .. }))
+        ));
+    }
+}
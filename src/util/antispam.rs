@@ -0,0 +1,476 @@
+//! Keeping scripts from filling up the users table. Signup is the one form
+//! anybody can post to, so it gets a few checks that people don't notice
+//! and form-stuffing bots trip over:
+//!
+//! - A honeypot: a `website` field that's hidden from people and screen
+//!   readers, but that naive scripts fill in like any other.
+//! - A time trap: the form carries a signed stamp of when we rendered it,
+//!   and nobody types a username and two passwords in under
+//!   `signup.min_seconds`.
+//! - Optionally, a challenge (`signup.challenge`): either a proof-of-work
+//!   puzzle that client.js solves while people type, or a Turnstile or
+//!   hCaptcha widget whose answer we check with the provider. Like the
+//!   other outside checks, a provider that's slow or down lets people
+//!   through.
+
+use super::{sign_action_link, verify_action_link, RateLimiter};
+use crate::config::{SignupChallenge, SignupConfig};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use time::OffsetDateTime;
+use tower_cookies::Key;
+use tracing::warn;
+
+const TURNSTILE_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+const HCAPTCHA_VERIFY_URL: &str = "https://api.hcaptcha.com/siteverify";
+/// Signing up shouldn't hang on someone else's API.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest a proof-of-work answer stays spent. Past this, the form's
+/// stamp is too old to use anyway.
+const MAX_FORM_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Clock slop to allow for between processes, for stamps from "the future."
+const MAX_CLOCK_SKEW: i64 = 60;
+
+/// The signup form fields these checks look at.
+#[derive(Deserialize, Debug, Default)]
+pub struct SignupGuardFields {
+    /// The honeypot. People never see it, so it should come back blank.
+    pub website: Option<String>,
+    /// When we rendered the form, signed.
+    pub signup_stamp: Option<String>,
+    /// client.js's answer to the proof-of-work puzzle.
+    pub pow_nonce: Option<String>,
+    /// The captcha widget's answer. Each provider names it differently.
+    #[serde(rename = "cf-turnstile-response")]
+    pub turnstile_response: Option<String>,
+    #[serde(rename = "h-captcha-response")]
+    pub hcaptcha_response: Option<String>,
+}
+
+/// Why a signup got turned away.
+#[derive(Error, Debug, PartialEq)]
+pub enum SignupRejection {
+    #[error("Something filled in a field that's supposed to stay blank, which is what bots do. If you're a person, try again.")]
+    Honeypot,
+    #[error("That was faster than anyone can fill out a form, which is what bots do. If you're a person, wait a few seconds and try again.")]
+    TooFast,
+    #[error("The signup form you used was stale or had been tampered with. Try again.")]
+    BadStamp,
+    #[error("Your browser didn't finish the anti-spam check. It needs JavaScript turned on; try again once it is.")]
+    NoProofOfWork,
+    #[error("The anti-spam check didn't pass. Try it again.")]
+    ChallengeFailed,
+}
+
+impl SignupRejection {
+    /// A short name for the audit log.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::Honeypot => "honeypot",
+            Self::TooFast => "too_fast",
+            Self::BadStamp => "bad_stamp",
+            Self::NoProofOfWork => "no_proof_of_work",
+            Self::ChallengeFailed => "challenge_failed",
+        }
+    }
+}
+
+/// What the signup form needs to show for the configured challenge.
+#[derive(Serialize, Debug)]
+pub struct ChallengeWidget<'a> {
+    /// "proof_of_work", "turnstile", or "hcaptcha".
+    pub provider: &'static str,
+    /// Leading zero bits the proof-of-work answer needs.
+    pub difficulty: Option<u8>,
+    /// The captcha provider's public key for this site.
+    pub site_key: Option<&'a str>,
+}
+
+/// Somewhere to check captcha answers. The real one is the providers'
+/// siteverify APIs (Turnstile and hCaptcha speak the same protocol); tests
+/// use a stub.
+pub trait CaptchaSource: Send + Sync + Debug {
+    /// Whether the provider at `url` likes a widget's answer.
+    fn verify<'a>(
+        &'a self,
+        url: &'static str,
+        secret: &'a str,
+        response: &'a str,
+        remote_ip: Option<&'a str>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>>;
+}
+
+/// The real siteverify APIs.
+#[derive(Debug)]
+struct SiteVerifyApi {
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+impl CaptchaSource for SiteVerifyApi {
+    fn verify<'a>(
+        &'a self,
+        url: &'static str,
+        secret: &'a str,
+        response: &'a str,
+        remote_ip: Option<&'a str>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let mut form = vec![("secret", secret), ("response", response)];
+            if let Some(ip) = remote_ip {
+                form.push(("remoteip", ip));
+            }
+            let verdict: SiteVerifyResponse = self
+                .http
+                .post(url)
+                .form(&form)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok(verdict.success)
+        })
+    }
+}
+
+/// Runs the signup checks the config asks for. Cheap to clone; clones
+/// share the list of spent proof-of-work answers.
+#[derive(Clone, Debug)]
+pub struct SignupGuard {
+    min_seconds: u64,
+    challenge: Option<SignupChallenge>,
+    source: Arc<dyn CaptchaSource>,
+    // Proof-of-work answers, by form stamp, so one can't get replayed for
+    // a hundred signups. Per-process, same as the rate limiters.
+    spent: RateLimiter,
+}
+
+impl SignupGuard {
+    /// A guard that checks captchas with the real providers.
+    pub fn new(config: &SignupConfig) -> reqwest::Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(VERIFY_TIMEOUT)
+            .user_agent(concat!("eardogger-rs/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(Self::with_source(config, SiteVerifyApi { http }))
+    }
+
+    /// A guard that checks captchas somewhere else.
+    pub fn with_source(config: &SignupConfig, source: impl CaptchaSource + 'static) -> Self {
+        Self {
+            min_seconds: config.min_seconds,
+            challenge: config.challenge.clone(),
+            source: Arc::new(source),
+            spent: RateLimiter::new(1, MAX_FORM_AGE),
+        }
+    }
+
+    /// The stamp for a signup form rendered right now, tied to the form's
+    /// login csrf token so it can't move to another form.
+    pub fn stamp(&self, key: &Key, csrf_token: &str) -> String {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        sign_action_link(key, &stamp_action(csrf_token), now)
+    }
+
+    /// What the form needs to show for the challenge, if there is one.
+    pub fn widget(&self) -> Option<ChallengeWidget<'_>> {
+        let widget = match self.challenge.as_ref()? {
+            SignupChallenge::ProofOfWork { difficulty } => ChallengeWidget {
+                provider: "proof_of_work",
+                difficulty: Some(*difficulty),
+                site_key: None,
+            },
+            SignupChallenge::Turnstile { site_key, .. } => ChallengeWidget {
+                provider: "turnstile",
+                difficulty: None,
+                site_key: Some(site_key),
+            },
+            SignupChallenge::Hcaptcha { site_key, .. } => ChallengeWidget {
+                provider: "hcaptcha",
+                difficulty: None,
+                site_key: Some(site_key),
+            },
+        };
+        Some(widget)
+    }
+
+    /// Run every check on a submitted signup form. Expects the csrf token
+    /// to have been checked already.
+    #[tracing::instrument(skip_all)]
+    pub async fn check(
+        &self,
+        key: &Key,
+        csrf_token: &str,
+        fields: &SignupGuardFields,
+        remote_ip: Option<&str>,
+    ) -> Result<(), SignupRejection> {
+        if fields.website.as_deref().is_some_and(|w| !w.is_empty()) {
+            return Err(SignupRejection::Honeypot);
+        }
+        let stamp = fields.signup_stamp.as_deref().unwrap_or_default();
+        if self.min_seconds > 0 || self.challenge.is_some() {
+            let issued = verify_action_link(key, &stamp_action(csrf_token), stamp)
+                .ok_or(SignupRejection::BadStamp)?;
+            let age = OffsetDateTime::now_utc().unix_timestamp() - issued;
+            if age < -MAX_CLOCK_SKEW || age > MAX_FORM_AGE.as_secs() as i64 {
+                return Err(SignupRejection::BadStamp);
+            }
+            if age < self.min_seconds as i64 {
+                return Err(SignupRejection::TooFast);
+            }
+        }
+
+        let captcha = |response: &Option<String>| response.as_deref().unwrap_or_default();
+        match &self.challenge {
+            None => Ok(()),
+            Some(SignupChallenge::ProofOfWork { difficulty }) => {
+                let nonce = fields.pow_nonce.as_deref().unwrap_or_default();
+                if nonce.is_empty() {
+                    return Err(SignupRejection::NoProofOfWork);
+                }
+                if !proof_of_work_ok(stamp, nonce, *difficulty) || self.spent.record(stamp) > 1 {
+                    return Err(SignupRejection::ChallengeFailed);
+                }
+                Ok(())
+            }
+            Some(SignupChallenge::Turnstile { secret_key, .. }) => {
+                let response = captcha(&fields.turnstile_response);
+                self.verify_captcha(TURNSTILE_VERIFY_URL, secret_key, response, remote_ip)
+                    .await
+            }
+            Some(SignupChallenge::Hcaptcha { secret_key, .. }) => {
+                let response = captcha(&fields.hcaptcha_response);
+                self.verify_captcha(HCAPTCHA_VERIFY_URL, secret_key, response, remote_ip)
+                    .await
+            }
+        }
+    }
+
+    async fn verify_captcha(
+        &self,
+        url: &'static str,
+        secret: &str,
+        response: &str,
+        remote_ip: Option<&str>,
+    ) -> Result<(), SignupRejection> {
+        if response.is_empty() {
+            return Err(SignupRejection::ChallengeFailed);
+        }
+        match self.source.verify(url, secret, response, remote_ip).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(SignupRejection::ChallengeFailed),
+            Err(e) => {
+                warn!("couldn't check a signup captcha: {}", e);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn stamp_action(csrf_token: &str) -> String {
+    format!("signup form {}", csrf_token)
+}
+
+/// Whether SHA-256 of `challenge:nonce` starts with at least `difficulty`
+/// zero bits. client.js does the same math, many times over.
+pub fn proof_of_work_ok(challenge: &str, nonce: &str, difficulty: u8) -> bool {
+    // Nonces are counters; anything else is junk.
+    if nonce.len() > 20 || !nonce.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    let digest = Sha256::digest(format!("{}:{}", challenge, nonce));
+    let mut zeros = 0;
+    for byte in digest {
+        zeros += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    zeros >= u32::from(difficulty)
+}
+
+/// A captcha source that accepts a fixed list of answers, and errors on
+/// "broken".
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct StubCaptcha {
+    answers: Vec<String>,
+}
+
+#[cfg(test)]
+impl StubCaptcha {
+    pub fn new(answers: &[&str]) -> Self {
+        Self {
+            answers: answers.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl CaptchaSource for StubCaptcha {
+    fn verify<'a>(
+        &'a self,
+        _url: &'static str,
+        _secret: &'a str,
+        response: &'a str,
+        _remote_ip: Option<&'a str>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            if response == "broken" {
+                anyhow::bail!("stub captcha is broken");
+            }
+            Ok(self.answers.iter().any(|a| a == response))
+        })
+    }
+}
+
+/// Find a nonce that passes `proof_of_work_ok`, the slow way.
+#[cfg(test)]
+pub fn solve_proof_of_work(challenge: &str, difficulty: u8) -> String {
+    (0u64..)
+        .map(|n| n.to_string())
+        .find(|nonce| proof_of_work_ok(challenge, nonce, difficulty))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard_with(min_seconds: u64, challenge: Option<SignupChallenge>) -> SignupGuard {
+        let config = SignupConfig {
+            min_seconds,
+            challenge,
+            welcome: false,
+        };
+        SignupGuard::with_source(&config, StubCaptcha::new(&["good"]))
+    }
+
+    fn backdated_stamp(key: &Key, csrf_token: &str, seconds_ago: i64) -> String {
+        let then = OffsetDateTime::now_utc().unix_timestamp() - seconds_ago;
+        sign_action_link(key, &stamp_action(csrf_token), then)
+    }
+
+    #[tokio::test]
+    async fn honeypot_and_time_trap() {
+        let key = Key::generate();
+        let guard = guard_with(3, None);
+        let fields = |website: &str, stamp: &str| SignupGuardFields {
+            website: Some(website.to_string()),
+            signup_stamp: Some(stamp.to_string()),
+            ..Default::default()
+        };
+        let old = backdated_stamp(&key, "csrf", 10);
+        assert_eq!(
+            guard.check(&key, "csrf", &fields("", &old), None).await,
+            Ok(())
+        );
+        assert_eq!(
+            guard
+                .check(&key, "csrf", &fields("http://spam.example", &old), None)
+                .await,
+            Err(SignupRejection::Honeypot)
+        );
+        let fresh = guard.stamp(&key, "csrf");
+        assert_eq!(
+            guard.check(&key, "csrf", &fields("", &fresh), None).await,
+            Err(SignupRejection::TooFast)
+        );
+        // Stamps only work with their own form, and can't be made up.
+        for (csrf, stamp) in [("other", old.as_str()), ("csrf", ""), ("csrf", "12.nope")] {
+            assert_eq!(
+                guard.check(&key, csrf, &fields("", stamp), None).await,
+                Err(SignupRejection::BadStamp)
+            );
+        }
+        let future = backdated_stamp(&key, "csrf", -3600);
+        assert_eq!(
+            guard.check(&key, "csrf", &fields("", &future), None).await,
+            Err(SignupRejection::BadStamp)
+        );
+        // Off means off.
+        let guard = guard_with(0, None);
+        assert_eq!(
+            guard
+                .check(&key, "csrf", &SignupGuardFields::default(), None)
+                .await,
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn proof_of_work() {
+        let key = Key::generate();
+        let guard = guard_with(0, Some(SignupChallenge::ProofOfWork { difficulty: 8 }));
+        let stamp = guard.stamp(&key, "csrf");
+        let fields = |nonce: &str| SignupGuardFields {
+            signup_stamp: Some(stamp.clone()),
+            pow_nonce: Some(nonce.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            guard.check(&key, "csrf", &fields(""), None).await,
+            Err(SignupRejection::NoProofOfWork)
+        );
+        let nonce = solve_proof_of_work(&stamp, 8);
+        assert!(!proof_of_work_ok(&stamp, &nonce, 40));
+        assert!(!proof_of_work_ok(&stamp, &format!("{}x", nonce), 8));
+        assert_eq!(
+            guard.check(&key, "csrf", &fields(&nonce), None).await,
+            Ok(())
+        );
+        // Spent.
+        assert_eq!(
+            guard.check(&key, "csrf", &fields(&nonce), None).await,
+            Err(SignupRejection::ChallengeFailed)
+        );
+    }
+
+    #[tokio::test]
+    async fn captchas() {
+        let key = Key::generate();
+        let guard = guard_with(
+            0,
+            Some(SignupChallenge::Turnstile {
+                site_key: "site".to_string(),
+                secret_key: "secret".to_string(),
+            }),
+        );
+        let stamp = guard.stamp(&key, "csrf");
+        let fields = |response: &str| SignupGuardFields {
+            signup_stamp: Some(stamp.clone()),
+            turnstile_response: Some(response.to_string()),
+            // Answers for the wrong provider don't count.
+            hcaptcha_response: Some("good".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            guard.check(&key, "csrf", &fields("good"), None).await,
+            Ok(())
+        );
+        for response in ["", "bad"] {
+            assert_eq!(
+                guard.check(&key, "csrf", &fields(response), None).await,
+                Err(SignupRejection::ChallengeFailed)
+            );
+        }
+        // Can't tell, so it passes.
+        assert_eq!(
+            guard.check(&key, "csrf", &fields("broken"), None).await,
+            Ok(())
+        );
+        let widget = guard.widget().unwrap();
+        assert_eq!(widget.provider, "turnstile");
+        assert_eq!(widget.site_key, Some("site"));
+    }
+}
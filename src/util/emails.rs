@@ -0,0 +1,288 @@
+//! Checking that an email address could actually get mail, before we save
+//! it. The syntax check is the practical subset of RFC 5321 that real
+//! providers hand out (no quoted local parts, no IP literals), and it
+//! lowercases the domain, since that part's case-insensitive and we'd
+//! rather not have `Example.COM` and `example.com` look like two places.
+//!
+//! There's also an optional DNS check (`mail.check_mx`) that the domain
+//! takes mail at all: an MX record, or failing that an address record,
+//! which RFC 5321 says counts as an implicit MX. Like the breach check for
+//! passwords, it fails open: a slow or broken resolver doesn't block
+//! anybody, only a clear "nothing lives there" answer does.
+
+use super::{clean_field, Field, UserError};
+use futures_util::future::BoxFuture;
+use hickory_resolver::{error::ResolveErrorKind, TokioAsyncResolver};
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+use url::Host;
+
+/// The RFC 5321 limit on the part before the @.
+const LOCAL_PART_MAX_LENGTH: usize = 64;
+/// The DNS limit on one label of a domain name.
+const LABEL_MAX_LENGTH: usize = 63;
+/// Signing up shouldn't hang on somebody's nameserver.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(3);
+/// Characters allowed in the part before the @, besides letters and
+/// numbers. Non-ASCII letters are fine too, for SMTPUTF8 addresses.
+const LOCAL_PART_SYMBOLS: &str = "!#$%&'*+/=?^_`{|}~.-";
+
+/// Trim, check, and normalize an optional email address, where blank means
+/// none.
+pub fn clean_email(email: Option<&str>) -> Result<Option<String>, UserError> {
+    match clean_field(Field::Email, email)? {
+        Some(email) => normalize_email(email).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Check an email address's syntax, and return it with its domain
+/// lowercased. Expects it already trimmed.
+pub fn normalize_email(email: &str) -> Result<String, UserError> {
+    let bad = |why: &'static str| UserError::BadEmail {
+        email: email.to_string(),
+        why,
+    };
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return Err(bad("It needs an @ and a domain, like you@example.com."));
+    };
+
+    if local.is_empty() {
+        return Err(bad("There's nothing before the @."));
+    }
+    if local.chars().count() > LOCAL_PART_MAX_LENGTH {
+        return Err(bad("The part before the @ is too long."));
+    }
+    if local.starts_with('.') || local.ends_with('.') || local.contains("..") {
+        return Err(bad(
+            "The part before the @ can't start or end with a dot, or have two dots in a row.",
+        ));
+    }
+    if !local
+        .chars()
+        .all(|c| c.is_alphanumeric() || LOCAL_PART_SYMBOLS.contains(c))
+    {
+        return Err(bad(
+            "The part before the @ has characters that email addresses can't use.",
+        ));
+    }
+
+    if domain.is_empty() {
+        return Err(bad("There's nothing after the @."));
+    }
+    let Some(ascii_domain) = ascii_domain(domain) else {
+        return Err(bad("The part after the @ isn't a valid domain name."));
+    };
+    let labels: Vec<&str> = ascii_domain.split('.').collect();
+    if labels.len() < 2 {
+        return Err(bad("The domain needs a dot in it, like example.com."));
+    }
+    let label_ok = |label: &&str| {
+        (1..=LABEL_MAX_LENGTH).contains(&label.len())
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    let tld_ok = labels
+        .last()
+        .is_some_and(|tld| !tld.chars().all(|c| c.is_ascii_digit()));
+    if !labels.iter().all(label_ok) || !tld_ok {
+        return Err(bad("The part after the @ isn't a valid domain name."));
+    }
+
+    Ok(format!("{}@{}", local, domain.to_lowercase()))
+}
+
+/// The IDNA (punycode, lowercase) form of a domain, or None if it isn't a
+/// domain name at all (including if it's an IP address).
+fn ascii_domain(domain: &str) -> Option<String> {
+    match Host::parse(domain) {
+        Ok(Host::Domain(d)) => Some(d),
+        _ => None,
+    }
+}
+
+/// Somewhere to ask whether a domain takes mail. The real one is DNS;
+/// tests use a stub.
+pub trait MxSource: Send + Sync + Debug {
+    /// Ok(false) only if the domain definitely doesn't take mail. Expects
+    /// an ASCII (punycode) domain.
+    fn accepts_mail<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, anyhow::Result<bool>>;
+}
+
+/// The system's resolver, per /etc/resolv.conf.
+struct SystemResolver {
+    resolver: TokioAsyncResolver,
+}
+
+// The resolver's own Debug output is a whole config dump.
+impl Debug for SystemResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SystemResolver")
+    }
+}
+
+impl MxSource for SystemResolver {
+    fn accepts_mail<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            // Fully qualified, so the resolver doesn't try search domains.
+            let fqdn = format!("{}.", domain);
+            match self.resolver.mx_lookup(fqdn.as_str()).await {
+                // A lone "0 ." record is a null MX (RFC 7505): no mail, on purpose.
+                Ok(mx) => return Ok(mx.iter().any(|r| !r.exchange().is_root())),
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {}
+                Err(e) => return Err(e.into()),
+            }
+            match self.resolver.lookup_ip(fqdn.as_str()).await {
+                Ok(_) => Ok(true),
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(false),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+}
+
+/// Checks whether email addresses' domains take mail. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct MxChecker {
+    source: Arc<dyn MxSource>,
+}
+
+impl MxChecker {
+    /// A checker that asks the system's resolver.
+    pub fn new() -> anyhow::Result<Self> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+        Ok(Self::with_source(SystemResolver { resolver }))
+    }
+
+    /// A checker that asks somewhere else.
+    pub fn with_source(source: impl MxSource + 'static) -> Self {
+        Self {
+            source: Arc::new(source),
+        }
+    }
+
+    /// Whether an (already normalized) address's domain looks like it takes
+    /// mail. True if we couldn't find out.
+    #[tracing::instrument(skip_all)]
+    pub async fn accepts_mail(&self, email: &str) -> bool {
+        let Some(domain) = email.rsplit_once('@').and_then(|(_, d)| ascii_domain(d)) else {
+            return false;
+        };
+        match tokio::time::timeout(LOOKUP_TIMEOUT, self.source.accepts_mail(&domain)).await {
+            Ok(Ok(accepts)) => accepts,
+            Ok(Err(e)) => {
+                warn!("couldn't look up mail servers for {}: {}", domain, e);
+                true
+            }
+            Err(_) => {
+                warn!("timed out looking up mail servers for {}", domain);
+                true
+            }
+        }
+    }
+}
+
+/// An MX source that knows a fixed list of domains that take mail, and
+/// errors on any domain starting with "broken.".
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct StubMx {
+    domains: Vec<String>,
+}
+
+#[cfg(test)]
+impl StubMx {
+    pub fn new(domains: &[&str]) -> Self {
+        Self {
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl MxSource for StubMx {
+    fn accepts_mail<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            if domain.starts_with("broken.") {
+                anyhow::bail!("stub resolver is broken");
+            }
+            Ok(self.domains.iter().any(|d| d == domain))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syntax() {
+        for (email, normalized) in [
+            ("dog@example.com", "dog@example.com"),
+            ("Dog.Person@Example.COM", "Dog.Person@example.com"),
+            (
+                "dog+eardogger@mail.example.co.uk",
+                "dog+eardogger@mail.example.co.uk",
+            ),
+            ("o'brien@example.ie", "o'brien@example.ie"),
+            ("zoë@bücher.example", "zoë@bücher.example"),
+            ("dog@XN--BCHER-KVA.example", "dog@xn--bcher-kva.example"),
+        ] {
+            assert_eq!(normalize_email(email).unwrap(), normalized, "{}", email);
+        }
+        for email in [
+            "dog",
+            "dog@",
+            "@example.com",
+            "dog@localhost",
+            "dog@example",
+            "dog@example.com.",
+            "dog@-example.com",
+            "dog@exa_mple.com",
+            "dog@192.0.2.1",
+            "dog@[192.0.2.1]",
+            "dog@example.123",
+            ".dog@example.com",
+            "dog.@example.com",
+            "d..og@example.com",
+            "\"dog\"@example.com",
+            "dog(comment)@example.com",
+            "dog@@example.com",
+            "dog,cat@example.com",
+        ] {
+            assert!(normalize_email(email).is_err(), "{}", email);
+        }
+        let long_local = format!("{}@example.com", "x".repeat(65));
+        assert!(normalize_email(&long_local).is_err());
+        let long_label = format!("dog@{}.com", "x".repeat(64));
+        assert!(normalize_email(&long_label).is_err());
+    }
+
+    #[test]
+    fn clean() {
+        assert_eq!(clean_email(None).unwrap(), None);
+        assert_eq!(clean_email(Some("  ")).unwrap(), None);
+        assert_eq!(
+            clean_email(Some(" dog@EXAMPLE.com ")).unwrap().as_deref(),
+            Some("dog@example.com")
+        );
+        let err = clean_email(Some("dog at example.com")).unwrap_err();
+        assert!(matches!(err, UserError::BadCharacters { .. }));
+        let err = clean_email(Some("dog")).unwrap_err();
+        assert!(err.to_string().contains("It needs an @"));
+    }
+
+    #[tokio::test]
+    async fn mx() {
+        let checker =
+            MxChecker::with_source(StubMx::new(&["example.com", "xn--bcher-kva.example"]));
+        assert!(checker.accepts_mail("dog@example.com").await);
+        assert!(checker.accepts_mail("dog@bücher.example").await);
+        assert!(!checker.accepts_mail("dog@nowhere.example").await);
+        // Can't tell, so it passes.
+        assert!(checker.accepts_mail("dog@broken.example").await);
+    }
+}
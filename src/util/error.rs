@@ -1,3 +1,4 @@
+use super::Field;
 use http::StatusCode;
 use thiserror::Error;
 
@@ -28,23 +29,62 @@ pub enum UserError {
     #[error("Can't use {name} as a username on this site. Usernames can only use letters, numbers, hyphens (-), and underscores (_), and can't be longer than 80 characters.")]
     BadUsername { name: String },
 
+    #[error("Can't use {name} as a username on this site. {why}")]
+    UsernameNotAllowed { name: String, why: &'static str },
+
     #[error("User {name} already exists.")]
     UserExists { name: String },
 
+    #[error("Can't use {name} as a username on this site, since it's too easy to mistake for someone else's.")]
+    UsernameTooSimilar { name: String },
+
+    #[error("{email} doesn't look like an email address. {why}")]
+    BadEmail { email: String, why: &'static str },
+
+    #[error("Nothing at {domain} takes email, so {email} can't get any. Check for typos?")]
+    EmailUndeliverable { email: String, domain: String },
+
     #[error("Empty password isn't allowed.")]
     BlankPassword,
 
-    // This happens when the provided value of the Origin header can't
-    // be turned back into a HeaderValue. I'm pretty sure something
-    // further out in the stack would explode long before this reached my code.
-    #[error("That HTTP request was mangled in a way most would believe impossible. Good work, but also I can't do anything with it.")]
-    HttpFucked,
-
     #[error("Something impossible happened: {0}")]
     Impossible(&'static str),
 
     #[error("Requested page size is too large")]
     PageOversize,
+
+    #[error("No shared dogears found for {name}.")]
+    Grant404 { name: String },
+
+    #[error("You can't share your dogears with yourself.")]
+    GrantSelf,
+
+    #[error("{} can't be longer than {max} characters.", field.label())]
+    TooLong { field: Field, max: usize },
+
+    #[error("{} can't include {what}.", field.label())]
+    BadCharacters { field: Field, what: &'static str },
+
+    #[error("Custom CSS can't be longer than 4000 characters.")]
+    CustomCssTooLong,
+
+    #[error("Custom CSS can't include {what}. It's only for tweaking how the site looks, so it can't load anything from elsewhere (or break out of its <style> tag).")]
+    CustomCssNotAllowed { what: String },
+
+    #[error("Couldn't find any saved pages in that. Make sure it's the whole {format} export file, and that you picked the right kind of file.")]
+    ImportUnreadable { format: String },
+
+    #[error("That export has more than {max} saved pages, which is more than we can import at once. Try splitting it up.")]
+    ImportTooBig { max: usize },
+
+    #[error("That site rules bundle is version {version}, and this instance only reads version {supported}.")]
+    SiteRulesVersion { version: u32, supported: u32 },
+
+    #[error("That site rules bundle's signature doesn't check out. It's been edited since it was signed, or it's not a bundle at all.")]
+    SiteRulesBadSignature,
+
+    #[error("That site rules bundle is signed by {public_key}, which isn't in this instance's site_rules.trusted_keys.")]
+    SiteRulesUntrusted { public_key: String },
 }
 
 impl IntoHandlerError for UserError {
@@ -54,12 +94,26 @@ impl IntoHandlerError for UserError {
             UserError::DogearNonMatching { .. } => StatusCode::BAD_REQUEST,
             UserError::DogearExists { .. } => StatusCode::CONFLICT,
             UserError::DogearInvalidUrl { .. } => StatusCode::BAD_REQUEST,
-            UserError::HttpFucked => StatusCode::IM_A_TEAPOT,
             UserError::Impossible(_) => StatusCode::INTERNAL_SERVER_ERROR,
             UserError::PageOversize => StatusCode::BAD_REQUEST,
             UserError::BadUsername { .. } => StatusCode::BAD_REQUEST,
+            UserError::BadEmail { .. } => StatusCode::BAD_REQUEST,
+            UserError::EmailUndeliverable { .. } => StatusCode::BAD_REQUEST,
             UserError::BlankPassword => StatusCode::BAD_REQUEST,
+            UserError::UsernameNotAllowed { .. } => StatusCode::BAD_REQUEST,
             UserError::UserExists { .. } => StatusCode::CONFLICT,
+            UserError::UsernameTooSimilar { .. } => StatusCode::CONFLICT,
+            UserError::Grant404 { .. } => StatusCode::NOT_FOUND,
+            UserError::GrantSelf => StatusCode::BAD_REQUEST,
+            UserError::TooLong { .. } => StatusCode::BAD_REQUEST,
+            UserError::BadCharacters { .. } => StatusCode::BAD_REQUEST,
+            UserError::CustomCssTooLong => StatusCode::BAD_REQUEST,
+            UserError::CustomCssNotAllowed { .. } => StatusCode::BAD_REQUEST,
+            UserError::ImportUnreadable { .. } => StatusCode::BAD_REQUEST,
+            UserError::ImportTooBig { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            UserError::SiteRulesVersion { .. } => StatusCode::BAD_REQUEST,
+            UserError::SiteRulesBadSignature => StatusCode::BAD_REQUEST,
+            UserError::SiteRulesUntrusted { .. } => StatusCode::FORBIDDEN,
         };
         (status, self.to_string())
     }
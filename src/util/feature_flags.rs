@@ -0,0 +1,92 @@
+//! Feature flags, for shipping something dark and then turning it on for
+//! more and more people without a redeploy. The config file lists the
+//! flags and their starting rollouts; an operator can override those live
+//! (see `/admin/features`), and every user lands either in or out of each
+//! flag's rollout. Routes check `AuthSession::features`, and templates get
+//! the same list as `common.features`.
+
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Whether a user falls inside a flag's rollout percentage. Each user gets
+/// a stable spot from 0 to 99 for each flag, so turning a rollout up only
+/// ever adds people, and different flags pick different people.
+pub fn in_rollout(flag: &str, user_id: i64, rollout: u8) -> bool {
+    let hash = Sha256::digest(format!("{}:{}", flag, user_id));
+    let spot = u16::from_be_bytes([hash[0], hash[1]]) % 100;
+    spot < u16::from(rollout)
+}
+
+/// The live rollouts: the config file's flags, with any overrides on top.
+/// Overrides for flags the config doesn't list anymore don't count.
+pub fn live_rollouts<'a>(
+    configured: &BTreeMap<String, u8>,
+    overrides: impl IntoIterator<Item = (&'a str, u8)>,
+) -> BTreeMap<String, u8> {
+    let mut rollouts = configured.clone();
+    for (name, rollout) in overrides {
+        if let Some(current) = rollouts.get_mut(name) {
+            *current = rollout.min(100);
+        }
+    }
+    rollouts
+}
+
+/// The flags that are on for one user.
+pub fn features_for(rollouts: &BTreeMap<String, u8>, user_id: i64) -> BTreeSet<String> {
+    rollouts
+        .iter()
+        .filter(|(name, rollout)| in_rollout(name, user_id, **rollout))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollouts() {
+        let users = 1..=1000;
+        // The ends are all or nothing.
+        assert!(users.clone().all(|id| !in_rollout("queue_mode", id, 0)));
+        assert!(users.clone().all(|id| in_rollout("queue_mode", id, 100)));
+        // The middle is about right, and only grows.
+        let at = |pct: u8| {
+            users
+                .clone()
+                .filter(|id| in_rollout("queue_mode", *id, pct))
+                .collect::<BTreeSet<_>>()
+        };
+        let (ten, fifty) = (at(10), at(50));
+        assert!((50..=150).contains(&ten.len()), "{}", ten.len());
+        assert!((400..=600).contains(&fifty.len()), "{}", fifty.len());
+        assert!(ten.is_subset(&fifty));
+        // Different flags, different people.
+        let other = users
+            .clone()
+            .filter(|id| in_rollout("sse_refresh", *id, 50))
+            .collect::<BTreeSet<_>>();
+        assert_ne!(fifty, other);
+    }
+
+    #[test]
+    fn overrides_and_features() {
+        let configured = BTreeMap::from([
+            ("queue_mode".to_string(), 0),
+            ("sse_refresh".to_string(), 100),
+        ]);
+        let rollouts = live_rollouts(&configured, [("queue_mode", 100), ("gone", 100)]);
+        assert_eq!(rollouts.len(), 2);
+        assert_eq!(rollouts["queue_mode"], 100);
+        assert_eq!(
+            features_for(&rollouts, 1).into_iter().collect::<Vec<_>>(),
+            vec!["queue_mode", "sse_refresh"]
+        );
+        let rollouts = live_rollouts(&configured, []);
+        assert_eq!(
+            features_for(&rollouts, 1).into_iter().collect::<Vec<_>>(),
+            vec!["sse_refresh"]
+        );
+    }
+}
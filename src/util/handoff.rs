@@ -0,0 +1,78 @@
+//! Federation-lite: handing a logged-out /resume off to whichever Eardogger
+//! instance the visitor's account actually lives on. Communities with a few
+//! instances can share one set of bookmarklets pointed at one of them, and
+//! people with accounts elsewhere still end up at their own dogears.
+//!
+//! The hint about where someone's account lives comes from a cookie, which
+//! means it comes from the client, so it's only ever used to *pick* one of
+//! the peers from the config. The redirect is built from the configured
+//! peer's origin, never from anything in the request.
+
+use super::url_encoding::encode_uri_component;
+use url::Url;
+
+/// The query param we tack onto a handed-off /resume URL, so the receiving
+/// instance knows not to hand it off again. (Two instances whose cookies
+/// point at each other would otherwise bounce a browser back and forth.)
+const HANDOFF_PARAM: &str = "handed_off";
+
+/// The configured peer a browser's home-instance hint points at, if any.
+/// A hint that doesn't match a peer (tampered with, or the peer's since
+/// been dropped from the config) is the same as no hint.
+pub fn home_peer<'a>(peers: &'a [Url], hint: Option<&str>) -> Option<&'a Url> {
+    let hint = Url::parse(hint?).ok()?;
+    peers.iter().find(|peer| peer.origin() == hint.origin())
+}
+
+/// The /resume URL on a peer instance for a given site URL.
+pub fn handoff_url(peer: &Url, url: &str) -> String {
+    // Peers are bare origins, so they already end with a slash.
+    format!(
+        "{}resume/{}?{}=1",
+        peer,
+        encode_uri_component(url),
+        HANDOFF_PARAM
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_configured_peers_count() {
+        let peers = vec![
+            Url::parse("https://dogs.example.org").unwrap(),
+            Url::parse("https://eardogger.example.net").unwrap(),
+        ];
+        assert_eq!(
+            home_peer(&peers, Some("https://eardogger.example.net")),
+            Some(&peers[1])
+        );
+        // Same origin, different spelling
+        assert_eq!(
+            home_peer(&peers, Some("https://DOGS.example.org/")),
+            Some(&peers[0])
+        );
+        // Anything else is nothing
+        assert_eq!(home_peer(&peers, Some("https://example.horse")), None);
+        assert_eq!(home_peer(&peers, Some("http://dogs.example.org")), None);
+        assert_eq!(
+            home_peer(&peers, Some("https://dogs.example.org.example.horse")),
+            None
+        );
+        assert_eq!(home_peer(&peers, Some("//example.horse")), None);
+        assert_eq!(home_peer(&peers, Some("")), None);
+        assert_eq!(home_peer(&peers, None), None);
+        assert_eq!(home_peer(&[], Some("https://dogs.example.org")), None);
+    }
+
+    #[test]
+    fn handoff_urls() {
+        let peer = Url::parse("https://dogs.example.org").unwrap();
+        assert_eq!(
+            handoff_url(&peer, "https://example.com/comic/5?page=2"),
+            "https://dogs.example.org/resume/https%3A%2F%2Fexample.com%2Fcomic%2F5%3Fpage%3D2?handed_off=1"
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! When each background worker last finished a pass, for the status report.
+//! Like the rate limiters, it's per-process: under mod_fcgid, each process
+//! runs its own workers and only knows about those.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use time::{serde::iso8601, OffsetDateTime};
+
+/// The last time each job finished a pass. Cheap to clone; clones share the
+/// same times.
+#[derive(Clone, Debug, Default)]
+pub struct JobClock {
+    // std Mutex is fine, since nobody holds it across an await.
+    runs: Arc<Mutex<BTreeMap<&'static str, OffsetDateTime>>>,
+}
+
+impl JobClock {
+    /// Note that `job` just finished a pass, whether or not it went well
+    /// (the log has the details).
+    pub fn ran(&self, job: &'static str) {
+        let mut runs = self.runs.lock().unwrap_or_else(|e| e.into_inner());
+        runs.insert(job, OffsetDateTime::now_utc());
+    }
+
+    /// Every job that's finished a pass since startup, by name. Jobs that
+    /// haven't yet (or that are turned off) aren't listed.
+    pub fn snapshot(&self) -> Vec<JobRun> {
+        let runs = self.runs.lock().unwrap_or_else(|e| e.into_inner());
+        runs.iter()
+            .map(|(&job, &last_run)| JobRun { job, last_run })
+            .collect()
+    }
+}
+
+/// See `JobClock`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct JobRun {
+    pub job: &'static str,
+    #[serde(with = "iso8601")]
+    pub last_run: OffsetDateTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_runs() {
+        let clock = JobClock::default();
+        assert!(clock.snapshot().is_empty());
+        let other = clock.clone();
+        other.ran("backups");
+        clock.ran("daily_cleanup");
+        let first = clock.snapshot();
+        assert_eq!(
+            first.iter().map(|r| r.job).collect::<Vec<_>>(),
+            vec!["backups", "daily_cleanup"]
+        );
+        // Running again moves the time up.
+        other.ran("backups");
+        let second = other.snapshot();
+        assert!(second[0].last_run >= first[0].last_run);
+        assert_eq!(second.len(), 2);
+    }
+}
@@ -0,0 +1,222 @@
+//! Sending email. We don't speak SMTP; we build a plain text message and pipe
+//! it to a sendmail-compatible program, which is what most hosts expect you
+//! to do anyway, and it means delivery, retries, and DKIM are somebody
+//! else's problem.
+
+use crate::config::MailConfig;
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// How long sendmail gets before we give up on it. It normally just drops
+/// the message in a queue and exits.
+const SENDMAIL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A plain text email to one recipient, maybe with a file attached.
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub attachment: Option<Attachment>,
+}
+
+/// A file to send along with an email.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// How long each line of a base64'd attachment gets, per RFC 2045.
+const BASE64_LINE_LENGTH: usize = 76;
+
+impl Email {
+    /// Render the full message, headers and all. Refuses any header value
+    /// with a line break in it, since that's how you smuggle in extra
+    /// headers (and recipients).
+    pub fn to_message(&self, from: &str) -> anyhow::Result<String> {
+        let filename = self.attachment.as_ref().map(|a| a.filename.as_str());
+        let content_type = self.attachment.as_ref().map(|a| a.content_type.as_str());
+        for (name, value) in [
+            ("From", Some(from)),
+            ("To", Some(self.to.as_str())),
+            ("Subject", Some(self.subject.as_str())),
+            ("attachment filename", filename),
+            ("attachment Content-Type", content_type),
+        ] {
+            if value.is_some_and(|v| v.contains(['\r', '\n'])) {
+                bail!(
+                    "refusing to send mail with a line break in the {} header",
+                    name
+                );
+            }
+        }
+        if filename.is_some_and(|f| f.contains(['"', '\\'])) {
+            bail!("refusing to send an attachment with quotes in its filename");
+        }
+        let headers = format!(
+            "From: {}\nTo: {}\nSubject: {}\nMIME-Version: 1.0\n",
+            from, &self.to, &self.subject
+        );
+        let text_part = format!(
+            "Content-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: 8bit\n\n{}\n",
+            &self.body
+        );
+        let Some(attachment) = &self.attachment else {
+            return Ok(format!("{}{}", headers, text_part));
+        };
+        // "=_" can't show up in base64, and the uuid keeps it out of the body.
+        let boundary = format!("=_eardogger_{}", uuid::Uuid::new_v4().simple());
+        let encoded = BASE64.encode(&attachment.data);
+        // Base64 is pure ASCII, so chunking the bytes can't split a character.
+        let lines: Vec<&str> = encoded
+            .as_bytes()
+            .chunks(BASE64_LINE_LENGTH)
+            .map(|line| std::str::from_utf8(line).unwrap_or_default())
+            .collect();
+        Ok(format!(
+            "{headers}Content-Type: multipart/mixed; boundary=\"{boundary}\"\n\n--{boundary}\n{text_part}--{boundary}\nContent-Type: {}\nContent-Disposition: attachment; filename=\"{}\"\nContent-Transfer-Encoding: base64\n\n{}\n--{boundary}--\n",
+            &attachment.content_type,
+            &attachment.filename,
+            lines.join("\n"),
+        ))
+    }
+}
+
+/// Hand an email off to sendmail, and wait for it to accept it.
+#[tracing::instrument(skip_all)]
+pub async fn send_mail(config: &MailConfig, email: &Email) -> anyhow::Result<()> {
+    let message = email.to_message(&config.from)?;
+    let mut child = Command::new(&config.sendmail)
+        .arg("-t")
+        .arg("-i")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("couldn't get sendmail's stdin"))?;
+    let output = tokio::time::timeout(SENDMAIL_TIMEOUT, async move {
+        stdin.write_all(message.as_bytes()).await?;
+        // Hang up, so it knows the message is over.
+        drop(stdin);
+        child.wait_with_output().await
+    })
+    .await
+    .map_err(|_| anyhow!("sendmail took longer than {:?}", SENDMAIL_TIMEOUT))??;
+    if !output.status.success() {
+        bail!(
+            "sendmail failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email() -> Email {
+        Email {
+            to: "someone@example.com".to_string(),
+            subject: "Hi".to_string(),
+            body: "Line one\nLine two".to_string(),
+            attachment: None,
+        }
+    }
+
+    #[test]
+    fn messages() {
+        let message = email().to_message("Eardogger <dogs@example.com>").unwrap();
+        assert!(message.starts_with(
+            "From: Eardogger <dogs@example.com>\nTo: someone@example.com\nSubject: Hi\n"
+        ));
+        assert!(message.ends_with("\n\nLine one\nLine two\n"));
+
+        // No header injection
+        let sneaky = Email {
+            to: "someone@example.com\nBcc: everyone@example.com".to_string(),
+            ..email()
+        };
+        assert!(sneaky.to_message("dogs@example.com").is_err());
+        assert!(email()
+            .to_message("dogs@example.com\r\nBcc: x@example.com")
+            .is_err());
+    }
+
+    #[test]
+    fn attachments() {
+        let data = "{\"id\":1}\n".repeat(20);
+        let with_file = Email {
+            attachment: Some(Attachment {
+                filename: "backup.jsonl".to_string(),
+                content_type: "application/x-ndjson".to_string(),
+                data: data.clone().into_bytes(),
+            }),
+            ..email()
+        };
+        let message = with_file.to_message("dogs@example.com").unwrap();
+        let (headers, rest) = message.split_once("\n\n").unwrap();
+        let boundary = headers
+            .split_once("boundary=\"")
+            .and_then(|(_, b)| b.strip_suffix('"'))
+            .expect("multipart");
+        // The text part, then the file, then the end.
+        let parts: Vec<&str> = rest.split(&format!("--{}", boundary)).collect();
+        assert_eq!(parts.len(), 4);
+        assert!(parts[1].ends_with("\n\nLine one\nLine two\n"));
+        assert!(parts[2].contains("filename=\"backup.jsonl\""));
+        assert_eq!(parts[3], "--\n");
+        let (_, encoded) = parts[2].split_once("\n\n").unwrap();
+        assert!(encoded.lines().all(|l| l.len() <= BASE64_LINE_LENGTH));
+        let decoded = BASE64.decode(encoded.replace('\n', "")).unwrap();
+        assert_eq!(decoded, data.as_bytes());
+
+        // No header injection through the filename either
+        let mut sneaky = with_file.clone();
+        sneaky.attachment.as_mut().unwrap().filename = "a\"\nBcc: x@example.com".to_string();
+        assert!(sneaky.to_message("dogs@example.com").is_err());
+    }
+
+    /// A fake sendmail that saves whatever it gets, to check the plumbing.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sends_through_sendmail() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("sendmail");
+        let outbox = dir.path().join("outbox.eml");
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\ncat > {:?}\n", outbox.to_str().unwrap()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let config = MailConfig {
+            sendmail: script,
+            from: "dogs@example.com".to_string(),
+            check_mx: false,
+        };
+        send_mail(&config, &email()).await.expect("sent");
+        let sent = std::fs::read_to_string(&outbox).unwrap();
+        assert_eq!(sent, email().to_message("dogs@example.com").unwrap());
+
+        // A sendmail that fails is an error
+        let config = MailConfig {
+            sendmail: "/bin/false".into(),
+            ..config
+        };
+        assert!(send_mail(&config, &email()).await.is_err());
+    }
+}
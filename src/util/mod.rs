@@ -1,16 +1,64 @@
+mod action_links;
+mod antispam;
 mod bookmarklets;
+mod emails;
 mod error;
+mod feature_flags;
+mod handoff;
+mod job_clock;
+mod mail;
+mod passwords;
+mod prefixes;
+mod pwned;
+mod rate_limit;
+mod redirects;
+mod totp;
 pub mod url_encoding;
+mod usernames;
+mod validation;
 
+use http::{header, HeaderMap};
+use md5::Md5;
 use rand::{thread_rng, RngCore};
-use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use thiserror::Error;
 use time::{format_description::FormatItem, macros::format_description};
 use url::Url;
 
+pub use action_links::{sign_action_link, verify_action_link};
+#[cfg(test)]
+pub use antispam::{solve_proof_of_work, StubCaptcha};
+pub use antispam::{ChallengeWidget, SignupGuard, SignupGuardFields, SignupRejection};
 pub use bookmarklets::*;
+#[cfg(test)]
+pub use emails::StubMx;
+pub use emails::{clean_email, normalize_email, MxChecker};
 pub use error::*;
+pub use feature_flags::{features_for, live_rollouts};
+pub use handoff::{handoff_url, home_peer};
+pub use job_clock::{JobClock, JobRun};
+pub use mail::{send_mail, Attachment, Email};
+pub use passwords::{check_new_password, validate_new_password, NewPasswordError};
+pub use prefixes::{site_host, suggest_prefix};
+pub use pwned::PwnedChecker;
+#[cfg(test)]
+pub use pwned::StubRange;
+pub use rate_limit::{
+    FailureCounts, FailureCountsSnapshot, IpRange, LimiterSnapshot, RateLimitAllowlist, RateLimiter,
+};
+pub use redirects::RedirectResolver;
+#[cfg(test)]
+pub use totp::current_totp;
+pub use totp::{
+    check_totp, new_recovery_codes, new_totp_secret, normalize_recovery_code, provisioning_uri,
+    totp_step, RECOVERY_CODE_COUNT,
+};
+pub use usernames::{
+    clean_username, normalize_username, username_key, UsernamePolicy, USERNAME_MAX_LENGTH,
+};
+pub use validation::{
+    clean_field, validate, Field, DISPLAY_NAME_MAX_LENGTH, EMAIL_MAX_LENGTH, NOTE_MAX_LENGTH,
+    PREFIX_MAX_LENGTH, TOKEN_COMMENT_MAX_LENGTH, URL_MAX_LENGTH,
+};
 
 // Constants
 /// A time crate format description, like this: 2024-3-22
@@ -24,8 +72,26 @@ pub const COOKIE_SESSION: &str = "eardogger.sessid";
 /// an anti-CSRF token stored in the session, but the session doesn't exist
 /// until after you log in, so.
 pub const COOKIE_LOGIN_CSRF: &str = "eardogger.loginguard";
+/// Which peer instance this browser's account lives on, if it told us. Just
+/// an origin, and only ever used to pick from the configured peers.
+pub const COOKIE_HOME_INSTANCE: &str = "eardogger.home";
+/// Someone who got their password right but still owes us a two-factor
+/// code. Signed, short-lived, and only good for the code form.
+pub const COOKIE_TOTP_PENDING: &str = "eardogger.totp";
+/// This browser's color scheme choice: light, dark, or (if it's missing)
+/// whatever the device says.
+pub const COOKIE_THEME: &str = "eardogger.theme";
+/// Response header with the session's new anti-CSRF token, on fragments and
+/// fetches that rotated it. client.js copies it into the rest of the page's
+/// forms, which would otherwise be holding a dead token.
+pub const CSRF_TOKEN_HEADER: &str = "eardogger-csrf-token";
+/// Request header the fragment buttons send their anti-CSRF token in, to
+/// keep it out of URLs (and thus access logs and Referer headers). Being a
+/// custom header, it also can't come from another site without a CORS
+/// preflight, which we don't allow for fragments.
+pub const CSRF_SUBMIT_HEADER: &str = "x-csrf-token";
 pub const PAGE_DEFAULT_SIZE: u32 = 50;
-const PAGE_MAX_SIZE: u32 = 500;
+pub const PAGE_MAX_SIZE: u32 = 500;
 pub const DELETE_ACCOUNT_CONFIRM_STRING: &str = "delete my account";
 
 /// Use the thread_rng CSPRNG to create a random UUID, formatted as a String.
@@ -52,6 +118,14 @@ pub fn sha256sum(cleartext: &str) -> String {
     base16ct::lower::encode_string(&hash)
 }
 
+/// Hex md5 of some text. Don't use this for anything you'd choose md5 for on
+/// purpose; it's here because the KOReader sync protocol hashes passwords
+/// with it on the client side, so that's what we get to compare against.
+pub fn md5sum(cleartext: &str) -> String {
+    let hash = Md5::digest(cleartext);
+    base16ct::lower::encode_string(&hash)
+}
+
 /// Metadata about which fraction of a collection was returned by a
 /// list method, for building pagination affordances.
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -62,11 +136,14 @@ pub struct ListMeta {
 }
 
 impl ListMeta {
-    pub fn to_pagination(self) -> Pagination {
+    /// `default_size` is whatever size the list would've been without a
+    /// `size` param (the viewer's page size pref, or the site default); the
+    /// page links only mention the size if it's something else.
+    pub fn to_pagination(self, default_size: u32) -> Pagination {
         let total_pages = self.count.div_ceil(self.size);
         // page 0 isn't a thing:
         let current_page = self.page.max(1);
-        let page_size = if self.size == PAGE_DEFAULT_SIZE {
+        let page_size = if self.size == default_size {
             None
         } else {
             Some(self.size)
@@ -93,19 +170,9 @@ impl ListMeta {
     }
 }
 
-/// Pagination details built from a ListMeta, useful when displaying
-/// page-turning controls in a template.
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Pagination {
-    pub current_page: u32,
-    // The reason page_size is optional is so that you get cleaner URLs if
-    // you didn't override the default size.
-    pub page_size: Option<u32>,
-    pub prev_page: Option<u32>,
-    pub next_page: Option<u32>,
-    pub total_pages: u32,
-    pub total_count: u32,
-}
+// Pagination is part of the API's wire format, so it lives in the library
+// half of the crate.
+pub use eardogger_rs::api_types::Pagination;
 
 /// Given a (1-indexed) page and size, calculate an OFFSET value to pass
 /// to a sqlite query. Sqlite integers in sqlx are pretty much always i64,
@@ -126,24 +193,6 @@ pub fn sqlite_offset(page: u32, size: u32) -> Result<i64, UserError> {
         ))
 }
 
-#[derive(Error, Debug)]
-pub enum NewPasswordError {
-    #[error("New passwords didn't match.")]
-    NonMatching,
-    #[error("New password can't be empty.")]
-    Empty,
-}
-
-pub fn check_new_password(new1: &str, new2: &str) -> Result<(), NewPasswordError> {
-    if new1 != new2 {
-        Err(NewPasswordError::NonMatching)
-    } else if new1.is_empty() {
-        Err(NewPasswordError::Empty)
-    } else {
-        Ok(())
-    }
-}
-
 /// Axum's `Form` fields show up as `Some("")` if they're present but empty,
 /// but we have a few functions that want to be able to omit empty fields.
 /// So the convention is that they're marked as `Option<String>` in the relevant
@@ -160,6 +209,107 @@ pub fn clean_optional_form_field(maybe: Option<&str>) -> Option<&str> {
     })
 }
 
+/// Clean up the optional note that can come with an update (blank means
+/// no note), and make sure it's actually short.
+pub fn clean_note(note: Option<&str>) -> Result<Option<&str>, UserError> {
+    clean_field(Field::Note, note)
+}
+
+/// Longest allowed custom CSS snippet, in characters.
+pub const CUSTOM_CSS_MAX_LENGTH: usize = 4000;
+
+/// Things custom CSS can't contain: `<` could close the style tag, and
+/// backslash escapes could sneak the rest of these past us. The others all
+/// fetch stuff from elsewhere (or, in old browsers, run script).
+/// (Old IE's `behavior:` isn't on the list, since it'd also catch the
+/// perfectly nice `scroll-behavior`, and no browser that honors it is
+/// still around.)
+const CUSTOM_CSS_FORBIDDEN: &[&str] = &[
+    "<",
+    "\\",
+    "url(",
+    "@import",
+    "image(",
+    "image-set(",
+    "cross-fade(",
+    "expression(",
+    "-moz-binding",
+    "javascript:",
+];
+
+/// Check a user's custom CSS snippet before saving or previewing it (blank
+/// means none). It goes into the page as-is, so this is strict: no markup,
+/// no escapes, and nothing that loads resources. Displaying a broken page
+/// is fine; phoning home isn't.
+pub fn clean_custom_css(css: Option<&str>) -> Result<Option<&str>, UserError> {
+    let Some(css) = clean_optional_form_field(css) else {
+        return Ok(None);
+    };
+    if css.chars().count() > CUSTOM_CSS_MAX_LENGTH {
+        return Err(UserError::CustomCssTooLong);
+    }
+    let lowered = css.to_lowercase();
+    if let Some(what) = CUSTOM_CSS_FORBIDDEN.iter().find(|f| lowered.contains(*f)) {
+        return Err(UserError::CustomCssNotAllowed {
+            what: what.to_string(),
+        });
+    }
+    Ok(Some(css))
+}
+
+/// The client's IP address, as best we can tell. We always run behind a web
+/// server (Apache, as a reverse proxy or via fcgi), so we never see the
+/// client's socket ourselves; a reverse proxy appends the address it saw to
+/// X-Forwarded-For. Anything earlier in that header came from the client and
+/// could be made up, so only the last entry counts. Under fcgi there's no
+/// such header, and you get None.
+pub fn client_ip(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .last()?
+        .to_str()
+        .ok()?
+        .rsplit(',')
+        .next()
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+}
+
+/// An opaque identifier for the device a request came from: a hash of the
+/// user agent and client IP, so we can recognize repeat logins without
+/// keeping either one around.
+pub fn device_hash(headers: &HeaderMap) -> String {
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let ip = client_ip(headers).unwrap_or_default();
+    sha256sum(&format!("{}\n{}", user_agent, ip))
+}
+
+/// A strong ETag for a response body, quotes and all. Half a sha256 is
+/// plenty to tell two versions of a list apart.
+pub fn etag(body: &[u8]) -> String {
+    let hash = Sha256::digest(body);
+    format!("\"{}\"", base16ct::lower::encode_string(&hash[..16]))
+}
+
+/// Whether a request's If-None-Match says the client already has the
+/// version with this ETag, so a 304 will do. Uses the weak comparison the
+/// RFC asks for here (a `W/` on either side doesn't matter), and `*`
+/// matches anything.
+pub fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
 /// Trim any leading "m." or "www." subdomains off a hostname at the start
 /// of a string. (Generally you'll call this function with *most* of a URL,
 /// after first removing the scheme and the `://` separator.)
@@ -179,6 +329,8 @@ fn trim_m_www(mut partial_url: &str) -> &str {
 /// Validate that the input is an HTTP or HTTPS URL, then remove the scheme and
 /// the `://` separator. The result can be passed to [`trim_m_www`].
 fn trim_and_check_scheme(url: &str) -> Result<&str, UserError> {
+    // Url::parse forgives surrounding whitespace, but our slicing wouldn't.
+    let url = url.trim();
     let Ok(parsed) = Url::parse(url) else {
         return Err(UserError::DogearInvalidUrl {
             url: url.to_string(),
@@ -207,7 +359,10 @@ pub fn matchable_from_url(url: &str) -> Result<&str, UserError> {
 
 /// Clean and normalize a provided prefix matcher string before persisting it.
 /// A cleaned prefix can reliably match the results of `matchable_from_url`.
+/// If you change the rules here, run `eardogger-rs db normalize` on existing
+/// deployments to bring their old rows up to date.
 pub fn normalize_prefix_matcher(prefix: &str) -> &str {
+    let prefix = prefix.trim();
     // The input shouldn't have a URL scheme, so we normally expect to
     // just eat this error. But if we *happen* to have an http(s) scheme,
     // go ahead and trim it, since the user's intent was still clear.
@@ -215,12 +370,57 @@ pub fn normalize_prefix_matcher(prefix: &str) -> &str {
         Ok(s) => s,
         Err(_) => prefix,
     };
-    trim_m_www(scheme_trimmed)
+    let trimmed = trim_m_www(scheme_trimmed);
+    // Trailing periods are almost always from copying a URL out of a
+    // sentence. Dropping them from a prefix can only make it match a tiny
+    // bit more, so it's safe even when the period was real. (Unless that
+    // leaves nothing, which would match everything.)
+    match trimmed.trim_end_matches('.') {
+        "" => trimmed,
+        t => t,
+    }
+}
+
+/// Clean up a current URL before persisting it. Unlike prefixes, trailing
+/// periods stay: they're rare in real URLs, but when they're there they
+/// matter (looking at you, Wikipedia).
+pub fn normalize_current_url(url: &str) -> &str {
+    url.trim()
+}
+
+/// Split a URL into everything-but-the-trailing-number and the trailing
+/// number, ignoring one trailing slash. None if it doesn't end in a number.
+fn split_trailing_number(url: &str) -> Option<(&str, i64)> {
+    let url = url.strip_suffix('/').unwrap_or(url);
+    let stem = url.trim_end_matches(|c: char| c.is_ascii_digit());
+    let digits = &url[stem.len()..];
+    // Past 18 digits, it's not a chapter number, it's an ID or a timestamp.
+    if digits.is_empty() || digits.len() > 18 {
+        return None;
+    }
+    Some((stem, digits.parse().ok()?))
+}
+
+/// How many chapters a dogear moved between two URLs, if we can tell: both
+/// need to end in a number, and be otherwise identical (modulo scheme and
+/// `m.`/`www.`). Positive is forward, negative is backward. Totally
+/// heuristic, since plenty of sites number their pages some other way, but
+/// it's right often enough to be a nice touch.
+pub fn chapter_delta(previous: &str, current: &str) -> Option<i64> {
+    let previous = matchable_from_url(previous).unwrap_or(previous);
+    let current = matchable_from_url(current).unwrap_or(current);
+    let (prev_stem, prev_num) = split_trailing_number(previous)?;
+    let (cur_stem, cur_num) = split_trailing_number(current)?;
+    if prev_stem != cur_stem {
+        return None;
+    }
+    Some(cur_num - prev_num)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::util::{normalize_prefix_matcher, trim_m_www};
+    use crate::util::{chapter_delta, etag, if_none_match, normalize_prefix_matcher, trim_m_www};
+    use http::{header, HeaderMap, HeaderValue};
 
     use super::trim_and_check_scheme;
 
@@ -243,6 +443,10 @@ mod tests {
             trim_and_check_scheme("http://example.com/comic").unwrap(),
             "example.com/comic"
         );
+        assert_eq!(
+            trim_and_check_scheme(" https://example.com/comic\t").unwrap(),
+            "example.com/comic"
+        );
         assert!(trim_and_check_scheme("noscheme.example.com/comic").is_err());
         assert!(trim_and_check_scheme("ftp://example.com/comic.tgz").is_err());
     }
@@ -261,6 +465,16 @@ mod tests {
             normalize_prefix_matcher("http://www.m.example.com"),
             "example.com"
         );
+        // Copy-paste junk
+        assert_eq!(
+            normalize_prefix_matcher("  www.example.com/comic. \n"),
+            "example.com/comic"
+        );
+        assert_eq!(
+            normalize_prefix_matcher(" https://example.com/comic..."),
+            "example.com/comic"
+        );
+        assert_eq!(normalize_prefix_matcher("..."), "...");
         // If you do this one, you just fucked up and need to fix it, we can't help ya:
         assert_eq!(
             normalize_prefix_matcher("ftp://www.m.example.com"),
@@ -268,7 +482,7 @@ mod tests {
         );
     }
 
-    use super::clean_optional_form_field;
+    use super::{clean_note, clean_optional_form_field, NOTE_MAX_LENGTH};
 
     #[test]
     fn clean_optional_test() {
@@ -283,4 +497,152 @@ mod tests {
         );
         assert_eq!(clean_optional_form_field(Some("")), None);
     }
+
+    #[test]
+    fn clean_note_test() {
+        assert_eq!(clean_note(Some(" mid-scene ")).unwrap(), Some("mid-scene"));
+        assert_eq!(clean_note(Some("  ")).unwrap(), None);
+        // Counts characters, not bytes
+        assert!(clean_note(Some(&"🐶".repeat(NOTE_MAX_LENGTH))).is_ok());
+        assert!(clean_note(Some(&"x".repeat(NOTE_MAX_LENGTH + 1))).is_err());
+    }
+
+    #[test]
+    fn clean_custom_css_test() {
+        assert_eq!(clean_custom_css(None).unwrap(), None);
+        assert_eq!(clean_custom_css(Some("  ")).unwrap(), None);
+        let fine = "html { scroll-behavior: smooth; }\n.dogear > a { color: rebeccapurple; }";
+        assert_eq!(clean_custom_css(Some(fine)).unwrap(), Some(fine));
+        for bad in [
+            "</style><script>alert(1)</script>",
+            "body { background: URL(https://example.com/track.gif); }",
+            "@import 'https://example.com/x.css';",
+            "body { background: \\75 rl(x); }",
+            "a { background-image: -webkit-image-set('x.png' 1x); }",
+        ] {
+            assert!(matches!(
+                clean_custom_css(Some(bad)),
+                Err(UserError::CustomCssNotAllowed { .. })
+            ));
+        }
+        let long = "a{}".repeat(CUSTOM_CSS_MAX_LENGTH);
+        assert!(matches!(
+            clean_custom_css(Some(&long)),
+            Err(UserError::CustomCssTooLong)
+        ));
+    }
+
+    #[test]
+    fn client_device_test() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(client_ip(&headers), None);
+        let anonymous = device_hash(&headers);
+        headers.insert(header::USER_AGENT, "Firefox".parse().unwrap());
+        let no_ip = device_hash(&headers);
+        assert_ne!(anonymous, no_ip);
+
+        // Only the proxy's entry counts, not whatever the client claimed
+        headers.insert("x-forwarded-for", "10.0.0.1, 203.0.113.9".parse().unwrap());
+        assert_eq!(client_ip(&headers), Some("203.0.113.9"));
+        let with_ip = device_hash(&headers);
+        assert_ne!(no_ip, with_ip);
+        headers.insert("x-forwarded-for", "10.0.0.2, 203.0.113.9".parse().unwrap());
+        assert_eq!(device_hash(&headers), with_ip);
+        // Split across repeated headers, same deal
+        headers.append("x-forwarded-for", "198.51.100.7".parse().unwrap());
+        assert_eq!(client_ip(&headers), Some("198.51.100.7"));
+    }
+
+    #[test]
+    fn chapter_deltas() {
+        // Forward, backward, and nowhere
+        assert_eq!(
+            chapter_delta(
+                "https://example.com/comic/24",
+                "https://example.com/comic/27"
+            ),
+            Some(3)
+        );
+        assert_eq!(
+            chapter_delta(
+                "https://example.com/comic/24",
+                "https://example.com/comic/23"
+            ),
+            Some(-1)
+        );
+        assert_eq!(
+            chapter_delta(
+                "https://example.com/comic/24",
+                "https://example.com/comic/24"
+            ),
+            Some(0)
+        );
+        // Trailing slashes, schemes, and non-signifying subdomains don't matter
+        assert_eq!(
+            chapter_delta("http://www.example.com/c/9/", "https://example.com/c/10"),
+            Some(1)
+        );
+        // Query strings are fine, as long as the number's at the end
+        assert_eq!(
+            chapter_delta(
+                "https://example.com/view.php?page=99",
+                "https://example.com/view.php?page=101"
+            ),
+            Some(2)
+        );
+        // Different stems: no idea
+        assert_eq!(
+            chapter_delta(
+                "https://example.com/book1/chapter/5",
+                "https://example.com/book2/chapter/1"
+            ),
+            None
+        );
+        // Not numbered
+        assert_eq!(
+            chapter_delta(
+                "https://example.com/comic/24",
+                "https://example.com/comic/extras"
+            ),
+            None
+        );
+        assert_eq!(
+            chapter_delta(
+                "https://example.com/story/the-beginning",
+                "https://example.com/story/the-end"
+            ),
+            None
+        );
+        // Huge numbers are probably IDs, and would overflow anyway
+        assert_eq!(
+            chapter_delta(
+                "https://example.com/p/1234567890123456789012",
+                "https://example.com/p/1234567890123456789013"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn etags() {
+        let tag = etag(b"some list");
+        assert!(tag.starts_with('"') && tag.ends_with('"'));
+        assert_eq!(tag, etag(b"some list"));
+        assert_ne!(tag, etag(b"some other list"));
+
+        let with = |values: &[&str]| {
+            let mut headers = HeaderMap::new();
+            for v in values {
+                headers.append(header::IF_NONE_MATCH, HeaderValue::from_str(v).unwrap());
+            }
+            headers
+        };
+        assert!(!if_none_match(&with(&[]), &tag));
+        assert!(if_none_match(&with(&[&tag]), &tag));
+        assert!(if_none_match(&with(&[&format!("W/{}", tag)]), &tag));
+        assert!(if_none_match(&with(&[&format!("\"abc\", {}", tag)]), &tag));
+        assert!(if_none_match(&with(&["\"abc\"", &tag]), &tag));
+        assert!(if_none_match(&with(&["*"]), &tag));
+        assert!(!if_none_match(&with(&["\"abc\""]), &tag));
+    }
 }
@@ -0,0 +1,180 @@
+//! Checking new passwords against the site's password policy (see
+//! `config::PasswordPolicy`), at signup and when changing one. Strength
+//! comes from zxcvbn, which guesses how many tries an attacker would need
+//! and is pretty good at spotting keyboard walks, dictionary words, and
+//! dates dressed up with numbers. If the policy says to, we also check
+//! whether a password has already leaked in somebody's breach (see
+//! `pwned`).
+
+use super::pwned::PwnedChecker;
+use crate::config::PasswordPolicy;
+use thiserror::Error;
+use zxcvbn::zxcvbn;
+
+/// What we say when zxcvbn doesn't have any specific advice.
+const GENERIC_ADVICE: &str = "Try a longer one, like a few unrelated words together.";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum NewPasswordError {
+    #[error("New passwords didn't match.")]
+    NonMatching,
+    #[error("New password can't be empty.")]
+    Empty,
+    #[error("New password has to be at least {min} characters long.")]
+    TooShort { min: usize },
+    #[error("That password is on this site's list of banned passwords. Pick something else.")]
+    Banned,
+    #[error("That password would be too easy to guess. {advice}")]
+    TooWeak { advice: String },
+    #[error("That password has shown up {count} times in known data breaches, so it's one of the first things an attacker would try. Pick something else.")]
+    Breached { count: u64 },
+}
+
+impl NewPasswordError {
+    /// The id of the form field this is about, on the forms that set a new
+    /// password (signup and change password both use the same ids).
+    pub fn field(&self) -> &'static str {
+        match self {
+            Self::NonMatching => "new_password_again",
+            _ => "new_password",
+        }
+    }
+}
+
+/// Check a new password (and its confirmation) against the policy. The
+/// username counts as something a guesser would try, so passwords built
+/// out of it come out weak.
+pub fn check_new_password(
+    new1: &str,
+    new2: &str,
+    username: &str,
+    policy: &PasswordPolicy,
+) -> Result<(), NewPasswordError> {
+    if new1 != new2 {
+        return Err(NewPasswordError::NonMatching);
+    }
+    if new1.is_empty() {
+        return Err(NewPasswordError::Empty);
+    }
+    if new1.chars().count() < policy.min_length {
+        return Err(NewPasswordError::TooShort {
+            min: policy.min_length,
+        });
+    }
+    if policy
+        .banned
+        .iter()
+        .any(|b| b.trim().eq_ignore_ascii_case(new1.trim()))
+    {
+        return Err(NewPasswordError::Banned);
+    }
+    let mut user_inputs: Vec<&str> = vec![username, "eardogger"];
+    user_inputs.extend(policy.banned.iter().map(String::as_str));
+    let estimate = zxcvbn(new1, &user_inputs);
+    if u8::from(estimate.score()) < policy.min_strength {
+        let advice = estimate
+            .feedback()
+            .map(|f| {
+                let mut parts: Vec<String> =
+                    f.warning().map(|w| w.to_string()).into_iter().collect();
+                parts.extend(f.suggestions().iter().map(|s| s.to_string()));
+                parts.join(" ")
+            })
+            .filter(|a| !a.is_empty())
+            .unwrap_or_else(|| GENERIC_ADVICE.to_string());
+        return Err(NewPasswordError::TooWeak { advice });
+    }
+    Ok(())
+}
+
+/// The whole check for a new password: `check_new_password`, then the
+/// breach list if the policy turns that on. If we can't reach the breach
+/// list, the password passes.
+pub async fn validate_new_password(
+    new1: &str,
+    new2: &str,
+    username: &str,
+    policy: &PasswordPolicy,
+    pwned: &PwnedChecker,
+) -> Result<(), NewPasswordError> {
+    check_new_password(new1, new2, username, policy)?;
+    if policy.check_breached {
+        if let Some(count) = pwned.times_pwned(new1).await.filter(|c| *c > 0) {
+            return Err(NewPasswordError::Breached { count });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::pwned::StubRange;
+    use super::*;
+
+    #[test]
+    fn password_policy() {
+        let policy = PasswordPolicy {
+            banned: vec!["Eardogger.com".to_string()],
+            ..Default::default()
+        };
+        let check = |p: &str| check_new_password(p, p, "whoever", &policy);
+
+        assert_eq!(
+            check_new_password("one thing", "another", "whoever", &policy),
+            Err(NewPasswordError::NonMatching)
+        );
+        assert_eq!(check(""), Err(NewPasswordError::Empty));
+        assert_eq!(check("short"), Err(NewPasswordError::TooShort { min: 8 }));
+        assert_eq!(check("eardogger.COM"), Err(NewPasswordError::Banned));
+        for weak in [
+            "password",
+            "12345678",
+            "qwertyuiop",
+            "whoever123",
+            "eardogger2024",
+        ] {
+            match check(weak) {
+                Err(NewPasswordError::TooWeak { advice }) => assert!(!advice.is_empty()),
+                other => panic!("{:?} should be too weak, got {:?}", weak, other),
+            }
+        }
+        assert_eq!(check("correct horse battery staple"), Ok(()));
+        assert_eq!(check("vivid-Otter-lamp-97"), Ok(()));
+
+        // Anything goes, if the operator says so
+        let lax = PasswordPolicy {
+            min_length: 1,
+            min_strength: 0,
+            banned: Vec::new(),
+            check_breached: false,
+        };
+        assert_eq!(check_new_password("a", "a", "whoever", &lax), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn breach_check() {
+        let breached = "vivid-Otter-lamp-97";
+        let pwned = PwnedChecker::with_source(StubRange::new(&[breached]));
+        let mut policy = PasswordPolicy::default();
+        let check = |p: &'static str, policy: PasswordPolicy| {
+            let pwned = pwned.clone();
+            async move { validate_new_password(p, p, "whoever", &policy, &pwned).await }
+        };
+        // Off by default
+        assert_eq!(check(breached, policy.clone()).await, Ok(()));
+        policy.check_breached = true;
+        assert_eq!(
+            check(breached, policy.clone()).await,
+            Err(NewPasswordError::Breached { count: 1234 })
+        );
+        assert_eq!(
+            check("correct horse battery staple", policy.clone()).await,
+            Ok(())
+        );
+        // The local rules still come first
+        assert_eq!(
+            check("short", policy).await,
+            Err(NewPasswordError::TooShort { min: 8 })
+        );
+    }
+}
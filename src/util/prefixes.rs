@@ -0,0 +1,250 @@
+//! Guessing a prefix from a single URL, for the create form and the suggest
+//! API. Serial sites mostly put the story's identity up front and the
+//! chapter at the end, so the trick is knowing how much of the end is
+//! chapter: numbers, `chapter-12`-style slugs, blog dates, and the title
+//! slugs some sites tack on after a chapter number.
+//!
+//! It's a guess, and the user gets to fix it before saving. When nothing
+//! looks chapter-ish, we just drop the last path segment. For sites where
+//! the guess is reliably wrong, the operator can load a site rule (see
+//! `crate::site_rules`), which says outright how many path segments name
+//! a story; that skips the guessing.
+
+use super::normalize_prefix_matcher;
+use lazy_static::lazy_static;
+use regex::Regex;
+use url::Url;
+
+lazy_static! {
+    /// A segment that names one chapter: a number, maybe with a keyword in
+    /// front (`ch12`, `page-24`, `season-3-ep-100`) and maybe with some
+    /// title or file extension after (`12-the-big-day`, `0012.html`).
+    static ref CHAPTERISH: Regex = Regex::new(
+        r"(?i)^(?:(?:chapter|chap|ch|c|page|pg|p|part|pt|episode|ep|e|season|vol|volume|book|issue|no|strip)[-_.]?)?\d+(?:[-_.,].*)?$"
+    )
+    .unwrap();
+    /// A segment that means "the next bit is the chapter", like the
+    /// `chapters` in `/works/123/chapters/456`.
+    static ref KEYWORD: Regex = Regex::new(
+        r"(?i)^(?:chapters?|chap|ch|pages?|episodes?|ep|parts?)$"
+    )
+    .unwrap();
+    /// A filename that isn't part of the story at all.
+    static ref NOISE: Regex = Regex::new(r"(?i)^(?:index\.\w+|viewer|view|read)$").unwrap();
+    static ref YEAR: Regex = Regex::new(r"^(?:19|20)\d\d$").unwrap();
+    static ref MONTH_OR_DAY: Regex = Regex::new(r"^\d\d?$").unwrap();
+    static ref DIGIT: Regex = Regex::new(r"\d").unwrap();
+}
+
+/// Big bare numbers are usually story or post IDs, not chapter numbers.
+/// We'll only treat them as chapters when a keyword says so.
+const MAX_BARE_CHAPTER_DIGITS: usize = 4;
+
+/// Propose a prefix for a URL: its host (minus `www.` or `m.`) plus the
+/// part of its path that looks like it's about the whole serial. If there's
+/// a site rule for the host, pass its segment count as `rule_segments` and
+/// we'll use exactly that many instead of guessing. Returns None for
+/// non-http(s) URLs.
+pub fn suggest_prefix(url: &str, rule_segments: Option<usize>) -> Option<String> {
+    let parsed = parse_web_url(url)?;
+    let mut prefix = site_host_of(&parsed)?;
+    let segments: Vec<&str> = parsed
+        .path_segments()
+        .map(|s| s.filter(|seg| !seg.is_empty()).collect())
+        .unwrap_or_default();
+    let keep = match rule_segments {
+        Some(n) => segments[..n.min(segments.len())].to_vec(),
+        None => story_segments(&segments),
+    };
+    for segment in keep {
+        prefix.push('/');
+        prefix.push_str(segment);
+    }
+    Some(normalize_prefix_matcher(&prefix).to_string())
+}
+
+/// A URL's host the way prefixes spell it: minus `www.` or `m.`, plus the
+/// port if there is one. Site rules are keyed on this.
+pub fn site_host(url: &str) -> Option<String> {
+    site_host_of(&parse_web_url(url)?)
+}
+
+fn parse_web_url(url: &str) -> Option<Url> {
+    let parsed = Url::parse(url.trim()).ok()?;
+    matches!(parsed.scheme(), "http" | "https").then_some(parsed)
+}
+
+fn site_host_of(parsed: &Url) -> Option<String> {
+    let mut host = parsed.host_str()?.to_string();
+    if let Some(port) = parsed.port() {
+        host.push_str(&format!(":{}", port));
+    }
+    Some(normalize_prefix_matcher(&host).to_string())
+}
+
+/// Peel the chapter-ish stuff off the end of a path.
+fn story_segments<'a>(segments: &[&'a str]) -> Vec<&'a str> {
+    let mut segs = segments.to_vec();
+    if segs.last().is_some_and(|s| NOISE.is_match(s)) {
+        segs.pop();
+    }
+    // Blog-style dates: `/2024/03/18/some-post` is a post on the blog at
+    // whatever came before the date.
+    if let Some(start) = date_start(&segs) {
+        segs.truncate(start);
+        return segs;
+    }
+    let before_trim = segs.len();
+    // A title slug after the chapter number: `/chapter/1234567/the-title`
+    let n = segs.len();
+    if n >= 2 && !DIGIT.is_match(segs[n - 1]) && is_chapter(&segs, n - 2) {
+        segs.pop();
+    }
+    let n = segs.len();
+    if n >= 1 && is_chapter(&segs, n - 1) {
+        segs.pop();
+        if segs.last().is_some_and(|s| KEYWORD.is_match(s)) {
+            segs.pop();
+        }
+    }
+    // Nothing looked like a chapter, so assume the last bit is one anyway
+    // (unless it's an ID, like a one-shot story's).
+    if segs.len() == before_trim && !segs.last().is_some_and(|s| is_id(s)) {
+        segs.pop();
+    }
+    segs
+}
+
+/// Whether the segment at `i` names a chapter.
+fn is_chapter(segs: &[&str], i: usize) -> bool {
+    let seg = segs[i];
+    if !CHAPTERISH.is_match(seg) {
+        return false;
+    }
+    if is_id(seg) {
+        return i > 0 && KEYWORD.is_match(segs[i - 1]);
+    }
+    true
+}
+
+fn is_id(seg: &str) -> bool {
+    seg.len() > MAX_BARE_CHAPTER_DIGITS && seg.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Where a `year/month(/day)` run starts, if it's followed by at most one
+/// more segment (the post slug).
+fn date_start(segs: &[&str]) -> Option<usize> {
+    (0..segs.len().saturating_sub(1)).find(|&i| {
+        if !YEAR.is_match(segs[i]) || !MONTH_OR_DAY.is_match(segs[i + 1]) {
+            return false;
+        }
+        let mut end = i + 2;
+        if segs.get(end).is_some_and(|s| MONTH_OR_DAY.is_match(s)) {
+            end += 1;
+        }
+        segs.len() - end <= 1
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_url_shapes() {
+        let cases = [
+            // Webcomics
+            ("https://example.com/comic/24", "example.com/comic"),
+            ("https://www.example.com/comic/page-24/", "example.com/comic"),
+            ("https://example.com/comic/page/24", "example.com/comic"),
+            ("https://xkcd.com/2000/", "xkcd.com"),
+            ("https://example.com/archive/0012.html", "example.com/archive"),
+            ("https://example.com/strips/strip-305", "example.com/strips"),
+            ("https://example.com/c/5", "example.com/c"),
+            // Chaptered fiction
+            (
+                "https://example.com/story/chapter-12.html",
+                "example.com/story",
+            ),
+            ("https://example.com/novel/ch12", "example.com/novel"),
+            (
+                "https://example.com/serial/12-the-big-day/",
+                "example.com/serial",
+            ),
+            (
+                "https://archiveofourown.org/works/12345678/chapters/98765432",
+                "archiveofourown.org/works/12345678",
+            ),
+            (
+                "https://archiveofourown.org/works/12345678",
+                "archiveofourown.org/works/12345678",
+            ),
+            (
+                "https://www.fanfiction.net/s/12345678/5/Some-Story-Title",
+                "fanfiction.net/s/12345678",
+            ),
+            (
+                "https://www.royalroad.com/fiction/12345/some-story/chapter/1234567/the-one-with-the-thing",
+                "royalroad.com/fiction/12345/some-story",
+            ),
+            (
+                "https://m.webtoons.com/en/fantasy/tower-of-god/season-3-ep-100/viewer?title_no=95&episode_no=500",
+                "webtoons.com/en/fantasy/tower-of-god",
+            ),
+            (
+                "https://tapas.io/episode/1234567",
+                "tapas.io",
+            ),
+            // Blog-hosted serials
+            (
+                "https://example.wordpress.com/2024/03/18/chapter-12-the-thing/",
+                "example.wordpress.com",
+            ),
+            (
+                "https://example.com/serial/2024/03/the-thing",
+                "example.com/serial",
+            ),
+            // Nothing chapter-ish: drop the last bit
+            ("https://example.com/serial/the-beginning", "example.com/serial"),
+            ("https://example.com/viewcomic.php?page=24", "example.com"),
+            ("https://example.com/", "example.com"),
+            ("http://localhost:3000/book/3", "localhost:3000/book"),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(
+                suggest_prefix(url, None).as_deref(),
+                Some(expected),
+                "{}",
+                url
+            );
+        }
+        assert_eq!(suggest_prefix("ftp://example.com/files/3", None), None);
+        assert_eq!(suggest_prefix("not a url", None), None);
+    }
+
+    #[test]
+    fn site_rules_override_the_guess() {
+        let url = "https://www.example.com/fiction/123/some-story/chapter/456/title";
+        let cases = [
+            (0, "example.com"),
+            (2, "example.com/fiction/123"),
+            (3, "example.com/fiction/123/some-story"),
+            // More than there are: the whole path
+            (99, "example.com/fiction/123/some-story/chapter/456/title"),
+        ];
+        for (segments, expected) in cases {
+            assert_eq!(
+                suggest_prefix(url, Some(segments)).as_deref(),
+                Some(expected),
+                "{}",
+                segments
+            );
+        }
+        assert_eq!(site_host(url).as_deref(), Some("example.com"));
+        assert_eq!(
+            site_host("http://m.localhost:3000/x").as_deref(),
+            Some("localhost:3000")
+        );
+        assert_eq!(site_host("mailto:nobody@example.com"), None);
+    }
+}
@@ -0,0 +1,220 @@
+//! Checking new passwords against Have I Been Pwned's list of passwords
+//! from known breaches, without ever telling it the password. We send the
+//! first five hex digits of the password's SHA-1, get back every breached
+//! hash that starts with them (the "range" API, which is HIBP's take on
+//! k-anonymity), and look for ours in the pile ourselves.
+//!
+//! It's optional (`passwords.check_breached`), and it fails open: if the
+//! API is slow or down, a password just gets judged on the local policy.
+//! Ranges get cached for a while, since they barely change and signups
+//! shouldn't each cost a round trip.
+
+use futures_util::future::BoxFuture;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const HIBP_RANGE_URL: &str = "https://api.pwnedpasswords.com/range/";
+/// Signing up shouldn't hang on someone else's API.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long to trust a fetched range. The real list changes rarely.
+const CACHE_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+// Once the cache gets this big, sweep out expired ranges on the next write.
+const PRUNE_THRESHOLD: usize = 1000;
+
+/// Somewhere to get ranges from. The real one is HIBP; tests use a stub.
+pub trait RangeSource: Send + Sync + std::fmt::Debug {
+    /// Fetch the `SUFFIX:COUNT` lines for a five-hex-digit (uppercase) hash
+    /// prefix.
+    fn fetch<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<String>>;
+}
+
+/// The real HIBP range API.
+#[derive(Debug)]
+struct HibpApi {
+    http: reqwest::Client,
+}
+
+impl RangeSource for HibpApi {
+    fn fetch<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<String>> {
+        Box::pin(async move {
+            let body = self
+                .http
+                .get(format!("{}{}", HIBP_RANGE_URL, prefix))
+                // Pads the response with fake zero-count entries, so its
+                // size doesn't give away which prefix we asked for.
+                .header("Add-Padding", "true")
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            Ok(body)
+        })
+    }
+}
+
+type Range = Arc<HashMap<String, u64>>;
+
+/// Looks up how many breaches a password has shown up in. Cheap to clone;
+/// clones share the same cache.
+#[derive(Clone, Debug)]
+pub struct PwnedChecker {
+    source: Arc<dyn RangeSource>,
+    // prefix => (fetched at, suffix => count). std Mutex is fine, since
+    // nobody holds it across an await.
+    cache: Arc<Mutex<HashMap<String, (Instant, Range)>>>,
+}
+
+impl PwnedChecker {
+    /// A checker that asks HIBP.
+    pub fn new() -> reqwest::Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .user_agent(concat!("eardogger-rs/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(Self::with_source(HibpApi { http }))
+    }
+
+    /// A checker that asks somewhere else.
+    pub fn with_source(source: impl RangeSource + 'static) -> Self {
+        Self {
+            source: Arc::new(source),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// How many times a password has turned up in known breaches (zero if
+    /// never), or None if we couldn't find out.
+    #[tracing::instrument(skip_all)]
+    pub async fn times_pwned(&self, password: &str) -> Option<u64> {
+        let hash = base16ct::upper::encode_string(&Sha1::digest(password));
+        let (prefix, suffix) = hash.split_at(5);
+        let range = match self.cached(prefix) {
+            Some(range) => range,
+            None => match self.source.fetch(prefix).await {
+                Ok(body) => self.remember(prefix, parse_range(&body)),
+                Err(e) => {
+                    warn!("couldn't check a password against the breach list: {}", e);
+                    return None;
+                }
+            },
+        };
+        Some(range.get(suffix).copied().unwrap_or(0))
+    }
+
+    fn cached(&self, prefix: &str) -> Option<Range> {
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        match cache.get(prefix) {
+            Some((fetched, range)) if fetched.elapsed() < CACHE_TTL => Some(range.clone()),
+            _ => None,
+        }
+    }
+
+    fn remember(&self, prefix: &str, range: HashMap<String, u64>) -> Range {
+        let range = Arc::new(range);
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if cache.len() >= PRUNE_THRESHOLD {
+            cache.retain(|_, (fetched, _)| fetched.elapsed() < CACHE_TTL);
+        }
+        cache.insert(prefix.to_string(), (Instant::now(), range.clone()));
+        range
+    }
+}
+
+/// Read a range response. Zero-count lines are padding, and junk lines get
+/// skipped rather than failing the whole thing.
+fn parse_range(body: &str) -> HashMap<String, u64> {
+    body.lines()
+        .filter_map(|line| {
+            let (suffix, count) = line.trim().split_once(':')?;
+            let count: u64 = count.trim().parse().ok()?;
+            (count > 0).then(|| (suffix.to_ascii_uppercase(), count))
+        })
+        .collect()
+}
+
+/// A range source that knows a fixed list of breached passwords, and can be
+/// told to fail. Counts its fetches, for checking the cache.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct StubRange {
+    passwords: Vec<String>,
+    pub broken: std::sync::atomic::AtomicBool,
+    pub fetches: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl StubRange {
+    pub fn new(passwords: &[&str]) -> Self {
+        Self {
+            passwords: passwords.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl RangeSource for StubRange {
+    fn fetch<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<String>> {
+        use std::sync::atomic::Ordering;
+        Box::pin(async move {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            if self.broken.load(Ordering::SeqCst) {
+                anyhow::bail!("stub range source is broken");
+            }
+            let mut body = String::from("0000000000000000000000000000000000A:0\n");
+            for password in &self.passwords {
+                let hash = base16ct::upper::encode_string(&Sha1::digest(password));
+                if let Some(suffix) = hash.strip_prefix(prefix) {
+                    body.push_str(&format!("{}:1234\n", suffix));
+                }
+            }
+            Ok(body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn range_parsing() {
+        let range = parse_range(
+            "1E4C9B93F3F0682250B6CF8331B7EE68FD8:3730471\r\n\
+             011053FD0102E94D6AE2F8B83D76FAF94F6:0\r\n\
+             nonsense\r\n\
+             00d4f6e8fa6eecad2a3aa415eec418d38ec:2\r\n",
+        );
+        assert_eq!(range.len(), 2);
+        assert_eq!(range["1E4C9B93F3F0682250B6CF8331B7EE68FD8"], 3730471);
+        assert_eq!(range["00D4F6E8FA6EECAD2A3AA415EEC418D38EC"], 2);
+    }
+
+    #[tokio::test]
+    async fn lookups_cache_and_fail_open() {
+        let stub = Arc::new(StubRange::new(&["password", "hunter2"]));
+        let checker = PwnedChecker {
+            source: stub.clone(),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        };
+        assert_eq!(checker.times_pwned("password").await, Some(1234));
+        assert_eq!(checker.times_pwned("vivid-Otter-lamp-97").await, Some(0));
+        assert_eq!(stub.fetches.load(Ordering::SeqCst), 2);
+        // Same prefix again: from the cache
+        assert_eq!(checker.times_pwned("password").await, Some(1234));
+        assert_eq!(stub.fetches.load(Ordering::SeqCst), 2);
+
+        // Cached ranges survive an outage; anything else is a shrug
+        stub.broken.store(true, Ordering::SeqCst);
+        assert_eq!(checker.times_pwned("password").await, Some(1234));
+        assert_eq!(checker.times_pwned("hunter2").await, None);
+        // ...and failures don't get cached
+        stub.broken.store(false, Ordering::SeqCst);
+        assert_eq!(checker.times_pwned("hunter2").await, Some(1234));
+    }
+}
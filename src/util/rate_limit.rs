@@ -0,0 +1,377 @@
+//! A dumb little in-memory, fixed-window rate limiter. It's per-process, so
+//! under mod_fcgid (which can run several of us at once) the real limit is
+//! "this many per process," which is still plenty to make guessing passwords
+//! pointless. If that ever stops being good enough, it'll have to move into
+//! the database.
+//!
+//! Some clients should never get throttled at all (uptime monitors, the
+//! operator's own machines), so there's also an allowlist of IP ranges
+//! and user agents that skip the limiters entirely.
+
+use super::client_ip;
+use http::{header, HeaderMap};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Once the map gets this big, sweep out expired windows on the next write.
+const PRUNE_THRESHOLD: usize = 1000;
+
+/// Counts attempts per key within a fixed window. Cheap to clone; clones
+/// share the same counts.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    max: u32,
+    window: Duration,
+    // key => (window start, count). std Mutex is fine, since nobody holds
+    // it across an await.
+    hits: Arc<Mutex<HashMap<String, (Instant, u32)>>>,
+}
+
+impl RateLimiter {
+    /// Allow up to `max` attempts per key in each `window`.
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            hits: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// How many attempts each key gets per window.
+    pub fn max(&self) -> u32 {
+        self.max
+    }
+
+    /// Whether the key has used up its attempts for the current window.
+    /// Doesn't count as an attempt.
+    pub fn is_limited(&self, key: &str) -> bool {
+        let hits = self.hits.lock().unwrap_or_else(|e| e.into_inner());
+        match hits.get(key) {
+            Some((start, count)) => start.elapsed() < self.window && *count >= self.max,
+            None => false,
+        }
+    }
+
+    /// Count an attempt against the key, and return how many attempts it's
+    /// made in the current window (including this one).
+    pub fn record(&self, key: &str) -> u32 {
+        let mut hits = self.hits.lock().unwrap_or_else(|e| e.into_inner());
+        if hits.len() >= PRUNE_THRESHOLD {
+            hits.retain(|_, (start, _)| start.elapsed() < self.window);
+        }
+        let entry = hits.entry(key.to_string()).or_insert((Instant::now(), 0));
+        if entry.0.elapsed() >= self.window {
+            *entry = (Instant::now(), 0);
+        }
+        entry.1 += 1;
+        entry.1
+    }
+
+    /// Forget about a key, e.g. after a successful login.
+    pub fn reset(&self, key: &str) {
+        let mut hits = self.hits.lock().unwrap_or_else(|e| e.into_inner());
+        hits.remove(key);
+    }
+
+    /// What the limiter's tracking right now, for debugging lockouts: the
+    /// `top` keys with live windows, limited ones first, then by count.
+    pub fn snapshot(&self, top: usize) -> LimiterSnapshot {
+        let hits = self.hits.lock().unwrap_or_else(|e| e.into_inner());
+        let mut entries: Vec<LimiterEntry> = hits
+            .iter()
+            .filter(|(_, (start, _))| start.elapsed() < self.window)
+            .map(|(key, (start, count))| LimiterEntry {
+                key: key.clone(),
+                count: *count,
+                limited: *count >= self.max,
+                resets_in_secs: self.window.saturating_sub(start.elapsed()).as_secs(),
+            })
+            .collect();
+        drop(hits);
+        let tracked = entries.len();
+        entries.sort_by(|a, b| {
+            b.limited
+                .cmp(&a.limited)
+                .then(b.count.cmp(&a.count))
+                .then(a.key.cmp(&b.key))
+        });
+        entries.truncate(top);
+        LimiterSnapshot {
+            max: self.max,
+            window_secs: self.window.as_secs(),
+            tracked,
+            entries,
+        }
+    }
+}
+
+/// A limiter's state at a glance. See `RateLimiter::snapshot`.
+#[derive(Serialize, Debug)]
+pub struct LimiterSnapshot {
+    pub max: u32,
+    pub window_secs: u64,
+    /// How many keys have a live window, whether or not they're listed.
+    pub tracked: usize,
+    pub entries: Vec<LimiterEntry>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct LimiterEntry {
+    pub key: String,
+    pub count: u32,
+    /// Whether it's currently getting turned away.
+    pub limited: bool,
+    pub resets_in_secs: u64,
+}
+
+/// Running totals for failed Bearer token logins since this process
+/// started, for the admin report. The limiter only remembers its current
+/// windows; these say whether anyone's been knocking on the door all week.
+/// Cheap to clone; clones share the counts.
+#[derive(Clone, Debug, Default)]
+pub struct FailureCounts {
+    counts: Arc<FailureCountsInner>,
+}
+
+#[derive(Debug, Default)]
+struct FailureCountsInner {
+    failures: AtomicU64,
+    unattributed: AtomicU64,
+    alerts: AtomicU64,
+    blocked: AtomicU64,
+}
+
+impl FailureCounts {
+    /// Count a bad token. Unattributed ones came from a client we couldn't
+    /// get an IP for (like anything under fcgi), so no limiter saw them.
+    pub fn failure(&self, attributed: bool) {
+        self.counts.failures.fetch_add(1, Ordering::Relaxed);
+        if !attributed {
+            self.counts.unattributed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a client hitting the limit and setting off an alert.
+    pub fn alert(&self) {
+        self.counts.alerts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request turned away because its client was over the limit.
+    pub fn blocked(&self) {
+        self.counts.blocked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> FailureCountsSnapshot {
+        FailureCountsSnapshot {
+            failures: self.counts.failures.load(Ordering::Relaxed),
+            unattributed: self.counts.unattributed.load(Ordering::Relaxed),
+            alerts: self.counts.alerts.load(Ordering::Relaxed),
+            blocked: self.counts.blocked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// See `FailureCounts`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FailureCountsSnapshot {
+    pub failures: u64,
+    pub unattributed: u64,
+    pub alerts: u64,
+    pub blocked: u64,
+}
+
+/// A block of IP addresses in CIDR notation, like `192.0.2.0/24` or
+/// `2001:db8::/32`. A bare address means just that one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn parse(text: &str) -> Option<Self> {
+        let (addr, prefix) = match text.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse().ok().filter(|p| *p <= max)?,
+            None => max,
+        };
+        Some(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 can show up dressed as IPv6 (::ffff:192.0.2.1).
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for IpRange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Clients that skip rate limiting. User agents are trivially faked, so
+/// only list ones you'd be fine with anybody borrowing; the IP ranges rely
+/// on `client_ip`, so they only work behind a reverse proxy.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RateLimitAllowlist {
+    pub ips: Vec<IpRange>,
+    /// Matched as case-insensitive substrings of the User-Agent header.
+    pub user_agents: Vec<String>,
+}
+
+impl RateLimitAllowlist {
+    pub fn exempts(&self, headers: &HeaderMap) -> bool {
+        let ip_ok = client_ip(headers)
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .is_some_and(|ip| self.ips.iter().any(|range| range.contains(ip)));
+        if ip_ok {
+            return true;
+        }
+        let Some(agent) = headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return false;
+        };
+        let agent = agent.to_lowercase();
+        self.user_agents
+            .iter()
+            .any(|allowed| agent.contains(&allowed.to_lowercase()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_and_resets() {
+        let limiter = RateLimiter::new(3, Duration::from_secs(600));
+        assert!(!limiter.is_limited("someone"));
+        assert_eq!(limiter.record("someone"), 1);
+        assert_eq!(limiter.record("someone"), 2);
+        assert!(!limiter.is_limited("someone"));
+        assert_eq!(limiter.record("someone"), 3);
+        assert!(limiter.is_limited("someone"));
+        // Other keys are unaffected
+        assert!(!limiter.is_limited("someone_else"));
+        // Clones share counts
+        assert!(limiter.clone().is_limited("someone"));
+        limiter.reset("someone");
+        assert!(!limiter.is_limited("someone"));
+    }
+
+    #[test]
+    fn snapshots() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(600));
+        limiter.record("a");
+        limiter.record("b");
+        limiter.record("b");
+        limiter.record("c");
+        let snap = limiter.snapshot(2);
+        assert_eq!((snap.max, snap.window_secs, snap.tracked), (2, 600, 3));
+        let keys: Vec<(&str, bool)> = snap
+            .entries
+            .iter()
+            .map(|e| (e.key.as_str(), e.limited))
+            .collect();
+        assert_eq!(keys, vec![("b", true), ("a", false)]);
+        assert!(snap.entries[0].resets_in_secs <= 600);
+    }
+
+    #[test]
+    fn failure_counts() {
+        let counts = FailureCounts::default();
+        counts.failure(true);
+        counts.failure(false);
+        counts.clone().alert();
+        counts.blocked();
+        assert_eq!(
+            counts.snapshot(),
+            FailureCountsSnapshot {
+                failures: 2,
+                unattributed: 1,
+                alerts: 1,
+                blocked: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn allowlists() {
+        let range = |s: &str| IpRange::parse(s).unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(range("192.0.2.0/24").contains(ip("192.0.2.200")));
+        assert!(!range("192.0.2.0/24").contains(ip("192.0.3.1")));
+        assert!(range("192.0.2.7").contains(ip("192.0.2.7")));
+        assert!(!range("192.0.2.7").contains(ip("192.0.2.8")));
+        assert!(range("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(range("192.0.2.0/24").contains(ip("::ffff:192.0.2.1")));
+        assert!(range("2001:db8::/32").contains(ip("2001:db8:1::5")));
+        assert!(!range("2001:db8::/32").contains(ip("192.0.2.1")));
+        assert_eq!(range("10.1.2.3/8").to_string(), "10.1.2.3/8");
+        for bad in ["192.0.2.0/33", "2001:db8::/129", "nope", "192.0.2.0/x", ""] {
+            assert_eq!(IpRange::parse(bad), None, "{}", bad);
+        }
+
+        let allowlist = RateLimitAllowlist {
+            ips: vec![range("198.51.100.0/24")],
+            user_agents: vec!["UptimeRobot".to_string()],
+        };
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut h = HeaderMap::new();
+            for (name, value) in pairs {
+                h.append(*name, value.parse().unwrap());
+            }
+            h
+        };
+        assert!(allowlist.exempts(&headers(&[("x-forwarded-for", "198.51.100.7")])));
+        // Only the last hop counts
+        assert!(!allowlist.exempts(&headers(&[(
+            "x-forwarded-for",
+            "198.51.100.7, 203.0.113.9"
+        )])));
+        assert!(allowlist.exempts(&headers(&[(
+            "user-agent",
+            "Mozilla/5.0+(compatible; uptimerobot/2.0)"
+        )])));
+        assert!(!allowlist.exempts(&headers(&[("user-agent", "curl/8.0")])));
+        assert!(!allowlist.exempts(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn windows_expire() {
+        let limiter = RateLimiter::new(1, Duration::ZERO);
+        limiter.record("someone");
+        // Zero-length window is always already over
+        assert!(!limiter.is_limited("someone"));
+        assert_eq!(limiter.record("someone"), 1);
+    }
+}
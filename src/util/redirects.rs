@@ -0,0 +1,300 @@
+//! Following redirect chains, so that a new dogear gets the URL a site
+//! actually lives at instead of whatever alias you happened to land on.
+//! (Lots of serial sites have short links like `/c/5` that bounce to
+//! `/chapters/5`, and marking both means two dogears for one story.)
+//!
+//! This makes outbound requests to URLs that users type in, so it's off
+//! unless the config turns it on, it stays on the submitted URL's host (a
+//! redirect to anywhere else ends the chase), and it goes through the
+//! shared outbound rules, so in production it won't go anywhere private.
+
+use super::{matchable_from_url, normalize_prefix_matcher};
+use crate::outbound::{self, validate_url, Schemes};
+use reqwest::{header, StatusCode};
+use std::time::Duration;
+use tracing::debug;
+use url::Url;
+
+/// How many redirects we'll follow before settling for the last stop.
+pub const MAX_REDIRECT_HOPS: usize = 5;
+/// Per-request budget. Creating a dogear shouldn't hang on a slow site.
+const HOP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A reqwest client that doesn't follow redirects on its own, so we can
+/// check each hop. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct RedirectResolver {
+    production: bool,
+    http: reqwest::Client,
+}
+
+impl RedirectResolver {
+    pub fn new(production: bool) -> reqwest::Result<Self> {
+        let http = outbound::client(HOP_TIMEOUT, 0, production)?;
+        Ok(Self { production, http })
+    }
+
+    /// Follow the redirect chain for a URL (HEAD requests, same host only,
+    /// at most MAX_REDIRECT_HOPS) and return where it ends up. Best effort:
+    /// if anything goes wrong, you get the furthest URL we got to, which is
+    /// the original URL if we didn't get anywhere.
+    #[tracing::instrument(skip(self))]
+    pub async fn resolve(&self, url: &str) -> String {
+        let Ok(original) = validate_url(url, Schemes::Any, self.production).await else {
+            return url.to_string();
+        };
+        let mut current = original.clone();
+        for _ in 0..MAX_REDIRECT_HOPS {
+            let resp = match self.http.head(current.clone()).send().await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    debug!("stopped resolving at {}: {}", current, e);
+                    break;
+                }
+            };
+            let location = resp
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|v| v.to_str().ok());
+            match next_hop(&current, resp.status(), location) {
+                Some(next) => current = next,
+                None => break,
+            }
+        }
+        // Only swap in a new string if something actually changed, so the
+        // caller's URL keeps its exact original spelling otherwise.
+        if current == original {
+            url.to_string()
+        } else {
+            current.into()
+        }
+    }
+
+    /// Resolve the current URL for a new dogear, and adjust the prefix to
+    /// match if the redirect moved things around. Returns the (prefix,
+    /// current) to actually save.
+    ///
+    /// If the resolved URL still matches the prefix, only the current URL
+    /// changes. If it doesn't, but it ends with the same bit that came after
+    /// the prefix in the original URL (e.g. prefix `site.com/c`, `/5` both
+    /// times), we swap the prefix's stem for the new one. Otherwise we can't
+    /// tell what the right prefix would be, so we leave both alone.
+    pub async fn canonicalize(&self, prefix: &str, current: &str) -> (String, String) {
+        let resolved = self.resolve(current).await;
+        let unchanged = (prefix.to_string(), current.to_string());
+        if resolved == current {
+            return unchanged;
+        }
+        match rebase_prefix(prefix, current, &resolved) {
+            Some(new_prefix) => (new_prefix, resolved),
+            None => {
+                debug!(
+                    "resolved {} to {}, but couldn't fit prefix {} to it",
+                    current, resolved, prefix
+                );
+                unchanged
+            }
+        }
+    }
+}
+
+/// Given a response, decide where (if anywhere) to go next. Only redirect
+/// statuses with a parseable Location on the same host (ignoring `www.`
+/// and `m.`) count.
+fn next_hop(current: &Url, status: StatusCode, location: Option<&str>) -> Option<Url> {
+    if !status.is_redirection() || status == StatusCode::NOT_MODIFIED {
+        return None;
+    }
+    let next = current.join(location?).ok()?;
+    if !matches!(next.scheme(), "http" | "https") || next == *current {
+        return None;
+    }
+    if trimmed_host(&next)? != trimmed_host(current)? {
+        debug!(
+            "not following off-site redirect from {} to {}",
+            current, next
+        );
+        return None;
+    }
+    Some(next)
+}
+
+fn trimmed_host(url: &Url) -> Option<&str> {
+    let host = url.host_str()?;
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    Some(host.strip_prefix("m.").unwrap_or(host))
+}
+
+/// Work out the prefix for a resolved URL; see `canonicalize`.
+fn rebase_prefix(prefix: &str, original: &str, resolved: &str) -> Option<String> {
+    let prefix = normalize_prefix_matcher(prefix);
+    let resolved_matchable = matchable_from_url(resolved).ok()?;
+    if resolved_matchable.starts_with(prefix) {
+        return Some(prefix.to_string());
+    }
+    let rest = matchable_from_url(original).ok()?.strip_prefix(prefix)?;
+    if rest.is_empty() {
+        return None;
+    }
+    let new_prefix = resolved_matchable.strip_suffix(rest)?;
+    // A prefix that's down to just a hostname (or less) would match the
+    // whole site, which is not what anybody asked for.
+    if !new_prefix.contains('/') {
+        return None;
+    }
+    Some(new_prefix.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Path, response::Redirect, routing::get, Router};
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn hops() {
+        let here = url("https://www.example.com/c/5");
+        let moved = StatusCode::MOVED_PERMANENTLY;
+        // Relative and same-host locations are fine, www or no
+        assert_eq!(
+            next_hop(&here, moved, Some("/chapters/5")),
+            Some(url("https://www.example.com/chapters/5"))
+        );
+        assert_eq!(
+            next_hop(
+                &here,
+                StatusCode::FOUND,
+                Some("https://example.com/chapters/5")
+            ),
+            Some(url("https://example.com/chapters/5"))
+        );
+        // Other hosts, non-redirects, loops, and junk Locations all stop
+        assert_eq!(
+            next_hop(&here, moved, Some("https://example.horse/5")),
+            None
+        );
+        assert_eq!(
+            next_hop(&here, moved, Some("https://cdn.example.com/5")),
+            None
+        );
+        assert_eq!(next_hop(&here, StatusCode::OK, Some("/chapters/5")), None);
+        assert_eq!(next_hop(&here, StatusCode::NOT_MODIFIED, Some("/x")), None);
+        assert_eq!(next_hop(&here, moved, Some("/c/5")), None);
+        assert_eq!(next_hop(&here, moved, None), None);
+        assert_eq!(next_hop(&here, moved, Some("ftp://example.com/c/5")), None);
+    }
+
+    #[test]
+    fn prefix_rebasing() {
+        // Still matches: keep the prefix
+        assert_eq!(
+            rebase_prefix(
+                "example.com/comic",
+                "https://example.com/comic/c/5",
+                "https://example.com/comic/chapters/5"
+            ),
+            Some("example.com/comic".to_string())
+        );
+        // Same tail: swap the stem
+        assert_eq!(
+            rebase_prefix(
+                "example.com/c",
+                "https://example.com/c/5",
+                "https://www.example.com/chapters/5"
+            ),
+            Some("example.com/chapters".to_string())
+        );
+        // Different tail: no idea
+        assert_eq!(
+            rebase_prefix(
+                "example.com/c",
+                "https://example.com/c/5",
+                "https://example.com/chapters/5-the-big-day"
+            ),
+            None
+        );
+        // Would widen to the whole site: nope
+        assert_eq!(
+            rebase_prefix(
+                "example.com/c",
+                "https://example.com/c/5",
+                "https://example.com/5"
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn resolves_against_a_real_server() {
+        let app = Router::new()
+            .route(
+                "/c/:n",
+                get(|Path(n): Path<u32>| async move { Redirect::permanent(&format!("/ch/{}", n)) }),
+            )
+            .route(
+                "/ch/:n",
+                get(|Path(n): Path<u32>| async move {
+                    Redirect::temporary(&format!("/chapters/{}", n))
+                }),
+            )
+            .route("/chapters/:n", get(|| async { "the good stuff" }))
+            .route("/loop", get(|| async { Redirect::temporary("/loop2") }))
+            .route("/loop2", get(|| async { Redirect::temporary("/loop") }))
+            .route(
+                "/away",
+                get(|| async { Redirect::temporary("https://example.horse/") }),
+            );
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+        let resolver = RedirectResolver::new(false).unwrap();
+
+        // Two hops
+        assert_eq!(
+            resolver.resolve(&format!("{}/c/5", base)).await,
+            format!("{}/chapters/5", base)
+        );
+        // Already there
+        assert_eq!(
+            resolver.resolve(&format!("{}/chapters/5", base)).await,
+            format!("{}/chapters/5", base)
+        );
+        // Loops give up after the hop limit, somewhere in the loop
+        let looped = resolver.resolve(&format!("{}/loop", base)).await;
+        assert!(looped.starts_with(&format!("{}/loop", base)));
+        // Won't leave the site
+        assert_eq!(
+            resolver.resolve(&format!("{}/away", base)).await,
+            format!("{}/away", base)
+        );
+        // Unreachable or weird: leave it alone
+        assert_eq!(
+            resolver.resolve("http://127.0.0.1:1/c/5").await,
+            "http://127.0.0.1:1/c/5"
+        );
+        assert_eq!(resolver.resolve("not a url").await, "not a url");
+        // In production, a private host doesn't even get asked.
+        let production = RedirectResolver::new(true).unwrap();
+        assert_eq!(
+            production.resolve(&format!("{}/c/5", base)).await,
+            format!("{}/c/5", base)
+        );
+
+        // The whole deal, prefix and all. Host is an IP here, so the prefix
+        // includes the port.
+        let host = base.trim_start_matches("http://");
+        assert_eq!(
+            resolver
+                .canonicalize(&format!("{}/c", host), &format!("{}/c/5", base))
+                .await,
+            (format!("{}/chapters", host), format!("{}/chapters/5", base))
+        );
+
+        server.abort();
+    }
+}
@@ -0,0 +1,236 @@
+//! What counts as a username. By default it's ASCII letters, numbers, `-`,
+//! and `_`, which is easy to reason about but shuts out anyone whose name
+//! isn't spelled in English. The `unicode_usernames` config switch opens it
+//! up to letters and numbers in any script, with some care taken so that
+//! two names can't look the same on screen.
+//!
+//! Every user gets a `username_key` either way: the name, lowercased, with
+//! lookalike letters from other scripts swapped for the Latin ones they
+//! resemble. For ASCII names that's plain `lower(username)`, which is what
+//! the migration backfilled. Under the Unicode policy, a new name can't
+//! share a key with anyone else's; older accounts that already collide
+//! (say, `Bob` and `bob`) are grandfathered in.
+
+use super::UserError;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::borrow::Cow;
+use unicode_normalization::{
+    char::is_combining_mark, is_nfkc_quick, IsNormalized, UnicodeNormalization,
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Longest allowed username. Characters under the ASCII policy, graphemes
+/// (what a reader would call characters) under the Unicode one.
+pub const USERNAME_MAX_LENGTH: usize = 80;
+
+/// Most code points one grapheme can have. Real scripts stack a few marks
+/// on a letter; a pile of dozens is just there to mess up the page.
+const MAX_GRAPHEME_LENGTH: usize = 5;
+
+/// Which new usernames we take. Existing accounts keep working under either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UsernamePolicy {
+    #[default]
+    Ascii,
+    Unicode,
+}
+
+impl UsernamePolicy {
+    pub fn from_config(unicode_usernames: bool) -> Self {
+        if unicode_usernames {
+            Self::Unicode
+        } else {
+            Self::Ascii
+        }
+    }
+}
+
+/// Trim and NFKC-normalize a username, which is how new ones get saved.
+/// Lookups by name go through this too, so a name typed with fullwidth or
+/// decomposed characters still finds its account. ASCII passes through
+/// untouched.
+pub fn normalize_username(username: &str) -> Cow<'_, str> {
+    let username = username.trim();
+    if is_nfkc_quick(username.chars()) == IsNormalized::Yes {
+        Cow::Borrowed(username)
+    } else {
+        Cow::Owned(username.nfkc().collect())
+    }
+}
+
+/// Check a new username against the policy, and return it the way it
+/// should be saved.
+pub fn clean_username(policy: UsernamePolicy, username: &str) -> Result<Cow<'_, str>, UserError> {
+    match policy {
+        UsernamePolicy::Ascii => clean_ascii(username.trim()).map(Cow::Borrowed),
+        UsernamePolicy::Unicode => {
+            let username = normalize_username(username);
+            clean_unicode(&username)?;
+            Ok(username)
+        }
+    }
+}
+
+fn clean_ascii(username: &str) -> Result<&str, UserError> {
+    lazy_static! {
+        static ref USERNAME_REGEX: Regex = Regex::new(r#"\A[a-zA-Z0-9_-]{1,80}\z"#).unwrap();
+    }
+    if USERNAME_REGEX.is_match(username) {
+        Ok(username)
+    } else {
+        Err(UserError::BadUsername {
+            name: username.to_string(),
+        })
+    }
+}
+
+/// Letters and numbers from any script, plus the marks that go on them,
+/// plus `-` and `_`. No spaces, punctuation, symbols, or invisible
+/// formatting characters (zero-width joiners and such), since those are
+/// the usual ways to make one name pass for another.
+fn clean_unicode(username: &str) -> Result<(), UserError> {
+    let bad = |why: &'static str| UserError::UsernameNotAllowed {
+        name: username.to_string(),
+        why,
+    };
+    let allowed = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+    let Some(first) = username.chars().next() else {
+        return Err(bad("Usernames can't be blank."));
+    };
+    if !username.chars().all(|c| allowed(c) || is_combining_mark(c)) {
+        return Err(bad(
+            "Usernames can only use letters, numbers, hyphens (-), and underscores (_).",
+        ));
+    }
+    if !allowed(first) {
+        return Err(bad(
+            "Usernames have to start with a letter, number, hyphen, or underscore.",
+        ));
+    }
+    let mut length = 0;
+    for grapheme in username.graphemes(true) {
+        if grapheme.chars().count() > MAX_GRAPHEME_LENGTH {
+            return Err(bad("That's too many accents on one letter."));
+        }
+        length += 1;
+    }
+    if length > USERNAME_MAX_LENGTH {
+        return Err(bad("Usernames can't be longer than 80 characters."));
+    }
+    Ok(())
+}
+
+/// The key two names have to share to count as the same name. Expects an
+/// already-normalized username.
+pub fn username_key(username: &str) -> String {
+    username
+        .chars()
+        .flat_map(char::to_lowercase)
+        .map(latin_lookalike)
+        .collect()
+}
+
+/// The Latin letter a lowercase Cyrillic or Greek one is easily mistaken
+/// for, if any. Not the whole Unicode confusables list, but it covers the
+/// ones that show up in impersonation attempts.
+fn latin_lookalike(c: char) -> char {
+    match c {
+        // Cyrillic
+        'а' => 'a',
+        'ԁ' => 'd',
+        'е' | 'ё' => 'e',
+        'һ' => 'h',
+        'і' | 'ӏ' => 'i',
+        'ј' => 'j',
+        'к' => 'k',
+        'о' => 'o',
+        'р' => 'p',
+        'ԛ' => 'q',
+        'ѕ' => 's',
+        'с' => 'c',
+        'у' => 'y',
+        'ԝ' => 'w',
+        'х' => 'x',
+        // Greek
+        'α' => 'a',
+        'ε' => 'e',
+        'η' => 'n',
+        'ι' => 'i',
+        'κ' => 'k',
+        'ν' => 'v',
+        'ο' => 'o',
+        'ρ' => 'p',
+        'υ' => 'u',
+        'χ' => 'x',
+        // Latin, but not ASCII
+        'ı' => 'i',
+        'ȷ' => 'j',
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_policy() {
+        let clean = |name| clean_username(UsernamePolicy::Ascii, name);
+        assert_eq!(clean("  some_one-2 ").unwrap(), "some_one-2");
+        assert!(clean("").is_err());
+        assert!(clean("two words").is_err());
+        assert!(clean("zoë").is_err());
+        assert!(clean(&"x".repeat(81)).is_err());
+    }
+
+    #[test]
+    fn unicode_policy() {
+        let clean = |name| clean_username(UsernamePolicy::Unicode, name);
+        for name in [
+            "some_one-2",
+            "zoë",
+            "Ἀριστοτέλης",
+            "дмитрий",
+            "佐藤",
+            "हिन्दी",
+            "محمد",
+        ] {
+            assert_eq!(clean(name).unwrap(), name);
+        }
+        // NFKC: fullwidth folds to ASCII, and a decomposed ë composes.
+        assert_eq!(clean("ｆｕｌｌ").unwrap(), "full");
+        assert_eq!(clean("zoe\u{308}").unwrap(), "zoë");
+        // Length is in graphemes, not bytes or code points.
+        assert!(clean(&"हि".repeat(80)).is_ok());
+        assert!(clean(&"ë".repeat(81)).is_err());
+        // No invisibles, spaces, symbols, or piles of marks.
+        for name in [
+            "",
+            "   ",
+            "two words",
+            "zero\u{200b}width",
+            "joi\u{200d}ner",
+            "rtl\u{202e}flip",
+            "ex@mple",
+            "🐶",
+            "\u{301}leading",
+            "z\u{301}\u{302}\u{303}\u{304}\u{305}\u{306}algo",
+        ] {
+            assert!(clean(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn keys() {
+        // ASCII keys are just lowercase, to match the migration's backfill.
+        assert_eq!(username_key("Some_One-2"), "some_one-2");
+        // Lookalikes from other scripts collapse onto Latin...
+        assert_eq!(username_key("раураl"), "paypal");
+        assert_eq!(username_key("ΑΡΕ"), "ape");
+        assert_eq!(username_key(&normalize_username("ｂｏｂ")), "bob");
+        // ...but real differences stay different.
+        assert_ne!(username_key("zoë"), username_key("zoe"));
+        assert_eq!(username_key("Дмитрий"), "дмитpий");
+    }
+}
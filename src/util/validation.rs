@@ -0,0 +1,155 @@
+//! Limits on the free-form text people can hand us, all in one place. The
+//! db methods that save these fields check them on the way in, so nothing
+//! that gets past a form or the API can bloat the tables or wreck the list
+//! page; routes can check earlier (for friendlier errors) with the same
+//! rules. Lengths are in characters, not bytes.
+
+use super::{clean_optional_form_field, UserError};
+
+/// Longest allowed dogear display name.
+pub const DISPLAY_NAME_MAX_LENGTH: usize = 200;
+/// Longest allowed dogear prefix. Same as URLs, since prefixes mostly get
+/// cut down from one.
+pub const PREFIX_MAX_LENGTH: usize = URL_MAX_LENGTH;
+/// Longest allowed dogear URL. Long enough for any real chapter link,
+/// query string and all.
+pub const URL_MAX_LENGTH: usize = 2000;
+/// Longest allowed email address (the SMTP limit).
+pub const EMAIL_MAX_LENGTH: usize = 254;
+/// Longest allowed token comment.
+pub const TOKEN_COMMENT_MAX_LENGTH: usize = 100;
+/// Longest allowed note on a dogear update.
+pub const NOTE_MAX_LENGTH: usize = 200;
+/// Longest allowed dogears list search. Anything longer than a URL can't
+/// match anything anyway.
+pub const SEARCH_MAX_LENGTH: usize = URL_MAX_LENGTH;
+
+/// The kinds of text we check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    DisplayName,
+    Prefix,
+    Url,
+    Email,
+    TokenComment,
+    Note,
+    Search,
+}
+
+impl Field {
+    pub fn max_length(self) -> usize {
+        match self {
+            Field::DisplayName => DISPLAY_NAME_MAX_LENGTH,
+            Field::Prefix => PREFIX_MAX_LENGTH,
+            Field::Url => URL_MAX_LENGTH,
+            Field::Email => EMAIL_MAX_LENGTH,
+            Field::TokenComment => TOKEN_COMMENT_MAX_LENGTH,
+            Field::Note => NOTE_MAX_LENGTH,
+            Field::Search => SEARCH_MAX_LENGTH,
+        }
+    }
+
+    /// What to call it at the start of an error message.
+    pub fn label(self) -> &'static str {
+        match self {
+            Field::DisplayName => "Display names",
+            Field::Prefix => "Prefixes",
+            Field::Url => "URLs",
+            Field::Email => "Email addresses",
+            Field::TokenComment => "Token names",
+            Field::Note => "Notes",
+            Field::Search => "Searches",
+        }
+    }
+
+    /// Whether it's one unbroken string, where any whitespace is a mistake.
+    fn unbroken(self) -> bool {
+        matches!(self, Field::Prefix | Field::Url | Field::Email)
+    }
+}
+
+/// Check a value against its field's rules: not too long, no control
+/// characters (tabs and newlines included, since everything here displays
+/// on one line), and no whitespace at all in URL-ish fields. Doesn't trim;
+/// do that first if you're going to.
+pub fn validate(field: Field, value: &str) -> Result<(), UserError> {
+    let max = field.max_length();
+    if value.chars().count() > max {
+        return Err(UserError::TooLong { field, max });
+    }
+    if field.unbroken() {
+        if value.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(UserError::BadCharacters {
+                field,
+                what: "spaces or control characters",
+            });
+        }
+    } else if value.chars().any(char::is_control) {
+        return Err(UserError::BadCharacters {
+            field,
+            what: "control characters",
+        });
+    }
+    Ok(())
+}
+
+/// `clean_optional_form_field` plus `validate`, for optional fields where
+/// blank means none.
+pub fn clean_field(field: Field, value: Option<&str>) -> Result<Option<&str>, UserError> {
+    let cleaned = clean_optional_form_field(value);
+    if let Some(v) = cleaned {
+        validate(field, v)?;
+    }
+    Ok(cleaned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lengths() {
+        for field in [
+            Field::DisplayName,
+            Field::Prefix,
+            Field::Url,
+            Field::Email,
+            Field::TokenComment,
+            Field::Note,
+            Field::Search,
+        ] {
+            let max = field.max_length();
+            // Counted in characters, so multibyte stuff gets the full length.
+            assert!(validate(field, &"🐶".repeat(max)).is_ok());
+            let err = validate(field, &"x".repeat(max + 1)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("{} can't be longer than {} characters.", field.label(), max)
+            );
+        }
+    }
+
+    #[test]
+    fn characters() {
+        assert!(validate(Field::DisplayName, "Some Serial: Book 2").is_ok());
+        assert!(validate(Field::DisplayName, "Some\nSerial").is_err());
+        assert!(validate(Field::Note, "tab\there").is_err());
+        assert!(validate(Field::Url, "https://example.com/comic/1?a=b").is_ok());
+        assert!(validate(Field::Url, "https://example.com/comic 1").is_err());
+        assert!(validate(Field::Prefix, "example.com/\u{7f}").is_err());
+        assert!(validate(Field::Email, "dog@example.com").is_ok());
+        assert!(validate(Field::Email, "dog @example.com").is_err());
+    }
+
+    #[test]
+    fn clean() {
+        assert_eq!(clean_field(Field::DisplayName, None).unwrap(), None);
+        assert_eq!(clean_field(Field::DisplayName, Some("  ")).unwrap(), None);
+        assert_eq!(
+            clean_field(Field::DisplayName, Some(" Comic ")).unwrap(),
+            Some("Comic")
+        );
+        let long = "x".repeat(DISPLAY_NAME_MAX_LENGTH + 1);
+        assert!(clean_field(Field::DisplayName, Some(&long)).is_err());
+    }
+}
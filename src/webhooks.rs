@@ -0,0 +1,475 @@
+//! Outgoing webhooks: anyone can register a few URLs on their account page
+//! (if the site's `webhooks` setting is on), and each one gets a JSON POST
+//! whenever one of their dogears is made, moves, gets archived, or gets
+//! deleted. Database triggers queue up a delivery per webhook per change,
+//! with snapshots of the dogear from right before and right after it, so
+//! nothing that writes dogears has to remember about any of this; a
+//! background job sends whatever's due right after each change notice, and
+//! once a minute regardless, to pick up retries. Every change goes out as
+//! its own versioned event (see `DogearEvent` and its JSON Schema in
+//! api_types), in order. Each POST is signed with the webhook's secret,
+//! GitHub-style, so the receiving end can tell it's really from us.
+
+use crate::config::DogConfig;
+use crate::db::{Db, DueDelivery};
+use crate::outbound::{self, validate_url, Schemes};
+use crate::util::chapter_delta;
+use anyhow::{anyhow, bail};
+use eardogger_rs::api_types::{ApiWebhookEvent, DogearEventKind};
+use hmac::{Hmac, Mac};
+use reqwest::header;
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tracing::info;
+
+/// How many due deliveries to send per pass. The rest wait for the next one.
+const DELIVERIES_PER_PASS: u32 = 100;
+/// How long a webhook gets to answer.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest a failing delivery waits between tries.
+const MAX_RETRY_MINUTES: i64 = 6 * 60;
+/// The header the signature goes in.
+pub const SIGNATURE_HEADER: &str = "x-eardogger-signature";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The signature header's value for a request body: `sha256=` plus the hex
+/// HMAC-SHA256 of the body, keyed with the webhook's secret.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    let sig = mac.finalize().into_bytes();
+    format!("sha256={}", base16ct::lower::encode_string(&sig))
+}
+
+/// How long to wait after a delivery fails for the nth time: two minutes,
+/// doubling each time, up to six hours.
+pub fn retry_minutes(attempts: i64) -> i64 {
+    (1i64 << attempts.clamp(1, 10)).min(MAX_RETRY_MINUTES)
+}
+
+/// What one pass of the sender did.
+#[derive(Debug, Default, PartialEq)]
+pub struct WebhookReport {
+    pub sent: u32,
+    pub failed: u32,
+}
+
+/// Sends webhook deliveries that are due. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct WebhookSender {
+    db: Db,
+    enabled: bool,
+    production: bool,
+    http: reqwest::Client,
+}
+
+impl WebhookSender {
+    pub fn new(db: Db, config: &DogConfig) -> reqwest::Result<Self> {
+        let http = outbound::client(WEBHOOK_TIMEOUT, 0, config.production)?;
+        Ok(Self {
+            db,
+            enabled: config.webhooks,
+            production: config.production,
+            http,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Send every delivery that's due (up to DELIVERIES_PER_PASS of them),
+    /// and record how each one went. Does nothing if the config says not to.
+    #[tracing::instrument(skip_all)]
+    pub async fn run_due(&self) -> sqlx::Result<WebhookReport> {
+        let mut report = WebhookReport::default();
+        if !self.enabled {
+            return Ok(report);
+        }
+        let webhooks = self.db.webhooks();
+        // Once a webhook fails, leave the rest of its deliveries for later
+        // instead of hammering it. (That also keeps its events in order;
+        // after this pass, `due` holds them back until the retry.)
+        let mut failing = HashSet::new();
+        for due in webhooks.due(DELIVERIES_PER_PASS).await? {
+            if failing.contains(&due.webhook_id) {
+                continue;
+            }
+            match self.send(&due).await {
+                Ok(()) => {
+                    report.sent += 1;
+                    webhooks.record_delivered(due.id, due.webhook_id).await?;
+                }
+                Err(e) => {
+                    report.failed += 1;
+                    failing.insert(due.webhook_id);
+                    let attempts = due.attempts + 1;
+                    info!(
+                        webhook_id = due.webhook_id,
+                        attempts, "webhook delivery failed: {}", e
+                    );
+                    let retry = format!("+{} minutes", retry_minutes(attempts));
+                    webhooks
+                        .record_failure(due.id, due.webhook_id, &e.to_string(), &retry)
+                        .await?;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    async fn send(&self, due: &DueDelivery) -> anyhow::Result<()> {
+        // The rules might have tightened since they saved it.
+        let url = validate_url(&due.url, Schemes::HttpsInProduction, self.production)
+            .await
+            .map_err(|e| anyhow!(e))?;
+        let change = due.change()?;
+        let Some(dogear) = change.latest().cloned() else {
+            bail!("delivery {} has no dogear snapshots", due.id);
+        };
+        let delta = match (change.event, &change.before, &change.after) {
+            (DogearEventKind::Updated, Some(before), Some(after)) => {
+                chapter_delta(&before.current, &after.current)
+            }
+            _ => None,
+        };
+        let body = serde_json::to_vec(&ApiWebhookEvent {
+            change,
+            dogear,
+            chapter_delta: delta,
+            sent: OffsetDateTime::now_utc(),
+        })?;
+        let resp = self
+            .http
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, signature(&due.secret, &body))
+            .body(body)
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            bail!("the webhook answered {}", resp.status());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Bytes,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Router,
+    };
+    use eardogger_rs::api_types::{DOGEAR_EVENT_SCHEMA, DOGEAR_EVENT_VERSION};
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// Just enough JSON Schema to check our bodies against the published
+    /// schema: the keywords that file actually uses, and local $refs.
+    fn validate(schema: &Value, root: &Value, value: &Value) -> Result<(), String> {
+        let Some(schema) = schema.as_object() else {
+            return Ok(());
+        };
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let target = reference
+                .strip_prefix('#')
+                .and_then(|pointer| root.pointer(pointer))
+                .ok_or_else(|| format!("can't resolve {}", reference))?;
+            validate(target, root, value)?;
+        }
+        if let Some(types) = schema.get("type") {
+            let type_name = match value {
+                Value::Null => "null",
+                Value::Bool(_) => "boolean",
+                Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+                Value::Number(_) => "number",
+                Value::String(_) => "string",
+                Value::Array(_) => "array",
+                Value::Object(_) => "object",
+            };
+            let allowed = match types {
+                Value::Array(types) => types.iter().any(|t| t == type_name),
+                t => t == type_name,
+            };
+            if !allowed {
+                return Err(format!("{} isn't a {}", value, types));
+            }
+        }
+        if let Some(constant) = schema.get("const") {
+            if constant != value {
+                return Err(format!("{} isn't {}", value, constant));
+            }
+        }
+        if let Some(Value::Array(options)) = schema.get("enum") {
+            if !options.contains(value) {
+                return Err(format!("{} isn't one of {:?}", value, options));
+            }
+        }
+        if let Some(object) = value.as_object() {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for key in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(key) {
+                        return Err(format!("missing {}", key));
+                    }
+                }
+            }
+            if let Some(Value::Object(properties)) = schema.get("properties") {
+                for (key, subschema) in properties {
+                    if let Some(v) = object.get(key) {
+                        validate(subschema, root, v).map_err(|e| format!("{}: {}", key, e))?;
+                    }
+                }
+            }
+        }
+        if let Some(Value::Array(subschemas)) = schema.get("allOf") {
+            for subschema in subschemas {
+                validate(subschema, root, value)?;
+            }
+        }
+        if let Some(Value::Array(subschemas)) = schema.get("oneOf") {
+            let matching = subschemas
+                .iter()
+                .filter(|s| validate(s, root, value).is_ok())
+                .count();
+            if matching != 1 {
+                return Err(format!("{} matches {} of oneOf", value, matching));
+            }
+        }
+        if let (Some(condition), Some(then)) = (schema.get("if"), schema.get("then")) {
+            if validate(condition, root, value).is_ok() {
+                validate(then, root, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_against_schema(value: &Value) -> Result<(), String> {
+        let schema: Value = serde_json::from_str(DOGEAR_EVENT_SCHEMA).unwrap();
+        validate(&schema, &schema, value)
+    }
+
+    /// Parse a webhook body, after making sure it matches the schema.
+    fn checked_event(body: &[u8]) -> ApiWebhookEvent {
+        let value: Value = serde_json::from_slice(body).unwrap();
+        if let Err(e) = check_against_schema(&value) {
+            panic!("doesn't match the schema ({}): {}", e, value);
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn schema_catches_mistakes() {
+        let dogear = serde_json::json!({
+            "id": 1, "user_id": 1, "prefix": "example.com/", "current": "https://example.com/1",
+            "display_name": null, "updated": "2026-10-15T12:00:00Z",
+            "paused": false, "public": false, "archived": false,
+        });
+        let event = |kind: &str, before: &Value, after: &Value| {
+            serde_json::json!({
+                "version": 1, "event": kind, "dogear_id": 1, "occurred": "2026-10-15T12:00:00Z",
+                "before": before, "after": after,
+            })
+        };
+        assert!(check_against_schema(&event("created", &Value::Null, &dogear)).is_ok());
+        assert!(check_against_schema(&event("deleted", &dogear, &Value::Null)).is_ok());
+        assert!(check_against_schema(&event("updated", &dogear, &dogear)).is_ok());
+        // Wrong snapshots for the kind of event
+        assert!(check_against_schema(&event("created", &dogear, &dogear)).is_err());
+        assert!(check_against_schema(&event("updated", &Value::Null, &dogear)).is_err());
+        assert!(check_against_schema(&event("deleted", &dogear, &dogear)).is_err());
+        // Made-up events, missing fields, wrong types
+        assert!(check_against_schema(&event("moved", &dogear, &dogear)).is_err());
+        let mut wrong = event("created", &Value::Null, &dogear);
+        wrong.as_object_mut().unwrap().remove("version");
+        assert!(check_against_schema(&wrong).is_err());
+        let mut wrong = event("created", &Value::Null, &dogear);
+        wrong["after"]["paused"] = Value::from("no");
+        assert!(check_against_schema(&wrong).is_err());
+        let mut delta = event("updated", &dogear, &dogear);
+        delta["chapter_delta"] = Value::from(2);
+        assert!(check_against_schema(&delta).is_ok());
+        delta["chapter_delta"] = Value::from("two");
+        assert!(check_against_schema(&delta).is_err());
+    }
+
+    #[test]
+    fn backoff() {
+        assert_eq!(retry_minutes(1), 2);
+        assert_eq!(retry_minutes(3), 8);
+        assert_eq!(retry_minutes(50), MAX_RETRY_MINUTES);
+    }
+
+    #[tokio::test]
+    async fn delivering() {
+        let db = Db::new_test_db().await;
+        let user = db.test_user("whoever").await.unwrap();
+
+        // Takes POSTs at /hook and passes them along; /broken always fails.
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(move |headers: HeaderMap, body: Bytes| async move {
+                    let sig = headers
+                        .get(SIGNATURE_HEADER)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or("")
+                        .to_string();
+                    tx.send((sig, body)).unwrap();
+                    StatusCode::NO_CONTENT
+                }),
+            )
+            .route("/broken", post(|| async { StatusCode::BAD_GATEWAY }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let hook = db
+            .webhooks()
+            .create(user.id, &format!("http://{}/hook", addr))
+            .await
+            .unwrap()
+            .unwrap();
+        let broken = db
+            .webhooks()
+            .create(user.id, &format!("http://{}/broken", addr))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Off unless the config says otherwise.
+        let sender = WebhookSender::new(db.clone(), &DogConfig::test_config().unwrap()).unwrap();
+        let dogear = db
+            .dogears()
+            .create(
+                user.id,
+                "example.com/serial",
+                "https://example.com/serial/1",
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(sender.run_due().await.unwrap(), WebhookReport::default());
+
+        let mut config = DogConfig::test_config().unwrap();
+        config.webhooks = true;
+        let sender = WebhookSender::new(db.clone(), &config).unwrap();
+
+        // The create and a move, queued up; each gets its own POST.
+        db.dogears()
+            .update_one(dogear.id, user.id, "https://example.com/serial/2", None)
+            .await
+            .unwrap()
+            .unwrap();
+        let report = sender.run_due().await.unwrap();
+        assert_eq!(report, WebhookReport { sent: 2, failed: 1 });
+        let (sig, body) = rx.recv().await.unwrap();
+        assert_eq!(sig, signature(&hook.secret, &body));
+        let event = checked_event(&body);
+        assert_eq!(event.change.version, DOGEAR_EVENT_VERSION);
+        assert_eq!(event.change.event, DogearEventKind::Created);
+        assert_eq!(event.change.dogear_id, dogear.id);
+        assert!(event.change.before.is_none());
+        assert_eq!(event.chapter_delta, None);
+        let after = event.change.after.unwrap();
+        assert_eq!(after.current, "https://example.com/serial/1");
+        assert_eq!(event.dogear.current, "https://example.com/serial/1");
+        let event = checked_event(&rx.recv().await.unwrap().1);
+        assert_eq!(event.change.event, DogearEventKind::Updated);
+        assert_eq!(event.chapter_delta, Some(1));
+        assert_eq!(
+            event.change.before.unwrap().current,
+            "https://example.com/serial/1"
+        );
+        assert_eq!(
+            event.change.after.unwrap().current,
+            "https://example.com/serial/2"
+        );
+        assert_eq!(event.dogear.current, "https://example.com/serial/2");
+        assert!(rx.try_recv().is_err());
+
+        // Pausing doesn't count; moving does.
+        db.dogears()
+            .set_paused(dogear.id, user.id, true)
+            .await
+            .unwrap();
+        // (And the broken one's backed off, so it doesn't get tried either.)
+        assert_eq!(sender.run_due().await.unwrap(), WebhookReport::default());
+        db.dogears()
+            .set_paused(dogear.id, user.id, false)
+            .await
+            .unwrap();
+        db.dogears()
+            .update_one(dogear.id, user.id, "https://example.com/serial/3", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sender.run_due().await.unwrap().sent, 1);
+        let event = checked_event(&rx.recv().await.unwrap().1);
+        assert_eq!(event.change.event, DogearEventKind::Updated);
+        assert_eq!(event.dogear.current, "https://example.com/serial/3");
+
+        // Archiving and deleting get events too, and deleting sends the
+        // dogear as it was.
+        db.dogears()
+            .set_archived(dogear.id, user.id, true)
+            .await
+            .unwrap()
+            .unwrap();
+        db.dogears()
+            .destroy(dogear.id, user.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sender.run_due().await.unwrap().sent, 2);
+        let event = checked_event(&rx.recv().await.unwrap().1);
+        assert_eq!(event.change.event, DogearEventKind::Archived);
+        assert_eq!(event.chapter_delta, None);
+        assert!(!event.change.before.unwrap().archived);
+        assert!(event.change.after.unwrap().archived);
+        let event = checked_event(&rx.recv().await.unwrap().1);
+        assert_eq!(event.change.event, DogearEventKind::Deleted);
+        assert!(event.change.after.is_none());
+        assert_eq!(event.change.before.unwrap().id, dogear.id);
+        assert_eq!(event.dogear.current, "https://example.com/serial/3");
+
+        // The broken one's waiting on a retry, and says why.
+        let hooks = db.webhooks().list(user.id).await.unwrap();
+        let working = hooks.iter().find(|h| h.id == hook.id).unwrap();
+        assert!(working.last_delivered.is_some());
+        assert!(working.last_error.is_none());
+        let failing = hooks.iter().find(|h| h.id == broken.id).unwrap();
+        assert!(failing.last_delivered.is_none());
+        assert!(failing.last_error.as_ref().unwrap().contains("502"));
+        let pending = sqlx::query_scalar::<_, i64>(
+            "SELECT attempts FROM webhook_deliveries WHERE webhook_id = ? ORDER BY id;",
+        )
+        .bind(broken.id)
+        .fetch_all(&db.read_pool)
+        .await
+        .unwrap();
+        // The create got tried once, and everything after it is still
+        // waiting its turn behind the retry.
+        assert_eq!(pending, vec![1, 0, 0, 0, 0]);
+
+        // Deleting it takes its queue along.
+        db.webhooks()
+            .destroy(broken.id, user.id)
+            .await
+            .unwrap()
+            .unwrap();
+        let count = sqlx::query_scalar::<_, i64>("SELECT count(*) FROM webhook_deliveries;")
+            .fetch_one(&db.read_pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+}